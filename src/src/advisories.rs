@@ -0,0 +1,156 @@
+//! Matching the dependencies of modules against security advisories.
+//!
+//! Advisories are read from a local JSON database, so that update servers can refuse to publish
+//! modules whose bill of materials lists a known-vulnerable component without depending on an
+//! online service:
+//!
+//! ```json
+//! {
+//!   "advisories": [
+//!     {
+//!       "id": "GHSA-xxxx-xxxx-xxxx",
+//!       "package": "org/zlib",
+//!       "affected": [">=1.2.0, <1.2.12"],
+//!       "severity": "high",
+//!       "summary": "Memory corruption when inflating"
+//!     }
+//!   ]
+//! }
+//! ```
+
+use crate::error::*;
+use crate::provenance::SbomComponent;
+use crate::wasm_module::*;
+
+use log::*;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Parse a component version, accepting a `v` prefix and missing minor and patch numbers.
+fn parse_version(version: &str) -> Option<Version> {
+    let version = version.trim().trim_start_matches('v');
+    let core_len = version.find(['-', '+']).unwrap_or(version.len());
+    let (core, rest) = version.split_at(core_len);
+    let padding = match core.matches('.').count() {
+        0 => ".0.0",
+        1 => ".0",
+        _ => "",
+    };
+    Version::parse(&format!("{}{}{}", core, padding, rest)).ok()
+}
+
+/// A known vulnerability in some versions of a package.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Advisory {
+    /// Identifier of the advisory, such as a CVE or GHSA identifier.
+    pub id: String,
+    /// Name of the package, as `group/name` if it has a group.
+    pub package: String,
+    /// Affected versions. A version is affected if it matches any of the requirements.
+    pub affected: Vec<VersionReq>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+}
+
+impl Advisory {
+    /// Return `true` if a version of the package is affected.
+    pub fn affects(&self, version: &Version) -> bool {
+        self.affected.iter().any(|req| req.matches(version))
+    }
+}
+
+/// A set of advisories.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdvisoryDatabase {
+    pub advisories: Vec<Advisory>,
+}
+
+impl AdvisoryDatabase {
+    /// Parse a database from JSON.
+    pub fn from_json(json: &[u8]) -> Result<Self, WSError> {
+        Ok(serde_json::from_slice(json)?)
+    }
+
+    /// Load a database from a JSON file.
+    pub fn from_file(file: impl AsRef<Path>) -> Result<Self, WSError> {
+        Self::from_json(&std::fs::read(file.as_ref())?)
+    }
+
+    /// Return the advisories affecting a component.
+    ///
+    /// Components whose version is not a semantic version, even with missing minor and patch
+    /// numbers, can't be matched, and are ignored.
+    pub fn matches<'t>(&'t self, component: &SbomComponent) -> Vec<&'t Advisory> {
+        let advisories: Vec<_> = self
+            .advisories
+            .iter()
+            .filter(|advisory| advisory.package == component.name)
+            .collect();
+        if advisories.is_empty() {
+            return advisories;
+        }
+        let version = match parse_version(&component.version) {
+            Some(version) => version,
+            None => {
+                warn!(
+                    "Unable to check {} against advisories: [{}] is not a semantic version",
+                    component.name, component.version
+                );
+                return vec![];
+            }
+        };
+        advisories
+            .into_iter()
+            .filter(|advisory| advisory.affects(&version))
+            .collect()
+    }
+}
+
+/// A component of a module affected by an advisory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdvisoryMatch {
+    pub component: SbomComponent,
+    pub advisory: Advisory,
+}
+
+impl Module {
+    /// Return the advisories affecting the components listed in the bill of materials of the module.
+    ///
+    /// Modules without a bill of materials have no known dependencies, and nothing is returned.
+    pub fn audit_dependencies(
+        &self,
+        database: &AdvisoryDatabase,
+    ) -> Result<Vec<AdvisoryMatch>, WSError> {
+        let mut matches = vec![];
+        for component in self.sbom_components()?.unwrap_or_default() {
+            for advisory in database.matches(&component) {
+                matches.push(AdvisoryMatch {
+                    component: component.clone(),
+                    advisory: advisory.clone(),
+                });
+            }
+        }
+        Ok(matches)
+    }
+
+    /// Fail if a component of the module is affected by an advisory.
+    pub fn check_dependencies(&self, database: &AdvisoryDatabase) -> Result<(), WSError> {
+        let matches = self.audit_dependencies(database)?;
+        if matches.is_empty() {
+            return Ok(());
+        }
+        let descriptions: Vec<_> = matches
+            .iter()
+            .map(|m| {
+                format!(
+                    "{} {} ({})",
+                    m.component.name, m.component.version, m.advisory.id
+                )
+            })
+            .collect();
+        Err(WSError::VulnerableDependency(descriptions.join(", ")))
+    }
+}
@@ -0,0 +1,181 @@
+use super::*;
+use crate::repo::{build_id, validate_name};
+
+use std::collections::BTreeMap;
+
+/// Where an installed module is stored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModuleLocation {
+    Slot(Slot),
+    Golden,
+}
+
+impl std::fmt::Display for ModuleLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModuleLocation::Slot(slot) => write!(f, "slot {}", slot),
+            ModuleLocation::Golden => write!(f, "golden module"),
+        }
+    }
+}
+
+impl<S: Storage> Slots<S> {
+    /// Return the logical names of modules, such as `payment-plugin`, along with the build
+    /// identifiers they point to.
+    pub fn aliases(&self) -> Result<BTreeMap<String, String>, WSError> {
+        Ok(self.state()?.aliases)
+    }
+
+    fn set_aliases(&self, aliases: BTreeMap<String, String>) -> Result<(), WSError> {
+        let mut state = self.state()?;
+        state.aliases = aliases;
+        self.set_state(&state)
+    }
+
+    /// Point a logical name to an installed module, and return the build identifier it
+    /// previously pointed to.
+    ///
+    /// Hosts resolving the name either get the previous module or the new one, never a mix.
+    pub fn set_alias(&self, name: &str, build_id: &str) -> Result<Option<String>, WSError> {
+        validate_name(name)?;
+        if self.locate(build_id)?.is_none() {
+            return Err(WSError::NotFound(format!("installed module {}", build_id)));
+        }
+        let mut aliases = self.aliases()?;
+        let previous = aliases.insert(name.to_string(), build_id.to_string());
+        self.set_aliases(aliases)?;
+        Ok(previous)
+    }
+
+    /// Forget a logical name, and return the build identifier it pointed to.
+    pub fn remove_alias(&self, name: &str) -> Result<Option<String>, WSError> {
+        let mut aliases = self.aliases()?;
+        let previous = aliases.remove(name);
+        if previous.is_some() {
+            self.set_aliases(aliases)?;
+        }
+        Ok(previous)
+    }
+
+    /// Return where a module is installed, along with its description.
+    ///
+    /// The active slot is preferred if both slots hold the module.
+    pub fn locate(&self, build_id: &str) -> Result<Option<(ModuleLocation, SlotInfo)>, WSError> {
+        let state = self.state()?;
+        let active = state.active.unwrap_or(Slot::A);
+        for slot in [active, active.other()] {
+            if let Some(info) = state.info(slot) {
+                if info.build_id.eq_ignore_ascii_case(build_id) {
+                    return Ok(Some((ModuleLocation::Slot(slot), info.clone())));
+                }
+            }
+        }
+        match self.golden()? {
+            Some(info) if info.build_id.eq_ignore_ascii_case(build_id) => {
+                Ok(Some((ModuleLocation::Golden, info)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Return where the module a logical name points to is installed, along with its description.
+    pub fn resolve_alias(&self, name: &str) -> Result<(ModuleLocation, SlotInfo), WSError> {
+        let build_id = self
+            .aliases()?
+            .remove(name)
+            .ok_or_else(|| WSError::NotFound(format!("alias [{}]", name)))?;
+        self.locate(&build_id)?.ok_or_else(|| {
+            WSError::NotFound(format!(
+                "module {} that [{}] points to is not installed any more",
+                build_id, name
+            ))
+        })
+    }
+
+    /// Return the module a logical name points to.
+    pub fn read_alias(&self, name: &str) -> Result<Vec<u8>, WSError> {
+        let (location, info) = self.resolve_alias(name)?;
+        let module_bin = match location {
+            ModuleLocation::Slot(slot) => self.read(slot)?,
+            ModuleLocation::Golden => self.read_golden()?,
+        };
+        // The slot may have been overwritten by an installation in the meantime.
+        if !build_id(&module_bin).eq_ignore_ascii_case(&info.build_id) {
+            return Err(WSError::DigestMismatch);
+        }
+        Ok(module_bin)
+    }
+
+    /// Return the aliases pointing to modules that are not installed any more.
+    pub fn dangling_aliases(&self) -> Result<Vec<(String, String)>, WSError> {
+        let mut dangling = vec![];
+        for (name, build_id) in self.aliases()? {
+            if self.locate(&build_id)?.is_none() {
+                dangling.push((name, build_id));
+            }
+        }
+        Ok(dangling)
+    }
+}
+
+impl SlotsState {
+    /// Make the aliases of the previously active module point to the newly active one, so that
+    /// logical names follow updates, rollbacks and restorations of the active slot.
+    pub(super) fn repoint_aliases(&mut self, from: &str, to: &str) {
+        for (name, build_id) in self.aliases.iter_mut() {
+            if build_id.eq_ignore_ascii_case(from) {
+                debug!("Repointing [{}] from {} to {}", name, from, to);
+                *build_id = to.to_string();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(module_bin: &[u8], version: &str) -> SlotInfo {
+        SlotInfo {
+            build_id: build_id(module_bin),
+            version: version.parse().unwrap(),
+            signature: None,
+            security_version: 0,
+        }
+    }
+
+    #[test]
+    fn aliases_follow_the_active_module_through_power_losses() {
+        let (v1, v2) = (
+            b"\0asm\x01\0\0\0\0\x01\x01".to_vec(),
+            b"\0asm\x01\0\0\0".to_vec(),
+        );
+        for cut_point in 0.. {
+            let storage = FaultyStorage::new(MemoryStorage::new());
+            let slots = Slots::new(storage.clone());
+            slots.install(&v1, info(&v1, "1.0.0")).unwrap();
+            slots.confirm().unwrap();
+            slots.set_alias("app", &build_id(&v1)).unwrap();
+
+            storage.cut_after(cut_point);
+            let res = slots
+                .install(&v2, info(&v2, "2.0.0"))
+                .and_then(|_| slots.rollback());
+            let cut = storage.is_cut();
+            storage.restore();
+            let state = slots.state().unwrap();
+            let (_, active) = state.active_info().unwrap();
+            assert_eq!(
+                state.aliases["app"], active.build_id,
+                "cut at {}",
+                cut_point
+            );
+            if !cut {
+                res.unwrap();
+                assert_eq!(active.build_id, build_id(&v1));
+                break;
+            }
+        }
+    }
+}
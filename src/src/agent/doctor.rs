@@ -0,0 +1,368 @@
+use super::golden::{GOLDEN_INFO_NAME, GOLDEN_NAME};
+use super::sidecar::{sidecar_info_name, sidecar_name};
+use super::slots::STATE_NAME;
+use super::{
+    DownloadState, Slot, SlotInfo, Slots, Storage, DOWNLOAD_NAME, DOWNLOAD_STATE_NAME,
+    REVOCATIONS_NAME,
+};
+use crate::error::*;
+use crate::repo::build_id;
+use crate::signature::*;
+use crate::wasm_module::*;
+
+use log::*;
+use std::fmt;
+
+/// How serious a problem found by `Slots::diagnose()` is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The device works, but needs attention.
+    Warning,
+    /// The device may not run the expected module, or may not be able to roll back.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A fix for a problem found by `Slots::diagnose()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Repair {
+    /// Make a slot holding an intact module the active one, and forget the other slot.
+    Activate(Slot),
+    /// Forget the module of an inactive slot, and remove it.
+    ClearSlot(Slot),
+    /// Remove an object that doesn't belong to an installation or a download.
+    Remove(String),
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Repair::Activate(slot) => write!(f, "activate slot {}", slot),
+            Repair::ClearSlot(slot) => write!(f, "clear slot {}", slot),
+            Repair::Remove(name) => write!(f, "remove [{}]", name),
+        }
+    }
+}
+
+/// A problem found by `Slots::diagnose()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Name of the check: `state`, `slots`, `pending`, `signatures`, `golden`, `aliases` or
+    /// `inventory`.
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    /// How the problem can be fixed, if it can be fixed without a decision from an operator.
+    pub repair: Option<Repair>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{}] {}", self.severity, self.check, self.message)?;
+        if let Some(repair) = &self.repair {
+            write!(f, " (repair: {})", repair)?;
+        }
+        Ok(())
+    }
+}
+
+/// The problems found by `Slots::diagnose()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Diagnosis {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Diagnosis {
+    /// Return `true` if no errors were found. Warnings are allowed.
+    pub fn is_healthy(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .all(|diagnostic| diagnostic.severity < Severity::Error)
+    }
+
+    /// Return the repairs fixing the problems that can be fixed, in order.
+    pub fn repairs(&self) -> impl Iterator<Item = &Repair> {
+        self.diagnostics
+            .iter()
+            .filter_map(|diagnostic| diagnostic.repair.as_ref())
+    }
+
+    fn push(
+        &mut self,
+        check: &'static str,
+        severity: Severity,
+        message: String,
+        repair: Option<Repair>,
+    ) {
+        self.diagnostics.push(Diagnostic {
+            check,
+            severity,
+            message,
+            repair,
+        });
+    }
+}
+
+impl<S: Storage> Slots<S> {
+    /// Return the problem with the module stored in a slot, if any.
+    fn slot_problem(&self, slot: Slot, info: &SlotInfo) -> Result<Option<&'static str>, WSError> {
+        Ok(match self.storage().read(slot.object_name())? {
+            None => Some("missing"),
+            Some(module_bin) if build_id(&module_bin) != info.build_id => Some("corrupted"),
+            Some(_) => None,
+        })
+    }
+
+    /// Check the slots of a device.
+    ///
+    /// This checks that the state is readable, that every slot it describes holds the expected
+    /// module, that no installation is waiting for a confirmation, that the modules are signed
+    /// by one of the trusted keys if there are any, that the golden module is intact, and that
+    /// the storage holds no leftover objects. Problems are reported, not fixed: see `repair()`.
+    pub fn diagnose(
+        &self,
+        trusted_keys: &KeyRing,
+        ignored_sections: &IgnoreList,
+    ) -> Result<Diagnosis, WSError> {
+        let mut diagnosis = Diagnosis::default();
+        let state = match self.state() {
+            Ok(state) => state,
+            Err(e) => {
+                diagnosis.push(
+                    "state",
+                    Severity::Error,
+                    format!("unreadable slots state: {}", e),
+                    None,
+                );
+                return Ok(diagnosis);
+            }
+        };
+        let mut intact = vec![];
+        for slot in [Slot::A, Slot::B] {
+            let info = match state.info(slot) {
+                None => continue,
+                Some(info) => info,
+            };
+            match self.slot_problem(slot, info)? {
+                None => intact.push(slot),
+                Some(problem) => {
+                    let active = state.active == Some(slot);
+                    let repair = match active {
+                        false => Some(Repair::ClearSlot(slot)),
+                        true => state
+                            .info(slot.other())
+                            .filter(|info| {
+                                matches!(self.slot_problem(slot.other(), info), Ok(None))
+                            })
+                            .map(|_| Repair::Activate(slot.other())),
+                    };
+                    let role = if active { "active" } else { "inactive" };
+                    diagnosis.push(
+                        "slots",
+                        Severity::Error,
+                        format!(
+                            "module {} of the {} slot {} is {}",
+                            info.version, role, slot, problem
+                        ),
+                        repair,
+                    );
+                }
+            }
+        }
+        if let Some(active) = state.active {
+            if state.info(active).is_none() {
+                let repair = intact
+                    .contains(&active.other())
+                    .then_some(Repair::Activate(active.other()));
+                diagnosis.push(
+                    "slots",
+                    Severity::Error,
+                    format!("active slot {} is empty", active),
+                    repair,
+                );
+            }
+        }
+        if let Some((active, info)) = state.active_info() {
+            if state.pending {
+                diagnosis.push(
+                    "pending",
+                    Severity::Warning,
+                    format!(
+                        "the installation of {} in slot {} hasn't been confirmed",
+                        info.version, active
+                    ),
+                    None,
+                );
+            }
+        }
+        if !trusted_keys.is_empty() {
+            for &slot in &intact {
+                let info = match state.info(slot) {
+                    None => continue,
+                    Some(info) => info,
+                };
+                let signature = match &info.signature {
+                    None => {
+                        diagnosis.push(
+                            "signatures",
+                            Severity::Warning,
+                            format!(
+                                "module {} of slot {} has no recorded signature",
+                                info.version, slot
+                            ),
+                            None,
+                        );
+                        continue;
+                    }
+                    Some(signature) => signature,
+                };
+                let res = Signature::from_base64(signature).and_then(|signature| {
+                    trusted_keys.verify_module_ignoring(
+                        &self.read(slot)?,
+                        ignored_sections,
+                        &signature,
+                    )
+                });
+                if let Err(e) = res {
+                    let repair = (state.active != Some(slot)).then_some(Repair::ClearSlot(slot));
+                    diagnosis.push(
+                        "signatures",
+                        Severity::Error,
+                        format!(
+                            "module {} of slot {} has an invalid signature: {}",
+                            info.version, slot, e
+                        ),
+                        repair,
+                    );
+                }
+            }
+        }
+        if let Some(info) = self.golden()? {
+            let problem = match self.storage().read(GOLDEN_NAME)? {
+                None => Some("missing"),
+                Some(module_bin) if build_id(&module_bin) != info.build_id => Some("corrupted"),
+                Some(_) => None,
+            };
+            if let Some(problem) = problem {
+                diagnosis.push(
+                    "golden",
+                    Severity::Warning,
+                    format!("golden module {} is {}", info.version, problem),
+                    None,
+                );
+            }
+        }
+        for (name, build_id) in self.dangling_aliases()? {
+            diagnosis.push(
+                "aliases",
+                Severity::Warning,
+                format!("[{}] points to {}, which is not installed", name, build_id),
+                None,
+            );
+        }
+        let names = match self.storage().list()? {
+            None => {
+                debug!("The storage can't list its objects, skipping the inventory check");
+                return Ok(diagnosis);
+            }
+            Some(names) => names,
+        };
+        let download_state: Option<DownloadState> =
+            match self.storage().read(DOWNLOAD_STATE_NAME)? {
+                Some(json) => serde_json::from_slice(&json).ok(),
+                None => None,
+            };
+        for name in names {
+            let slot = [Slot::A, Slot::B].into_iter().find(|&slot| {
+                name == slot.object_name()
+                    || name == sidecar_name(slot)
+                    || name == sidecar_info_name(slot)
+            });
+            let (message, repair) = match slot {
+                Some(slot) if state.info(slot).is_some() => continue,
+                Some(slot) if name == slot.object_name() => (
+                    format!(
+                        "slot {} holds a module that the state doesn't describe",
+                        slot
+                    ),
+                    true,
+                ),
+                Some(slot) => (
+                    format!("[{}] belongs to the empty slot {}", name, slot),
+                    true,
+                ),
+                None if name == STATE_NAME
+                    || name == GOLDEN_INFO_NAME
+                    || name == REVOCATIONS_NAME =>
+                {
+                    continue
+                }
+                None if name == GOLDEN_NAME => {
+                    if self.golden()?.is_some() {
+                        continue;
+                    }
+                    (
+                        format!("[{}] isn't described as a golden module", name),
+                        true,
+                    )
+                }
+                None if name == DOWNLOAD_NAME || name == DOWNLOAD_STATE_NAME => {
+                    if download_state.is_some() {
+                        continue;
+                    }
+                    (format!("[{}] belongs to an unknown download", name), true)
+                }
+                None => (format!("unknown object [{}]", name), false),
+            };
+            let repair = repair.then_some(Repair::Remove(name));
+            diagnosis.push("inventory", Severity::Warning, message, repair);
+        }
+        Ok(diagnosis)
+    }
+
+    /// Apply a repair suggested by `diagnose()`.
+    pub fn repair(&self, repair: &Repair) -> Result<(), WSError> {
+        let (state, cleared) = match repair {
+            Repair::Remove(name) => return self.storage().remove(name),
+            Repair::Activate(slot) => {
+                let mut state = self.state()?;
+                if state.info(*slot).is_none() {
+                    return Err(WSError::NotFound(format!("module in slot {}", slot)));
+                }
+                state.active = Some(*slot);
+                state.pending = false;
+                state.set_info(slot.other(), None);
+                (state, slot.other())
+            }
+            Repair::ClearSlot(slot) => {
+                let mut state = self.state()?;
+                if state.active == Some(*slot) {
+                    return Err(WSError::InvariantViolation(format!(
+                        "slot {} is the active slot",
+                        slot
+                    )));
+                }
+                state.set_info(*slot, None);
+                (state, *slot)
+            }
+        };
+        self.set_state(&state)?;
+        for name in [
+            cleared.object_name(),
+            sidecar_name(cleared),
+            sidecar_info_name(cleared),
+        ] {
+            self.storage().remove(name)?;
+        }
+        info!("Repaired the slots: {}", repair);
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+use super::{Agent, Clock, Slot, Slots, Storage, Transport};
+use crate::error::*;
+use crate::repo::build_id;
+
+use log::*;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Debug, Default)]
+struct FaultState {
+    operations: u64,
+    cut_after: Option<u64>,
+    cut: bool,
+}
+
+/// A storage wrapper simulating a power loss.
+///
+/// Once the configured number of modifying operations (`write`, `append`, `rename` and `remove`)
+/// has been reached, the next one is interrupted and every later operation fails, until `restore()`
+/// is called. An interrupted `write` or `rename` has no effect, as these are atomic, while an
+/// interrupted `append` only stores the first half of its data.
+///
+/// Clones share the same fault state.
+#[derive(Debug, Clone)]
+pub struct FaultyStorage<S: Storage> {
+    inner: S,
+    state: Arc<Mutex<FaultState>>,
+}
+
+fn power_loss() -> WSError {
+    WSError::IOError(io::Error::other("simulated power loss"))
+}
+
+impl<S: Storage> FaultyStorage<S> {
+    /// Wrap a storage. No faults are injected until `cut_after()` is called.
+    pub fn new(inner: S) -> Self {
+        FaultyStorage {
+            inner,
+            state: Arc::new(Mutex::new(FaultState::default())),
+        }
+    }
+
+    /// Return the underlying storage.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn state(&self) -> MutexGuard<'_, FaultState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Interrupt the modifying operation following the next `operations` ones.
+    pub fn cut_after(&self, operations: u64) {
+        let mut state = self.state();
+        state.cut_after = Some(state.operations + operations);
+    }
+
+    /// Return `true` if the power was cut.
+    pub fn is_cut(&self) -> bool {
+        self.state().cut
+    }
+
+    /// Return the number of modifying operations performed so far.
+    pub fn operations(&self) -> u64 {
+        self.state().operations
+    }
+
+    /// Restore the power, and stop injecting faults.
+    pub fn restore(&self) {
+        let mut state = self.state();
+        state.cut = false;
+        state.cut_after = None;
+    }
+
+    fn check_power(&self) -> Result<(), WSError> {
+        match self.state().cut {
+            true => Err(power_loss()),
+            false => Ok(()),
+        }
+    }
+
+    /// Count a modifying operation, and return `false` if it must be interrupted.
+    fn modify(&self) -> Result<bool, WSError> {
+        let mut state = self.state();
+        if state.cut {
+            return Err(power_loss());
+        }
+        if state.cut_after == Some(state.operations) {
+            debug!("Cutting the power after {} operations", state.operations);
+            state.cut = true;
+            return Ok(false);
+        }
+        state.operations += 1;
+        Ok(true)
+    }
+}
+
+impl<S: Storage> Storage for FaultyStorage<S> {
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>, WSError> {
+        self.check_power()?;
+        self.inner.read(name)
+    }
+
+    fn len(&self, name: &str) -> Result<Option<u64>, WSError> {
+        self.check_power()?;
+        self.inner.len(name)
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), WSError> {
+        if !self.modify()? {
+            return Err(power_loss());
+        }
+        self.inner.write(name, data)
+    }
+
+    fn append(&self, name: &str, data: &[u8]) -> Result<(), WSError> {
+        if !self.modify()? {
+            self.inner.append(name, &data[..data.len() / 2])?;
+            return Err(power_loss());
+        }
+        self.inner.append(name, data)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), WSError> {
+        if !self.modify()? {
+            return Err(power_loss());
+        }
+        self.inner.rename(from, to)
+    }
+
+    fn remove(&self, name: &str) -> Result<(), WSError> {
+        if !self.modify()? {
+            return Err(power_loss());
+        }
+        self.inner.remove(name)
+    }
+
+    fn available_space(&self) -> Result<Option<u64>, WSError> {
+        self.check_power()?;
+        self.inner.available_space()
+    }
+    fn list(&self) -> Result<Option<Vec<String>>, WSError> {
+        self.check_power()?;
+        self.inner.list()
+    }
+}
+
+/// Check that the slots are consistent.
+///
+/// The state must be readable, the active slot must describe a module, and every slot
+/// described in the state must contain the module with that build identifier.
+pub fn check_invariants<S: Storage>(slots: &Slots<S>) -> Result<(), WSError> {
+    let violation = |message: String| Err(WSError::InvariantViolation(message));
+    let state = match slots.state() {
+        Ok(state) => state,
+        Err(e) => return violation(format!("unreadable slots state: {}", e)),
+    };
+    if let Some(active) = state.active {
+        if state.info(active).is_none() {
+            return violation(format!("active slot {} is empty", active));
+        }
+    }
+    for slot in [Slot::A, Slot::B] {
+        let info = match state.info(slot) {
+            None => continue,
+            Some(info) => info,
+        };
+        match slots.storage().read(slot.object_name())? {
+            None => return violation(format!("module of slot {} is missing", slot)),
+            Some(module_bin) if build_id(&module_bin) != info.build_id => {
+                return violation(format!("module of slot {} is corrupted", slot))
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Cut the power at every possible point of an update cycle, and check that the device always
+/// recovers.
+///
+/// `new_agent` must return a new agent in its initial state every time it is called. For every
+/// modifying storage operation of an update cycle, a new agent is created, the power is cut at
+/// that operation, then restored. The slots must then satisfy `check_invariants()`, the previously
+/// active module must still be either active or available for a rollback, and the next update
+/// cycle must succeed.
+///
+/// Return the number of points the power was cut at.
+pub fn check_power_loss<T: Transport, S: Storage, C: Clock>(
+    mut new_agent: impl FnMut() -> Result<Agent<T, FaultyStorage<S>, C>, WSError>,
+) -> Result<u64, WSError> {
+    let mut cut_point = 0;
+    loop {
+        let agent = new_agent()?;
+        let slots = agent.slots();
+        let before = slots.state()?;
+        slots.storage().cut_after(cut_point);
+        let res = agent.run_once();
+        let cut = slots.storage().is_cut();
+        slots.storage().restore();
+        if !cut {
+            res?;
+            return Ok(cut_point);
+        }
+        debug!("Power cut after {} operations: {:?}", cut_point, res);
+        check_invariants(slots)?;
+        let after = slots.state()?;
+        if let Some((active, info)) = before.active_info() {
+            if after.info(active) != Some(info) {
+                return Err(WSError::InvariantViolation(format!(
+                    "previous module lost after {} operations",
+                    cut_point
+                )));
+            }
+        }
+        agent.run_once().map_err(|e| {
+            WSError::InvariantViolation(format!(
+                "no recovery after {} operations: {}",
+                cut_point, e
+            ))
+        })?;
+        check_invariants(slots)?;
+        cut_point += 1;
+    }
+}
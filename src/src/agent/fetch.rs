@@ -0,0 +1,178 @@
+use super::Clock;
+use crate::error::*;
+
+use hmac_sha256::Hash;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Retry policy with jittered exponential backoff.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backoff {
+    /// Delay before the first retry.
+    pub initial: Duration,
+    /// Maximum delay between two attempts.
+    pub max: Duration,
+    /// Maximum number of retries after the first attempt.
+    pub max_retries: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(300),
+            max_retries: 5,
+        }
+    }
+}
+
+impl Backoff {
+    /// Return the delay to wait before the given retry (starting at 0).
+    ///
+    /// The delay doubles after every attempt, up to `max`, and half of it is randomized
+    /// using `seed`, so that devices failing at the same time don't retry in lockstep.
+    pub fn delay(&self, retry: u32, seed: &[u8]) -> Duration {
+        let base = self
+            .initial
+            .saturating_mul(1u32.checked_shl(retry.min(31)).unwrap_or(u32::MAX))
+            .min(self.max);
+        let mut h = Hash::new();
+        h.update(seed);
+        h.update(retry.to_le_bytes());
+        let r = u64::from_le_bytes(h.finalize()[..8].try_into().unwrap());
+        let half = base / 2;
+        let jitter_ms = match half.as_millis() as u64 {
+            0 => 0,
+            max_ms => r % (max_ms + 1),
+        };
+        half + Duration::from_millis(jitter_ms)
+    }
+}
+
+/// A daily time window, in UTC, during which downloads are allowed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DownloadWindow {
+    start: u32,
+    end: u32,
+}
+
+impl DownloadWindow {
+    /// Create a window from `start` to `end`, expressed as seconds since midnight UTC.
+    ///
+    /// If `end` is before `start`, the window spans midnight.
+    pub fn new(start: u32, end: u32) -> Result<Self, WSError> {
+        if start >= 86400 || end > 86400 || start == end {
+            return Err(WSError::InvalidDownloadWindow);
+        }
+        Ok(DownloadWindow { start, end })
+    }
+
+    /// Parse a window written as `HH:MM-HH:MM`.
+    pub fn parse(s: &str) -> Result<Self, WSError> {
+        let parse_time = |t: &str| -> Option<u32> {
+            let (h, m) = t.trim().split_once(':')?;
+            let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+            if h > 24 || m > 59 || (h == 24 && m != 0) {
+                return None;
+            }
+            Some(h * 3600 + m * 60)
+        };
+        let (start, end) = s.split_once('-').ok_or(WSError::InvalidDownloadWindow)?;
+        match (parse_time(start), parse_time(end)) {
+            (Some(start), Some(end)) => Self::new(start % 86400, end),
+            _ => Err(WSError::InvalidDownloadWindow),
+        }
+    }
+
+    /// Return the time to wait for the window to open, or zero if it is open.
+    pub fn wait_time(&self, now: SystemTime) -> Duration {
+        let secs = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| (d.as_secs() % 86400) as u32)
+            .unwrap_or(0);
+        let open = if self.start < self.end {
+            secs >= self.start && secs < self.end
+        } else {
+            secs >= self.start || secs < self.end
+        };
+        if open {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(((self.start + 86400 - secs) % 86400) as u64)
+        }
+    }
+}
+
+/// Settings controlling how updates are downloaded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FetchConfig {
+    /// Maximum download rate, in bytes per second.
+    pub rate_limit: Option<u64>,
+    /// Retry policy for failed downloads.
+    pub backoff: Backoff,
+    /// Windows during which downloads are allowed. Downloads are always allowed if empty.
+    pub windows: Vec<DownloadWindow>,
+}
+
+impl FetchConfig {
+    /// Return the time to wait before a download can start, or zero if it can start now.
+    pub fn wait_time(&self, now: SystemTime) -> Duration {
+        self.windows
+            .iter()
+            .map(|window| window.wait_time(now))
+            .min()
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+pub(crate) struct RateLimiter<'t, C: Clock> {
+    clock: &'t C,
+    rate: Option<u64>,
+    started: SystemTime,
+    bytes: u64,
+}
+
+impl<'t, C: Clock> RateLimiter<'t, C> {
+    pub(crate) fn new(clock: &'t C, rate: Option<u64>) -> Self {
+        RateLimiter {
+            clock,
+            rate: rate.filter(|&rate| rate > 0),
+            started: clock.now(),
+            bytes: 0,
+        }
+    }
+
+    /// Return the maximum number of bytes to read at once.
+    pub(crate) fn chunk_size(&self, max: usize) -> usize {
+        match self.rate {
+            None => max,
+            Some(rate) => max.min((rate as usize / 4).max(1)),
+        }
+    }
+
+    /// Account for `len` bytes, and wait if they were received too quickly.
+    pub(crate) fn consume(&mut self, len: usize) {
+        let rate = match self.rate {
+            None => return,
+            Some(rate) => rate,
+        };
+        self.bytes += len as u64;
+        let expected = Duration::from_secs_f64(self.bytes as f64 / rate as f64);
+        let elapsed = self
+            .clock
+            .now()
+            .duration_since(self.started)
+            .unwrap_or_default();
+        if expected > elapsed {
+            self.clock.sleep(expected - elapsed);
+        }
+    }
+}
+
+/// Return `true` if an error is likely to be temporary.
+pub(crate) fn is_retryable(error: &WSError) -> bool {
+    match error {
+        WSError::IOError(_) | WSError::Eof => true,
+        WSError::HttpError(status) => *status == 408 || *status == 429 || *status >= 500,
+        _ => false,
+    }
+}
@@ -0,0 +1,283 @@
+use super::*;
+use crate::repo::build_id;
+
+pub(super) const GOLDEN_NAME: &str = "golden.wasm";
+pub(super) const GOLDEN_INFO_NAME: &str = "golden.json";
+
+impl<S: Storage> Slots<S> {
+    /// Return the description of the golden module, if one is pinned.
+    pub fn golden(&self) -> Result<Option<SlotInfo>, WSError> {
+        match self.storage().read(GOLDEN_INFO_NAME)? {
+            None => Ok(None),
+            Some(json) => Ok(Some(serde_json::from_slice(&json)?)),
+        }
+    }
+
+    /// Return the golden module.
+    pub fn read_golden(&self) -> Result<Vec<u8>, WSError> {
+        self.storage()
+            .read(GOLDEN_NAME)?
+            .ok_or_else(|| WSError::NotFound(GOLDEN_NAME.to_string()))
+    }
+
+    /// Keep a known-good module aside, outside of the slots, replacing the previous one.
+    ///
+    /// The golden module is left alone by installations and rollbacks, so that it can always be
+    /// restored with `restore_golden()`.
+    pub fn pin_golden(&self, module_bin: &[u8], info: SlotInfo) -> Result<(), WSError> {
+        if !build_id(module_bin).eq_ignore_ascii_case(&info.build_id) {
+            return Err(WSError::DigestMismatch);
+        }
+        let storage = self.storage();
+        storage.remove(GOLDEN_INFO_NAME)?;
+        storage.write(GOLDEN_NAME, module_bin)?;
+        storage.write(GOLDEN_INFO_NAME, &serde_json::to_vec_pretty(&info)?)?;
+        Ok(())
+    }
+
+    /// Pin the module of the active slot as the golden module.
+    ///
+    /// The installation of the active slot must have been confirmed.
+    pub fn pin_active_golden(&self) -> Result<SlotInfo, WSError> {
+        let state = self.state()?;
+        let (slot, info) = state
+            .active_info()
+            .ok_or_else(|| WSError::NotFound("active slot".to_string()))?;
+        if state.pending {
+            return Err(WSError::InvariantViolation(format!(
+                "the installation of slot {} hasn't been confirmed",
+                slot
+            )));
+        }
+        self.pin_golden(&self.read(slot)?, info.clone())?;
+        Ok(info.clone())
+    }
+
+    /// Forget the golden module, and remove it.
+    pub fn unpin_golden(&self) -> Result<(), WSError> {
+        let storage = self.storage();
+        storage.remove(GOLDEN_INFO_NAME)?;
+        storage.remove(GOLDEN_NAME)
+    }
+
+    /// Check that the golden module is intact, and signed by one of the keys if there are any.
+    ///
+    /// Return its description along with the module. Callers must only pass an empty key ring if
+    /// unsigned modules are allowed.
+    pub fn verify_golden(
+        &self,
+        golden_keys: &KeyRing,
+        ignored_sections: &IgnoreList,
+    ) -> Result<(SlotInfo, Vec<u8>), WSError> {
+        let info = self
+            .golden()?
+            .ok_or_else(|| WSError::NotFound("golden module".to_string()))?;
+        let module_bin = self.read_golden()?;
+        if !build_id(&module_bin).eq_ignore_ascii_case(&info.build_id) {
+            return Err(WSError::DigestMismatch);
+        }
+        if !golden_keys.is_empty() {
+            let signature = info.signature.as_deref().ok_or(WSError::NoSignature)?;
+            golden_keys.verify_module_ignoring(
+                &module_bin,
+                ignored_sections,
+                &Signature::from_base64(signature)?,
+            )?;
+        }
+        Ok((info, module_bin))
+    }
+
+    /// Install the golden module into the inactive slot, and make it the active one.
+    ///
+    /// This is a last resort: the golden module is verified with `golden_keys`, but doesn't
+    /// depend on the other slot or on the server, and is installed as confirmed. Its security
+    /// version must not be lower than the one of the device, unless its downgrade was authorized
+    /// with one of `downgrade_keys`. The security version of the device is never lowered.
+    pub fn restore_golden(
+        &self,
+        golden_keys: &KeyRing,
+        downgrade_keys: &KeyRing,
+        ignored_sections: &IgnoreList,
+    ) -> Result<Slot, WSError> {
+        let (info, module_bin) = self.verify_golden(golden_keys, ignored_sections)?;
+        let mut state = self.state()?;
+        let module = Module::deserialize(&mut io::Cursor::new(&module_bin))?;
+        module.check_security_version(state.security_version, downgrade_keys)?;
+        // The description of the golden module is not signed, unlike the module itself.
+        let info = SlotInfo {
+            security_version: module.security_version()?,
+            ..info
+        };
+        let slot = state.active.map(Slot::other).unwrap_or(Slot::A);
+        self.storage().write(slot.object_name(), &module_bin)?;
+        state.security_version = state.security_version.max(info.security_version);
+        state.set_info(slot, Some(info));
+        state.active = Some(slot);
+        state.pending = false;
+        self.set_state(&state)?;
+        Ok(slot)
+    }
+}
+
+impl<T: Transport, S: Storage, C: Clock> Agent<T, S, C> {
+    /// Return the keys golden modules are verified with.
+    fn golden_keys(&self) -> KeyRing {
+        if self.config.golden_keys.is_empty() {
+            self.key_ring(&self.config.trusted_keys)
+        } else {
+            self.key_ring(&self.config.golden_keys)
+        }
+    }
+
+    /// Download the golden artifact offered by the server and pin it, unless it is already pinned.
+    pub(super) fn sync_golden(&self, golden: &GoldenArtifact) -> Result<(), WSError> {
+        if self
+            .slots
+            .golden()?
+            .is_some_and(|info| info.build_id.eq_ignore_ascii_case(&golden.build_id))
+        {
+            return Ok(());
+        }
+        let download = &golden.download;
+        let mut module_bin = vec![];
+        self.transport
+            .get_range(&download.url, 0, Some(download.size))?
+            .take(download.size.saturating_add(1))
+            .read_to_end(&mut module_bin)?;
+        if module_bin.len() as u64 != download.size
+            || !download.sha256.eq_ignore_ascii_case(&golden.build_id)
+            || !build_id(&module_bin).eq_ignore_ascii_case(&golden.build_id)
+        {
+            return Err(WSError::DigestMismatch);
+        }
+        let golden_keys = self.golden_keys();
+        if golden_keys.is_empty() {
+            self.check_unsigned_allowed()?;
+        } else {
+            let signature = golden.signature.as_deref().ok_or(WSError::NoSignature)?;
+            golden_keys.verify_module_ignoring(
+                &module_bin,
+                &self.config.ignored_sections,
+                &Signature::from_base64(signature)?,
+            )?;
+        }
+        let module = Module::deserialize(&mut io::Cursor::new(&module_bin))?;
+        // A replayed response could otherwise offer an older golden module as a way to downgrade.
+        module.check_security_version(
+            self.slots.state()?.security_version,
+            &self.key_ring(&self.config.downgrade_keys),
+        )?;
+        let info = SlotInfo {
+            build_id: golden.build_id.clone(),
+            version: golden.version.clone(),
+            signature: golden.signature.clone(),
+            security_version: module.security_version()?,
+        };
+        self.slots.pin_golden(&module_bin, info)?;
+        info!("Pinned {} as the golden module", golden.version);
+        Ok(())
+    }
+
+    /// Restore the golden module, and report it.
+    pub fn restore_golden(&self) -> Result<Slot, WSError> {
+        let started = self.clock.now();
+        let mut report = self.new_report(InstallStatus::RolledBack)?;
+        let res = self.restore_verified_golden();
+        match &res {
+            Ok(slot) => {
+                report.to = self.slots.state()?.info(*slot).map(|info| BuildRef {
+                    build_id: info.build_id.clone(),
+                    version: info.version.clone(),
+                });
+            }
+            Err(e) => report.set_error(e),
+        }
+        self.send_report(&mut report, started);
+        res
+    }
+
+    fn restore_verified_golden(&self) -> Result<Slot, WSError> {
+        let golden_keys = self.golden_keys();
+        if golden_keys.is_empty() {
+            self.check_unsigned_allowed()?;
+        }
+        self.slots.restore_golden(
+            &golden_keys,
+            &self.key_ring(&self.config.downgrade_keys),
+            &self.config.ignored_sections,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::{MemoryBackend, Repository};
+
+    fn golden_module(security_version: u64) -> (Vec<u8>, SlotInfo) {
+        let mut module = Module {
+            header: [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00],
+            ..Module::default()
+        };
+        module.set_security_version(security_version).unwrap();
+        let mut module_bin = vec![];
+        module.serialize(&mut module_bin).unwrap();
+        let info = SlotInfo {
+            build_id: build_id(&module_bin),
+            version: "1.0.0".parse().unwrap(),
+            signature: None,
+            security_version,
+        };
+        (module_bin, info)
+    }
+
+    #[test]
+    fn unsigned_golden_modules_must_be_allowed() {
+        let storage = MemoryStorage::new();
+        let (module_bin, info) = golden_module(0);
+        Slots::new(storage.clone())
+            .pin_golden(&module_bin, info)
+            .unwrap();
+        let mut config = AgentConfig::new("device", "stable");
+        let agent = |config: AgentConfig| {
+            Agent::new(
+                config,
+                LocalTransport::new(Repository::new(MemoryBackend::new())),
+                storage.clone(),
+                VirtualClock::default(),
+            )
+        };
+        assert!(matches!(
+            agent(config.clone()).restore_golden(),
+            Err(WSError::ConfigError(_))
+        ));
+        config.allow_unsigned = true;
+        assert_eq!(agent(config).restore_golden().unwrap(), Slot::A);
+    }
+
+    #[test]
+    fn build_ids_are_compared_ignoring_case() {
+        let slots = Slots::new(MemoryStorage::new());
+        let (module_bin, mut info) = golden_module(0);
+        info.build_id = info.build_id.to_ascii_uppercase();
+        slots.pin_golden(&module_bin, info).unwrap();
+        slots
+            .verify_golden(&KeyRing::new(), &IgnoreList::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn restores_use_the_security_version_of_the_module() {
+        let slots = Slots::new(MemoryStorage::new());
+        let (module_bin, mut info) = golden_module(5);
+        // A description claiming a higher security version would lock out every later update.
+        info.security_version = 100;
+        slots.pin_golden(&module_bin, info).unwrap();
+        let slot = slots
+            .restore_golden(&KeyRing::new(), &KeyRing::new(), &IgnoreList::default())
+            .unwrap();
+        let state = slots.state().unwrap();
+        assert_eq!(state.security_version, 5);
+        assert_eq!(state.info(slot).unwrap().security_version, 5);
+    }
+}
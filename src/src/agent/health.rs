@@ -0,0 +1,57 @@
+use super::*;
+use crate::health::HealthCheck;
+
+/// Runs the health check of the active module, in the host runtime.
+pub trait HealthProbe {
+    /// Call the export named by `health_check`, and return an error if the module is unhealthy.
+    ///
+    /// Checks taking longer than the timeout of the contract are considered failed.
+    fn probe(&self, health_check: &HealthCheck) -> Result<(), WSError>;
+}
+
+/// The result of `Agent::check_health()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthOutcome {
+    /// No installation is waiting for confirmation.
+    NotPending,
+    /// The active module doesn't declare a health check, so it was left unconfirmed.
+    Undeclared,
+    /// The health check passed, and the installation was confirmed.
+    Confirmed,
+    /// The health check failed, and the previous module was restored into the given slot.
+    RolledBack { slot: Slot, reason: String },
+}
+
+impl<T: Transport, S: Storage, C: Clock> Agent<T, S, C> {
+    /// Confirm or roll back a pending installation, according to the health check declared by the module.
+    ///
+    /// Modules without a health-check contract are left unconfirmed, for the host to decide.
+    pub fn check_health(&self, probe: &impl HealthProbe) -> Result<HealthOutcome, WSError> {
+        if !self.slots.state()?.pending {
+            return Ok(HealthOutcome::NotPending);
+        }
+        let module = Module::deserialize(&mut io::Cursor::new(self.slots.read_active()?))?;
+        let health_check = match module.health_check()? {
+            None => return Ok(HealthOutcome::Undeclared),
+            Some(health_check) => health_check,
+        };
+        let started = self.clock.now();
+        let res = probe.probe(&health_check);
+        let elapsed = self.clock.now().duration_since(started).unwrap_or_default();
+        let reason = match res {
+            Ok(()) if elapsed <= health_check.timeout => {
+                self.slots.confirm()?;
+                info!("Health check [{}] passed", health_check.export);
+                return Ok(HealthOutcome::Confirmed);
+            }
+            Ok(()) => format!("timed out after {:?}", elapsed),
+            Err(e) => e.to_string(),
+        };
+        warn!(
+            "Health check [{}] failed: {}, rolling back",
+            health_check.export, reason
+        );
+        let slot = self.rollback()?;
+        Ok(HealthOutcome::RolledBack { slot, reason })
+    }
+}
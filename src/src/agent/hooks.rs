@@ -0,0 +1,35 @@
+use super::*;
+use crate::hooks::{Hook, HookStage, Hooks};
+
+/// Runs the install hooks of updates, in the host.
+pub trait HookRunner {
+    /// Run a hook, and return an error if it failed.
+    ///
+    /// Hooks are only passed to the runner if they are allowed by the `hook_policy` of the agent.
+    fn run(&self, hook: &Hook) -> Result<(), WSError>;
+}
+
+impl<T: Transport, S: Storage, C: Clock> Agent<T, S, C> {
+    /// Set the runner of the install hooks carried by updates.
+    ///
+    /// Without a runner, updates with hooks are rejected.
+    pub fn set_hook_runner(&mut self, hook_runner: impl HookRunner + 'static) {
+        self.hook_runner = Some(Box::new(hook_runner));
+    }
+
+    /// Run the hooks of a given stage, stopping at the first failure.
+    pub(super) fn run_hooks(&self, hooks: &Hooks, stage: HookStage) -> Result<(), WSError> {
+        for hook in hooks.stage(stage) {
+            let hook_runner = self
+                .hook_runner
+                .as_ref()
+                .ok_or_else(|| WSError::HookRejected("no hook runner".to_string()))?;
+            debug!("Running {:?} hook [{}]", stage, hook.name);
+            if let Err(e) = hook_runner.run(hook) {
+                warn!("{:?} hook [{}] failed: {}", stage, hook.name, e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
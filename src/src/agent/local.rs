@@ -0,0 +1,67 @@
+use super::{ObjectMetadata, Transport};
+use crate::error::*;
+use crate::protocol::*;
+use crate::repo::*;
+
+use std::io::{self, prelude::*};
+
+/// A transport reading updates directly from a repository.
+///
+/// With an `FsBackend`, this allows updating devices from a repository copied to local
+/// or removable storage, without any network access. URLs are the ones returned by
+/// `Repository::check_update`.
+#[derive(Debug, Clone)]
+pub struct LocalTransport<B: Backend> {
+    repo: Repository<B>,
+}
+
+impl<B: Backend> LocalTransport<B> {
+    /// Create a transport for the given repository.
+    pub fn new(repo: Repository<B>) -> Self {
+        LocalTransport { repo }
+    }
+
+    /// Return the repository.
+    pub fn repository(&self) -> &Repository<B> {
+        &self.repo
+    }
+
+    fn object(&self, url: &str) -> Result<Vec<u8>, WSError> {
+        let key = url.trim_start_matches('/');
+        if !(key.starts_with("artifacts/") || key.starts_with("patches/")) {
+            return Err(WSError::UnsupportedUrl(url.to_string()));
+        }
+        self.repo
+            .backend()
+            .get(key)?
+            .ok_or_else(|| WSError::NotFound(url.to_string()))
+    }
+}
+
+impl<B: Backend> Transport for LocalTransport<B> {
+    fn check(&self, request: &UpdateCheckRequest) -> Result<UpdateCheckResponse, WSError> {
+        self.repo.check_update(request)
+    }
+
+    fn get_range(
+        &self,
+        url: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read>, WSError> {
+        let data = self.object(url)?;
+        let start = (offset as usize).min(data.len());
+        let end = match len {
+            Some(len) => start.saturating_add(len as usize).min(data.len()),
+            None => data.len(),
+        };
+        let mut reader = io::Cursor::new(data);
+        reader.set_position(start as u64);
+        Ok(Box::new(reader.take((end - start) as u64)))
+    }
+
+    fn get_metadata(&self, url: &str) -> Result<ObjectMetadata, WSError> {
+        let size = self.object(url)?.len() as u64;
+        Ok(ObjectMetadata { size: Some(size) })
+    }
+}
@@ -0,0 +1,505 @@
+//! Minimal multicast DNS (RFC 6762) and DNS-SD (RFC 6763) support, over IPv4.
+
+use crate::error::*;
+
+use log::*;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Name of the DNS-SD service announced by peers.
+pub(crate) const SERVICE_NAME: &str = "_wasi-update._tcp.local";
+
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const MAX_PACKET_SIZE: usize = 9000;
+const MAX_LABEL_SIZE: usize = 63;
+const TTL: u32 = 120;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CACHE_FLUSH: u16 = 0x8000;
+const FLAGS_RESPONSE: u16 = 0x8400;
+
+/// A service instance found on the local network.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ServiceInstance {
+    pub(crate) name: String,
+    pub(crate) addr: Option<SocketAddr>,
+    pub(crate) txt: Vec<String>,
+}
+
+/// A service instance announced by `Responder`.
+#[derive(Debug, Clone)]
+pub(crate) struct Announcement {
+    pub(crate) instance: String,
+    pub(crate) port: u16,
+    pub(crate) txt: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum RecordData {
+    A(Ipv4Addr),
+    Ptr(String),
+    Srv { port: u16, target: String },
+    Txt(Vec<String>),
+    Other,
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+    name: String,
+    data: RecordData,
+}
+
+/// Turn an arbitrary string into a single DNS label.
+pub(crate) fn label(s: &str) -> String {
+    let mut label = String::new();
+    for c in s.chars() {
+        let c = match c {
+            '.' | '\0'..=' ' => '-',
+            c => c,
+        };
+        if label.len() + c.len_utf8() > MAX_LABEL_SIZE {
+            break;
+        }
+        label.push(c);
+    }
+    label
+}
+
+fn push_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn push_name(buf: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(MAX_LABEL_SIZE)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+fn push_header(buf: &mut Vec<u8>, id: u16, flags: u16, counts: [u16; 4]) {
+    push_u16(buf, id);
+    push_u16(buf, flags);
+    for count in counts {
+        push_u16(buf, count);
+    }
+}
+
+fn push_record(buf: &mut Vec<u8>, name: &str, class: u16, data: &RecordData) {
+    let mut rdata = vec![];
+    let rtype = match data {
+        RecordData::A(ip) => {
+            rdata.extend_from_slice(&ip.octets());
+            TYPE_A
+        }
+        RecordData::Ptr(target) => {
+            push_name(&mut rdata, target);
+            TYPE_PTR
+        }
+        RecordData::Srv { port, target } => {
+            push_u16(&mut rdata, 0);
+            push_u16(&mut rdata, 0);
+            push_u16(&mut rdata, *port);
+            push_name(&mut rdata, target);
+            TYPE_SRV
+        }
+        RecordData::Txt(strings) => {
+            for s in strings {
+                let s = &s.as_bytes()[..s.len().min(255)];
+                rdata.push(s.len() as u8);
+                rdata.extend_from_slice(s);
+            }
+            if strings.is_empty() {
+                rdata.push(0);
+            }
+            TYPE_TXT
+        }
+        RecordData::Other => return,
+    };
+    push_name(buf, name);
+    push_u16(buf, rtype);
+    push_u16(buf, class);
+    buf.extend_from_slice(&TTL.to_be_bytes());
+    push_u16(buf, rdata.len() as u16);
+    buf.extend_from_slice(&rdata);
+}
+
+struct Reader<'t> {
+    packet: &'t [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Result<u8, WSError> {
+        let v = *self.packet.get(self.pos).ok_or(WSError::ParseError)?;
+        self.pos += 1;
+        Ok(v)
+    }
+
+    fn u16(&mut self) -> Result<u16, WSError> {
+        Ok(((self.u8()? as u16) << 8) | self.u8()? as u16)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&[u8], WSError> {
+        let end = self.pos.checked_add(len).ok_or(WSError::ParseError)?;
+        let bytes = self.packet.get(self.pos..end).ok_or(WSError::ParseError)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn name(&mut self) -> Result<String, WSError> {
+        let mut labels: Vec<String> = vec![];
+        let mut pos = self.pos;
+        let mut jumped = false;
+        for _ in 0..128 {
+            let len = *self.packet.get(pos).ok_or(WSError::ParseError)? as usize;
+            match len {
+                0 => {
+                    if !jumped {
+                        self.pos = pos + 1;
+                    }
+                    return Ok(labels.join("."));
+                }
+                0xc0..=0xff => {
+                    let low = *self.packet.get(pos + 1).ok_or(WSError::ParseError)? as usize;
+                    if !jumped {
+                        self.pos = pos + 2;
+                    }
+                    jumped = true;
+                    pos = ((len & 0x3f) << 8) | low;
+                }
+                1..=0x3f => {
+                    let label = self
+                        .packet
+                        .get(pos + 1..pos + 1 + len)
+                        .ok_or(WSError::ParseError)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+                _ => return Err(WSError::ParseError),
+            }
+        }
+        Err(WSError::ParseError)
+    }
+
+    fn record(&mut self) -> Result<Record, WSError> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let _class = self.u16()?;
+        self.bytes(4)?;
+        let len = self.u16()? as usize;
+        let end = self.pos + len;
+        let data = match rtype {
+            TYPE_A if len == 4 => {
+                let ip = self.bytes(4)?;
+                RecordData::A(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))
+            }
+            TYPE_PTR => RecordData::Ptr(self.name()?),
+            TYPE_SRV => {
+                self.bytes(4)?;
+                let port = self.u16()?;
+                RecordData::Srv {
+                    port,
+                    target: self.name()?,
+                }
+            }
+            TYPE_TXT => {
+                let mut strings = vec![];
+                while self.pos < end {
+                    let len = self.u8()? as usize;
+                    let s = self.bytes(len)?;
+                    if !s.is_empty() {
+                        strings.push(String::from_utf8_lossy(s).into_owned());
+                    }
+                }
+                RecordData::Txt(strings)
+            }
+            _ => RecordData::Other,
+        };
+        if self.pos > end || end > self.packet.len() {
+            return Err(WSError::ParseError);
+        }
+        self.pos = end;
+        Ok(Record { name, data })
+    }
+}
+
+struct Message {
+    id: u16,
+    response: bool,
+    questions: Vec<(String, u16)>,
+    records: Vec<Record>,
+}
+
+fn parse(packet: &[u8]) -> Result<Message, WSError> {
+    let mut reader = Reader { packet, pos: 0 };
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let qdcount = reader.u16()?;
+    let counts = [reader.u16()?, reader.u16()?, reader.u16()?];
+    let mut questions = vec![];
+    for _ in 0..qdcount {
+        let name = reader.name()?;
+        let qtype = reader.u16()?;
+        reader.u16()?;
+        questions.push((name, qtype));
+    }
+    let mut records = vec![];
+    for _ in 0..counts.iter().map(|&count| count as usize).sum::<usize>() {
+        records.push(reader.record()?);
+    }
+    Ok(Message {
+        id,
+        response: flags & 0x8000 != 0,
+        questions,
+        records,
+    })
+}
+
+/// Return the address of the local interface used to reach `peer`.
+fn local_ip(peer: SocketAddr) -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(peer).ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+        _ => None,
+    }
+}
+
+#[cfg(unix)]
+fn bind_shared(port: u16) -> Result<UdpSocket, WSError> {
+    use std::os::unix::io::FromRawFd;
+
+    // Other mDNS responders may already be listening on the same port.
+    unsafe {
+        let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let socket = UdpSocket::from_raw_fd(fd);
+        let one: libc::c_int = 1;
+        for option in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                &one as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&one) as libc::socklen_t,
+            );
+        }
+        let mut sin: libc::sockaddr_in = std::mem::zeroed();
+        sin.sin_family = libc::AF_INET as _;
+        sin.sin_port = port.to_be();
+        if libc::bind(
+            fd,
+            &sin as *const _ as *const libc::sockaddr,
+            std::mem::size_of_val(&sin) as libc::socklen_t,
+        ) != 0
+        {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(socket)
+    }
+}
+
+#[cfg(not(unix))]
+fn bind_shared(port: u16) -> Result<UdpSocket, WSError> {
+    Ok(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?)
+}
+
+/// Answers queries for a service instance, on the mDNS port.
+pub(crate) struct Responder {
+    socket: UdpSocket,
+    host: String,
+}
+
+impl Responder {
+    pub(crate) fn bind() -> Result<Self, WSError> {
+        let socket = bind_shared(MDNS_PORT)?;
+        socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        let host = format!("wasi-update-{:x}.local", query_id());
+        Ok(Responder { socket, host })
+    }
+
+    /// Answer queries forever, calling `announcement` to get the description of the instance.
+    pub(crate) fn run(&self, announcement: impl Fn() -> Option<Announcement>) -> ! {
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            let (len, src) = match self.socket.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("mDNS receive error: {}", e);
+                    std::thread::sleep(Duration::from_secs(1));
+                    continue;
+                }
+            };
+            let query = match parse(&buf[..len]) {
+                Ok(query) if !query.response => query,
+                _ => continue,
+            };
+            let Some(announcement) = announcement() else {
+                continue;
+            };
+            if let Err(e) = self.answer(&query, &announcement, src) {
+                debug!("Unable to answer mDNS query from {}: {}", src, e);
+            }
+        }
+    }
+
+    fn answer(
+        &self,
+        query: &Message,
+        announcement: &Announcement,
+        src: SocketAddr,
+    ) -> Result<(), WSError> {
+        let instance_name = format!("{}.{}", announcement.instance, SERVICE_NAME);
+        let asked = query.questions.iter().any(|(name, qtype)| {
+            (name.eq_ignore_ascii_case(SERVICE_NAME) && matches!(*qtype, TYPE_PTR | TYPE_ANY))
+                || (name.eq_ignore_ascii_case(&instance_name)
+                    && matches!(*qtype, TYPE_SRV | TYPE_TXT | TYPE_ANY))
+        });
+        if !asked {
+            return Ok(());
+        }
+        let mut records = vec![
+            (
+                SERVICE_NAME.to_string(),
+                CLASS_IN,
+                RecordData::Ptr(instance_name.clone()),
+            ),
+            (
+                instance_name.clone(),
+                CLASS_IN | CACHE_FLUSH,
+                RecordData::Srv {
+                    port: announcement.port,
+                    target: self.host.clone(),
+                },
+            ),
+            (
+                instance_name,
+                CLASS_IN | CACHE_FLUSH,
+                RecordData::Txt(announcement.txt.clone()),
+            ),
+        ];
+        if let Some(ip) = local_ip(src) {
+            records.push((self.host.clone(), CLASS_IN | CACHE_FLUSH, RecordData::A(ip)));
+        }
+        // Queries that are not sent from the mDNS port expect a unicast answer echoing the question.
+        let legacy = src.port() != MDNS_PORT;
+        let mut packet = vec![];
+        let questions = if legacy { query.questions.len() } else { 0 };
+        push_header(
+            &mut packet,
+            if legacy { query.id } else { 0 },
+            FLAGS_RESPONSE,
+            [questions as u16, records.len() as u16, 0, 0],
+        );
+        for (name, qtype) in query.questions.iter().take(questions) {
+            push_name(&mut packet, name);
+            push_u16(&mut packet, *qtype);
+            push_u16(&mut packet, CLASS_IN);
+        }
+        for (name, class, data) in &records {
+            // The cache-flush bit must not be set in legacy unicast answers.
+            let class = if legacy { CLASS_IN } else { *class };
+            push_record(&mut packet, name, class, data);
+        }
+        let dst = match legacy {
+            true => src,
+            false => SocketAddr::V4(SocketAddrV4::new(MDNS_GROUP, MDNS_PORT)),
+        };
+        self.socket.send_to(&packet, dst)?;
+        Ok(())
+    }
+}
+
+fn query_id() -> u16 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    (nanos ^ std::process::id()) as u16 | 1
+}
+
+/// Find the instances of the service on the local network, waiting for answers for `timeout`.
+pub(crate) fn browse(timeout: Duration) -> Result<Vec<ServiceInstance>, WSError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let id = query_id();
+    let mut query = vec![];
+    push_header(&mut query, id, 0, [1, 0, 0, 0]);
+    push_name(&mut query, SERVICE_NAME);
+    push_u16(&mut query, TYPE_PTR);
+    push_u16(&mut query, CLASS_IN);
+    socket.send_to(&query, (MDNS_GROUP, MDNS_PORT))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut records = vec![];
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match parse(&buf[..len]) {
+            Ok(message) if message.response && message.id == id => {
+                records.extend(message.records.into_iter().map(|record| (src, record)))
+            }
+            _ => continue,
+        }
+    }
+
+    let mut instances: Vec<ServiceInstance> = vec![];
+    for (_, record) in &records {
+        if let RecordData::Ptr(instance) = &record.data {
+            if record.name.eq_ignore_ascii_case(SERVICE_NAME)
+                && !instances.iter().any(|known| &known.name == instance)
+            {
+                instances.push(ServiceInstance {
+                    name: instance.clone(),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    for instance in &mut instances {
+        for (src, record) in records.iter().filter(|(_, r)| r.name == instance.name) {
+            match &record.data {
+                RecordData::Srv { port, target } => {
+                    let ip = records
+                        .iter()
+                        .find_map(|(_, r)| match &r.data {
+                            RecordData::A(ip) if &r.name == target => Some(IpAddr::V4(*ip)),
+                            _ => None,
+                        })
+                        .unwrap_or(src.ip());
+                    instance.addr = Some(SocketAddr::new(ip, *port));
+                }
+                RecordData::Txt(txt) => instance.txt.clone_from(txt),
+                _ => {}
+            }
+        }
+    }
+    instances.retain(|instance| instance.addr.is_some());
+    debug!("Found {} peers", instances.len());
+    Ok(instances)
+}
@@ -0,0 +1,176 @@
+use super::{Clock, Storage};
+use crate::error::*;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Storage keeping objects in memory.
+///
+/// Clones share the same objects, so that a test can inspect the storage of an agent.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    capacity: Option<u64>,
+}
+
+impl MemoryStorage {
+    /// Create an empty storage, with unknown available space.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty storage holding at most `capacity` bytes.
+    ///
+    /// Writes exceeding the capacity fail, and the remaining space is reported to the agent.
+    pub fn with_capacity(capacity: u64) -> Self {
+        MemoryStorage {
+            capacity: Some(capacity),
+            ..Self::default()
+        }
+    }
+
+    /// Return the names of the stored objects.
+    pub fn names(&self) -> Vec<String> {
+        self.objects().keys().cloned().collect()
+    }
+
+    fn objects(&self) -> MutexGuard<'_, BTreeMap<String, Vec<u8>>> {
+        self.objects.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn check_space(
+        &self,
+        objects: &BTreeMap<String, Vec<u8>>,
+        removed: u64,
+        added: u64,
+    ) -> Result<(), WSError> {
+        let capacity = match self.capacity {
+            None => return Ok(()),
+            Some(capacity) => capacity,
+        };
+        let used = objects.values().map(|data| data.len() as u64).sum::<u64>() - removed;
+        let available = capacity.saturating_sub(used);
+        if added > available {
+            return Err(WSError::InsufficientSpace {
+                required: added,
+                available,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), WSError> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(WSError::InvalidKey(name.to_string()));
+    }
+    Ok(())
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>, WSError> {
+        validate_name(name)?;
+        Ok(self.objects().get(name).cloned())
+    }
+
+    fn len(&self, name: &str) -> Result<Option<u64>, WSError> {
+        validate_name(name)?;
+        Ok(self.objects().get(name).map(|data| data.len() as u64))
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), WSError> {
+        validate_name(name)?;
+        let mut objects = self.objects();
+        let previous_len = objects.get(name).map_or(0, |data| data.len() as u64);
+        self.check_space(&objects, previous_len, data.len() as u64)?;
+        objects.insert(name.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn append(&self, name: &str, data: &[u8]) -> Result<(), WSError> {
+        validate_name(name)?;
+        let mut objects = self.objects();
+        self.check_space(&objects, 0, data.len() as u64)?;
+        objects
+            .entry(name.to_string())
+            .or_default()
+            .extend_from_slice(data);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), WSError> {
+        validate_name(from)?;
+        validate_name(to)?;
+        let mut objects = self.objects();
+        let data = objects
+            .remove(from)
+            .ok_or_else(|| WSError::NotFound(from.to_string()))?;
+        objects.insert(to.to_string(), data);
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<(), WSError> {
+        validate_name(name)?;
+        self.objects().remove(name);
+        Ok(())
+    }
+
+    fn available_space(&self) -> Result<Option<u64>, WSError> {
+        let objects = self.objects();
+        Ok(self.capacity.map(|capacity| {
+            capacity.saturating_sub(objects.values().map(|data| data.len() as u64).sum())
+        }))
+    }
+    fn list(&self) -> Result<Option<Vec<String>>, WSError> {
+        Ok(Some(self.names()))
+    }
+}
+
+/// A clock that only moves forward when told to, or when slept on.
+///
+/// Sleeping returns immediately after advancing the time, so that retries, backoff and update
+/// intervals can be tested without waiting. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new(UNIX_EPOCH)
+    }
+}
+
+impl VirtualClock {
+    /// Create a clock starting at the given time.
+    pub fn new(start: SystemTime) -> Self {
+        VirtualClock {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    fn now_mut(&self) -> MutexGuard<'_, SystemTime> {
+        self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Move the clock forward.
+    pub fn advance(&self, duration: Duration) {
+        *self.now_mut() += duration;
+    }
+
+    /// Set the current time.
+    pub fn set(&self, now: SystemTime) {
+        *self.now_mut() = now;
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        *self.now_mut()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
@@ -0,0 +1,1244 @@
+mod aliases;
+mod doctor;
+mod faults;
+mod fetch;
+mod golden;
+mod health;
+mod hooks;
+mod http;
+mod local;
+#[cfg(feature = "peer")]
+mod mdns;
+mod memory;
+mod oneshot;
+mod patches;
+#[cfg(feature = "peer")]
+mod peer;
+mod sidecar;
+mod slots;
+mod state;
+mod storage;
+mod verify;
+
+pub use aliases::ModuleLocation;
+pub use doctor::{Diagnosis, Diagnostic, Repair, Severity};
+pub use faults::{check_invariants, check_power_loss, FaultyStorage};
+pub use fetch::{Backoff, DownloadWindow, FetchConfig};
+pub use health::{HealthOutcome, HealthProbe};
+pub use hooks::HookRunner;
+#[cfg(any(feature = "oci", feature = "s3"))]
+pub(crate) use http::HttpResponse;
+pub use http::HttpTransport;
+pub use local::LocalTransport;
+pub use memory::{MemoryStorage, VirtualClock};
+pub use oneshot::{OneShotTransport, ONESHOT_URL};
+#[cfg(feature = "peer")]
+pub use peer::{discover_peers, Peer, PeerServer, PeerTransport, PEER_PATH_PREFIX};
+pub use sidecar::Precompiler;
+pub use slots::*;
+pub use state::StateManager;
+pub use storage::*;
+pub use verify::{StreamVerifier, VerifierState};
+
+use crate::codec::{Codec, Codecs};
+use crate::config_schema::HostConfig;
+use crate::error::*;
+use crate::hooks::{HookPolicy, HookStage, Hooks, HOOKS_SECTION_NAME};
+use crate::instrument::Phase;
+use crate::metrics::{self, Metrics, NoopMetrics};
+use crate::migrations::Migrations;
+use crate::protocol::*;
+use crate::report::*;
+use crate::revocation::SignedRevocationList;
+use crate::security_version::SECURITY_VERSION_SECTION_NAME;
+use crate::signature::*;
+use crate::wasm_module::*;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::io::{self, prelude::*};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DOWNLOAD_NAME: &str = "download.part";
+const DOWNLOAD_STATE_NAME: &str = "download.json";
+const REVOCATIONS_NAME: &str = "revocations.bin";
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+const DOWNLOAD_CHECKPOINT_INTERVAL: u64 = 1024 * 1024;
+
+/// Metadata about an object available through a transport.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObjectMetadata {
+    /// Size of the object, in bytes, if known.
+    pub size: Option<u64>,
+}
+
+/// A way to reach an update server.
+///
+/// Downloads only go through this trait, so that updates can be delivered over any
+/// channel while the agent keeps handling resumption, verification and installation.
+pub trait Transport {
+    /// Send an update check to the server.
+    fn check(&self, request: &UpdateCheckRequest) -> Result<UpdateCheckResponse, WSError>;
+
+    /// Download part of an object, starting at `offset`.
+    ///
+    /// If `len` is `None`, the object is read until the end.
+    fn get_range(&self, url: &str, offset: u64, len: Option<u64>)
+        -> Result<Box<dyn Read>, WSError>;
+
+    /// Return metadata about an object.
+    fn get_metadata(&self, url: &str) -> Result<ObjectMetadata, WSError>;
+}
+
+/// A source of time.
+pub trait Clock {
+    /// Return the current time.
+    fn now(&self) -> SystemTime;
+
+    /// Wait for the given duration.
+    fn sleep(&self, duration: Duration);
+}
+
+/// The system clock.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+/// Configuration of an update agent.
+#[derive(Debug, Clone)]
+pub struct AgentConfig {
+    /// Unique identifier of the device.
+    pub device_id: String,
+    /// Channel to follow.
+    pub channel: String,
+    /// Time between two update checks.
+    pub interval: Duration,
+    /// Runtime reported to the server.
+    pub runtime: Option<RuntimeProfile>,
+    /// Download settings.
+    pub fetch: FetchConfig,
+    /// Move downloads into the inactive slot instead of copying them.
+    ///
+    /// The rollback module is never removed to make room: if the update still doesn't fit, the
+    /// download fails with `InsufficientSpace` before anything is written.
+    pub low_space: bool,
+    /// Keys allowed to sign updates.
+    ///
+    /// If empty, updates are refused, unless `allow_unsigned` is set.
+    pub trusted_keys: KeyRing,
+    /// Install updates without verifying their signature when no trusted keys are configured.
+    ///
+    /// This is meant for development only: anyone able to serve updates to the device can then
+    /// run code on it.
+    pub allow_unsigned: bool,
+    /// Keys allowed to authorize updates with a lower security version than the installed module.
+    ///
+    /// If empty, such updates are always rejected.
+    pub downgrade_keys: KeyRing,
+    /// Keys allowed to sign golden modules. If empty, `trusted_keys` are used.
+    pub golden_keys: KeyRing,
+    /// Send a fresh nonce with every update check, and reject responses that don't echo it.
+    ///
+    /// This prevents an older response from being replayed to force a downgrade, if responses are
+    /// signed (see `HttpTransport::manifest_keys()`).
+    pub require_nonce: bool,
+    /// Root key signing revocation lists.
+    ///
+    /// If set, revocation lists embedded in the installed module and in updates are honored.
+    pub revocation_root: Option<PublicKey>,
+    /// Custom sections left out of signature verification.
+    ///
+    /// Updates must have been signed with the same list.
+    pub ignored_sections: IgnoreList,
+    /// Codecs modules can be downloaded with, most preferred first.
+    pub codecs: Codecs,
+    /// Configuration provided to modules by the host.
+    ///
+    /// If set, updates whose configuration schema it doesn't satisfy are not installed.
+    pub host_config: Option<HostConfig>,
+    /// Install hooks updates are allowed to run.
+    pub hook_policy: HookPolicy,
+    /// Data migrations to run after installing an update, before post-install hooks.
+    pub migrations: Migrations,
+    /// Key signing the precompiled artifacts stored next to the slots.
+    ///
+    /// If set, artifacts that were not signed with it are compiled again instead of being used.
+    pub sidecar_key: Option<SecretKey>,
+}
+
+impl AgentConfig {
+    /// Create a configuration with default settings.
+    pub fn new(device_id: impl Into<String>, channel: impl Into<String>) -> Self {
+        AgentConfig {
+            device_id: device_id.into(),
+            channel: channel.into(),
+            interval: Duration::from_secs(3600),
+            runtime: None,
+            fetch: FetchConfig::default(),
+            low_space: false,
+            trusted_keys: KeyRing::new(),
+            allow_unsigned: false,
+            downgrade_keys: KeyRing::new(),
+            golden_keys: KeyRing::new(),
+            require_nonce: false,
+            revocation_root: None,
+            ignored_sections: IgnoreList::new(),
+            codecs: Codecs::builtin(),
+            host_config: None,
+            hook_policy: HookPolicy::new(),
+            migrations: Migrations::new(),
+            sidecar_key: None,
+        }
+    }
+}
+
+/// The result of an update cycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// No update is available.
+    UpToDate,
+    /// The previous installation hasn't been confirmed yet, so no update was attempted.
+    PendingConfirmation,
+    /// An update is available, but downloads are not allowed before the given delay.
+    Deferred { wait: Duration },
+    /// An update was installed into the given slot.
+    Installed { slot: Slot, info: SlotInfo },
+}
+
+/// What installing an update requires, besides the module.
+struct UpdateRequirements {
+    hooks: Hooks,
+    state_format: Option<u32>,
+    security_version: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct DownloadState {
+    build_id: String,
+    url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verifier: Option<VerifierState>,
+}
+
+/// An update agent.
+///
+/// Each cycle checks for an update, downloads it (resuming a previously interrupted
+/// download if possible), verifies its size, digest, signature and structure, and installs it
+/// into the inactive slot. A report is sent after every cycle.
+pub struct Agent<T: Transport, S: Storage, C: Clock> {
+    config: AgentConfig,
+    transport: T,
+    slots: Slots<S>,
+    clock: C,
+    reporter: Box<dyn Reporter>,
+    metrics: Box<dyn Metrics>,
+    hook_runner: Option<Box<dyn HookRunner>>,
+    state_manager: Option<Box<dyn StateManager>>,
+    precompiler: Option<Box<dyn Precompiler>>,
+}
+
+impl<T: Transport, S: Storage, C: Clock> Agent<T, S, C> {
+    /// Create a new agent.
+    pub fn new(config: AgentConfig, transport: T, storage: S, clock: C) -> Self {
+        Agent {
+            config,
+            transport,
+            slots: Slots::new(storage),
+            clock,
+            reporter: Box::new(NoopReporter),
+            metrics: Box::new(NoopMetrics),
+            hook_runner: None,
+            state_manager: None,
+            precompiler: None,
+        }
+    }
+
+    /// Set the reporter receiving the result of each update cycle.
+    pub fn set_reporter(&mut self, reporter: impl Reporter + 'static) {
+        self.reporter = Box::new(reporter);
+    }
+
+    /// Set the destination of the metrics collected while downloading, verifying and installing updates.
+    pub fn set_metrics(&mut self, metrics: impl Metrics + 'static) {
+        self.metrics = Box::new(metrics);
+    }
+
+    /// Return the configuration of the agent.
+    pub fn config(&self) -> &AgentConfig {
+        &self.config
+    }
+
+    /// Return the slots managed by the agent.
+    pub fn slots(&self) -> &Slots<S> {
+        &self.slots
+    }
+
+    /// Ask the server for an update.
+    pub fn check(&self) -> Result<Option<UpdateDescriptor>, WSError> {
+        Ok(self.check_response()?.update)
+    }
+
+    fn check_response(&self) -> Result<UpdateCheckResponse, WSError> {
+        let state = self.slots.state()?;
+        let installed = state.active_info().map(|(_, info)| info.clone());
+        let available_build_ids = state
+            .active
+            .and_then(|active| state.info(active.other()))
+            .map(|info| info.build_id.clone())
+            .into_iter()
+            .collect();
+        let request = UpdateCheckRequest {
+            device_id: self.config.device_id.clone(),
+            channel: self.config.channel.clone(),
+            installed_build_id: installed.as_ref().map(|info| info.build_id.clone()),
+            installed_version: installed.map(|info| info.version),
+            available_build_ids,
+            runtime: self.config.runtime.clone(),
+            accept_encodings: self.config.codecs.names(),
+            nonce: self.config.require_nonce.then(generate_nonce),
+        };
+        let response = self.transport.check(&request)?;
+        if let Some(nonce) = &request.nonce {
+            response.check_nonce(nonce)?;
+        }
+        Ok(response)
+    }
+
+    /// Download and verify an update.
+    pub fn download(&self, update: &UpdateDescriptor) -> Result<Vec<u8>, WSError> {
+        self.download_counted(update, &mut 0)
+    }
+
+    /// Return what to download to get an update: the full module, encoded if a known codec was used.
+    fn selected_download<'t>(
+        &self,
+        update: &'t UpdateDescriptor,
+    ) -> (&'t Download, Option<Arc<dyn Codec>>) {
+        if let Some(encoded) = &update.encoded {
+            match self.config.codecs.get(&encoded.encoding) {
+                Ok(codec) => return (&encoded.download, Some(codec)),
+                Err(e) => debug!("Downloading the module as-is: {}", e),
+            }
+        }
+        (&update.full, None)
+    }
+
+    /// Download an object of an update, resuming an interrupted download of the same object.
+    ///
+    /// Return the object, along with the verifier its content went through.
+    fn fetch(
+        &self,
+        update: &UpdateDescriptor,
+        download: &Download,
+        bytes_downloaded: &mut u64,
+    ) -> Result<(Vec<u8>, StreamVerifier), WSError> {
+        let storage = self.slots.storage();
+        let mut download_state = DownloadState {
+            build_id: update.build_id.clone(),
+            url: download.url.clone(),
+            verifier: None,
+        };
+        let previous_state: Option<DownloadState> = match storage.read(DOWNLOAD_STATE_NAME)? {
+            Some(json) => serde_json::from_slice(&json).ok(),
+            None => None,
+        };
+        let (mut offset, verifier_state) = match previous_state {
+            Some(previous_state)
+                if previous_state.build_id == download_state.build_id
+                    && previous_state.url == download_state.url =>
+            {
+                (
+                    storage.len(DOWNLOAD_NAME)?.unwrap_or(0),
+                    previous_state.verifier,
+                )
+            }
+            _ => {
+                storage.remove(DOWNLOAD_NAME)?;
+                storage.write(DOWNLOAD_STATE_NAME, &serde_json::to_vec(&download_state)?)?;
+                (0, None)
+            }
+        };
+        if offset > download.size {
+            storage.remove(DOWNLOAD_NAME)?;
+            offset = 0;
+        }
+        self.preflight(update, download, offset)?;
+        let mut verifier = match verifier_state.map(|state| StreamVerifier::resume(&state)) {
+            Some(Ok(verifier)) if verifier.bytes_verified() <= offset => verifier,
+            _ => StreamVerifier::new(),
+        };
+        if verifier.bytes_verified() < offset {
+            // Only hash the part of the download that wasn't verified before the interruption.
+            let partial = storage.read(DOWNLOAD_NAME)?.unwrap_or_default();
+            let end = (offset as usize).min(partial.len());
+            verifier.update(&partial[(verifier.bytes_verified() as usize).min(end)..end]);
+        }
+        let mut fetch_phase = Phase::Fetch.start();
+        let fetch_start = *bytes_downloaded;
+        let backoff = &self.config.fetch.backoff;
+        let seed = format!("{}/{}", self.config.device_id, update.build_id);
+        let mut retry = 0;
+        while offset < download.size {
+            let res = self.transfer(
+                download,
+                &mut offset,
+                bytes_downloaded,
+                &mut verifier,
+                &mut download_state,
+            );
+            self.checkpoint(&mut download_state, &verifier)?;
+            match res {
+                Ok(()) => break,
+                Err(e) if fetch::is_retryable(&e) && retry < backoff.max_retries => {
+                    let delay = backoff.delay(retry, seed.as_bytes());
+                    warn!("Download failed ({}), retrying in {:?}", e, delay);
+                    self.metrics.counter(metrics::DOWNLOAD_RETRIES, 1);
+                    self.clock.sleep(delay);
+                    retry += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        fetch_phase.add_bytes(*bytes_downloaded - fetch_start);
+        drop(fetch_phase);
+        let downloaded = storage.read(DOWNLOAD_NAME)?.unwrap_or_default();
+        Ok((downloaded, verifier))
+    }
+
+    fn download_counted(
+        &self,
+        update: &UpdateDescriptor,
+        bytes_downloaded: &mut u64,
+    ) -> Result<Vec<u8>, WSError> {
+        let storage = self.slots.storage();
+        let patched = match self.patch_chain(update)? {
+            None => None,
+            Some(patches) => match self.download_patches(update, patches, bytes_downloaded) {
+                Ok(module_bin) => Some((module_bin, patches.iter().map(|p| p.download.size).sum())),
+                Err(e) => {
+                    warn!(
+                        "Unable to apply patches, downloading the full module: {}",
+                        e
+                    );
+                    self.clear_download()?;
+                    None
+                }
+            },
+        };
+        // Unless the full module was downloaded as-is, the download isn't the module to install.
+        let (res, restage, fetched, verify_phase) = match patched {
+            Some((module_bin, fetched)) => {
+                let mut verify_phase = Phase::Verify.start();
+                verify_phase.add_bytes(module_bin.len() as _);
+                (Ok(module_bin), true, fetched, verify_phase)
+            }
+            None => {
+                let (download, codec) = self.selected_download(update);
+                let (downloaded, verifier) = self.fetch(update, download, bytes_downloaded)?;
+                let mut verify_phase = Phase::Verify.start();
+                verify_phase.add_bytes(downloaded.len() as _);
+                let res = match &codec {
+                    None => verify_download(&downloaded, verifier, download).map(|_| downloaded),
+                    Some(codec) => decode_download(
+                        codec.as_ref(),
+                        &downloaded,
+                        verifier,
+                        download,
+                        &update.full,
+                    ),
+                };
+                (res, codec.is_some(), download.size, verify_phase)
+            }
+        };
+        let module_bin = match res.and_then(|module_bin| {
+            // Reject malformed modules before verifying their signature.
+            Module::quick_check_reader(&mut io::Cursor::new(&module_bin))?.into_result()?;
+            self.verify_signature(&module_bin, update)?;
+            Ok(module_bin)
+        }) {
+            Ok(module_bin) => module_bin,
+            Err(e) => {
+                self.metrics.counter(metrics::VERIFY_FAILURES, 1);
+                self.clear_download()?;
+                return Err(e);
+            }
+        };
+        if restage && self.config.low_space {
+            // The module is what gets moved into the slot.
+            storage.write(DOWNLOAD_NAME, &module_bin)?;
+        }
+        self.metrics.histogram(
+            metrics::VERIFY_SECONDS,
+            verify_phase.elapsed().as_secs_f64(),
+        );
+        drop(verify_phase);
+        if update.full.size > 0 {
+            // What was fetched, rather than what the plan offered: the agent may have had to
+            // fall back to the full module.
+            self.metrics.histogram(
+                metrics::PATCH_RATIO,
+                fetched as f64 / update.full.size as f64,
+            );
+        }
+        Ok(module_bin)
+    }
+
+    fn verify_signature(
+        &self,
+        module_bin: &[u8],
+        update: &UpdateDescriptor,
+    ) -> Result<(), WSError> {
+        if self.config.trusted_keys.is_empty() {
+            return self.check_unsigned_allowed();
+        }
+        let signature = update.signature.as_deref().ok_or(WSError::NoSignature)?;
+        self.trusted_keys(Some(module_bin))?.verify_module_ignoring(
+            module_bin,
+            &self.config.ignored_sections,
+            &Signature::from_base64(signature)?,
+        )
+    }
+
+    /// Return the trusted keys, minus the keys revoked by the newest list seen so far, and by the
+    /// lists of the active module and of `module_bin`.
+    ///
+    /// The newest valid list is kept in storage, so that installing a module with an older list,
+    /// or without any, doesn't bring revoked keys back.
+    pub(super) fn trusted_keys(&self, module_bin: Option<&[u8]>) -> Result<KeyRing, WSError> {
+        let mut trusted_keys = self.key_ring(&self.config.trusted_keys);
+        let root_pk = match &self.config.revocation_root {
+            None => return Ok(trusted_keys),
+            Some(root_pk) => root_pk,
+        };
+        let storage = self.slots.storage();
+        let mut lists = vec![];
+        if let Some(list_bin) = storage.read(REVOCATIONS_NAME)? {
+            lists.push(SignedRevocationList::from_bytes(&list_bin)?);
+        }
+        let stored = !lists.is_empty();
+        let installed_bin = match self.slots.read_active() {
+            Ok(installed_bin) => Some(installed_bin),
+            Err(WSError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        for bin in installed_bin.as_deref().into_iter().chain(module_bin) {
+            let module = Module::deserialize(&mut io::Cursor::new(bin))?;
+            lists.extend(module.revocation_list()?);
+        }
+        let mut newest: Option<(usize, u64)> = None;
+        for (i, list) in lists.iter().enumerate() {
+            match trusted_keys.add_revocations(list, root_pk) {
+                Ok(list) if newest.is_none_or(|(_, issued_at)| list.issued_at > issued_at) => {
+                    newest = Some((i, list.issued_at))
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Ignoring invalid revocation list: {}", e),
+            }
+        }
+        match newest {
+            Some((0, _)) if stored => {}
+            Some((i, issued_at)) => {
+                debug!("Keeping the revocation list issued at {}", issued_at);
+                storage.write(REVOCATIONS_NAME, &lists[i].to_bytes()?)?;
+            }
+            None => {}
+        }
+        Ok(trusted_keys)
+    }
+
+    /// Return a copy of a key ring checking revocations with the clock of the agent.
+    pub(super) fn key_ring(&self, keys: &KeyRing) -> KeyRing {
+        let mut keys = keys.clone();
+        keys.set_time(self.clock.now());
+        keys
+    }
+
+    /// Return an error unless modules can be installed without verifying their signature.
+    pub(super) fn check_unsigned_allowed(&self) -> Result<(), WSError> {
+        if !self.config.allow_unsigned {
+            return Err(WSError::ConfigError(
+                "no trusted keys are configured, and unsigned updates are not allowed".to_string(),
+            ));
+        }
+        warn!("No trusted keys are configured, skipping signature verification");
+        Ok(())
+    }
+
+    /// Persist the verification state, so that it can be resumed after an interruption.
+    fn checkpoint(
+        &self,
+        download_state: &mut DownloadState,
+        verifier: &StreamVerifier,
+    ) -> Result<(), WSError> {
+        download_state.verifier = Some(verifier.suspend());
+        self.slots
+            .storage()
+            .write(DOWNLOAD_STATE_NAME, &serde_json::to_vec(download_state)?)
+    }
+
+    /// Check that there is enough space to finish downloading and install an update.
+    fn preflight(
+        &self,
+        update: &UpdateDescriptor,
+        download: &Download,
+        offset: u64,
+    ) -> Result<(), WSError> {
+        let storage = self.slots.storage();
+        let available = match storage.available_space()? {
+            None => return Ok(()),
+            Some(available) => available,
+        };
+        let size = update.full.size;
+        let remaining = download.size.saturating_sub(offset);
+        // Unless it is moved, the module is copied into the slot before the download is removed.
+        // A decoded module replaces the download before being moved.
+        let required = if self.config.low_space {
+            remaining + size.saturating_sub(download.size)
+        } else {
+            remaining + size
+        };
+        // The module of the inactive slot is only replaced once the update is installed, so that
+        // its space can't be counted: an interrupted installation must still be able to roll back.
+        if available >= required {
+            return Ok(());
+        }
+        Err(WSError::InsufficientSpace {
+            required,
+            available,
+        })
+    }
+
+    fn transfer(
+        &self,
+        download: &Download,
+        offset: &mut u64,
+        bytes_downloaded: &mut u64,
+        verifier: &mut StreamVerifier,
+        download_state: &mut DownloadState,
+    ) -> Result<(), WSError> {
+        let storage = self.slots.storage();
+        let mut last_checkpoint = *offset;
+        debug!("Downloading [{}] from offset {}", download.url, offset);
+        let mut reader = self.transport.get_range(&download.url, *offset, None)?;
+        let mut limiter = fetch::RateLimiter::new(&self.clock, self.config.fetch.rate_limit);
+        let mut buf = vec![0u8; limiter.chunk_size(DOWNLOAD_CHUNK_SIZE)];
+        while *offset < download.size {
+            let max = buf.len().min((download.size - *offset) as usize);
+            let len = match reader.read(&mut buf[..max]) {
+                Ok(0) => return Err(WSError::IOError(io::ErrorKind::UnexpectedEof.into())),
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            storage.append(DOWNLOAD_NAME, &buf[..len])?;
+            verifier.update(&buf[..len]);
+            *offset += len as u64;
+            *bytes_downloaded += len as u64;
+            self.metrics.counter(metrics::BYTES_DOWNLOADED, len as u64);
+            limiter.consume(len);
+            if *offset - last_checkpoint >= DOWNLOAD_CHECKPOINT_INTERVAL {
+                self.checkpoint(download_state, verifier)?;
+                last_checkpoint = *offset;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that an update suits the host, and return what installing it requires.
+    fn check_update(&self, module_bin: &[u8]) -> Result<UpdateRequirements, WSError> {
+        let module = Module::deserialize(&mut io::Cursor::new(module_bin))?;
+        if let Some(host_config) = &self.config.host_config {
+            module.check_host_config(host_config)?;
+        }
+        let unsigned = |name: &str| {
+            module.sections.iter().any(|section| match section {
+                Section::Custom(custom) => {
+                    custom.name() == name && self.config.ignored_sections.ignores(section)
+                }
+                _ => false,
+            })
+        };
+        if unsigned(SECURITY_VERSION_SECTION_NAME) {
+            return Err(WSError::SecurityDowngrade(
+                "the security version is left out of signature verification".to_string(),
+            ));
+        }
+        module.check_security_version(
+            self.slots.state()?.security_version,
+            &self.key_ring(&self.config.downgrade_keys),
+        )?;
+        let security_version = module.security_version()?;
+        let state_format = module.state_format()?;
+        let hooks = match module.hooks()? {
+            None => {
+                return Ok(UpdateRequirements {
+                    hooks: Hooks::default(),
+                    state_format,
+                    security_version,
+                })
+            }
+            Some(hooks) => hooks,
+        };
+        if unsigned(HOOKS_SECTION_NAME) {
+            return Err(WSError::HookRejected(
+                "install hooks are left out of signature verification".to_string(),
+            ));
+        }
+        self.config.hook_policy.check(&hooks)?;
+        Ok(UpdateRequirements {
+            hooks,
+            state_format,
+            security_version,
+        })
+    }
+
+    fn clear_download(&self) -> Result<(), WSError> {
+        let storage = self.slots.storage();
+        storage.remove(DOWNLOAD_NAME)?;
+        storage.remove(DOWNLOAD_STATE_NAME)
+    }
+
+    fn new_report(&self, status: InstallStatus) -> Result<InstallReport, WSError> {
+        let from = self.slots.state()?.active_info().map(|(_, info)| BuildRef {
+            build_id: info.build_id.clone(),
+            version: info.version.clone(),
+        });
+        Ok(InstallReport {
+            device_id: self.config.device_id.clone(),
+            channel: self.config.channel.clone(),
+            status,
+            from,
+            to: None,
+            error_code: None,
+            error: None,
+            started_at: unix_time(self.clock.now()),
+            duration_ms: 0,
+            bytes_downloaded: 0,
+        })
+    }
+
+    fn send_report(&self, report: &mut InstallReport, started: SystemTime) {
+        report.duration_ms = self
+            .clock
+            .now()
+            .duration_since(started)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        if let Err(e) = self.reporter.report(report) {
+            warn!("Unable to send report: {}", e);
+        }
+    }
+
+    /// Run a single update cycle.
+    pub fn run_once(&self) -> Result<Outcome, WSError> {
+        let started = self.clock.now();
+        let mut report = self.new_report(InstallStatus::UpToDate)?;
+        let res = self.update_cycle(&mut report);
+        match &res {
+            Ok(Outcome::Installed { info, .. }) => {
+                report.status = InstallStatus::Success;
+                report.to = Some(BuildRef {
+                    build_id: info.build_id.clone(),
+                    version: info.version.clone(),
+                });
+            }
+            Ok(Outcome::PendingConfirmation) => report.status = InstallStatus::PendingConfirmation,
+            Ok(Outcome::Deferred { .. }) => report.status = InstallStatus::Deferred,
+            Ok(Outcome::UpToDate) => {}
+            Err(e) => report.set_error(e),
+        }
+        self.send_report(&mut report, started);
+        res
+    }
+
+    fn update_cycle(&self, report: &mut InstallReport) -> Result<Outcome, WSError> {
+        if self.slots.state()?.pending {
+            return Ok(Outcome::PendingConfirmation);
+        }
+        let response = self.check_response()?;
+        if let Some(golden) = &response.golden {
+            if let Err(e) = self.sync_golden(golden) {
+                warn!("Unable to pin the golden module {}: {}", golden.version, e);
+            }
+        }
+        let update = match response.update {
+            None => return Ok(Outcome::UpToDate),
+            Some(update) => update,
+        };
+        info!("Update available: {} [{}]", update.version, update.build_id);
+        report.to = Some(BuildRef {
+            build_id: update.build_id.clone(),
+            version: update.version.clone(),
+        });
+        let wait = self.config.fetch.wait_time(self.clock.now());
+        if !wait.is_zero() {
+            debug!("Outside of the download windows, deferring for {:?}", wait);
+            return Ok(Outcome::Deferred { wait });
+        }
+        let module_bin = self.download_counted(&update, &mut report.bytes_downloaded)?;
+        let requirements = self.check_update(&module_bin)?;
+        self.run_hooks(&requirements.hooks, HookStage::PreInstall)?;
+        let snapshot = self.snapshot_state()?;
+        let from = self
+            .slots
+            .state()?
+            .active_info()
+            .map(|(_, info)| info.version.clone());
+        let info = SlotInfo {
+            build_id: update.build_id.clone(),
+            version: update.version.clone(),
+            signature: update.signature.clone(),
+            security_version: requirements.security_version,
+        };
+        let mut install_phase = Phase::Install.start();
+        install_phase.add_bytes(module_bin.len() as _);
+        let slot = if self.config.low_space {
+            self.slots.install_staged(DOWNLOAD_NAME, info.clone())?
+        } else {
+            self.slots.install(&module_bin, info.clone())?
+        };
+        self.metrics.histogram(
+            metrics::INSTALL_SECONDS,
+            install_phase.elapsed().as_secs_f64(),
+        );
+        self.metrics.counter(metrics::INSTALLS, 1);
+        drop(install_phase);
+        self.clear_download()?;
+        let res = match &from {
+            Some(from) => self
+                .config
+                .migrations
+                .run(from, &update.version)
+                .map(|_| ()),
+            None => Ok(()),
+        };
+        let res = res
+            .and_then(|()| self.restore_state(snapshot.as_ref(), requirements.state_format))
+            .and_then(|()| self.run_hooks(&requirements.hooks, HookStage::PostInstall));
+        if let Err(e) = res {
+            let slot = self.slots.rollback()?;
+            self.restore_previous_state(snapshot.as_ref());
+            warn!(
+                "Restored slot {} after a failed migration or post-install hook",
+                slot
+            );
+            return Err(e);
+        }
+        if let Err(e) = self.generate_sidecar(slot) {
+            warn!("Unable to precompile the module of slot {}: {}", slot, e);
+        }
+        info!("Installed {} into slot {}", update.version, slot);
+        Ok(Outcome::Installed { slot, info })
+    }
+
+    /// Restore the previously installed module, and report it.
+    pub fn rollback(&self) -> Result<Slot, WSError> {
+        let started = self.clock.now();
+        let mut report = self.new_report(InstallStatus::RolledBack)?;
+        let res = self.slots.rollback();
+        match &res {
+            Ok(slot) => {
+                report.to = self.slots.state()?.info(*slot).map(|info| BuildRef {
+                    build_id: info.build_id.clone(),
+                    version: info.version.clone(),
+                });
+            }
+            Err(e) => report.set_error(e),
+        }
+        self.send_report(&mut report, started);
+        res
+    }
+
+    /// Run update cycles forever, waiting for the configured interval between them.
+    ///
+    /// Errors are logged, and the next cycle resumes where the previous one stopped.
+    pub fn run(&self) -> ! {
+        loop {
+            match self.run_once() {
+                Ok(outcome) => debug!("Update cycle: {:?}", outcome),
+                Err(e) => warn!("Update cycle failed: {}", e),
+            }
+            self.clock.sleep(self.config.interval);
+        }
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn verify_download(
+    module_bin: &[u8],
+    verifier: StreamVerifier,
+    download: &Download,
+) -> Result<(), WSError> {
+    if module_bin.len() as u64 != download.size {
+        return Err(WSError::VerificationFailed);
+    }
+    verifier.verify(download)?;
+    Module::deserialize(&mut io::Cursor::new(module_bin))?;
+    Ok(())
+}
+
+/// Verify an encoded download, then decode it and verify the module.
+fn decode_download(
+    codec: &dyn Codec,
+    encoded: &[u8],
+    verifier: StreamVerifier,
+    download: &Download,
+    full: &Download,
+) -> Result<Vec<u8>, WSError> {
+    if encoded.len() as u64 != download.size {
+        return Err(WSError::VerificationFailed);
+    }
+    verifier.verify(download)?;
+    let module_bin = codec
+        .decode(encoded, full.size)
+        .map_err(|_| WSError::VerificationFailed)?;
+    let mut verifier = StreamVerifier::new();
+    verifier.update(&module_bin);
+    verify_download(&module_bin, verifier, full)?;
+    Ok(module_bin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::{MemoryBackend, Repository};
+
+    fn module(payload: &[u8]) -> Vec<u8> {
+        let module = Module {
+            header: [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00],
+            sections: vec![Section::Custom(CustomSection::new(
+                "payload".to_string(),
+                payload.to_vec(),
+            ))],
+            ..Module::default()
+        };
+        let mut module_bin = vec![];
+        module.serialize(&mut module_bin).unwrap();
+        module_bin
+    }
+
+    fn payload(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn publish(repo: &Repository<MemoryBackend>, version: &str, module_bin: &[u8]) -> String {
+        repo.publish(module_bin, &version.parse().unwrap(), "stable")
+            .unwrap()
+            .build_id
+    }
+
+    /// Metrics keeping the sum of counters and the last value of histograms, so that tests can
+    /// check them.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<std::sync::Mutex<std::collections::BTreeMap<String, f64>>>);
+
+    impl Recorder {
+        fn take(&self, name: &str) -> f64 {
+            self.0.lock().unwrap().remove(name).unwrap_or(0.0)
+        }
+    }
+
+    impl Metrics for Recorder {
+        fn counter(&self, name: &str, value: u64) {
+            *self.0.lock().unwrap().entry(name.to_string()).or_default() += value as f64;
+        }
+
+        fn histogram(&self, name: &str, value: f64) {
+            self.0.lock().unwrap().insert(name.to_string(), value);
+        }
+    }
+
+    fn agent(
+        repo: &Repository<MemoryBackend>,
+        storage: &MemoryStorage,
+        low_space: bool,
+    ) -> Agent<LocalTransport<MemoryBackend>, MemoryStorage, VirtualClock> {
+        let mut config = AgentConfig::new("device", "stable");
+        config.allow_unsigned = true;
+        config.low_space = low_space;
+        Agent::new(
+            config,
+            LocalTransport::new(repo.clone()),
+            storage.clone(),
+            VirtualClock::default(),
+        )
+    }
+
+    #[test]
+    fn low_space_keeps_the_rollback_module() {
+        let repo = Repository::new(MemoryBackend::new());
+        let storage = MemoryStorage::with_capacity(6000);
+        let agent = agent(&repo, &storage, true);
+        for (version, len) in [("1.0.0", 1000), ("2.0.0", 1000), ("3.0.0", 2500)] {
+            let module_bin = module(&vec![version.as_bytes()[0]; len]);
+            repo.publish(&module_bin, &version.parse().unwrap(), "stable")
+                .unwrap();
+            assert!(matches!(agent.run_once(), Ok(Outcome::Installed { .. })));
+            agent.slots().confirm().unwrap();
+        }
+        let rollback = agent.slots().read(Slot::B).unwrap();
+
+        let module_bin = module(&[b'4'; 3000]);
+        repo.publish(&module_bin, &"4.0.0".parse().unwrap(), "stable")
+            .unwrap();
+        assert!(matches!(
+            agent.run_once(),
+            Err(WSError::InsufficientSpace { .. })
+        ));
+        assert_eq!(agent.slots().read(Slot::B).unwrap(), rollback);
+        assert!(agent.slots().state().unwrap().b.is_some());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn patches_are_applied() {
+        use crate::codec::DictionaryPatch;
+
+        let repo = Repository::new(MemoryBackend::new());
+        let recorder = Recorder::default();
+        let mut agent = agent(&repo, &MemoryStorage::new(), false);
+        agent.set_metrics(recorder.clone());
+        let mut payload = payload(20000, 1);
+        let base_build_id = publish(&repo, "1.0.0", &module(&payload));
+        agent.run_once().unwrap();
+        agent.slots().confirm().unwrap();
+
+        payload[10000..10100].fill(0);
+        let module_bin = module(&payload);
+        let build_id = publish(&repo, "2.0.0", &module_bin);
+        repo.add_dictionary_patch(&base_build_id, &build_id, &DictionaryPatch::new())
+            .unwrap();
+        recorder.take(metrics::BYTES_DOWNLOADED);
+        let slot = match agent.run_once().unwrap() {
+            Outcome::Installed { slot, .. } => slot,
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        };
+        assert_eq!(agent.slots().read(slot).unwrap(), module_bin);
+        assert!(recorder.take(metrics::BYTES_DOWNLOADED) < module_bin.len() as f64 / 2.0);
+        assert!(recorder.take(metrics::PATCH_RATIO) < 0.5);
+    }
+
+    #[test]
+    fn broken_patches_fall_back_to_the_full_module() {
+        let repo = Repository::new(MemoryBackend::new());
+        let recorder = Recorder::default();
+        let mut agent = agent(&repo, &MemoryStorage::new(), false);
+        agent.set_metrics(recorder.clone());
+        let base_build_id = publish(&repo, "1.0.0", &module(&payload(20000, 1)));
+        agent.run_once().unwrap();
+        agent.slots().confirm().unwrap();
+
+        let module_bin = module(&payload(20000, 2));
+        let build_id = publish(&repo, "2.0.0", &module_bin);
+        repo.add_patch(&base_build_id, &build_id, b"not a patch")
+            .unwrap();
+        let update = agent.check().unwrap().unwrap();
+        assert!(matches!(update.plan, UpdatePlan::Patches(_)));
+        recorder.take(metrics::BYTES_DOWNLOADED);
+        let slot = match agent.run_once().unwrap() {
+            Outcome::Installed { slot, .. } => slot,
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        };
+        assert_eq!(agent.slots().read(slot).unwrap(), module_bin);
+        assert!(recorder.take(metrics::BYTES_DOWNLOADED) >= module_bin.len() as f64);
+        assert_eq!(recorder.take(metrics::PATCH_RATIO), 1.0);
+    }
+
+    /// Return an agent only accepting modules and patches signed with `kp`, that installed a
+    /// signed base module, along with the build identifiers of the base module and of a signed
+    /// update published afterwards.
+    #[cfg(feature = "zstd")]
+    fn signed_update(
+        repo: &Repository<MemoryBackend>,
+        kp: &KeyPair,
+    ) -> (
+        Agent<LocalTransport<MemoryBackend>, MemoryStorage, VirtualClock>,
+        String,
+        String,
+    ) {
+        let mut config = AgentConfig::new("device", "stable");
+        config.trusted_keys.add(kp.pk.clone());
+        let agent = Agent::new(
+            config,
+            LocalTransport::new(repo.clone()),
+            MemoryStorage::new(),
+            VirtualClock::default(),
+        );
+        let mut payload = payload(20000, 1);
+        let mut build_ids = vec![];
+        for version in ["1.0.0", "2.0.0"] {
+            let module_bin = module(&payload);
+            let build_id = publish(repo, version, &module_bin);
+            repo.add_signature(&build_id, &kp.sk.sign_module(&module_bin))
+                .unwrap();
+            if build_ids.is_empty() {
+                agent.run_once().unwrap();
+                agent.slots().confirm().unwrap();
+            }
+            build_ids.push(build_id);
+            payload[10000..10100].fill(0);
+        }
+        let build_id = build_ids.pop().unwrap();
+        (agent, build_ids.pop().unwrap(), build_id)
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn unsigned_patches_are_not_used() {
+        use crate::codec::DictionaryPatch;
+
+        let repo = Repository::new(MemoryBackend::new());
+        let kp = KeyPair::generate();
+        let (agent, base_build_id, build_id) = signed_update(&repo, &kp);
+        repo.add_dictionary_patch(&base_build_id, &build_id, &DictionaryPatch::new())
+            .unwrap();
+        let update = agent.check().unwrap().unwrap();
+        assert!(matches!(update.plan, UpdatePlan::Patches(_)));
+        assert!(agent.patch_chain(&update).unwrap().is_none());
+        assert!(matches!(agent.run_once(), Ok(Outcome::Installed { .. })));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn tampered_patches_are_not_applied() {
+        use crate::codec::DictionaryPatch;
+
+        let repo = Repository::new(MemoryBackend::new());
+        let kp = KeyPair::generate();
+        let (mut agent, base_build_id, build_id) = signed_update(&repo, &kp);
+        let recorder = Recorder::default();
+        agent.set_metrics(recorder.clone());
+        repo.add_dictionary_patch(&base_build_id, &build_id, &DictionaryPatch::new())
+            .unwrap();
+        let patch = repo.patch(&base_build_id, &build_id).unwrap().unwrap();
+        let signature = kp.sk.sign_patch(&base_build_id, &build_id, &patch).unwrap();
+        repo.add_patch_signature(&base_build_id, &build_id, &signature)
+            .unwrap();
+        let update = agent.check().unwrap().unwrap();
+        let patches = agent.patch_chain(&update).unwrap().unwrap();
+        agent.download_patches(&update, patches, &mut 0).unwrap();
+
+        // The digest of the check response is not signed, so it can match the tampered patch.
+        let mut tampered = patch.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        repo.add_patch(&base_build_id, &build_id, &tampered)
+            .unwrap();
+        let update = agent.check().unwrap().unwrap();
+        let patches = agent.patch_chain(&update).unwrap().unwrap();
+        assert!(matches!(
+            agent.download_patches(&update, patches, &mut 0),
+            Err(WSError::VerificationFailed)
+        ));
+        agent.clear_download().unwrap();
+        assert!(matches!(agent.run_once(), Ok(Outcome::Installed { .. })));
+        assert_eq!(recorder.take(metrics::PATCH_RATIO), 1.0);
+    }
+
+    #[test]
+    fn revocation_lists_are_honored() {
+        use crate::revocation::{Revocation, RevocationList};
+
+        let repo = Repository::new(MemoryBackend::new());
+        let (root, kp, revoked) = (
+            KeyPair::generate(),
+            KeyPair::generate(),
+            KeyPair::generate(),
+        );
+        let mut config = AgentConfig::new("device", "stable");
+        config.trusted_keys.add(kp.pk.clone());
+        config.trusted_keys.add(revoked.pk.clone());
+        config.revocation_root = Some(root.pk.clone());
+        let storage = MemoryStorage::new();
+        let agent = Agent::new(
+            config,
+            LocalTransport::new(repo.clone()),
+            storage.clone(),
+            VirtualClock::default(),
+        );
+        let revocations = |issued_at, kp: &KeyPair, root_sk| {
+            RevocationList {
+                issued_at,
+                revocations: vec![Revocation {
+                    key_id: *kp.pk.key_id(),
+                    effective_at: 0,
+                }],
+            }
+            .sign(root_sk)
+            .unwrap()
+        };
+        let release = |version: &str, list: Option<&SignedRevocationList>, kp: &KeyPair| {
+            let mut module =
+                Module::deserialize(&mut io::Cursor::new(module(version.as_bytes()))).unwrap();
+            if let Some(list) = list {
+                module.set_revocation_list(list).unwrap();
+            }
+            let mut module_bin = vec![];
+            module.serialize(&mut module_bin).unwrap();
+            let build_id = publish(&repo, version, &module_bin);
+            repo.add_signature(&build_id, &kp.sk.sign_module(&module_bin))
+                .unwrap();
+            agent.run_once()
+        };
+
+        let newest = revocations(2000, &revoked, &root.sk);
+        assert!(matches!(
+            release("1.0.0", Some(&newest), &kp),
+            Ok(Outcome::Installed { .. })
+        ));
+        agent.slots().confirm().unwrap();
+        assert!(matches!(
+            release("2.0.0", None, &revoked),
+            Err(WSError::KeyRevoked(_))
+        ));
+
+        // Lists that are not signed by the root key are ignored.
+        let forged = revocations(3000, &kp, &KeyPair::generate().sk);
+        assert!(matches!(
+            release("3.0.0", Some(&forged), &kp),
+            Ok(Outcome::Installed { .. })
+        ));
+        agent.slots().confirm().unwrap();
+
+        // A module with an older list doesn't restore the revoked key.
+        let older = RevocationList {
+            issued_at: 1000,
+            revocations: vec![],
+        }
+        .sign(&root.sk)
+        .unwrap();
+        assert!(matches!(
+            release("4.0.0", Some(&older), &kp),
+            Ok(Outcome::Installed { .. })
+        ));
+        agent.slots().confirm().unwrap();
+        assert!(matches!(
+            release("5.0.0", None, &revoked),
+            Err(WSError::KeyRevoked(_))
+        ));
+        assert_eq!(
+            storage.read(REVOCATIONS_NAME).unwrap(),
+            Some(newest.to_bytes().unwrap())
+        );
+    }
+}
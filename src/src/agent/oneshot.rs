@@ -0,0 +1,103 @@
+use super::{ObjectMetadata, Transport};
+use crate::error::*;
+use crate::protocol::*;
+use crate::repo::build_id;
+use crate::signature::*;
+
+use semver::Version;
+use std::io::{self, prelude::*};
+
+/// URL of the module offered by a `OneShotTransport`.
+pub const ONESHOT_URL: &str = "/oneshot/module.wasm";
+
+/// A transport offering a single module as an update.
+///
+/// This allows installing a module obtained out of band, such as a file or a download, without
+/// an update server, while the agent still verifies, stages and commits it as any other update.
+/// The module is not offered to devices that already run it.
+#[derive(Debug, Clone)]
+pub struct OneShotTransport {
+    module_bin: Vec<u8>,
+    build_id: String,
+    version: Version,
+    signature: Option<Signature>,
+}
+
+impl OneShotTransport {
+    /// Create a transport offering a module, as the given version.
+    pub fn new(module_bin: Vec<u8>, version: Version) -> Self {
+        OneShotTransport {
+            build_id: build_id(&module_bin),
+            module_bin,
+            version,
+            signature: None,
+        }
+    }
+
+    /// Set the detached signature of the module.
+    pub fn signature(mut self, signature: Signature) -> Self {
+        self.signature = Some(signature);
+        self
+    }
+
+    /// Return the build identifier of the module.
+    pub fn build_id(&self) -> &str {
+        &self.build_id
+    }
+
+    fn object(&self, url: &str) -> Result<&[u8], WSError> {
+        if url != ONESHOT_URL {
+            return Err(WSError::UnsupportedUrl(url.to_string()));
+        }
+        Ok(&self.module_bin)
+    }
+}
+
+impl Transport for OneShotTransport {
+    fn check(&self, request: &UpdateCheckRequest) -> Result<UpdateCheckResponse, WSError> {
+        if request.installed_build_id.as_deref() == Some(self.build_id.as_str()) {
+            return Ok(UpdateCheckResponse {
+                update: None,
+                nonce: request.nonce.clone(),
+                golden: None,
+            });
+        }
+        let update = UpdateDescriptor {
+            version: self.version.clone(),
+            build_id: self.build_id.clone(),
+            full: Download {
+                url: ONESHOT_URL.to_string(),
+                size: self.module_bin.len() as u64,
+                sha256: self.build_id.clone(),
+            },
+            plan: UpdatePlan::Full,
+            signature: self.signature.as_ref().map(Signature::to_base64),
+            encoded: None,
+        };
+        Ok(UpdateCheckResponse {
+            update: Some(update),
+            nonce: request.nonce.clone(),
+            golden: None,
+        })
+    }
+
+    fn get_range(
+        &self,
+        url: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read>, WSError> {
+        let data = self.object(url)?;
+        let start = (offset as usize).min(data.len());
+        let end = match len {
+            Some(len) => start.saturating_add(len as usize).min(data.len()),
+            None => data.len(),
+        };
+        Ok(Box::new(io::Cursor::new(data[start..end].to_vec())))
+    }
+
+    fn get_metadata(&self, url: &str) -> Result<ObjectMetadata, WSError> {
+        let size = self.object(url)?.len() as u64;
+        Ok(ObjectMetadata { size: Some(size) })
+    }
+}
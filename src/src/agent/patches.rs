@@ -0,0 +1,111 @@
+use super::*;
+use crate::repo::build_id;
+
+impl<T: Transport, S: Storage, C: Clock> Agent<T, S, C> {
+    /// Return the chain of patches to follow to get an update, if the agent can follow it.
+    ///
+    /// Patches require the `zstd` feature, and the first one must apply to a module stored on
+    /// the device, in a slot or as the golden module. If trusted keys are configured, every patch
+    /// must be signed.
+    pub(super) fn patch_chain<'t>(
+        &self,
+        update: &'t UpdateDescriptor,
+    ) -> Result<Option<&'t [PatchDescriptor]>, WSError> {
+        let patches = match &update.plan {
+            UpdatePlan::Patches(patches) if !patches.is_empty() => patches,
+            _ => return Ok(None),
+        };
+        if !cfg!(feature = "zstd") {
+            debug!("Patches are not supported, downloading the full module");
+            return Ok(None);
+        }
+        if !self.config.trusted_keys.is_empty() {
+            if let Some(patch) = patches.iter().find(|patch| patch.signature.is_none()) {
+                debug!(
+                    "Patch {} -> {} is not signed, downloading the full module",
+                    patch.base_build_id, patch.build_id
+                );
+                return Ok(None);
+            }
+        }
+        let base_build_id = &patches[0].base_build_id;
+        if self.slots.locate(base_build_id)?.is_none() {
+            debug!(
+                "The module {} the patches apply to is not stored any more",
+                base_build_id
+            );
+            return Ok(None);
+        }
+        Ok(Some(patches))
+    }
+
+    /// Download a chain of patches, apply them in order to the stored base module, and return
+    /// the resulting module.
+    ///
+    /// Each patch is verified with its digest and its signature before being applied, each
+    /// intermediate module must have the build identifier the patch is expected to produce, and
+    /// the final module is verified like a full download.
+    pub(super) fn download_patches(
+        &self,
+        update: &UpdateDescriptor,
+        patches: &[PatchDescriptor],
+        bytes_downloaded: &mut u64,
+    ) -> Result<Vec<u8>, WSError> {
+        let (location, _) = self
+            .slots
+            .locate(&patches[0].base_build_id)?
+            .ok_or_else(|| WSError::NotFound(patches[0].base_build_id.clone()))?;
+        let mut module_bin = match location {
+            ModuleLocation::Slot(slot) => self.slots.read(slot)?,
+            ModuleLocation::Golden => self.slots.read_golden()?,
+        };
+        for patch in patches {
+            if !build_id(&module_bin).eq_ignore_ascii_case(&patch.base_build_id) {
+                return Err(WSError::DigestMismatch);
+            }
+            let (patch_bin, verifier) = self.fetch(update, &patch.download, bytes_downloaded)?;
+            if patch_bin.len() as u64 != patch.download.size {
+                return Err(WSError::VerificationFailed);
+            }
+            verifier.verify(&patch.download)?;
+            self.verify_patch(patch, &patch_bin)?;
+            debug!(
+                "Applying patch {} -> {}",
+                patch.base_build_id, patch.build_id
+            );
+            module_bin = apply_patch(&module_bin, &patch_bin, update.full.size)?;
+            if !build_id(&module_bin).eq_ignore_ascii_case(&patch.build_id) {
+                return Err(WSError::DigestMismatch);
+            }
+            self.clear_download()?;
+        }
+        let mut verifier = StreamVerifier::new();
+        verifier.update(&module_bin);
+        verify_download(&module_bin, verifier, &update.full)?;
+        Ok(module_bin)
+    }
+
+    /// Verify the signature of a patch, so that the decoder never runs on unauthenticated data.
+    fn verify_patch(&self, patch: &PatchDescriptor, patch_bin: &[u8]) -> Result<(), WSError> {
+        if self.config.trusted_keys.is_empty() {
+            return self.check_unsigned_allowed();
+        }
+        let signature = patch.signature.as_deref().ok_or(WSError::NoSignature)?;
+        self.trusted_keys(None)?.verify_patch(
+            &patch.base_build_id,
+            &patch.build_id,
+            patch_bin,
+            &Signature::from_base64(signature)?,
+        )
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn apply_patch(base: &[u8], patch: &[u8], max_size: u64) -> Result<Vec<u8>, WSError> {
+    crate::codec::apply_dictionary_patch(base, patch, max_size)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn apply_patch(_base: &[u8], _patch: &[u8], _max_size: u64) -> Result<Vec<u8>, WSError> {
+    Err(WSError::UnsupportedCodec("zstd".to_string()))
+}
@@ -0,0 +1,430 @@
+use super::mdns::{self, Announcement};
+use super::{HttpTransport, ObjectMetadata, Slot, Slots, Storage, Transport};
+use crate::error::*;
+use crate::protocol::*;
+use crate::repo::build_id;
+use crate::server::{data_response, Request, Response};
+
+use log::*;
+use std::collections::HashSet;
+use std::io::{self, prelude::*, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Path prefix under which peers serve modules, followed by their build identifier.
+pub const PEER_PATH_PREFIX: &str = "/v1/peer/";
+
+const TXT_BUILD_ID_PREFIX: &str = "b=";
+const PEER_CACHE_TTL: Duration = Duration::from_secs(300);
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A device of the local network sharing its modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// Build identifiers of the modules announced by the peer.
+    pub build_ids: Vec<String>,
+}
+
+/// Find the peers of the local network, waiting for `timeout` for them to answer.
+pub fn discover_peers(timeout: Duration) -> Result<Vec<Peer>, WSError> {
+    Ok(mdns::browse(timeout)?
+        .into_iter()
+        .filter_map(|instance| {
+            Some(Peer {
+                addr: instance.addr?,
+                build_ids: instance
+                    .txt
+                    .iter()
+                    .filter_map(|txt| txt.strip_prefix(TXT_BUILD_ID_PREFIX))
+                    .map(|build_id| build_id.to_ascii_lowercase())
+                    .collect(),
+            })
+        })
+        .collect())
+}
+
+/// Shares the modules installed on a device with the other devices of the local network.
+///
+/// The modules of both slots are served over HTTP under `PEER_PATH_PREFIX`, and announced with mDNS,
+/// as `_wasi-update._tcp.local` services listing their build identifiers. A module is only served if
+/// it still matches its build identifier. Modules are served to anyone on the network.
+pub struct PeerServer<S: Storage> {
+    slots: Arc<Slots<S>>,
+    instance: String,
+}
+
+impl<S: Storage + Send + Sync + 'static> PeerServer<S> {
+    /// Share the modules of the slots stored in `storage`, announcing them under the name of the device.
+    pub fn new(storage: S, device_id: &str) -> Self {
+        PeerServer {
+            slots: Arc::new(Slots::new(storage)),
+            instance: mdns::label(device_id),
+        }
+    }
+
+    /// Listen on the given address, then announce and serve the modules until an error occurs.
+    pub fn serve(&self, addr: impl ToSocketAddrs) -> Result<(), WSError> {
+        let listener = TcpListener::bind(addr)?;
+        let port = listener.local_addr()?.port();
+        let responder = mdns::Responder::bind()?;
+        info!("Sharing modules on port {}", port);
+        let slots = self.slots.clone();
+        let instance = self.instance.clone();
+        thread::spawn(move || {
+            responder.run(|| {
+                let build_ids = available_build_ids(&slots);
+                if build_ids.is_empty() {
+                    return None;
+                }
+                Some(Announcement {
+                    instance: instance.clone(),
+                    port,
+                    txt: build_ids
+                        .iter()
+                        .map(|build_id| format!("{}{}", TXT_BUILD_ID_PREFIX, build_id))
+                        .collect(),
+                })
+            })
+        });
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Connection failed: {}", e);
+                    continue;
+                }
+            };
+            let slots = self.slots.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(&slots, stream) {
+                    debug!("Connection error: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn available_build_ids<S: Storage>(slots: &Slots<S>) -> Vec<String> {
+    let state = match slots.state() {
+        Ok(state) => state,
+        Err(e) => {
+            warn!("Unable to read the state of the slots: {}", e);
+            return vec![];
+        }
+    };
+    [Slot::A, Slot::B]
+        .iter()
+        .filter_map(|&slot| state.info(slot))
+        .map(|info| info.build_id.clone())
+        .collect()
+}
+
+fn module<S: Storage>(slots: &Slots<S>, requested: &str) -> Result<Vec<u8>, WSError> {
+    let state = slots.state()?;
+    for slot in [Slot::A, Slot::B] {
+        if !state
+            .info(slot)
+            .is_some_and(|info| info.build_id.eq_ignore_ascii_case(requested))
+        {
+            continue;
+        }
+        let module_bin = slots.read(slot)?;
+        if build_id(&module_bin).eq_ignore_ascii_case(requested) {
+            return Ok(module_bin);
+        }
+        warn!(
+            "The module of slot {} doesn't match its build identifier",
+            slot
+        );
+    }
+    Err(WSError::NotFound(requested.to_string()))
+}
+
+fn handle_connection<S: Storage>(slots: &Slots<S>, stream: TcpStream) -> Result<(), WSError> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = io::BufWriter::new(stream);
+    let request = match Request::read(&mut reader) {
+        Ok(request) => request,
+        Err(_) => return Response::error(400).write(&mut writer, false),
+    };
+    let requested = request
+        .path
+        .strip_prefix(PEER_PATH_PREFIX)
+        .and_then(|name| name.strip_suffix(".wasm"));
+    let response = match requested {
+        _ if request.method != "GET" && request.method != "HEAD" => Response::error(405),
+        None => Response::error(404),
+        Some(requested) => match module(slots, requested) {
+            Ok(module_bin) => data_response(&request, "application/wasm", module_bin),
+            Err(WSError::NotFound(_)) => Response::error(404),
+            Err(e) => {
+                warn!("{} {}: {}", request.method, request.path, e);
+                Response::error(500)
+            }
+        },
+    };
+    debug!("{} {} {}", request.method, request.path, response.status);
+    response.write(&mut writer, request.method == "HEAD")
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    /// The full download of the last update offered by the server.
+    offered: Option<Download>,
+    peers: Option<(Instant, Vec<Peer>)>,
+    /// Modules entirely downloaded from peers.
+    served: HashSet<String>,
+    /// Modules that must not be downloaded from peers any more.
+    excluded: HashSet<String>,
+}
+
+/// A transport downloading updates from peers of the local network when possible.
+///
+/// Update checks are sent to the upstream transport. Full modules are downloaded from peers announcing
+/// them if there are any, and from upstream otherwise. Downloads are verified by the agent as usual,
+/// whatever their source. If a module downloaded from peers is discarded, for example because it failed
+/// verification, it is downloaded from upstream from then on.
+pub struct PeerTransport<T: Transport> {
+    upstream: T,
+    discovery: bool,
+    discovery_timeout: Duration,
+    static_peers: Vec<SocketAddr>,
+    state: Arc<Mutex<PeerState>>,
+}
+
+struct PeerReader {
+    inner: Box<dyn Read>,
+    remaining: u64,
+    sha256: String,
+    state: Arc<Mutex<PeerState>>,
+}
+
+impl Read for PeerReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.remaining = self.remaining.saturating_sub(len as u64);
+        if len > 0 && self.remaining == 0 {
+            lock(&self.state).served.insert(self.sha256.clone());
+        }
+        Ok(len)
+    }
+}
+
+fn lock(state: &Mutex<PeerState>) -> MutexGuard<'_, PeerState> {
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl<T: Transport> PeerTransport<T> {
+    /// Use peers found with mDNS, in addition to the upstream transport.
+    pub fn new(upstream: T) -> Self {
+        PeerTransport {
+            upstream,
+            discovery: true,
+            discovery_timeout: Duration::from_secs(1),
+            static_peers: vec![],
+            state: Arc::new(Mutex::new(PeerState::default())),
+        }
+    }
+
+    /// Also try a peer at a known address, such as a local cache on networks without multicast.
+    pub fn peer(mut self, addr: SocketAddr) -> Self {
+        self.static_peers.push(addr);
+        self
+    }
+
+    /// Enable or disable the discovery of peers with mDNS.
+    pub fn discovery(mut self, discovery: bool) -> Self {
+        self.discovery = discovery;
+        self
+    }
+
+    /// Set how long to wait for peers to answer discovery queries.
+    pub fn discovery_timeout(mut self, timeout: Duration) -> Self {
+        self.discovery_timeout = timeout;
+        self
+    }
+
+    /// Return the addresses of the peers that may serve a module.
+    fn peers_for(&self, sha256: &str) -> Vec<SocketAddr> {
+        let mut addrs = self.static_peers.clone();
+        if !self.discovery {
+            return addrs;
+        }
+        let mut state = lock(&self.state);
+        let fresh = state
+            .peers
+            .as_ref()
+            .is_some_and(|(found_at, _)| found_at.elapsed() < PEER_CACHE_TTL);
+        if !fresh {
+            let peers = discover_peers(self.discovery_timeout).unwrap_or_else(|e| {
+                warn!("Unable to discover peers: {}", e);
+                vec![]
+            });
+            state.peers = Some((Instant::now(), peers));
+        }
+        if let Some((_, peers)) = &state.peers {
+            addrs.extend(
+                peers
+                    .iter()
+                    .filter(|peer| peer.build_ids.iter().any(|build_id| build_id == sha256))
+                    .map(|peer| peer.addr),
+            );
+        }
+        addrs
+    }
+}
+
+impl<T: Transport> Transport for PeerTransport<T> {
+    fn check(&self, request: &UpdateCheckRequest) -> Result<UpdateCheckResponse, WSError> {
+        let response = self.upstream.check(request)?;
+        lock(&self.state).offered = response.update.as_ref().map(|update| update.full.clone());
+        Ok(response)
+    }
+
+    fn get_range(
+        &self,
+        url: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read>, WSError> {
+        let download = {
+            let mut state = lock(&self.state);
+            match state.offered.clone() {
+                Some(download) if download.url == url && offset < download.size => {
+                    let sha256 = download.sha256.to_ascii_lowercase();
+                    // Downloads only start over once the previous one was discarded.
+                    if offset == 0 && state.served.remove(&sha256) {
+                        warn!(
+                            "A download of [{}] from peers was discarded, using upstream",
+                            sha256
+                        );
+                        state.excluded.insert(sha256.clone());
+                    }
+                    (!state.excluded.contains(&sha256)).then_some((sha256, download.size))
+                }
+                _ => None,
+            }
+        };
+        let Some((sha256, size)) = download else {
+            return self.upstream.get_range(url, offset, len);
+        };
+        let path = format!("{}{}.wasm", PEER_PATH_PREFIX, sha256);
+        for addr in self.peers_for(&sha256) {
+            let peer = HttpTransport::new(format!("http://{}", addr)).timeout(PEER_TIMEOUT);
+            match peer.get_range(&path, offset, len) {
+                Ok(inner) => {
+                    debug!("Downloading [{}] from peer {}", sha256, addr);
+                    return Ok(Box::new(PeerReader {
+                        inner,
+                        remaining: len.unwrap_or(size - offset).min(size - offset),
+                        sha256,
+                        state: self.state.clone(),
+                    }));
+                }
+                Err(e) => debug!("Peer {} can't serve [{}]: {}", addr, sha256, e),
+            }
+        }
+        self.upstream.get_range(url, offset, len)
+    }
+
+    fn get_metadata(&self, url: &str) -> Result<ObjectMetadata, WSError> {
+        self.upstream.get_metadata(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{
+        Agent, AgentConfig, LocalTransport, MemoryStorage, Outcome, SlotInfo, VirtualClock,
+    };
+    use crate::repo::{MemoryBackend, Repository};
+    use crate::server::serve_fake;
+
+    fn wasm(payload: &[u8]) -> Vec<u8> {
+        let mut module_bin = b"\0asm\x01\0\0\0\0".to_vec();
+        module_bin.push(payload.len() as u8 + 1);
+        module_bin.push(0);
+        module_bin.extend_from_slice(payload);
+        module_bin
+    }
+
+    #[test]
+    fn corrupted_modules_are_not_served() {
+        let storage = MemoryStorage::new();
+        let slots = Slots::new(storage.clone());
+        let module_bin = wasm(b"v1");
+        let info = SlotInfo {
+            build_id: build_id(&module_bin),
+            version: "1.0.0".parse().unwrap(),
+            signature: None,
+            security_version: 0,
+        };
+        let slot = slots.install(&module_bin, info).unwrap();
+        assert_eq!(module(&slots, &build_id(&module_bin)).unwrap(), module_bin);
+        assert_eq!(
+            module(&slots, &build_id(&module_bin).to_ascii_uppercase()).unwrap(),
+            module_bin
+        );
+
+        storage.write(slot.object_name(), &wasm(b"v2")).unwrap();
+        assert!(matches!(
+            module(&slots, &build_id(&module_bin)),
+            Err(WSError::NotFound(_))
+        ));
+        assert!(matches!(
+            module(&slots, &build_id(&wasm(b"v2"))),
+            Err(WSError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn unverified_peer_data_is_refused() {
+        let repo = Repository::new(MemoryBackend::new());
+        let module_bin = wasm(b"v1");
+        repo.publish(&module_bin, &"1.0.0".parse().unwrap(), "stable")
+            .unwrap();
+
+        // A peer serving another module of the same size under the build identifier.
+        let requests = Arc::new(Mutex::new(vec![]));
+        let url = serve_fake({
+            let requests = requests.clone();
+            move |request| {
+                requests.lock().unwrap().push(request.path.clone());
+                data_response(request, "application/wasm", wasm(b"v2"))
+            }
+        });
+        let addr = url.strip_prefix("http://").unwrap().parse().unwrap();
+        let transport = PeerTransport::new(LocalTransport::new(repo.clone()))
+            .discovery(false)
+            .peer(addr);
+        let mut config = AgentConfig::new("device", "stable");
+        config.allow_unsigned = true;
+        let agent = Agent::new(
+            config,
+            transport,
+            MemoryStorage::new(),
+            VirtualClock::default(),
+        );
+
+        assert!(matches!(agent.run_once(), Err(WSError::DigestMismatch)));
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [format!(
+                "{}{}.wasm",
+                PEER_PATH_PREFIX,
+                build_id(&module_bin)
+            )]
+        );
+        assert!(agent.slots().state().unwrap().active.is_none());
+
+        // The module is then downloaded from upstream only.
+        assert!(matches!(agent.run_once(), Ok(Outcome::Installed { .. })));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(agent.slots().read_active().unwrap(), module_bin);
+    }
+}
@@ -0,0 +1,164 @@
+use super::*;
+
+use ct_codecs::{Encoder, Hex};
+use hmac_sha256::Hash;
+
+const ARTIFACT_CONTEXT: &[u8] = b"wasi-update/artifact/v1\0";
+
+/// Compiles modules ahead of time for the runtime of the host.
+pub trait Precompiler {
+    /// Return an identifier of the runtime and of its configuration, such as its version.
+    ///
+    /// Artifacts made with a different identifier are not used.
+    fn runtime_id(&self) -> String;
+
+    /// Compile a module, and return the artifact.
+    fn precompile(&self, module_bin: &[u8]) -> Result<Vec<u8>, WSError>;
+}
+
+/// Description of the precompiled artifact stored next to a slot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SidecarInfo {
+    build_id: String,
+    runtime_id: String,
+    /// Hex-encoded SHA-256 digest of the artifact.
+    digest: String,
+    /// Base64-encoded signature of the artifact, if a sidecar key is configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+pub(super) fn sidecar_name(slot: Slot) -> &'static str {
+    match slot {
+        Slot::A => "slot-a.cwasm",
+        Slot::B => "slot-b.cwasm",
+    }
+}
+
+pub(super) fn sidecar_info_name(slot: Slot) -> &'static str {
+    match slot {
+        Slot::A => "slot-a.cwasm.json",
+        Slot::B => "slot-b.cwasm.json",
+    }
+}
+
+fn artifact_message(build_id: &str, runtime_id: &str, digest: &str) -> Result<Vec<u8>, WSError> {
+    let mut message = ARTIFACT_CONTEXT.to_vec();
+    varint::put_slice(&mut message, build_id.as_bytes())?;
+    varint::put_slice(&mut message, runtime_id.as_bytes())?;
+    varint::put_slice(&mut message, digest.as_bytes())?;
+    Ok(message)
+}
+
+impl<T: Transport, S: Storage, C: Clock> Agent<T, S, C> {
+    /// Set the precompiler creating artifacts for installed modules.
+    pub fn set_precompiler(&mut self, precompiler: impl Precompiler + 'static) {
+        self.precompiler = Some(Box::new(precompiler));
+    }
+
+    /// Precompile the module of a slot, and store the artifact next to it.
+    pub fn generate_sidecar(&self, slot: Slot) -> Result<Option<Vec<u8>>, WSError> {
+        let precompiler = match &self.precompiler {
+            None => return Ok(None),
+            Some(precompiler) => precompiler,
+        };
+        let build_id = match self.slots.state()?.info(slot) {
+            None => return Err(WSError::NotFound(format!("module in slot {}", slot))),
+            Some(info) => info.build_id.clone(),
+        };
+        let artifact = precompiler.precompile(&self.slots.read(slot)?)?;
+        let runtime_id = precompiler.runtime_id();
+        let digest = Hex::encode_to_string(Hash::hash(&artifact)).unwrap();
+        let signature = match &self.config.sidecar_key {
+            None => None,
+            Some(sk) => Some(
+                sk.sign(
+                    SignatureKind::Artifact,
+                    &artifact_message(&build_id, &runtime_id, &digest)?,
+                )
+                .to_base64(),
+            ),
+        };
+        let info = SidecarInfo {
+            build_id,
+            runtime_id,
+            digest,
+            signature,
+        };
+        let storage = self.slots.storage();
+        storage.remove(sidecar_info_name(slot))?;
+        storage.write(sidecar_name(slot), &artifact)?;
+        storage.write(sidecar_info_name(slot), &serde_json::to_vec_pretty(&info)?)?;
+        debug!(
+            "Stored a {} bytes artifact for slot {} ({})",
+            artifact.len(),
+            slot,
+            info.runtime_id
+        );
+        Ok(Some(artifact))
+    }
+
+    /// Return the precompiled artifact of the active module.
+    ///
+    /// Artifacts made for another module or another runtime, or whose signature doesn't match
+    /// the sidecar key, are replaced with a new one.
+    pub fn sidecar(&self) -> Result<Option<Vec<u8>>, WSError> {
+        let precompiler = match &self.precompiler {
+            None => return Ok(None),
+            Some(precompiler) => precompiler,
+        };
+        let state = self.slots.state()?;
+        let (slot, slot_info) = match state.active_info() {
+            None => return Ok(None),
+            Some(active) => active,
+        };
+        match self.stored_sidecar(slot, &slot_info.build_id, &precompiler.runtime_id()) {
+            Ok(Some(artifact)) => return Ok(Some(artifact)),
+            Ok(None) => debug!("No valid artifact for slot {}", slot),
+            Err(e) => warn!("Ignoring the artifact of slot {}: {}", slot, e),
+        }
+        self.generate_sidecar(slot)
+    }
+
+    fn stored_sidecar(
+        &self,
+        slot: Slot,
+        build_id: &str,
+        runtime_id: &str,
+    ) -> Result<Option<Vec<u8>>, WSError> {
+        let storage = self.slots.storage();
+        let info: SidecarInfo = match storage.read(sidecar_info_name(slot))? {
+            None => return Ok(None),
+            Some(json) => serde_json::from_slice(&json)?,
+        };
+        if info.build_id != build_id || info.runtime_id != runtime_id {
+            return Ok(None);
+        }
+        let artifact = match storage.read(sidecar_name(slot))? {
+            None => return Ok(None),
+            Some(artifact) => artifact,
+        };
+        if Hex::encode_to_string(Hash::hash(&artifact)).unwrap() != info.digest {
+            return Err(WSError::DigestMismatch);
+        }
+        if let Some(sk) = &self.config.sidecar_key {
+            let signature = info.signature.as_deref().ok_or(WSError::NoSignature)?;
+            sk.public_key().verify(
+                SignatureKind::Artifact,
+                &artifact_message(&info.build_id, &info.runtime_id, &info.digest)?,
+                &Signature::from_base64(signature)?,
+            )?;
+        }
+        Ok(Some(artifact))
+    }
+
+    /// Remove the precompiled artifacts of both slots.
+    pub fn remove_sidecars(&self) -> Result<(), WSError> {
+        let storage = self.slots.storage();
+        for slot in [Slot::A, Slot::B] {
+            storage.remove(sidecar_info_name(slot))?;
+            storage.remove(sidecar_name(slot))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+use super::Storage;
+use crate::error::*;
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub(super) const STATE_NAME: &str = "slots.json";
+
+/// One of the two installation slots.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    /// Return the other slot.
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    /// Return the name of the object storing the module of the slot.
+    pub fn object_name(self) -> &'static str {
+        match self {
+            Slot::A => "slot-a.wasm",
+            Slot::B => "slot-b.wasm",
+        }
+    }
+}
+
+impl std::fmt::Display for Slot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Slot::A => write!(f, "A"),
+            Slot::B => write!(f, "B"),
+        }
+    }
+}
+
+/// Description of the module installed in a slot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlotInfo {
+    pub build_id: String,
+    pub version: Version,
+    /// Base64-encoded signature the module was verified with, if it was signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// Security version declared by the module.
+    #[serde(default)]
+    pub security_version: u64,
+}
+
+/// Persistent state of the slots.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SlotsState {
+    /// The slot the module should be loaded from.
+    pub active: Option<Slot>,
+    /// Content of slot A.
+    pub a: Option<SlotInfo>,
+    /// Content of slot B.
+    pub b: Option<SlotInfo>,
+    /// `true` if the active slot was installed, but not confirmed as working yet.
+    pub pending: bool,
+    /// Security version of the last confirmed module.
+    ///
+    /// Modules with a lower security version are neither installed, unless their downgrade was
+    /// authorized, nor rolled back to.
+    #[serde(default)]
+    pub security_version: u64,
+    /// Logical names of modules, along with the build identifiers they point to.
+    ///
+    /// They are part of the state, so that they are repointed in the same write that switches
+    /// slots.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aliases: BTreeMap<String, String>,
+}
+
+impl SlotsState {
+    /// Return the description of the module installed in a slot.
+    pub fn info(&self, slot: Slot) -> Option<&SlotInfo> {
+        match slot {
+            Slot::A => self.a.as_ref(),
+            Slot::B => self.b.as_ref(),
+        }
+    }
+
+    pub(super) fn set_info(&mut self, slot: Slot, info: Option<SlotInfo>) {
+        match slot {
+            Slot::A => self.a = info,
+            Slot::B => self.b = info,
+        }
+    }
+
+    /// Return the active slot and the description of its module.
+    pub fn active_info(&self) -> Option<(Slot, &SlotInfo)> {
+        let slot = self.active?;
+        Some((slot, self.info(slot)?))
+    }
+}
+
+/// A/B installation slots.
+///
+/// A new module is always written to the inactive slot, and the slots are only switched
+/// once the module has been fully written, so that a failed installation never affects
+/// the running module. The previous module stays available for a rollback.
+#[derive(Debug, Clone)]
+pub struct Slots<S: Storage> {
+    storage: S,
+}
+
+impl<S: Storage> Slots<S> {
+    /// Manage slots on the given storage.
+    pub fn new(storage: S) -> Self {
+        Slots { storage }
+    }
+
+    /// Return the underlying storage.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Return the current state of the slots.
+    pub fn state(&self) -> Result<SlotsState, WSError> {
+        match self.storage.read(STATE_NAME)? {
+            None => Ok(SlotsState::default()),
+            Some(json) => Ok(serde_json::from_slice(&json)?),
+        }
+    }
+
+    /// Replace the state of the slots.
+    ///
+    /// If another module becomes active, the aliases of the previous one are moved to it, in the
+    /// same write.
+    pub(super) fn set_state(&self, state: &SlotsState) -> Result<(), WSError> {
+        let previous = self
+            .state()?
+            .active_info()
+            .map(|(_, info)| info.build_id.clone());
+        let mut state = state.clone();
+        if let (Some(from), Some((_, info))) = (previous, state.active_info()) {
+            if !from.eq_ignore_ascii_case(&info.build_id) {
+                let to = info.build_id.clone();
+                state.repoint_aliases(&from, &to);
+            }
+        }
+        self.storage
+            .write(STATE_NAME, &serde_json::to_vec_pretty(&state)?)
+    }
+
+    /// Return the module stored in a slot.
+    pub fn read(&self, slot: Slot) -> Result<Vec<u8>, WSError> {
+        self.storage
+            .read(slot.object_name())?
+            .ok_or_else(|| WSError::NotFound(slot.object_name().to_string()))
+    }
+
+    /// Return the module of the active slot.
+    pub fn read_active(&self) -> Result<Vec<u8>, WSError> {
+        let slot = self
+            .state()?
+            .active
+            .ok_or_else(|| WSError::NotFound("active slot".to_string()))?;
+        self.read(slot)
+    }
+
+    /// Install a module into the inactive slot, and make it the active one.
+    ///
+    /// The installation remains pending until `confirm()` is called.
+    pub fn install(&self, module_bin: &[u8], info: SlotInfo) -> Result<Slot, WSError> {
+        let mut state = self.state()?;
+        let slot = state.active.map(Slot::other).unwrap_or(Slot::A);
+        self.storage.write(slot.object_name(), module_bin)?;
+        state.set_info(slot, Some(info));
+        state.active = Some(slot);
+        state.pending = true;
+        self.set_state(&state)?;
+        Ok(slot)
+    }
+
+    /// Move a staged object into the inactive slot, and make it the active one.
+    ///
+    /// This is equivalent to `install()`, without requiring space for a second copy of the module.
+    pub fn install_staged(&self, name: &str, info: SlotInfo) -> Result<Slot, WSError> {
+        let mut state = self.state()?;
+        let slot = state.active.map(Slot::other).unwrap_or(Slot::A);
+        self.storage.rename(name, slot.object_name())?;
+        state.set_info(slot, Some(info));
+        state.active = Some(slot);
+        state.pending = true;
+        self.set_state(&state)?;
+        Ok(slot)
+    }
+
+    /// Confirm that the active slot works as expected.
+    ///
+    /// The security version of the device becomes the one of the confirmed module. It can only be
+    /// lower than before if the downgrade was authorized when the module was installed.
+    pub fn confirm(&self) -> Result<(), WSError> {
+        let mut state = self.state()?;
+        if state.pending {
+            state.pending = false;
+            if let Some((_, info)) = state.active_info() {
+                state.security_version = info.security_version;
+            }
+            self.set_state(&state)?;
+        }
+        Ok(())
+    }
+
+    /// Switch back to the previously installed module.
+    ///
+    /// Modules with a lower security version than the one of the device can't be rolled back to.
+    pub fn rollback(&self) -> Result<Slot, WSError> {
+        let mut state = self.state()?;
+        let slot = state
+            .active
+            .map(Slot::other)
+            .ok_or_else(|| WSError::NotFound("active slot".to_string()))?;
+        let info = state
+            .info(slot)
+            .ok_or_else(|| WSError::NotFound(format!("module in slot {}", slot)))?;
+        if info.security_version < state.security_version {
+            return Err(WSError::SecurityDowngrade(format!(
+                "slot {} has security version {}, lower than {}",
+                slot, info.security_version, state.security_version
+            )));
+        }
+        state.active = Some(slot);
+        state.pending = false;
+        self.set_state(&state)?;
+        Ok(slot)
+    }
+}
@@ -0,0 +1,103 @@
+use super::*;
+use crate::state::StateSnapshot;
+
+/// Moves the state of a running module to its update, in the host runtime.
+pub trait StateManager {
+    /// Serialize the state of the running module, in the given format.
+    fn snapshot(&self, format: u32) -> Result<Vec<u8>, WSError>;
+
+    /// Convert a snapshot to another format.
+    ///
+    /// By default, snapshots cannot be converted.
+    fn transform(&self, snapshot: StateSnapshot, format: u32) -> Result<StateSnapshot, WSError> {
+        Err(WSError::StateTransferFailed(format!(
+            "no conversion from state format {} to {}",
+            snapshot.format, format
+        )))
+    }
+
+    /// Restore a snapshot into the active module.
+    fn restore(&self, snapshot: &StateSnapshot) -> Result<(), WSError>;
+}
+
+impl<T: Transport, S: Storage, C: Clock> Agent<T, S, C> {
+    /// Set the manager taking snapshots of the state of modules before they are updated.
+    ///
+    /// Only modules declaring a state format are snapshotted.
+    pub fn set_state_manager(&mut self, state_manager: impl StateManager + 'static) {
+        self.state_manager = Some(Box::new(state_manager));
+    }
+
+    /// Take a snapshot of the state of the active module, if it declares a state format.
+    pub(super) fn snapshot_state(&self) -> Result<Option<StateSnapshot>, WSError> {
+        let state_manager = match &self.state_manager {
+            None => return Ok(None),
+            Some(state_manager) => state_manager,
+        };
+        let installed_bin = match self.slots.read_active() {
+            Ok(installed_bin) => installed_bin,
+            Err(WSError::NotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let format =
+            match Module::deserialize(&mut io::Cursor::new(installed_bin))?.state_format()? {
+                None => return Ok(None),
+                Some(format) => format,
+            };
+        let data = state_manager
+            .snapshot(format)
+            .map_err(|e| WSError::StateTransferFailed(format!("snapshot: {}", e)))?;
+        debug!(
+            "Took a {} bytes snapshot in state format {}",
+            data.len(),
+            format
+        );
+        Ok(Some(StateSnapshot { format, data }))
+    }
+
+    /// Restore a snapshot into the newly installed module, converting it to its state format.
+    ///
+    /// Snapshots are dropped if the new module doesn't declare a state format.
+    pub(super) fn restore_state(
+        &self,
+        snapshot: Option<&StateSnapshot>,
+        format: Option<u32>,
+    ) -> Result<(), WSError> {
+        let (state_manager, snapshot) = match (&self.state_manager, snapshot) {
+            (Some(state_manager), Some(snapshot)) => (state_manager, snapshot),
+            _ => return Ok(()),
+        };
+        let format = match format {
+            None => {
+                warn!("The new module doesn't declare a state format, dropping its previous state");
+                return Ok(());
+            }
+            Some(format) => format,
+        };
+        let converted;
+        let snapshot = if snapshot.format == format {
+            snapshot
+        } else {
+            converted = state_manager.transform(snapshot.clone(), format)?;
+            if converted.format != format {
+                return Err(WSError::StateTransferFailed(format!(
+                    "state converted to format {} instead of {}",
+                    converted.format, format
+                )));
+            }
+            &converted
+        };
+        state_manager
+            .restore(snapshot)
+            .map_err(|e| WSError::StateTransferFailed(format!("restore: {}", e)))
+    }
+
+    /// Give the state back to the previous module after a rollback.
+    pub(super) fn restore_previous_state(&self, snapshot: Option<&StateSnapshot>) {
+        if let (Some(state_manager), Some(snapshot)) = (&self.state_manager, snapshot) {
+            if let Err(e) = state_manager.restore(snapshot) {
+                warn!("Unable to restore the state of the previous module: {}", e);
+            }
+        }
+    }
+}
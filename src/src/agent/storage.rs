@@ -0,0 +1,374 @@
+use crate::error::*;
+
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// Persistent storage used by the agent for downloads and slots.
+///
+/// Objects are identified by flat names. `write` and `rename` must be atomic:
+/// after a crash, an object either has its previous or its new content.
+pub trait Storage {
+    /// Read an object, or return `None` if it doesn't exist.
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>, WSError>;
+
+    /// Return the size of an object, or `None` if it doesn't exist.
+    fn len(&self, name: &str) -> Result<Option<u64>, WSError>;
+
+    /// Atomically replace the content of an object.
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), WSError>;
+
+    /// Append data to an object, creating it if necessary.
+    fn append(&self, name: &str, data: &[u8]) -> Result<(), WSError>;
+
+    /// Atomically rename an object, replacing the destination if it exists.
+    fn rename(&self, from: &str, to: &str) -> Result<(), WSError>;
+
+    /// Remove an object. Removing an object that doesn't exist is not an error.
+    fn remove(&self, name: &str) -> Result<(), WSError>;
+
+    /// Return the number of bytes that can still be stored, or `None` if it is unknown.
+    fn available_space(&self) -> Result<Option<u64>, WSError> {
+        Ok(None)
+    }
+
+    /// Return the names of the stored objects, or `None` if the storage can't list them.
+    fn list(&self) -> Result<Option<Vec<String>>, WSError> {
+        Ok(None)
+    }
+}
+
+/// Storage backed by a directory.
+///
+/// Objects are regular files of the directory. Symbolic links are never followed, so that a link
+/// planted in the directory can't make the agent read or overwrite a file outside of it. The
+/// directory is flushed after objects are created or replaced. On Windows, where renaming over a
+/// file fails while another process has it open, objects are replaced with `ReplaceFileW()`
+/// instead.
+#[derive(Debug, Clone)]
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    /// Use the given directory as storage, creating it if necessary.
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, WSError> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        Ok(FsStorage { root })
+    }
+
+    /// Return the root directory of the storage.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, WSError> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(WSError::InvalidKey(name.to_string()));
+        }
+        Ok(self.root.join(name))
+    }
+
+    /// Make the files created or replaced in the directory survive a power loss.
+    fn sync_root(&self) -> Result<(), WSError> {
+        sync_dir(&self.root)?;
+        Ok(())
+    }
+
+    /// Return the path of an object, refusing objects that are symbolic links.
+    fn object_path(&self, name: &str) -> Result<PathBuf, WSError> {
+        let path = self.path(name)?;
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                Err(WSError::UnsafePath(path.display().to_string()))
+            }
+            Ok(_) => Ok(path),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(path),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Return options to open files with, that don't follow symbolic links. This closes the window
+/// between `object_path()` and opening the file.
+///
+/// On Unix, opening a link fails with `ELOOP`. On Windows, the link itself is opened, and is then
+/// rejected by `open_file()`.
+fn open_options() -> fs::OpenOptions {
+    #[allow(unused_mut)]
+    let mut options = fs::OpenOptions::new();
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::custom_flags(&mut options, libc::O_NOFOLLOW);
+    #[cfg(windows)]
+    std::os::windows::fs::OpenOptionsExt::custom_flags(
+        &mut options,
+        windows_sys::Win32::Storage::FileSystem::FILE_FLAG_OPEN_REPARSE_POINT,
+    );
+    options
+}
+
+/// Open a file with options from `open_options()`, refusing symbolic links.
+fn open_file(options: &fs::OpenOptions, path: &Path) -> Result<fs::File, WSError> {
+    let fp = match options.open(path) {
+        Ok(fp) => fp,
+        #[cfg(unix)]
+        Err(e) if e.raw_os_error() == Some(libc::ELOOP) => {
+            return Err(WSError::UnsafePath(path.display().to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    #[cfg(windows)]
+    if fp.metadata()?.file_type().is_symlink() {
+        return Err(WSError::UnsafePath(path.display().to_string()));
+    }
+    Ok(fp)
+}
+
+/// Flush the entries of a directory, so that files renamed into it survive a power loss.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be flushed on Windows, where `ReplaceFileW()` and `MoveFileExW()` update
+/// the journaled metadata of NTFS before returning.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Atomically replace `to` with `from`.
+#[cfg(not(windows))]
+fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    fs::rename(from, to)
+}
+
+/// Atomically replace `to` with `from`.
+///
+/// `MoveFileExW()`, used by `fs::rename()`, fails when the destination is open, for example
+/// while the runtime has the active module mapped. `ReplaceFileW()` doesn't, but requires the
+/// destination to exist.
+#[cfg(windows)]
+fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Foundation::ERROR_FILE_NOT_FOUND;
+    use windows_sys::Win32::Storage::FileSystem::{
+        ReplaceFileW, REPLACEFILE_IGNORE_ACL_ERRORS, REPLACEFILE_IGNORE_MERGE_ERRORS,
+    };
+
+    let wide =
+        |path: &Path| -> Vec<u16> { path.as_os_str().encode_wide().chain(Some(0)).collect() };
+    let (from_w, to_w) = (wide(from), wide(to));
+    let res = unsafe {
+        ReplaceFileW(
+            to_w.as_ptr(),
+            from_w.as_ptr(),
+            std::ptr::null(),
+            REPLACEFILE_IGNORE_MERGE_ERRORS | REPLACEFILE_IGNORE_ACL_ERRORS,
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    if res != 0 {
+        return Ok(());
+    }
+    let e = std::io::Error::last_os_error();
+    if e.raw_os_error() == Some(ERROR_FILE_NOT_FOUND as i32) {
+        return fs::rename(from, to);
+    }
+    Err(e)
+}
+
+impl Storage for FsStorage {
+    fn read(&self, name: &str) -> Result<Option<Vec<u8>>, WSError> {
+        let mut fp = match open_file(open_options().read(true), &self.object_path(name)?) {
+            Ok(fp) => fp,
+            Err(WSError::IOError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        let mut data = vec![];
+        fp.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn len(&self, name: &str) -> Result<Option<u64>, WSError> {
+        match fs::symlink_metadata(self.object_path(name)?) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), WSError> {
+        let path = self.object_path(name)?;
+        let tmp_path = self.root.join(format!(".{}.tmp", name));
+        let mut fp = open_file(
+            open_options().write(true).create(true).truncate(true),
+            &tmp_path,
+        )?;
+        fp.write_all(data)?;
+        fp.sync_all()?;
+        // Windows can't replace a file with one that is still open.
+        drop(fp);
+        replace_file(&tmp_path, &path)?;
+        self.sync_root()
+    }
+
+    fn append(&self, name: &str, data: &[u8]) -> Result<(), WSError> {
+        let created = self.len(name)?.is_none();
+        let mut fp = open_file(
+            open_options().create(true).append(true),
+            &self.object_path(name)?,
+        )?;
+        fp.write_all(data)?;
+        fp.sync_data()?;
+        if created {
+            self.sync_root()?;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), WSError> {
+        replace_file(&self.object_path(from)?, &self.object_path(to)?)?;
+        self.sync_root()
+    }
+
+    fn remove(&self, name: &str) -> Result<(), WSError> {
+        match fs::remove_file(self.path(name)?) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list(&self) -> Result<Option<Vec<String>>, WSError> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            // Temporary files of interrupted writes are not objects.
+            match entry.file_name().into_string() {
+                Ok(name) if !name.starts_with('.') => names.push(name),
+                _ => {}
+            }
+        }
+        names.sort();
+        Ok(Some(names))
+    }
+
+    #[cfg(unix)]
+    #[allow(clippy::unnecessary_cast)]
+    fn available_space(&self) -> Result<Option<u64>, WSError> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = CString::new(self.root.as_os_str().as_bytes())
+            .map_err(|_| WSError::InvalidKey(self.root.display().to_string()))?;
+        let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+        if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let stat = unsafe { stat.assume_init() };
+        Ok(Some(
+            (stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(name: &str) -> FsStorage {
+        let root = std::env::temp_dir().join(format!(
+            "wasi-update-storage-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&root);
+        FsStorage::new(root).unwrap()
+    }
+
+    #[test]
+    fn names_are_confined_to_the_directory() {
+        let storage = storage("confined");
+        for name in [
+            "",
+            ".",
+            "..",
+            "../escape",
+            "a/b",
+            "a\\b",
+            ".hidden",
+            "/etc/passwd",
+        ] {
+            assert!(
+                matches!(storage.write(name, b"data"), Err(WSError::InvalidKey(_))),
+                "{}",
+                name
+            );
+            assert!(matches!(storage.read(name), Err(WSError::InvalidKey(_))));
+            assert!(matches!(
+                storage.rename("object", name),
+                Err(WSError::InvalidKey(_))
+            ));
+        }
+        assert_eq!(storage.list().unwrap(), Some(vec![]));
+        fs::remove_dir_all(storage.root()).unwrap();
+    }
+
+    #[test]
+    fn writes_replace_objects_atomically() {
+        let storage = storage("atomic");
+        storage.write("object", b"previous").unwrap();
+        // A temporary file left by an interrupted write is neither an object nor an obstacle.
+        fs::write(storage.root().join(".object.tmp"), b"partial").unwrap();
+        assert_eq!(storage.read("object").unwrap().unwrap(), b"previous");
+        assert_eq!(storage.list().unwrap(), Some(vec!["object".to_string()]));
+
+        storage.write("object", b"new").unwrap();
+        assert_eq!(storage.read("object").unwrap().unwrap(), b"new");
+        assert!(!storage.root().join(".object.tmp").exists());
+
+        storage.write("other", b"other").unwrap();
+        storage.rename("other", "object").unwrap();
+        assert_eq!(storage.read("object").unwrap().unwrap(), b"other");
+        assert_eq!(storage.read("other").unwrap(), None);
+        fs::remove_dir_all(storage.root()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symbolic_links_are_refused() {
+        let storage = storage("symlinks");
+        let outside = storage.root().with_extension("outside");
+        fs::write(&outside, b"outside").unwrap();
+        std::os::unix::fs::symlink(&outside, storage.root().join("object")).unwrap();
+        assert!(matches!(
+            storage.read("object"),
+            Err(WSError::UnsafePath(_))
+        ));
+        assert!(matches!(
+            storage.append("object", b"data"),
+            Err(WSError::UnsafePath(_))
+        ));
+        assert!(matches!(
+            storage.write("object", b"data"),
+            Err(WSError::UnsafePath(_))
+        ));
+        // A link planted as the temporary file isn't followed either.
+        std::os::unix::fs::symlink(&outside, storage.root().join(".other.tmp")).unwrap();
+        assert!(matches!(
+            storage.write("other", b"data"),
+            Err(WSError::UnsafePath(_))
+        ));
+        assert_eq!(fs::read(&outside).unwrap(), b"outside");
+        fs::remove_dir_all(storage.root()).unwrap();
+        fs::remove_file(outside).unwrap();
+    }
+}
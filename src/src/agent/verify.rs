@@ -0,0 +1,276 @@
+use crate::error::*;
+use crate::protocol::*;
+
+use ct_codecs::{Decoder, Encoder, Hex};
+use serde::{Deserialize, Serialize};
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+fn compress(h: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 64];
+    for (i, word) in block.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes(word.try_into().unwrap());
+    }
+    for i in 16..64 {
+        let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+        let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+        w[i] = w[i - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[i - 7])
+            .wrapping_add(s1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = *h;
+    for i in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = hh
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(K[i])
+            .wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        hh = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+    for (x, y) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+        *x = x.wrapping_add(y);
+    }
+}
+
+/// The state of a suspended `StreamVerifier`, that can be persisted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifierState {
+    /// Number of bytes verified so far.
+    pub bytes_verified: u64,
+    /// Hex-encoded intermediate hash, covering all the complete blocks.
+    chain: String,
+    /// Hex-encoded data following the last complete block.
+    pending: String,
+}
+
+/// An incremental SHA-256 verifier whose progress can be saved and restored.
+///
+/// This allows an interrupted download to be resumed without hashing again what has
+/// already been received.
+#[derive(Debug, Clone)]
+pub struct StreamVerifier {
+    h: [u32; 8],
+    len: u64,
+    pending: Vec<u8>,
+}
+
+impl Default for StreamVerifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StreamVerifier {
+    /// Create a verifier for a new object.
+    pub fn new() -> Self {
+        StreamVerifier {
+            h: IV,
+            len: 0,
+            pending: Vec::with_capacity(64),
+        }
+    }
+
+    /// Return the number of bytes verified so far.
+    pub fn bytes_verified(&self) -> u64 {
+        self.len
+    }
+
+    /// Add data to the object being verified.
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if !self.pending.is_empty() {
+            let n = data.len().min(64 - self.pending.len());
+            self.pending.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.pending.len() < 64 {
+                return;
+            }
+            compress(&mut self.h, &self.pending);
+            self.pending.clear();
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            compress(&mut self.h, block);
+        }
+        self.pending.extend_from_slice(blocks.remainder());
+    }
+
+    /// Save the current state of the verifier.
+    pub fn suspend(&self) -> VerifierState {
+        let chain: Vec<u8> = self.h.iter().flat_map(|x| x.to_be_bytes()).collect();
+        VerifierState {
+            bytes_verified: self.len,
+            chain: Hex::encode_to_string(chain).unwrap(),
+            pending: Hex::encode_to_string(&self.pending).unwrap(),
+        }
+    }
+
+    /// Restore a verifier from a saved state.
+    pub fn resume(state: &VerifierState) -> Result<Self, WSError> {
+        let chain = Hex::decode_to_vec(&state.chain, None).map_err(|_| WSError::ParseError)?;
+        let pending = Hex::decode_to_vec(&state.pending, None).map_err(|_| WSError::ParseError)?;
+        if chain.len() != 32 || pending.len() as u64 != state.bytes_verified % 64 {
+            return Err(WSError::ParseError);
+        }
+        let mut h = [0u32; 8];
+        for (x, word) in h.iter_mut().zip(chain.chunks_exact(4)) {
+            *x = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        Ok(StreamVerifier {
+            h,
+            len: state.bytes_verified,
+            pending,
+        })
+    }
+
+    /// Return the SHA-256 digest of the object.
+    pub fn finalize(mut self) -> [u8; 32] {
+        let bit_len = self.len.wrapping_mul(8);
+        let mut padding = vec![0x80];
+        padding.resize(1 + (119 - self.len as usize % 64) % 64, 0);
+        padding.extend_from_slice(&bit_len.to_be_bytes());
+        self.update(&padding);
+        debug_assert!(self.pending.is_empty());
+        let mut digest = [0u8; 32];
+        for (out, x) in digest.chunks_exact_mut(4).zip(self.h) {
+            out.copy_from_slice(&x.to_be_bytes());
+        }
+        digest
+    }
+
+    /// Check that the object matches the size and digest of a download.
+    pub fn verify(self, download: &Download) -> Result<(), WSError> {
+        if self.len != download.size {
+            return Err(WSError::VerificationFailed);
+        }
+        let digest = Hex::encode_to_string(self.finalize()).unwrap();
+        if !digest.eq_ignore_ascii_case(&download.sha256) {
+            return Err(WSError::DigestMismatch);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hmac_sha256::Hash;
+
+    fn hex_digest(data: &[u8]) -> String {
+        let mut verifier = StreamVerifier::new();
+        verifier.update(data);
+        Hex::encode_to_string(verifier.finalize()).unwrap()
+    }
+
+    #[test]
+    fn known_answers() {
+        for (message, digest) in [
+            (
+                &b""[..],
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                b"abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+            (
+                b"abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmno\
+                  ijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+                "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1",
+            ),
+        ] {
+            assert_eq!(hex_digest(message), digest);
+        }
+    }
+
+    #[test]
+    fn resumed_verifiers_match_one_shot_hashing() {
+        let data: Vec<u8> = (0..200u32).map(|i| (i * 7 + 3) as u8).collect();
+        // Lengths around the padding boundaries of one and two blocks.
+        for len in (54..=66).chain(118..=130) {
+            let data = &data[..len];
+            for split in 0..=len {
+                let mut verifier = StreamVerifier::new();
+                verifier.update(&data[..split]);
+                let state = serde_json::to_string(&verifier.suspend()).unwrap();
+                let mut verifier =
+                    StreamVerifier::resume(&serde_json::from_str(&state).unwrap()).unwrap();
+                assert_eq!(verifier.bytes_verified(), split as u64);
+                verifier.update(&data[split..]);
+                assert_eq!(
+                    verifier.finalize(),
+                    Hash::hash(data),
+                    "{} at {}",
+                    len,
+                    split
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn inconsistent_states_are_rejected() {
+        let mut verifier = StreamVerifier::new();
+        verifier.update(&[1; 70]);
+        let mut state = verifier.suspend();
+        state.bytes_verified += 1;
+        assert!(StreamVerifier::resume(&state).is_err());
+        let mut state = verifier.suspend();
+        state.chain.truncate(62);
+        assert!(StreamVerifier::resume(&state).is_err());
+    }
+
+    #[test]
+    fn downloads_are_checked() {
+        let data = b"module";
+        let download = |size, sha256: &[u8]| Download {
+            url: "/module".to_string(),
+            size,
+            sha256: Hex::encode_to_string(sha256).unwrap(),
+        };
+        let verifier = || {
+            let mut verifier = StreamVerifier::new();
+            verifier.update(data);
+            verifier
+        };
+        verifier().verify(&download(6, &Hash::hash(data))).unwrap();
+        assert!(matches!(
+            verifier().verify(&download(7, &Hash::hash(data))),
+            Err(WSError::VerificationFailed)
+        ));
+        assert!(matches!(
+            verifier().verify(&download(6, &Hash::hash(b"other!"))),
+            Err(WSError::DigestMismatch)
+        ));
+    }
+}
@@ -0,0 +1,98 @@
+use crate::error::*;
+use crate::wasm_module::*;
+
+use std::io;
+
+/// Name of the custom sections used as padding.
+pub const PADDING_SECTION_NAME: &str = "padding";
+
+/// Size of a serialized section.
+fn serialized_len(section: &Section) -> Result<usize, WSError> {
+    let mut writer = io::Cursor::new(vec![]);
+    section.serialize(&mut writer)?;
+    Ok(writer.into_inner().len())
+}
+
+fn varint_len(v: usize) -> usize {
+    let mut len = 1;
+    let mut v = v >> 7;
+    while v > 0 {
+        len += 1;
+        v >>= 7;
+    }
+    len
+}
+
+/// Return a padding section whose serialized size is exactly `size` bytes, if possible.
+fn padding_section(size: usize) -> Option<Section> {
+    // section id, payload length, name length and name
+    let fixed = 1 + 1 + PADDING_SECTION_NAME.len();
+    (1..=5).find_map(|len_len| {
+        let payload_len = size.checked_sub(fixed + len_len)?;
+        if varint_len(1 + PADDING_SECTION_NAME.len() + payload_len) != len_len {
+            return None;
+        }
+        Some(Section::Custom(CustomSection::new(
+            PADDING_SECTION_NAME.to_string(),
+            vec![0u8; payload_len],
+        )))
+    })
+}
+
+impl Section {
+    /// Return `true` if the section is a padding section.
+    pub fn is_padding(&self) -> bool {
+        matches!(self, Section::Custom(s) if s.name() == PADDING_SECTION_NAME)
+    }
+}
+
+impl Module {
+    /// Return the offset of each section in the serialized module.
+    pub fn section_offsets(&self) -> Result<Vec<usize>, WSError> {
+        let mut offset = self.header.len();
+        let mut offsets = Vec::with_capacity(self.sections.len());
+        for section in &self.sections {
+            offsets.push(offset);
+            offset += serialized_len(section)?;
+        }
+        Ok(offsets)
+    }
+
+    /// Remove all padding sections, and return how many were removed.
+    pub fn remove_padding(&mut self) -> usize {
+        let count = self.sections.len();
+        self.sections.retain(|section| !section.is_padding());
+        count - self.sections.len()
+    }
+
+    /// Insert padding sections so that every other section starts at a multiple of `alignment`.
+    ///
+    /// Padding sections are regular custom sections filled with zeros, so they are covered
+    /// by hashes and signatures like any other section. Previous padding is replaced.
+    /// An `alignment` of `0` or `1` only removes the padding.
+    pub fn align_sections(&mut self, alignment: usize) -> Result<(), WSError> {
+        self.remove_padding();
+        if alignment <= 1 {
+            return Ok(());
+        }
+        let mut sections = Vec::with_capacity(self.sections.len() * 2);
+        let mut offset = self.header.len();
+        for section in self.sections.drain(..) {
+            let mut gap = (alignment - offset % alignment) % alignment;
+            if gap > 0 {
+                let padding = loop {
+                    if let Some(padding) = padding_section(gap) {
+                        break padding;
+                    }
+                    gap += alignment;
+                };
+                sections.push(padding);
+                offset += gap;
+            }
+            offset += serialized_len(&section)?;
+            sections.push(section);
+        }
+        self.sections = sections;
+        Ok(())
+    }
+}
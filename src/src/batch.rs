@@ -0,0 +1,329 @@
+//! Operations applied to every module of a directory tree.
+//!
+//! Detached signatures are stored next to the modules they cover, with `SIGNATURE_FILE_SUFFIX` appended
+//! to the file name.
+
+use crate::error::*;
+use crate::memory;
+use crate::signature::*;
+use crate::wasm_module::*;
+
+use hmac_sha256::Hash;
+use log::*;
+use std::fs;
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Suffix added to the name of a module to get the name of its detached signature.
+pub const SIGNATURE_FILE_SUFFIX: &str = ".sig";
+
+/// Size of the chunks modules are read in when verifying them.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// An operation applied to every module by `Batch::run()`.
+#[derive(Debug, Clone)]
+pub enum BatchOperation {
+    /// Verify the detached signature of every module.
+    Verify(KeyRing),
+    /// Sign every module, replacing existing detached signatures.
+    Resign(SecretKey),
+    /// Remove custom sections. If the list is empty, all custom sections are removed.
+    Strip(IgnoreList),
+    /// Decompress, then compress again the given custom sections, as well as the ones that were already
+    /// compressed.
+    #[cfg(feature = "compression")]
+    Recompress(Vec<String>),
+}
+
+/// A module processed by `Batch::run()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchResult {
+    pub path: PathBuf,
+    /// `true` if the module or its signature was rewritten.
+    pub modified: bool,
+}
+
+/// The result of `Batch::run()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchReport {
+    pub processed: Vec<BatchResult>,
+    /// Modules that couldn't be processed, along with the reason.
+    pub failures: Vec<(PathBuf, String)>,
+}
+
+impl BatchReport {
+    /// Return the number of modules that were rewritten.
+    pub fn modified(&self) -> usize {
+        self.processed
+            .iter()
+            .filter(|result| result.modified)
+            .count()
+    }
+}
+
+/// A set of modules, found by recursively walking a directory.
+///
+/// Hidden files and directories are skipped, and symbolic links are not followed.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    root: PathBuf,
+    extension: String,
+    jobs: usize,
+}
+
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), WSError> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = parent.join(format!(".{}.tmp", file_name));
+    let mut fp = fs::File::create(&tmp_path)?;
+    fp.write_all(data)?;
+    fp.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn signature_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(SIGNATURE_FILE_SUFFIX);
+    path.with_file_name(file_name)
+}
+
+fn rewrite(path: &Path, module_bin: &[u8], module: &Module) -> Result<bool, WSError> {
+    let mut new_module_bin = vec![];
+    module.serialize(&mut new_module_bin)?;
+    if new_module_bin == module_bin {
+        return Ok(false);
+    }
+    write_atomically(path, &new_module_bin)?;
+    Ok(true)
+}
+
+fn read_signature(path: &Path) -> Result<Signature, WSError> {
+    let signature_bin = fs::read(signature_path(path)).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => WSError::NotFound(format!("signature of {}", path.display())),
+        _ => e.into(),
+    })?;
+    Signature::from_bytes(&signature_bin)
+}
+
+/// A reader hashing the data it reads.
+struct HashingReader<R: Read> {
+    reader: R,
+    hash: Hash,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.hash.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Verify the detached signature of a module, checking its structure and hashing it as it is read.
+fn verify(path: &Path, key_ring: &KeyRing) -> Result<KeyId, WSError> {
+    let file = fs::File::open(path)?;
+    let signature = read_signature(path)?;
+    let mut reader = HashingReader {
+        reader: io::BufReader::with_capacity(READ_CHUNK_SIZE, file),
+        hash: Hash::new(),
+    };
+    Module::stream_init(&mut reader)?;
+    let mut stream = Module::stream_payloads(&mut reader);
+    while stream.next_section()?.is_some() {}
+    key_ring.verify_module_digest(&reader.hash.finalize(), &signature)?;
+    Ok(signature.key_id)
+}
+
+fn load(path: &Path) -> Result<(Vec<u8>, Module), WSError> {
+    let module_bin = fs::read(path)?;
+    let module = Module::deserialize(&mut io::Cursor::new(&module_bin))?;
+    Ok((module_bin, module))
+}
+
+fn apply(path: &Path, operation: &BatchOperation) -> Result<bool, WSError> {
+    match operation {
+        BatchOperation::Verify(key_ring) => verify(path, key_ring).map(|_| false),
+        BatchOperation::Resign(sk) => {
+            let (module_bin, _) = load(path)?;
+            let signature_bin = sk.sign_module(&module_bin).to_bytes();
+            let signature_path = signature_path(path);
+            if fs::read(&signature_path).ok().as_ref() == Some(&signature_bin) {
+                return Ok(false);
+            }
+            write_atomically(&signature_path, &signature_bin)?;
+            Ok(true)
+        }
+        BatchOperation::Strip(sections) => {
+            let (module_bin, mut module) = load(path)?;
+            module.sections.retain(|section| match section {
+                Section::Custom(_) => !sections.is_empty() && !sections.ignores(section),
+                Section::Standard(_) => true,
+            });
+            rewrite(path, &module_bin, &module)
+        }
+        #[cfg(feature = "compression")]
+        BatchOperation::Recompress(names) => {
+            use crate::compression::COMPRESSED_SECTION_SUFFIX;
+            let (module_bin, mut module) = load(path)?;
+            let mut names = names.clone();
+            for section in &module.sections {
+                if let Section::Custom(s) = section {
+                    if let Some(name) = s.name().strip_suffix(COMPRESSED_SECTION_SUFFIX) {
+                        names.push(name.to_string());
+                    }
+                }
+            }
+            module.decompress_sections()?;
+            module.compress_sections(&names)?;
+            rewrite(path, &module_bin, &module)
+        }
+    }
+}
+
+impl Batch {
+    /// Create a batch of all the `.wasm` files under `root`, processed by as many threads as there are CPUs.
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Batch {
+            root: root.as_ref().to_path_buf(),
+            extension: "wasm".to_string(),
+            jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+
+    /// Only process files with the given extension.
+    pub fn extension(mut self, extension: impl Into<String>) -> Self {
+        self.extension = extension.into();
+        self
+    }
+
+    /// Set the number of modules processed in parallel.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    fn walk(&self, dir: &Path, paths: &mut Vec<PathBuf>) -> Result<(), WSError> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                self.walk(&path, paths)?;
+            } else if file_type.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|ext| ext == self.extension.as_str())
+            {
+                paths.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the paths of the modules, sorted.
+    pub fn modules(&self) -> Result<Vec<PathBuf>, WSError> {
+        let mut paths = vec![];
+        self.walk(&self.root, &mut paths)?;
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Apply an operation to every module.
+    ///
+    /// Modules are only rewritten if the operation changes them. Modules that fail to be processed are
+    /// listed in the report, and don't stop the other ones from being processed.
+    /// Stripping or recompressing modules invalidates their signatures, which have to be created again.
+    /// Under a memory limit, each job gets an equal share of it.
+    pub fn run(&self, operation: &BatchOperation) -> Result<BatchReport, WSError> {
+        let paths = self.modules()?;
+        let results = run_jobs(&paths, self.jobs, |path| apply(path, operation));
+
+        let mut report = BatchReport::default();
+        for (path, res) in paths.into_iter().zip(results) {
+            match res {
+                Ok(modified) => {
+                    if modified {
+                        debug!("Rewrote {}", path.display());
+                    }
+                    report.processed.push(BatchResult { path, modified });
+                }
+                Err(e) => {
+                    warn!("Unable to process {}: {}", path.display(), e);
+                    report.failures.push((path, e.to_string()));
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Apply `f` to every path with `jobs` threads, and return the results in the same order.
+///
+/// Under a memory limit, each job gets an equal share of it.
+fn run_jobs<P: AsRef<Path> + Sync, T: Send>(
+    paths: &[P],
+    jobs: usize,
+    f: impl Fn(&Path) -> T + Sync,
+) -> Vec<T> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(paths.len()));
+    let jobs = jobs.clamp(1, paths.len().max(1));
+    let limit = memory::memory_limit().map(|limit| limit / jobs as u64);
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = paths.get(index) else {
+                    break;
+                };
+                let res = match limit {
+                    Some(limit) => memory::with_memory_limit(limit, || f(path.as_ref())),
+                    None => f(path.as_ref()),
+                };
+                results
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push((index, res));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap_or_else(|e| e.into_inner());
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, res)| res).collect()
+}
+
+/// The outcome of verifying a module with `verify_all()`.
+#[derive(Debug)]
+pub struct VerificationOutcome {
+    pub path: PathBuf,
+    /// The identifier of the key that signed the module, or why it couldn't be verified.
+    pub result: Result<KeyId, WSError>,
+}
+
+/// Verify the detached signatures of modules with `parallelism` threads, and return the outcome
+/// for each of them, in the same order.
+///
+/// Modules are streamed from disk, so that memory use doesn't depend on their size. Failures
+/// don't stop the other modules from being verified.
+pub fn verify_all(
+    paths: &[impl AsRef<Path> + Sync],
+    keys: &KeyRing,
+    parallelism: usize,
+) -> Vec<VerificationOutcome> {
+    let results = run_jobs(paths, parallelism, |path| verify(path, keys));
+    paths
+        .iter()
+        .zip(results)
+        .map(|(path, result)| VerificationOutcome {
+            path: path.as_ref().to_path_buf(),
+            result,
+        })
+        .collect()
+}
@@ -0,0 +1,465 @@
+//! Bundles of the modules making up a release.
+//!
+//! A bundle is a single file holding a manifest and every module of a release, so that releases
+//! made of multiple modules can be shipped, archived and audited as a unit. The manifest lists
+//! the name, build identifier and size of each module, along with its detached signature if the
+//! bundle was signed.
+//!
+//! Bundles are created from a TOML description of the release, whose relative paths are
+//! resolved against the directory of the description:
+//!
+//! ```toml
+//! name = "gateway"
+//! version = "1.4.0"
+//!
+//! [[artifacts]]
+//! name = "app"
+//! file = "target/app.wasm"
+//!
+//! [[artifacts]]
+//! name = "plugin-modbus"
+//! file = "target/plugin-modbus.wasm"
+//! ```
+//!
+//! Members can have a version, and require versions of other members, such as a plugin
+//! requiring `host-api >= 1.2`. An artifact can be listed several times with different versions:
+//! the most recent versions satisfying every requirement are then selected when the bundle is
+//! created.
+//!
+//! ```toml
+//! [[artifacts]]
+//! name = "plugin-modbus"
+//! file = "target/plugin-modbus.wasm"
+//! version = "2.0.1"
+//! requires = { host-api = ">=1.2, <2" }
+//! ```
+//!
+//! A bundle file starts with `BUNDLE_MAGIC` and a version byte, followed by the JSON manifest
+//! and the modules, as length-prefixed byte strings.
+
+use crate::batch::SIGNATURE_FILE_SUFFIX;
+use crate::error::*;
+use crate::repo::{build_id, validate_name};
+use crate::signature::{KeyRing, SecretKey, Signature};
+use crate::wasm_module::*;
+
+use log::*;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+/// Magic bytes bundle files start with.
+pub const BUNDLE_MAGIC: &[u8; 4] = b"WUBN";
+const BUNDLE_VERSION: u8 = 0x01;
+
+/// Name of the manifest written next to extracted modules.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// A module of a bundle, as described by its manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleArtifact {
+    /// Name of the artifact, unique within the bundle.
+    pub name: String,
+    pub build_id: String,
+    /// Size of the module, in bytes.
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<Version>,
+    /// Versions of other artifacts of the bundle this one requires, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub requires: BTreeMap<String, VersionReq>,
+    /// Base64-encoded detached signature of the module.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// The description of the content of a bundle.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleManifest {
+    /// Name of the release.
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<Version>,
+    pub artifacts: Vec<BundleArtifact>,
+}
+
+impl BundleManifest {
+    /// Check that the requirements of every artifact are satisfied by the other artifacts.
+    pub fn check_requirements(&self) -> Result<(), WSError> {
+        let members: Vec<Member> = self.artifacts.iter().map(Member::from).collect();
+        match unsatisfied(&members) {
+            Some(reason) => Err(WSError::UnsatisfiedRequirement(reason)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The name, version and requirements of an artifact, be it in a bundle or in its description.
+#[derive(Clone, Copy)]
+struct Member<'t> {
+    name: &'t str,
+    version: Option<&'t Version>,
+    requires: &'t BTreeMap<String, VersionReq>,
+}
+
+impl<'t> From<&'t BundleArtifact> for Member<'t> {
+    fn from(artifact: &'t BundleArtifact) -> Self {
+        Member {
+            name: &artifact.name,
+            version: artifact.version.as_ref(),
+            requires: &artifact.requires,
+        }
+    }
+}
+
+impl<'t> From<&'t ArtifactSpec> for Member<'t> {
+    fn from(artifact: &'t ArtifactSpec) -> Self {
+        Member {
+            name: &artifact.name,
+            version: artifact.version.as_ref(),
+            requires: &artifact.requires,
+        }
+    }
+}
+
+/// Return why `member` can't be used along with `dependency`, if it requires it.
+fn conflict(member: &Member, dependency: &Member) -> Option<String> {
+    let req = member.requires.get(dependency.name)?;
+    match dependency.version {
+        Some(version) if req.matches(version) => None,
+        Some(version) => Some(format!(
+            "[{}] requires [{}] {}, but its version is {}",
+            member.name, dependency.name, req, version
+        )),
+        None => Some(format!(
+            "[{}] requires [{}] {}, but it has no version",
+            member.name, dependency.name, req
+        )),
+    }
+}
+
+/// Return the first requirement that a set of members doesn't satisfy, if any.
+fn unsatisfied(members: &[Member]) -> Option<String> {
+    members.iter().find_map(|member| {
+        member.requires.keys().find_map(|name| {
+            match members.iter().find(|dependency| dependency.name == name) {
+                Some(dependency) => conflict(member, dependency),
+                None => Some(format!(
+                    "[{}] requires [{}], which is not in the bundle",
+                    member.name, name
+                )),
+            }
+        })
+    })
+}
+
+/// Select a candidate for every member, starting with `selected.len()`, so that every
+/// requirement is satisfied. Candidates are tried in order.
+fn select<'t>(candidates: &[Vec<Member<'t>>], selected: &mut Vec<Member<'t>>) -> bool {
+    let Some(member_candidates) = candidates.get(selected.len()) else {
+        return true;
+    };
+    for candidate in member_candidates {
+        if selected.iter().any(|member| {
+            conflict(member, candidate).is_some() || conflict(candidate, member).is_some()
+        }) {
+            continue;
+        }
+        selected.push(*candidate);
+        if select(candidates, selected) {
+            return true;
+        }
+        selected.pop();
+    }
+    false
+}
+
+/// A set of modules, along with their manifest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bundle {
+    pub manifest: BundleManifest,
+    /// The modules, in the order of the manifest.
+    pub modules: Vec<Vec<u8>>,
+}
+
+impl Bundle {
+    /// Create an empty bundle.
+    pub fn new(name: impl Into<String>) -> Self {
+        Bundle {
+            manifest: BundleManifest {
+                name: name.into(),
+                ..Default::default()
+            },
+            modules: vec![],
+        }
+    }
+
+    /// Set the version of the release.
+    pub fn version(mut self, version: Version) -> Self {
+        self.manifest.version = Some(version);
+        self
+    }
+
+    /// Add a module to the bundle.
+    ///
+    /// Names must be distinct, and suitable as file names.
+    pub fn add(&mut self, name: impl Into<String>, module_bin: Vec<u8>) -> Result<(), WSError> {
+        let name = name.into();
+        validate_name(&name)?;
+        if self.artifact(&name).is_some() {
+            return Err(WSError::InvalidBundle(format!(
+                "artifact [{}] is listed twice",
+                name
+            )));
+        }
+        Module::deserialize(&mut io::Cursor::new(&module_bin))?;
+        self.manifest.artifacts.push(BundleArtifact {
+            name,
+            build_id: build_id(&module_bin),
+            size: module_bin.len() as u64,
+            version: None,
+            requires: BTreeMap::new(),
+            signature: None,
+        });
+        self.modules.push(module_bin);
+        Ok(())
+    }
+
+    /// Sign every module of the bundle.
+    pub fn sign(&mut self, sk: &SecretKey) {
+        for (artifact, module_bin) in self.manifest.artifacts.iter_mut().zip(&self.modules) {
+            artifact.signature = Some(sk.sign_module(module_bin).to_base64());
+        }
+    }
+
+    /// Return an artifact and its module, given its name.
+    pub fn artifact(&self, name: &str) -> Option<(&BundleArtifact, &[u8])> {
+        self.manifest
+            .artifacts
+            .iter()
+            .zip(&self.modules)
+            .find(|(artifact, _)| artifact.name == name)
+            .map(|(artifact, module_bin)| (artifact, module_bin.as_slice()))
+    }
+
+    /// Check that every module matches the manifest and is a valid module, and, if a key ring
+    /// is given, that every module has a valid signature from a trusted key.
+    pub fn verify(&self, key_ring: Option<&KeyRing>) -> Result<(), WSError> {
+        if self.modules.len() != self.manifest.artifacts.len() {
+            return Err(WSError::InvalidBundle(format!(
+                "the manifest lists {} artifacts, but the bundle has {} modules",
+                self.manifest.artifacts.len(),
+                self.modules.len()
+            )));
+        }
+        let mut names = HashSet::new();
+        for (artifact, module_bin) in self.manifest.artifacts.iter().zip(&self.modules) {
+            validate_name(&artifact.name)?;
+            if !names.insert(&artifact.name) {
+                return Err(WSError::InvalidBundle(format!(
+                    "artifact [{}] is listed twice",
+                    artifact.name
+                )));
+            }
+            if module_bin.len() as u64 != artifact.size {
+                return Err(WSError::InvalidBundle(format!(
+                    "artifact [{}] has {} bytes instead of {}",
+                    artifact.name,
+                    module_bin.len(),
+                    artifact.size
+                )));
+            }
+            if !build_id(module_bin).eq_ignore_ascii_case(&artifact.build_id) {
+                return Err(WSError::DigestMismatch);
+            }
+            Module::deserialize(&mut io::Cursor::new(module_bin))?;
+            if let Some(key_ring) = key_ring {
+                let signature = artifact.signature.as_ref().ok_or(WSError::NoSignature)?;
+                key_ring.verify_module(module_bin, &Signature::from_base64(signature)?)?;
+            }
+            debug!("Verified artifact [{}]", artifact.name);
+        }
+        self.manifest.check_requirements()
+    }
+
+    /// Serialize the bundle.
+    pub fn serialize(&self, writer: &mut impl Write) -> Result<(), WSError> {
+        writer.write_all(BUNDLE_MAGIC)?;
+        writer.write_all(&[BUNDLE_VERSION])?;
+        varint::put_slice(writer, serde_json::to_vec(&self.manifest)?)?;
+        for module_bin in &self.modules {
+            varint::put_slice(writer, module_bin)?;
+        }
+        Ok(())
+    }
+
+    /// Deserialize a bundle.
+    ///
+    /// The modules are not checked against the manifest: use `verify()` for that.
+    pub fn deserialize(reader: &mut impl Read) -> Result<Self, WSError> {
+        let mut header = [0u8; 5];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != BUNDLE_MAGIC || header[4] != BUNDLE_VERSION {
+            return Err(WSError::ParseError);
+        }
+        let manifest: BundleManifest = serde_json::from_slice(&varint::get_slice(reader)?)?;
+        let mut modules = Vec::with_capacity(manifest.artifacts.len());
+        for _ in &manifest.artifacts {
+            modules.push(varint::get_slice(reader)?);
+        }
+        if reader.read(&mut [0u8; 1])? != 0 {
+            return Err(WSError::ParseError);
+        }
+        Ok(Bundle { manifest, modules })
+    }
+
+    /// Serialize the bundle to a file.
+    pub fn serialize_to_file(&self, file: impl AsRef<Path>) -> Result<(), WSError> {
+        let mut writer = io::BufWriter::new(fs::File::create(file.as_ref())?);
+        self.serialize(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Deserialize a bundle from a file.
+    pub fn deserialize_from_file(file: impl AsRef<Path>) -> Result<Self, WSError> {
+        Self::deserialize(&mut io::BufReader::new(fs::File::open(file.as_ref())?))
+    }
+
+    /// Write every module to `<name>.wasm` in a directory, along with its signature in
+    /// `<name>.wasm.sig` if it has one, and the manifest in `manifest.json`.
+    pub fn extract(&self, dir: impl AsRef<Path>) -> Result<(), WSError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        for (artifact, module_bin) in self.manifest.artifacts.iter().zip(&self.modules) {
+            validate_name(&artifact.name)?;
+            let file = dir.join(format!("{}.wasm", artifact.name));
+            fs::write(&file, module_bin)?;
+            if let Some(signature) = &artifact.signature {
+                let mut signature_file = file.into_os_string();
+                signature_file.push(SIGNATURE_FILE_SUFFIX);
+                fs::write(
+                    signature_file,
+                    Signature::from_base64(signature)?.to_bytes(),
+                )?;
+            }
+        }
+        fs::write(
+            dir.join(MANIFEST_FILE_NAME),
+            serde_json::to_vec_pretty(&self.manifest)?,
+        )?;
+        Ok(())
+    }
+}
+
+/// A module to add to a bundle.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArtifactSpec {
+    pub name: String,
+    pub file: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<Version>,
+    /// Versions of other artifacts of the bundle this one requires, by name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub requires: BTreeMap<String, VersionReq>,
+}
+
+/// The description of a release to bundle.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BundleSpec {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<Version>,
+    pub artifacts: Vec<ArtifactSpec>,
+}
+
+impl BundleSpec {
+    /// Parse a description.
+    pub fn from_toml(toml: &str) -> Result<Self, WSError> {
+        toml::from_str(toml).map_err(|e| WSError::ConfigError(e.message().to_string()))
+    }
+
+    /// Load a description, resolving the paths of the modules against its directory.
+    pub fn load(file: impl AsRef<Path>) -> Result<Self, WSError> {
+        let file = file.as_ref();
+        let mut spec = Self::from_toml(&fs::read_to_string(file)?)?;
+        if let Some(dir) = file.parent() {
+            for artifact in &mut spec.artifacts {
+                artifact.file = dir.join(&artifact.file);
+            }
+        }
+        Ok(spec)
+    }
+
+    /// Select one version of every artifact, so that the requirements of every selected
+    /// artifact are satisfied.
+    ///
+    /// Artifacts listed once are always selected. For artifacts listed with several versions,
+    /// the most recent versions are preferred, in the order of the description.
+    pub fn resolve(&self) -> Result<Vec<&ArtifactSpec>, WSError> {
+        let mut indices: HashMap<&str, usize> = HashMap::new();
+        let mut candidates: Vec<Vec<Member>> = vec![];
+        for artifact in &self.artifacts {
+            let index = *indices.entry(&artifact.name).or_insert_with(|| {
+                candidates.push(vec![]);
+                candidates.len() - 1
+            });
+            candidates[index].push(Member::from(artifact));
+        }
+        for member_candidates in &mut candidates {
+            if member_candidates.len() < 2 {
+                continue;
+            }
+            let mut versions = HashSet::new();
+            for candidate in member_candidates.iter() {
+                if !candidate
+                    .version
+                    .is_some_and(|version| versions.insert(version))
+                {
+                    return Err(WSError::InvalidBundle(format!(
+                        "artifact [{}] is listed twice without distinct versions",
+                        candidate.name
+                    )));
+                }
+            }
+            member_candidates.sort_by(|a, b| b.version.cmp(&a.version));
+        }
+
+        let mut selected = vec![];
+        if !select(&candidates, &mut selected) {
+            let latest: Vec<Member> = candidates.iter().map(|c| c[0]).collect();
+            let reason = unsatisfied(&latest).unwrap_or_else(|| {
+                "no combination of versions satisfies every requirement".to_string()
+            });
+            return Err(WSError::UnsatisfiedRequirement(reason));
+        }
+        Ok(selected
+            .iter()
+            .filter_map(|member| {
+                self.artifacts.iter().find(|artifact| {
+                    artifact.name == member.name && artifact.version.as_ref() == member.version
+                })
+            })
+            .collect())
+    }
+
+    /// Read the modules of the selected versions, and create the bundle.
+    pub fn bundle(&self) -> Result<Bundle, WSError> {
+        let mut bundle = Bundle::new(&self.name);
+        if let Some(version) = &self.version {
+            bundle = bundle.version(version.clone());
+        }
+        for artifact in self.resolve()? {
+            bundle.add(&artifact.name, fs::read(&artifact.file)?)?;
+            let added = bundle.manifest.artifacts.last_mut().unwrap();
+            added.version = artifact.version.clone();
+            added.requires = artifact.requires.clone();
+        }
+        Ok(bundle)
+    }
+}
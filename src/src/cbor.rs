@@ -0,0 +1,489 @@
+use crate::error::*;
+
+const MAX_DEPTH: usize = 64;
+
+/// A CBOR data item.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// An unsigned integer.
+    Unsigned(u64),
+    /// A negative integer, stored as `-1 - n`.
+    Negative(u64),
+    Bytes(Vec<u8>),
+    Text(String),
+    Array(Vec<Value>),
+    /// A map, whose entries are kept in order.
+    Map(Vec<(Value, Value)>),
+    Tag(u64, Box<Value>),
+    Bool(bool),
+    Null,
+    Float(f64),
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        if v >= 0 {
+            Value::Unsigned(v as u64)
+        } else {
+            Value::Negative(!(v as u64))
+        }
+    }
+}
+
+fn put_head(out: &mut Vec<u8>, major: u8, v: u64) {
+    let major = major << 5;
+    match v {
+        0..=23 => out.push(major | v as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, v as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(v as u16).to_be_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(v as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+}
+
+fn f16_to_f64(bits: u16) -> f64 {
+    let exponent = (bits >> 10) & 0x1f;
+    let mantissa = (bits & 0x3ff) as f64;
+    let v = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f64.powi(exponent as i32 - 15),
+    };
+    if bits & 0x8000 != 0 {
+        -v
+    } else {
+        v
+    }
+}
+
+struct Decoder<'t> {
+    input: &'t [u8],
+}
+
+impl<'t> Decoder<'t> {
+    fn take(&mut self, len: usize) -> Result<&'t [u8], WSError> {
+        if self.input.len() < len {
+            return Err(WSError::Eof);
+        }
+        let (head, tail) = self.input.split_at(len);
+        self.input = tail;
+        Ok(head)
+    }
+
+    fn head(&mut self) -> Result<(u8, u8, u64), WSError> {
+        let initial = self.take(1)?[0];
+        let (major, info) = (initial >> 5, initial & 0x1f);
+        let v = match info {
+            0..=23 => info as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes(self.take(2)?.try_into().unwrap()) as u64,
+            26 => u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64,
+            27 => u64::from_be_bytes(self.take(8)?.try_into().unwrap()),
+            _ => return Err(WSError::ParseError),
+        };
+        Ok((major, info, v))
+    }
+
+    fn len(&self, v: u64) -> Result<usize, WSError> {
+        if v > self.input.len() as u64 {
+            return Err(WSError::Eof);
+        }
+        Ok(v as usize)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, WSError> {
+        if depth > MAX_DEPTH {
+            return Err(WSError::ParseError);
+        }
+        let (major, info, v) = self.head()?;
+        let value = match major {
+            0 => Value::Unsigned(v),
+            1 => Value::Negative(v),
+            2 => Value::Bytes(self.take(self.len(v)?)?.to_vec()),
+            3 => Value::Text(std::str::from_utf8(self.take(self.len(v)?)?)?.to_string()),
+            4 => {
+                let count = self.len(v)?;
+                let mut items = Vec::with_capacity(count);
+                for _ in 0..count {
+                    items.push(self.value(depth + 1)?);
+                }
+                Value::Array(items)
+            }
+            5 => {
+                let count = self.len(v)?;
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key = self.value(depth + 1)?;
+                    entries.push((key, self.value(depth + 1)?));
+                }
+                Value::Map(entries)
+            }
+            6 => Value::Tag(v, Box::new(self.value(depth + 1)?)),
+            _ => match (info, v) {
+                (20, _) => Value::Bool(false),
+                (21, _) => Value::Bool(true),
+                (22, _) => Value::Null,
+                (25, bits) => Value::Float(f16_to_f64(bits as u16)),
+                (26, bits) => Value::Float(f32::from_bits(bits as u32) as f64),
+                (27, bits) => Value::Float(f64::from_bits(bits)),
+                _ => return Err(WSError::ParseError),
+            },
+        };
+        Ok(value)
+    }
+}
+
+impl Value {
+    /// Encode the value. Definite lengths and the shortest integer encodings are always used.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Unsigned(v) => put_head(out, 0, *v),
+            Value::Negative(v) => put_head(out, 1, *v),
+            Value::Bytes(bytes) => {
+                put_head(out, 2, bytes.len() as _);
+                out.extend_from_slice(bytes);
+            }
+            Value::Text(text) => {
+                put_head(out, 3, text.len() as _);
+                out.extend_from_slice(text.as_bytes());
+            }
+            Value::Array(items) => {
+                put_head(out, 4, items.len() as _);
+                for item in items {
+                    item.encode_into(out);
+                }
+            }
+            Value::Map(entries) => {
+                put_head(out, 5, entries.len() as _);
+                for (key, value) in entries {
+                    key.encode_into(out);
+                    value.encode_into(out);
+                }
+            }
+            Value::Tag(tag, value) => {
+                put_head(out, 6, *tag);
+                value.encode_into(out);
+            }
+            Value::Bool(false) => out.push(0xf4),
+            Value::Bool(true) => out.push(0xf5),
+            Value::Null => out.push(0xf6),
+            Value::Float(v) => {
+                out.push(0xfb);
+                out.extend_from_slice(&v.to_bits().to_be_bytes());
+            }
+        }
+    }
+
+    /// Decode a single value, that must span the entire input.
+    pub fn decode(bytes: &[u8]) -> Result<Self, WSError> {
+        let mut decoder = Decoder { input: bytes };
+        let value = decoder.value(0)?;
+        if !decoder.input.is_empty() {
+            return Err(WSError::ParseError);
+        }
+        Ok(value)
+    }
+
+    /// Return the value of a map entry.
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Return the content of a byte string.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Return the content of a text string.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// Return the value of an integer, if it fits in an `i64`.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Unsigned(v) => i64::try_from(*v).ok(),
+            Value::Negative(v) => i64::try_from(*v).ok().map(|v| -1 - v),
+            _ => None,
+        }
+    }
+
+    /// Return the items of an array.
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// Convert a JSON value. Objects become maps with text keys.
+    pub fn from_json(json: &serde_json::Value) -> Self {
+        match json {
+            serde_json::Value::Null => Value::Null,
+            serde_json::Value::Bool(v) => Value::Bool(*v),
+            serde_json::Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+                (Some(v), _) => Value::Unsigned(v),
+                (None, Some(v)) => Value::from(v),
+                _ => Value::Float(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            serde_json::Value::String(s) => Value::Text(s.clone()),
+            serde_json::Value::Array(items) => {
+                Value::Array(items.iter().map(Self::from_json).collect())
+            }
+            serde_json::Value::Object(entries) => Value::Map(
+                entries
+                    .iter()
+                    .map(|(k, v)| (Value::Text(k.clone()), Self::from_json(v)))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Convert the value to JSON. Byte strings, tags and non-text map keys are not supported.
+    pub fn to_json(&self) -> Result<serde_json::Value, WSError> {
+        let json = match self {
+            Value::Null => serde_json::Value::Null,
+            Value::Bool(v) => serde_json::Value::Bool(*v),
+            Value::Unsigned(v) => serde_json::Value::from(*v),
+            Value::Negative(_) => {
+                serde_json::Value::from(self.as_i64().ok_or(WSError::ParseError)?)
+            }
+            Value::Float(v) => serde_json::Number::from_f64(*v)
+                .map(serde_json::Value::Number)
+                .ok_or(WSError::ParseError)?,
+            Value::Text(s) => serde_json::Value::String(s.clone()),
+            Value::Array(items) => {
+                serde_json::Value::Array(items.iter().map(Self::to_json).collect::<Result<_, _>>()?)
+            }
+            Value::Map(entries) => {
+                let mut map = serde_json::Map::new();
+                for (k, v) in entries {
+                    let k = k.as_text().ok_or(WSError::ParseError)?;
+                    map.insert(k.to_string(), v.to_json()?);
+                }
+                serde_json::Value::Object(map)
+            }
+            Value::Bytes(_) | Value::Tag(..) => return Err(WSError::ParseError),
+        };
+        Ok(json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ct_codecs::{Decoder as _, Hex};
+
+    fn hex(s: &str) -> Vec<u8> {
+        Hex::decode_to_vec(s, None).unwrap()
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    /// Examples from RFC 8949, Appendix A.
+    #[test]
+    fn rfc8949_examples() {
+        let examples = [
+            (Value::Unsigned(0), "00"),
+            (Value::Unsigned(1), "01"),
+            (Value::Unsigned(10), "0a"),
+            (Value::Unsigned(23), "17"),
+            (Value::Unsigned(24), "1818"),
+            (Value::Unsigned(25), "1819"),
+            (Value::Unsigned(100), "1864"),
+            (Value::Unsigned(1000), "1903e8"),
+            (Value::Unsigned(1000000), "1a000f4240"),
+            (Value::Unsigned(1000000000000), "1b000000e8d4a51000"),
+            (Value::Unsigned(u64::MAX), "1bffffffffffffffff"),
+            (Value::Negative(u64::MAX), "3bffffffffffffffff"),
+            (Value::from(-1), "20"),
+            (Value::from(-10), "29"),
+            (Value::from(-100), "3863"),
+            (Value::from(-1000), "3903e7"),
+            (Value::Float(1.1), "fb3ff199999999999a"),
+            (Value::Float(-4.1), "fbc010666666666666"),
+            (Value::Float(1.0e300), "fb7e37e43c8800759c"),
+            (Value::Bool(false), "f4"),
+            (Value::Bool(true), "f5"),
+            (Value::Null, "f6"),
+            (
+                Value::Tag(0, Box::new(text("2013-03-21T20:04:00Z"))),
+                "c074323031332d30332d32315432303a30343a30305a",
+            ),
+            (
+                Value::Tag(1, Box::new(Value::Unsigned(1363896240))),
+                "c11a514b67b0",
+            ),
+            (
+                Value::Tag(23, Box::new(Value::Bytes(hex("01020304")))),
+                "d74401020304",
+            ),
+            (Value::Bytes(vec![]), "40"),
+            (Value::Bytes(hex("01020304")), "4401020304"),
+            (text(""), "60"),
+            (text("a"), "6161"),
+            (text("IETF"), "6449455446"),
+            (text("\"\\"), "62225c"),
+            (text("\u{00fc}"), "62c3bc"),
+            (text("\u{6c34}"), "63e6b0b4"),
+            (text("\u{10151}"), "64f0908591"),
+            (Value::Array(vec![]), "80"),
+            (
+                Value::Array((1..=3).map(Value::Unsigned).collect()),
+                "83010203",
+            ),
+            (
+                Value::Array(vec![
+                    Value::Unsigned(1),
+                    Value::Array(vec![Value::Unsigned(2), Value::Unsigned(3)]),
+                    Value::Array(vec![Value::Unsigned(4), Value::Unsigned(5)]),
+                ]),
+                "8301820203820405",
+            ),
+            (
+                Value::Array((1..=25).map(Value::Unsigned).collect()),
+                "98190102030405060708090a0b0c0d0e0f101112131415161718181819",
+            ),
+            (Value::Map(vec![]), "a0"),
+            (
+                Value::Map(vec![
+                    (Value::Unsigned(1), Value::Unsigned(2)),
+                    (Value::Unsigned(3), Value::Unsigned(4)),
+                ]),
+                "a201020304",
+            ),
+            (
+                Value::Map(vec![
+                    (text("a"), Value::Unsigned(1)),
+                    (
+                        text("b"),
+                        Value::Array(vec![Value::Unsigned(2), Value::Unsigned(3)]),
+                    ),
+                ]),
+                "a26161016162820203",
+            ),
+            (
+                Value::Array(vec![text("a"), Value::Map(vec![(text("b"), text("c"))])]),
+                "826161a161626163",
+            ),
+        ];
+        for (value, encoded) in examples {
+            assert_eq!(value.encode(), hex(encoded), "{:?}", value);
+            assert_eq!(Value::decode(&hex(encoded)).unwrap(), value, "{}", encoded);
+        }
+
+        // Shorter floats are decoded, but never produced.
+        let floats = [
+            ("f90000", 0.0),
+            ("f98000", -0.0),
+            ("f93c00", 1.0),
+            ("f93e00", 1.5),
+            ("f97bff", 65504.0),
+            ("fa47c35000", 100000.0),
+            ("fa7f7fffff", 3.4028234663852886e+38),
+            ("f90001", 5.960464477539063e-8),
+            ("f90400", 0.00006103515625),
+            ("f9c400", -4.0),
+            ("f97c00", f64::INFINITY),
+            ("f9fc00", f64::NEG_INFINITY),
+            ("fa7f800000", f64::INFINITY),
+        ];
+        for (encoded, v) in floats {
+            match Value::decode(&hex(encoded)).unwrap() {
+                Value::Float(decoded) => {
+                    assert_eq!(decoded.to_bits(), v.to_bits(), "{}", encoded)
+                }
+                value => panic!("{}: {:?}", encoded, value),
+            }
+        }
+        for encoded in ["f97e00", "fa7fc00000", "fb7ff8000000000000"] {
+            assert!(matches!(Value::decode(&hex(encoded)).unwrap(), Value::Float(v) if v.is_nan()));
+        }
+    }
+
+    #[test]
+    fn indefinite_lengths_are_rejected() {
+        // Indefinite-length examples from RFC 8949, Appendix A, and reserved additional information.
+        for encoded in [
+            "5f42010243030405ff",
+            "7f657374726561646d696e67ff",
+            "9fff",
+            "9f018202039f0405ffff",
+            "83018202039f0405ff",
+            "bf61610161629f0203ffff",
+            "bf6346756ef563416d7421ff",
+            "1f",
+            "1c",
+            "ff",
+        ] {
+            assert!(
+                matches!(Value::decode(&hex(encoded)), Err(WSError::ParseError)),
+                "{}",
+                encoded
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        let nested = |depth: usize| {
+            let mut encoded = vec![0x81; depth];
+            encoded.push(0x00);
+            encoded
+        };
+        Value::decode(&nested(MAX_DEPTH)).unwrap();
+        assert!(Value::decode(&nested(MAX_DEPTH + 1)).is_err());
+        let mut tags = vec![0xc1; MAX_DEPTH + 1];
+        tags.push(0x00);
+        assert!(Value::decode(&tags).is_err());
+
+        // Lengths larger than the input are rejected before anything is allocated.
+        for encoded in [
+            "9bffffffffffffffff",
+            "5bffffffffffffffff",
+            "bb00000001000000",
+        ] {
+            assert!(Value::decode(&hex(encoded)).is_err(), "{}", encoded);
+        }
+        for encoded in [
+            "", "18", "1903", "4401", "62c3", "8201", "a101", "c1", "fb3ff1",
+        ] {
+            assert!(
+                matches!(Value::decode(&hex(encoded)), Err(WSError::Eof)),
+                "{}",
+                encoded
+            );
+        }
+        // Trailing data, invalid UTF-8 and unsupported simple values.
+        for encoded in ["0000", "62c328", "f0", "f7", "f820"] {
+            assert!(Value::decode(&hex(encoded)).is_err(), "{}", encoded);
+        }
+    }
+}
@@ -0,0 +1,576 @@
+//! Patches compressing a module with Zstandard, using the previous version as a dictionary.
+//!
+//! This is a simpler alternative to structural diffing: code shared with the base module is
+//! coded as matches into the dictionary.
+//! A patch is a sequence of chunks. Each chunk is a skippable frame describing a window of the
+//! base module, followed by a regular Zstandard frame compressing the next part of the module with
+//! that window as the dictionary. Windows are checked against their digest, so that a patch applied
+//! to the wrong base is rejected.
+//!
+//! Patches made for a memory budget are split into chunks small enough to be applied within that
+//! budget, streaming the base module, the patch and the output. Patches made without one have a
+//! single chunk, that `zstd -D` can also decode, with the window as the dictionary.
+
+use crate::error::*;
+use crate::memory::Reservation;
+use crate::wasm_module::{varint, SectionId};
+
+use hmac_sha256::Hash;
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use zstd::zstd_safe::{self, CCtx, CParameter, DCtx};
+
+/// Skippable frame describing a chunk.
+const CHUNK_MAGIC: u32 = 0x184d_2a5e;
+const DESCRIPTION_LEN: usize = 4 * 8 + 32;
+
+/// Memory used to apply a chunk, besides its window, its content and its frame.
+const DECODER_OVERHEAD: u64 = 256 * 1024;
+
+/// Memory used to make a chunk, besides its frame and the tables of the match finder.
+const ENCODER_OVERHEAD: u64 = 1024 * 1024;
+
+/// Compression level of patches, which are made once and applied on many devices.
+const LEVEL: i32 = 19;
+
+/// Largest window decoders accept by default. Matches further away than that are not used.
+const MAX_WINDOW_LOG: u32 = 27;
+
+/// Smallest memory budget patches can be made for.
+pub const MIN_MEMORY_BUDGET: u64 = 1024 * 1024;
+
+/// Length of the sequences used to find the window matching a chunk, and interval between them in the base module.
+const ANCHOR_LEN: usize = 16;
+const ANCHOR_STEP: usize = 32;
+
+/// Number of consecutive anchors outside the window of a chunk, or inside it, causing the chunk to be cut.
+const CUT_RUN: usize = 8;
+const MAX_CUTS: usize = 8;
+const MIN_CHUNK_LEN: usize = 16 * 1024;
+
+/// Part of the base module used as the dictionary.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum DictionarySource {
+    /// The whole module.
+    #[default]
+    Module,
+    /// The code section only, which is smaller and usually where most of the changes are.
+    CodeSection,
+}
+
+impl DictionarySource {
+    /// Return the range of the base module windows can be taken from.
+    fn range(self, base: &[u8]) -> Result<Range<usize>, WSError> {
+        if self == DictionarySource::Module {
+            return Ok(0..base.len());
+        }
+        let mut reader = base.get(8..).ok_or(WSError::ParseError)?;
+        while !reader.is_empty() {
+            let id = SectionId::from(varint::get7(&mut reader)?);
+            let len = varint::get32(&mut reader)? as usize;
+            let start = base.len() - reader.len();
+            reader = reader.get(len..).ok_or(WSError::ParseError)?;
+            if id == SectionId::Code {
+                return Ok(start..start + len);
+            }
+        }
+        Err(WSError::NotFound("code section".to_string()))
+    }
+}
+
+/// Description of a chunk of a patch.
+struct Chunk {
+    window_offset: u64,
+    window_len: u64,
+    content_len: u64,
+    frame_len: u64,
+    digest: [u8; 32],
+}
+
+impl Chunk {
+    /// Return the memory needed to apply the chunk.
+    fn memory(&self) -> u64 {
+        DECODER_OVERHEAD
+            .saturating_add(self.window_len)
+            .saturating_add(self.content_len)
+            .saturating_add(self.frame_len)
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&CHUNK_MAGIC.to_le_bytes());
+        out.extend_from_slice(&(DESCRIPTION_LEN as u32).to_le_bytes());
+        for v in [
+            self.window_offset,
+            self.window_len,
+            self.content_len,
+            self.frame_len,
+        ] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out.extend_from_slice(&self.digest);
+    }
+
+    /// Read the description of the next chunk, or return `None` at the end of the patch.
+    fn read(reader: &mut impl Read) -> Result<Option<Self>, WSError> {
+        let mut header = [0u8; 8 + DESCRIPTION_LEN];
+        let mut len = 0;
+        while len < header.len() {
+            match reader.read(&mut header[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if len == 0 {
+            return Ok(None);
+        }
+        if len < header.len()
+            || header[..4] != CHUNK_MAGIC.to_le_bytes()
+            || header[4..8] != (DESCRIPTION_LEN as u32).to_le_bytes()
+        {
+            return Err(WSError::ParseError);
+        }
+        let field =
+            |i: usize| u64::from_le_bytes(header[8 + i * 8..16 + i * 8].try_into().unwrap());
+        Ok(Some(Chunk {
+            window_offset: field(0),
+            window_len: field(1),
+            content_len: field(2),
+            frame_len: field(3),
+            digest: header[40..].try_into().unwrap(),
+        }))
+    }
+}
+
+/// Positions of sequences of the base module, to find the window matching a chunk best.
+struct Anchors {
+    region: Range<usize>,
+    positions: HashMap<u128, Option<usize>>,
+}
+
+impl Anchors {
+    fn new(base: &[u8], region: Range<usize>) -> Self {
+        let mut positions = HashMap::new();
+        for pos in (region.start..region.end.saturating_sub(ANCHOR_LEN - 1)).step_by(ANCHOR_STEP) {
+            let seq = &base[pos..pos + ANCHOR_LEN];
+            // Sequences found more than once don't tell where a chunk comes from.
+            positions
+                .entry(anchor(seq))
+                .and_modify(|position| *position = None)
+                .or_insert(Some(pos));
+        }
+        Anchors { region, positions }
+    }
+
+    /// Return the window to use for the chunk of `module` starting at `offset`, and the length of the chunk.
+    ///
+    /// The window is the one covering most of the anchors found in the chunk. The chunk is cut
+    /// short if its content moves to another part of the base module.
+    fn next_chunk(
+        &self,
+        module: &[u8],
+        offset: usize,
+        chunk_len: usize,
+        window_len: usize,
+    ) -> (Range<usize>, usize) {
+        let mut len = chunk_len.min(module.len() - offset);
+        if self.region.len() <= window_len {
+            return (self.region.clone(), len);
+        }
+        // Anchors found in the chunk, as positions in the chunk and in the base module.
+        let found: Vec<(usize, usize)> = module[offset..offset + len]
+            .windows(ANCHOR_LEN)
+            .enumerate()
+            .filter_map(|(i, seq)| Some((i, self.positions.get(&anchor(seq)).copied()??)))
+            .collect();
+        for _ in 0..MAX_CUTS {
+            let found = &found[..found.partition_point(|&(i, _)| i < len)];
+            let window = self.window(found, offset + len / 2, module.len(), window_len);
+            let inside = |&(_, pos): &(usize, usize)| window.contains(&pos);
+            let initial = found.iter().take(CUT_RUN).filter(|a| inside(a)).count() * 2
+                > CUT_RUN.min(found.len());
+            let cut = found
+                .windows(CUT_RUN)
+                .find(|run| run.iter().all(|a| inside(a) != initial))
+                .map(|run| run[0].0);
+            match cut {
+                Some(cut) if cut >= MIN_CHUNK_LEN => len = cut,
+                _ => return (window, len),
+            }
+        }
+        let found = &found[..found.partition_point(|&(i, _)| i < len)];
+        (
+            self.window(found, offset + len / 2, module.len(), window_len),
+            len,
+        )
+    }
+
+    /// Return the window of `window_len` bytes covering most of the `found` anchors, for a chunk
+    /// whose middle is at `middle` in a module of `module_len` bytes.
+    fn window(
+        &self,
+        found: &[(usize, usize)],
+        middle: usize,
+        module_len: usize,
+        window_len: usize,
+    ) -> Range<usize> {
+        let region = &self.region;
+        let mut positions: Vec<usize> = found.iter().map(|&(_, pos)| pos).collect();
+        positions.sort_unstable();
+        let center = if positions.is_empty() {
+            // Nothing in common: assume that the module grew evenly.
+            region.start
+                + (middle as u128 * region.len() as u128 / module_len.max(1) as u128) as usize
+        } else {
+            let (mut best, mut first) = ((0, 0), 0);
+            for last in 0..positions.len() {
+                while positions[last] + ANCHOR_LEN - positions[first] > window_len {
+                    first += 1;
+                }
+                if last - first > best.1 - best.0 {
+                    best = (first, last);
+                }
+            }
+            (positions[best.0] + positions[best.1] + ANCHOR_LEN) / 2
+        };
+        let start = center
+            .saturating_sub(window_len / 2)
+            .clamp(region.start, region.end - window_len);
+        start..start + window_len
+    }
+}
+
+fn anchor(seq: &[u8]) -> u128 {
+    u128::from_le_bytes(seq.try_into().unwrap())
+}
+
+/// Makes dictionary patches.
+#[derive(Debug, Clone, Default)]
+pub struct DictionaryPatch {
+    source: DictionarySource,
+    memory_budget: Option<u64>,
+}
+
+impl DictionaryPatch {
+    /// Make patches using the whole base module, in a single chunk.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only take dictionaries from `source` of the base module.
+    pub fn source(mut self, source: DictionarySource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Make patches that can be applied with at most `bytes` of memory.
+    ///
+    /// The budget must be at least `MIN_MEMORY_BUDGET`.
+    pub fn memory_budget(mut self, bytes: u64) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Make a patch transforming `base` into `module`.
+    pub fn make(&self, base: &[u8], module: &[u8]) -> Result<Vec<u8>, WSError> {
+        let region = self.source.range(base)?;
+        let mut patch = vec![];
+        let Some(budget) = self.memory_budget else {
+            write_chunk(&mut patch, base, region, module)?;
+            return Ok(patch);
+        };
+        if budget < MIN_MEMORY_BUDGET {
+            return Err(WSError::MemoryLimit {
+                required: MIN_MEMORY_BUDGET,
+                available: budget,
+            });
+        }
+        // A quarter of the budget for the content, as much for its frame, and the rest for the window.
+        let available = usize::try_from(budget - DECODER_OVERHEAD).unwrap_or(usize::MAX);
+        let chunk_len = available / 4;
+        let window_len = available - chunk_len - zstd_safe::compress_bound(chunk_len);
+        let anchors = Anchors::new(base, region);
+        let mut offset = 0;
+        loop {
+            let (window, len) = anchors.next_chunk(module, offset, chunk_len, window_len);
+            write_chunk(&mut patch, base, window, &module[offset..offset + len])?;
+            offset += len;
+            if offset == module.len() {
+                return Ok(patch);
+            }
+        }
+    }
+}
+
+fn write_chunk(
+    patch: &mut Vec<u8>,
+    base: &[u8],
+    window: Range<usize>,
+    content: &[u8],
+) -> Result<(), WSError> {
+    let dictionary = &base[window.clone()];
+    let _reservation = Reservation::new(compress_memory(content.len(), dictionary.len()))?;
+    let frame = compress(content, dictionary)?;
+    Chunk {
+        window_offset: window.start as _,
+        window_len: dictionary.len() as _,
+        content_len: content.len() as _,
+        frame_len: frame.len() as _,
+        digest: Hash::hash(dictionary),
+    }
+    .write(patch);
+    patch.extend_from_slice(&frame);
+    Ok(())
+}
+
+/// Return the base-2 logarithm of the window covering `len` bytes.
+fn window_log(len: usize) -> u32 {
+    (usize::BITS - len.saturating_sub(1).leading_zeros()).clamp(10, MAX_WINDOW_LOG)
+}
+
+/// Return the memory used to compress `len` bytes with a dictionary of `dictionary_len` bytes.
+///
+/// The hash and chain tables of the match finder have up to twice as many 4-byte entries as the
+/// window.
+fn compress_memory(len: usize, dictionary_len: usize) -> u64 {
+    let window = 1u64 << window_log(dictionary_len + len);
+    ENCODER_OVERHEAD + zstd_safe::compress_bound(len) as u64 + 16 * window
+}
+
+/// Compress data as a single Zstandard frame, with `dictionary` as raw content preceding it.
+fn compress(data: &[u8], dictionary: &[u8]) -> Result<Vec<u8>, WSError> {
+    let error = |code| io::Error::other(zstd_safe::get_error_name(code));
+    let mut cctx = CCtx::create();
+    for param in [
+        CParameter::CompressionLevel(LEVEL),
+        // Matches can reach back to the start of the dictionary.
+        CParameter::WindowLog(window_log(dictionary.len() + data.len())),
+        CParameter::ChecksumFlag(true),
+    ] {
+        cctx.set_parameter(param).map_err(error)?;
+    }
+    cctx.ref_prefix(dictionary).map_err(error)?;
+    let mut frame = Vec::with_capacity(zstd_safe::compress_bound(data.len()));
+    cctx.compress2(&mut frame, data).map_err(error)?;
+    Ok(frame)
+}
+
+/// Decompress a single Zstandard frame into `content`, with `window` as raw content preceding it.
+///
+/// `content` is never grown: the frame fails to decompress if its content is larger than the
+/// capacity reserved for it.
+fn decompress_frame(frame: &[u8], window: &[u8], content: &mut Vec<u8>) -> Result<(), WSError> {
+    if zstd_safe::find_frame_compressed_size(frame) != Ok(frame.len()) {
+        return Err(WSError::ParseError);
+    }
+    let mut dctx = DCtx::create();
+    dctx.ref_prefix(window).map_err(|_| WSError::ParseError)?;
+    content.clear();
+    dctx.decompress(content, frame)
+        .map_err(|_| WSError::ParseError)?;
+    Ok(())
+}
+
+/// Applies dictionary patches, streaming the base module, the patch and the output.
+#[derive(Debug, Clone, Default)]
+pub struct PatchApplier {
+    memory_budget: Option<u64>,
+}
+
+impl PatchApplier {
+    /// Apply patches without a memory budget.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reject patches that would need more than `bytes` of memory to be applied.
+    pub fn memory_budget(mut self, bytes: u64) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Apply a patch to `base`, write the module to `output`, and return its size.
+    ///
+    /// Fails if the module would be larger than `max_size` bytes.
+    pub fn apply(
+        &self,
+        base: &mut (impl Read + Seek),
+        patch: &mut impl Read,
+        output: &mut impl Write,
+        max_size: u64,
+    ) -> Result<u64, WSError> {
+        let base_len = base.seek(SeekFrom::End(0))?;
+        let (mut written, mut chunks) = (0u64, 0);
+        // Buffers are reused, so that memory doesn't get fragmented from a chunk to the next.
+        let (mut window, mut content, mut frame) = (vec![], vec![], vec![]);
+        let mut reservation = Reservation::new(0)?;
+        let mut charged = 0;
+        while let Some(chunk) = Chunk::read(patch)? {
+            let required = chunk.memory();
+            if let Some(budget) = self.memory_budget.filter(|&budget| required > budget) {
+                return Err(WSError::MemoryLimit {
+                    required,
+                    available: budget,
+                });
+            }
+            if chunk.window_offset.saturating_add(chunk.window_len) > base_len
+                || chunk.content_len > max_size - written
+                || chunk.frame_len > zstd_safe::compress_bound(chunk.content_len as _) as u64
+            {
+                return Err(WSError::ParseError);
+            }
+            if required > charged {
+                reservation.grow(required - charged)?;
+                charged = required;
+            }
+            window.clear();
+            window.reserve_exact(chunk.window_len as _);
+            base.seek(SeekFrom::Start(chunk.window_offset))?;
+            base.by_ref()
+                .take(chunk.window_len)
+                .read_to_end(&mut window)?;
+            if window.len() as u64 != chunk.window_len {
+                return Err(WSError::ParseError);
+            }
+            if Hash::hash(&window) != chunk.digest {
+                return Err(WSError::DigestMismatch);
+            }
+            frame.resize(chunk.frame_len as _, 0);
+            patch.read_exact(&mut frame)?;
+            content.clear();
+            content.reserve_exact(chunk.content_len as _);
+            decompress_frame(&frame, &window, &mut content)?;
+            if content.len() as u64 != chunk.content_len {
+                return Err(WSError::ParseError);
+            }
+            output.write_all(&content)?;
+            written += chunk.content_len;
+            chunks += 1;
+        }
+        if chunks == 0 {
+            return Err(WSError::ParseError);
+        }
+        Ok(written)
+    }
+}
+
+/// Apply a patch to `base` in memory, failing if the result would be larger than `max_size` bytes.
+pub fn apply_dictionary_patch(
+    base: &[u8],
+    patch: &[u8],
+    max_size: u64,
+) -> Result<Vec<u8>, WSError> {
+    let mut output = ChargedWriter {
+        data: vec![],
+        reservation: Reservation::new(0)?,
+        error: None,
+    };
+    PatchApplier::new()
+        .apply(
+            &mut io::Cursor::new(base),
+            &mut &patch[..],
+            &mut output,
+            max_size,
+        )
+        .map_err(|e| output.error.take().unwrap_or(e))?;
+    output.reservation.retain();
+    Ok(output.data)
+}
+
+/// Buffer charging its memory before growing.
+struct ChargedWriter {
+    data: Vec<u8>,
+    reservation: Reservation,
+    error: Option<WSError>,
+}
+
+impl Write for ChargedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let data = &mut self.data;
+        if let Some(additional) = (data.len() + buf.len()).checked_sub(data.capacity()) {
+            if let Err(e) = self.reservation.grow(additional as _) {
+                self.error = Some(e);
+                return Err(io::ErrorKind::OutOfMemory.into());
+            }
+            data.reserve_exact(buf.len());
+        }
+        data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modules() -> (Vec<u8>, Vec<u8>) {
+        let mut seed = 0x9e37_79b9u32;
+        let base: Vec<u8> = (0..3 * 1024 * 1024)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let mut module = base[..1024 * 1024].to_vec();
+        module.extend_from_slice(b"some new code");
+        module.extend_from_slice(&base[2 * 1024 * 1024..]);
+        (base, module)
+    }
+
+    #[test]
+    fn patches_are_applied_within_their_budget() {
+        let (base, module) = modules();
+        let patch = DictionaryPatch::new()
+            .memory_budget(MIN_MEMORY_BUDGET)
+            .make(&base, &module)
+            .unwrap();
+        assert!(patch.len() < module.len() / 10);
+        let mut output = vec![];
+        let len = PatchApplier::new()
+            .memory_budget(MIN_MEMORY_BUDGET)
+            .apply(
+                &mut io::Cursor::new(&base),
+                &mut &patch[..],
+                &mut output,
+                module.len() as _,
+            )
+            .unwrap();
+        assert_eq!(len, module.len() as u64);
+        assert!(output == module);
+
+        let too_small = PatchApplier::new().memory_budget(MIN_MEMORY_BUDGET / 2);
+        assert!(matches!(
+            too_small.apply(
+                &mut io::Cursor::new(&base),
+                &mut &patch[..],
+                &mut vec![],
+                module.len() as _
+            ),
+            Err(WSError::MemoryLimit { .. })
+        ));
+    }
+
+    #[test]
+    fn patches_are_bound_to_their_base() {
+        let (base, module) = modules();
+        let patch = DictionaryPatch::new().make(&base, &module).unwrap();
+        assert!(apply_dictionary_patch(&base, &patch, module.len() as _).unwrap() == module);
+        assert!(apply_dictionary_patch(&base, &patch, module.len() as u64 - 1).is_err());
+        let mut other = base.clone();
+        other[100] ^= 1;
+        assert!(matches!(
+            apply_dictionary_patch(&other, &patch, module.len() as _),
+            Err(WSError::DigestMismatch)
+        ));
+        let mut corrupted = patch.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        assert!(apply_dictionary_patch(&base, &corrupted, module.len() as _).is_err());
+    }
+}
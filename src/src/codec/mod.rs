@@ -0,0 +1,351 @@
+//! Codecs compressing modules for transfer.
+//!
+//! Servers can store a module in several encodings. Devices list the codecs they can decode in their
+//! update checks, most preferred first, and are offered the first encoding available for the update.
+//! Constrained devices can leave out codecs whose decoders are too expensive for them.
+
+#[cfg(feature = "zstd")]
+mod dictionary;
+
+#[cfg(feature = "zstd")]
+pub use self::dictionary::{
+    apply_dictionary_patch, DictionaryPatch, DictionarySource, PatchApplier, MIN_MEMORY_BUDGET,
+};
+
+use crate::error::*;
+
+use std::fmt;
+use std::sync::Arc;
+
+/// Name of the codec leaving data as-is.
+pub const NO_ENCODING: &str = "none";
+
+/// A way to encode data for transfer.
+pub trait Codec: Send + Sync {
+    /// Return the name of the codec, as used in update checks and manifests.
+    fn name(&self) -> &str;
+
+    /// Encode data.
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, WSError>;
+
+    /// Decode data, failing if the decoded data would be larger than `max_size` bytes.
+    fn decode(&self, data: &[u8], max_size: u64) -> Result<Vec<u8>, WSError>;
+}
+
+/// The codec leaving data as-is.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoEncoding;
+
+impl Codec for NoEncoding {
+    fn name(&self) -> &str {
+        NO_ENCODING
+    }
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, WSError> {
+        Ok(data.to_vec())
+    }
+
+    fn decode(&self, data: &[u8], max_size: u64) -> Result<Vec<u8>, WSError> {
+        if data.len() as u64 > max_size {
+            return Err(WSError::ParseError);
+        }
+        Ok(data.to_vec())
+    }
+}
+
+/// The gzip codec.
+#[cfg(feature = "compression")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Gzip;
+
+#[cfg(feature = "compression")]
+impl Codec for Gzip {
+    fn name(&self) -> &str {
+        "gzip"
+    }
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, WSError> {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::prelude::*;
+
+        let mut encoder = GzEncoder::new(vec![], Compression::best());
+        encoder.write_all(data)?;
+        Ok(encoder.finish()?)
+    }
+
+    fn decode(&self, data: &[u8], max_size: u64) -> Result<Vec<u8>, WSError> {
+        use flate2::read::MultiGzDecoder;
+        use std::io::prelude::*;
+
+        let mut decoded = vec![];
+        MultiGzDecoder::new(data)
+            .take(max_size.saturating_add(1))
+            .read_to_end(&mut decoded)
+            .map_err(|_| WSError::ParseError)?;
+        if decoded.len() as u64 > max_size {
+            return Err(WSError::ParseError);
+        }
+        Ok(decoded)
+    }
+}
+
+/// The Zstandard codec.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Zstd;
+
+#[cfg(feature = "zstd")]
+impl Codec for Zstd {
+    fn name(&self) -> &str {
+        "zstd"
+    }
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, WSError> {
+        Ok(zstd::bulk::compress(data, 19)?)
+    }
+
+    fn decode(&self, data: &[u8], max_size: u64) -> Result<Vec<u8>, WSError> {
+        use std::io::prelude::*;
+
+        // An empty stream would otherwise decode to nothing.
+        if data.is_empty() {
+            return Err(WSError::ParseError);
+        }
+        let mut decoded = vec![];
+        zstd::stream::read::Decoder::with_buffer(data)?
+            .take(max_size.saturating_add(1))
+            .read_to_end(&mut decoded)
+            .map_err(|_| WSError::ParseError)?;
+        if decoded.len() as u64 > max_size {
+            return Err(WSError::ParseError);
+        }
+        Ok(decoded)
+    }
+}
+
+/// The Brotli codec.
+#[cfg(feature = "brotli")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Brotli;
+
+#[cfg(feature = "brotli")]
+impl Codec for Brotli {
+    fn name(&self) -> &str {
+        "brotli"
+    }
+
+    fn encode(&self, data: &[u8]) -> Result<Vec<u8>, WSError> {
+        use std::io::prelude::*;
+
+        // The smallest window holding the data, so that decoders allocate as little as possible.
+        let window_bits = (10..24)
+            .find(|&bits| (1usize << bits) - 16 >= data.len())
+            .unwrap_or(24);
+        let mut encoder = brotli::CompressorWriter::new(vec![], 4096, 11, window_bits);
+        encoder.write_all(data)?;
+        encoder.flush()?;
+        Ok(encoder.into_inner())
+    }
+
+    fn decode(&self, data: &[u8], max_size: u64) -> Result<Vec<u8>, WSError> {
+        use std::io::prelude::*;
+
+        let mut decoded = vec![];
+        brotli::Decompressor::new(data, 4096)
+            .take(max_size.saturating_add(1))
+            .read_to_end(&mut decoded)
+            .map_err(|_| WSError::ParseError)?;
+        if decoded.len() as u64 > max_size {
+            return Err(WSError::ParseError);
+        }
+        Ok(decoded)
+    }
+}
+
+/// A set of codecs, in order of preference.
+#[derive(Clone)]
+pub struct Codecs {
+    codecs: Vec<Arc<dyn Codec>>,
+}
+
+impl Codecs {
+    /// Create an empty set. Data can then only be transferred as-is.
+    pub fn new() -> Self {
+        Codecs { codecs: vec![] }
+    }
+
+    /// Return the codecs wasi-update was built with, the most efficient first.
+    pub fn builtin() -> Self {
+        #[allow(unused_mut)]
+        let mut codecs = Self::new();
+        #[cfg(feature = "zstd")]
+        {
+            codecs = codecs.with(Zstd);
+        }
+        #[cfg(feature = "brotli")]
+        {
+            codecs = codecs.with(Brotli);
+        }
+        #[cfg(feature = "compression")]
+        {
+            codecs = codecs.with(Gzip);
+        }
+        codecs
+    }
+
+    /// Add a codec, less preferred than the previous ones.
+    ///
+    /// A codec replaces any previous codec with the same name.
+    pub fn with(mut self, codec: impl Codec + 'static) -> Self {
+        self.codecs.retain(|c| c.name() != codec.name());
+        self.codecs.push(Arc::new(codec));
+        self
+    }
+
+    /// Only keep the codecs with the given names, in that order.
+    pub fn only(&self, names: &[impl AsRef<str>]) -> Result<Self, WSError> {
+        let codecs = names
+            .iter()
+            .map(|name| {
+                self.codecs
+                    .iter()
+                    .find(|c| c.name() == name.as_ref())
+                    .cloned()
+                    .ok_or_else(|| WSError::UnsupportedCodec(name.as_ref().to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Codecs { codecs })
+    }
+
+    /// Return the codec with the given name.
+    ///
+    /// The `none` codec is always available.
+    pub fn get(&self, name: &str) -> Result<Arc<dyn Codec>, WSError> {
+        if name == NO_ENCODING {
+            return Ok(Arc::new(NoEncoding));
+        }
+        self.codecs
+            .iter()
+            .find(|c| c.name() == name)
+            .cloned()
+            .ok_or_else(|| WSError::UnsupportedCodec(name.to_string()))
+    }
+
+    /// Return the names of the codecs, in order of preference.
+    pub fn names(&self) -> Vec<String> {
+        self.codecs.iter().map(|c| c.name().to_string()).collect()
+    }
+
+    /// Return `true` if the set doesn't contain any codec.
+    pub fn is_empty(&self) -> bool {
+        self.codecs.is_empty()
+    }
+}
+
+impl Default for Codecs {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl fmt::Debug for Codecs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return data covering literals-only, repetitive and incompressible content, as well as
+    /// several blocks.
+    fn samples() -> Vec<Vec<u8>> {
+        let mut seed = 0x2545_f491u32;
+        let mut random = |len: usize| {
+            (0..len)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                })
+                .collect::<Vec<u8>>()
+        };
+        let text = b"The quick brown fox jumps over the lazy dog. ".repeat(40);
+        let mut large = vec![];
+        while large.len() < 300 * 1024 {
+            large.extend_from_slice(&text[..1000]);
+            large.extend_from_slice(&random(3000));
+            large.extend_from_slice(&[0u8; 5000]);
+        }
+        vec![
+            vec![],
+            vec![42],
+            b"hello".to_vec(),
+            text,
+            vec![7; 200_000],
+            random(4096),
+            large,
+        ]
+    }
+
+    #[test]
+    fn codecs_round_trip() {
+        let codecs = Codecs::builtin();
+        for name in [NO_ENCODING]
+            .into_iter()
+            .chain(codecs.names().iter().map(|s| s.as_str()))
+        {
+            let codec = codecs.get(name).unwrap();
+            for data in samples() {
+                let encoded = codec.encode(&data).unwrap();
+                let decoded = codec.decode(&encoded, data.len() as u64).unwrap();
+                assert!(decoded == data, "{} on {} bytes", name, data.len());
+                if !data.is_empty() {
+                    assert!(codec.decode(&encoded, data.len() as u64 - 1).is_err());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_data_is_rejected() {
+        let codecs = Codecs::builtin();
+        for name in codecs.names() {
+            let codec = codecs.get(&name).unwrap();
+            let data = &samples()[3];
+            let encoded = codec.encode(data).unwrap();
+            for len in 0..encoded.len() {
+                assert!(
+                    codec.decode(&encoded[..len], data.len() as u64).is_err(),
+                    "{} truncated to {} bytes",
+                    name,
+                    len
+                );
+            }
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_reads_reference_frames() {
+        // "hello" as written by the `zstd` command-line tool, preceded by a skippable frame.
+        let frame = [
+            0x5a, 0x2a, 0x4d, 0x18, 3, 0, 0, 0, 1, 2, 3, 0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x58, 0x29,
+            0x00, 0x00, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0xa3, 0x6d, 0x9f, 0x88,
+        ];
+        assert_eq!(Zstd.decode(&frame, 5).unwrap(), b"hello");
+        let mut corrupted = frame;
+        corrupted[25] ^= 1;
+        assert!(Zstd.decode(&corrupted, 5).is_err());
+        assert!(Zstd.decode(b"", 0).is_err());
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn brotli_reads_reference_streams() {
+        assert_eq!(Brotli.decode(&[0x06], 0).unwrap(), b"");
+        let stream = [0x0b, 0x02, 0x80, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x03];
+        assert_eq!(Brotli.decode(&stream, 5).unwrap(), b"hello");
+        assert!(Brotli.decode(&stream[..8], 5).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Host binding stubs generated from the imports of a module.
+//!
+//! The generated code is a function registering every imported function with a
+//! `wasmtime::Linker`, with a typed closure whose body is left to the host. Hosts can generate it
+//! again after each module update, and compare it with their own shims to spot imports that were
+//! added, removed or whose signature changed.
+
+use crate::error::*;
+use crate::interface::*;
+use crate::wasm_module::*;
+
+use std::collections::HashSet;
+use std::fmt::Write as _;
+
+/// Options of the generated host shims.
+#[derive(Debug, Clone)]
+pub struct HostShims {
+    function_name: String,
+    state_type: Option<String>,
+}
+
+impl Default for HostShims {
+    fn default() -> Self {
+        HostShims {
+            function_name: "add_to_linker".to_string(),
+            state_type: None,
+        }
+    }
+}
+
+/// Return the Rust type of a WebAssembly value type, if `wasmtime::Linker::func_wrap()` accepts
+/// it without knowing more about the module.
+fn rust_type(val_type: &str) -> Option<&'static str> {
+    Some(match val_type {
+        "i32" => "i32",
+        "i64" => "i64",
+        "f32" => "f32",
+        "f64" => "f64",
+        "v128" => "wasmtime::V128",
+        _ => return None,
+    })
+}
+
+/// Return the Rust types of the parameters and results of a function signature, as described
+/// by `Module::interface()`.
+fn func_signature(signature: &str) -> Option<(Vec<&'static str>, Vec<&'static str>)> {
+    let mut rest = signature.strip_prefix("func")?.trim_start();
+    let (mut params, mut results) = (vec![], vec![]);
+    if let Some(group) = rest.strip_prefix("(param ") {
+        let (types, tail) = group.split_once(')')?;
+        params = types
+            .split_whitespace()
+            .map(rust_type)
+            .collect::<Option<_>>()?;
+        rest = tail.trim_start();
+    }
+    if let Some(group) = rest.strip_prefix("(result ") {
+        let (types, tail) = group.split_once(')')?;
+        results = types
+            .split_whitespace()
+            .map(rust_type)
+            .collect::<Option<_>>()?;
+        rest = tail.trim_start();
+    }
+    match rest.is_empty() {
+        true => Some((params, results)),
+        false => None,
+    }
+}
+
+impl HostShims {
+    /// Create the default options: a generic `add_to_linker<T>()` function.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the name of the generated function.
+    pub fn function_name(mut self, name: impl Into<String>) -> Self {
+        self.function_name = name.into();
+        self
+    }
+
+    /// Use a concrete type for the store data, instead of a generic parameter.
+    pub fn state_type(mut self, state_type: impl Into<String>) -> Self {
+        self.state_type = Some(state_type.into());
+        self
+    }
+
+    /// Generate the Rust source code of the host shims for the imports of a module.
+    ///
+    /// Functions whose types can't be expressed as Rust types, as well as memories, tables,
+    /// globals and tags, are listed as comments, to be defined by hand.
+    pub fn generate(&self, module: &Module) -> Result<String, WSError> {
+        let mut code = String::new();
+        // Writing to a string can't fail.
+        let _ = self.write(&mut code, &module.interface()?);
+        Ok(code)
+    }
+
+    fn write(&self, code: &mut String, interface: &[InterfaceItem]) -> std::fmt::Result {
+        let (generics, state_type) = match &self.state_type {
+            Some(state_type) => (String::new(), state_type.as_str()),
+            None => ("<T: 'static>".to_string(), "T"),
+        };
+        writeln!(code, "// Host functions imported by the module.")?;
+        writeln!(
+            code,
+            "// Generated by `wasi-update host-shims`: regenerate after updating the module."
+        )?;
+        writeln!(code)?;
+        writeln!(code, "#[allow(unused_variables)]")?;
+        writeln!(
+            code,
+            "pub fn {}{}(linker: &mut wasmtime::Linker<{}>) -> wasmtime::Result<()> {{",
+            self.function_name, generics, state_type
+        )?;
+        let mut defined = HashSet::new();
+        for item in interface {
+            if item.direction != InterfaceDirection::Import || !defined.insert(&item.name) {
+                continue;
+            }
+            let (module, name) = item
+                .name
+                .split_once("::")
+                .unwrap_or(("", item.name.as_str()));
+            let (params, results) = match func_signature(&item.signature) {
+                Some(types) => types,
+                None if item.signature.starts_with("func") => {
+                    writeln!(
+                        code,
+                        "    // {}: {} (define it with `linker.func_new()`)",
+                        item.name, item.signature
+                    )?;
+                    continue;
+                }
+                None => {
+                    writeln!(
+                        code,
+                        "    // {}: {} (define it with `linker.define()`)",
+                        item.name, item.signature
+                    )?;
+                    continue;
+                }
+            };
+            let mut closure_params = format!("caller: wasmtime::Caller<'_, {}>", state_type);
+            for (i, param) in params.iter().enumerate() {
+                write!(closure_params, ", p{}: {}", i, param)?;
+            }
+            let result = match results.as_slice() {
+                [] => "()".to_string(),
+                [result] => result.to_string(),
+                results => format!("({})", results.join(", ")),
+            };
+            writeln!(code, "    // {}: {}", item.name, item.signature)?;
+            writeln!(code, "    linker.func_wrap(")?;
+            writeln!(code, "        {:?},", module)?;
+            writeln!(code, "        {:?},", name)?;
+            writeln!(
+                code,
+                "        |{}| -> wasmtime::Result<{}> {{",
+                closure_params, result
+            )?;
+            writeln!(code, "            todo!()")?;
+            writeln!(code, "        }},")?;
+            writeln!(code, "    )?;")?;
+        }
+        writeln!(code, "    Ok(())")?;
+        writeln!(code, "}}")
+    }
+}
+
+impl Module {
+    /// Generate the Rust source code of `wasmtime` host shims for the imports of the module.
+    pub fn host_shims(&self, options: &HostShims) -> Result<String, WSError> {
+        options.generate(self)
+    }
+}
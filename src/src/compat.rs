@@ -0,0 +1,184 @@
+//! Compatibility checks of modules against the capabilities of a device.
+//!
+//! A device profile describes what a class of devices can run, and is usually kept next to the
+//! build scripts, so that incompatible modules are caught before they are published:
+//!
+//! ```toml
+//! name = "sensor-v2"
+//! features = ["mutable-global", "sign-extension", "bulk-memory", "multi-value"]
+//! max_memory_pages = 32
+//! allowed_imports = ["wasi_snapshot_preview1::*", "env::log"]
+//! ```
+//!
+//! Features use the names of the `wasmparser` features, in kebab case. Checking them requires
+//! the `wasm-tools` feature.
+
+use crate::error::*;
+use crate::sanitize;
+use crate::wasm_module::*;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// What a class of devices can run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceProfile {
+    /// Name of the profile.
+    pub name: String,
+    /// WebAssembly features supported by the runtime, in addition to the MVP.
+    /// If absent, features are not checked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<Vec<String>>,
+    /// Maximum number of pages of the memories defined by the module.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_memory_pages: Option<u64>,
+    /// Imports provided by the host, as `module::name`. A name of `*` allows every name of a
+    /// module. If absent, imports are not checked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_imports: Option<Vec<String>>,
+}
+
+impl DeviceProfile {
+    /// Parse a profile.
+    pub fn from_toml(toml: &str) -> Result<Self, WSError> {
+        toml::from_str(toml).map_err(|e| WSError::ConfigError(e.message().to_string()))
+    }
+
+    /// Load a profile file.
+    pub fn load(file: impl AsRef<Path>) -> Result<Self, WSError> {
+        Self::from_toml(&fs::read_to_string(file.as_ref())?)
+    }
+
+    fn allows_import(&self, module: &str, name: &str) -> bool {
+        let allowed_imports = match &self.allowed_imports {
+            None => return true,
+            Some(allowed_imports) => allowed_imports,
+        };
+        allowed_imports
+            .iter()
+            .any(|allowed| match allowed.split_once("::") {
+                Some((m, n)) => m == module && (n == "*" || n == name),
+                None => false,
+            })
+    }
+}
+
+/// The outcome of a compatibility check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompatCheck {
+    /// Name of the check: `features`, `imports` or `memory`.
+    pub name: &'static str,
+    /// Reasons the check failed. Empty if it passed.
+    pub reasons: Vec<String>,
+}
+
+impl CompatCheck {
+    /// Return `true` if the check passed.
+    pub fn passed(&self) -> bool {
+        self.reasons.is_empty()
+    }
+}
+
+impl fmt::Display for CompatCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.passed() {
+            return write!(f, "PASS {}", self.name);
+        }
+        write!(f, "FAIL {}: {}", self.name, self.reasons.join("; "))
+    }
+}
+
+/// The outcome of all the checks of a module against a device profile.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompatReport {
+    /// Checks that were run, in order. Checks the profile doesn't configure are skipped.
+    pub checks: Vec<CompatCheck>,
+}
+
+impl CompatReport {
+    /// Return `true` if every check passed.
+    pub fn is_compatible(&self) -> bool {
+        self.checks.iter().all(CompatCheck::passed)
+    }
+}
+
+#[cfg(feature = "wasm-tools")]
+fn feature_reasons(module: &Module, features: &[String]) -> Vec<String> {
+    let mut enabled = wasmparser::WasmFeatures::FLOATS;
+    let mut reasons = vec![];
+    for feature in features {
+        match wasmparser::WasmFeatures::from_name(&feature.replace('-', "_").to_uppercase()) {
+            Some(flag) => enabled |= flag,
+            None => reasons.push(format!("unknown feature in the profile: {}", feature)),
+        }
+    }
+    if reasons.is_empty() {
+        if let Err(e) = module.validate_with_features(enabled) {
+            reasons.push(format!("the module requires unsupported features: {}", e));
+        }
+    }
+    reasons
+}
+
+#[cfg(not(feature = "wasm-tools"))]
+fn feature_reasons(_module: &Module, _features: &[String]) -> Vec<String> {
+    vec!["features can't be checked without the wasm-tools feature".to_string()]
+}
+
+impl Module {
+    /// Check that the module can run on devices matching a profile.
+    pub fn check_compat(&self, profile: &DeviceProfile) -> Result<CompatReport, WSError> {
+        if self.is_component() {
+            return Err(WSError::UnsupportedModuleType);
+        }
+        let mut report = CompatReport::default();
+        if let Some(features) = &profile.features {
+            report.checks.push(CompatCheck {
+                name: "features",
+                reasons: feature_reasons(self, features),
+            });
+        }
+        if profile.allowed_imports.is_some() {
+            let mut reasons = vec![];
+            for section in &self.sections {
+                if section.id() == SectionId::Import {
+                    for (module, name) in sanitize::imports(section.payload())? {
+                        if !profile.allows_import(&module, &name) {
+                            reasons.push(format!("import {}::{} is not provided", module, name));
+                        }
+                    }
+                }
+            }
+            report.checks.push(CompatCheck {
+                name: "imports",
+                reasons,
+            });
+        }
+        if let Some(max_pages) = profile.max_memory_pages {
+            let mut reasons = vec![];
+            for section in &self.sections {
+                if section.id() == SectionId::Memory {
+                    for (index, (min, _)) in sanitize::memory_limits(section.payload())?
+                        .into_iter()
+                        .enumerate()
+                    {
+                        if min > max_pages {
+                            reasons.push(format!(
+                                "memory {} requires {} pages, more than the maximum of {}",
+                                index, min, max_pages
+                            ));
+                        }
+                    }
+                }
+            }
+            report.checks.push(CompatCheck {
+                name: "memory",
+                reasons,
+            });
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,112 @@
+//! Compression of individual custom sections.
+//!
+//! A compressed section is renamed by appending `COMPRESSED_SECTION_SUFFIX` to its name.
+//! Its payload is the size of the original payload, as a varint, followed by the zlib-compressed payload.
+
+use crate::error::*;
+use crate::wasm_module::*;
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use log::*;
+use std::io::{self, prelude::*};
+
+/// Suffix added to the name of compressed custom sections.
+pub const COMPRESSED_SECTION_SUFFIX: &str = ".zlib";
+
+/// Maximum size of a decompressed section.
+pub const MAX_DECOMPRESSED_SIZE: u64 = 256 * 1024 * 1024;
+
+impl CustomSection {
+    /// Return `true` if the section is compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.name().len() > COMPRESSED_SECTION_SUFFIX.len()
+            && self.name().ends_with(COMPRESSED_SECTION_SUFFIX)
+    }
+
+    /// Return a compressed version of the section.
+    pub fn compress(&self) -> Result<CustomSection, WSError> {
+        if self.is_compressed() {
+            return Ok(self.clone());
+        }
+        let mut payload = vec![];
+        varint::put(&mut payload, self.payload().len() as _)?;
+        let mut encoder = ZlibEncoder::new(payload, Compression::best());
+        encoder.write_all(self.payload())?;
+        let payload = encoder.finish()?;
+        Ok(CustomSection::new(
+            format!("{}{}", self.name(), COMPRESSED_SECTION_SUFFIX),
+            payload,
+        ))
+    }
+
+    /// Return a decompressed version of the section.
+    ///
+    /// Sections that are not compressed are returned as-is.
+    pub fn decompress(&self) -> Result<CustomSection, WSError> {
+        if !self.is_compressed() {
+            return Ok(self.clone());
+        }
+        let mut reader = io::Cursor::new(self.payload());
+        let len = varint::get32(&mut reader)? as u64;
+        if len > MAX_DECOMPRESSED_SIZE {
+            return Err(WSError::ParseError);
+        }
+        let mut payload = vec![];
+        ZlibDecoder::new(reader)
+            .take(len + 1)
+            .read_to_end(&mut payload)
+            .map_err(|_| WSError::ParseError)?;
+        if payload.len() as u64 != len {
+            return Err(WSError::ParseError);
+        }
+        let name = &self.name()[..self.name().len() - COMPRESSED_SECTION_SUFFIX.len()];
+        Ok(CustomSection::new(name.to_string(), payload))
+    }
+}
+
+impl Module {
+    /// Compress the custom sections with the given names.
+    ///
+    /// Sections are only replaced if compression makes them smaller.
+    /// Return the number of sections that have been compressed.
+    pub fn compress_sections(&mut self, names: &[impl AsRef<str>]) -> Result<usize, WSError> {
+        let mut count = 0;
+        for section in self.sections.iter_mut() {
+            let custom_section = match section {
+                Section::Custom(s) if names.iter().any(|n| n.as_ref() == s.name()) => s,
+                _ => continue,
+            };
+            let compressed = custom_section.compress()?;
+            if compressed.outer_payload()?.len() >= custom_section.outer_payload()?.len() {
+                continue;
+            }
+            debug!(
+                "Compressed section [{}]: {} -> {} bytes",
+                custom_section.name(),
+                custom_section.payload().len(),
+                compressed.payload().len()
+            );
+            *section = Section::Custom(compressed);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Decompress all the compressed custom sections.
+    ///
+    /// Return the number of sections that have been decompressed.
+    pub fn decompress_sections(&mut self) -> Result<usize, WSError> {
+        let mut count = 0;
+        for section in self.sections.iter_mut() {
+            if let Section::Custom(s) = section {
+                if s.is_compressed() {
+                    *section = Section::Custom(s.decompress()?);
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,534 @@
+//! Configuration files shared by the CLI and the agent.
+//!
+//! Settings are read from a TOML file, then overridden by `WASI_UPDATE_<SECTION>_<KEY>` environment
+//! variables (such as `WASI_UPDATE_AGENT_CHANNEL`), and finally by command-line flags.
+//! Relative paths in a file are relative to the directory of that file.
+//!
+//! ```toml
+//! [repository]
+//! path = "repo"
+//! url = "https://updates.example.com"
+//!
+//! [keys]
+//! signing = "keys/release.key"
+//! trusted = ["keys/release.pub"]
+//!
+//! [agent]
+//! device_id = "device-42"
+//! channel = "stable"
+//! slots = "/var/lib/wasi-update"
+//! download_windows = ["01:00-05:00"]
+//!
+//! [registry]
+//! user = "ci"
+//!
+//! [limits]
+//! memory = 67108864
+//! ```
+
+use crate::agent::*;
+use crate::error::*;
+#[cfg(feature = "oci")]
+use crate::oci::*;
+#[cfg(feature = "registry")]
+use crate::registry::*;
+use crate::repo::*;
+use crate::signature::*;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Environment variable pointing to the configuration file.
+pub const CONFIG_ENV_VAR: &str = "WASI_UPDATE_CONFIG";
+
+/// Prefix of the environment variables overriding settings.
+pub const ENV_VAR_PREFIX: &str = "WASI_UPDATE_";
+
+/// Names of the settings that can be overridden.
+pub const SETTINGS: &[&str] = &[
+    "repository.path",
+    "repository.url",
+    "repository.listen",
+    "repository.proxy",
+    "keys.signing",
+    "keys.trusted",
+    "keys.revocation_root",
+    "keys.downgrade",
+    "keys.golden",
+    "keys.domain",
+    "agent.device_id",
+    "agent.channel",
+    "agent.slots",
+    "agent.interval",
+    "agent.rate_limit",
+    "agent.download_windows",
+    "agent.low_space",
+    "agent.ignored_sections",
+    "agent.require_nonce",
+    "agent.allow_unsigned",
+    "registry.user",
+    "registry.password",
+    "limits.memory",
+    "s3.endpoint",
+    "s3.bucket",
+    "s3.region",
+    "s3.prefix",
+    "s3.access_key_id",
+    "s3.secret_access_key",
+    "s3.session_token",
+];
+
+/// Location of the update repository.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepositorySection {
+    /// Local repository directory.
+    pub path: Option<PathBuf>,
+    /// URL of the update server.
+    pub url: Option<String>,
+    /// Address the update server listens on.
+    pub listen: Option<String>,
+    /// HTTP proxy to connect to the update server through.
+    pub proxy: Option<String>,
+}
+
+/// Key files.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeysSection {
+    /// Secret key to sign modules and manifests with.
+    pub signing: Option<PathBuf>,
+    /// Public keys allowed to sign updates.
+    pub trusted: Vec<PathBuf>,
+    /// Public key signing revocation lists.
+    pub revocation_root: Option<PathBuf>,
+    /// Public keys allowed to authorize downgrades to a lower security version.
+    pub downgrade: Vec<PathBuf>,
+    /// Public keys allowed to sign golden modules (default: the trusted keys).
+    pub golden: Vec<PathBuf>,
+    /// Signing domain (tenant or project) the signing, trusted, downgrade and golden keys are bound to.
+    pub domain: Option<String>,
+}
+
+/// Settings of the update agent.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentSection {
+    pub device_id: Option<String>,
+    pub channel: Option<String>,
+    /// Storage directory for the slots.
+    pub slots: Option<PathBuf>,
+    /// Time between two update checks, in seconds.
+    pub interval: Option<u64>,
+    /// Maximum download rate, in bytes per second.
+    pub rate_limit: Option<u64>,
+    /// Windows during which downloads are allowed, such as `01:00-05:00`.
+    pub download_windows: Vec<String>,
+    pub low_space: Option<bool>,
+    /// Custom sections left out of signature verification.
+    pub ignored_sections: Vec<String>,
+    /// Require signed update-check responses, bound to a nonce sent by the device.
+    pub require_nonce: Option<bool>,
+    /// Install unsigned updates if no trusted keys are configured. For development only.
+    pub allow_unsigned: Option<bool>,
+}
+
+/// Credentials for OCI registries.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistrySection {
+    pub user: Option<String>,
+    /// Password or access token. Without a user, it is sent as a bearer token.
+    pub password: Option<String>,
+}
+
+/// Resource limits.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    /// Maximum memory holding module data, in bytes.
+    pub memory: Option<u64>,
+}
+
+/// Object store keeping the update repository, instead of `repository.path`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Section {
+    /// URL of the object store, such as `https://s3.eu-west-1.amazonaws.com`.
+    pub endpoint: Option<String>,
+    pub bucket: Option<String>,
+    pub region: Option<String>,
+    /// Prefix of the keys of the repository within the bucket.
+    pub prefix: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub session_token: Option<String>,
+}
+
+/// A configuration, shared by the CLI and the agent.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub repository: RepositorySection,
+    pub keys: KeysSection,
+    pub agent: AgentSection,
+    pub registry: RegistrySection,
+    pub limits: LimitsSection,
+    pub s3: S3Section,
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+fn parse_number(name: &str, value: &str) -> Result<u64, WSError> {
+    value
+        .parse()
+        .map_err(|_| WSError::ConfigError(format!("{} must be a number", name)))
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, WSError> {
+    match value {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" => Ok(false),
+        _ => Err(WSError::ConfigError(format!("{} must be a boolean", name))),
+    }
+}
+
+fn read_public_key(file: &Path) -> Result<PublicKey, WSError> {
+    PublicKey::from_bytes(&fs::read(file)?)
+}
+
+impl Config {
+    /// Parse a configuration.
+    pub fn from_toml(toml: &str) -> Result<Self, WSError> {
+        toml::from_str(toml).map_err(|e| WSError::ConfigError(e.message().to_string()))
+    }
+
+    /// Load a configuration file.
+    pub fn load(file: impl AsRef<Path>) -> Result<Self, WSError> {
+        let file = file.as_ref();
+        let mut config = Self::from_toml(&fs::read_to_string(file)?)?;
+        if let Some(dir) = file.parent() {
+            config.resolve_paths(dir);
+        }
+        debug!("Loaded configuration from [{}]", file.display());
+        Ok(config)
+    }
+
+    /// Load the configuration from `file`, or from the file set in `WASI_UPDATE_CONFIG`,
+    /// and apply the overrides from the environment.
+    ///
+    /// If no file is given, the configuration only comes from the environment.
+    pub fn load_layered(file: Option<&Path>) -> Result<Self, WSError> {
+        let env_file = std::env::var_os(CONFIG_ENV_VAR).map(PathBuf::from);
+        let mut config = match file.or(env_file.as_deref()) {
+            Some(file) => Self::load(file)?,
+            None => Self::default(),
+        };
+        config.apply_env(std::env::vars())?;
+        Ok(config)
+    }
+
+    fn resolve_paths(&mut self, dir: &Path) {
+        let paths = [
+            self.repository.path.as_mut(),
+            self.keys.signing.as_mut(),
+            self.keys.revocation_root.as_mut(),
+            self.agent.slots.as_mut(),
+        ];
+        for path in paths
+            .into_iter()
+            .flatten()
+            .chain(self.keys.trusted.iter_mut())
+            .chain(self.keys.downgrade.iter_mut())
+            .chain(self.keys.golden.iter_mut())
+        {
+            if path.is_relative() {
+                *path = dir.join(&*path);
+            }
+        }
+    }
+
+    /// Override settings with `WASI_UPDATE_<SECTION>_<KEY>` variables.
+    ///
+    /// Lists are comma-separated.
+    pub fn apply_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), WSError> {
+        for (name, value) in vars {
+            let setting = match name.strip_prefix(ENV_VAR_PREFIX) {
+                Some(setting) if name != CONFIG_ENV_VAR => setting.to_ascii_lowercase(),
+                _ => continue,
+            };
+            let setting = setting.replacen('_', ".", 1);
+            if !SETTINGS.contains(&setting.as_str()) {
+                warn!("Ignoring unknown setting [{}]", name);
+                continue;
+            }
+            self.set(&setting, &value)?;
+        }
+        Ok(())
+    }
+
+    /// Set a value, given its name, such as `agent.channel`.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), WSError> {
+        match name {
+            "repository.path" => self.repository.path = Some(value.into()),
+            "repository.url" => self.repository.url = Some(value.to_string()),
+            "repository.listen" => self.repository.listen = Some(value.to_string()),
+            "repository.proxy" => self.repository.proxy = Some(value.to_string()),
+            "keys.signing" => self.keys.signing = Some(value.into()),
+            "keys.trusted" => {
+                self.keys.trusted = parse_list(value).into_iter().map(PathBuf::from).collect()
+            }
+            "keys.revocation_root" => self.keys.revocation_root = Some(value.into()),
+            "keys.downgrade" => {
+                self.keys.downgrade = parse_list(value).into_iter().map(PathBuf::from).collect()
+            }
+            "keys.golden" => {
+                self.keys.golden = parse_list(value).into_iter().map(PathBuf::from).collect()
+            }
+            "keys.domain" => self.keys.domain = Some(value.to_string()),
+            "agent.device_id" => self.agent.device_id = Some(value.to_string()),
+            "agent.channel" => self.agent.channel = Some(value.to_string()),
+            "agent.slots" => self.agent.slots = Some(value.into()),
+            "agent.interval" => self.agent.interval = Some(parse_number(name, value)?),
+            "agent.rate_limit" => self.agent.rate_limit = Some(parse_number(name, value)?),
+            "agent.download_windows" => self.agent.download_windows = parse_list(value),
+            "agent.low_space" => self.agent.low_space = Some(parse_bool(name, value)?),
+            "agent.ignored_sections" => self.agent.ignored_sections = parse_list(value),
+            "agent.require_nonce" => self.agent.require_nonce = Some(parse_bool(name, value)?),
+            "agent.allow_unsigned" => self.agent.allow_unsigned = Some(parse_bool(name, value)?),
+            "registry.user" => self.registry.user = Some(value.to_string()),
+            "registry.password" => self.registry.password = Some(value.to_string()),
+            "limits.memory" => self.limits.memory = Some(parse_number(name, value)?),
+            "s3.endpoint" => self.s3.endpoint = Some(value.to_string()),
+            "s3.bucket" => self.s3.bucket = Some(value.to_string()),
+            "s3.region" => self.s3.region = Some(value.to_string()),
+            "s3.prefix" => self.s3.prefix = Some(value.to_string()),
+            "s3.access_key_id" => self.s3.access_key_id = Some(value.to_string()),
+            "s3.secret_access_key" => self.s3.secret_access_key = Some(value.to_string()),
+            "s3.session_token" => self.s3.session_token = Some(value.to_string()),
+            _ => return Err(WSError::ConfigError(format!("unknown setting: {}", name))),
+        }
+        Ok(())
+    }
+
+    /// Load a secret key, bound to the signing domain if one is configured.
+    pub fn read_secret_key(&self, file: &Path) -> Result<SecretKey, WSError> {
+        let sk = SecretKey::from_bytes(&fs::read(file)?)?;
+        Ok(match &self.keys.domain {
+            None => sk,
+            Some(domain) => sk.in_domain(domain),
+        })
+    }
+
+    /// Load the signing key, if one is configured.
+    pub fn signing_key(&self) -> Result<Option<SecretKey>, WSError> {
+        self.keys
+            .signing
+            .as_deref()
+            .map(|file| self.read_secret_key(file))
+            .transpose()
+    }
+
+    fn read_key_ring(&self, files: &[PathBuf]) -> Result<KeyRing, WSError> {
+        let mut key_ring = KeyRing::new();
+        for file in files {
+            let pk = read_public_key(file)?;
+            key_ring.add(match &self.keys.domain {
+                None => pk,
+                Some(domain) => pk.in_domain(domain),
+            });
+        }
+        if let Some(domain) = &self.keys.domain {
+            key_ring.set_domain(domain);
+        }
+        Ok(key_ring)
+    }
+
+    /// Load the trusted public keys.
+    pub fn trusted_keys(&self) -> Result<KeyRing, WSError> {
+        self.read_key_ring(&self.keys.trusted)
+    }
+
+    /// Load the public keys allowed to authorize downgrades.
+    pub fn downgrade_keys(&self) -> Result<KeyRing, WSError> {
+        self.read_key_ring(&self.keys.downgrade)
+    }
+
+    /// Load the public keys allowed to sign golden modules.
+    pub fn golden_keys(&self) -> Result<KeyRing, WSError> {
+        self.read_key_ring(&self.keys.golden)
+    }
+
+    /// Build the configuration of an agent.
+    pub fn agent_config(&self) -> Result<AgentConfig, WSError> {
+        let missing = |name: &str| WSError::ConfigError(format!("{} is not set", name));
+        let agent = &self.agent;
+        let mut config = AgentConfig::new(
+            agent
+                .device_id
+                .as_deref()
+                .ok_or_else(|| missing("agent.device_id"))?,
+            agent
+                .channel
+                .as_deref()
+                .ok_or_else(|| missing("agent.channel"))?,
+        );
+        if let Some(interval) = agent.interval {
+            config.interval = Duration::from_secs(interval);
+        }
+        config.fetch.rate_limit = agent.rate_limit;
+        for window in &agent.download_windows {
+            config.fetch.windows.push(DownloadWindow::parse(window)?);
+        }
+        config.low_space = agent.low_space.unwrap_or(false);
+        config.trusted_keys = self.trusted_keys()?;
+        config.downgrade_keys = self.downgrade_keys()?;
+        config.golden_keys = self.golden_keys()?;
+        config.revocation_root = self
+            .keys
+            .revocation_root
+            .as_deref()
+            .map(read_public_key)
+            .transpose()?;
+        config.ignored_sections = agent.ignored_sections.iter().cloned().collect();
+        config.require_nonce = agent.require_nonce.unwrap_or(false);
+        config.allow_unsigned = agent.allow_unsigned.unwrap_or(false);
+        Ok(config)
+    }
+
+    /// Open the storage backend of the configured repository: the object store if `s3.bucket` is
+    /// set, or the `repository.path` directory.
+    pub fn repository_backend(&self) -> Result<Box<dyn Backend>, WSError> {
+        if let Some(bucket) = &self.s3.bucket {
+            return self.s3_backend(bucket);
+        }
+        let path = self
+            .repository
+            .path
+            .as_ref()
+            .ok_or_else(|| WSError::ConfigError("repository.path is not set".to_string()))?;
+        Ok(Box::new(FsBackend::new(path)?))
+    }
+
+    #[cfg(feature = "s3")]
+    fn s3_backend(&self, bucket: &str) -> Result<Box<dyn Backend>, WSError> {
+        let s3 = &self.s3;
+        let endpoint = s3
+            .endpoint
+            .as_ref()
+            .ok_or_else(|| WSError::ConfigError("s3.endpoint is not set".to_string()))?;
+        let mut transport = HttpTransport::new(endpoint);
+        if let Some(proxy) = &self.repository.proxy {
+            transport = transport.proxy(proxy)?;
+        }
+        let region = s3.region.as_deref().unwrap_or("us-east-1");
+        let mut backend = S3Backend::with_transport(transport, bucket, region);
+        if let Some(prefix) = &s3.prefix {
+            backend = backend.prefix(prefix)?;
+        }
+        match (&s3.access_key_id, &s3.secret_access_key) {
+            (Some(access_key_id), Some(secret_access_key)) => {
+                backend = backend.credentials(access_key_id, secret_access_key)
+            }
+            (None, None) => {}
+            _ => {
+                return Err(WSError::ConfigError(
+                    "s3.access_key_id and s3.secret_access_key must be set together".to_string(),
+                ))
+            }
+        }
+        if let Some(session_token) = &s3.session_token {
+            backend = backend.session_token(session_token);
+        }
+        Ok(Box::new(backend))
+    }
+
+    #[cfg(not(feature = "s3"))]
+    fn s3_backend(&self, _bucket: &str) -> Result<Box<dyn Backend>, WSError> {
+        Err(WSError::ConfigError(
+            "s3.bucket is set, but S3 support is not enabled".to_string(),
+        ))
+    }
+
+    /// Build a transport to the configured update server.
+    ///
+    /// With `agent.require_nonce`, update-check responses must be signed by one of the trusted keys.
+    pub fn transport(&self) -> Result<HttpTransport, WSError> {
+        let url = self
+            .repository
+            .url
+            .as_ref()
+            .ok_or_else(|| WSError::ConfigError("repository.url is not set".to_string()))?;
+        let mut transport = HttpTransport::new(url);
+        if let Some(proxy) = &self.repository.proxy {
+            transport = transport.proxy(proxy)?;
+        }
+        if self.agent.require_nonce == Some(true) {
+            let trusted_keys = self.trusted_keys()?;
+            if trusted_keys.is_empty() {
+                return Err(WSError::ConfigError(
+                    "agent.require_nonce is set, but keys.trusted is empty".to_string(),
+                ));
+            }
+            transport = transport.manifest_keys(trusted_keys);
+        }
+        Ok(transport)
+    }
+
+    /// Build a client for an OCI registry, such as `https://ghcr.io`, with the configured credentials.
+    #[cfg(feature = "oci")]
+    pub fn oci_client(&self, registry: &str) -> Result<OciClient, WSError> {
+        let mut transport = HttpTransport::new(registry);
+        if let Some(proxy) = &self.repository.proxy {
+            transport = transport.proxy(proxy)?;
+        }
+        let client = OciClient::with_transport(transport);
+        Ok(match (&self.registry.user, &self.registry.password) {
+            (Some(user), Some(password)) => client.credentials(user, password),
+            (None, Some(token)) => client.token(token),
+            _ => client,
+        })
+    }
+
+    /// Discover the package registry of a domain, such as `example.com` or `http://localhost:5000`.
+    #[cfg(feature = "registry")]
+    pub fn package_registry(&self, domain: &str) -> Result<PackageRegistry, WSError> {
+        let url = match domain.contains("://") {
+            true => domain.to_string(),
+            false => format!("https://{}", domain),
+        };
+        let mut transport = HttpTransport::new(url);
+        if let Some(proxy) = &self.repository.proxy {
+            transport = transport.proxy(proxy)?;
+        }
+        let (registry, namespace_prefix) = PackageRegistry::discover(&transport)?;
+        Ok(PackageRegistry::new(
+            self.oci_client(&registry)?,
+            registry,
+            namespace_prefix,
+        ))
+    }
+
+    /// Open the storage of the agent.
+    pub fn storage(&self) -> Result<FsStorage, WSError> {
+        let slots = self
+            .agent
+            .slots
+            .as_ref()
+            .ok_or_else(|| WSError::ConfigError("agent.slots is not set".to_string()))?;
+        FsStorage::new(slots)
+    }
+}
@@ -0,0 +1,237 @@
+//! Configuration a module expects from the host.
+//!
+//! A module can declare the configuration keys it reads, and their types, in a custom section.
+//! Checking them against the configuration provided by a device before installing an update
+//! avoids booting a module that cannot start because a setting is missing.
+
+use crate::error::*;
+use crate::wasm_module::*;
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io::{self, prelude::*};
+use std::str;
+
+/// Name of the custom section storing the configuration schema.
+pub const CONFIG_SCHEMA_SECTION_NAME: &str = "config-schema";
+
+const CONFIG_SCHEMA_VERSION: u8 = 0x01;
+
+const FLAG_REQUIRED: u8 = 0x01;
+
+/// The type of a configuration value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ConfigType {
+    String,
+    Integer,
+    Boolean,
+    Bytes,
+}
+
+impl ConfigType {
+    fn to_u8(self) -> u8 {
+        match self {
+            ConfigType::String => 0x00,
+            ConfigType::Integer => 0x01,
+            ConfigType::Boolean => 0x02,
+            ConfigType::Bytes => 0x03,
+        }
+    }
+
+    fn from_u8(v: u8) -> Result<Self, WSError> {
+        match v {
+            0x00 => Ok(ConfigType::String),
+            0x01 => Ok(ConfigType::Integer),
+            0x02 => Ok(ConfigType::Boolean),
+            0x03 => Ok(ConfigType::Bytes),
+            _ => Err(WSError::ParseError),
+        }
+    }
+}
+
+impl fmt::Display for ConfigType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ConfigType::String => "string",
+            ConfigType::Integer => "integer",
+            ConfigType::Boolean => "boolean",
+            ConfigType::Bytes => "bytes",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A configuration value provided by the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigValue {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Bytes(Vec<u8>),
+}
+
+impl ConfigValue {
+    /// Return the type of the value.
+    pub fn config_type(&self) -> ConfigType {
+        match self {
+            ConfigValue::String(_) => ConfigType::String,
+            ConfigValue::Integer(_) => ConfigType::Integer,
+            ConfigValue::Boolean(_) => ConfigType::Boolean,
+            ConfigValue::Bytes(_) => ConfigType::Bytes,
+        }
+    }
+}
+
+/// The configuration provided by a host, by key.
+pub type HostConfig = BTreeMap<String, ConfigValue>;
+
+/// A configuration key expected by a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigKey {
+    pub name: String,
+    pub config_type: ConfigType,
+    /// `false` if the module can start without it.
+    pub required: bool,
+}
+
+impl ConfigKey {
+    /// Create a key the module cannot start without.
+    pub fn required(name: impl Into<String>, config_type: ConfigType) -> Self {
+        ConfigKey {
+            name: name.into(),
+            config_type,
+            required: true,
+        }
+    }
+
+    /// Create a key the module can start without.
+    pub fn optional(name: impl Into<String>, config_type: ConfigType) -> Self {
+        ConfigKey {
+            name: name.into(),
+            config_type,
+            required: false,
+        }
+    }
+}
+
+/// The configuration keys expected by a module, stored as a custom section.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigSchema {
+    pub keys: Vec<ConfigKey>,
+}
+
+impl ConfigSchema {
+    /// Serialize the schema as a custom section payload.
+    pub fn serialize(&self) -> Result<Vec<u8>, WSError> {
+        let mut writer = io::Cursor::new(vec![]);
+        writer.write_all(&[CONFIG_SCHEMA_VERSION])?;
+        varint::put(&mut writer, self.keys.len() as _)?;
+        for key in &self.keys {
+            varint::put_slice(&mut writer, key.name.as_bytes())?;
+            let flags = if key.required { FLAG_REQUIRED } else { 0 };
+            writer.write_all(&[key.config_type.to_u8(), flags])?;
+        }
+        Ok(writer.into_inner())
+    }
+
+    /// Deserialize a schema from a custom section payload.
+    ///
+    /// Schemas declaring the same key twice are rejected.
+    pub fn deserialize(payload: &[u8]) -> Result<Self, WSError> {
+        TakeExact::decode(payload, payload.len() as _, |reader| {
+            let mut version = [0u8; 1];
+            reader.read_exact(&mut version)?;
+            if version[0] != CONFIG_SCHEMA_VERSION {
+                return Err(WSError::ParseError);
+            }
+            let count = varint::get32(reader)?;
+            let mut keys = vec![];
+            let mut names = HashSet::new();
+            for _ in 0..count {
+                let name = str::from_utf8(&reader.get_slice()?)?.to_string();
+                let mut type_and_flags = [0u8; 2];
+                reader.read_exact(&mut type_and_flags)?;
+                let config_type = ConfigType::from_u8(type_and_flags[0])?;
+                if type_and_flags[1] & !FLAG_REQUIRED != 0 || !names.insert(name.clone()) {
+                    return Err(WSError::ParseError);
+                }
+                keys.push(ConfigKey {
+                    name,
+                    config_type,
+                    required: type_and_flags[1] & FLAG_REQUIRED != 0,
+                });
+            }
+            Ok(ConfigSchema { keys })
+        })
+    }
+
+    /// Return a description of each key missing from, or having the wrong type in, a host configuration.
+    ///
+    /// Keys provided by the host but not declared by the schema are ignored.
+    pub fn incompatibilities(&self, host_config: &HostConfig) -> Vec<String> {
+        self.keys
+            .iter()
+            .filter_map(|key| match host_config.get(&key.name) {
+                None if key.required => Some(format!("{} is missing", key.name)),
+                Some(value) if value.config_type() != key.config_type => Some(format!(
+                    "{} must be of type {}, not {}",
+                    key.name,
+                    key.config_type,
+                    value.config_type()
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Check that a host configuration provides everything the module expects.
+    pub fn check(&self, host_config: &HostConfig) -> Result<(), WSError> {
+        let incompatibilities = self.incompatibilities(host_config);
+        if !incompatibilities.is_empty() {
+            return Err(WSError::IncompatibleConfig(incompatibilities.join(", ")));
+        }
+        Ok(())
+    }
+}
+
+impl Module {
+    /// Return the configuration schema of the module, if it has one.
+    pub fn config_schema(&self) -> Result<Option<ConfigSchema>, WSError> {
+        for section in &self.sections {
+            if let Section::Custom(custom) = section {
+                if custom.name() == CONFIG_SCHEMA_SECTION_NAME {
+                    return Ok(Some(ConfigSchema::deserialize(custom.payload())?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Embed a configuration schema into the module, replacing the existing one.
+    pub fn set_config_schema(&mut self, schema: &ConfigSchema) -> Result<(), WSError> {
+        let payload = schema.serialize()?;
+        for section in self.sections.iter_mut() {
+            if let Section::Custom(custom) = section {
+                if custom.name() == CONFIG_SCHEMA_SECTION_NAME {
+                    custom.set_payload(payload);
+                    return Ok(());
+                }
+            }
+        }
+        self.sections.push(Section::Custom(CustomSection::new(
+            CONFIG_SCHEMA_SECTION_NAME.to_string(),
+            payload,
+        )));
+        Ok(())
+    }
+
+    /// Check that a host configuration provides everything the module expects.
+    ///
+    /// Modules without a configuration schema are compatible with any configuration.
+    pub fn check_host_config(&self, host_config: &HostConfig) -> Result<(), WSError> {
+        match self.config_schema()? {
+            Some(schema) => schema.check(host_config),
+            None => Ok(()),
+        }
+    }
+}
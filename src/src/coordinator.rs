@@ -0,0 +1,582 @@
+//! Hot swaps of modules in live runtimes.
+//!
+//! An `UpdateCoordinator` replaces the module running in a runtime without restarting the host:
+//! it stops the instance from accepting work and waits for pending work to complete, takes a
+//! snapshot of its state, replaces it with the new module, and resumes it. Runtimes are driven
+//! through the `RuntimeAdapter` trait.
+
+use crate::error::*;
+
+use log::*;
+use std::time::{Duration, Instant};
+
+/// A step of a hot swap.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SwapStage {
+    Drain,
+    Snapshot,
+    Swap,
+    Resume,
+}
+
+/// Operations a runtime provides for hot swaps.
+///
+/// The running instance must be kept until `swap()` succeeds, so that it can be resumed
+/// if a stage fails.
+pub trait RuntimeAdapter {
+    /// Stop accepting work, and wait at most `timeout` for pending work to complete.
+    fn drain(&mut self, timeout: Duration) -> Result<(), WSError>;
+
+    /// Serialize the state of the running instance, or return `None` if it is stateless.
+    fn snapshot(&mut self) -> Result<Option<Vec<u8>>, WSError>;
+
+    /// Replace the running instance with an instance of `module_bin`, initialized with `state`.
+    fn swap(&mut self, module_bin: &[u8], state: Option<&[u8]>) -> Result<(), WSError>;
+
+    /// Accept work again.
+    fn resume(&mut self) -> Result<(), WSError>;
+}
+
+/// The time spent in a stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageTiming {
+    pub stage: SwapStage,
+    pub elapsed: Duration,
+}
+
+/// The result of a hot swap.
+#[derive(Debug)]
+pub struct SwapReport {
+    /// The stages that ran, in order, including the one that failed, and resuming the previous
+    /// instance after a failure.
+    pub stages: Vec<StageTiming>,
+    /// The stage that failed, and why.
+    pub failure: Option<(SwapStage, WSError)>,
+    /// `true` if the previous instance was resumed after a failure.
+    pub resumed_previous: bool,
+    /// Size of the state snapshot, in bytes.
+    pub snapshot_size: Option<u64>,
+    /// Total duration of the swap.
+    pub elapsed: Duration,
+}
+
+impl SwapReport {
+    /// Return `true` if the new module is running.
+    pub fn is_success(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    /// Return the time spent in a stage, if it ran.
+    pub fn stage_time(&self, stage: SwapStage) -> Option<Duration> {
+        self.stages
+            .iter()
+            .find(|timing| timing.stage == stage)
+            .map(|timing| timing.elapsed)
+    }
+
+    /// Return the time work was not accepted, from the start of draining to the end of resuming.
+    pub fn downtime(&self) -> Duration {
+        self.stages.iter().map(|timing| timing.elapsed).sum()
+    }
+}
+
+/// Sequences hot swaps against a runtime.
+#[derive(Debug)]
+pub struct UpdateCoordinator<R: RuntimeAdapter> {
+    runtime: R,
+    drain_timeout: Duration,
+}
+
+impl<R: RuntimeAdapter> UpdateCoordinator<R> {
+    /// Create a coordinator for a runtime.
+    pub fn new(runtime: R) -> Self {
+        UpdateCoordinator {
+            runtime,
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Set the maximum time to wait for pending work to complete. The default is 30 seconds.
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Return the runtime.
+    pub fn runtime(&mut self) -> &mut R {
+        &mut self.runtime
+    }
+
+    /// Replace the running module with `module_bin`: drain, snapshot, swap, then resume.
+    ///
+    /// If draining, taking the snapshot or swapping fails, the previous instance is resumed.
+    /// If resuming the new instance fails, it is left stopped, and the caller should roll back.
+    pub fn swap(&mut self, module_bin: &[u8]) -> SwapReport {
+        let started = Instant::now();
+        let mut report = SwapReport {
+            stages: vec![],
+            failure: None,
+            resumed_previous: false,
+            snapshot_size: None,
+            elapsed: Duration::ZERO,
+        };
+        let res = self.replace(&mut report, module_bin);
+        match res {
+            Ok(()) => {
+                let _ = Self::run_stage(&mut report, SwapStage::Resume, || self.runtime.resume());
+            }
+            Err(()) => {
+                let resume_started = Instant::now();
+                let res = self.runtime.resume();
+                report.stages.push(StageTiming {
+                    stage: SwapStage::Resume,
+                    elapsed: resume_started.elapsed(),
+                });
+                match res {
+                    Ok(()) => report.resumed_previous = true,
+                    Err(e) => warn!("Unable to resume the previous instance: {}", e),
+                }
+            }
+        }
+        report.elapsed = started.elapsed();
+        match &report.failure {
+            None => info!("Hot swap completed in {:?}", report.elapsed),
+            Some((stage, e)) => warn!("Hot swap failed at the {:?} stage: {}", stage, e),
+        }
+        report
+    }
+
+    fn replace(&mut self, report: &mut SwapReport, module_bin: &[u8]) -> Result<(), ()> {
+        let drain_timeout = self.drain_timeout;
+        let runtime = &mut self.runtime;
+        Self::run_stage(report, SwapStage::Drain, || runtime.drain(drain_timeout))?;
+        let state = Self::run_stage(report, SwapStage::Snapshot, || runtime.snapshot())?;
+        report.snapshot_size = state.as_ref().map(|state| state.len() as u64);
+        Self::run_stage(report, SwapStage::Swap, || {
+            runtime.swap(module_bin, state.as_deref())
+        })
+    }
+
+    fn run_stage<T>(
+        report: &mut SwapReport,
+        stage: SwapStage,
+        f: impl FnOnce() -> Result<T, WSError>,
+    ) -> Result<T, ()> {
+        let started = Instant::now();
+        let res = f();
+        report.stages.push(StageTiming {
+            stage,
+            elapsed: started.elapsed(),
+        });
+        res.map_err(|e| report.failure = Some((stage, e)))
+    }
+}
+
+#[cfg(feature = "wasmtime")]
+pub use self::wasmtime_runtime::WasmtimeRuntime;
+
+#[cfg(feature = "wasmtime")]
+mod wasmtime_runtime {
+    use super::*;
+    use crate::wasmtime_loader::WasmtimeLoader;
+
+    /// Export returning the state of an instance, as `(ptr << 32) | len`.
+    const SNAPSHOT_EXPORT: &str = "wasi-update.snapshot";
+    /// Export allocating `len` bytes for the state to restore, and returning a pointer to them.
+    const ALLOC_EXPORT: &str = "wasi-update.alloc";
+    /// Export restoring the state written at `ptr`, of length `len`.
+    const RESTORE_EXPORT: &str = "wasi-update.restore";
+
+    fn runtime_error(e: impl std::fmt::Display) -> WSError {
+        WSError::RuntimeError(e.to_string())
+    }
+
+    fn state_error(e: impl std::fmt::Display) -> WSError {
+        WSError::StateTransferFailed(e.to_string())
+    }
+
+    /// A module running in wasmtime, that can be hot swapped.
+    ///
+    /// Work is submitted with `call()`. Calls are synchronous, so that no work is pending once
+    /// draining starts, and calls made until the instance is resumed are rejected.
+    ///
+    /// Stateful modules export their memory as `memory`, along with `wasi-update.snapshot`
+    /// (`() -> i64`, returning the location of the serialized state as `(ptr << 32) | len`),
+    /// `wasi-update.alloc` (`(len: i32) -> i32`) and `wasi-update.restore` (`(ptr: i32, len: i32)`).
+    /// Modules that don't export `wasi-update.snapshot` are stateless. Each instance has its own
+    /// store, so that the memory of the previous instance is released after a swap.
+    pub struct WasmtimeRuntime<T: 'static> {
+        loader: WasmtimeLoader,
+        linker: wasmtime::Linker<T>,
+        new_data: Box<dyn FnMut() -> T + Send>,
+        store: wasmtime::Store<T>,
+        instance: wasmtime::Instance,
+        accepting: bool,
+    }
+
+    impl<T: 'static> WasmtimeRuntime<T> {
+        /// Instantiate a module that has already been verified.
+        ///
+        /// The linker provides the imports of every instance, and `new_data` the data of their
+        /// stores.
+        pub fn new(
+            loader: WasmtimeLoader,
+            linker: wasmtime::Linker<T>,
+            mut new_data: impl FnMut() -> T + Send + 'static,
+            module_bin: &[u8],
+        ) -> Result<Self, WSError> {
+            let data = new_data();
+            let (store, instance) = Self::instantiate(&loader, &linker, data, module_bin)?;
+            Ok(WasmtimeRuntime {
+                loader,
+                linker,
+                new_data: Box::new(new_data),
+                store,
+                instance,
+                accepting: true,
+            })
+        }
+
+        fn instantiate(
+            loader: &WasmtimeLoader,
+            linker: &wasmtime::Linker<T>,
+            data: T,
+            module_bin: &[u8],
+        ) -> Result<(wasmtime::Store<T>, wasmtime::Instance), WSError> {
+            let module = loader.load_bytes(module_bin)?;
+            let mut store = wasmtime::Store::new(loader.engine(), data);
+            let instance = linker
+                .instantiate(&mut store, &module)
+                .map_err(runtime_error)?;
+            Ok((store, instance))
+        }
+
+        /// Return the running instance, along with its store.
+        pub fn instance(&mut self) -> (&mut wasmtime::Store<T>, wasmtime::Instance) {
+            (&mut self.store, self.instance)
+        }
+
+        /// Call a function exported by the running instance.
+        ///
+        /// Calls are rejected while the instance is drained.
+        pub fn call<Params, Results>(
+            &mut self,
+            name: &str,
+            params: Params,
+        ) -> Result<Results, WSError>
+        where
+            Params: wasmtime::WasmParams,
+            Results: wasmtime::WasmResults,
+        {
+            if !self.accepting {
+                return Err(WSError::RuntimeError(
+                    "the instance is not accepting work".to_string(),
+                ));
+            }
+            self.instance
+                .get_typed_func::<Params, Results>(&mut self.store, name)
+                .and_then(|func| func.call(&mut self.store, params))
+                .map_err(runtime_error)
+        }
+    }
+
+    fn memory<T: 'static>(
+        store: &mut wasmtime::Store<T>,
+        instance: &wasmtime::Instance,
+    ) -> Result<wasmtime::Memory, WSError> {
+        instance
+            .get_memory(&mut *store, "memory")
+            .ok_or_else(|| state_error("the module doesn't export its memory"))
+    }
+
+    impl<T: 'static> RuntimeAdapter for WasmtimeRuntime<T> {
+        fn drain(&mut self, _timeout: Duration) -> Result<(), WSError> {
+            self.accepting = false;
+            Ok(())
+        }
+
+        fn snapshot(&mut self) -> Result<Option<Vec<u8>>, WSError> {
+            let snapshot = match self
+                .instance
+                .get_typed_func::<(), i64>(&mut self.store, SNAPSHOT_EXPORT)
+            {
+                Ok(snapshot) => snapshot,
+                Err(_) => return Ok(None),
+            };
+            let location = snapshot.call(&mut self.store, ()).map_err(state_error)? as u64;
+            let (ptr, len) = ((location >> 32) as usize, (location & 0xffff_ffff) as usize);
+            let memory = memory(&mut self.store, &self.instance)?;
+            let state = memory
+                .data(&self.store)
+                .get(ptr..ptr.saturating_add(len))
+                .ok_or_else(|| state_error("the state is out of bounds"))?;
+            Ok(Some(state.to_vec()))
+        }
+
+        fn swap(&mut self, module_bin: &[u8], state: Option<&[u8]>) -> Result<(), WSError> {
+            let data = (self.new_data)();
+            let (mut store, instance) =
+                Self::instantiate(&self.loader, &self.linker, data, module_bin)?;
+            if let Some(state) = state {
+                let len = i32::try_from(state.len()).map_err(state_error)?;
+                let alloc = instance
+                    .get_typed_func::<i32, i32>(&mut store, ALLOC_EXPORT)
+                    .map_err(state_error)?;
+                let restore = instance
+                    .get_typed_func::<(i32, i32), ()>(&mut store, RESTORE_EXPORT)
+                    .map_err(state_error)?;
+                let ptr = alloc.call(&mut store, len).map_err(state_error)?;
+                memory(&mut store, &instance)?
+                    .write(&mut store, ptr as u32 as usize, state)
+                    .map_err(state_error)?;
+                restore.call(&mut store, (ptr, len)).map_err(state_error)?;
+            }
+            self.store = store;
+            self.instance = instance;
+            Ok(())
+        }
+
+        fn resume(&mut self) -> Result<(), WSError> {
+            self.accepting = true;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A runtime recording the calls it gets, and failing at a given stage.
+    #[derive(Default)]
+    struct MockRuntime {
+        calls: Vec<String>,
+        fail_at: Option<SwapStage>,
+        state: Option<Vec<u8>>,
+        running: Vec<u8>,
+    }
+
+    impl MockRuntime {
+        fn stage(&mut self, stage: SwapStage, call: String) -> Result<(), WSError> {
+            self.calls.push(call);
+            match self.fail_at {
+                Some(fail_at) if fail_at == stage => {
+                    Err(WSError::RuntimeError(format!("{:?} failed", stage)))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl RuntimeAdapter for MockRuntime {
+        fn drain(&mut self, timeout: Duration) -> Result<(), WSError> {
+            self.stage(SwapStage::Drain, format!("drain {:?}", timeout))
+        }
+
+        fn snapshot(&mut self) -> Result<Option<Vec<u8>>, WSError> {
+            self.stage(SwapStage::Snapshot, "snapshot".to_string())?;
+            Ok(self.state.clone())
+        }
+
+        fn swap(&mut self, module_bin: &[u8], state: Option<&[u8]>) -> Result<(), WSError> {
+            self.stage(SwapStage::Swap, format!("swap {:?}", state))?;
+            self.running = module_bin.to_vec();
+            Ok(())
+        }
+
+        fn resume(&mut self) -> Result<(), WSError> {
+            self.stage(SwapStage::Resume, "resume".to_string())
+        }
+    }
+
+    fn stages(report: &SwapReport) -> Vec<SwapStage> {
+        report.stages.iter().map(|timing| timing.stage).collect()
+    }
+
+    #[test]
+    fn swaps_run_every_stage_in_order() {
+        let runtime = MockRuntime {
+            state: Some(b"state".to_vec()),
+            running: b"old".to_vec(),
+            ..MockRuntime::default()
+        };
+        let mut coordinator = UpdateCoordinator::new(runtime).drain_timeout(Duration::from_secs(5));
+        let report = coordinator.swap(b"new");
+        assert!(report.is_success());
+        assert!(!report.resumed_previous);
+        assert_eq!(report.snapshot_size, Some(5));
+        assert_eq!(
+            stages(&report),
+            [
+                SwapStage::Drain,
+                SwapStage::Snapshot,
+                SwapStage::Swap,
+                SwapStage::Resume
+            ]
+        );
+        let runtime = coordinator.runtime();
+        assert_eq!(
+            runtime.calls,
+            [
+                "drain 5s",
+                "snapshot",
+                "swap Some([115, 116, 97, 116, 101])",
+                "resume"
+            ]
+        );
+        assert_eq!(runtime.running, b"new");
+    }
+
+    #[test]
+    fn failures_resume_the_previous_instance() {
+        for (fail_at, calls) in [
+            (SwapStage::Drain, 1),
+            (SwapStage::Snapshot, 2),
+            (SwapStage::Swap, 3),
+        ] {
+            let runtime = MockRuntime {
+                fail_at: Some(fail_at),
+                running: b"old".to_vec(),
+                ..MockRuntime::default()
+            };
+            let mut coordinator = UpdateCoordinator::new(runtime);
+            let report = coordinator.swap(b"new");
+            assert!(!report.is_success());
+            assert_eq!(
+                report.failure.as_ref().map(|(stage, _)| *stage),
+                Some(fail_at)
+            );
+            assert!(report.resumed_previous);
+            assert_eq!(stages(&report).len(), calls + 1);
+            assert_eq!(stages(&report).last(), Some(&SwapStage::Resume));
+            let runtime = coordinator.runtime();
+            assert_eq!(runtime.calls.len(), calls + 1);
+            assert_eq!(runtime.running, b"old");
+        }
+    }
+
+    #[test]
+    fn failing_to_resume_leaves_the_new_instance_stopped() {
+        let runtime = MockRuntime {
+            fail_at: Some(SwapStage::Resume),
+            ..MockRuntime::default()
+        };
+        let mut coordinator = UpdateCoordinator::new(runtime);
+        let report = coordinator.swap(b"new");
+        assert!(!report.is_success());
+        assert_eq!(
+            report.failure.as_ref().map(|(stage, _)| *stage),
+            Some(SwapStage::Resume)
+        );
+        assert!(!report.resumed_previous);
+        assert_eq!(report.snapshot_size, None);
+        assert_eq!(coordinator.runtime().running, b"new");
+    }
+
+    #[cfg(feature = "wasmtime")]
+    mod wasmtime {
+        use super::super::*;
+        use crate::wasmtime_loader::WasmtimeLoader;
+
+        fn section(module_bin: &mut Vec<u8>, id: u8, payload: &[u8]) {
+            module_bin.push(id);
+            module_bin.push(payload.len() as u8);
+            module_bin.extend_from_slice(payload);
+        }
+
+        /// A module keeping a counter at address 0, incremented by `step` on every call to `incr`.
+        ///
+        /// The counter is its state: `wasi-update.snapshot` returns its location, and restoring
+        /// it only requires `wasi-update.alloc` to return that location.
+        fn counter_module(step: u8) -> Vec<u8> {
+            let mut module_bin = b"\0asm\x01\0\0\0".to_vec();
+            #[rustfmt::skip]
+            section(&mut module_bin, 1, &[
+                4,
+                0x60, 0, 1, 0x7e,
+                0x60, 1, 0x7f, 1, 0x7f,
+                0x60, 2, 0x7f, 0x7f, 0,
+                0x60, 0, 1, 0x7f,
+            ]);
+            section(&mut module_bin, 3, &[4, 0, 1, 2, 3]);
+            section(&mut module_bin, 5, &[1, 0, 1]);
+            let mut exports = vec![5];
+            for (name, kind, index) in [
+                ("memory", 2, 0),
+                ("wasi-update.snapshot", 0, 0),
+                ("wasi-update.alloc", 0, 1),
+                ("wasi-update.restore", 0, 2),
+                ("incr", 0, 3),
+            ] {
+                exports.push(name.len() as u8);
+                exports.extend_from_slice(name.as_bytes());
+                exports.extend_from_slice(&[kind, index]);
+            }
+            section(&mut module_bin, 7, &exports);
+            #[rustfmt::skip]
+            section(&mut module_bin, 10, &[
+                4,
+                4, 0, 0x42, 4, 0x0b,
+                4, 0, 0x41, 0, 0x0b,
+                2, 0, 0x0b,
+                20, 0, 0x41, 0, 0x41, 0, 0x28, 2, 0, 0x41, step, 0x6a, 0x36, 2, 0,
+                0x41, 0, 0x28, 2, 0, 0x0b,
+            ]);
+            module_bin
+        }
+
+        fn runtime() -> WasmtimeRuntime<()> {
+            let loader = WasmtimeLoader::new(::wasmtime::Engine::default());
+            let linker = ::wasmtime::Linker::new(loader.engine());
+            WasmtimeRuntime::new(loader, linker, || (), &counter_module(1)).unwrap()
+        }
+
+        #[test]
+        fn swaps_keep_the_state() {
+            let mut coordinator = UpdateCoordinator::new(runtime());
+            assert_eq!(
+                coordinator.runtime().call::<(), i32>("incr", ()).unwrap(),
+                1
+            );
+            assert_eq!(
+                coordinator.runtime().call::<(), i32>("incr", ()).unwrap(),
+                2
+            );
+            let report = coordinator.swap(&counter_module(10));
+            assert!(report.is_success(), "{:?}", report.failure);
+            assert_eq!(report.snapshot_size, Some(4));
+            assert_eq!(
+                coordinator.runtime().call::<(), i32>("incr", ()).unwrap(),
+                12
+            );
+        }
+
+        #[test]
+        fn failed_swaps_resume_the_previous_instance() {
+            let mut coordinator = UpdateCoordinator::new(runtime());
+            assert_eq!(
+                coordinator.runtime().call::<(), i32>("incr", ()).unwrap(),
+                1
+            );
+            let report = coordinator.swap(b"\0asm\x01\0\0\0\xff");
+            assert!(matches!(report.failure, Some((SwapStage::Swap, _))));
+            assert!(report.resumed_previous);
+            assert_eq!(
+                coordinator.runtime().call::<(), i32>("incr", ()).unwrap(),
+                2
+            );
+        }
+
+        #[test]
+        fn calls_are_rejected_while_drained() {
+            let mut runtime = runtime();
+            runtime.drain(Duration::ZERO).unwrap();
+            assert!(matches!(
+                runtime.call::<(), i32>("incr", ()),
+                Err(WSError::RuntimeError(_))
+            ));
+            runtime.resume().unwrap();
+            assert_eq!(runtime.call::<(), i32>("incr", ()).unwrap(), 1);
+        }
+    }
+}
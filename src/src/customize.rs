@@ -0,0 +1,94 @@
+//! Per-device customization of signed modules.
+//!
+//! A base module is signed once, leaving out the device configuration section. Device-specific
+//! configuration can then be injected into it, producing per-device modules that the signature
+//! of the base still covers, except for that section.
+
+use crate::error::*;
+use crate::signature::*;
+use crate::wasm_module::*;
+
+use std::io;
+
+/// Name of the custom section storing the configuration of a device.
+pub const DEVICE_CONFIG_SECTION_NAME: &str = "device-config";
+
+/// Return the sections left out of the signature of a customizable module.
+///
+/// Base modules must be signed with `SecretKey::sign_module_ignoring()` and this list, and
+/// devices must verify them with the same list, such as with the `ignored_sections` of `AgentConfig`.
+pub fn device_config_scope() -> IgnoreList {
+    IgnoreList::from_iter([DEVICE_CONFIG_SECTION_NAME])
+}
+
+impl Module {
+    /// Store device configuration, replacing any existing one.
+    ///
+    /// It is stored as the last section, after watermarks, if any.
+    pub fn set_device_config(&mut self, config: &[u8]) {
+        self.remove_device_config();
+        self.sections.push(Section::Custom(CustomSection::new(
+            DEVICE_CONFIG_SECTION_NAME.to_string(),
+            config.to_vec(),
+        )));
+    }
+
+    /// Remove the device configuration.
+    ///
+    /// Return `true` if at least one device configuration section was found.
+    pub fn remove_device_config(&mut self) -> bool {
+        let count = self.sections.len();
+        self.sections.retain(|section| match section {
+            Section::Custom(custom) => custom.name() != DEVICE_CONFIG_SECTION_NAME,
+            _ => true,
+        });
+        self.sections.len() != count
+    }
+
+    /// Return the device configuration, if there is one.
+    ///
+    /// Modules with more than one device configuration section are rejected.
+    pub fn device_config(&self) -> Result<Option<&[u8]>, WSError> {
+        let mut configs = self.sections.iter().filter_map(|section| match section {
+            Section::Custom(custom) if custom.name() == DEVICE_CONFIG_SECTION_NAME => {
+                Some(custom.payload())
+            }
+            _ => None,
+        });
+        let config = configs.next();
+        if configs.next().is_some() {
+            return Err(WSError::ParseError);
+        }
+        Ok(config)
+    }
+}
+
+/// Inject device configuration into a signed base module, and return the per-device module.
+///
+/// The signature of the base is checked first, using `device_config_scope()`, so that only
+/// authentic modules get customized. The same signature covers the returned module.
+pub fn customize(
+    base_bin: &[u8],
+    signature: &Signature,
+    key_ring: &KeyRing,
+    config: &[u8],
+) -> Result<Vec<u8>, WSError> {
+    let scope = device_config_scope();
+    key_ring.verify_module_ignoring(base_bin, &scope, signature)?;
+    let mut module = Module::deserialize(&mut io::Cursor::new(base_bin))?;
+    module.set_device_config(config);
+    let mut module_bin = vec![];
+    module.serialize(&mut module_bin)?;
+    Ok(module_bin)
+}
+
+/// Verify a per-device module, and return its device configuration, if there is one.
+pub fn verify_customized(
+    module_bin: &[u8],
+    signature: &Signature,
+    key_ring: &KeyRing,
+) -> Result<Option<Vec<u8>>, WSError> {
+    key_ring.verify_module_ignoring(module_bin, &device_config_scope(), signature)?;
+    let module = Module::deserialize(&mut io::Cursor::new(module_bin))?;
+    Ok(module.device_config()?.map(|config| config.to_vec()))
+}
@@ -0,0 +1,195 @@
+//! Cross-checks of the exports of a module against the imports of the modules depending on it.
+//!
+//! In plugin ecosystems, a module exports functions that other modules import under a given
+//! module name. Before publishing a new version, checking its exports against the imports of
+//! these dependents tells which ones would fail to link, and have to be updated at the same time.
+
+use crate::error::*;
+use crate::interface::*;
+use crate::wasm_module::*;
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// An import of a dependent that the module doesn't satisfy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokenImport {
+    /// Name of the import, without the module name.
+    pub name: String,
+    /// Signature expected by the dependent.
+    pub expected: String,
+    /// Signature of the export with the same name, if there is one.
+    pub exported: Option<String>,
+}
+
+impl fmt::Display for BrokenImport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.exported {
+            None => write!(
+                f,
+                "{} is not exported (expected {})",
+                self.name, self.expected
+            ),
+            Some(exported) => write!(
+                f,
+                "{} is exported as {} (expected {})",
+                self.name, exported, self.expected
+            ),
+        }
+    }
+}
+
+/// The outcome of the check of a dependent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependentCheck {
+    /// Name of the dependent.
+    pub name: String,
+    /// Number of imports of the dependent from the module.
+    pub imports: usize,
+    /// Imports the module doesn't satisfy. Empty if the dependent would still link.
+    pub broken: Vec<BrokenImport>,
+}
+
+impl DependentCheck {
+    /// Return `true` if the dependent would still link with the module.
+    pub fn passed(&self) -> bool {
+        self.broken.is_empty()
+    }
+}
+
+impl fmt::Display for DependentCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.passed() {
+            return write!(f, "PASS {}", self.name);
+        }
+        let reasons: Vec<String> = self.broken.iter().map(ToString::to_string).collect();
+        write!(f, "FAIL {}: {}", self.name, reasons.join("; "))
+    }
+}
+
+/// The outcome of the checks of all the dependents of a module.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DependentsReport {
+    /// Dependents, in the order they were given.
+    pub dependents: Vec<DependentCheck>,
+}
+
+impl DependentsReport {
+    /// Return `true` if every dependent would still link with the module.
+    pub fn is_compatible(&self) -> bool {
+        self.dependents.iter().all(DependentCheck::passed)
+    }
+
+    /// Return the dependents that would break.
+    pub fn broken(&self) -> impl Iterator<Item = &DependentCheck> {
+        self.dependents
+            .iter()
+            .filter(|dependent| !dependent.passed())
+    }
+}
+
+/// Limits of a memory or a table, along with what follows them.
+struct Limits<'t> {
+    is_64: bool,
+    min: u64,
+    max: Option<u64>,
+    rest: Vec<&'t str>,
+}
+
+/// Parse the limits of a memory or a table, as described by `Module::interface()`.
+fn limits(description: &str) -> Option<Limits<'_>> {
+    let mut tokens = description.split_whitespace().peekable();
+    let is_64 = tokens.next_if_eq(&"i64").is_some();
+    let min = tokens.next()?.parse().ok()?;
+    let max = match tokens.peek().map(|token| token.parse()) {
+        Some(Ok(max)) => {
+            tokens.next();
+            Some(max)
+        }
+        _ => None,
+    };
+    Some(Limits {
+        is_64,
+        min,
+        max,
+        rest: tokens.collect(),
+    })
+}
+
+/// Return `true` if an export with the given signature can be used for an import.
+///
+/// Memories and tables can be bigger than required, as long as they stay within the maximum of
+/// the import. Other items must have the same type. Types referring to type indices are compared
+/// as-is, although the indices of the two modules may not refer to the same types.
+fn satisfies(exported: &str, expected: &str) -> bool {
+    if exported == expected {
+        return true;
+    }
+    let (exported, expected) = match (exported.split_once(' '), expected.split_once(' ')) {
+        (Some(("memory", exported)), Some(("memory", expected)))
+        | (Some(("table", exported)), Some(("table", expected))) => (exported, expected),
+        _ => return false,
+    };
+    let (exported, expected) = match (limits(exported), limits(expected)) {
+        (Some(exported), Some(expected)) => (exported, expected),
+        _ => return false,
+    };
+    exported.is_64 == expected.is_64
+        && exported.rest == expected.rest
+        && exported.min >= expected.min
+        && match (exported.max, expected.max) {
+            (_, None) => true,
+            (Some(exported_max), Some(expected_max)) => exported_max <= expected_max,
+            (None, Some(_)) => false,
+        }
+}
+
+impl Module {
+    /// Check that modules importing from this one under `module_name` would still link with it.
+    ///
+    /// Each dependent is given with a name used in the report, such as its file name. Imports
+    /// from other modules are ignored.
+    pub fn check_dependents<'t>(
+        &self,
+        module_name: &str,
+        dependents: impl IntoIterator<Item = (&'t str, &'t Module)>,
+    ) -> Result<DependentsReport, WSError> {
+        let exports: HashMap<String, String> = self
+            .interface()?
+            .into_iter()
+            .filter(|item| item.direction == InterfaceDirection::Export)
+            .map(|item| (item.name, item.signature))
+            .collect();
+        let mut report = DependentsReport::default();
+        for (name, dependent) in dependents {
+            let mut check = DependentCheck {
+                name: name.to_string(),
+                imports: 0,
+                broken: vec![],
+            };
+            for item in dependent.interface()? {
+                let import_name = match item.name.split_once("::") {
+                    Some((module, import_name))
+                        if item.direction == InterfaceDirection::Import
+                            && module == module_name =>
+                    {
+                        import_name
+                    }
+                    _ => continue,
+                };
+                check.imports += 1;
+                let exported = exports.get(import_name);
+                if exported.is_some_and(|exported| satisfies(exported, &item.signature)) {
+                    continue;
+                }
+                check.broken.push(BrokenImport {
+                    name: import_name.to_string(),
+                    expected: item.signature,
+                    exported: exported.cloned(),
+                });
+            }
+            report.dependents.push(check);
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,110 @@
+use crate::agent::*;
+use crate::error::*;
+use crate::repo::*;
+use crate::signature::*;
+
+use log::*;
+use semver::Version;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Channel modules are published on by a development session.
+pub const DEV_CHANNEL: &str = "dev";
+
+/// Publish and install modules on a simulated device, during development.
+///
+/// Every module is published to a local repository with the next patch version, signed, and installed
+/// by an agent through the same verify/stage/commit pipeline as a real device.
+pub struct DevSession {
+    repo: Repository<FsBackend>,
+    agent: Agent<LocalTransport<FsBackend>, FsStorage, SystemClock>,
+    key: SecretKey,
+}
+
+impl DevSession {
+    /// Create a session for a simulated device storing its slots in `device_dir`.
+    ///
+    /// If `key` is `None`, an ephemeral key pair is generated.
+    pub fn new(
+        device_dir: impl AsRef<Path>,
+        repo_dir: impl AsRef<Path>,
+        key: Option<SecretKey>,
+    ) -> Result<Self, WSError> {
+        let key = key.unwrap_or_else(|| KeyPair::generate().sk);
+        let repo = Repository::new(FsBackend::new(repo_dir)?);
+        let mut config = AgentConfig::new("dev", DEV_CHANNEL);
+        config.trusted_keys.add(key.public_key());
+        let agent = Agent::new(
+            config,
+            LocalTransport::new(repo.clone()),
+            FsStorage::new(device_dir)?,
+            SystemClock,
+        );
+        Ok(DevSession { repo, agent, key })
+    }
+
+    /// Return the agent of the simulated device.
+    pub fn agent(&self) -> &Agent<LocalTransport<FsBackend>, FsStorage, SystemClock> {
+        &self.agent
+    }
+
+    /// Publish a module, install it on the simulated device, and confirm the installation.
+    ///
+    /// Return `None` if the module has already been published.
+    pub fn push(&self, module_bin: &[u8]) -> Result<Option<Outcome>, WSError> {
+        let build_id = build_id(module_bin);
+        match self.repo.info(&build_id) {
+            Ok(info) => {
+                debug!("{} was already published as {}", build_id, info.version);
+                return Ok(None);
+            }
+            Err(WSError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        let version = match self.repo.versions(DEV_CHANNEL)?.last() {
+            None => Version::new(0, 0, 1),
+            Some(latest) => Version::new(
+                latest.version.major,
+                latest.version.minor,
+                latest.version.patch + 1,
+            ),
+        };
+        self.repo.publish(module_bin, &version, DEV_CHANNEL)?;
+        self.repo
+            .add_signature(&build_id, &self.key.sign_module(module_bin))?;
+        let outcome = self.agent.run_once()?;
+        if let Outcome::Installed { .. } = outcome {
+            self.agent.slots().confirm()?;
+        }
+        Ok(Some(outcome))
+    }
+}
+
+/// Watch a file, and call `changed` with its content every time it is modified.
+///
+/// The file is polled every `interval`. `changed` is also called on startup if the file exists.
+/// Errors returned by `changed` stop watching.
+pub fn watch(
+    file: impl AsRef<Path>,
+    interval: Duration,
+    mut changed: impl FnMut(&[u8]) -> Result<(), WSError>,
+) -> Result<(), WSError> {
+    let file: PathBuf = file.as_ref().to_path_buf();
+    let mut last: Option<(SystemTime, u64)> = None;
+    loop {
+        let current = fs::metadata(&file)
+            .and_then(|metadata| Ok((metadata.modified()?, metadata.len())))
+            .ok();
+        if current.is_some() && current != last {
+            match fs::read(&file) {
+                Ok(content) => {
+                    last = current;
+                    changed(&content)?;
+                }
+                Err(e) => debug!("Unable to read [{}]: {}", file.display(), e),
+            }
+        }
+        std::thread::sleep(interval);
+    }
+}
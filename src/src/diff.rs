@@ -0,0 +1,297 @@
+use crate::error::*;
+use crate::instrument::Phase;
+use crate::wasm_module::*;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+
+/// How a section differs between two modules.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    Unchanged,
+    Modified,
+    Added,
+    Removed,
+}
+
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChangeKind::Unchanged => write!(f, "unchanged"),
+            ChangeKind::Modified => write!(f, "modified"),
+            ChangeKind::Added => write!(f, "added"),
+            ChangeKind::Removed => write!(f, "removed"),
+        }
+    }
+}
+
+/// What a change affects, from least to most significant.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeImpact {
+    /// Nothing changed.
+    None,
+    /// Only custom sections changed.
+    Metadata,
+    /// Only data segments changed.
+    Data,
+    /// Code or other definitions changed, with the same imports and exports.
+    Code,
+    /// Imports or exports changed.
+    Interface,
+}
+
+impl fmt::Display for ChangeImpact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChangeImpact::None => write!(f, "none"),
+            ChangeImpact::Metadata => write!(f, "metadata-only"),
+            ChangeImpact::Data => write!(f, "data-only"),
+            ChangeImpact::Code => write!(f, "code"),
+            ChangeImpact::Interface => write!(f, "interface"),
+        }
+    }
+}
+
+/// Return the impact of a change to a section of a module or component.
+fn section_impact(key: &SectionKey, is_component: bool) -> ChangeImpact {
+    let id = match key {
+        SectionKey::Custom { .. } => return ChangeImpact::Metadata,
+        SectionKey::Standard(id) => u8::from(*id),
+    };
+    match (is_component, id) {
+        // Component imports and exports
+        (true, 10 | 11) => ChangeImpact::Interface,
+        (true, _) => ChangeImpact::Code,
+        // Module imports and exports
+        (false, 2 | 7) => ChangeImpact::Interface,
+        // Data and data count
+        (false, 11 | 12) => ChangeImpact::Data,
+        (false, _) => ChangeImpact::Code,
+    }
+}
+
+/// A section, compared between two modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionChange {
+    pub key: SectionKey,
+    pub kind: ChangeKind,
+    /// `ChangeImpact::None` if the section is unchanged.
+    pub impact: ChangeImpact,
+    /// Size of the serialized section in the old module, if present.
+    pub old_size: Option<u64>,
+    /// Size of the serialized section in the new module, if present.
+    pub new_size: Option<u64>,
+}
+
+/// The differences between two modules, section by section.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleDiff {
+    /// Sections of the new module in order, followed by the sections that were removed.
+    pub changes: Vec<SectionChange>,
+}
+
+impl ModuleDiff {
+    /// Return `true` if no sections were modified, added or removed.
+    pub fn is_identical(&self) -> bool {
+        self.changes
+            .iter()
+            .all(|change| change.kind == ChangeKind::Unchanged)
+    }
+
+    /// Return the sections that were modified, added or removed.
+    pub fn changed(&self) -> impl Iterator<Item = &SectionChange> {
+        self.changes
+            .iter()
+            .filter(|change| change.kind != ChangeKind::Unchanged)
+    }
+
+    /// Return the most significant impact of all the changes.
+    pub fn impact(&self) -> ChangeImpact {
+        self.changes
+            .iter()
+            .map(|change| change.impact)
+            .max()
+            .unwrap_or(ChangeImpact::None)
+    }
+}
+
+/// Count the bytes written, without keeping them.
+struct CountingWriter(u64);
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn serialized_size(section: &Section) -> Result<u64, WSError> {
+    let mut writer = CountingWriter(0);
+    section.serialize(&mut writer)?;
+    Ok(writer.0)
+}
+
+/// A section, identified by its address, so that it is only hashed once while it is borrowed.
+struct SectionRef<'t>(&'t Section);
+
+impl PartialEq for SectionRef<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.0, other.0)
+    }
+}
+
+impl Eq for SectionRef<'_> {}
+
+impl Hash for SectionRef<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::ptr::hash(self.0, state)
+    }
+}
+
+/// A cache of section digests and sizes, for comparing the same modules multiple times.
+///
+/// When comparing a new version with many old bases, the sections of the new version are only
+/// hashed once, and the size of a section is only computed once per digest. The cache borrows
+/// the modules it was used with, so that they can't change while it is in use.
+#[derive(Default)]
+pub struct SectionCache<'t> {
+    digests: HashMap<SectionRef<'t>, [u8; 32]>,
+    sizes: HashMap<[u8; 32], u64>,
+}
+
+impl<'t> SectionCache<'t> {
+    /// Create an empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the number of distinct sections in the cache.
+    pub fn len(&self) -> usize {
+        self.sizes.len()
+    }
+
+    /// Return `true` if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    /// Return the key, digest and serialized size of each section, in order.
+    pub(crate) fn sections(
+        &mut self,
+        module: &'t Module,
+    ) -> Result<Vec<(SectionKey, [u8; 32], u64)>, WSError> {
+        module
+            .section_keys()
+            .into_iter()
+            .zip(&module.sections)
+            .map(|(key, section)| {
+                let digest = match self.digests.get(&SectionRef(section)) {
+                    Some(digest) => *digest,
+                    None => {
+                        let digest = section.stable_hash()?;
+                        self.digests.insert(SectionRef(section), digest);
+                        digest
+                    }
+                };
+                let size = match self.sizes.get(&digest) {
+                    Some(size) => *size,
+                    None => {
+                        let size = serialized_size(section)?;
+                        self.sizes.insert(digest, size);
+                        size
+                    }
+                };
+                Ok((key, digest, size))
+            })
+            .collect()
+    }
+}
+
+impl Module {
+    /// Compare the sections of this module with the sections of a newer version.
+    ///
+    /// Sections are matched by key, so that changes in a custom section appearing multiple times
+    /// are attributed deterministically.
+    pub fn diff(&self, new: &Module) -> Result<ModuleDiff, WSError> {
+        self.diff_ignoring(new, &IgnoreList::new())
+    }
+
+    /// Compare the sections of this module with the sections of a newer version,
+    /// leaving out the ignored sections.
+    pub fn diff_ignoring(&self, new: &Module, ignored: &IgnoreList) -> Result<ModuleDiff, WSError> {
+        self.diff_cached(new, ignored, &mut SectionCache::new())
+    }
+
+    /// Compare the sections of this module with the sections of a newer version, leaving out
+    /// the ignored sections, and reusing the digests and sizes computed by previous comparisons.
+    pub fn diff_cached<'t>(
+        &'t self,
+        new: &'t Module,
+        ignored: &IgnoreList,
+        cache: &mut SectionCache<'t>,
+    ) -> Result<ModuleDiff, WSError> {
+        let mut phase = Phase::Diff.start();
+        let old = cache.sections(self)?;
+        let mut old_sections: HashMap<&SectionKey, ([u8; 32], u64)> = old
+            .iter()
+            .zip(&self.sections)
+            .filter(|(_, section)| !ignored.ignores(section))
+            .map(|((key, digest, size), _)| (key, (*digest, *size)))
+            .collect();
+        let is_component = self.is_component() && new.is_component();
+        let impact = |key: &SectionKey, kind: ChangeKind| match kind {
+            ChangeKind::Unchanged => ChangeImpact::None,
+            _ => section_impact(key, is_component),
+        };
+        let mut changes = vec![];
+        for ((key, digest, size), section) in cache.sections(new)?.into_iter().zip(&new.sections) {
+            if ignored.ignores(section) {
+                continue;
+            }
+            phase.add_bytes(size);
+            let new_size = Some(size);
+            let change = match old_sections.remove(&key) {
+                None => SectionChange {
+                    impact: impact(&key, ChangeKind::Added),
+                    key,
+                    kind: ChangeKind::Added,
+                    old_size: None,
+                    new_size,
+                },
+                Some((old_digest, old_size)) => {
+                    let kind = if old_digest == digest {
+                        ChangeKind::Unchanged
+                    } else {
+                        ChangeKind::Modified
+                    };
+                    SectionChange {
+                        impact: impact(&key, kind),
+                        key,
+                        kind,
+                        old_size: Some(old_size),
+                        new_size,
+                    }
+                }
+            };
+            changes.push(change);
+        }
+        for (key, _, size) in &old {
+            if old_sections.remove(key).is_some() {
+                changes.push(SectionChange {
+                    impact: impact(key, ChangeKind::Removed),
+                    key: key.clone(),
+                    kind: ChangeKind::Removed,
+                    old_size: Some(*size),
+                    new_size: None,
+                });
+            }
+        }
+        Ok(ModuleDiff { changes })
+    }
+}
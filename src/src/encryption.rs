@@ -0,0 +1,669 @@
+//! Encryption of individual sections.
+//!
+//! Selected sections are encrypted with a random data key, and replaced with `ENCRYPTED_SECTION_NAME`
+//! custom sections at the same position. The data key is wrapped for every recipient, using X25519 with an
+//! ephemeral key, and stored in a `ENCRYPTION_KEYS_SECTION_NAME` custom section. Sections are encrypted with
+//! AEGIS-256.
+//!
+//! Other sections remain in plaintext, so that encrypted modules can still be inspected, diffed and signed.
+//!
+//! The recipients of a module can be changed with `Module::rotate_encryption_keys()`, which only replaces the
+//! `ENCRYPTION_KEYS_SECTION_NAME` section. Signatures made with that section in the ignore list remain valid.
+
+use crate::error::*;
+use crate::signature::{key_id_to_hex, KeyId};
+use crate::wasm_module::*;
+
+use aegis::aegis256::Aegis256;
+use ed25519_compact::{x25519, Seed};
+use hmac_sha256::{Hash, HMAC};
+use log::*;
+use std::fmt;
+use std::io::{self, prelude::*};
+
+/// Name of the custom section storing the wrapped data keys.
+pub const ENCRYPTION_KEYS_SECTION_NAME: &str = "wasi-update.keys";
+
+/// Name of the custom sections replacing encrypted sections.
+pub const ENCRYPTED_SECTION_NAME: &str = "wasi-update.encrypted";
+
+const KEY_VERSION: u8 = 0x01;
+const ENVELOPE_VERSION: u8 = 0x01;
+const ROTATED_ENVELOPE_VERSION: u8 = 0x02;
+const KEK_CONTEXT: &[u8] = b"wasi-update/encryption/v1\0";
+const TAG_BYTES: usize = 16;
+
+/// A data key, encrypting the sections of a module.
+pub type DataKey = [u8; 32];
+
+fn derive_key_id(pk: &x25519::PublicKey) -> KeyId {
+    Hash::hash(pk.as_ref())[..8].try_into().unwrap()
+}
+
+fn derive_kek(dh: &[u8], ephemeral_pk: &x25519::PublicKey, pk: &x25519::PublicKey) -> DataKey {
+    let mut hmac = HMAC::new(dh);
+    hmac.update(KEK_CONTEXT);
+    hmac.update(ephemeral_pk.as_ref());
+    hmac.update(pk.as_ref());
+    hmac.finalize()
+}
+
+/// A public key, used to encrypt modules for a recipient.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionPublicKey {
+    key_id: KeyId,
+    pk: x25519::PublicKey,
+}
+
+impl fmt::Debug for EncryptionPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EncryptionPublicKey({})", key_id_to_hex(&self.key_id))
+    }
+}
+
+impl EncryptionPublicKey {
+    /// Return the identifier of the key.
+    pub fn key_id(&self) -> &KeyId {
+        &self.key_id
+    }
+
+    /// Serialize the public key.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![KEY_VERSION];
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(self.pk.as_ref());
+        bytes
+    }
+
+    /// Deserialize a public key.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WSError> {
+        if bytes.len() != 1 + 8 + 32 || bytes[0] != KEY_VERSION {
+            return Err(WSError::InvalidKey("encryption public key".to_string()));
+        }
+        let pk = x25519::PublicKey::from_slice(&bytes[9..])
+            .map_err(|_| WSError::InvalidKey("encryption public key".to_string()))?;
+        Ok(EncryptionPublicKey {
+            key_id: bytes[1..9].try_into().unwrap(),
+            pk,
+        })
+    }
+}
+
+/// A secret key, used to decrypt modules.
+#[derive(Clone)]
+pub struct EncryptionSecretKey {
+    key_id: KeyId,
+    sk: x25519::SecretKey,
+}
+
+impl fmt::Debug for EncryptionSecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EncryptionSecretKey({})", key_id_to_hex(&self.key_id))
+    }
+}
+
+impl EncryptionSecretKey {
+    /// Return the identifier of the key.
+    pub fn key_id(&self) -> &KeyId {
+        &self.key_id
+    }
+
+    /// Return the public key corresponding to this secret key.
+    pub fn public_key(&self) -> Result<EncryptionPublicKey, WSError> {
+        let pk = self
+            .sk
+            .recover_public_key()
+            .map_err(|_| WSError::InvalidKey("encryption secret key".to_string()))?;
+        Ok(EncryptionPublicKey {
+            key_id: self.key_id,
+            pk,
+        })
+    }
+
+    /// Serialize the secret key.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![KEY_VERSION];
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(self.sk.as_ref());
+        bytes
+    }
+
+    /// Deserialize a secret key.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WSError> {
+        if bytes.len() != 1 + 8 + 32 || bytes[0] != KEY_VERSION {
+            return Err(WSError::InvalidKey("encryption secret key".to_string()));
+        }
+        let sk = x25519::SecretKey::from_slice(&bytes[9..])
+            .map_err(|_| WSError::InvalidKey("encryption secret key".to_string()))?;
+        Ok(EncryptionSecretKey {
+            key_id: bytes[1..9].try_into().unwrap(),
+            sk,
+        })
+    }
+}
+
+/// An encryption key pair.
+#[derive(Debug, Clone)]
+pub struct EncryptionKeyPair {
+    pub pk: EncryptionPublicKey,
+    pub sk: EncryptionSecretKey,
+}
+
+impl EncryptionKeyPair {
+    /// Generate a new key pair.
+    ///
+    /// The key identifier is derived from the public key.
+    pub fn generate() -> Self {
+        let kp = x25519::KeyPair::generate();
+        let key_id = derive_key_id(&kp.pk);
+        EncryptionKeyPair {
+            pk: EncryptionPublicKey { key_id, pk: kp.pk },
+            sk: EncryptionSecretKey { key_id, sk: kp.sk },
+        }
+    }
+}
+
+/// A data key, wrapped for a recipient.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WrappedKey {
+    /// Identifier of the key of the recipient.
+    pub key_id: KeyId,
+    /// The encrypted data key, followed by its authentication tag.
+    pub wrapped: Vec<u8>,
+}
+
+/// The data key of a module, wrapped for each recipient.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEnvelope {
+    /// Ephemeral public key the wrapping keys were derived from.
+    pub ephemeral_pk: [u8; 32],
+    /// Ephemeral public key the module was first encrypted with, that the encrypted sections are bound to.
+    ///
+    /// It is the same as `ephemeral_pk`, unless the keys have been rotated.
+    pub sections_pk: [u8; 32],
+    pub wrapped_keys: Vec<WrappedKey>,
+}
+
+impl KeyEnvelope {
+    /// Wrap a data key for every recipient, with a new ephemeral key.
+    fn wrap(
+        data_key: &DataKey,
+        sections_pk: Option<[u8; 32]>,
+        recipients: &[EncryptionPublicKey],
+    ) -> Result<Self, WSError> {
+        if recipients.is_empty() {
+            return Err(WSError::InvalidKey("no recipients".to_string()));
+        }
+        let ephemeral = x25519::KeyPair::generate();
+        let ephemeral_pk: [u8; 32] = *ephemeral.pk;
+        let mut wrapped_keys = vec![];
+        for recipient in recipients {
+            let dh = recipient
+                .pk
+                .dh(&ephemeral.sk)
+                .map_err(|_| WSError::InvalidKey("encryption public key".to_string()))?;
+            let kek = derive_kek(dh.as_ref(), &ephemeral.pk, &recipient.pk);
+            let (mut wrapped, tag) =
+                Aegis256::<TAG_BYTES>::new(&kek, &[0u8; 32]).encrypt(data_key, &ephemeral_pk);
+            wrapped.extend_from_slice(&tag);
+            wrapped_keys.push(WrappedKey {
+                key_id: recipient.key_id,
+                wrapped,
+            });
+        }
+        Ok(KeyEnvelope {
+            ephemeral_pk,
+            sections_pk: sections_pk.unwrap_or(ephemeral_pk),
+            wrapped_keys,
+        })
+    }
+
+    fn serialize(&self) -> Result<Vec<u8>, WSError> {
+        let mut payload;
+        if self.sections_pk == self.ephemeral_pk {
+            payload = vec![ENVELOPE_VERSION];
+            payload.extend_from_slice(&self.ephemeral_pk);
+        } else {
+            payload = vec![ROTATED_ENVELOPE_VERSION];
+            payload.extend_from_slice(&self.ephemeral_pk);
+            payload.extend_from_slice(&self.sections_pk);
+        }
+        varint::put(&mut payload, self.wrapped_keys.len() as _)?;
+        for wrapped_key in &self.wrapped_keys {
+            payload.extend_from_slice(&wrapped_key.key_id);
+            varint::put_slice(&mut payload, &wrapped_key.wrapped)?;
+        }
+        Ok(payload)
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, WSError> {
+        let mut reader = io::Cursor::new(payload);
+        let mut version = [0u8; 1];
+        reader.read_exact(&mut version)?;
+        let mut ephemeral_pk = [0u8; 32];
+        reader.read_exact(&mut ephemeral_pk)?;
+        let sections_pk = match version[0] {
+            ENVELOPE_VERSION => ephemeral_pk,
+            ROTATED_ENVELOPE_VERSION => {
+                let mut sections_pk = [0u8; 32];
+                reader.read_exact(&mut sections_pk)?;
+                sections_pk
+            }
+            _ => return Err(WSError::ParseError),
+        };
+        let mut wrapped_keys = vec![];
+        for _ in 0..varint::get32(&mut reader)? {
+            let mut key_id = KeyId::default();
+            reader.read_exact(&mut key_id)?;
+            let wrapped = varint::get_slice(&mut reader)?;
+            wrapped_keys.push(WrappedKey { key_id, wrapped });
+        }
+        Ok(KeyEnvelope {
+            ephemeral_pk,
+            sections_pk,
+            wrapped_keys,
+        })
+    }
+}
+
+/// A way to recover the data key of an encrypted module.
+///
+/// `EncryptionSecretKey` implements it. Other implementations can keep secret keys in a secure
+/// element or a key management service.
+pub trait Decryptor {
+    /// Recover the data key from the envelope of a module.
+    fn unwrap_key(&self, envelope: &KeyEnvelope) -> Result<DataKey, WSError>;
+}
+
+impl Decryptor for EncryptionSecretKey {
+    fn unwrap_key(&self, envelope: &KeyEnvelope) -> Result<DataKey, WSError> {
+        let ephemeral_pk = x25519::PublicKey::new(envelope.ephemeral_pk);
+        let pk = self.public_key()?.pk;
+        let wrapped_key = envelope
+            .wrapped_keys
+            .iter()
+            .find(|wrapped_key| wrapped_key.key_id == self.key_id)
+            .ok_or(WSError::DecryptionFailed)?;
+        if wrapped_key.wrapped.len() != 32 + TAG_BYTES {
+            return Err(WSError::DecryptionFailed);
+        }
+        let dh = ephemeral_pk
+            .dh(&self.sk)
+            .map_err(|_| WSError::DecryptionFailed)?;
+        let kek = derive_kek(dh.as_ref(), &ephemeral_pk, &pk);
+        let (wrapped, tag) = wrapped_key.wrapped.split_at(32);
+        let key = Aegis256::<TAG_BYTES>::new(&kek, &[0u8; 32])
+            .decrypt(wrapped, tag.try_into().unwrap(), &envelope.ephemeral_pk)
+            .map_err(|_| WSError::DecryptionFailed)?;
+        Ok(key.try_into().unwrap())
+    }
+}
+
+fn encrypt_section(
+    section: &Section,
+    key: &DataKey,
+    ephemeral_pk: &[u8; 32],
+) -> Result<Section, WSError> {
+    let mut plaintext = vec![];
+    section.serialize(&mut plaintext)?;
+    let nonce = *Seed::generate();
+    let (ciphertext, tag) =
+        Aegis256::<TAG_BYTES>::new(key, &nonce).encrypt(&plaintext, ephemeral_pk);
+    let mut payload = nonce.to_vec();
+    payload.extend_from_slice(&tag);
+    payload.extend_from_slice(&ciphertext);
+    Ok(Section::Custom(CustomSection::new(
+        ENCRYPTED_SECTION_NAME.to_string(),
+        payload,
+    )))
+}
+
+fn decrypt_section(
+    payload: &[u8],
+    key: &DataKey,
+    ephemeral_pk: &[u8; 32],
+) -> Result<Section, WSError> {
+    if payload.len() < 32 + TAG_BYTES {
+        return Err(WSError::DecryptionFailed);
+    }
+    let (nonce, rest) = payload.split_at(32);
+    let (tag, ciphertext) = rest.split_at(TAG_BYTES);
+    let plaintext = Aegis256::<TAG_BYTES>::new(key, nonce.try_into().unwrap())
+        .decrypt(ciphertext, tag.try_into().unwrap(), ephemeral_pk)
+        .map_err(|_| WSError::DecryptionFailed)?;
+    let mut reader = io::Cursor::new(&plaintext);
+    let section = Section::deserialize(&mut reader)?.ok_or(WSError::ParseError)?;
+    if reader.position() != plaintext.len() as u64 {
+        return Err(WSError::ParseError);
+    }
+    Ok(section)
+}
+
+fn is_custom_section(section: &Section, name: &str) -> bool {
+    matches!(section, Section::Custom(custom) if custom.name() == name)
+}
+
+impl Module {
+    /// Encrypt the given sections, so that only the recipients can decrypt them.
+    ///
+    /// Return the number of encrypted sections.
+    pub fn encrypt_sections(
+        &mut self,
+        keys: &[SectionKey],
+        recipients: &[EncryptionPublicKey],
+    ) -> Result<usize, WSError> {
+        if self.is_encrypted() {
+            return Err(WSError::SectionConflict(
+                ENCRYPTION_KEYS_SECTION_NAME.to_string(),
+            ));
+        }
+        let section_keys = self.section_keys();
+        if let Some(missing) = keys.iter().find(|key| !section_keys.contains(key)) {
+            return Err(WSError::NotFound(missing.to_string()));
+        }
+        let data_key = *Seed::generate();
+        let envelope = KeyEnvelope::wrap(&data_key, None, recipients)?;
+        let mut count = 0;
+        for (key, section) in section_keys.iter().zip(self.sections.iter_mut()) {
+            if keys.contains(key) {
+                *section = encrypt_section(section, &data_key, &envelope.sections_pk)?;
+                count += 1;
+            }
+        }
+        self.sections.push(Section::Custom(CustomSection::new(
+            ENCRYPTION_KEYS_SECTION_NAME.to_string(),
+            envelope.serialize()?,
+        )));
+        debug!(
+            "Encrypted {} sections for {} recipients",
+            count,
+            recipients.len()
+        );
+        Ok(count)
+    }
+
+    /// Return `true` if the module contains encrypted sections.
+    pub fn is_encrypted(&self) -> bool {
+        self.sections
+            .iter()
+            .any(|section| is_custom_section(section, ENCRYPTION_KEYS_SECTION_NAME))
+    }
+
+    /// Return the envelope of the data key, if the module is encrypted.
+    pub fn key_envelope(&self) -> Result<Option<KeyEnvelope>, WSError> {
+        self.sections
+            .iter()
+            .find(|section| is_custom_section(section, ENCRYPTION_KEYS_SECTION_NAME))
+            .map(|section| KeyEnvelope::deserialize(section.payload()))
+            .transpose()
+    }
+
+    /// Decrypt the encrypted sections, restoring them at their original position.
+    ///
+    /// The module is left unchanged if any section fails to decrypt. Return the number of decrypted sections.
+    pub fn decrypt_sections(&mut self, decryptor: &impl Decryptor) -> Result<usize, WSError> {
+        let envelope = match self.key_envelope()? {
+            None => return Ok(0),
+            Some(envelope) => envelope,
+        };
+        let data_key = decryptor.unwrap_key(&envelope)?;
+        let mut sections = Vec::with_capacity(self.sections.len());
+        let mut count = 0;
+        for section in &self.sections {
+            if is_custom_section(section, ENCRYPTION_KEYS_SECTION_NAME) {
+                continue;
+            }
+            if is_custom_section(section, ENCRYPTED_SECTION_NAME) {
+                sections.push(decrypt_section(
+                    section.payload(),
+                    &data_key,
+                    &envelope.sections_pk,
+                )?);
+                count += 1;
+                continue;
+            }
+            sections.push(section.clone());
+        }
+        self.sections = sections;
+        debug!("Decrypted {} sections", count);
+        Ok(count)
+    }
+
+    /// Wrap the data key of an encrypted module for a new set of recipients.
+    ///
+    /// The data key is recovered with `decryptor`, and only the envelope is replaced: the encrypted sections
+    /// are left untouched, so signatures made with `ENCRYPTION_KEYS_SECTION_NAME` in the ignore list remain
+    /// valid. Recipients that are not in `recipients` lose access to the module.
+    /// Return `false` if the module is not encrypted.
+    pub fn rotate_encryption_keys(
+        &mut self,
+        decryptor: &impl Decryptor,
+        recipients: &[EncryptionPublicKey],
+    ) -> Result<bool, WSError> {
+        let envelope = match self.key_envelope()? {
+            None => return Ok(false),
+            Some(envelope) => envelope,
+        };
+        let data_key = decryptor.unwrap_key(&envelope)?;
+        let envelope = KeyEnvelope::wrap(&data_key, Some(envelope.sections_pk), recipients)?;
+        let payload = envelope.serialize()?;
+        for section in self.sections.iter_mut() {
+            if is_custom_section(section, ENCRYPTION_KEYS_SECTION_NAME) {
+                *section = Section::Custom(CustomSection::new(
+                    ENCRYPTION_KEYS_SECTION_NAME.to_string(),
+                    payload.clone(),
+                ));
+            }
+        }
+        debug!(
+            "Rotated the encryption keys for {} recipients",
+            recipients.len()
+        );
+        Ok(true)
+    }
+
+    /// Deserialize a module, decrypting its encrypted sections.
+    pub fn deserialize_decrypted(
+        reader: &mut impl Read,
+        decryptor: &impl Decryptor,
+    ) -> Result<Self, WSError> {
+        let mut module = Module::deserialize(reader)?;
+        module.decrypt_sections(decryptor)?;
+        Ok(module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::{KeyPair, KeyRing};
+    use std::slice;
+
+    const SECRET: &str = "secret";
+
+    fn module() -> Module {
+        Module {
+            header: [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00],
+            sections: vec![
+                Section::Standard(StandardSection::new(SectionId::Type, vec![0x00])),
+                Section::Custom(CustomSection::new(SECRET.to_string(), b"payload".to_vec())),
+                Section::Standard(StandardSection::new(SectionId::Code, vec![0x00])),
+            ],
+            ..Module::default()
+        }
+    }
+
+    fn encrypted(recipients: &[EncryptionPublicKey]) -> Module {
+        let mut module = module();
+        let keys = [
+            SectionKey::Custom {
+                name: SECRET.to_string(),
+                index: 0,
+            },
+            SectionKey::Standard(SectionId::Code),
+        ];
+        assert_eq!(module.encrypt_sections(&keys, recipients).unwrap(), 2);
+        module
+    }
+
+    fn to_bytes(module: &Module) -> Vec<u8> {
+        let mut module_bin = vec![];
+        module.serialize(&mut module_bin).unwrap();
+        module_bin
+    }
+
+    /// Return a copy of `module`, with the payload of the first `name` custom section changed by `f`.
+    fn with_payload(module: &Module, name: &str, f: impl FnOnce(&mut Vec<u8>)) -> Module {
+        let mut module = module.clone();
+        let section = module
+            .sections
+            .iter_mut()
+            .find(|section| is_custom_section(section, name))
+            .unwrap();
+        let mut payload = section.payload().to_vec();
+        f(&mut payload);
+        *section = Section::Custom(CustomSection::new(name.to_string(), payload));
+        module
+    }
+
+    fn assert_rejected(module: &Module, sk: &EncryptionSecretKey) {
+        let mut decrypted = module.clone();
+        assert!(decrypted.decrypt_sections(sk).is_err());
+        assert_eq!(to_bytes(&decrypted), to_bytes(module));
+    }
+
+    #[test]
+    fn sections_round_trip() {
+        let kp = EncryptionKeyPair::generate();
+        let other = EncryptionKeyPair::generate();
+        let module = encrypted(&[other.pk, kp.pk.clone()]);
+        assert!(module.is_encrypted());
+        let module_bin = to_bytes(&module);
+        assert!(!module_bin
+            .windows(b"payload".len())
+            .any(|window| window == b"payload"));
+
+        let decrypted =
+            Module::deserialize_decrypted(&mut io::Cursor::new(&module_bin), &kp.sk).unwrap();
+        assert!(!decrypted.is_encrypted());
+        assert_eq!(to_bytes(&decrypted), to_bytes(&self::module()));
+    }
+
+    #[test]
+    fn other_recipients_can_not_decrypt() {
+        let kp = EncryptionKeyPair::generate();
+        let module = encrypted(slice::from_ref(&kp.pk));
+
+        let other = EncryptionKeyPair::generate();
+        assert_rejected(&module, &other.sk);
+
+        // A key claiming the identifier of the recipient doesn't derive the same wrapping key.
+        let mut sk_bin = other.sk.to_bytes();
+        sk_bin[1..9].copy_from_slice(kp.sk.key_id());
+        let impostor = EncryptionSecretKey::from_bytes(&sk_bin).unwrap();
+        assert_rejected(&module, &impostor);
+    }
+
+    #[test]
+    fn tampered_modules_are_rejected() {
+        let kp = EncryptionKeyPair::generate();
+        let module = encrypted(slice::from_ref(&kp.pk));
+
+        // Nonce, tag and ciphertext of an encrypted section.
+        for offset in [0, 31, 32, 32 + TAG_BYTES - 1, 32 + TAG_BYTES] {
+            let tampered = with_payload(&module, ENCRYPTED_SECTION_NAME, |payload| {
+                payload[offset] ^= 0x01
+            });
+            assert_rejected(&tampered, &kp.sk);
+        }
+        // Ephemeral key and wrapped key of the envelope.
+        let envelope_len = module
+            .key_envelope()
+            .unwrap()
+            .unwrap()
+            .serialize()
+            .unwrap()
+            .len();
+        for offset in [1, 32, envelope_len - TAG_BYTES - 1, envelope_len - 1] {
+            let tampered = with_payload(&module, ENCRYPTION_KEYS_SECTION_NAME, |payload| {
+                payload[offset] ^= 0x01
+            });
+            assert_rejected(&tampered, &kp.sk);
+        }
+        // Envelope swapped with the one of another module encrypted for the same recipient.
+        let other = encrypted(slice::from_ref(&kp.pk));
+        let mut swapped = module.clone();
+        *swapped.sections.last_mut().unwrap() = other.sections.last().unwrap().clone();
+        assert_rejected(&swapped, &kp.sk);
+    }
+
+    #[test]
+    fn truncated_sections_are_rejected() {
+        let kp = EncryptionKeyPair::generate();
+        let module = encrypted(slice::from_ref(&kp.pk));
+
+        for name in [ENCRYPTED_SECTION_NAME, ENCRYPTION_KEYS_SECTION_NAME] {
+            let payload_len = module
+                .sections
+                .iter()
+                .find(|section| is_custom_section(section, name))
+                .unwrap()
+                .payload()
+                .len();
+            for truncated_len in 0..payload_len {
+                let truncated =
+                    with_payload(&module, name, |payload| payload.truncate(truncated_len));
+                assert_rejected(&truncated, &kp.sk);
+            }
+        }
+    }
+
+    #[test]
+    fn rotated_keys_keep_signatures_valid() {
+        let old = EncryptionKeyPair::generate();
+        let new = EncryptionKeyPair::generate();
+        let mut module = encrypted(slice::from_ref(&old.pk));
+
+        let kp = KeyPair::generate();
+        let mut key_ring = KeyRing::new();
+        key_ring.add(kp.pk.clone());
+        let ignored: IgnoreList = [ENCRYPTION_KEYS_SECTION_NAME].into_iter().collect();
+        let signature = kp
+            .sk
+            .sign_module_ignoring(&to_bytes(&module), &ignored)
+            .unwrap();
+        let encrypted_sections: Vec<_> = module.sections[..module.sections.len() - 1].to_vec();
+
+        assert!(module
+            .rotate_encryption_keys(&old.sk, slice::from_ref(&new.pk))
+            .unwrap());
+        // Only the envelope, at the end of the module, has been replaced.
+        assert!(module.sections[..module.sections.len() - 1] == encrypted_sections[..]);
+        key_ring
+            .verify_module_ignoring(&to_bytes(&module), &ignored, &signature)
+            .unwrap();
+        assert!(key_ring
+            .verify_module(&to_bytes(&module), &signature)
+            .is_err());
+
+        assert_rejected(&module, &old.sk);
+        assert!(module
+            .rotate_encryption_keys(&old.sk, slice::from_ref(&old.pk))
+            .is_err());
+        let mut decrypted = module.clone();
+        assert_eq!(decrypted.decrypt_sections(&new.sk).unwrap(), 2);
+        assert_eq!(to_bytes(&decrypted), to_bytes(&self::module()));
+
+        // Keys can be rotated again, and unencrypted modules are left alone.
+        assert!(module
+            .rotate_encryption_keys(&new.sk, &[old.pk.clone(), new.pk.clone()])
+            .unwrap());
+        assert_eq!(module.clone().decrypt_sections(&old.sk).unwrap(), 2);
+        key_ring
+            .verify_module_ignoring(&to_bytes(&module), &ignored, &signature)
+            .unwrap();
+        assert!(!decrypted
+            .rotate_encryption_keys(&new.sk, slice::from_ref(&new.pk))
+            .unwrap());
+    }
+}
@@ -14,4 +14,646 @@ pub enum WSError {
 
     #[error("UTF-8 error")]
     UTF8Error(#[from] std::str::Utf8Error),
+
+    #[error("Undefined template variable: {0}")]
+    UndefinedVariable(String),
+
+    #[error("No watermark found")]
+    NoWatermark,
+
+    #[error("Multiple watermarks found")]
+    DuplicateWatermark,
+
+    #[error("Verification failed")]
+    VerificationFailed,
+
+    #[error("Invalid key: {0}")]
+    InvalidKey(String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Version already exists: {0}")]
+    VersionExists(String),
+
+    #[error("Unsupported URL: {0}")]
+    UnsupportedUrl(String),
+
+    #[error("HTTP error: {0}")]
+    HttpError(u16),
+
+    #[error("Digest mismatch")]
+    DigestMismatch,
+
+    #[error("JSON error")]
+    JSONError(#[from] serde_json::Error),
+
+    #[error("Invalid download window")]
+    InvalidDownloadWindow,
+
+    #[error("Invalid certificate")]
+    InvalidCertificate,
+
+    #[error("TLS error: {0}")]
+    TlsError(String),
+
+    #[error("Insufficient storage space: {required} bytes required, {available} available")]
+    InsufficientSpace { required: u64, available: u64 },
+
+    #[error("No signature")]
+    NoSignature,
+
+    #[error("Unknown key: {0}")]
+    UnknownKey(String),
+
+    #[error("Revoked key: {0}")]
+    KeyRevoked(String),
+
+    #[error("Validation error: {0}")]
+    ValidationError(String),
+
+    #[error("Conflicting section: {0}")]
+    SectionConflict(String),
+
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+
+    #[error("Slots invariant violated: {0}")]
+    InvariantViolation(String),
+
+    #[error("Policy violation: {0}")]
+    PolicyViolation(String),
+
+    #[error("Decryption failed")]
+    DecryptionFailed,
+
+    #[error("Unsupported signature version: {0}")]
+    UnsupportedSignatureVersion(u8),
+
+    #[error("Registry error: {0}")]
+    RegistryError(String),
+
+    #[error("Object store error: {0}")]
+    ObjectStoreError(String),
+
+    #[error("Concurrent modification: {0}")]
+    ConcurrentModification(String),
+
+    #[error("Unsupported codec: {0}")]
+    UnsupportedCodec(String),
+
+    #[error("Memory limit exceeded: {required} bytes required, {available} available")]
+    MemoryLimit { required: u64, available: u64 },
+
+    #[error("Incompatible configuration: {0}")]
+    IncompatibleConfig(String),
+
+    #[error("Install hook rejected: {0}")]
+    HookRejected(String),
+
+    #[error("Data migration failed: {0}")]
+    MigrationFailed(String),
+
+    #[error("State transfer failed: {0}")]
+    StateTransferFailed(String),
+
+    #[error("Unknown feature flag: {0}")]
+    UnknownFeatureFlag(String),
+
+    #[error("Vulnerable dependency: {0}")]
+    VulnerableDependency(String),
+
+    #[error("Invalid bundle: {0}")]
+    InvalidBundle(String),
+
+    #[error("Unsatisfied requirement: {0}")]
+    UnsatisfiedRequirement(String),
+
+    #[error("Unsafe path: {0}")]
+    UnsafePath(String),
+
+    #[error("Replayed update response: {0}")]
+    ReplayedResponse(String),
+
+    #[error("Security downgrade: {0}")]
+    SecurityDowngrade(String),
+
+    #[error("Signing domain mismatch: {0}")]
+    DomainMismatch(String),
+
+    #[error("Runtime error: {0}")]
+    RuntimeError(String),
+}
+
+impl WSError {
+    /// Return a stable code identifying the kind of error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            WSError::UnsupportedModuleType => "E001",
+            WSError::ParseError => "E002",
+            WSError::IOError(_) => "E003",
+            WSError::Eof => "E004",
+            WSError::UTF8Error(_) => "E005",
+            WSError::UndefinedVariable(_) => "E006",
+            WSError::NoWatermark => "E007",
+            WSError::DuplicateWatermark => "E008",
+            WSError::VerificationFailed => "E009",
+            WSError::InvalidKey(_) => "E010",
+            WSError::NotFound(_) => "E011",
+            WSError::VersionExists(_) => "E012",
+            WSError::UnsupportedUrl(_) => "E013",
+            WSError::HttpError(_) => "E014",
+            WSError::DigestMismatch => "E015",
+            WSError::JSONError(_) => "E016",
+            WSError::InvalidDownloadWindow => "E017",
+            WSError::InvalidCertificate => "E018",
+            WSError::TlsError(_) => "E019",
+            WSError::InsufficientSpace { .. } => "E020",
+            WSError::NoSignature => "E021",
+            WSError::UnknownKey(_) => "E022",
+            WSError::KeyRevoked(_) => "E023",
+            WSError::ValidationError(_) => "E024",
+            WSError::SectionConflict(_) => "E025",
+            WSError::InvalidQuery(_) => "E026",
+            WSError::ConfigError(_) => "E027",
+            WSError::InvariantViolation(_) => "E028",
+            WSError::PolicyViolation(_) => "E029",
+            WSError::DecryptionFailed => "E030",
+            WSError::UnsupportedSignatureVersion(_) => "E031",
+            WSError::RegistryError(_) => "E032",
+            WSError::ObjectStoreError(_) => "E033",
+            WSError::ConcurrentModification(_) => "E034",
+            WSError::UnsupportedCodec(_) => "E035",
+            WSError::MemoryLimit { .. } => "E036",
+            WSError::IncompatibleConfig(_) => "E037",
+            WSError::HookRejected(_) => "E038",
+            WSError::MigrationFailed(_) => "E039",
+            WSError::StateTransferFailed(_) => "E040",
+            WSError::UnknownFeatureFlag(_) => "E041",
+            WSError::VulnerableDependency(_) => "E042",
+            WSError::InvalidBundle(_) => "E043",
+            WSError::UnsafePath(_) => "E044",
+            WSError::ReplayedResponse(_) => "E045",
+            WSError::SecurityDowngrade(_) => "E046",
+            WSError::DomainMismatch(_) => "E047",
+            WSError::UnsatisfiedRequirement(_) => "E048",
+            WSError::RuntimeError(_) => "E049",
+        }
+    }
+}
+
+/// A description of an error code, for users.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorExplanation {
+    pub code: &'static str,
+    /// Name of the `WSError` variant.
+    pub name: &'static str,
+    /// Likely causes.
+    pub causes: &'static str,
+    /// What to do about it.
+    pub remediation: &'static str,
+}
+
+const EXPLANATIONS: &[ErrorExplanation] = &[
+    ErrorExplanation {
+        code: "E001",
+        name: "UnsupportedModuleType",
+        causes: "The file is not a WebAssembly module or component, or uses an unsupported binary format version.",
+        remediation: "Check that the right file was given, and that it was not truncated or wrapped in another format (archive, base64, HTTP error page).",
+    },
+    ErrorExplanation {
+        code: "E002",
+        name: "ParseError",
+        causes: "The input is malformed: a section, signature, key, manifest or stored state could not be decoded.",
+        remediation: "Regenerate the input with the tool that produced it. If the file was transferred, check that it was not altered or truncated.",
+    },
+    ErrorExplanation {
+        code: "E003",
+        name: "IOError",
+        causes: "A file or network operation failed.",
+        remediation: "Check that the paths exist, permissions allow access, the disk is not full, and the network is reachable.",
+    },
+    ErrorExplanation {
+        code: "E004",
+        name: "Eof",
+        causes: "The input ended before the data being read was complete.",
+        remediation: "The file was probably truncated. Download or copy it again.",
+    },
+    ErrorExplanation {
+        code: "E005",
+        name: "UTF8Error",
+        causes: "A name or text field is not valid UTF-8.",
+        remediation: "Regenerate the module or metadata with a tool producing valid UTF-8 names.",
+    },
+    ErrorExplanation {
+        code: "E006",
+        name: "UndefinedVariable",
+        causes: "A ${NAME} placeholder has no value.",
+        remediation: "Define the variable with -D NAME=VALUE, or remove the placeholder from the section.",
+    },
+    ErrorExplanation {
+        code: "E007",
+        name: "NoWatermark",
+        causes: "The module doesn't contain a watermark.",
+        remediation: "Embed a watermark before distributing the module, or check that the right file was given.",
+    },
+    ErrorExplanation {
+        code: "E008",
+        name: "DuplicateWatermark",
+        causes: "The module contains more than one watermark section.",
+        remediation: "Remove the existing watermarks, and embed a single one.",
+    },
+    ErrorExplanation {
+        code: "E009",
+        name: "VerificationFailed",
+        causes: "A signature, watermark or digest doesn't match the data, or the signing key has been revoked.",
+        remediation: "Make sure that the module was not modified after signing, and that it was signed with a valid, trusted key. Sign it again if necessary.",
+    },
+    ErrorExplanation {
+        code: "E010",
+        name: "InvalidKey",
+        causes: "A key, or a name used as a key, is malformed.",
+        remediation: "Check the key file, and that names only contain letters, digits, '-', '_' and '.'.",
+    },
+    ErrorExplanation {
+        code: "E011",
+        name: "NotFound",
+        causes: "The requested build, channel, object or section doesn't exist.",
+        remediation: "Check the identifier, and that the module has been published to the repository in use.",
+    },
+    ErrorExplanation {
+        code: "E012",
+        name: "VersionExists",
+        causes: "This version has already been published on the channel, or the module is already published under another version.",
+        remediation: "Publish with a new version number.",
+    },
+    ErrorExplanation {
+        code: "E013",
+        name: "UnsupportedUrl",
+        causes: "The URL uses an unsupported scheme, or points outside of the repository.",
+        remediation: "Use an http:// URL, or an https:// URL with the tls feature enabled.",
+    },
+    ErrorExplanation {
+        code: "E014",
+        name: "HttpError",
+        causes: "The server returned an unexpected HTTP status.",
+        remediation: "Check the server logs, the URL and the credentials. 404 means the object doesn't exist, 5xx a server-side issue.",
+    },
+    ErrorExplanation {
+        code: "E015",
+        name: "DigestMismatch",
+        causes: "Downloaded data doesn't match its expected digest or size.",
+        remediation: "The download was corrupted or the server data changed. Retry, and check the integrity of the repository.",
+    },
+    ErrorExplanation {
+        code: "E016",
+        name: "JSONError",
+        causes: "A JSON document is malformed or doesn't have the expected structure.",
+        remediation: "Check that the client and server versions are compatible, and that stored JSON files were not edited by hand.",
+    },
+    ErrorExplanation {
+        code: "E017",
+        name: "InvalidDownloadWindow",
+        causes: "A download window is not in the HH:MM-HH:MM format.",
+        remediation: "Use a window such as 01:00-05:00, with hours below 24 and minutes below 60.",
+    },
+    ErrorExplanation {
+        code: "E018",
+        name: "InvalidCertificate",
+        causes: "A certificate could not be parsed.",
+        remediation: "Provide PEM-encoded certificates.",
+    },
+    ErrorExplanation {
+        code: "E019",
+        name: "TlsError",
+        causes: "The TLS connection could not be established.",
+        remediation: "Check the device clock, the server certificate, and the configured root certificates.",
+    },
+    ErrorExplanation {
+        code: "E020",
+        name: "InsufficientSpace",
+        causes: "There is not enough storage space to install the update.",
+        remediation: "Free some space, or enable low-space mode to move downloads into the slot instead of copying them.",
+    },
+    ErrorExplanation {
+        code: "E021",
+        name: "NoSignature",
+        causes: "A signature is required but the update isn't signed.",
+        remediation: "Sign the module and upload its signature to the repository, or remove trusted keys from the device configuration.",
+    },
+    ErrorExplanation {
+        code: "E022",
+        name: "UnknownKey",
+        causes: "The update is signed with a key that the device doesn't trust.",
+        remediation: "Add the signing public key to the trusted keys, or sign with a trusted key.",
+    },
+    ErrorExplanation {
+        code: "E023",
+        name: "KeyRevoked",
+        causes: "The signing key has been revoked.",
+        remediation: "Sign the module again with a key that hasn't been revoked.",
+    },
+    ErrorExplanation {
+        code: "E024",
+        name: "ValidationError",
+        causes: "The module is not valid WebAssembly.",
+        remediation: "Rebuild the module, and check the reported error against the compiler and tools in use.",
+    },
+    ErrorExplanation {
+        code: "E025",
+        name: "SectionConflict",
+        causes: "Two modules being merged have different standard sections, or conflicting custom sections.",
+        remediation: "Merge modules built from the same code, or choose another conflict policy.",
+    },
+    ErrorExplanation {
+        code: "E026",
+        name: "InvalidQuery",
+        causes: "A metadata query is malformed.",
+        remediation: "Use paths such as custom['producers'].sdk or sections[0].size.",
+    },
+    ErrorExplanation {
+        code: "E027",
+        name: "ConfigError",
+        causes: "The configuration file is malformed, contains an unknown setting, or a required setting is missing.",
+        remediation: "Fix the reported setting in the configuration file, or in the WASI_UPDATE_* environment variables.",
+    },
+    ErrorExplanation {
+        code: "E028",
+        name: "InvariantViolation",
+        causes: "A simulated power loss left the slots in an inconsistent state, such as an active slot with a missing or corrupted module.",
+        remediation: "Use a storage whose write and rename operations are atomic, and report the issue if it happens with the provided storage.",
+    },
+    ErrorExplanation {
+        code: "E029",
+        name: "PolicyViolation",
+        causes: "A module imports functions the sanitization policy doesn't allow, requires more memory than allowed, or declares licenses the license policy doesn't accept.",
+        remediation: "Allow the reported imports or licenses in the policy, or rebuild the module without them.",
+    },
+    ErrorExplanation {
+        code: "E030",
+        name: "DecryptionFailed",
+        causes: "The module wasn't encrypted for the given key, or an encrypted section was modified.",
+        remediation: "Decrypt the module with a key it was encrypted for, or fetch an unmodified copy.",
+    },
+    ErrorExplanation {
+        code: "E031",
+        name: "UnsupportedSignatureVersion",
+        causes: "A signature uses a format version this version of wasi-update doesn't support.",
+        remediation: "Upgrade wasi-update, or replace the signatures of the repository with `wasi-update migrate-signatures`.",
+    },
+    ErrorExplanation {
+        code: "E032",
+        name: "RegistryError",
+        causes: "An OCI registry rejected a request, for example because the credentials are missing or don't allow the operation, or the repository doesn't exist.",
+        remediation: "Check the reference and the credentials, and that the token has the required push or pull permissions.",
+    },
+    ErrorExplanation {
+        code: "E033",
+        name: "ObjectStoreError",
+        causes: "An object store rejected a request, for example because the bucket doesn't exist, the credentials are invalid, or the region doesn't match the bucket.",
+        remediation: "Check the endpoint, bucket, region and credentials, and that they allow reading, writing and listing objects.",
+    },
+    ErrorExplanation {
+        code: "E034",
+        name: "ConcurrentModification",
+        causes: "An object kept being modified by other publishers while it was being updated.",
+        remediation: "Retry the operation, or avoid publishing to the same channel from many places at once.",
+    },
+    ErrorExplanation {
+        code: "E035",
+        name: "UnsupportedCodec",
+        causes: "Data was encoded with a codec that isn't known, or that wasi-update was built without, or uses a feature of the codec that isn't supported.",
+        remediation: "Check the name of the codec, and enable the matching Cargo feature (`compression`, `zstd` or `brotli`).",
+    },
+    ErrorExplanation {
+        code: "E036",
+        name: "MemoryLimit",
+        causes: "Parsing, diffing or patching a module needed more memory than the limit allows, or a patch needs more memory to be applied than the device has, or the memory budget a patch was made for is too small.",
+        remediation: "Raise the limit (`limits.memory`), or make patches for the memory budget of the devices (`make-patch --memory-budget`).",
+    },
+    ErrorExplanation {
+        code: "E037",
+        name: "IncompatibleConfig",
+        causes: "The configuration schema of a module declares keys that the configuration provided by the device is missing, or provides with a different type.",
+        remediation: "Provide the missing keys on the device before updating it, or make them optional in the configuration schema of the module.",
+    },
+    ErrorExplanation {
+        code: "E038",
+        name: "HookRejected",
+        causes: "An update carries install hooks that the hook policy of the agent doesn't allow, that are not covered by its signature, or that no hook runner was set up to run.",
+        remediation: "Allow the hooks in the `hook_policy` of `AgentConfig` and set a runner with `Agent::set_hook_runner()`, or publish the update without them.",
+    },
+    ErrorExplanation {
+        code: "E039",
+        name: "MigrationFailed",
+        causes: "A data migration step failed while installing an update, or a WebAssembly migration step was registered without a runner. The previous module was restored.",
+        remediation: "Check the logs of the migration step for the version given in the message, and set a runner with `Migrations::wasm_runner()` for WebAssembly steps.",
+    },
+    ErrorExplanation {
+        code: "E040",
+        name: "StateTransferFailed",
+        causes: "The runtime couldn't take a snapshot of the state of the running module, convert it to the state format of the update, or restore it into the update.",
+        remediation: "Check the `StateManager` of the agent, and make sure that it can convert between the state formats declared by the modules.",
+    },
+    ErrorExplanation {
+        code: "E041",
+        name: "UnknownFeatureFlag",
+        causes: "A feature flag default was overridden, but the module doesn't declare that flag in its `feature-flags` section.",
+        remediation: "Check the name of the flag, and list the flags of the module with `wasi-update inspect --get custom.feature-flags`.",
+    },
+    ErrorExplanation {
+        code: "E042",
+        name: "VulnerableDependency",
+        causes: "The bill of materials of the module lists a component version affected by a known advisory.",
+        remediation: "Upgrade the components listed in the message to a version the advisory doesn't affect, and rebuild the module.",
+    },
+    ErrorExplanation {
+        code: "E043",
+        name: "InvalidBundle",
+        causes: "A bundle lists the same artifact twice, or an artifact doesn't match the size recorded in the manifest.",
+        remediation: "Give every artifact a distinct name, and recreate the bundle if it was modified after it was created.",
+    },
+    ErrorExplanation {
+        code: "E044",
+        name: "UnsafePath",
+        causes: "An object of the slot directory is a symbolic link, which could make the agent read or overwrite a file outside of that directory.",
+        remediation: "Remove the link from the slot directory. To keep the slots on another file system, point agent.slots to a directory there instead.",
+    },
+    ErrorExplanation {
+        code: "E045",
+        name: "ReplayedResponse",
+        causes: "The response to an update check doesn't carry the nonce sent with the request: it was recorded earlier and replayed, possibly to force a downgrade, or the server doesn't support nonces.",
+        remediation: "Check that the server echoes the nonce of update checks in its responses. If it does, the device may be behind a proxy or a network that replays responses.",
+    },
+    ErrorExplanation {
+        code: "E046",
+        name: "SecurityDowngrade",
+        causes: "The security version of the update, or of the slot to roll back to, is lower than the security version the device already confirmed, and the module doesn't carry a downgrade authorization made with a trusted downgrade key.",
+        remediation: "Publish an update with a security version at least equal to the one of the device. If the downgrade is intended, authorize it with `set-security-version --authorize`, using a key listed in keys.downgrade.",
+    },
+    ErrorExplanation {
+        code: "E047",
+        name: "DomainMismatch",
+        causes: "A signature was made with the key of another signing domain (tenant or project) than the one expected, or an artifact already belongs to another domain. This is what happens if a key compromised in one project is used to sign updates for another project.",
+        remediation: "Sign the artifact with a key of its own domain, and check that keys.domain is set to the right domain. If the key shouldn't have been used, revoke it.",
+    },
+    ErrorExplanation {
+        code: "E048",
+        name: "UnsatisfiedRequirement",
+        causes: "A member of a bundle requires another member that is missing, or whose version doesn't match the requirement, and no combination of the available versions satisfies every requirement.",
+        remediation: "Add a version of the required member matching the requirement to the release description, or relax the requirement if the member is still compatible.",
+    },
+    ErrorExplanation {
+        code: "E049",
+        name: "RuntimeError",
+        causes: "A module could not be instantiated in the runtime, one of its functions trapped, or a call was made while the instance was being hot swapped.",
+        remediation: "Check that the host provides every import of the module, and retry calls once the swap is complete. Traps are bugs of the module.",
+    },
+];
+
+impl WSError {
+    /// Return the likely causes of the error, and how to fix it.
+    pub fn explanation(&self) -> &'static ErrorExplanation {
+        explain(self.code()).unwrap()
+    }
+}
+
+/// Return the explanation of an error, given its code (`E001`) or name (`UnsupportedModuleType`).
+pub fn explain(code: &str) -> Option<&'static ErrorExplanation> {
+    let code = code.trim();
+    let code = code.strip_prefix("WSError::").unwrap_or(code);
+    EXPLANATIONS.iter().find(|explanation| {
+        explanation.code.eq_ignore_ascii_case(code) || explanation.name.eq_ignore_ascii_case(code)
+    })
+}
+
+/// Exit status of the command-line tool.
+///
+/// Statuses group errors by what a script can do about them, and are stable: new error kinds
+/// are mapped to an existing status rather than getting a new one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ExitStatus {
+    Success = 0,
+    /// An error that doesn't fit any other status.
+    Failure = 1,
+    /// Invalid command-line arguments.
+    Usage = 2,
+    /// Some of the inputs of a batch operation were processed, but not all of them.
+    PartialFailure = 3,
+    /// A signature, key or digest couldn't be verified.
+    VerificationFailed = 4,
+    /// A file, artifact, channel or key doesn't exist.
+    NotFound = 5,
+    /// A file, network or storage operation failed, and may succeed if retried.
+    Unavailable = 6,
+    /// An input is malformed or unsupported.
+    InvalidInput = 7,
+    /// The configuration is invalid.
+    ConfigError = 8,
+    /// A policy, check or hook rejected the operation.
+    Rejected = 9,
+    /// The operation conflicts with something that already exists or changed concurrently.
+    Conflict = 10,
+    /// The slots or resources of the device prevent the operation.
+    DeviceState = 11,
+}
+
+impl ExitStatus {
+    /// Return the process exit code.
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    /// Return the name of the status, as used in JSON results.
+    pub fn name(self) -> &'static str {
+        match self {
+            ExitStatus::Success => "success",
+            ExitStatus::Failure => "failure",
+            ExitStatus::Usage => "usage",
+            ExitStatus::PartialFailure => "partial_failure",
+            ExitStatus::VerificationFailed => "verification_failed",
+            ExitStatus::NotFound => "not_found",
+            ExitStatus::Unavailable => "unavailable",
+            ExitStatus::InvalidInput => "invalid_input",
+            ExitStatus::ConfigError => "config_error",
+            ExitStatus::Rejected => "rejected",
+            ExitStatus::Conflict => "conflict",
+            ExitStatus::DeviceState => "device_state",
+        }
+    }
+}
+
+impl std::fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl From<&std::io::Error> for ExitStatus {
+    fn from(e: &std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::NotFound => ExitStatus::NotFound,
+            _ => ExitStatus::Unavailable,
+        }
+    }
+}
+
+impl WSError {
+    /// Return the exit status of the command-line tool for the error.
+    pub fn exit_status(&self) -> ExitStatus {
+        match self {
+            WSError::IOError(e) => e.into(),
+            WSError::HttpError(404) | WSError::NotFound(_) => ExitStatus::NotFound,
+            WSError::HttpError(_)
+            | WSError::TlsError(_)
+            | WSError::InvalidCertificate
+            | WSError::RegistryError(_)
+            | WSError::ObjectStoreError(_) => ExitStatus::Unavailable,
+            WSError::VerificationFailed
+            | WSError::DigestMismatch
+            | WSError::NoSignature
+            | WSError::UnknownKey(_)
+            | WSError::KeyRevoked(_)
+            | WSError::DecryptionFailed
+            | WSError::ReplayedResponse(_)
+            | WSError::DomainMismatch(_) => ExitStatus::VerificationFailed,
+            WSError::UnsupportedModuleType
+            | WSError::ParseError
+            | WSError::Eof
+            | WSError::UTF8Error(_)
+            | WSError::UndefinedVariable(_)
+            | WSError::NoWatermark
+            | WSError::DuplicateWatermark
+            | WSError::InvalidKey(_)
+            | WSError::UnsupportedUrl(_)
+            | WSError::JSONError(_)
+            | WSError::ValidationError(_)
+            | WSError::InvalidQuery(_)
+            | WSError::UnsupportedSignatureVersion(_)
+            | WSError::UnsupportedCodec(_)
+            | WSError::UnknownFeatureFlag(_)
+            | WSError::InvalidBundle(_) => ExitStatus::InvalidInput,
+            WSError::ConfigError(_)
+            | WSError::InvalidDownloadWindow
+            | WSError::IncompatibleConfig(_) => ExitStatus::ConfigError,
+            WSError::PolicyViolation(_)
+            | WSError::HookRejected(_)
+            | WSError::VulnerableDependency(_)
+            | WSError::SecurityDowngrade(_) => ExitStatus::Rejected,
+            WSError::VersionExists(_)
+            | WSError::SectionConflict(_)
+            | WSError::ConcurrentModification(_)
+            | WSError::UnsatisfiedRequirement(_) => ExitStatus::Conflict,
+            WSError::InsufficientSpace { .. }
+            | WSError::InvariantViolation(_)
+            | WSError::MemoryLimit { .. }
+            | WSError::MigrationFailed(_)
+            | WSError::StateTransferFailed(_)
+            | WSError::UnsafePath(_)
+            | WSError::RuntimeError(_) => ExitStatus::DeviceState,
+        }
+    }
 }
@@ -0,0 +1,150 @@
+//! Feature flags compiled into a module.
+//!
+//! A module can list the feature flags it was built with, and their default values, in a
+//! custom section that it reads at startup. Defaults can be overridden when the module is
+//! published, so that a feature can be enabled on one channel and not on another, without
+//! rebuilding the module.
+
+use crate::error::*;
+use crate::wasm_module::*;
+
+use std::collections::HashSet;
+use std::io::{self, prelude::*};
+use std::str;
+
+/// Name of the custom section storing the feature flags.
+pub const FEATURE_FLAGS_SECTION_NAME: &str = "feature-flags";
+
+const FEATURE_FLAGS_VERSION: u8 = 0x01;
+
+/// A feature flag and its default value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureFlag {
+    pub name: String,
+    pub default: bool,
+}
+
+impl FeatureFlag {
+    /// Create a new flag.
+    pub fn new(name: impl Into<String>, default: bool) -> Self {
+        FeatureFlag {
+            name: name.into(),
+            default,
+        }
+    }
+}
+
+/// The feature flags of a module, stored as a custom section.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeatureFlags {
+    pub flags: Vec<FeatureFlag>,
+}
+
+impl FeatureFlags {
+    /// Serialize the flags as a custom section payload.
+    pub fn serialize(&self) -> Result<Vec<u8>, WSError> {
+        let mut writer = io::Cursor::new(vec![]);
+        writer.write_all(&[FEATURE_FLAGS_VERSION])?;
+        varint::put(&mut writer, self.flags.len() as _)?;
+        for flag in &self.flags {
+            varint::put_slice(&mut writer, flag.name.as_bytes())?;
+            writer.write_all(&[flag.default as u8])?;
+        }
+        Ok(writer.into_inner())
+    }
+
+    /// Deserialize flags from a custom section payload.
+    ///
+    /// Sections declaring the same flag twice are rejected.
+    pub fn deserialize(payload: &[u8]) -> Result<Self, WSError> {
+        TakeExact::decode(payload, payload.len() as _, |reader| {
+            let mut version = [0u8; 1];
+            reader.read_exact(&mut version)?;
+            if version[0] != FEATURE_FLAGS_VERSION {
+                return Err(WSError::ParseError);
+            }
+            let count = varint::get32(reader)?;
+            let mut flags = vec![];
+            let mut names = HashSet::new();
+            for _ in 0..count {
+                let name = str::from_utf8(&reader.get_slice()?)?.to_string();
+                let mut default = [0u8; 1];
+                reader.read_exact(&mut default)?;
+                if default[0] > 1 || !names.insert(name.clone()) {
+                    return Err(WSError::ParseError);
+                }
+                flags.push(FeatureFlag {
+                    name,
+                    default: default[0] == 1,
+                });
+            }
+            Ok(FeatureFlags { flags })
+        })
+    }
+
+    /// Return the default value of a flag, or `None` if it isn't declared.
+    pub fn get(&self, name: &str) -> Option<bool> {
+        self.flags
+            .iter()
+            .find(|flag| flag.name == name)
+            .map(|flag| flag.default)
+    }
+
+    /// Change the default value of a declared flag.
+    pub fn set_default(&mut self, name: &str, default: bool) -> Result<(), WSError> {
+        match self.flags.iter_mut().find(|flag| flag.name == name) {
+            Some(flag) => {
+                flag.default = default;
+                Ok(())
+            }
+            None => Err(WSError::UnknownFeatureFlag(name.to_string())),
+        }
+    }
+}
+
+impl Module {
+    /// Return the feature flags of the module, if it declares any.
+    pub fn feature_flags(&self) -> Result<Option<FeatureFlags>, WSError> {
+        for section in &self.sections {
+            if let Section::Custom(custom) = section {
+                if custom.name() == FEATURE_FLAGS_SECTION_NAME {
+                    return Ok(Some(FeatureFlags::deserialize(custom.payload())?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Embed feature flags into the module, replacing the existing ones.
+    pub fn set_feature_flags(&mut self, flags: &FeatureFlags) -> Result<(), WSError> {
+        let payload = flags.serialize()?;
+        for section in self.sections.iter_mut() {
+            if let Section::Custom(custom) = section {
+                if custom.name() == FEATURE_FLAGS_SECTION_NAME {
+                    custom.set_payload(payload);
+                    return Ok(());
+                }
+            }
+        }
+        self.sections.push(Section::Custom(CustomSection::new(
+            FEATURE_FLAGS_SECTION_NAME.to_string(),
+            payload,
+        )));
+        Ok(())
+    }
+
+    /// Override the defaults of feature flags declared by the module.
+    ///
+    /// Flags the module doesn't declare are rejected, and the module is left unchanged.
+    /// Signatures have to be created again afterwards.
+    pub fn override_feature_flags(&mut self, overrides: &[(&str, bool)]) -> Result<(), WSError> {
+        if overrides.is_empty() {
+            return Ok(());
+        }
+        let mut flags = self.feature_flags()?.unwrap_or_default();
+        for (name, default) in overrides {
+            flags.set_default(name, *default)?;
+        }
+        self.set_feature_flags(&flags)
+    }
+}
@@ -0,0 +1,127 @@
+//! Section sizes across a series of module versions.
+//!
+//! A size history lists the serialized size of every section in each version, so that the
+//! growth of the code, the data, or a custom section can be tracked release after release.
+//! Sections are identified by their stable identifier, so that a custom section appearing
+//! multiple times is tracked per occurrence.
+
+use crate::diff::*;
+use crate::error::*;
+use crate::wasm_module::*;
+
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// The sizes of a section across versions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SectionSizes {
+    /// Stable identifier of the section.
+    pub section: String,
+    /// Serialized size of the section in each version, `None` if the version doesn't have it.
+    pub sizes: Vec<Option<u64>>,
+}
+
+impl SectionSizes {
+    /// Return the difference between the size of the section in the last version and in the
+    /// first one, counting missing sections as empty.
+    pub fn growth(&self) -> i64 {
+        let size = |size: Option<&Option<u64>>| size.copied().flatten().unwrap_or(0) as i64;
+        size(self.sizes.last()) - size(self.sizes.first())
+    }
+}
+
+/// The section sizes of a series of module versions.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SizeHistory {
+    /// Labels of the versions, in order.
+    pub versions: Vec<String>,
+    /// Sections found in any version, in order of first appearance.
+    pub sections: Vec<SectionSizes>,
+    /// Total size of the sections of each version.
+    pub totals: Vec<u64>,
+}
+
+impl SizeHistory {
+    /// Compute the section sizes of a series of versions, given as `(label, module)` pairs from
+    /// the oldest to the newest.
+    ///
+    /// Sections shared by multiple versions are only measured once.
+    pub fn new<'t>(
+        versions: impl IntoIterator<Item = (impl Into<String>, &'t Module)>,
+    ) -> Result<Self, WSError> {
+        let mut cache = SectionCache::new();
+        let mut history = SizeHistory::default();
+        let mut rows: HashMap<String, usize> = HashMap::new();
+        for (label, module) in versions {
+            let version = history.versions.len();
+            history.versions.push(label.into());
+            let mut total = 0;
+            for (key, _, size) in cache.sections(module)? {
+                let section = key.stable_id();
+                let row = *rows.entry(section.clone()).or_insert_with(|| {
+                    history.sections.push(SectionSizes {
+                        section,
+                        sizes: vec![None; version],
+                    });
+                    history.sections.len() - 1
+                });
+                history.sections[row].sizes.push(Some(size));
+                total += size;
+            }
+            for sizes in &mut history.sections {
+                sizes.sizes.resize(version + 1, None);
+            }
+            history.totals.push(total);
+        }
+        Ok(history)
+    }
+
+    /// Return the sizes of a section, given its stable identifier.
+    pub fn section(&self, section: &str) -> Option<&SectionSizes> {
+        self.sections.iter().find(|sizes| sizes.section == section)
+    }
+
+    /// Serialize the history as JSON.
+    pub fn to_json(&self) -> Result<String, WSError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Serialize the history as CSV, with a row per section, a column per version, a final
+    /// column with the growth between the first and the last version, and a final row with
+    /// the totals. Missing sections are left empty.
+    pub fn to_csv(&self) -> String {
+        fn field(value: &str) -> String {
+            if value.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            } else {
+                value.to_string()
+            }
+        }
+        let mut csv = String::from("section");
+        for version in &self.versions {
+            csv.push(',');
+            csv.push_str(&field(version));
+        }
+        csv.push_str(",growth\n");
+        for sizes in &self.sections {
+            csv.push_str(&field(&sizes.section));
+            for size in &sizes.sizes {
+                csv.push(',');
+                if let Some(size) = size {
+                    csv.push_str(&size.to_string());
+                }
+            }
+            csv.push_str(&format!(",{}\n", sizes.growth()));
+        }
+        csv.push_str("total");
+        for total in &self.totals {
+            csv.push_str(&format!(",{}", total));
+        }
+        let growth = match (self.totals.first(), self.totals.last()) {
+            (Some(&first), Some(&last)) => last as i64 - first as i64,
+            _ => 0,
+        };
+        csv.push_str(&format!(",{}\n", growth));
+        csv
+    }
+}
@@ -0,0 +1,99 @@
+//! Health-check contracts.
+//!
+//! A module can declare the export to call after an update to check that it works, and how long
+//! that check may take. Agents read it to confirm the update or roll it back, so that hosts don't
+//! have to rely on naming conventions.
+
+use crate::error::*;
+use crate::wasm_module::*;
+
+use std::io::{self, prelude::*};
+use std::str;
+use std::time::Duration;
+
+/// Name of the custom section storing the health-check contract.
+pub const HEALTH_CHECK_SECTION_NAME: &str = "health-check";
+
+const HEALTH_CHECK_VERSION: u8 = 0x01;
+
+/// How to check that a module works after an update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// Name of the exported function to call.
+    pub export: String,
+    /// Maximum time the check may take before the module is considered unhealthy.
+    pub timeout: Duration,
+}
+
+impl HealthCheck {
+    /// Create a new health-check contract.
+    pub fn new(export: impl Into<String>, timeout: Duration) -> Self {
+        HealthCheck {
+            export: export.into(),
+            timeout,
+        }
+    }
+
+    /// Serialize the contract as a custom section payload.
+    ///
+    /// The timeout is stored in milliseconds.
+    pub fn serialize(&self) -> Result<Vec<u8>, WSError> {
+        let mut writer = io::Cursor::new(vec![]);
+        writer.write_all(&[HEALTH_CHECK_VERSION])?;
+        varint::put_slice(&mut writer, self.export.as_bytes())?;
+        varint::put(
+            &mut writer,
+            u64::try_from(self.timeout.as_millis()).unwrap_or(u64::MAX),
+        )?;
+        Ok(writer.into_inner())
+    }
+
+    /// Deserialize a contract from a custom section payload.
+    pub fn deserialize(payload: &[u8]) -> Result<Self, WSError> {
+        TakeExact::decode(payload, payload.len() as _, |reader| {
+            let mut version = [0u8; 1];
+            reader.read_exact(&mut version)?;
+            if version[0] != HEALTH_CHECK_VERSION {
+                return Err(WSError::ParseError);
+            }
+            let export = str::from_utf8(&reader.get_slice()?)?.to_string();
+            if export.is_empty() {
+                return Err(WSError::ParseError);
+            }
+            let timeout = Duration::from_millis(varint::get64(reader)?);
+            Ok(HealthCheck { export, timeout })
+        })
+    }
+}
+
+impl Module {
+    /// Return the health-check contract of the module, if it has one.
+    pub fn health_check(&self) -> Result<Option<HealthCheck>, WSError> {
+        for section in &self.sections {
+            if let Section::Custom(custom) = section {
+                if custom.name() == HEALTH_CHECK_SECTION_NAME {
+                    return Ok(Some(HealthCheck::deserialize(custom.payload())?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Embed a health-check contract into the module, replacing the existing one.
+    pub fn set_health_check(&mut self, health_check: &HealthCheck) -> Result<(), WSError> {
+        let payload = health_check.serialize()?;
+        for section in self.sections.iter_mut() {
+            if let Section::Custom(custom) = section {
+                if custom.name() == HEALTH_CHECK_SECTION_NAME {
+                    custom.set_payload(payload);
+                    return Ok(());
+                }
+            }
+        }
+        self.sections.push(Section::Custom(CustomSection::new(
+            HEALTH_CHECK_SECTION_NAME.to_string(),
+            payload,
+        )));
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+//! Install hooks.
+//!
+//! Updates can carry actions to run before and after they are installed, such as migrating data to
+//! a new format, in a custom section. Hooks are either WebAssembly modules, run by the host, or
+//! descriptions of host commands. Agents only run the ones allowed by a `HookPolicy`, which
+//! allows none by default.
+
+use crate::error::*;
+use crate::wasm_module::*;
+
+use std::collections::BTreeSet;
+use std::io::{self, prelude::*};
+use std::str;
+
+/// Name of the custom section storing install hooks.
+pub const HOOKS_SECTION_NAME: &str = "install-hooks";
+
+const HOOKS_VERSION: u8 = 0x01;
+
+/// When a hook runs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HookStage {
+    /// Before the update is installed. A failure aborts the installation.
+    PreInstall,
+    /// After the update is installed. A failure rolls the installation back.
+    PostInstall,
+}
+
+/// What a hook does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookAction {
+    /// Run a WebAssembly module.
+    Wasm(Vec<u8>),
+    /// Run a host program.
+    Command { program: String, args: Vec<String> },
+}
+
+/// An action to run when installing an update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hook {
+    /// Name of the hook, for logs and reports.
+    pub name: String,
+    pub stage: HookStage,
+    pub action: HookAction,
+}
+
+/// The hooks of an update, stored as a custom section.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Hooks {
+    pub entries: Vec<Hook>,
+}
+
+impl Hooks {
+    /// Return the hooks to run at a given stage, in order.
+    pub fn stage(&self, stage: HookStage) -> impl Iterator<Item = &Hook> {
+        self.entries.iter().filter(move |hook| hook.stage == stage)
+    }
+
+    /// Serialize the hooks as a custom section payload.
+    pub fn serialize(&self) -> Result<Vec<u8>, WSError> {
+        let mut writer = io::Cursor::new(vec![]);
+        writer.write_all(&[HOOKS_VERSION])?;
+        varint::put(&mut writer, self.entries.len() as _)?;
+        for hook in &self.entries {
+            varint::put_slice(&mut writer, hook.name.as_bytes())?;
+            let stage = match hook.stage {
+                HookStage::PreInstall => 0x00,
+                HookStage::PostInstall => 0x01,
+            };
+            match &hook.action {
+                HookAction::Wasm(module_bin) => {
+                    writer.write_all(&[stage, 0x00])?;
+                    varint::put_slice(&mut writer, module_bin)?;
+                }
+                HookAction::Command { program, args } => {
+                    writer.write_all(&[stage, 0x01])?;
+                    varint::put_slice(&mut writer, program.as_bytes())?;
+                    varint::put(&mut writer, args.len() as _)?;
+                    for arg in args {
+                        varint::put_slice(&mut writer, arg.as_bytes())?;
+                    }
+                }
+            }
+        }
+        Ok(writer.into_inner())
+    }
+
+    /// Deserialize hooks from a custom section payload.
+    pub fn deserialize(payload: &[u8]) -> Result<Self, WSError> {
+        TakeExact::decode(payload, payload.len() as _, |reader| {
+            let mut version = [0u8; 1];
+            reader.read_exact(&mut version)?;
+            if version[0] != HOOKS_VERSION {
+                return Err(WSError::ParseError);
+            }
+            let count = varint::get32(reader)?;
+            let mut entries = vec![];
+            for _ in 0..count {
+                let name = str::from_utf8(&reader.get_slice()?)?.to_string();
+                let mut stage_and_kind = [0u8; 2];
+                reader.read_exact(&mut stage_and_kind)?;
+                let stage = match stage_and_kind[0] {
+                    0x00 => HookStage::PreInstall,
+                    0x01 => HookStage::PostInstall,
+                    _ => return Err(WSError::ParseError),
+                };
+                let action = match stage_and_kind[1] {
+                    0x00 => HookAction::Wasm(reader.get_slice()?),
+                    0x01 => {
+                        let program = str::from_utf8(&reader.get_slice()?)?.to_string();
+                        let argc = varint::get32(reader)?;
+                        let mut args = vec![];
+                        for _ in 0..argc {
+                            args.push(str::from_utf8(&reader.get_slice()?)?.to_string());
+                        }
+                        HookAction::Command { program, args }
+                    }
+                    _ => return Err(WSError::ParseError),
+                };
+                entries.push(Hook {
+                    name,
+                    stage,
+                    action,
+                });
+            }
+            Ok(Hooks { entries })
+        })
+    }
+}
+
+/// The hooks an agent is allowed to run.
+///
+/// The default policy rejects all hooks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HookPolicy {
+    /// Maximum size of WebAssembly hooks, or `None` to reject them.
+    pub max_wasm_size: Option<u64>,
+    /// Host programs that command hooks can run.
+    pub allowed_commands: BTreeSet<String>,
+}
+
+impl HookPolicy {
+    /// Create a policy rejecting all hooks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow WebAssembly hooks up to `max_size` bytes.
+    pub fn allow_wasm(mut self, max_size: u64) -> Self {
+        self.max_wasm_size = Some(max_size);
+        self
+    }
+
+    /// Allow command hooks running `program`.
+    pub fn allow_command(mut self, program: impl Into<String>) -> Self {
+        self.allowed_commands.insert(program.into());
+        self
+    }
+
+    /// Check that every hook is allowed, and that WebAssembly hooks are valid modules.
+    pub fn check(&self, hooks: &Hooks) -> Result<(), WSError> {
+        for hook in &hooks.entries {
+            let rejected =
+                |reason: &str| WSError::HookRejected(format!("{}: {}", hook.name, reason));
+            match &hook.action {
+                HookAction::Wasm(module_bin) => {
+                    let max_size = self
+                        .max_wasm_size
+                        .ok_or_else(|| rejected("WebAssembly hooks are not allowed"))?;
+                    if module_bin.len() as u64 > max_size {
+                        return Err(rejected("module is too large"));
+                    }
+                    Module::deserialize(&mut io::Cursor::new(module_bin))
+                        .map_err(|_| rejected("invalid module"))?;
+                }
+                HookAction::Command { program, .. } => {
+                    if !self.allowed_commands.contains(program) {
+                        return Err(rejected(&format!("{} is not an allowed command", program)));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Module {
+    /// Return the install hooks embedded in the module, if any.
+    pub fn hooks(&self) -> Result<Option<Hooks>, WSError> {
+        for section in &self.sections {
+            if let Section::Custom(custom) = section {
+                if custom.name() == HOOKS_SECTION_NAME {
+                    return Ok(Some(Hooks::deserialize(custom.payload())?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Embed install hooks into the module, replacing existing ones.
+    pub fn set_hooks(&mut self, hooks: &Hooks) -> Result<(), WSError> {
+        let payload = hooks.serialize()?;
+        for section in self.sections.iter_mut() {
+            if let Section::Custom(custom) = section {
+                if custom.name() == HOOKS_SECTION_NAME {
+                    custom.set_payload(payload);
+                    return Ok(());
+                }
+            }
+        }
+        self.sections.push(Section::Custom(CustomSection::new(
+            HOOKS_SECTION_NAME.to_string(),
+            payload,
+        )));
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+//! Instrumentation of the update phases.
+//!
+//! With the `tracing` feature, each phase runs in a `tracing` span recording the number of bytes
+//! processed and the elapsed time. These are also logged at the `trace` level.
+
+use std::io::{self, prelude::*};
+use std::time::{Duration, Instant};
+
+/// A phase of the update process.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Phase {
+    Parse,
+    Verify,
+    Diff,
+    Fetch,
+    Install,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Parse => "parse",
+            Phase::Verify => "verify",
+            Phase::Diff => "diff",
+            Phase::Fetch => "fetch",
+            Phase::Install => "install",
+        }
+    }
+
+    /// Enter the phase. It ends when the returned guard is dropped.
+    pub fn start(self) -> PhaseGuard {
+        #[cfg(feature = "tracing")]
+        let span = {
+            use tracing::field::Empty;
+            match self {
+                Phase::Parse => tracing::info_span!("parse", bytes = Empty, elapsed_us = Empty),
+                Phase::Verify => tracing::info_span!("verify", bytes = Empty, elapsed_us = Empty),
+                Phase::Diff => tracing::info_span!("diff", bytes = Empty, elapsed_us = Empty),
+                Phase::Fetch => tracing::info_span!("fetch", bytes = Empty, elapsed_us = Empty),
+                Phase::Install => {
+                    tracing::info_span!("install", bytes = Empty, elapsed_us = Empty)
+                }
+            }
+            .entered()
+        };
+        PhaseGuard {
+            phase: self,
+            started: Instant::now(),
+            bytes: 0,
+            #[cfg(feature = "tracing")]
+            span,
+        }
+    }
+}
+
+/// A running phase.
+pub(crate) struct PhaseGuard {
+    phase: Phase,
+    started: Instant,
+    bytes: u64,
+    #[cfg(feature = "tracing")]
+    span: tracing::span::EnteredSpan,
+}
+
+impl PhaseGuard {
+    /// Count bytes processed during the phase.
+    pub fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+
+    /// Return the time elapsed since the phase started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+impl Drop for PhaseGuard {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("bytes", self.bytes);
+            self.span.record("elapsed_us", elapsed.as_micros() as u64);
+        }
+        log::trace!(
+            "Phase [{}]: {} bytes in {:?}",
+            self.phase.name(),
+            self.bytes,
+            elapsed
+        );
+    }
+}
+
+/// A reader counting the bytes read through it.
+pub(crate) struct CountingReader<'t, R: Read> {
+    reader: &'t mut R,
+    pub count: u64,
+}
+
+impl<'t, R: Read> CountingReader<'t, R> {
+    pub fn new(reader: &'t mut R) -> Self {
+        CountingReader { reader, count: 0 }
+    }
+}
+
+impl<'t, R: Read> Read for CountingReader<'t, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
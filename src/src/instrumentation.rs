@@ -0,0 +1,184 @@
+//! Injection of instrumentation probes into the functions of a module.
+//!
+//! Probes run when a function is entered. Counters increment a mutable global exported by the
+//! module, that the host can read without any call into it. Trace points call a function
+//! imported from the host with the probe identifier. Instrumented modules can be deployed to
+//! part of a fleet, to compare their behavior with the regular ones.
+
+use crate::error::*;
+use crate::wasm_module::*;
+
+use std::collections::{BTreeSet, HashMap};
+use walrus::ir::{BinaryOp, Binop, Call, Const, GlobalGet, GlobalSet, Value};
+use walrus::{ConstExpr, ExportItem, FunctionId, FunctionKind, ValType};
+
+/// Prefix of the names counter globals are exported as, followed by the probe identifier.
+pub const COUNTER_EXPORT_PREFIX: &str = "wasi-update.counter.";
+
+/// What a probe does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeKind {
+    /// Count calls in an exported `i64` global.
+    Counter,
+    /// Call the function `name` imported from `module`, with the probe identifier as an `i32`.
+    TracePoint { module: String, name: String },
+}
+
+/// The functions to instrument.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FunctionFilter {
+    /// All the functions defined by the module.
+    All,
+    /// Exported functions.
+    Exported,
+    /// Functions with these names, as found in the name section or the exports.
+    Named(BTreeSet<String>),
+    /// Functions whose name starts with this prefix.
+    Prefix(String),
+}
+
+/// How to instrument a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instrumentation {
+    pub kind: ProbeKind,
+    pub filter: FunctionFilter,
+}
+
+impl Instrumentation {
+    /// Count calls to the functions selected by `filter`.
+    pub fn counters(filter: FunctionFilter) -> Self {
+        Instrumentation {
+            kind: ProbeKind::Counter,
+            filter,
+        }
+    }
+
+    /// Add trace points calling `module`.`name` to the functions selected by `filter`.
+    pub fn trace_points(
+        module: impl Into<String>,
+        name: impl Into<String>,
+        filter: FunctionFilter,
+    ) -> Self {
+        Instrumentation {
+            kind: ProbeKind::TracePoint {
+                module: module.into(),
+                name: name.into(),
+            },
+            filter,
+        }
+    }
+}
+
+/// A probe injected into a function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Probe {
+    /// Identifier of the probe, passed to trace points, and used in the names of counters.
+    pub id: u32,
+    /// Names of the function, from the name section and the exports.
+    pub names: Vec<String>,
+}
+
+impl Module {
+    /// Return a copy of the module with probes injected at the entry of the selected functions,
+    /// and the list of probes.
+    ///
+    /// This requires the `walrus` feature. Custom sections are kept, but moved after the
+    /// standard sections, and signatures have to be created again.
+    pub fn instrument(
+        &self,
+        instrumentation: &Instrumentation,
+    ) -> Result<(Module, Vec<Probe>), WSError> {
+        let mut module = walrus::Module::try_from(self)?;
+        let mut exports: HashMap<FunctionId, Vec<String>> = HashMap::new();
+        for export in module.exports.iter() {
+            if let ExportItem::Function(id) = export.item {
+                exports.entry(id).or_default().push(export.name.clone());
+            }
+        }
+        let mut selected: Vec<(FunctionId, Vec<String>)> = vec![];
+        for function in module.funcs.iter() {
+            if !matches!(function.kind, FunctionKind::Local(_)) {
+                continue;
+            }
+            let exported = exports.get(&function.id());
+            let mut names: Vec<String> = function.name.iter().cloned().collect();
+            for name in exported.into_iter().flatten() {
+                if !names.contains(name) {
+                    names.push(name.clone());
+                }
+            }
+            let matches = match &instrumentation.filter {
+                FunctionFilter::All => true,
+                FunctionFilter::Exported => exported.is_some(),
+                FunctionFilter::Named(wanted) => names.iter().any(|name| wanted.contains(name)),
+                FunctionFilter::Prefix(prefix) => {
+                    names.iter().any(|name| name.starts_with(prefix.as_str()))
+                }
+            };
+            if matches {
+                selected.push((function.id(), names));
+            }
+        }
+        let trace_func = match &instrumentation.kind {
+            ProbeKind::Counter => None,
+            ProbeKind::TracePoint {
+                module: import_module,
+                name,
+            } => {
+                let ty = module.types.add(&[ValType::I32], &[]);
+                Some(module.add_import_func(import_module, name, ty).0)
+            }
+        };
+        let mut probes = vec![];
+        for (id, (func_id, names)) in selected.into_iter().enumerate() {
+            let id = id as u32;
+            let counter = match trace_func {
+                Some(_) => None,
+                None => {
+                    let global = module.globals.add_local(
+                        ValType::I64,
+                        true,
+                        false,
+                        ConstExpr::Value(Value::I64(0)),
+                    );
+                    module
+                        .exports
+                        .add(&format!("{}{}", COUNTER_EXPORT_PREFIX, id), global);
+                    Some(global)
+                }
+            };
+            let function = module.funcs.get_mut(func_id).kind.unwrap_local_mut();
+            let mut body = function.builder_mut().func_body();
+            match (trace_func, counter) {
+                (Some(trace_func), _) => {
+                    body.instr_at(
+                        0,
+                        Const {
+                            value: Value::I32(id as i32),
+                        },
+                    )
+                    .instr_at(1, Call { func: trace_func });
+                }
+                (None, Some(global)) => {
+                    body.instr_at(0, GlobalGet { global })
+                        .instr_at(
+                            1,
+                            Const {
+                                value: Value::I64(1),
+                            },
+                        )
+                        .instr_at(
+                            2,
+                            Binop {
+                                op: BinaryOp::I64Add,
+                            },
+                        )
+                        .instr_at(3, GlobalSet { global });
+                }
+                (None, None) => unreachable!(),
+            }
+            probes.push(Probe { id, names });
+        }
+        Ok((Module::try_from(&mut module)?, probes))
+    }
+}
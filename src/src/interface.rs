@@ -0,0 +1,491 @@
+//! The interface of a module: what it imports from the host, and what it exports.
+//!
+//! Imports and exports are described by their kind and type, in a syntax close to the text
+//! format, such as `func (param i32 i32) (result i32)` or `memory 1 16`, so that interface
+//! changes between two versions can be reviewed without disassembling them.
+
+use crate::diff::ChangeKind;
+use crate::error::*;
+use crate::sanitize::{get_u8, skip_leb, Limits};
+use crate::wasm_module::*;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, prelude::*};
+use std::str;
+
+const TAG_SECTION_ID: u8 = 13;
+
+/// Whether an item is provided by the host or by the module.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum InterfaceDirection {
+    Import,
+    Export,
+}
+
+impl fmt::Display for InterfaceDirection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterfaceDirection::Import => write!(f, "import"),
+            InterfaceDirection::Export => write!(f, "export"),
+        }
+    }
+}
+
+/// An import or an export.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceItem {
+    pub direction: InterfaceDirection,
+    /// `module::name` for imports, the name of the export for exports.
+    pub name: String,
+    /// Kind and type of the item, such as `func (param i32) (result i32)`.
+    pub signature: String,
+}
+
+/// An import or an export, compared between two modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceChange {
+    pub direction: InterfaceDirection,
+    pub name: String,
+    pub kind: ChangeKind,
+    /// Signature of the item in the old module, if present.
+    pub old_signature: Option<String>,
+    /// Signature of the item in the new module, if present.
+    pub new_signature: Option<String>,
+}
+
+impl fmt::Display for InterfaceChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.kind, self.direction, self.name)?;
+        match (&self.old_signature, &self.new_signature) {
+            (Some(old), Some(new)) if old != new => write!(f, ": {} -> {}", old, new),
+            (_, Some(signature)) | (Some(signature), None) => write!(f, ": {}", signature),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// The differences between the imports and exports of two modules.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceDiff {
+    /// Items of the new module that were added or modified, in order, followed by the items
+    /// that were removed.
+    pub changes: Vec<InterfaceChange>,
+}
+
+impl InterfaceDiff {
+    /// Return `true` if the modules have the same imports and exports.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+fn get_s33(reader: &mut impl Read) -> Result<i64, WSError> {
+    let mut value = 0i64;
+    let mut shift = 0;
+    loop {
+        let byte = get_u8(reader)?;
+        if shift >= 35 {
+            return Err(WSError::ParseError);
+        }
+        value |= ((byte & 0x7f) as i64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            if byte & 0x40 != 0 && shift < 64 {
+                value |= -1i64 << shift;
+            }
+            return Ok(value);
+        }
+    }
+}
+
+fn heap_type(reader: &mut impl Read) -> Result<String, WSError> {
+    let heap_type = get_s33(reader)?;
+    if heap_type >= 0 {
+        return Ok(heap_type.to_string());
+    }
+    abstract_heap_type((heap_type & 0x7f) as u8)
+        .map(str::to_string)
+        .ok_or(WSError::ParseError)
+}
+
+fn abstract_heap_type(code: u8) -> Option<&'static str> {
+    Some(match code {
+        0x70 => "func",
+        0x6f => "extern",
+        0x6e => "any",
+        0x6d => "eq",
+        0x6c => "i31",
+        0x6b => "struct",
+        0x6a => "array",
+        0x69 => "exn",
+        0x71 => "none",
+        0x72 => "noextern",
+        0x73 => "nofunc",
+        0x74 => "noexn",
+        _ => return None,
+    })
+}
+
+fn val_type(reader: &mut impl Read) -> Result<String, WSError> {
+    let code = get_u8(reader)?;
+    val_type_with_code(code, reader)
+}
+
+fn val_type_with_code(code: u8, reader: &mut impl Read) -> Result<String, WSError> {
+    Ok(match code {
+        0x7f => "i32".to_string(),
+        0x7e => "i64".to_string(),
+        0x7d => "f32".to_string(),
+        0x7c => "f64".to_string(),
+        0x7b => "v128".to_string(),
+        0x63 => format!("(ref null {})", heap_type(reader)?),
+        0x64 => format!("(ref {})", heap_type(reader)?),
+        _ => match abstract_heap_type(code) {
+            Some(heap_type) => format!("{}ref", heap_type),
+            None => return Err(WSError::ParseError),
+        },
+    })
+}
+
+fn val_types(reader: &mut impl Read) -> Result<Vec<String>, WSError> {
+    (0..varint::get32(reader)?)
+        .map(|_| val_type(reader))
+        .collect()
+}
+
+fn global_type(reader: &mut impl Read) -> Result<String, WSError> {
+    let val_type = val_type(reader)?;
+    Ok(match get_u8(reader)? {
+        0x00 => format!("global {}", val_type),
+        0x01 => format!("global (mut {})", val_type),
+        _ => return Err(WSError::ParseError),
+    })
+}
+
+fn table_type(reader: &mut impl Read) -> Result<String, WSError> {
+    let ref_type = val_type(reader)?;
+    Ok(format!("table {} {}", limits(reader)?, ref_type))
+}
+
+fn memory_type(reader: &mut impl Read) -> Result<String, WSError> {
+    Ok(format!("memory {}", limits(reader)?))
+}
+
+fn limits(reader: &mut impl Read) -> Result<String, WSError> {
+    let limits = Limits::deserialize(reader)?;
+    let mut description = String::new();
+    if limits.is_64() {
+        description.push_str("i64 ");
+    }
+    description.push_str(&limits.min.to_string());
+    if let Some(max) = limits.max {
+        description.push_str(&format!(" {}", max));
+    }
+    if limits.is_shared() {
+        description.push_str(" shared");
+    }
+    Ok(description)
+}
+
+/// Skip a field of a structure or an array.
+fn skip_field_type(reader: &mut impl Read) -> Result<(), WSError> {
+    match get_u8(reader)? {
+        // Packed i8 and i16
+        0x78 | 0x77 => {}
+        code => {
+            val_type_with_code(code, reader)?;
+        }
+    }
+    get_u8(reader)?;
+    Ok(())
+}
+
+/// Skip a composite type, returning the parameters and results if it is a function type.
+fn composite_type(code: u8, reader: &mut impl Read) -> Result<Option<String>, WSError> {
+    match code {
+        0x60 => {
+            let params = val_types(reader)?;
+            let results = val_types(reader)?;
+            let mut description = String::new();
+            if !params.is_empty() {
+                description.push_str(&format!(" (param {})", params.join(" ")));
+            }
+            if !results.is_empty() {
+                description.push_str(&format!(" (result {})", results.join(" ")));
+            }
+            Ok(Some(description))
+        }
+        0x5f => {
+            for _ in 0..varint::get32(reader)? {
+                skip_field_type(reader)?;
+            }
+            Ok(None)
+        }
+        0x5e => {
+            skip_field_type(reader)?;
+            Ok(None)
+        }
+        _ => Err(WSError::ParseError),
+    }
+}
+
+fn sub_type(code: u8, reader: &mut impl Read) -> Result<Option<String>, WSError> {
+    match code {
+        0x50 | 0x4f => {
+            for _ in 0..varint::get32(reader)? {
+                skip_leb(reader)?;
+            }
+            let code = get_u8(reader)?;
+            composite_type(code, reader)
+        }
+        _ => composite_type(code, reader),
+    }
+}
+
+/// Return the parameters and results of every type that is a function type.
+fn func_types(payload: &[u8]) -> Result<Vec<Option<String>>, WSError> {
+    let mut reader = io::Cursor::new(payload);
+    let mut types = vec![];
+    for _ in 0..varint::get32(&mut reader)? {
+        match get_u8(&mut reader)? {
+            // Recursive group
+            0x4e => {
+                for _ in 0..varint::get32(&mut reader)? {
+                    let code = get_u8(&mut reader)?;
+                    types.push(sub_type(code, &mut reader)?);
+                }
+            }
+            code => types.push(sub_type(code, &mut reader)?),
+        }
+    }
+    Ok(types)
+}
+
+/// Skip a constant expression. Return `false` if it uses an instruction that isn't known to
+/// be constant.
+fn skip_const_expr(reader: &mut impl Read) -> Result<bool, WSError> {
+    loop {
+        match get_u8(reader)? {
+            0x0b => return Ok(true),
+            // i32.const, i64.const, global.get, ref.func
+            0x41 | 0x42 | 0x23 | 0xd2 => skip_leb(reader)?,
+            // f32.const
+            0x43 => reader.read_exact(&mut [0u8; 4])?,
+            // f64.const
+            0x44 => reader.read_exact(&mut [0u8; 8])?,
+            // ref.null
+            0xd0 => {
+                get_s33(reader)?;
+            }
+            // Extended constant arithmetic
+            0x6a | 0x6b | 0x6c | 0x7c | 0x7d | 0x7e => {}
+            _ => return Ok(false),
+        }
+    }
+}
+
+/// What the index spaces of a module refer to.
+#[derive(Default)]
+struct IndexSpaces {
+    types: Vec<Option<String>>,
+    funcs: Vec<u32>,
+    tables: Vec<String>,
+    memories: Vec<String>,
+    globals: Vec<String>,
+    tags: Vec<u32>,
+}
+
+impl IndexSpaces {
+    fn func(&self, type_index: u32) -> String {
+        match self.types.get(type_index as usize) {
+            Some(Some(description)) => format!("func{}", description),
+            _ => format!("func (type {})", type_index),
+        }
+    }
+
+    fn tag(&self, type_index: u32) -> String {
+        match self.types.get(type_index as usize) {
+            Some(Some(description)) => format!("tag{}", description),
+            _ => format!("tag (type {})", type_index),
+        }
+    }
+
+    fn describe(&self, kind: u8, index: u32) -> Result<String, WSError> {
+        let index = index as usize;
+        let description = match kind {
+            0x00 => self
+                .funcs
+                .get(index)
+                .map(|&type_index| self.func(type_index)),
+            0x01 => self.tables.get(index).cloned(),
+            0x02 => self.memories.get(index).cloned(),
+            0x03 => Some(
+                self.globals
+                    .get(index)
+                    .cloned()
+                    .unwrap_or_else(|| "global".to_string()),
+            ),
+            0x04 => self.tags.get(index).map(|&type_index| self.tag(type_index)),
+            _ => return Err(WSError::ParseError),
+        };
+        description.ok_or(WSError::ParseError)
+    }
+}
+
+impl Module {
+    /// Return the imports of the module, followed by its exports.
+    pub fn interface(&self) -> Result<Vec<InterfaceItem>, WSError> {
+        if self.is_component() {
+            return Err(WSError::UnsupportedModuleType);
+        }
+        let mut spaces = IndexSpaces::default();
+        let mut items = vec![];
+        for section in &self.sections {
+            let section = match section {
+                Section::Standard(section) => section,
+                _ => continue,
+            };
+            let mut reader = io::Cursor::new(section.payload());
+            match section.id() {
+                SectionId::Type => spaces.types = func_types(section.payload())?,
+                SectionId::Import => {
+                    for _ in 0..varint::get32(&mut reader)? {
+                        let module = str::from_utf8(&varint::get_slice(&mut reader)?)?.to_string();
+                        let name = str::from_utf8(&varint::get_slice(&mut reader)?)?.to_string();
+                        let signature = match get_u8(&mut reader)? {
+                            0x00 => {
+                                let type_index = varint::get32(&mut reader)?;
+                                spaces.funcs.push(type_index);
+                                spaces.func(type_index)
+                            }
+                            0x01 => {
+                                let table = table_type(&mut reader)?;
+                                spaces.tables.push(table.clone());
+                                table
+                            }
+                            0x02 => {
+                                let memory = memory_type(&mut reader)?;
+                                spaces.memories.push(memory.clone());
+                                memory
+                            }
+                            0x03 => {
+                                let global = global_type(&mut reader)?;
+                                spaces.globals.push(global.clone());
+                                global
+                            }
+                            0x04 => {
+                                get_u8(&mut reader)?;
+                                let type_index = varint::get32(&mut reader)?;
+                                spaces.tags.push(type_index);
+                                spaces.tag(type_index)
+                            }
+                            _ => return Err(WSError::ParseError),
+                        };
+                        items.push(InterfaceItem {
+                            direction: InterfaceDirection::Import,
+                            name: format!("{}::{}", module, name),
+                            signature,
+                        });
+                    }
+                }
+                SectionId::Function => {
+                    for _ in 0..varint::get32(&mut reader)? {
+                        spaces.funcs.push(varint::get32(&mut reader)?);
+                    }
+                }
+                SectionId::Table => {
+                    for _ in 0..varint::get32(&mut reader)? {
+                        if section.payload().get(reader.position() as usize) == Some(&0x40) {
+                            // Table with an initializer
+                            reader.read_exact(&mut [0u8; 2])?;
+                            spaces.tables.push(table_type(&mut reader)?);
+                            skip_const_expr(&mut reader)?;
+                        } else {
+                            spaces.tables.push(table_type(&mut reader)?);
+                        }
+                    }
+                }
+                SectionId::Memory => {
+                    for _ in 0..varint::get32(&mut reader)? {
+                        spaces.memories.push(memory_type(&mut reader)?);
+                    }
+                }
+                SectionId::Global => {
+                    // Globals after an unknown initializer are described without their type.
+                    for _ in 0..varint::get32(&mut reader)? {
+                        spaces.globals.push(global_type(&mut reader)?);
+                        if !skip_const_expr(&mut reader)? {
+                            break;
+                        }
+                    }
+                }
+                SectionId::Extension(TAG_SECTION_ID) => {
+                    for _ in 0..varint::get32(&mut reader)? {
+                        get_u8(&mut reader)?;
+                        spaces.tags.push(varint::get32(&mut reader)?);
+                    }
+                }
+                SectionId::Export => {
+                    for _ in 0..varint::get32(&mut reader)? {
+                        let name = str::from_utf8(&varint::get_slice(&mut reader)?)?.to_string();
+                        let kind = get_u8(&mut reader)?;
+                        let index = varint::get32(&mut reader)?;
+                        items.push(InterfaceItem {
+                            direction: InterfaceDirection::Export,
+                            name,
+                            signature: spaces.describe(kind, index)?,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(items)
+    }
+
+    /// Compare the imports and exports of this module with the ones of a newer version.
+    pub fn diff_interface(&self, new: &Module) -> Result<InterfaceDiff, WSError> {
+        let old = self.interface()?;
+        let mut old_items: HashMap<(InterfaceDirection, String), &str> = old
+            .iter()
+            .map(|item| ((item.direction, item.name.clone()), item.signature.as_str()))
+            .collect();
+        let mut diff = InterfaceDiff::default();
+        for item in new.interface()? {
+            let change = match old_items.remove(&(item.direction, item.name.clone())) {
+                Some(old_signature) if old_signature == item.signature => continue,
+                Some(old_signature) => InterfaceChange {
+                    direction: item.direction,
+                    name: item.name,
+                    kind: ChangeKind::Modified,
+                    old_signature: Some(old_signature.to_string()),
+                    new_signature: Some(item.signature),
+                },
+                None => InterfaceChange {
+                    direction: item.direction,
+                    name: item.name,
+                    kind: ChangeKind::Added,
+                    old_signature: None,
+                    new_signature: Some(item.signature),
+                },
+            };
+            diff.changes.push(change);
+        }
+        for item in &old {
+            if old_items
+                .remove(&(item.direction, item.name.clone()))
+                .is_some()
+            {
+                diff.changes.push(InterfaceChange {
+                    direction: item.direction,
+                    name: item.name.clone(),
+                    kind: ChangeKind::Removed,
+                    old_signature: Some(item.signature.clone()),
+                    new_signature: None,
+                });
+            }
+        }
+        Ok(diff)
+    }
+}
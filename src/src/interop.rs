@@ -0,0 +1,130 @@
+//! Adapters between this crate's types and the `wasmparser` and `wasm-encoder` crates.
+
+use crate::error::*;
+use crate::wasm_module::*;
+
+use std::borrow::Cow;
+use std::io;
+
+impl wasm_encoder::Encode for StandardSection {
+    fn encode(&self, sink: &mut Vec<u8>) {
+        self.payload().encode(sink);
+    }
+}
+
+impl wasm_encoder::Encode for CustomSection {
+    fn encode(&self, sink: &mut Vec<u8>) {
+        wasm_encoder::CustomSection::from(self).encode(sink);
+    }
+}
+
+impl wasm_encoder::Encode for Section {
+    fn encode(&self, sink: &mut Vec<u8>) {
+        match self {
+            Section::Standard(s) => s.encode(sink),
+            Section::Custom(s) => s.encode(sink),
+        }
+    }
+}
+
+impl wasm_encoder::Section for Section {
+    fn id(&self) -> u8 {
+        SectionLike::id(self).into()
+    }
+}
+
+impl wasm_encoder::ComponentSection for Section {
+    fn id(&self) -> u8 {
+        SectionLike::id(self).into()
+    }
+}
+
+impl<'a> From<&'a StandardSection> for wasm_encoder::RawSection<'a> {
+    fn from(section: &'a StandardSection) -> Self {
+        wasm_encoder::RawSection {
+            id: section.id().into(),
+            data: section.payload(),
+        }
+    }
+}
+
+impl<'a> From<&'a CustomSection> for wasm_encoder::CustomSection<'a> {
+    fn from(section: &'a CustomSection) -> Self {
+        wasm_encoder::CustomSection {
+            name: Cow::Borrowed(section.name()),
+            data: Cow::Borrowed(section.payload()),
+        }
+    }
+}
+
+impl From<wasm_encoder::CustomSection<'_>> for CustomSection {
+    fn from(section: wasm_encoder::CustomSection<'_>) -> Self {
+        CustomSection::new(section.name.into_owned(), section.data.into_owned())
+    }
+}
+
+impl From<wasmparser::CustomSectionReader<'_>> for CustomSection {
+    fn from(reader: wasmparser::CustomSectionReader<'_>) -> Self {
+        CustomSection::new(reader.name().to_string(), reader.data().to_vec())
+    }
+}
+
+impl TryFrom<&Module> for wasm_encoder::Module {
+    type Error = WSError;
+
+    /// Convert a core module. Components are not supported.
+    fn try_from(module: &Module) -> Result<Self, WSError> {
+        if module.is_component() {
+            return Err(WSError::UnsupportedModuleType);
+        }
+        let mut encoded = wasm_encoder::Module::new();
+        for section in &module.sections {
+            encoded.section(section);
+        }
+        Ok(encoded)
+    }
+}
+
+impl TryFrom<&wasm_encoder::Module> for Module {
+    type Error = WSError;
+
+    fn try_from(module: &wasm_encoder::Module) -> Result<Self, WSError> {
+        Module::deserialize(&mut io::Cursor::new(module.as_slice()))
+    }
+}
+
+impl Section {
+    /// Create a section from a `wasmparser` payload, and the data it was parsed from.
+    ///
+    /// Returns `None` for payloads that don't represent a whole section, such as function bodies.
+    pub fn from_payload(
+        data: &[u8],
+        payload: &wasmparser::Payload<'_>,
+    ) -> Result<Option<Self>, WSError> {
+        let (id, range) = match payload.as_section() {
+            None => return Ok(None),
+            Some(section) => section,
+        };
+        let payload = data.get(range).ok_or(WSError::Eof)?;
+        Ok(Some(Section::new(id.into(), payload.to_vec())?))
+    }
+}
+
+impl Module {
+    /// Fully validate the module or component with `wasmparser`.
+    pub fn validate(&self) -> Result<wasmparser::types::Types, WSError> {
+        self.validate_with_features(wasmparser::WasmFeatures::default())
+    }
+
+    /// Fully validate the module or component with `wasmparser`, using the given set of features.
+    pub fn validate_with_features(
+        &self,
+        features: wasmparser::WasmFeatures,
+    ) -> Result<wasmparser::types::Types, WSError> {
+        let mut bin = vec![];
+        self.serialize(&mut bin)?;
+        wasmparser::Validator::new_with_features(features)
+            .validate_all(&bin)
+            .map_err(|e| WSError::ValidationError(e.to_string()))
+    }
+}
@@ -0,0 +1,73 @@
+pub mod advisories;
+pub mod agent;
+pub mod align;
+pub mod batch;
+pub mod bundle;
+pub mod cbor;
+pub mod codec;
+#[cfg(feature = "codegen")]
+pub mod codegen;
+pub mod compat;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod config;
+pub mod config_schema;
+pub mod coordinator;
+pub mod customize;
+pub mod dependents;
+pub mod dev;
+pub mod diff;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+pub mod error;
+pub mod flags;
+pub mod growth;
+pub mod health;
+pub mod hooks;
+mod instrument;
+#[cfg(feature = "walrus")]
+pub mod instrumentation;
+pub mod interface;
+#[cfg(feature = "wasm-tools")]
+pub mod interop;
+pub mod licenses;
+pub mod memory;
+pub mod merge;
+pub mod metrics;
+pub mod migrations;
+pub mod notices;
+#[cfg(feature = "oci")]
+pub mod oci;
+pub mod protocol;
+pub mod provenance;
+pub mod query;
+#[cfg(feature = "registry")]
+pub mod registry;
+pub mod release_notes;
+#[cfg(feature = "wasm-tools")]
+pub mod relocations;
+pub mod repo;
+pub mod report;
+#[cfg(feature = "wasm-tools")]
+pub mod reproducibility;
+pub mod revocation;
+pub mod rules;
+pub mod sanitize;
+pub mod schema;
+pub mod security_version;
+pub mod server;
+pub mod signature;
+pub mod state;
+pub mod suit;
+pub mod template;
+pub mod thin;
+pub mod timestamps;
+#[cfg(feature = "walrus")]
+pub mod walrus_bridge;
+pub mod wasm_module;
+#[cfg(feature = "wasmtime")]
+pub mod wasmtime_loader;
+pub mod watermark;
+
+pub use error::{ExitStatus, WSError};
+pub use wasm_module::*;
@@ -0,0 +1,160 @@
+//! License rules over the notices and the bill of materials of a module.
+//!
+//! Licenses are SPDX identifiers or expressions. An expression is denied if every way of
+//! satisfying it requires a denied license: `MIT OR GPL-3.0` is accepted when only `GPL-3.0` is
+//! denied, but `MIT AND GPL-3.0` isn't.
+
+use crate::error::*;
+use crate::wasm_module::*;
+
+use std::collections::BTreeSet;
+
+/// Rules applied to the licenses declared by a module.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LicensePolicy {
+    /// SPDX identifiers of the licenses that are not allowed, compared case-insensitively.
+    pub denied_licenses: BTreeSet<String>,
+    /// Reject modules without a `notices` section, or with an empty one.
+    pub require_notices: bool,
+    /// Reject notices and components that don't declare a license.
+    pub require_licenses: bool,
+}
+
+impl LicensePolicy {
+    /// Create a policy accepting every module.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Deny a license.
+    pub fn deny_license(mut self, id: impl Into<String>) -> Self {
+        self.denied_licenses.insert(id.into());
+        self
+    }
+
+    /// Reject modules without notices.
+    pub fn require_notices(mut self, require: bool) -> Self {
+        self.require_notices = require;
+        self
+    }
+
+    /// Reject notices and components that don't declare a license.
+    pub fn require_licenses(mut self, require: bool) -> Self {
+        self.require_licenses = require;
+        self
+    }
+
+    fn is_denied(&self, id: &str) -> bool {
+        let id = id.strip_suffix('+').unwrap_or(id);
+        self.denied_licenses
+            .iter()
+            .any(|denied| denied.eq_ignore_ascii_case(id))
+    }
+
+    /// Return `Some(true)` if an SPDX expression can be satisfied without a denied license,
+    /// or `None` if it can't be parsed.
+    fn accepts(&self, expression: &str) -> Option<bool> {
+        let spaced = expression.replace('(', " ( ").replace(')', " ) ");
+        let tokens: Vec<&str> = spaced.split_whitespace().collect();
+        let mut pos = 0;
+        let accepted = self.accepts_or(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return None;
+        }
+        Some(accepted)
+    }
+
+    fn accepts_or(&self, tokens: &[&str], pos: &mut usize) -> Option<bool> {
+        let mut accepted = self.accepts_and(tokens, pos)?;
+        while tokens.get(*pos) == Some(&"OR") {
+            *pos += 1;
+            accepted |= self.accepts_and(tokens, pos)?;
+        }
+        Some(accepted)
+    }
+
+    fn accepts_and(&self, tokens: &[&str], pos: &mut usize) -> Option<bool> {
+        let mut accepted = self.accepts_term(tokens, pos)?;
+        while tokens.get(*pos) == Some(&"AND") {
+            *pos += 1;
+            accepted &= self.accepts_term(tokens, pos)?;
+        }
+        Some(accepted)
+    }
+
+    fn accepts_term(&self, tokens: &[&str], pos: &mut usize) -> Option<bool> {
+        let token = *tokens.get(*pos)?;
+        *pos += 1;
+        match token {
+            "(" => {
+                let accepted = self.accepts_or(tokens, pos)?;
+                if tokens.get(*pos) != Some(&")") {
+                    return None;
+                }
+                *pos += 1;
+                Some(accepted)
+            }
+            ")" | "AND" | "OR" | "WITH" => None,
+            id => {
+                if tokens.get(*pos) == Some(&"WITH") {
+                    tokens.get(*pos + 1)?;
+                    *pos += 2;
+                }
+                Some(!self.is_denied(id))
+            }
+        }
+    }
+
+    fn check_license(&self, what: &str, license: &str, violations: &mut Vec<String>) {
+        if license.trim().is_empty() {
+            if self.require_licenses {
+                violations.push(format!("{} has no license", what));
+            }
+            return;
+        }
+        match self.accepts(license) {
+            Some(true) => {}
+            Some(false) => violations.push(format!("{} is licensed under {}", what, license)),
+            None => violations.push(format!(
+                "{} has an invalid license expression: {}",
+                what, license
+            )),
+        }
+    }
+
+    /// Return a description of each rule the module breaks.
+    pub fn violations(&self, module: &Module) -> Result<Vec<String>, WSError> {
+        let mut violations = vec![];
+        let notices = module.notices()?.unwrap_or_default();
+        if self.require_notices && notices.entries.is_empty() {
+            violations.push("the module has no notices".to_string());
+        }
+        for notice in &notices.entries {
+            let what = format!("notice for {}", notice.package);
+            self.check_license(&what, &notice.license, &mut violations);
+        }
+        for component in module.sbom_components()?.unwrap_or_default() {
+            let what = format!("component {}", component.name);
+            if component.licenses.is_empty() {
+                self.check_license(&what, "", &mut violations);
+            }
+            for license in &component.licenses {
+                self.check_license(&what, license, &mut violations);
+            }
+        }
+        Ok(violations)
+    }
+}
+
+impl Module {
+    /// Check the licenses declared by the module against a policy.
+    ///
+    /// If the module breaks any rule, an error listing every violation is returned.
+    pub fn check_licenses(&self, policy: &LicensePolicy) -> Result<(), WSError> {
+        let violations = policy.violations(self)?;
+        if !violations.is_empty() {
+            return Err(WSError::PolicyViolation(violations.join("; ")));
+        }
+        Ok(())
+    }
+}
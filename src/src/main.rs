@@ -1,15 +1,159 @@
-mod error;
-mod wasm_module;
-
 pub use anyhow::{anyhow, bail, ensure, Error};
-use clap::Arg;
+use clap::{Arg, ArgAction, ArgMatches};
+use ct_codecs::{Decoder, Encoder, Hex};
 use std::{
     fs::File,
     io::{Cursor, Write},
+    path::Path,
+    time::Duration,
 };
-use wasm_module::*;
+use wasi_update::template::*;
+use wasi_update::*;
 use wit_component::*;
 
+/// An error carrying the exit status of a command, for failures that don't come from a `WSError`.
+#[derive(Debug)]
+struct CommandError {
+    status: ExitStatus,
+    message: String,
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Return an error making the command exit with the given status.
+fn failure(status: ExitStatus, message: impl Into<String>) -> Error {
+    CommandError {
+        status,
+        message: message.into(),
+    }
+    .into()
+}
+
+/// Return the exit status for an error, from its first cause with a known status.
+fn exit_status(e: &Error) -> ExitStatus {
+    e.chain()
+        .find_map(|e| {
+            if let Some(e) = e.downcast_ref::<CommandError>() {
+                Some(e.status)
+            } else if let Some(e) = e.downcast_ref::<WSError>() {
+                Some(e.exit_status())
+            } else {
+                e.downcast_ref::<std::io::Error>().map(ExitStatus::from)
+            }
+        })
+        .unwrap_or(ExitStatus::Failure)
+}
+
+/// Where commands report their results.
+///
+/// In text mode, lines are printed as they are produced, and fields are ignored. In JSON mode,
+/// lines are ignored, and fields are collected into the `result` object printed by `finish()`,
+/// so that the standard output holds a single JSON object.
+struct Output {
+    json: bool,
+    command: String,
+    result: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Output {
+    fn new(matches: &ArgMatches) -> Self {
+        let mut names = vec![];
+        let mut matches = matches;
+        while let Some((name, sub_matches)) = matches.subcommand() {
+            names.push(name);
+            matches = sub_matches;
+        }
+        let command = match names.is_empty() {
+            true => "convert".to_string(),
+            false => names.join(" "),
+        };
+        Output {
+            json: matches
+                .get_one::<String>("output_format")
+                .is_some_and(|format| format == "json"),
+            command,
+            result: serde_json::Map::new(),
+        }
+    }
+
+    /// Print a line, in text mode.
+    fn line(&self, line: std::fmt::Arguments) {
+        if !self.json {
+            println!("{}", line);
+        }
+    }
+
+    /// Set a field of the result.
+    fn set(&mut self, key: &str, value: impl serde::Serialize) {
+        if self.json {
+            let value = serde_json::to_value(value).unwrap_or_default();
+            self.result.insert(key.to_string(), value);
+        }
+    }
+
+    /// Append a value to a list of the result.
+    fn push(&mut self, key: &str, value: impl serde::Serialize) {
+        if self.json {
+            let value = serde_json::to_value(value).unwrap_or_default();
+            let list = self
+                .result
+                .entry(key)
+                .or_insert_with(|| serde_json::Value::Array(vec![]));
+            if let serde_json::Value::Array(list) = list {
+                list.push(value);
+            }
+        }
+    }
+
+    fn object(&mut self, status: ExitStatus, error: Option<&Error>) -> serde_json::Value {
+        let mut object = serde_json::json!({
+            "command": self.command,
+            "status": status.name(),
+            "exit_code": status.code(),
+            "result": std::mem::take(&mut self.result),
+        });
+        if let Some(e) = error {
+            let ws_error = e.chain().find_map(|e| e.downcast_ref::<WSError>());
+            object["error"] = serde_json::json!({
+                "message": format!("{:#}", e),
+                "code": ws_error.map(|e| e.code()),
+                "name": ws_error.map(|e| e.explanation().name),
+            });
+        }
+        object
+    }
+
+    /// Print the result collected so far as an event of a command that keeps running, with a
+    /// `running` status, and start a new result.
+    fn emit(&mut self) {
+        if self.json {
+            let mut object = self.object(ExitStatus::Success, None);
+            object["status"] = "running".into();
+            println!("{}", object);
+        }
+    }
+
+    /// Print the result of the command.
+    fn finish(mut self, status: ExitStatus, error: Option<&Error>) {
+        if self.json {
+            println!("{}", self.object(status, error));
+        }
+    }
+}
+
+/// Print a line of the result of a command, in text mode.
+macro_rules! outln {
+    ($out:expr, $($arg:tt)*) => {
+        $out.line(format_args!($($arg)*))
+    };
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 enum ModuleType {
     Freestanding,
@@ -27,7 +171,7 @@ impl std::fmt::Display for ModuleType {
     }
 }
 
-fn guess_module_type(module: &Module) -> Result<ModuleType, Error> {
+fn guess_module_type(module: &Module, out: &mut Output) -> Result<ModuleType, Error> {
     let mut has_start = false;
     let mut has_wasi_core_import = false;
     for section in &module.sections {
@@ -46,7 +190,8 @@ fn guess_module_type(module: &Module) -> Result<ModuleType, Error> {
                     let kind = varint::get7(&mut reader)?;
                     let _id = varint::get32(&mut reader)?;
                     if kind == 0 {
-                        println!("Exported:\t{}", name);
+                        outln!(out, "Exported:\t{}", name);
+                        out.push("exports", name);
                         if name == "_start" {
                             has_start = true;
                         }
@@ -65,7 +210,8 @@ fn guess_module_type(module: &Module) -> Result<ModuleType, Error> {
                     let kind = varint::get7(&mut reader)?;
                     let _id = varint::get32(&mut reader)?;
                     if kind == 0 {
-                        println!("Imported:\t{}#{}", module_name, name);
+                        outln!(out, "Imported:\t{}#{}", module_name, name);
+                        out.push("imports", format!("{}#{}", module_name, name));
                         if module_name == "wasi_snapshot_preview1" {
                             has_wasi_core_import = true;
                         }
@@ -84,8 +230,51 @@ fn guess_module_type(module: &Module) -> Result<ModuleType, Error> {
     Ok(res)
 }
 
-fn main() -> Result<(), Error> {
-    let matches = clap::command!()
+fn main() {
+    let matches = cli().get_matches();
+    let mut out = Output::new(&matches);
+    let res = run(&matches, &mut out);
+    let status = match &res {
+        Ok(()) => ExitStatus::Success,
+        Err(e) => exit_status(e),
+    };
+    if let Err(e) = &res {
+        eprintln!("Error: {:?}", e);
+        if let Some(e) = e.downcast_ref::<WSError>() {
+            eprintln!(
+                "\nError code: {} (run `wasi-update explain {}` for details)",
+                e.code(),
+                e.code()
+            );
+        }
+    }
+    out.finish(status, res.as_ref().err());
+    std::process::exit(status.code());
+}
+
+fn cli() -> clap::Command {
+    clap::command!()
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .num_args(1)
+                .value_name("FILE")
+                .global(true)
+                .help("Configuration file (default: $WASI_UPDATE_CONFIG)"),
+        )
+        .arg(
+            Arg::new("output_format")
+                .long("output-format")
+                .num_args(1)
+                .value_name("FORMAT")
+                .value_parser(["text", "json"])
+                .default_value("text")
+                .global(true)
+                .help("Print results as text, or as a single JSON object"),
+        )
         .arg(
             Arg::new("input")
                 .short('i')
@@ -104,21 +293,3663 @@ fn main() -> Result<(), Error> {
                 .help("Output file (component)")
                 .required(true),
         )
-        .get_matches();
+        .subcommand(
+            clap::Command::new("stamp")
+                .about("Replace ${NAME} placeholders in custom sections")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Input file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Output file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("section")
+                        .short('s')
+                        .long("section")
+                        .num_args(1)
+                        .value_name("NAME")
+                        .action(ArgAction::Append)
+                        .help("Custom section to process (can be repeated)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("var")
+                        .short('D')
+                        .long("var")
+                        .num_args(1)
+                        .value_name("NAME=VALUE")
+                        .action(ArgAction::Append)
+                        .help("Define a variable (can be repeated)"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("normalize")
+                .about("Replace timestamps in custom sections, for reproducible builds")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Input file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Output file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("section")
+                        .short('s')
+                        .long("section")
+                        .num_args(1)
+                        .value_name("NAME")
+                        .action(ArgAction::Append)
+                        .help("Custom section to process (can be repeated, default: producers, build-info)"),
+                )
+                .arg(
+                    Arg::new("sort_custom_sections")
+                        .long("sort-custom-sections")
+                        .action(ArgAction::SetTrue)
+                        .help("Also sort custom sections by name, between the same standard sections"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("set-flags")
+                .about("Override the defaults of the feature flags of a module")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Input file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Output file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("flag")
+                        .short('f')
+                        .long("flag")
+                        .num_args(1)
+                        .value_name("NAME=on|off")
+                        .action(ArgAction::Append)
+                        .help("Set the default of a flag (can be repeated)")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("set-security-version")
+                .about("Set the security version devices enforce, or authorize a downgrade")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Input file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Output file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("security_version")
+                        .short('v')
+                        .long("security-version")
+                        .num_args(1)
+                        .value_name("NUMBER")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Security version (default: keep the current one)"),
+                )
+                .arg(
+                    Arg::new("authorize")
+                        .short('a')
+                        .long("authorize")
+                        .num_args(1)
+                        .value_name("KEY")
+                        .help("Downgrade key to allow installing the module over a higher security version with"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("quick-check")
+                .about("Check the structure of a module file, without parsing it")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Input file")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("inspect")
+                .about("Print the metadata of a module")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Input file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("get")
+                        .short('g')
+                        .long("get")
+                        .num_args(1)
+                        .value_name("QUERY")
+                        .help("Only print a single value, such as custom['producers'].sdk"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("audit")
+                .about("Check the dependencies of a module against advisories and license rules")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Input file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("advisories")
+                        .short('a')
+                        .long("advisories")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("JSON advisory database"),
+                )
+                .arg(
+                    Arg::new("deny_license")
+                        .long("deny-license")
+                        .num_args(1)
+                        .value_name("SPDX_ID")
+                        .action(ArgAction::Append)
+                        .help("Reject dependencies under this license (can be repeated)"),
+                )
+                .arg(
+                    Arg::new("require_notices")
+                        .long("require-notices")
+                        .action(ArgAction::SetTrue)
+                        .help("Reject modules without license notices"),
+                )
+                .arg(
+                    Arg::new("require_licenses")
+                        .long("require-licenses")
+                        .action(ArgAction::SetTrue)
+                        .help("Reject notices and components without a license"),
+                )
+                .args(rule_plugin_args()),
+        )
+        .subcommand(
+            clap::Command::new("check-compat")
+                .about("Check that a module can run on devices matching a profile")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Input file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("profile")
+                        .short('p')
+                        .long("profile")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("TOML device profile")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("check-dependents")
+                .about("Check that modules importing from a module would still link with it")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Input file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("module_name")
+                        .short('m')
+                        .long("module-name")
+                        .num_args(1)
+                        .value_name("NAME")
+                        .help("Module name dependents import from")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("dependent")
+                        .short('d')
+                        .long("dependent")
+                        .num_args(1..)
+                        .action(ArgAction::Append)
+                        .value_name("FILE")
+                        .help("Dependent module")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("diff")
+                .about("Compare two versions of a module")
+                .arg(
+                    Arg::new("old")
+                        .long("old")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Previous version")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("new")
+                        .long("new")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("New version")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("stat")
+                        .long("stat")
+                        .action(ArgAction::SetTrue)
+                        .help("Only print the sizes of the sections that changed"),
+                )
+                .arg(
+                    Arg::new("interface")
+                        .long("interface")
+                        .action(ArgAction::SetTrue)
+                        .help("Only print the imports and exports that changed"),
+                )
+                .arg(
+                    Arg::new("full")
+                        .long("full")
+                        .action(ArgAction::SetTrue)
+                        .help("Print every section, and the interface changes (default)"),
+                )
+                .group(clap::ArgGroup::new("mode").args(["stat", "interface", "full"])),
+        )
+        .subcommand(
+            clap::Command::new("provenance-diff")
+                .about("Compare the toolchains and dependencies declared by two versions of a module")
+                .arg(
+                    Arg::new("old")
+                        .long("old")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Previous version")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("new")
+                        .long("new")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("New version")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("release-notes")
+                .about("Derive release notes from the differences between two versions of a module")
+                .arg(
+                    Arg::new("old")
+                        .long("old")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Previous version")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("new")
+                        .long("new")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("New version")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .num_args(1)
+                        .value_name("FORMAT")
+                        .value_parser(["markdown", "json"])
+                        .default_value("markdown")
+                        .help("Format of the release notes"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("size-history")
+                .about("Report the size of each section across versions of a module")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .action(ArgAction::Append)
+                        .help("Version of the module, from the oldest to the newest (can be repeated)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .num_args(1)
+                        .value_name("FORMAT")
+                        .value_parser(["csv", "json"])
+                        .default_value("csv")
+                        .help("Output format"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("dev")
+                .about("Install a module on a simulated device every time it changes")
+                .arg(
+                    Arg::new("watch")
+                        .short('w')
+                        .long("watch")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Module to watch")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("slot")
+                        .short('s')
+                        .long("slot")
+                        .num_args(1)
+                        .value_name("DIR")
+                        .help("Storage directory of the simulated device (default: agent.slots)"),
+                )
+                .arg(
+                    Arg::new("repo")
+                        .short('r')
+                        .long("repo")
+                        .num_args(1)
+                        .value_name("DIR")
+                        .help("Repository directory (default: repository.path, or <slot>/repo)"),
+                )
+                .arg(
+                    Arg::new("key")
+                        .short('k')
+                        .long("key")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Secret key to sign modules with (default: keys.signing, or an ephemeral key)"),
+                )
+                .arg(
+                    Arg::new("interval")
+                        .long("interval")
+                        .num_args(1)
+                        .value_name("MS")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("500")
+                        .help("Time between two checks of the watched file, in milliseconds"),
+                ),
+        )
+        .subcommand(agent_command())
+        .subcommand(
+            clap::Command::new("apply")
+                .about("Verify and install a module into the slots of the device")
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .num_args(1)
+                        .value_name("FILE|URL")
+                        .help("Module to install")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("slot_dir")
+                        .short('s')
+                        .long("slot-dir")
+                        .num_args(1)
+                        .value_name("DIR")
+                        .help("Storage directory of the slots (default: agent.slots)"),
+                )
+                .arg(
+                    Arg::new("version")
+                        .short('v')
+                        .long("version")
+                        .num_args(1)
+                        .value_name("VERSION")
+                        .value_parser(clap::value_parser!(semver::Version))
+                        .help("Version of the module")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("signature")
+                        .short('S')
+                        .long("signature")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Signature of the module (default: <FILE>.sig, if it exists)"),
+                )
+                .arg(
+                    Arg::new("trusted")
+                        .short('t')
+                        .long("trusted")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .action(ArgAction::Append)
+                        .help("Public key to verify the signature with (can be repeated, default: keys.trusted)"),
+                )
+                .arg(
+                    Arg::new("confirm")
+                        .long("confirm")
+                        .action(ArgAction::SetTrue)
+                        .help("Confirm the installation, instead of leaving it pending"),
+                )
+                .arg(
+                    Arg::new("allow_unsigned")
+                        .long("allow-unsigned")
+                        .action(ArgAction::SetTrue)
+                        .help("Install the module without verifying its signature if no trusted keys are configured"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("doctor")
+                .about("Check the slots of the device, and optionally repair them")
+                .arg(
+                    Arg::new("slot_dir")
+                        .short('s')
+                        .long("slot-dir")
+                        .num_args(1)
+                        .value_name("DIR")
+                        .help("Storage directory of the slots (default: agent.slots)"),
+                )
+                .arg(
+                    Arg::new("trusted")
+                        .short('t')
+                        .long("trusted")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .action(ArgAction::Append)
+                        .help("Public key to verify the installed modules with (can be repeated, default: keys.trusted)"),
+                )
+                .arg(
+                    Arg::new("repair")
+                        .long("repair")
+                        .action(ArgAction::SetTrue)
+                        .help("Apply the repairs that don't require a decision from an operator"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("bundle")
+                .about("Create, extract and verify bundles of the modules of a release")
+                .subcommand_required(true)
+                .subcommand(
+                    clap::Command::new("create")
+                        .about("Bundle the modules listed by a release description")
+                        .arg(
+                            Arg::new("manifest")
+                                .short('m')
+                                .long("manifest")
+                                .num_args(1)
+                                .value_name("FILE")
+                                .help("TOML release description")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("input")
+                                .short('i')
+                                .long("input")
+                                .num_args(1)
+                                .value_name("NAME=FILE")
+                                .action(ArgAction::Append)
+                                .help("Additional module (can be repeated)"),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .num_args(1)
+                                .value_name("FILE")
+                                .help("Output file")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("key")
+                                .short('k')
+                                .long("key")
+                                .num_args(1)
+                                .value_name("FILE")
+                                .help("Secret key to sign the modules with (default: keys.signing)"),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("extract")
+                        .about("Verify a bundle, and write its modules and manifest to a directory")
+                        .arg(
+                            Arg::new("input")
+                                .short('i')
+                                .long("input")
+                                .num_args(1)
+                                .value_name("FILE")
+                                .help("Bundle file")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("output")
+                                .short('o')
+                                .long("output")
+                                .num_args(1)
+                                .value_name("DIR")
+                                .help("Output directory")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("trusted")
+                                .short('t')
+                                .long("trusted")
+                                .num_args(1)
+                                .value_name("FILE")
+                                .action(ArgAction::Append)
+                                .help("Public key to verify the signatures with (can be repeated, default: keys.trusted)"),
+                        ),
+                )
+                .subcommand(
+                    clap::Command::new("verify")
+                        .about("Check the modules of a bundle against its manifest and signatures")
+                        .arg(
+                            Arg::new("input")
+                                .short('i')
+                                .long("input")
+                                .num_args(1)
+                                .value_name("FILE")
+                                .help("Bundle file")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("trusted")
+                                .short('t')
+                                .long("trusted")
+                                .num_args(1)
+                                .value_name("FILE")
+                                .action(ArgAction::Append)
+                                .help("Public key to verify the signatures with (can be repeated, default: keys.trusted)"),
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("explain")
+                .about("Explain an error code")
+                .arg(
+                    Arg::new("code")
+                        .value_name("CODE")
+                        .help("Error code (such as E001) or name")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("schema")
+                .about("Print the JSON Schemas of protocol and repository documents")
+                .arg(
+                    Arg::new("name")
+                        .value_name("NAME")
+                        .value_parser(schema::SCHEMA_NAMES.to_vec())
+                        .help("Only print the schema of this document"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("completions")
+                .about("Generate a shell completion script")
+                .arg(
+                    Arg::new("shell")
+                        .value_name("SHELL")
+                        .value_parser(clap::value_parser!(clap_complete::Shell))
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("batch")
+                .about("Verify, re-sign, strip or recompress every module of a directory tree")
+                .arg(
+                    Arg::new("dir")
+                        .short('d')
+                        .long("dir")
+                        .num_args(1)
+                        .value_name("DIR")
+                        .help("Directory containing the modules")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("action")
+                        .short('a')
+                        .long("action")
+                        .num_args(1)
+                        .value_name("ACTION")
+                        .value_parser(BATCH_ACTIONS.to_vec())
+                        .help("Operation to apply to every module")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("key")
+                        .short('k')
+                        .long("key")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Secret key to sign with (default: keys.signing)"),
+                )
+                .arg(
+                    Arg::new("trusted")
+                        .short('t')
+                        .long("trusted")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .action(ArgAction::Append)
+                        .help("Public key to verify with (can be repeated, default: keys.trusted)"),
+                )
+                .arg(
+                    Arg::new("section")
+                        .short('s')
+                        .long("section")
+                        .num_args(1)
+                        .value_name("NAME")
+                        .action(ArgAction::Append)
+                        .help("Custom section to strip or compress (can be repeated, default: strip all custom sections)"),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .short('j')
+                        .long("jobs")
+                        .num_args(1)
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Number of modules processed in parallel (default: number of CPUs)"),
+                ),
+        )
+        .subcommand(key_command())
+        .subcommand(
+            clap::Command::new("migrate-signatures")
+                .about("Bring the signatures of a repository to the current format")
+                .arg(
+                    Arg::new("repo")
+                        .short('r')
+                        .long("repo")
+                        .num_args(1)
+                        .value_name("DIR")
+                        .help("Repository directory (default: repository.path)"),
+                )
+                .arg(
+                    Arg::new("key")
+                        .short('k')
+                        .long("key")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Secret key to sign with (default: keys.signing)"),
+                )
+                .arg(
+                    Arg::new("retire")
+                        .long("retire")
+                        .num_args(1)
+                        .value_name("KEY_ID")
+                        .action(ArgAction::Append)
+                        .help("Replace the signatures made by this key (hex-encoded key identifier)"),
+                )
+                .arg(
+                    Arg::new("sign_missing")
+                        .long("sign-missing")
+                        .action(ArgAction::SetTrue)
+                        .help("Also sign artifacts and patches without a signature"),
+                )
+                .arg(
+                    Arg::new("dry_run")
+                        .short('n')
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Only print what would be done"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("plan-patches")
+                .about("Choose the patches to generate for a fleet to update to the latest release")
+                .arg(
+                    Arg::new("repo")
+                        .short('r')
+                        .long("repo")
+                        .num_args(1)
+                        .value_name("DIR")
+                        .help("Repository directory (default: repository.path)"),
+                )
+                .arg(
+                    Arg::new("channel")
+                        .long("channel")
+                        .num_args(1)
+                        .value_name("CHANNEL")
+                        .default_value("stable")
+                        .help("Channel whose latest release the fleet updates to"),
+                )
+                .arg(
+                    Arg::new("inventory")
+                        .short('i')
+                        .long("inventory")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .required(true)
+                        .help("JSON object mapping build identifiers to numbers of devices"),
+                )
+                .arg(
+                    Arg::new("ratio")
+                        .long("ratio")
+                        .num_args(1)
+                        .value_name("RATIO")
+                        .value_parser(clap::value_parser!(f64))
+                        .help("Expected size of a patch, relative to the module (default: 0.2)"),
+                )
+                .arg(
+                    Arg::new("throughput")
+                        .long("throughput")
+                        .num_args(1)
+                        .value_name("BYTES")
+                        .value_parser(clap::value_parser!(f64))
+                        .help("Bytes processed per second while generating patches (default: 1 MiB)"),
+                )
+                .arg(
+                    Arg::new("max_time")
+                        .long("max-time")
+                        .num_args(1)
+                        .value_name("SECONDS")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Maximum total time spent generating patches"),
+                )
+                .arg(
+                    Arg::new("min_savings")
+                        .long("min-savings")
+                        .num_args(1)
+                        .value_name("BYTES")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Skip patches saving fewer bytes across the fleet"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("encode-artifacts")
+                .about("Store compressed copies of the artifacts of a repository")
+                .arg(
+                    Arg::new("repo")
+                        .short('r')
+                        .long("repo")
+                        .num_args(1)
+                        .value_name("DIR")
+                        .help("Repository directory (default: repository.path)"),
+                )
+                .arg(
+                    Arg::new("codec")
+                        .long("codec")
+                        .num_args(1)
+                        .value_name("NAME")
+                        .action(ArgAction::Append)
+                        .help("Codec to encode with (default: all the built-in codecs)"),
+                )
+                .arg(
+                    Arg::new("build_id")
+                        .long("build-id")
+                        .num_args(1)
+                        .value_name("BUILD_ID")
+                        .action(ArgAction::Append)
+                        .help("Only encode this artifact (default: all artifacts)"),
+                ),
+        )
+        .subcommands(dictionary_patch_commands())
+        .subcommands(oci_commands())
+        .subcommands(registry_commands())
+        .subcommands(codegen_commands())
+        .subcommand(repo_command())
+        .subcommand(
+            clap::Command::new("serve")
+                .about("Serve a repository over HTTP")
+                .arg(
+                    Arg::new("repo")
+                        .short('r')
+                        .long("repo")
+                        .num_args(1)
+                        .value_name("DIR")
+                        .help("Repository directory (default: repository.path)"),
+                )
+                .arg(
+                    Arg::new("listen")
+                        .short('l')
+                        .long("listen")
+                        .num_args(1)
+                        .value_name("ADDR")
+                        .help("Address to listen on (default: repository.listen, or 127.0.0.1:8080)"),
+                )
+                .arg(
+                    Arg::new("key")
+                        .short('k')
+                        .long("key")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Secret key to sign update-check responses with (default: keys.signing)"),
+                ),
+        )
+}
+
+#[cfg(feature = "wasmtime")]
+fn rule_plugin_args() -> Vec<Arg> {
+    vec![Arg::new("rule")
+        .long("rule")
+        .num_args(1)
+        .value_name("FILE")
+        .action(ArgAction::Append)
+        .help("WebAssembly plugin implementing a custom rule (can be repeated)")]
+}
+
+#[cfg(not(feature = "wasmtime"))]
+fn rule_plugin_args() -> Vec<Arg> {
+    vec![]
+}
+
+#[cfg(feature = "zstd")]
+fn dictionary_patch_commands() -> Vec<clap::Command> {
+    vec![clap::Command::new("make-patch")
+        .about("Store a patch compressing an artifact with a previous one as the dictionary")
+        .arg(
+            Arg::new("repo")
+                .short('r')
+                .long("repo")
+                .num_args(1)
+                .value_name("DIR")
+                .help("Repository directory (default: repository.path)"),
+        )
+        .arg(
+            Arg::new("from")
+                .long("from")
+                .num_args(1)
+                .value_name("BUILD_ID")
+                .required(true)
+                .help("Artifact the patch applies to"),
+        )
+        .arg(
+            Arg::new("to")
+                .long("to")
+                .num_args(1)
+                .value_name("BUILD_ID")
+                .required(true)
+                .help("Artifact the patch produces"),
+        )
+        .arg(
+            Arg::new("code_section")
+                .long("code-section")
+                .action(ArgAction::SetTrue)
+                .help("Only use the code section of the base as the dictionary"),
+        )
+        .arg(
+            Arg::new("memory_budget")
+                .long("memory-budget")
+                .num_args(1)
+                .value_name("BYTES")
+                .value_parser(clap::value_parser!(u64))
+                .help("Memory devices can use to apply the patch (default: enough for the whole modules)"),
+        )
+        .arg(
+            Arg::new("key")
+                .short('k')
+                .long("key")
+                .num_args(1)
+                .value_name("FILE")
+                .help("Secret key to sign the patch with (default: keys.signing)"),
+        )]
+}
+
+#[cfg(not(feature = "zstd"))]
+fn dictionary_patch_commands() -> Vec<clap::Command> {
+    vec![]
+}
+
+#[cfg(feature = "oci")]
+fn oci_commands() -> Vec<clap::Command> {
+    vec![
+        clap::Command::new("push")
+            .about("Push a module to an OCI registry")
+            .arg(
+                Arg::new("input")
+                    .short('i')
+                    .long("input")
+                    .num_args(1)
+                    .value_name("FILE")
+                    .help("Module to push")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("to")
+                    .short('t')
+                    .long("to")
+                    .num_args(1)
+                    .value_name("REFERENCE")
+                    .help("Destination, such as ghcr.io/org/app:1.2.0")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("version")
+                    .short('v')
+                    .long("version")
+                    .num_args(1)
+                    .value_name("VERSION")
+                    .value_parser(clap::value_parser!(semver::Version))
+                    .help("Version of the module"),
+            )
+            .arg(
+                Arg::new("key")
+                    .short('k')
+                    .long("key")
+                    .num_args(1)
+                    .value_name("FILE")
+                    .help("Secret key to sign the module with (default: keys.signing)"),
+            ),
+        clap::Command::new("pull")
+            .about("Pull a module from an OCI registry")
+            .arg(
+                Arg::new("from")
+                    .short('f')
+                    .long("from")
+                    .num_args(1)
+                    .value_name("REFERENCE")
+                    .help("Source, such as ghcr.io/org/app:1.2.0 or ghcr.io/org/app@sha256:...")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .num_args(1)
+                    .value_name("FILE")
+                    .help("Output file; the signature, if any, is written to <FILE>.sig")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("trusted")
+                    .long("trusted")
+                    .num_args(1)
+                    .value_name("FILE")
+                    .action(ArgAction::Append)
+                    .help("Public key to verify the signature with (can be repeated, default: keys.trusted)"),
+            ),
+    ]
+}
+
+#[cfg(not(feature = "oci"))]
+fn oci_commands() -> Vec<clap::Command> {
+    vec![]
+}
+
+#[cfg(feature = "registry")]
+fn registry_commands() -> Vec<clap::Command> {
+    let registry = Arg::new("registry")
+        .short('r')
+        .long("registry")
+        .num_args(1)
+        .value_name("DOMAIN")
+        .help("Domain of the package registry, such as example.com")
+        .required(true);
+    let package = Arg::new("package")
+        .short('p')
+        .long("package")
+        .num_args(1)
+        .value_name("NAMESPACE:NAME")
+        .help("Name of the package")
+        .required(true);
+    vec![
+        clap::Command::new("publish-package")
+            .about("Publish a module to a wasm package registry")
+            .arg(
+                Arg::new("input")
+                    .short('i')
+                    .long("input")
+                    .num_args(1)
+                    .value_name("FILE")
+                    .help("Module to publish")
+                    .required(true),
+            )
+            .arg(registry.clone())
+            .arg(package.clone())
+            .arg(
+                Arg::new("version")
+                    .short('v')
+                    .long("version")
+                    .num_args(1)
+                    .value_name("VERSION")
+                    .value_parser(clap::value_parser!(semver::Version))
+                    .help("Version of the package")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("key")
+                    .short('k')
+                    .long("key")
+                    .num_args(1)
+                    .value_name("FILE")
+                    .help("Secret key to sign the module with (default: keys.signing)"),
+            )
+            .arg(
+                Arg::new("advisories")
+                    .long("advisories")
+                    .num_args(1)
+                    .value_name("FILE")
+                    .help("Refuse to publish modules with dependencies affected by these advisories"),
+            ),
+        clap::Command::new("fetch-package")
+            .about("Fetch a module from a wasm package registry")
+            .arg(registry)
+            .arg(package)
+            .arg(
+                Arg::new("version")
+                    .short('v')
+                    .long("version")
+                    .num_args(1)
+                    .value_name("REQUIREMENT")
+                    .value_parser(clap::value_parser!(semver::VersionReq))
+                    .help("Version requirement, such as ^1.2 (default: the latest version)"),
+            )
+            .arg(
+                Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .num_args(1)
+                    .value_name("FILE")
+                    .help("Output file; the signature, if any, is written to <FILE>.sig")
+                    .required(true),
+            )
+            .arg(
+                Arg::new("trusted")
+                    .long("trusted")
+                    .num_args(1)
+                    .value_name("FILE")
+                    .action(ArgAction::Append)
+                    .help("Public key to verify the signature with (can be repeated, default: keys.trusted)"),
+            ),
+    ]
+}
+
+#[cfg(not(feature = "registry"))]
+fn registry_commands() -> Vec<clap::Command> {
+    vec![]
+}
+
+#[cfg(feature = "codegen")]
+fn codegen_commands() -> Vec<clap::Command> {
+    vec![clap::Command::new("host-shims")
+        .about("Generate wasmtime host binding stubs for the imports of a module")
+        .arg(
+            Arg::new("input")
+                .short('i')
+                .long("input")
+                .num_args(1)
+                .value_name("FILE")
+                .help("Input file")
+                .required(true),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .num_args(1)
+                .value_name("FILE")
+                .help("Rust source file to write (default: standard output)"),
+        )
+        .arg(
+            Arg::new("function")
+                .long("function")
+                .num_args(1)
+                .value_name("NAME")
+                .default_value("add_to_linker")
+                .help("Name of the generated function"),
+        )
+        .arg(
+            Arg::new("state_type")
+                .long("state-type")
+                .num_args(1)
+                .value_name("TYPE")
+                .help("Type of the store data (default: a generic parameter)"),
+        )
+        .arg(
+            Arg::new("check")
+                .long("check")
+                .action(ArgAction::SetTrue)
+                .requires("output")
+                .help("Fail if the output file is not up to date, instead of writing it"),
+        )]
+}
+
+#[cfg(not(feature = "codegen"))]
+fn codegen_commands() -> Vec<clap::Command> {
+    vec![]
+}
+
+fn agent_command() -> clap::Command {
+    let slot_dir = Arg::new("slot_dir")
+        .short('s')
+        .long("slot-dir")
+        .num_args(1)
+        .value_name("DIR")
+        .help("Storage directory of the slots (default: agent.slots)");
+    clap::Command::new("agent")
+        .about("Run the update agent of the device")
+        .subcommand_required(true)
+        .subcommand(
+            clap::Command::new("run")
+                .about("Check for updates and install them, at the configured interval")
+                .arg(slot_dir.clone())
+                .arg(
+                    Arg::new("device_id")
+                        .long("device-id")
+                        .num_args(1)
+                        .value_name("ID")
+                        .help("Identifier of the device (default: agent.device_id)"),
+                )
+                .arg(
+                    Arg::new("channel")
+                        .long("channel")
+                        .num_args(1)
+                        .value_name("CHANNEL")
+                        .help("Channel to follow (default: agent.channel)"),
+                )
+                .arg(
+                    Arg::new("once")
+                        .long("once")
+                        .action(ArgAction::SetTrue)
+                        .help("Run a single update cycle"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("confirm")
+                .about("Confirm that the installed module works")
+                .arg(slot_dir.clone()),
+        )
+        .subcommand(
+            clap::Command::new("rollback")
+                .about("Restore the previously installed module")
+                .arg(slot_dir.clone()),
+        )
+        .subcommand(
+            clap::Command::new("pin-golden")
+                .about("Keep the installed module aside, to restore it if all else fails")
+                .arg(slot_dir.clone()),
+        )
+        .subcommand(
+            clap::Command::new("restore-golden")
+                .about("Verify and restore the golden module")
+                .arg(slot_dir.clone()),
+        )
+        .subcommand(
+            clap::Command::new("alias")
+                .about("List, resolve, set or remove the logical names of installed modules")
+                .arg(slot_dir)
+                .arg(
+                    Arg::new("name")
+                        .num_args(1)
+                        .value_name("NAME")
+                        .help("Logical name (default: list all the names)"),
+                )
+                .arg(
+                    Arg::new("build_id")
+                        .num_args(1)
+                        .value_name("BUILD_ID")
+                        .requires("name")
+                        .help("Installed module to point the name to"),
+                )
+                .arg(
+                    Arg::new("active")
+                        .long("active")
+                        .action(ArgAction::SetTrue)
+                        .requires("name")
+                        .conflicts_with("build_id")
+                        .help("Point the name to the module of the active slot"),
+                )
+                .arg(
+                    Arg::new("remove")
+                        .long("remove")
+                        .action(ArgAction::SetTrue)
+                        .requires("name")
+                        .conflicts_with_all(["build_id", "active"])
+                        .help("Forget the name"),
+                ),
+        )
+}
+
+fn key_command() -> clap::Command {
+    let output = Arg::new("output")
+        .short('o')
+        .long("output")
+        .num_args(1)
+        .value_name("PREFIX")
+        .help("Path of the new key pair, without the .key and .pub extensions")
+        .required(true);
+    clap::Command::new("key")
+        .about("Generate, rotate and revoke signing keys")
+        .subcommand_required(true)
+        .subcommand(
+            clap::Command::new("generate")
+                .about("Generate a new key pair")
+                .arg(output.clone()),
+        )
+        .subcommand(
+            clap::Command::new("rotate")
+                .about("Replace a signing key with a new key pair, and re-sign a repository")
+                .arg(output)
+                .arg(
+                    Arg::new("key")
+                        .short('k')
+                        .long("key")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Secret key to retire (default: keys.signing)"),
+                )
+                .arg(
+                    Arg::new("resign_dir")
+                        .long("resign-dir")
+                        .num_args(1)
+                        .value_name("DIR")
+                        .help("Repository whose signatures made by the retired key are replaced"),
+                )
+                .arg(
+                    Arg::new("dry_run")
+                        .short('n')
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Only print what would be done"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("revoke")
+                .about("Embed a signed revocation list into a module")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Module to embed the list into")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Output file")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("root")
+                        .long("root")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Secret root key signing the revocation list")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("revoke")
+                        .long("revoke")
+                        .num_args(1)
+                        .value_name("KEY")
+                        .action(ArgAction::Append)
+                        .help("Key to revoke: public key file, or hex-encoded key identifier (can be repeated)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("effective_at")
+                        .long("effective-at")
+                        .num_args(1)
+                        .value_name("SECONDS")
+                        .value_parser(clap::value_parser!(u64))
+                        .help("Time from which signatures made with the keys are rejected, in seconds since the UNIX epoch (default: now)"),
+                )
+                .arg(
+                    Arg::new("key")
+                        .short('k')
+                        .long("key")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Secret key to sign the output with (default: keys.signing)"),
+                ),
+        )
+        .subcommands(encryption_key_commands())
+}
+
+#[cfg(feature = "encryption")]
+fn encryption_key_commands() -> Vec<clap::Command> {
+    vec![clap::Command::new("rotate-encryption")
+        .about("Replace an encryption key with a new key pair, and rekey the artifacts of a repository")
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .num_args(1)
+                .value_name("PREFIX")
+                .help("Path of the new key pair, without the .key and .pub extensions")
+                .required(true),
+        )
+        .arg(
+            Arg::new("key")
+                .short('k')
+                .long("key")
+                .num_args(1)
+                .value_name("FILE")
+                .help("Secret encryption key to retire")
+                .required(true),
+        )
+        .arg(
+            Arg::new("recipient")
+                .long("recipient")
+                .num_args(1)
+                .value_name("FILE")
+                .action(ArgAction::Append)
+                .help("Public encryption key of another recipient to keep (can be repeated)"),
+        )
+        .arg(
+            Arg::new("repo")
+                .short('r')
+                .long("repo")
+                .num_args(1)
+                .value_name("DIR")
+                .help("Repository directory (default: repository.path)"),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .short('n')
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Only print what would be done"),
+        )]
+}
+
+#[cfg(not(feature = "encryption"))]
+fn encryption_key_commands() -> Vec<clap::Command> {
+    vec![]
+}
+
+fn repo_command() -> clap::Command {
+    let repo = Arg::new("repo")
+        .short('r')
+        .long("repo")
+        .num_args(1)
+        .value_name("DIR")
+        .help("Repository directory (default: s3.bucket, or repository.path)");
+    clap::Command::new("repo")
+        .about("Manage the artifacts and channels of a repository")
+        .subcommand_required(true)
+        .subcommand(
+            clap::Command::new("publish")
+                .about("Publish a module on a channel")
+                .arg(
+                    Arg::new("input")
+                        .value_name("FILE")
+                        .help("Module to publish")
+                        .required(true),
+                )
+                .arg(repo.clone())
+                .arg(
+                    Arg::new("channel")
+                        .long("channel")
+                        .num_args(1)
+                        .value_name("CHANNEL")
+                        .help("Channel to publish on")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("version")
+                        .short('v')
+                        .long("version")
+                        .num_args(1)
+                        .value_name("VERSION")
+                        .value_parser(clap::value_parser!(semver::Version))
+                        .help("Version of the module")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("key")
+                        .short('k')
+                        .long("key")
+                        .num_args(1)
+                        .value_name("FILE")
+                        .help("Secret key to sign the module with (default: keys.signing)"),
+                )
+                .arg(
+                    Arg::new("notes")
+                        .long("notes")
+                        .action(ArgAction::SetTrue)
+                        .help(
+                            "Attach release notes derived from the previous version of the channel",
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("release-notes")
+                .about("Print the release notes attached to an artifact")
+                .arg(
+                    Arg::new("build_id")
+                        .value_name("BUILD_ID")
+                        .help("Artifact")
+                        .required(true),
+                )
+                .arg(repo.clone())
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .num_args(1)
+                        .value_name("FORMAT")
+                        .value_parser(["markdown", "json"])
+                        .default_value("markdown")
+                        .help("Format of the release notes"),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("promote")
+                .about("Publish an artifact of the repository on another channel")
+                .arg(
+                    Arg::new("build_id")
+                        .value_name("BUILD_ID")
+                        .help("Artifact to promote")
+                        .required(true),
+                )
+                .arg(repo.clone())
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .num_args(1)
+                        .value_name("CHANNEL")
+                        .help("Channel to publish on")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("pin-golden")
+                .about("Pin an artifact as the golden module devices of a channel keep aside")
+                .arg(
+                    Arg::new("build_id")
+                        .value_name("BUILD_ID")
+                        .help("Artifact to pin")
+                        .required(true),
+                )
+                .arg(repo.clone())
+                .arg(
+                    Arg::new("channel")
+                        .long("channel")
+                        .num_args(1)
+                        .value_name("CHANNEL")
+                        .help("Channel whose devices keep the artifact")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("unpin-golden")
+                .about("Stop offering a golden module to the devices of a channel")
+                .arg(repo.clone())
+                .arg(
+                    Arg::new("channel")
+                        .long("channel")
+                        .num_args(1)
+                        .value_name("CHANNEL")
+                        .help("Channel to unpin the golden module of")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("gc")
+                .about("Remove the artifacts that are neither on a channel nor pinned")
+                .arg(repo)
+                .arg(
+                    Arg::new("min_age")
+                        .long("min-age")
+                        .num_args(1)
+                        .value_name("SECONDS")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("3600")
+                        .help("Keep artifacts published more recently"),
+                )
+                .arg(
+                    Arg::new("dry_run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Only list what would be removed"),
+                ),
+        )
+}
+
+fn run(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let config_file = match matches.subcommand() {
+        Some((_, matches)) => matches.get_one::<String>("config"),
+        None => matches.get_one::<String>("config"),
+    };
+    let mut config = config::Config::load_layered(config_file.map(Path::new))?;
+    match config.limits.memory {
+        Some(limit) => memory::with_memory_limit(limit, || dispatch(matches, &mut config, out)),
+        None => dispatch(matches, &mut config, out),
+    }
+}
+
+fn dispatch(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    match matches.subcommand() {
+        Some(("stamp", matches)) => stamp(matches, out),
+        Some(("normalize", matches)) => normalize(matches, out),
+        Some(("set-flags", matches)) => set_flags(matches, out),
+        Some(("set-security-version", matches)) => set_security_version(matches, config, out),
+        Some(("quick-check", matches)) => quick_check(matches, out),
+        Some(("inspect", matches)) => inspect(matches, out),
+        Some(("audit", matches)) => audit(matches, out),
+        Some(("check-compat", matches)) => check_compat(matches, out),
+        Some(("check-dependents", matches)) => check_dependents(matches, out),
+        Some(("diff", matches)) => diff(matches, out),
+        Some(("provenance-diff", matches)) => provenance_diff(matches, out),
+        Some(("release-notes", matches)) => release_notes(matches, out),
+        Some(("size-history", matches)) => size_history(matches, out),
+        Some(("dev", matches)) => dev(matches, config, out),
+        Some(("agent", matches)) => {
+            let (command, matches) = matches.subcommand().unwrap();
+            if let Some(slot_dir) = matches.get_one::<String>("slot_dir") {
+                config.set("agent.slots", slot_dir)?;
+            }
+            match command {
+                "run" => agent_run(matches, config, out),
+                "confirm" => agent_confirm(config, out),
+                "rollback" => agent_rollback(config, out),
+                "pin-golden" => agent_pin_golden(config, out),
+                "restore-golden" => agent_restore_golden(config, out),
+                "alias" => agent_alias(matches, config, out),
+                _ => unreachable!(),
+            }
+        }
+        Some(("apply", matches)) => apply(matches, config, out),
+        Some(("doctor", matches)) => doctor(matches, config, out),
+        Some(("bundle", matches)) => match matches.subcommand() {
+            Some(("create", matches)) => bundle_create(matches, config, out),
+            Some(("extract", matches)) => bundle_extract(matches, config, out),
+            Some(("verify", matches)) => bundle_verify(matches, config, out),
+            _ => unreachable!(),
+        },
+        Some(("explain", matches)) => explain(matches, out),
+        Some(("schema", matches)) => print_schema(matches, out),
+        Some(("completions", matches)) => completions(matches, out),
+        Some(("batch", matches)) => batch(matches, config, out),
+        Some(("key", matches)) => match matches.subcommand() {
+            Some(("generate", matches)) => key_generate(matches, out),
+            Some(("rotate", matches)) => key_rotate(matches, config, out),
+            Some(("revoke", matches)) => key_revoke(matches, config, out),
+            #[cfg(feature = "encryption")]
+            Some(("rotate-encryption", matches)) => key_rotate_encryption(matches, config, out),
+            _ => unreachable!(),
+        },
+        Some(("migrate-signatures", matches)) => migrate_signatures(matches, config, out),
+        Some(("plan-patches", matches)) => plan_patches(matches, config, out),
+        Some(("encode-artifacts", matches)) => encode_artifacts(matches, config, out),
+        #[cfg(feature = "zstd")]
+        Some(("make-patch", matches)) => make_patch(matches, config, out),
+        #[cfg(feature = "oci")]
+        Some(("push", matches)) => push(matches, config, out),
+        #[cfg(feature = "oci")]
+        Some(("pull", matches)) => pull(matches, config, out),
+        #[cfg(feature = "registry")]
+        Some(("publish-package", matches)) => publish_package(matches, config, out),
+        #[cfg(feature = "registry")]
+        Some(("fetch-package", matches)) => fetch_package(matches, config, out),
+        #[cfg(feature = "codegen")]
+        Some(("host-shims", matches)) => host_shims(matches, out),
+        Some(("repo", matches)) => {
+            let (command, matches) = matches.subcommand().unwrap();
+            if let Some(repo_dir) = matches.get_one::<String>("repo") {
+                config.set("repository.path", repo_dir)?;
+                config.s3.bucket = None;
+            }
+            let repo = repo::Repository::new(config.repository_backend()?);
+            match command {
+                "publish" => repo_publish(&repo, matches, config, out),
+                "release-notes" => repo_release_notes(&repo, matches, out),
+                "promote" => repo_promote(&repo, matches, out),
+                "pin-golden" => repo_pin_golden(&repo, matches, out),
+                "unpin-golden" => repo_unpin_golden(&repo, matches, out),
+                "gc" => repo_gc(&repo, matches, out),
+                _ => unreachable!(),
+            }
+        }
+        Some(("serve", matches)) => serve(matches, config, out),
+        _ => convert(matches, out),
+    }
+}
+
+fn stamp(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let input_file = matches.get_one::<String>("input").unwrap();
+    let output_file = matches.get_one::<String>("output").unwrap();
+    let section_names: Vec<&String> = matches.get_many::<String>("section").unwrap().collect();
+
+    let mut vars = TemplateVars::with_builtins();
+    for var in matches.get_many::<String>("var").unwrap_or_default() {
+        let (name, value) = var
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Variables must be defined as NAME=VALUE"))?;
+        vars.set(name, value);
+    }
+
+    outln!(out, "Reading module from:\t[{}]", input_file);
+    let mut module = Module::deserialize_from_file(input_file)?;
+    let count = substitute(&mut module, &section_names, &vars)?;
+    outln!(out, "Sections updated:\t{}", count);
+
+    write_module(&module, output_file, out)?;
+    out.set("input", input_file);
+    out.set("output", output_file);
+    out.set("sections_updated", count);
+
+    Ok(())
+}
+
+/// Write a module, and print its build identifier along with the digests of its sections.
+fn write_module(module: &Module, output_file: &str, out: &mut Output) -> Result<(), Error> {
+    outln!(out, "Writing module to:\t[{}]", output_file);
+    let summary = module.serialize_to_file_with_summary(output_file)?;
+    let build_id = Hex::encode_to_string(summary.digest)?;
+    outln!(out, "Build ID:\t{}", build_id);
+    out.set("build_id", build_id);
+    for section in &summary.sections {
+        out.push(
+            "section_digests",
+            serde_json::json!({
+                "id": section.key.stable_id(),
+                "digest": Hex::encode_to_string(section.digest)?,
+            }),
+        );
+    }
+    Ok(())
+}
+
+fn normalize(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let input_file = matches.get_one::<String>("input").unwrap();
+    let output_file = matches.get_one::<String>("output").unwrap();
+
+    let mut normalizer = timestamps::TimestampNormalizer::new()
+        .sort_custom_sections(matches.get_flag("sort_custom_sections"));
+    if let Some(section_names) = matches.get_many::<String>("section") {
+        let section_names: Vec<&String> = section_names.collect();
+        normalizer = normalizer.sections(&section_names);
+    }
+
+    outln!(out, "Reading module from:\t[{}]", input_file);
+    let mut module = Module::deserialize_from_file(input_file)?;
+    let count = normalizer.normalize(&mut module)?;
+    outln!(out, "Sections updated:\t{}", count);
+
+    write_module(&module, output_file, out)?;
+    out.set("input", input_file);
+    out.set("output", output_file);
+    out.set("sections_updated", count);
+
+    Ok(())
+}
+
+fn set_flags(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let input_file = matches.get_one::<String>("input").unwrap();
+    let output_file = matches.get_one::<String>("output").unwrap();
+
+    let mut overrides = vec![];
+    for flag in matches.get_many::<String>("flag").unwrap() {
+        let (name, value) = flag
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Flags must be set as NAME=on or NAME=off"))?;
+        let default = match value {
+            "on" | "true" | "1" => true,
+            "off" | "false" | "0" => false,
+            _ => bail!("Invalid value for flag [{}]: [{}]", name, value),
+        };
+        overrides.push((name, default));
+    }
+
+    outln!(out, "Reading module from:\t[{}]", input_file);
+    let mut module = Module::deserialize_from_file(input_file)?;
+    module.override_feature_flags(&overrides)?;
+    outln!(out, "Flags updated:\t\t{}", overrides.len());
+
+    write_module(&module, output_file, out)?;
+    out.set("input", input_file);
+    out.set("output", output_file);
+    out.set("flags_updated", overrides.len());
+
+    Ok(())
+}
 
+fn set_security_version(
+    matches: &ArgMatches,
+    config: &config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
     let input_file = matches.get_one::<String>("input").unwrap();
     let output_file = matches.get_one::<String>("output").unwrap();
+    let security_version = matches.get_one::<u64>("security_version");
+    let downgrade_sk = matches
+        .get_one::<String>("authorize")
+        .map(|file| config.read_secret_key(Path::new(file)))
+        .transpose()?;
+    if security_version.is_none() && downgrade_sk.is_none() {
+        bail!("Either a security version or a downgrade key is required");
+    }
+
+    outln!(out, "Reading module from:\t[{}]", input_file);
+    let mut module = Module::deserialize_from_file(input_file)?;
+    if let Some(security_version) = security_version {
+        module.set_security_version(*security_version)?;
+    }
+    let security_version = module.security_version()?;
+    outln!(out, "Security version:\t{}", security_version);
+    if let Some(downgrade_sk) = &downgrade_sk {
+        module.authorize_downgrade(downgrade_sk)?;
+        outln!(
+            out,
+            "Downgrade authorized:\t{}",
+            signature::key_id_to_hex(downgrade_sk.key_id())
+        );
+    }
+
+    write_module(&module, output_file, out)?;
+    out.set("input", input_file);
+    out.set("output", output_file);
+    out.set("security_version", security_version);
+    out.set("downgrade_authorized", downgrade_sk.is_some());
+
+    Ok(())
+}
+
+fn inspect(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let input_file = matches.get_one::<String>("input").unwrap();
+
+    let module = Module::deserialize_from_file(input_file)?;
+    let value = match matches.get_one::<String>("get") {
+        Some(query) => module
+            .query(query)?
+            .ok_or_else(|| anyhow!("No value found for [{}]", query))?,
+        None => module.metadata()?,
+    };
+    match &value {
+        serde_json::Value::String(s) => outln!(out, "{}", s),
+        value => outln!(out, "{}", serde_json::to_string_pretty(value)?),
+    }
+    out.set("value", value);
+
+    Ok(())
+}
+
+fn audit(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let input_file = matches.get_one::<String>("input").unwrap();
+    let mut license_policy = licenses::LicensePolicy::new()
+        .require_notices(matches.get_flag("require_notices"))
+        .require_licenses(matches.get_flag("require_licenses"));
+    for license in matches
+        .get_many::<String>("deny_license")
+        .unwrap_or_default()
+    {
+        license_policy = license_policy.deny_license(license);
+    }
 
-    println!("Reading module from:\t[{}]", input_file);
+    let module = Module::deserialize_from_file(input_file)?;
+    let mut problems = 0;
+    if let Some(advisories) = matches.get_one::<String>("advisories") {
+        let database = advisories::AdvisoryDatabase::from_file(advisories)?;
+        let has_sbom = module.sbom_components()?.is_some();
+        if !has_sbom {
+            outln!(out, "The module has no bill of materials");
+        }
+        out.set("sbom", has_sbom);
+        let matches = module.audit_dependencies(&database)?;
+        for m in &matches {
+            let severity = m.advisory.severity.as_deref().unwrap_or("unknown");
+            outln!(
+                out,
+                "{} {}: {} [{}]",
+                m.component.name,
+                m.component.version,
+                m.advisory.id,
+                severity
+            );
+            if let Some(summary) = &m.advisory.summary {
+                outln!(out, "\t{}", summary);
+            }
+            out.push(
+                "advisories",
+                serde_json::json!({
+                    "component": m.component.name,
+                    "version": m.component.version,
+                    "advisory": m.advisory.id,
+                    "severity": severity,
+                    "summary": m.advisory.summary,
+                }),
+            );
+        }
+        problems += matches.len();
+    }
+    if license_policy != licenses::LicensePolicy::new() {
+        let violations = license_policy.violations(&module)?;
+        for violation in &violations {
+            outln!(out, "License policy: {}", violation);
+            out.push("license_violations", violation.to_string());
+        }
+        problems += violations.len();
+    }
+    let rules = rule_plugins(matches)?;
+    if !rules.is_empty() {
+        let violations = rules.violations(&module)?;
+        for violation in &violations {
+            outln!(out, "Rule: {}", violation);
+            out.push("rule_violations", violation);
+        }
+        problems += violations.len();
+    }
+    out.set("problems", problems);
+    if problems > 0 {
+        return Err(failure(
+            ExitStatus::Rejected,
+            format!("{} problems found", problems),
+        ));
+    }
+    outln!(out, "No problems found");
+
+    Ok(())
+}
+
+#[cfg(feature = "wasmtime")]
+fn rule_plugins(matches: &ArgMatches) -> Result<rules::RuleSet, Error> {
+    let mut rules = rules::RuleSet::new();
+    for file in matches.get_many::<String>("rule").unwrap_or_default() {
+        rules = rules.rule(rules::WasmRule::from_file(file)?);
+    }
+    Ok(rules)
+}
+
+#[cfg(not(feature = "wasmtime"))]
+fn rule_plugins(_matches: &ArgMatches) -> Result<rules::RuleSet, Error> {
+    Ok(rules::RuleSet::new())
+}
+
+fn check_compat(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let input_file = matches.get_one::<String>("input").unwrap();
+    let profile_file = matches.get_one::<String>("profile").unwrap();
+
+    let profile = compat::DeviceProfile::load(profile_file)?;
+    let module = Module::deserialize_from_file(input_file)?;
+    let report = module.check_compat(&profile)?;
+    for check in &report.checks {
+        outln!(out, "{}", check);
+        out.push("checks", check.to_string());
+    }
+    out.set("compatible", report.is_compatible());
+    if !report.is_compatible() {
+        return Err(failure(
+            ExitStatus::Rejected,
+            format!(
+                "The module is not compatible with the profile in [{}]",
+                profile_file
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_dependents(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let input_file = matches.get_one::<String>("input").unwrap();
+    let module_name = matches.get_one::<String>("module_name").unwrap();
+    let dependent_files: Vec<&String> = matches.get_many::<String>("dependent").unwrap().collect();
+
+    let module = Module::deserialize_from_file(input_file)?;
+    let dependents = dependent_files
+        .iter()
+        .map(|file| Ok((file.as_str(), Module::deserialize_from_file(file)?)))
+        .collect::<Result<Vec<_>, WSError>>()?;
+    let report = module.check_dependents(
+        module_name,
+        dependents
+            .iter()
+            .map(|(file, dependent)| (*file, dependent)),
+    )?;
+    for check in &report.dependents {
+        outln!(out, "{}", check);
+        out.push("dependents", check.to_string());
+    }
+    out.set("compatible", report.is_compatible());
+    let broken: Vec<&str> = report.broken().map(|check| check.name.as_str()).collect();
+    if !broken.is_empty() {
+        return Err(failure(
+            ExitStatus::Rejected,
+            format!(
+                "{} dependent(s) of [{}] would break: {}",
+                broken.len(),
+                input_file,
+                broken.join(", ")
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+fn size_change(old_size: Option<u64>, new_size: Option<u64>) -> String {
+    match (old_size, new_size) {
+        (Some(old_size), Some(new_size)) if old_size != new_size => format!(
+            "{} -> {} bytes ({:+})",
+            old_size,
+            new_size,
+            new_size as i64 - old_size as i64
+        ),
+        (Some(size), _) | (None, Some(size)) => format!("{} bytes", size),
+        (None, None) => "-".to_string(),
+    }
+}
+
+fn quick_check(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let input_file = matches.get_one::<String>("input").unwrap();
+
+    let report = Module::quick_check(input_file)?;
+    outln!(out, "Size:\t{} bytes", report.len);
+    outln!(out, "Sections:\t{}", report.sections.len());
+    out.set("len", report.len);
+    out.set("sections", report.sections.len());
+    if let Some(issue) = &report.issue {
+        return Err(failure(
+            ExitStatus::InvalidInput,
+            format!("Quick check failed: {}", issue),
+        ));
+    }
+    outln!(out, "No problems found");
+
+    Ok(())
+}
+
+fn diff(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let old_file = matches.get_one::<String>("old").unwrap();
+    let new_file = matches.get_one::<String>("new").unwrap();
+
+    let old_module = Module::deserialize_from_file(old_file)?;
+    let new_module = Module::deserialize_from_file(new_file)?;
+    if matches.get_flag("interface") {
+        let diff = old_module.diff_interface(&new_module)?;
+        if diff.is_empty() {
+            outln!(out, "No interface changes");
+        }
+        for change in &diff.changes {
+            outln!(out, "{}", change);
+            out.push("interface_changes", change.to_string());
+        }
+        return Ok(());
+    }
+    let diff = old_module.diff(&new_module)?;
+    if matches.get_flag("stat") {
+        for change in diff.changed() {
+            outln!(
+                out,
+                "{}:\t{}",
+                change.key,
+                size_change(change.old_size, change.new_size)
+            );
+            out.push(
+                "sections",
+                serde_json::json!({
+                    "key": change.key.to_string(),
+                    "old_size": change.old_size,
+                    "new_size": change.new_size,
+                }),
+            );
+        }
+        let total = |size: fn(&diff::SectionChange) -> Option<u64>| {
+            diff.changes.iter().filter_map(size).sum::<u64>()
+        };
+        let (old_total, new_total) = (
+            total(|change| change.old_size),
+            total(|change| change.new_size),
+        );
+        outln!(
+            out,
+            "Total:\t{}",
+            size_change(Some(old_total), Some(new_total))
+        );
+        out.set(
+            "total",
+            serde_json::json!({ "old_size": old_total, "new_size": new_total }),
+        );
+        return Ok(());
+    }
+    for change in &diff.changes {
+        outln!(
+            out,
+            "{}\t{}\t{}\t{}",
+            change.kind,
+            change.impact,
+            change.key,
+            size_change(change.old_size, change.new_size)
+        );
+        out.push(
+            "sections",
+            serde_json::json!({
+                "kind": change.kind.to_string(),
+                "impact": change.impact.to_string(),
+                "key": change.key.to_string(),
+                "old_size": change.old_size,
+                "new_size": change.new_size,
+            }),
+        );
+    }
+    if !(old_module.is_component() || new_module.is_component()) {
+        for change in &old_module.diff_interface(&new_module)?.changes {
+            outln!(out, "{}", change);
+            out.push("interface_changes", change.to_string());
+        }
+    }
+    outln!(out, "Impact:\t{}", diff.impact());
+    out.set("impact", diff.impact().to_string());
+
+    Ok(())
+}
+
+fn provenance_diff(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let old_file = matches.get_one::<String>("old").unwrap();
+    let new_file = matches.get_one::<String>("new").unwrap();
+
+    let old_module = Module::deserialize_from_file(old_file)?;
+    let new_module = Module::deserialize_from_file(new_file)?;
+    let diff = old_module.provenance_diff(&new_module)?;
+    if diff.is_empty() {
+        outln!(out, "No provenance changes");
+    }
+    for change in &diff.changes {
+        outln!(out, "{}", change);
+        out.push("changes", change.to_string());
+    }
+
+    Ok(())
+}
+
+fn print_release_notes(
+    notes: &release_notes::ReleaseNotes,
+    matches: &ArgMatches,
+    out: &mut Output,
+) -> Result<(), Error> {
+    if out.json {
+        out.set("notes", notes);
+        return Ok(());
+    }
+    match matches.get_one::<String>("format").unwrap().as_str() {
+        "json" => println!("{}", notes.to_json()?),
+        _ => print!("{}", notes.to_markdown()),
+    }
+    Ok(())
+}
+
+fn release_notes(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let old_file = matches.get_one::<String>("old").unwrap();
+    let new_file = matches.get_one::<String>("new").unwrap();
+
+    let old_module = Module::deserialize_from_file(old_file)?;
+    let new_module = Module::deserialize_from_file(new_file)?;
+    print_release_notes(&old_module.release_notes(&new_module)?, matches, out)
+}
+
+#[cfg(feature = "codegen")]
+fn host_shims(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let input_file = matches.get_one::<String>("input").unwrap();
+
+    let mut options =
+        codegen::HostShims::new().function_name(matches.get_one::<String>("function").unwrap());
+    if let Some(state_type) = matches.get_one::<String>("state_type") {
+        options = options.state_type(state_type);
+    }
+    let module = Module::deserialize_from_file(input_file)?;
+    let code = module.host_shims(&options)?;
+    let output_file = match matches.get_one::<String>("output") {
+        Some(output_file) => output_file,
+        None => {
+            print!("{}", code);
+            return Ok(());
+        }
+    };
+    if matches.get_flag("check") {
+        let up_to_date = std::fs::read_to_string(output_file).ok().as_deref() == Some(&code);
+        out.set("up_to_date", up_to_date);
+        if !up_to_date {
+            return Err(failure(
+                ExitStatus::Rejected,
+                format!(
+                    "[{}] doesn't match the imports of [{}]",
+                    output_file, input_file
+                ),
+            ));
+        }
+        outln!(out, "[{}] is up to date", output_file);
+        return Ok(());
+    }
+    std::fs::write(output_file, &code)?;
+    outln!(out, "Host shims written to [{}]", output_file);
+
+    Ok(())
+}
+
+fn size_history(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let input_files: Vec<&String> = matches.get_many::<String>("input").unwrap().collect();
+
+    let mut modules = vec![];
+    for input_file in &input_files {
+        modules.push(Module::deserialize_from_file(input_file)?);
+    }
+    let history = growth::SizeHistory::new(input_files.iter().map(|f| f.as_str()).zip(&modules))?;
+    if out.json {
+        let history: serde_json::Value = serde_json::from_str(&history.to_json()?)?;
+        out.set("history", history);
+        return Ok(());
+    }
+    match matches.get_one::<String>("format").unwrap().as_str() {
+        "json" => println!("{}", history.to_json()?),
+        _ => print!("{}", history.to_csv()),
+    }
+
+    Ok(())
+}
+
+fn bundle_create(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    if let Some(key) = matches.get_one::<String>("key") {
+        config.set("keys.signing", key)?;
+    }
+    let manifest_file = matches.get_one::<String>("manifest").unwrap();
+    let output_file = matches.get_one::<String>("output").unwrap();
+
+    let mut bundle = bundle::BundleSpec::load(manifest_file)?.bundle()?;
+    for input in matches.get_many::<String>("input").unwrap_or_default() {
+        let (name, file) = input
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Modules must be given as NAME=FILE, not [{}]", input))?;
+        bundle.add(name, std::fs::read(file)?)?;
+    }
+    bundle.manifest.check_requirements()?;
+    if let Some(sk) = config.signing_key()? {
+        bundle.sign(&sk);
+    }
+    bundle.serialize_to_file(output_file)?;
+    print_bundle(&bundle, out);
+
+    Ok(())
+}
+
+/// Load a bundle, and verify it with the trusted keys, if any.
+fn load_bundle(matches: &ArgMatches, config: &mut config::Config) -> Result<bundle::Bundle, Error> {
+    if let Some(trusted) = matches.get_many::<String>("trusted") {
+        config.set(
+            "keys.trusted",
+            &trusted.cloned().collect::<Vec<_>>().join(","),
+        )?;
+    }
+    let input_file = matches.get_one::<String>("input").unwrap();
+
+    let bundle = bundle::Bundle::deserialize_from_file(input_file)?;
+    let key_ring = config.trusted_keys()?;
+    bundle.verify((!key_ring.is_empty()).then_some(&key_ring))?;
+    Ok(bundle)
+}
+
+fn print_bundle(bundle: &bundle::Bundle, out: &mut Output) {
+    let manifest = &bundle.manifest;
+    match &manifest.version {
+        Some(version) => outln!(out, "{} ({})", manifest.name, version),
+        None => outln!(out, "{}", manifest.name),
+    }
+    out.set("manifest", manifest);
+    for artifact in &manifest.artifacts {
+        let name = match &artifact.version {
+            Some(version) => format!("{} {}", artifact.name, version),
+            None => artifact.name.clone(),
+        };
+        outln!(
+            out,
+            "{}\t{}\t{} bytes{}",
+            name,
+            artifact.build_id,
+            artifact.size,
+            if artifact.signature.is_some() {
+                ", signed"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+fn bundle_extract(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    let bundle = load_bundle(matches, config)?;
+    bundle.extract(matches.get_one::<String>("output").unwrap())?;
+    print_bundle(&bundle, out);
+
+    Ok(())
+}
+
+fn bundle_verify(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    let bundle = load_bundle(matches, config)?;
+    print_bundle(&bundle, out);
+
+    Ok(())
+}
+
+fn dev(matches: &ArgMatches, config: &mut config::Config, out: &mut Output) -> Result<(), Error> {
+    let watched_file = matches.get_one::<String>("watch").unwrap();
+    for (arg, setting) in [
+        ("slot", "agent.slots"),
+        ("repo", "repository.path"),
+        ("key", "keys.signing"),
+    ] {
+        if let Some(value) = matches.get_one::<String>(arg) {
+            config.set(setting, value)?;
+        }
+    }
+    let slot_dir = config
+        .agent
+        .slots
+        .clone()
+        .ok_or_else(|| anyhow!("The device storage directory must be set with --slot"))?;
+    let repo_dir = match &config.repository.path {
+        Some(repo_dir) => repo_dir.clone(),
+        None => slot_dir.join("repo"),
+    };
+    let interval = Duration::from_millis(*matches.get_one::<u64>("interval").unwrap());
+    let key = config.signing_key()?;
+
+    let session = dev::DevSession::new(&slot_dir, &repo_dir, key)?;
+    outln!(out, "Watching module:\t[{}]", watched_file);
+    outln!(out, "Device storage:\t[{}]", slot_dir.display());
+    outln!(out, "Repository:\t[{}]", repo_dir.display());
+    out.set("watched", watched_file);
+    out.set("slots", &slot_dir);
+    out.set("repository", &repo_dir);
+    out.emit();
+    dev::watch(watched_file, interval, |module_bin| {
+        match session.push(module_bin) {
+            Ok(None) => {
+                outln!(out, "Module already published");
+                out.set("outcome", "published");
+            }
+            Ok(Some(outcome)) => {
+                match &outcome {
+                    agent::Outcome::Installed { slot, info } => {
+                        outln!(out, "Installed {} into slot {}", info.version, slot)
+                    }
+                    outcome => outln!(out, "Not installed: {:?}", outcome),
+                }
+                out.set("outcome", outcome_value(&outcome));
+            }
+            Err(e) => {
+                outln!(out, "Error: {} [{}]", e, e.code());
+                out.set(
+                    "error",
+                    serde_json::json!({ "message": e.to_string(), "code": e.code() }),
+                );
+            }
+        }
+        out.emit();
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+fn agent_run(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    for (arg, setting) in [
+        ("device_id", "agent.device_id"),
+        ("channel", "agent.channel"),
+    ] {
+        if let Some(value) = matches.get_one::<String>(arg) {
+            config.set(setting, value)?;
+        }
+    }
+    let once = matches.get_flag("once");
+    let local = config.repository.path.is_some() || config.s3.bucket.is_some();
+    if config.repository.url.is_some() || !local {
+        run_agent(config, config.transport()?, once, out)
+    } else {
+        let repo = repo::Repository::new(config.repository_backend()?);
+        run_agent(config, agent::LocalTransport::new(repo), once, out)
+    }
+}
+
+fn run_agent(
+    config: &config::Config,
+    transport: impl agent::Transport,
+    once: bool,
+    out: &mut Output,
+) -> Result<(), Error> {
+    let agent = agent::Agent::new(
+        config.agent_config()?,
+        transport,
+        config.storage()?,
+        agent::SystemClock,
+    );
+    if !once {
+        agent.run();
+    }
+    print_outcome(&agent.run_once()?, out);
+
+    Ok(())
+}
+
+/// Return the JSON description of the outcome of an update cycle.
+fn outcome_value(outcome: &agent::Outcome) -> serde_json::Value {
+    match outcome {
+        agent::Outcome::UpToDate => serde_json::json!({ "outcome": "up_to_date" }),
+        agent::Outcome::PendingConfirmation => {
+            serde_json::json!({ "outcome": "pending_confirmation" })
+        }
+        agent::Outcome::Deferred { wait } => {
+            serde_json::json!({ "outcome": "deferred", "wait": wait.as_secs() })
+        }
+        agent::Outcome::Installed { slot, info } => serde_json::json!({
+            "outcome": "installed",
+            "slot": slot,
+            "version": info.version,
+            "build_id": info.build_id,
+        }),
+    }
+}
+
+fn print_outcome(outcome: &agent::Outcome, out: &mut Output) {
+    match outcome {
+        agent::Outcome::UpToDate => outln!(out, "Up to date"),
+        agent::Outcome::PendingConfirmation => {
+            outln!(
+                out,
+                "Not installed: the previous installation must be confirmed first"
+            )
+        }
+        agent::Outcome::Deferred { wait } => {
+            outln!(out, "Deferred for {} seconds", wait.as_secs())
+        }
+        agent::Outcome::Installed { slot, info } => {
+            outln!(out, "Installed {} into slot {}", info.version, slot)
+        }
+    }
+    out.set("outcome", outcome_value(outcome));
+}
+
+fn agent_confirm(config: &mut config::Config, out: &mut Output) -> Result<(), Error> {
+    let slots = agent::Slots::new(config.storage()?);
+    slots.confirm()?;
+    if let Some((slot, info)) = slots.state()?.active_info() {
+        outln!(out, "Confirmed {} in slot {}", info.version, slot);
+        out.set("slot", slot);
+        out.set("version", &info.version);
+    }
+
+    Ok(())
+}
+
+fn agent_rollback(config: &mut config::Config, out: &mut Output) -> Result<(), Error> {
+    let slots = agent::Slots::new(config.storage()?);
+    let slot = slots.rollback()?;
+    out.set("slot", slot);
+    match slots.state()?.info(slot) {
+        Some(info) => {
+            outln!(out, "Restored {} in slot {}", info.version, slot);
+            out.set("version", &info.version);
+        }
+        None => outln!(out, "Restored slot {}", slot),
+    }
+
+    Ok(())
+}
+
+fn agent_pin_golden(config: &mut config::Config, out: &mut Output) -> Result<(), Error> {
+    let slots = agent::Slots::new(config.storage()?);
+    let info = slots.pin_active_golden()?;
+    outln!(out, "Pinned {} as the golden module", info.version);
+    out.set("build_id", &info.build_id);
+    out.set("version", &info.version);
+
+    Ok(())
+}
+
+fn agent_restore_golden(config: &mut config::Config, out: &mut Output) -> Result<(), Error> {
+    let mut golden_keys = config.golden_keys()?;
+    if golden_keys.is_empty() {
+        golden_keys = config.trusted_keys()?;
+    }
+    if golden_keys.is_empty() && !config.agent.allow_unsigned.unwrap_or(false) {
+        return Err(WSError::ConfigError(
+            "no trusted keys are configured, and unsigned updates are not allowed".to_string(),
+        )
+        .into());
+    }
+    let ignored_sections: IgnoreList = config.agent.ignored_sections.iter().cloned().collect();
+    let slots = agent::Slots::new(config.storage()?);
+    let slot = slots.restore_golden(&golden_keys, &config.downgrade_keys()?, &ignored_sections)?;
+    out.set("slot", slot);
+    if let Some(info) = slots.state()?.info(slot) {
+        outln!(
+            out,
+            "Restored the golden module {} in slot {}",
+            info.version,
+            slot
+        );
+        out.set("version", &info.version);
+    }
+
+    Ok(())
+}
+
+fn agent_alias(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    let slots = agent::Slots::new(config.storage()?);
+    let name = match matches.get_one::<String>("name") {
+        Some(name) => name,
+        None => {
+            let aliases = slots.aliases()?;
+            for (name, build_id) in &aliases {
+                match slots.locate(build_id)? {
+                    Some((location, info)) => {
+                        outln!(
+                            out,
+                            "{}\t{}\t{} ({})",
+                            name,
+                            build_id,
+                            info.version,
+                            location
+                        )
+                    }
+                    None => outln!(out, "{}\t{}\tnot installed", name, build_id),
+                }
+            }
+            out.set("aliases", &aliases);
+            return Ok(());
+        }
+    };
+    if matches.get_flag("remove") {
+        match slots.remove_alias(name)? {
+            Some(build_id) => outln!(out, "Removed [{}], which pointed to {}", name, build_id),
+            None => outln!(out, "[{}] is not defined", name),
+        }
+        return Ok(());
+    }
+    let build_id = if matches.get_flag("active") {
+        let state = slots.state()?;
+        let (_, info) = state
+            .active_info()
+            .ok_or_else(|| WSError::NotFound("active slot".to_string()))?;
+        Some(info.build_id.clone())
+    } else {
+        matches.get_one::<String>("build_id").cloned()
+    };
+    if let Some(build_id) = build_id {
+        slots.set_alias(name, &build_id)?;
+    }
+    let (location, info) = slots.resolve_alias(name)?;
+    outln!(
+        out,
+        "[{}] points to {} {} ({})",
+        name,
+        info.version,
+        info.build_id,
+        location
+    );
+    out.set("name", name);
+    out.set("build_id", &info.build_id);
+    out.set("version", &info.version);
+    out.set("location", location);
+
+    Ok(())
+}
+
+fn apply(matches: &ArgMatches, config: &mut config::Config, out: &mut Output) -> Result<(), Error> {
+    if let Some(slot_dir) = matches.get_one::<String>("slot_dir") {
+        config.set("agent.slots", slot_dir)?;
+    }
+    if let Some(trusted) = matches.get_many::<String>("trusted") {
+        config.set(
+            "keys.trusted",
+            &trusted.cloned().collect::<Vec<_>>().join(","),
+        )?;
+    }
+    if matches.get_flag("allow_unsigned") {
+        config.set("agent.allow_unsigned", "true")?;
+    }
+    let source = matches.get_one::<String>("from").unwrap();
+    let version = matches.get_one::<semver::Version>("version").unwrap();
+
+    let is_url = source.starts_with("http://") || source.starts_with("https://");
+    let module_bin = if is_url {
+        let mut transport = agent::HttpTransport::new(source);
+        if let Some(proxy) = &config.repository.proxy {
+            transport = transport.proxy(proxy)?;
+        }
+        let mut module_bin = vec![];
+        agent::Transport::get_range(&transport, source, 0, None)?.read_to_end(&mut module_bin)?;
+        module_bin
+    } else {
+        std::fs::read(source)?
+    };
+    let signature_file = match matches.get_one::<String>("signature") {
+        Some(signature_file) => Some(signature_file.clone()),
+        None if !is_url => Some(format!("{}{}", source, batch::SIGNATURE_FILE_SUFFIX))
+            .filter(|signature_file| Path::new(signature_file).exists()),
+        None => None,
+    };
+    let mut transport = agent::OneShotTransport::new(module_bin, version.clone());
+    if let Some(signature_file) = signature_file {
+        transport = transport.signature(signature::Signature::from_bytes(&std::fs::read(
+            signature_file,
+        )?)?);
+    }
+
+    // The device identifier and the channel only appear in reports.
+    config
+        .agent
+        .device_id
+        .get_or_insert_with(|| "local".to_string());
+    config
+        .agent
+        .channel
+        .get_or_insert_with(|| "oneshot".to_string());
+    let agent = agent::Agent::new(
+        config.agent_config()?,
+        transport,
+        config.storage()?,
+        agent::SystemClock,
+    );
+    let outcome = agent.run_once()?;
+    if let agent::Outcome::Installed { .. } = outcome {
+        if matches.get_flag("confirm") {
+            agent.slots().confirm()?;
+        }
+    }
+    print_outcome(&outcome, out);
+
+    Ok(())
+}
+
+fn diagnostics_value(diagnosis: &agent::Diagnosis) -> serde_json::Value {
+    diagnosis
+        .diagnostics
+        .iter()
+        .map(|diagnostic| {
+            serde_json::json!({
+                "check": diagnostic.check,
+                "severity": diagnostic.severity.to_string(),
+                "message": diagnostic.message,
+                "repair": diagnostic.repair.as_ref().map(|repair| repair.to_string()),
+            })
+        })
+        .collect()
+}
+
+fn doctor(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    if let Some(slot_dir) = matches.get_one::<String>("slot_dir") {
+        config.set("agent.slots", slot_dir)?;
+    }
+    if let Some(trusted) = matches.get_many::<String>("trusted") {
+        config.set(
+            "keys.trusted",
+            &trusted.cloned().collect::<Vec<_>>().join(","),
+        )?;
+    }
+    let trusted_keys = config.trusted_keys()?;
+    let ignored_sections: IgnoreList = config.agent.ignored_sections.iter().cloned().collect();
+    let slots = agent::Slots::new(config.storage()?);
+
+    let mut diagnosis = slots.diagnose(&trusted_keys, &ignored_sections)?;
+    for diagnostic in &diagnosis.diagnostics {
+        outln!(out, "{}", diagnostic);
+    }
+    out.set("diagnostics", diagnostics_value(&diagnosis));
+    if matches.get_flag("repair") {
+        let repairs: Vec<_> = diagnosis.repairs().cloned().collect();
+        if !repairs.is_empty() {
+            for repair in &repairs {
+                slots.repair(repair)?;
+                outln!(out, "Repaired:\t{}", repair);
+                out.push("repairs", repair.to_string());
+            }
+            diagnosis = slots.diagnose(&trusted_keys, &ignored_sections)?;
+            for diagnostic in &diagnosis.diagnostics {
+                outln!(out, "Remaining: {}", diagnostic);
+            }
+            out.set("remaining", diagnostics_value(&diagnosis));
+        }
+    }
+    if diagnosis.diagnostics.is_empty() {
+        outln!(out, "No problems found");
+    }
+    out.set("healthy", diagnosis.is_healthy());
+    if !diagnosis.is_healthy() {
+        return Err(failure(
+            ExitStatus::DeviceState,
+            "The slots of the device need to be repaired",
+        ));
+    }
+
+    Ok(())
+}
+
+fn explain(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let code = matches.get_one::<String>("code").unwrap();
+    let explanation =
+        error::explain(code).ok_or_else(|| anyhow!("Unknown error code: [{}]", code))?;
+    outln!(out, "{} ({})", explanation.code, explanation.name);
+    outln!(out, "\nLikely causes:\n  {}", explanation.causes);
+    outln!(out, "\nRemediation:\n  {}", explanation.remediation);
+    out.set("code", explanation.code);
+    out.set("name", explanation.name);
+    out.set("causes", explanation.causes);
+    out.set("remediation", explanation.remediation);
+
+    Ok(())
+}
+
+fn print_schema(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let value = match matches.get_one::<String>("name") {
+        Some(name) => schema::schema(name)?,
+        None => {
+            let mut schemas = serde_json::Map::new();
+            for name in schema::SCHEMA_NAMES {
+                schemas.insert(name.to_string(), schema::schema(name)?);
+            }
+            serde_json::Value::Object(schemas)
+        }
+    };
+    outln!(out, "{}", serde_json::to_string_pretty(&value)?);
+    out.set("schema", value);
+
+    Ok(())
+}
+
+fn completions(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let shell = *matches.get_one::<clap_complete::Shell>("shell").unwrap();
+    let mut cli = cli();
+    let name = cli.get_name().to_string();
+    if out.json {
+        let mut script = vec![];
+        clap_complete::generate(shell, &mut cli, name, &mut script);
+        out.set("script", String::from_utf8(script)?);
+        return Ok(());
+    }
+    clap_complete::generate(shell, &mut cli, name, &mut std::io::stdout());
+
+    Ok(())
+}
+
+#[cfg(feature = "compression")]
+const BATCH_ACTIONS: [&str; 4] = ["verify", "resign", "strip", "recompress"];
+#[cfg(not(feature = "compression"))]
+const BATCH_ACTIONS: [&str; 3] = ["verify", "resign", "strip"];
+
+fn batch(matches: &ArgMatches, config: &mut config::Config, out: &mut Output) -> Result<(), Error> {
+    if let Some(key) = matches.get_one::<String>("key") {
+        config.set("keys.signing", key)?;
+    }
+    if let Some(trusted) = matches.get_many::<String>("trusted") {
+        config.set(
+            "keys.trusted",
+            &trusted.cloned().collect::<Vec<_>>().join(","),
+        )?;
+    }
+    let sections = matches
+        .get_many::<String>("section")
+        .into_iter()
+        .flatten()
+        .cloned();
+    let operation = match matches.get_one::<String>("action").unwrap().as_str() {
+        "verify" => {
+            let key_ring = config.trusted_keys()?;
+            ensure!(
+                !key_ring.is_empty(),
+                "Trusted keys must be set with --trusted"
+            );
+            batch::BatchOperation::Verify(key_ring)
+        }
+        "resign" => batch::BatchOperation::Resign(
+            config
+                .signing_key()?
+                .ok_or_else(|| anyhow!("The signing key must be set with --key"))?,
+        ),
+        "strip" => batch::BatchOperation::Strip(sections.collect()),
+        #[cfg(feature = "compression")]
+        "recompress" => batch::BatchOperation::Recompress(sections.collect()),
+        action => bail!("Unsupported action: [{}]", action),
+    };
+    let mut batch = batch::Batch::new(matches.get_one::<String>("dir").unwrap());
+    if let Some(jobs) = matches.get_one::<usize>("jobs") {
+        batch = batch.jobs(*jobs);
+    }
+
+    let report = batch.run(&operation)?;
+    for result in &report.processed {
+        if result.modified {
+            outln!(out, "Rewritten:\t{}", result.path.display());
+            out.push("rewritten", &result.path);
+        }
+    }
+    for (path, error) in &report.failures {
+        outln!(out, "Failed:\t{} ({})", path.display(), error);
+        out.push(
+            "failures",
+            serde_json::json!({ "path": path, "error": error }),
+        );
+    }
+    outln!(
+        out,
+        "{} processed, {} rewritten, {} failed",
+        report.processed.len(),
+        report.modified(),
+        report.failures.len()
+    );
+    out.set("processed", report.processed.len());
+    out.set("modified", report.modified());
+    out.set("failed", report.failures.len());
+    if !report.failures.is_empty() {
+        return Err(failure(
+            ExitStatus::PartialFailure,
+            "Some modules couldn't be processed",
+        ));
+    }
+
+    Ok(())
+}
+
+fn key_files(prefix: &str) -> (String, String) {
+    (format!("{}.key", prefix), format!("{}.pub", prefix))
+}
+
+fn key_generate(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let (sk_file, pk_file) = key_files(matches.get_one::<String>("output").unwrap());
+
+    let kp = signature::KeyPair::generate();
+    kp.save(&sk_file, &pk_file)?;
+    let key_id = signature::key_id_to_hex(kp.pk.key_id());
+    outln!(out, "Key identifier:\t{}", key_id);
+    outln!(out, "Secret key:\t[{}]", sk_file);
+    outln!(out, "Public key:\t[{}]", pk_file);
+    out.set("key_id", key_id);
+    out.set("secret_key", sk_file);
+    out.set("public_key", pk_file);
+
+    Ok(())
+}
+
+fn key_rotate(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    if let Some(key) = matches.get_one::<String>("key") {
+        config.set("keys.signing", key)?;
+    }
+    let (sk_file, pk_file) = key_files(matches.get_one::<String>("output").unwrap());
+    let dry_run = matches.get_flag("dry_run");
+    let retired_sk = config
+        .signing_key()?
+        .ok_or_else(|| anyhow!("The key to retire must be set with --key"))?;
+
+    let kp = signature::KeyPair::generate();
+    if !dry_run {
+        kp.save(&sk_file, &pk_file)?;
+    }
+    let sk = match retired_sk.domain() {
+        None => kp.sk.clone(),
+        Some(domain) => kp.sk.clone().in_domain(domain),
+    };
+    let (retired_key_id, key_id) = (
+        signature::key_id_to_hex(retired_sk.key_id()),
+        signature::key_id_to_hex(kp.pk.key_id()),
+    );
+    outln!(out, "Retired key:\t{}", retired_key_id);
+    outln!(out, "New key:\t{} [{}]", key_id, sk_file);
+    out.set("retired_key_id", retired_key_id);
+    out.set("key_id", key_id);
+    out.set("secret_key", &sk_file);
+    out.set("public_key", &pk_file);
+    if let Some(repo_dir) = matches.get_one::<String>("resign_dir") {
+        let migration = repo::SignatureMigration::new()
+            .retire_key(*retired_sk.key_id())
+            .dry_run(dry_run);
+        let repo = repo::Repository::new(repo::FsBackend::new(repo_dir)?);
+        print_migration_report(&repo.migrate_signatures(&sk, &migration)?, out)?;
+    }
+    outln!(
+        out,
+        "Devices must trust [{}] before the retired key is revoked",
+        pk_file
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+fn key_rotate_encryption(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    if let Some(repo_dir) = matches.get_one::<String>("repo") {
+        config.set("repository.path", repo_dir)?;
+    }
+    let repo_dir = config
+        .repository
+        .path
+        .clone()
+        .ok_or_else(|| anyhow!("The repository directory must be set with --repo"))?;
+    let (sk_file, pk_file) = key_files(matches.get_one::<String>("output").unwrap());
+    let dry_run = matches.get_flag("dry_run");
+    let retired_sk = encryption::EncryptionSecretKey::from_bytes(&std::fs::read(
+        matches.get_one::<String>("key").unwrap(),
+    )?)?;
+
+    let kp = encryption::EncryptionKeyPair::generate();
+    let mut recipients = vec![kp.pk.clone()];
+    for pk_file in matches
+        .get_many::<String>("recipient")
+        .into_iter()
+        .flatten()
+    {
+        recipients.push(encryption::EncryptionPublicKey::from_bytes(
+            &std::fs::read(pk_file)?,
+        )?);
+    }
+    if !dry_run {
+        std::fs::write(&sk_file, kp.sk.to_bytes())?;
+        std::fs::write(&pk_file, kp.pk.to_bytes())?;
+    }
+    let (retired_key_id, key_id) = (
+        signature::key_id_to_hex(retired_sk.key_id()),
+        signature::key_id_to_hex(kp.pk.key_id()),
+    );
+    outln!(out, "Retired key:\t{}", retired_key_id);
+    outln!(out, "New key:\t{} [{}]", key_id, sk_file);
+    out.set("retired_key_id", retired_key_id);
+    out.set("key_id", key_id);
+    out.set("secret_key", &sk_file);
+    out.set("public_key", &pk_file);
+
+    let repo = repo::Repository::new(repo::FsBackend::new(&repo_dir)?);
+    let report = repo.rotate_encryption_keys(&retired_sk, &recipients, dry_run)?;
+    for artifact in &report.rekeyed {
+        outln!(
+            out,
+            "Rekeyed:\t{} -> {}",
+            artifact.build_id,
+            artifact.new_build_id
+        );
+        out.push(
+            "rekeyed",
+            serde_json::json!({
+                "build_id": artifact.build_id,
+                "new_build_id": artifact.new_build_id,
+            }),
+        );
+    }
+    for (build_id, error) in &report.failures {
+        outln!(out, "Failed:\t{} ({})", build_id, error);
+        out.push(
+            "failures",
+            serde_json::json!({ "build_id": build_id, "error": error }),
+        );
+    }
+    outln!(
+        out,
+        "{} rekeyed, {} failed",
+        report.rekeyed.len(),
+        report.failures.len()
+    );
+    out.set("failed", report.failures.len());
+    if !report.failures.is_empty() {
+        return Err(failure(
+            ExitStatus::PartialFailure,
+            "Some artifacts couldn't be rekeyed",
+        ));
+    }
+    outln!(
+        out,
+        "Devices must be given [{}] to decrypt the rekeyed artifacts",
+        sk_file
+    );
+
+    Ok(())
+}
+
+fn key_revoke(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    if let Some(key) = matches.get_one::<String>("key") {
+        config.set("keys.signing", key)?;
+    }
+    let input_file = matches.get_one::<String>("input").unwrap();
+    let output_file = matches.get_one::<String>("output").unwrap();
+    let root_file = matches.get_one::<String>("root").unwrap();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let effective_at = matches
+        .get_one::<u64>("effective_at")
+        .copied()
+        .unwrap_or(now);
+
+    let root_sk = signature::SecretKey::from_bytes(&std::fs::read(root_file)?)?;
+    let mut module = Module::deserialize_from_file(input_file)?;
+    let mut list = match module.revocation_list()? {
+        Some(list) => list.verify(&root_sk.public_key())?.clone(),
+        None => revocation::RevocationList::default(),
+    };
+    for key in matches.get_many::<String>("revoke").unwrap() {
+        let key_id = if Path::new(key).exists() {
+            *signature::PublicKey::from_bytes(&std::fs::read(key)?)?.key_id()
+        } else {
+            parse_key_id(key)?
+        };
+        list.revocations
+            .retain(|revocation| revocation.key_id != key_id);
+        list.revocations.push(revocation::Revocation {
+            key_id,
+            effective_at,
+        });
+        outln!(out, "Revoked:\t{}", signature::key_id_to_hex(&key_id));
+        out.push("revoked", signature::key_id_to_hex(&key_id));
+    }
+    list.issued_at = now;
+    module.set_revocation_list(&list.sign(&root_sk)?)?;
+    module.serialize_to_file(output_file)?;
+    out.set("output", output_file);
+    out.set("effective_at", effective_at);
+    if let Some(sk) = config.signing_key()? {
+        let module_bin = std::fs::read(output_file)?;
+        std::fs::write(
+            format!("{}{}", output_file, batch::SIGNATURE_FILE_SUFFIX),
+            sk.sign_module(&module_bin).to_bytes(),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn migrate_signatures(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    for (arg, setting) in [("repo", "repository.path"), ("key", "keys.signing")] {
+        if let Some(value) = matches.get_one::<String>(arg) {
+            config.set(setting, value)?;
+        }
+    }
+    let repo_dir = config
+        .repository
+        .path
+        .clone()
+        .ok_or_else(|| anyhow!("The repository directory must be set with --repo"))?;
+    let sk = config
+        .signing_key()?
+        .ok_or_else(|| anyhow!("The signing key must be set with --key"))?;
+    let mut migration = repo::SignatureMigration::new()
+        .sign_missing(matches.get_flag("sign_missing"))
+        .dry_run(matches.get_flag("dry_run"));
+    for key_id in matches.get_many::<String>("retire").into_iter().flatten() {
+        migration = migration.retire_key(parse_key_id(key_id)?);
+    }
+
+    let repo = repo::Repository::new(repo::FsBackend::new(&repo_dir)?);
+    print_migration_report(&repo.migrate_signatures(&sk, &migration)?, out)
+}
+
+fn parse_key_id(key_id: &str) -> Result<signature::KeyId, Error> {
+    Hex::decode_to_vec(key_id, None)
+        .ok()
+        .and_then(|key_id| signature::KeyId::try_from(key_id).ok())
+        .ok_or_else(|| anyhow!("Invalid key identifier: [{}]", key_id))
+}
+
+fn print_migration_report(report: &repo::MigrationReport, out: &mut Output) -> Result<(), Error> {
+    for signature in &report.signatures {
+        if signature.action != repo::MigrationAction::Unchanged {
+            outln!(out, "{:?}:\t{}", signature.action, signature.object);
+            out.push(
+                "signatures",
+                serde_json::json!({
+                    "action": format!("{:?}", signature.action).to_lowercase(),
+                    "object": signature.object,
+                }),
+            );
+        }
+    }
+    for (object, error) in &report.failures {
+        outln!(out, "Failed:\t{} ({})", object, error);
+        out.push(
+            "failures",
+            serde_json::json!({ "object": object, "error": error }),
+        );
+    }
+    let counts = [
+        ("unchanged", repo::MigrationAction::Unchanged),
+        ("upgraded", repo::MigrationAction::Upgraded),
+        ("resigned", repo::MigrationAction::Resigned),
+        ("signed", repo::MigrationAction::Signed),
+    ]
+    .map(|(name, action)| (name, report.count(action)));
+    outln!(
+        out,
+        "{} unchanged, {} upgraded, {} re-signed, {} signed, {} failed",
+        counts[0].1,
+        counts[1].1,
+        counts[2].1,
+        counts[3].1,
+        report.failures.len()
+    );
+    for (name, count) in counts {
+        out.set(name, count);
+    }
+    out.set("failed", report.failures.len());
+    if !report.failures.is_empty() {
+        return Err(failure(
+            ExitStatus::PartialFailure,
+            "Some signatures couldn't be migrated",
+        ));
+    }
+
+    Ok(())
+}
+
+fn plan_patches(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    if let Some(repo_dir) = matches.get_one::<String>("repo") {
+        config.set("repository.path", repo_dir)?;
+    }
+    let repo_dir = config
+        .repository
+        .path
+        .clone()
+        .ok_or_else(|| anyhow!("The repository directory must be set with --repo"))?;
+    let channel = matches.get_one::<String>("channel").unwrap();
+    let inventory_json = std::fs::read(matches.get_one::<String>("inventory").unwrap())?;
+    let inventory: repo::FleetInventory = serde_json::from_slice(&inventory_json)?;
+    let mut model = repo::LinearCostModel::default();
+    if let Some(&ratio) = matches.get_one::<f64>("ratio") {
+        model.size_ratio = ratio;
+    }
+    if let Some(&throughput) = matches.get_one::<f64>("throughput") {
+        model.throughput = throughput;
+    }
+    let mut planning = repo::PatchPlanning::new();
+    if let Some(&max_time) = matches.get_one::<u64>("max_time") {
+        planning = planning.max_generation_time(Duration::from_secs(max_time));
+    }
+    if let Some(&min_savings) = matches.get_one::<u64>("min_savings") {
+        planning = planning.min_savings(min_savings);
+    }
+
+    let repo = repo::Repository::new(repo::FsBackend::new(&repo_dir)?);
+    let latest = repo
+        .versions(channel)?
+        .pop()
+        .ok_or_else(|| anyhow!("The channel [{}] has no releases", channel))?;
+    let plan = repo.plan_patches(&latest.build_id, &inventory, &model, &planning)?;
+    outln!(out, "Target:\t{} [{}]", latest.version, latest.build_id);
+    out.set(
+        "target",
+        serde_json::json!({ "version": latest.version, "build_id": latest.build_id }),
+    );
+    for patch in &plan.patches {
+        outln!(
+            out,
+            "Patch:\t{} ({} devices, ~{} bytes, ~{:.1}s, saves {} bytes)",
+            patch.base_build_id,
+            patch.devices,
+            patch.size,
+            patch.generation_time.as_secs_f64(),
+            patch.savings
+        );
+        out.push(
+            "patches",
+            serde_json::json!({
+                "base_build_id": patch.base_build_id,
+                "devices": patch.devices,
+                "size": patch.size,
+                "generation_time": patch.generation_time.as_secs_f64(),
+                "savings": patch.savings,
+            }),
+        );
+    }
+    out.set("devices", inventory.devices());
+    out.set("current_transfer", plan.current_transfer);
+    out.set("planned_transfer", plan.planned_transfer);
+    out.set("generation_time", plan.generation_time.as_secs_f64());
+    outln!(
+        out,
+        "{} devices, {} bytes to transfer, {} bytes once the {} planned patches exist (~{:.1}s)",
+        inventory.devices(),
+        plan.current_transfer,
+        plan.planned_transfer,
+        plan.patches.len(),
+        plan.generation_time.as_secs_f64()
+    );
+
+    Ok(())
+}
+
+fn encode_artifacts(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    if let Some(repo_dir) = matches.get_one::<String>("repo") {
+        config.set("repository.path", repo_dir)?;
+    }
+    let repo_dir = config
+        .repository
+        .path
+        .clone()
+        .ok_or_else(|| anyhow!("The repository directory must be set with --repo"))?;
+    let codecs = match matches.get_many::<String>("codec") {
+        Some(names) => codec::Codecs::builtin().only(&names.collect::<Vec<_>>())?,
+        None => codec::Codecs::builtin(),
+    };
+    if codecs.is_empty() {
+        return Err(anyhow!("wasi-update was built without any codec"));
+    }
+
+    let repo = repo::Repository::new(repo::FsBackend::new(&repo_dir)?);
+    let build_ids: Vec<String> = match matches.get_many::<String>("build_id") {
+        Some(build_ids) => build_ids.cloned().collect(),
+        None => repo.build_ids()?,
+    };
+    for build_id in &build_ids {
+        let size = repo.info(build_id)?.size;
+        for name in codecs.names() {
+            let encoded = repo.add_encoding(build_id, codecs.get(&name)?.as_ref())?;
+            outln!(
+                out,
+                "Encoded:\t[{}] {}: {} -> {} bytes",
+                build_id,
+                name,
+                size,
+                encoded.size
+            );
+            out.push(
+                "encodings",
+                serde_json::json!({
+                    "build_id": build_id,
+                    "codec": name,
+                    "size": size,
+                    "encoded_size": encoded.size,
+                }),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "zstd")]
+fn make_patch(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    for (arg, setting) in [("repo", "repository.path"), ("key", "keys.signing")] {
+        if let Some(value) = matches.get_one::<String>(arg) {
+            config.set(setting, value)?;
+        }
+    }
+    let repo_dir = config
+        .repository
+        .path
+        .clone()
+        .ok_or_else(|| anyhow!("The repository directory must be set with --repo"))?;
+    let base_build_id = matches.get_one::<String>("from").unwrap();
+    let build_id = matches.get_one::<String>("to").unwrap();
+    let mut patcher = codec::DictionaryPatch::new();
+    if matches.get_flag("code_section") {
+        patcher = patcher.source(codec::DictionarySource::CodeSection);
+    }
+    if let Some(memory_budget) = matches.get_one::<u64>("memory_budget") {
+        patcher = patcher.memory_budget(*memory_budget);
+    }
+
+    let repo = repo::Repository::new(repo::FsBackend::new(&repo_dir)?);
+    let size = repo.add_dictionary_patch(base_build_id, build_id, &patcher)?;
+    if let Some(sk) = config.signing_key()? {
+        let patch = repo
+            .patch(base_build_id, build_id)?
+            .ok_or_else(|| anyhow!("The patch was not stored"))?;
+        let signature = sk.sign_patch(base_build_id, build_id, &patch)?;
+        repo.add_patch_signature(base_build_id, build_id, &signature)?;
+    }
+    let module_size = repo.info(build_id)?.size;
+    outln!(
+        out,
+        "Patch:\t[{}] -> [{}]: {} -> {} bytes",
+        base_build_id,
+        build_id,
+        module_size,
+        size
+    );
+    out.set("base_build_id", base_build_id);
+    out.set("build_id", build_id);
+    out.set("module_size", module_size);
+    out.set("patch_size", size);
+
+    Ok(())
+}
+
+#[cfg(feature = "oci")]
+fn push(matches: &ArgMatches, config: &mut config::Config, out: &mut Output) -> Result<(), Error> {
+    if let Some(key) = matches.get_one::<String>("key") {
+        config.set("keys.signing", key)?;
+    }
+    let reference = oci::OciReference::parse(matches.get_one::<String>("to").unwrap())?;
+    let module_bin = std::fs::read(matches.get_one::<String>("input").unwrap())?;
+    let mut bundle = oci::OciBundle::new(module_bin);
+    if let Some(version) = matches.get_one::<semver::Version>("version") {
+        bundle = bundle.version(version.clone());
+    }
+    if let Some(sk) = config.signing_key()? {
+        let signature = sk.sign_module(&bundle.module);
+        bundle = bundle.signature(signature);
+    }
+
+    let digest = config
+        .oci_client(&reference.registry)?
+        .push(&reference, &bundle)?;
+    outln!(out, "{}@{}", reference, digest);
+    out.set("reference", reference.to_string());
+    out.set("digest", digest.to_string());
+
+    Ok(())
+}
+
+#[cfg(feature = "oci")]
+fn pull(matches: &ArgMatches, config: &mut config::Config, out: &mut Output) -> Result<(), Error> {
+    if let Some(trusted) = matches.get_many::<String>("trusted") {
+        config.set(
+            "keys.trusted",
+            &trusted.cloned().collect::<Vec<_>>().join(","),
+        )?;
+    }
+    let reference = oci::OciReference::parse(matches.get_one::<String>("from").unwrap())?;
+    let output_file = matches.get_one::<String>("output").unwrap();
+
+    let bundle = config.oci_client(&reference.registry)?.pull(&reference)?;
+    save_bundle(&bundle, output_file, config, out)
+}
+
+/// Verify a pulled bundle with the trusted keys, if any, and save the module and its signature.
+#[cfg(feature = "oci")]
+fn save_bundle(
+    bundle: &oci::OciBundle,
+    output_file: &str,
+    config: &config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    let key_ring = config.trusted_keys()?;
+    if !key_ring.is_empty() {
+        bundle.verify(&key_ring)?;
+    }
+    std::fs::write(output_file, &bundle.module)?;
+    if let Some(signature) = &bundle.signature {
+        std::fs::write(
+            format!("{}{}", output_file, batch::SIGNATURE_FILE_SUFFIX),
+            signature.to_bytes(),
+        )?;
+    }
+    match &bundle.version {
+        Some(version) => outln!(out, "{} ({})", bundle.build_id(), version),
+        None => outln!(out, "{}", bundle.build_id()),
+    }
+    out.set("build_id", bundle.build_id());
+    out.set("version", &bundle.version);
+    out.set("output", output_file);
+
+    Ok(())
+}
+
+#[cfg(feature = "registry")]
+fn publish_package(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    if let Some(key) = matches.get_one::<String>("key") {
+        config.set("keys.signing", key)?;
+    }
+    let package = registry::PackageName::parse(matches.get_one::<String>("package").unwrap())?;
+    let version = matches.get_one::<semver::Version>("version").unwrap();
+    let module_bin = std::fs::read(matches.get_one::<String>("input").unwrap())?;
+    if let Some(advisories) = matches.get_one::<String>("advisories") {
+        let database = advisories::AdvisoryDatabase::from_file(advisories)?;
+        Module::deserialize(&mut Cursor::new(&module_bin))?.check_dependencies(&database)?;
+    }
+    let mut bundle = oci::OciBundle::new(module_bin).version(version.clone());
+    if let Some(sk) = config.signing_key()? {
+        let signature = sk.sign_module(&bundle.module);
+        bundle = bundle.signature(signature);
+    }
+
+    let registry = config.package_registry(matches.get_one::<String>("registry").unwrap())?;
+    let digest = registry.publish(&package, &bundle)?;
+    outln!(out, "{}@{} ({})", package, version, digest);
+    out.set("package", package.to_string());
+    out.set("version", version);
+    out.set("digest", digest.to_string());
+
+    Ok(())
+}
+
+#[cfg(feature = "registry")]
+fn fetch_package(
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    if let Some(trusted) = matches.get_many::<String>("trusted") {
+        config.set(
+            "keys.trusted",
+            &trusted.cloned().collect::<Vec<_>>().join(","),
+        )?;
+    }
+    let package = registry::PackageName::parse(matches.get_one::<String>("package").unwrap())?;
+    let requirement = matches
+        .get_one::<semver::VersionReq>("version")
+        .cloned()
+        .unwrap_or(semver::VersionReq::STAR);
+    let output_file = matches.get_one::<String>("output").unwrap();
+
+    let registry = config.package_registry(matches.get_one::<String>("registry").unwrap())?;
+    let bundle = registry.fetch(&package, &requirement)?;
+    save_bundle(&bundle, output_file, config, out)
+}
+
+fn repo_publish(
+    repo: &repo::Repository<impl repo::Backend>,
+    matches: &ArgMatches,
+    config: &mut config::Config,
+    out: &mut Output,
+) -> Result<(), Error> {
+    if let Some(key) = matches.get_one::<String>("key") {
+        config.set("keys.signing", key)?;
+    }
+    let input_file = matches.get_one::<String>("input").unwrap();
+    let channel = matches.get_one::<String>("channel").unwrap();
+    let version = matches.get_one::<semver::Version>("version").unwrap();
+
+    let module_bin = std::fs::read(input_file)?;
+    let info =
+        repo.publish_in_domain(&module_bin, version, channel, config.keys.domain.as_deref())?;
+    if let Some(sk) = config.signing_key()? {
+        repo.add_signature(&info.build_id, &sk.sign_module(&module_bin))?;
+    }
+    outln!(
+        out,
+        "{} ({}) published on [{}]",
+        info.build_id,
+        info.version,
+        channel
+    );
+    out.set("build_id", &info.build_id);
+    out.set("version", &info.version);
+    out.set("channel", channel);
+    if matches.get_flag("notes") {
+        match repo.compose_release_notes(&info.build_id, channel)? {
+            Some(notes) => {
+                repo.add_release_notes(&info.build_id, &notes)?;
+                outln!(
+                    out,
+                    "Release notes attached, relative to {}",
+                    notes.previous_version.as_deref().unwrap_or_default()
+                );
+                out.set("notes", &notes);
+            }
+            None => outln!(
+                out,
+                "No previous version on [{}], no release notes attached",
+                channel
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+fn repo_release_notes(
+    repo: &repo::Repository<impl repo::Backend>,
+    matches: &ArgMatches,
+    out: &mut Output,
+) -> Result<(), Error> {
+    let build_id = matches.get_one::<String>("build_id").unwrap();
+
+    let notes = repo
+        .release_notes(build_id)?
+        .ok_or_else(|| anyhow!("No release notes attached to [{}]", build_id))?;
+    print_release_notes(&notes, matches, out)
+}
+
+fn repo_promote(
+    repo: &repo::Repository<impl repo::Backend>,
+    matches: &ArgMatches,
+    out: &mut Output,
+) -> Result<(), Error> {
+    let build_id = matches.get_one::<String>("build_id").unwrap();
+    let channel = matches.get_one::<String>("to").unwrap();
+
+    let release = repo.promote(build_id, channel)?;
+    outln!(
+        out,
+        "{} ({}) published on [{}]",
+        release.build_id,
+        release.version,
+        channel
+    );
+    out.set("build_id", &release.build_id);
+    out.set("version", &release.version);
+    out.set("channel", channel);
+
+    Ok(())
+}
+
+fn repo_pin_golden(
+    repo: &repo::Repository<impl repo::Backend>,
+    matches: &ArgMatches,
+    out: &mut Output,
+) -> Result<(), Error> {
+    let build_id = matches.get_one::<String>("build_id").unwrap();
+    let channel = matches.get_one::<String>("channel").unwrap();
+
+    let release = repo.pin_golden(channel, build_id)?;
+    outln!(
+        out,
+        "{} ({}) pinned as the golden module of [{}]",
+        release.build_id,
+        release.version,
+        channel
+    );
+    out.set("build_id", &release.build_id);
+    out.set("version", &release.version);
+    out.set("channel", channel);
+
+    Ok(())
+}
+
+fn repo_unpin_golden(
+    repo: &repo::Repository<impl repo::Backend>,
+    matches: &ArgMatches,
+    out: &mut Output,
+) -> Result<(), Error> {
+    let channel = matches.get_one::<String>("channel").unwrap();
+
+    let release = repo
+        .golden(channel)?
+        .ok_or_else(|| failure(ExitStatus::NotFound, "No golden module is pinned"))?;
+    repo.unpin_golden(channel)?;
+    outln!(
+        out,
+        "{} ({}) unpinned from [{}]",
+        release.build_id,
+        release.version,
+        channel
+    );
+    out.set("build_id", &release.build_id);
+    out.set("version", &release.version);
+    out.set("channel", channel);
+
+    Ok(())
+}
+
+fn repo_gc(
+    repo: &repo::Repository<impl repo::Backend>,
+    matches: &ArgMatches,
+    out: &mut Output,
+) -> Result<(), Error> {
+    let dry_run = matches.get_flag("dry_run");
+    let gc = repo::GarbageCollection::new()
+        .min_age(*matches.get_one::<u64>("min_age").unwrap())
+        .dry_run(dry_run);
+
+    let report = repo.collect_garbage(&gc)?;
+    for object in &report.objects {
+        outln!(
+            out,
+            "{}:\t{}",
+            if dry_run { "Would remove" } else { "Removed" },
+            object
+        );
+    }
+    outln!(
+        out,
+        "{} artifacts, {} objects",
+        report.artifacts.len(),
+        report.objects.len()
+    );
+    out.set("dry_run", dry_run);
+    out.set("artifacts", &report.artifacts);
+    out.set("objects", &report.objects);
+
+    Ok(())
+}
+
+fn serve(matches: &ArgMatches, config: &mut config::Config, out: &mut Output) -> Result<(), Error> {
+    for (arg, setting) in [
+        ("repo", "repository.path"),
+        ("listen", "repository.listen"),
+        ("key", "keys.signing"),
+    ] {
+        if let Some(value) = matches.get_one::<String>(arg) {
+            config.set(setting, value)?;
+        }
+    }
+    let repo_dir = config
+        .repository
+        .path
+        .clone()
+        .ok_or_else(|| anyhow!("The repository directory must be set with --repo"))?;
+    let listen = config
+        .repository
+        .listen
+        .clone()
+        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+
+    let repo = repo::Repository::new(repo::FsBackend::new(&repo_dir)?);
+    outln!(out, "Serving repository:\t[{}]", repo_dir.display());
+    outln!(out, "Listening on:\t{}", listen);
+    out.set("repository", &repo_dir);
+    out.set("listen", &listen);
+    let mut server = server::Server::new(repo);
+    if let Some(sk) = config.signing_key()? {
+        let key_id = signature::key_id_to_hex(sk.key_id());
+        outln!(out, "Signing responses with:\t{}", key_id);
+        out.set("manifest_key_id", key_id);
+        server = server.manifest_key(sk);
+    }
+    out.emit();
+    server.serve(listen.as_str())?;
+
+    Ok(())
+}
+
+fn convert(matches: &ArgMatches, out: &mut Output) -> Result<(), Error> {
+    let input_file = matches.get_one::<String>("input").unwrap();
+    let output_file = matches.get_one::<String>("output").unwrap();
+    outln!(out, "Reading module from:\t[{}]", input_file);
+    out.set("input", input_file);
     let module_bin = std::fs::read(input_file)?;
     let module = {
         let mut reader = Cursor::new(&module_bin);
         Module::deserialize(&mut reader)?
     };
-    println!("Module size:\t{} bytes", module_bin.len());
+    outln!(out, "Module size:\t{} bytes", module_bin.len());
+    out.set("module_size", module_bin.len());
 
-    let module_type = guess_module_type(&module)?;
-    println!("Module type:\t{}", module_type);
+    let module_type = guess_module_type(&module, out)?;
+    outln!(out, "Module type:\t{}", module_type);
+    out.set("module_type", module_type.to_string());
 
     static IMPORTED_MODULE_NAME: &str = "wasi_snapshot_preview1";
     let adapter = match module_type {
@@ -143,17 +3974,20 @@ fn main() -> Result<(), Error> {
         .validate(true)
         .encode()?;
 
-    println!("Verifying component");
+    outln!(out, "Verifying component");
     Module::deserialize(&mut Cursor::new(&component_bin))?;
 
-    println!("Writing component to:\t[{}]", output_file);
+    outln!(out, "Writing component to:\t[{}]", output_file);
     let mut fp = File::create(output_file)?;
     fp.write_all(&component_bin)?;
-    println!(
+    outln!(
+        out,
         "Component size:\t{} bytes (overhead: {} bytes)",
         component_bin.len(),
         component_bin.len() - module_bin.len()
     );
+    out.set("output", output_file);
+    out.set("component_size", component_bin.len());
 
     Ok(())
 }
@@ -0,0 +1,143 @@
+//! Accounting of the memory holding module data.
+//!
+//! Parsing, diffing and patching charge the buffers they allocate for module data to a limit set
+//! for the current thread with `with_memory_limit()`, before allocating them. Going over the limit
+//! fails with `WSError::MemoryLimit`, instead of getting the process killed on constrained hosts.
+//! This doesn't depend on the global allocator, and leaves out small allocations such as bookkeeping.
+
+use crate::error::*;
+
+use std::cell::Cell;
+use std::io::{self, Read};
+
+thread_local! {
+    static USAGE: Cell<MemoryUsage> = const { Cell::new(MemoryUsage::unlimited()) };
+}
+
+/// Memory charged to the limit of the current thread.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The limit, or `None` if there is none.
+    pub limit: Option<u64>,
+    /// Bytes currently charged.
+    pub used: u64,
+    /// Largest number of bytes charged at once.
+    pub peak: u64,
+}
+
+impl MemoryUsage {
+    const fn unlimited() -> Self {
+        MemoryUsage {
+            limit: None,
+            used: 0,
+            peak: 0,
+        }
+    }
+}
+
+/// Run `f` with at most `limit` bytes of module data in memory on the current thread.
+///
+/// Usage is counted from zero, and the previous limit is restored afterwards. Data returned by `f`,
+/// such as a parsed module, stays charged until then.
+pub fn with_memory_limit<T>(limit: u64, f: impl FnOnce() -> T) -> T {
+    struct Restore(MemoryUsage);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            USAGE.with(|usage| usage.set(self.0));
+        }
+    }
+
+    let _restore = Restore(USAGE.with(|usage| {
+        usage.replace(MemoryUsage {
+            limit: Some(limit),
+            used: 0,
+            peak: 0,
+        })
+    }));
+    f()
+}
+
+/// Return the memory limit of the current thread, if there is one.
+pub fn memory_limit() -> Option<u64> {
+    memory_usage().limit
+}
+
+/// Return the memory charged to the limit of the current thread.
+pub fn memory_usage() -> MemoryUsage {
+    USAGE.with(|usage| usage.get())
+}
+
+/// Memory charged to the limit of the current thread, until it is dropped.
+#[must_use]
+#[derive(Debug)]
+pub(crate) struct Reservation(u64);
+
+impl Reservation {
+    /// Charge `bytes`, or fail if that would go over the limit.
+    pub(crate) fn new(bytes: u64) -> Result<Self, WSError> {
+        let mut reservation = Reservation(0);
+        reservation.grow(bytes)?;
+        Ok(reservation)
+    }
+
+    /// Charge `bytes` more.
+    pub(crate) fn grow(&mut self, bytes: u64) -> Result<(), WSError> {
+        USAGE.with(|usage| {
+            let mut current = usage.get();
+            let Some(limit) = current.limit else {
+                return Ok(());
+            };
+            let required = current.used.saturating_add(bytes);
+            if required > limit {
+                return Err(WSError::MemoryLimit {
+                    required,
+                    available: limit,
+                });
+            }
+            current.used = required;
+            current.peak = current.peak.max(required);
+            usage.set(current);
+            self.0 += bytes;
+            Ok(())
+        })
+    }
+
+    /// Keep the memory charged until the end of the limit, for data handed over to the caller.
+    pub(crate) fn retain(self) {
+        std::mem::forget(self)
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        USAGE.with(|usage| {
+            let mut current = usage.get();
+            current.used = current.used.saturating_sub(self.0);
+            usage.set(current);
+        })
+    }
+}
+
+/// Read everything from `reader`, charging the buffer to `reservation` before growing it.
+pub(crate) fn read_to_end(
+    reader: &mut impl Read,
+    reservation: &mut Reservation,
+) -> Result<Vec<u8>, WSError> {
+    let mut data = vec![];
+    let mut chunk = [0u8; 16 * 1024];
+    loop {
+        let len = match reader.read(&mut chunk) {
+            Ok(0) => return Ok(data),
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if data.len() + len > data.capacity() {
+            let capacity = (data.capacity() * 2).max(data.len() + len);
+            reservation.grow((capacity - data.capacity()) as _)?;
+            data.reserve_exact(capacity - data.len());
+        }
+        data.extend_from_slice(&chunk[..len]);
+    }
+}
@@ -0,0 +1,93 @@
+use crate::error::*;
+use crate::wasm_module::*;
+
+use log::*;
+
+/// What to do when a custom section being appended has the same name as an existing one.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Fail with an error.
+    #[default]
+    Error,
+    /// Keep the existing sections, and skip the new ones.
+    KeepExisting,
+    /// Remove the existing sections, and add the new ones.
+    Replace,
+    /// Keep both.
+    Append,
+}
+
+impl Module {
+    /// Append the custom sections of `other` to the module.
+    ///
+    /// Standard sections of `other` are not copied; they must also be present in the module, and be identical.
+    /// This allows attaching sections from a metadata-only module, or from another build of the same code.
+    /// Custom sections already present with the same content are skipped, and conflicting ones are handled
+    /// according to `policy`. The module is left unchanged if an error is returned.
+    ///
+    /// Return the number of sections that have been added.
+    pub fn append_sections(
+        &mut self,
+        other: &Module,
+        policy: ConflictPolicy,
+    ) -> Result<usize, WSError> {
+        if self.header != other.header {
+            return Err(WSError::UnsupportedModuleType);
+        }
+        for section in &other.sections {
+            if let Section::Standard(s) = section {
+                if !self.sections.contains(section) {
+                    return Err(WSError::SectionConflict(s.id().to_string()));
+                }
+            }
+        }
+        let mut names = vec![];
+        for section in &other.sections {
+            if let Section::Custom(s) = section {
+                if !names.contains(&s.name()) {
+                    names.push(s.name());
+                }
+            }
+        }
+        let mut sections = self.sections.clone();
+        let mut count = 0;
+        for name in names {
+            let is_named = |s: &Section| matches!(s, Section::Custom(s) if s.name() == name);
+            let new: Vec<Section> = other
+                .sections
+                .iter()
+                .filter(|s| is_named(s))
+                .cloned()
+                .collect();
+            let existing: Vec<Section> = sections.iter().filter(|s| is_named(s)).cloned().collect();
+            if existing == new {
+                continue;
+            }
+            let added: Vec<Section> = match policy {
+                _ if existing.is_empty() => new,
+                ConflictPolicy::Error => {
+                    return Err(WSError::SectionConflict(format!(
+                        "custom section [{}]",
+                        name
+                    )))
+                }
+                ConflictPolicy::KeepExisting => {
+                    debug!("Keeping existing section [{}]", name);
+                    continue;
+                }
+                ConflictPolicy::Replace => {
+                    debug!("Replacing section [{}]", name);
+                    sections.retain(|s| !is_named(s));
+                    new
+                }
+                ConflictPolicy::Append => {
+                    new.into_iter().filter(|s| !existing.contains(s)).collect()
+                }
+            };
+            count += added.len();
+            sections.extend(added);
+        }
+        self.sections = sections;
+        Ok(count)
+    }
+}
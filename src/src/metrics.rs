@@ -0,0 +1,46 @@
+//! Metrics emitted by the update agent.
+//!
+//! Counters and histograms are identified by name, so that they can be forwarded to any
+//! monitoring system, such as Prometheus or StatsD.
+
+/// Counter: bytes downloaded.
+pub const BYTES_DOWNLOADED: &str = "bytes_downloaded";
+
+/// Counter: download attempts that failed and were retried.
+pub const DOWNLOAD_RETRIES: &str = "download_retries";
+
+/// Histogram: time spent verifying a download, in seconds.
+pub const VERIFY_SECONDS: &str = "verify_seconds";
+
+/// Counter: downloads that didn't pass verification.
+pub const VERIFY_FAILURES: &str = "verify_failures";
+
+/// Histogram: ratio between the size of what was downloaded to get an update (patches, or the
+/// full module, possibly encoded) and the size of the full module. It is below `1.0` when
+/// patches or a codec could be used.
+pub const PATCH_RATIO: &str = "patch_ratio";
+
+/// Histogram: time spent installing an update into a slot, in seconds.
+pub const INSTALL_SECONDS: &str = "install_seconds";
+
+/// Counter: updates installed.
+pub const INSTALLS: &str = "installs";
+
+/// A destination for metrics.
+pub trait Metrics {
+    /// Add `value` to a counter.
+    fn counter(&self, name: &str, value: u64);
+
+    /// Record a value in a histogram.
+    fn histogram(&self, name: &str, value: f64);
+}
+
+/// Metrics discarding all values.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {
+    fn counter(&self, _name: &str, _value: u64) {}
+
+    fn histogram(&self, _name: &str, _value: f64) {}
+}
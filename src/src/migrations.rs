@@ -0,0 +1,158 @@
+//! Data migrations between module versions.
+//!
+//! Migration steps are registered for the version that introduces a new data format. When the
+//! agent installs an update, it runs the steps of every version after the installed one, up to
+//! the version of the update, in order. Downgrades run no steps.
+
+use crate::error::*;
+
+use log::*;
+use semver::Version;
+use std::fmt;
+use std::sync::Arc;
+
+/// Runs exported functions of WebAssembly migration steps, in the host runtime.
+pub trait WasmRunner: Send + Sync {
+    /// Call `export` in a module, and return an error if it failed.
+    fn call(&self, module_bin: &[u8], export: &str) -> Result<(), WSError>;
+}
+
+/// A host function migrating data, given the installed version and the version being installed.
+pub type HostMigration = dyn Fn(&Version, &Version) -> Result<(), WSError> + Send + Sync;
+
+/// What a migration step does.
+#[derive(Clone)]
+pub enum MigrationStep {
+    /// Call an exported function of a WebAssembly module.
+    Wasm { module_bin: Vec<u8>, export: String },
+    /// Call a host function.
+    Host(Arc<HostMigration>),
+}
+
+impl fmt::Debug for MigrationStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationStep::Wasm { module_bin, export } => f
+                .debug_struct("Wasm")
+                .field("len", &module_bin.len())
+                .field("export", export)
+                .finish(),
+            MigrationStep::Host(_) => f.write_str("Host"),
+        }
+    }
+}
+
+/// A step migrating data to the format of a version.
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub version: Version,
+    pub step: MigrationStep,
+}
+
+/// A set of migration steps.
+#[derive(Clone, Default)]
+pub struct Migrations {
+    migrations: Vec<Migration>,
+    wasm_runner: Option<Arc<dyn WasmRunner>>,
+}
+
+impl Migrations {
+    /// Create an empty set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a host function migrating data to the format of `version`.
+    ///
+    /// Steps registered for the same version run in the order they were added.
+    pub fn host(
+        mut self,
+        version: Version,
+        f: impl Fn(&Version, &Version) -> Result<(), WSError> + Send + Sync + 'static,
+    ) -> Self {
+        self.migrations.push(Migration {
+            version,
+            step: MigrationStep::Host(Arc::new(f)),
+        });
+        self
+    }
+
+    /// Add an exported function of a WebAssembly module migrating data to the format of `version`.
+    pub fn wasm(
+        mut self,
+        version: Version,
+        module_bin: Vec<u8>,
+        export: impl Into<String>,
+    ) -> Self {
+        self.migrations.push(Migration {
+            version,
+            step: MigrationStep::Wasm {
+                module_bin,
+                export: export.into(),
+            },
+        });
+        self
+    }
+
+    /// Set the runner of WebAssembly steps.
+    pub fn wasm_runner(mut self, wasm_runner: impl WasmRunner + 'static) -> Self {
+        self.wasm_runner = Some(Arc::new(wasm_runner));
+        self
+    }
+
+    /// Return `true` if no steps were registered.
+    pub fn is_empty(&self) -> bool {
+        self.migrations.is_empty()
+    }
+
+    /// Return the steps to run to go from version `from` to version `to`, in order.
+    pub fn chain(&self, from: &Version, to: &Version) -> Vec<&Migration> {
+        let mut chain: Vec<_> = self
+            .migrations
+            .iter()
+            .filter(|migration| &migration.version > from && &migration.version <= to)
+            .collect();
+        chain.sort_by(|a, b| a.version.cmp(&b.version));
+        chain
+    }
+
+    /// Run the steps needed to go from version `from` to version `to`, and return how many ran.
+    ///
+    /// The first failing step stops the chain. WebAssembly steps are rejected before anything
+    /// runs if no runner was set.
+    pub fn run(&self, from: &Version, to: &Version) -> Result<usize, WSError> {
+        let chain = self.chain(from, to);
+        let needs_runner = chain
+            .iter()
+            .any(|migration| matches!(migration.step, MigrationStep::Wasm { .. }));
+        if needs_runner && self.wasm_runner.is_none() {
+            return Err(WSError::MigrationFailed(
+                "no runner for WebAssembly migrations".to_string(),
+            ));
+        }
+        for migration in &chain {
+            debug!("Migrating data to the format of {}", migration.version);
+            let res = match &migration.step {
+                MigrationStep::Wasm { module_bin, export } => {
+                    self.wasm_runner.as_ref().unwrap().call(module_bin, export)
+                }
+                MigrationStep::Host(f) => f(from, to),
+            };
+            if let Err(e) = res {
+                return Err(WSError::MigrationFailed(format!(
+                    "{}: {}",
+                    migration.version, e
+                )));
+            }
+        }
+        Ok(chain.len())
+    }
+}
+
+impl fmt::Debug for Migrations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.migrations.iter().map(|migration| &migration.version))
+            .finish()
+    }
+}
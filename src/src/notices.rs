@@ -0,0 +1,190 @@
+use crate::error::*;
+use crate::wasm_module::*;
+use crate::watermark::WATERMARK_SECTION_NAME;
+
+use std::io::{self, prelude::*};
+use std::path::Path;
+use std::str;
+
+/// Name of the custom section storing license texts and notices.
+pub const NOTICES_SECTION_NAME: &str = "notices";
+
+const NOTICES_VERSION: u8 = 0x01;
+
+/// A license or notice text for a third-party component.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notice {
+    /// Name of the component the notice applies to.
+    pub package: String,
+    /// SPDX license identifier, or an empty string if unknown.
+    pub license: String,
+    /// Full text of the license or notice.
+    pub text: String,
+}
+
+impl Notice {
+    /// Create a new notice.
+    pub fn new(
+        package: impl Into<String>,
+        license: impl Into<String>,
+        text: impl Into<String>,
+    ) -> Self {
+        Notice {
+            package: package.into(),
+            license: license.into(),
+            text: text.into(),
+        }
+    }
+
+    /// Create a new notice, reading the text from a file.
+    pub fn from_file(
+        package: impl Into<String>,
+        license: impl Into<String>,
+        file: impl AsRef<Path>,
+    ) -> Result<Self, WSError> {
+        let text = std::fs::read(file.as_ref())?;
+        let text = str::from_utf8(&text)?;
+        Ok(Self::new(package, license, text))
+    }
+}
+
+/// A set of notices, stored as a custom section.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Notices {
+    pub entries: Vec<Notice>,
+}
+
+impl Notices {
+    /// Serialize the notices as a custom section payload.
+    pub fn serialize(&self) -> Result<Vec<u8>, WSError> {
+        let mut writer = io::Cursor::new(vec![]);
+        writer.write_all(&[NOTICES_VERSION])?;
+        varint::put(&mut writer, self.entries.len() as _)?;
+        for notice in &self.entries {
+            varint::put_slice(&mut writer, notice.package.as_bytes())?;
+            varint::put_slice(&mut writer, notice.license.as_bytes())?;
+            varint::put_slice(&mut writer, notice.text.as_bytes())?;
+        }
+        Ok(writer.into_inner())
+    }
+
+    /// Deserialize notices from a custom section payload.
+    pub fn deserialize(payload: &[u8]) -> Result<Self, WSError> {
+        TakeExact::decode(payload, payload.len() as _, |reader| {
+            let mut version = [0u8; 1];
+            reader.read_exact(&mut version)?;
+            if version[0] != NOTICES_VERSION {
+                return Err(WSError::ParseError);
+            }
+            let count = varint::get32(reader)?;
+            let mut entries = vec![];
+            for _ in 0..count {
+                let package = str::from_utf8(&reader.get_slice()?)?.to_string();
+                let license = str::from_utf8(&reader.get_slice()?)?.to_string();
+                let text = str::from_utf8(&reader.get_slice()?)?.to_string();
+                entries.push(Notice {
+                    package,
+                    license,
+                    text,
+                });
+            }
+            Ok(Notices { entries })
+        })
+    }
+}
+
+impl Module {
+    /// Return the notices embedded in the module, if any.
+    pub fn notices(&self) -> Result<Option<Notices>, WSError> {
+        for section in &self.sections {
+            if let Section::Custom(custom) = section {
+                if custom.name() == NOTICES_SECTION_NAME {
+                    return Ok(Some(Notices::deserialize(custom.payload())?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Embed notices into the module, replacing existing ones.
+    ///
+    /// New notices are inserted before the watermark, which stays the last section. The watermark
+    /// covers the notices, so it has to be embedded again with `refresh_watermark()`.
+    pub fn set_notices(&mut self, notices: &Notices) -> Result<(), WSError> {
+        let payload = notices.serialize()?;
+        for section in self.sections.iter_mut() {
+            if let Section::Custom(custom) = section {
+                if custom.name() == NOTICES_SECTION_NAME {
+                    custom.set_payload(payload);
+                    return Ok(());
+                }
+            }
+        }
+        let position = self
+            .sections
+            .iter()
+            .position(|section| match section {
+                Section::Custom(custom) => custom.name() == WATERMARK_SECTION_NAME,
+                _ => false,
+            })
+            .unwrap_or(self.sections.len());
+        self.sections.insert(
+            position,
+            Section::Custom(CustomSection::new(
+                NOTICES_SECTION_NAME.to_string(),
+                payload,
+            )),
+        );
+        Ok(())
+    }
+
+    /// Add a notice to the module, keeping the existing ones.
+    pub fn add_notice(&mut self, notice: Notice) -> Result<(), WSError> {
+        let mut notices = self.notices()?.unwrap_or_default();
+        notices.entries.push(notice);
+        self.set_notices(&notices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notices_are_inserted_before_the_watermark() {
+        let key = b"watermark key";
+        let mut module = Module::default();
+        module.sections.push(Section::Custom(CustomSection::new(
+            "name".to_string(),
+            vec![0x00],
+        )));
+        module.embed_watermark("recipient", key).unwrap();
+
+        let mut notices = Notices::default();
+        notices.entries.push(Notice::new(
+            "component",
+            "MIT",
+            "Permission is hereby granted",
+        ));
+        module.set_notices(&notices).unwrap();
+
+        let names: Vec<_> = module
+            .sections
+            .iter()
+            .map(|section| match section {
+                Section::Custom(custom) => custom.name().to_string(),
+                _ => String::new(),
+            })
+            .collect();
+        assert_eq!(
+            names,
+            ["name", NOTICES_SECTION_NAME, WATERMARK_SECTION_NAME]
+        );
+        assert!(module.verify_watermark(key).is_err());
+
+        let watermark = module.refresh_watermark(key).unwrap();
+        assert_eq!(watermark.id, "recipient");
+        assert_eq!(module.verify_watermark(key).unwrap().id, "recipient");
+        assert_eq!(module.notices().unwrap(), Some(notices));
+    }
+}
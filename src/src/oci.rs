@@ -0,0 +1,881 @@
+//! Distribution of modules through OCI registries.
+//!
+//! A module is pushed as an OCI artifact with an empty configuration: the module is the first layer,
+//! and its detached signature, if any, the second one. The version and build identifier are stored
+//! as manifest annotations.
+
+use crate::agent::{HttpResponse, HttpTransport};
+use crate::error::*;
+use crate::repo::build_id;
+use crate::signature::*;
+use crate::wasm_module::*;
+
+use ct_codecs::{Base64, Encoder, Hex};
+use hmac_sha256::Hash;
+use log::*;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, prelude::*};
+use std::sync::Mutex;
+
+/// Media type of OCI image manifests.
+pub const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+/// Artifact type of pushed modules.
+pub const ARTIFACT_TYPE: &str = "application/vnd.wasi-update.module.v1";
+/// Media type of the empty configuration blob.
+pub const EMPTY_CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.empty.v1+json";
+/// Media type of module layers.
+pub const MODULE_MEDIA_TYPE: &str = "application/wasm";
+/// Media type of signature layers.
+pub const SIGNATURE_MEDIA_TYPE: &str = "application/vnd.wasi-update.signature.v1";
+
+/// Annotation carrying the version of the module.
+pub const VERSION_ANNOTATION: &str = "org.opencontainers.image.version";
+/// Annotation carrying the build identifier of the module.
+pub const BUILD_ID_ANNOTATION: &str = "wasi-update.build-id";
+/// Annotation carrying the file name of a layer.
+pub const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+
+/// Maximum size of a manifest.
+pub const MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024;
+
+const EMPTY_CONFIG: &[u8] = b"{}";
+const MAX_REDIRECTS: usize = 5;
+const MAX_ERROR_SIZE: u64 = 64 * 1024;
+const MAX_TAGS: usize = 100_000;
+
+/// A reference to an artifact, such as `ghcr.io/org/app:1.2.0` or `localhost:5000/app@sha256:...`.
+///
+/// The registry is reached over HTTPS, unless the reference starts with `http://`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OciReference {
+    /// URL of the registry, such as `https://ghcr.io`.
+    pub registry: String,
+    /// Name of the repository in the registry.
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl OciReference {
+    /// Parse a reference.
+    pub fn parse(reference: &str) -> Result<Self, WSError> {
+        let invalid = || WSError::UnsupportedUrl(reference.to_string());
+        let (scheme, rest) = match reference.split_once("://") {
+            Some((scheme @ ("http" | "https"), rest)) => (scheme, rest),
+            Some(_) => return Err(invalid()),
+            None => ("https", reference),
+        };
+        let (host, name) = rest.split_once('/').ok_or_else(invalid)?;
+        let (name, digest) = match name.split_once('@') {
+            Some((name, digest)) => (name, Some(digest.to_string())),
+            None => (name, None),
+        };
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, Some(tag.to_string())),
+            _ => (name, None),
+        };
+        let valid_name = |s: &str| {
+            !s.is_empty()
+                && s.split('/').all(|c| {
+                    !c.is_empty()
+                        && c.bytes().all(|b| {
+                            b.is_ascii_lowercase() || b.is_ascii_digit() || b"._-".contains(&b)
+                        })
+                })
+        };
+        let valid_tag = |s: &str| {
+            !s.is_empty()
+                && s.len() <= 128
+                && s.bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b))
+        };
+        if host.is_empty()
+            || !valid_name(name)
+            || tag.as_deref().is_some_and(|tag| !valid_tag(tag))
+            || digest.as_deref().is_some_and(|d| parse_digest(d).is_err())
+        {
+            return Err(invalid());
+        }
+        Ok(OciReference {
+            registry: format!("{}://{}", scheme, host),
+            repository: name.to_string(),
+            tag,
+            digest,
+        })
+    }
+
+    /// Return the digest if there is one, or the tag, defaulting to `latest`.
+    pub fn reference(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or(self.tag.as_deref())
+            .unwrap_or("latest")
+    }
+}
+
+impl fmt::Display for OciReference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let host = self.registry.trim_start_matches("https://");
+        write!(f, "{}/{}", host, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+/// A content descriptor.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciDescriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl OciDescriptor {
+    fn new(media_type: &str, data: &[u8]) -> Self {
+        OciDescriptor {
+            media_type: media_type.to_string(),
+            digest: digest(data),
+            size: data.len() as _,
+            annotations: BTreeMap::new(),
+        }
+    }
+}
+
+/// An OCI image manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciManifest {
+    pub schema_version: u32,
+    #[serde(default)]
+    pub media_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artifact_type: Option<String>,
+    pub config: OciDescriptor,
+    pub layers: Vec<OciDescriptor>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+/// A module, along with its version and detached signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OciBundle {
+    pub module: Vec<u8>,
+    pub version: Option<Version>,
+    pub signature: Option<Signature>,
+}
+
+impl OciBundle {
+    /// Create a bundle containing only a module.
+    pub fn new(module: Vec<u8>) -> Self {
+        OciBundle {
+            module,
+            version: None,
+            signature: None,
+        }
+    }
+
+    /// Set the version of the module.
+    pub fn version(mut self, version: Version) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Add the detached signature of the module.
+    pub fn signature(mut self, signature: Signature) -> Self {
+        self.signature = Some(signature);
+        self
+    }
+
+    /// Return the build identifier of the module.
+    pub fn build_id(&self) -> String {
+        build_id(&self.module)
+    }
+
+    /// Verify the signature of the module.
+    pub fn verify(&self, key_ring: &KeyRing) -> Result<(), WSError> {
+        let signature = self.signature.as_ref().ok_or(WSError::NoSignature)?;
+        key_ring.verify_module(&self.module, signature)
+    }
+
+    fn manifest(&self) -> (OciManifest, Vec<Vec<u8>>) {
+        let build_id = self.build_id();
+        let mut module_layer = OciDescriptor::new(MODULE_MEDIA_TYPE, &self.module);
+        module_layer.annotations.insert(
+            TITLE_ANNOTATION.to_string(),
+            format!("{}.wasm", &build_id[..16]),
+        );
+        let mut layers = vec![module_layer];
+        let mut blobs = vec![self.module.clone()];
+        if let Some(signature) = &self.signature {
+            let signature_bin = signature.to_bytes();
+            layers.push(OciDescriptor::new(SIGNATURE_MEDIA_TYPE, &signature_bin));
+            blobs.push(signature_bin);
+        }
+        let mut annotations = BTreeMap::new();
+        annotations.insert(BUILD_ID_ANNOTATION.to_string(), build_id);
+        if let Some(version) = &self.version {
+            annotations.insert(VERSION_ANNOTATION.to_string(), version.to_string());
+        }
+        let manifest = OciManifest {
+            schema_version: 2,
+            media_type: Some(MANIFEST_MEDIA_TYPE.to_string()),
+            artifact_type: Some(ARTIFACT_TYPE.to_string()),
+            config: OciDescriptor::new(EMPTY_CONFIG_MEDIA_TYPE, EMPTY_CONFIG),
+            layers,
+            annotations,
+        };
+        (manifest, blobs)
+    }
+}
+
+/// Return the digest of some data, as `sha256:<hex>`.
+pub fn digest(data: &[u8]) -> String {
+    format!(
+        "sha256:{}",
+        Hex::encode_to_string(Hash::hash(data)).unwrap()
+    )
+}
+
+fn parse_digest(digest: &str) -> Result<&str, WSError> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => Ok(hex),
+        _ => Err(WSError::ParseError),
+    }
+}
+
+fn check_digest(data: &[u8], expected: &str) -> Result<(), WSError> {
+    if !parse_digest(expected)?.eq_ignore_ascii_case(parse_digest(&digest(data))?) {
+        return Err(WSError::DigestMismatch);
+    }
+    Ok(())
+}
+
+fn url_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Parse the parameters of a `WWW-Authenticate` challenge.
+fn challenge_params(params: &str) -> BTreeMap<String, String> {
+    let mut map = BTreeMap::new();
+    let mut rest = params.trim();
+    while let Some((name, value)) = rest.split_once('=') {
+        let value = value.trim_start();
+        let (value, next) = match value.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((value, next)) => (value, next),
+                None => (quoted, ""),
+            },
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        map.insert(name.trim().to_ascii_lowercase(), value.to_string());
+        rest = next.trim_start_matches([',', ' ']);
+    }
+    map
+}
+
+fn read_limited(body: &mut dyn Read, max: u64) -> Result<Vec<u8>, WSError> {
+    let mut data = vec![];
+    body.take(max + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > max {
+        return Err(WSError::ParseError);
+    }
+    Ok(data)
+}
+
+#[derive(Deserialize)]
+struct RegistryErrors {
+    errors: Vec<RegistryErrorEntry>,
+}
+
+#[derive(Deserialize)]
+struct RegistryErrorEntry {
+    code: String,
+    #[serde(default)]
+    message: String,
+}
+
+/// Turn an unexpected response into an error, using the error messages returned by the registry.
+fn response_error(mut response: HttpResponse) -> WSError {
+    let body = read_limited(&mut response.body, MAX_ERROR_SIZE).unwrap_or_default();
+    match serde_json::from_slice::<RegistryErrors>(&body) {
+        Ok(errors) if !errors.errors.is_empty() => WSError::RegistryError(
+            errors
+                .errors
+                .iter()
+                .map(|e| match e.message.is_empty() {
+                    true => e.code.clone(),
+                    false => format!("{}: {}", e.code, e.message),
+                })
+                .collect::<Vec<_>>()
+                .join("; "),
+        ),
+        _ => WSError::HttpError(response.status),
+    }
+}
+
+/// A client for the OCI distribution API.
+///
+/// Anonymous access, basic authentication and bearer tokens are supported. When the registry asks
+/// for a token, one is requested from its authorization server, using the credentials if there are any.
+#[derive(Debug)]
+pub struct OciClient {
+    transport: HttpTransport,
+    credentials: Option<(String, String)>,
+    token: Mutex<Option<String>>,
+}
+
+impl OciClient {
+    /// Create a client for a registry, such as `https://ghcr.io`.
+    pub fn new(registry: impl Into<String>) -> Self {
+        Self::with_transport(HttpTransport::new(registry))
+    }
+
+    /// Create a client using a transport to the registry, for example to go through a proxy.
+    pub fn with_transport(transport: HttpTransport) -> Self {
+        OciClient {
+            transport,
+            credentials: None,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Authenticate with a user name and a password or access token.
+    pub fn credentials(mut self, user: impl Into<String>, password: impl Into<String>) -> Self {
+        self.credentials = Some((user.into(), password.into()));
+        self
+    }
+
+    /// Authenticate with a bearer token.
+    pub fn token(self, token: impl Into<String>) -> Self {
+        *self.token.lock().unwrap_or_else(|e| e.into_inner()) = Some(token.into());
+        self
+    }
+
+    fn basic_authorization(&self) -> Option<String> {
+        let (user, password) = self.credentials.as_ref()?;
+        let userinfo = format!("{}:{}", user, password);
+        Some(format!(
+            "Basic {}",
+            Base64::encode_to_string(userinfo.as_bytes()).ok()?
+        ))
+    }
+
+    fn authorization(&self) -> Option<String> {
+        match &*self.token.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(token) => Some(format!("Bearer {}", token)),
+            None => self.basic_authorization(),
+        }
+    }
+
+    /// Answer an authentication challenge. Return `false` if the challenge can't be answered.
+    fn authenticate(&self, challenge: &str) -> Result<bool, WSError> {
+        let (scheme, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return Ok(false);
+        }
+        let params = challenge_params(params);
+        let realm = match params.get("realm") {
+            None => return Ok(false),
+            Some(realm) => realm,
+        };
+        let query: Vec<_> = ["service", "scope"]
+            .into_iter()
+            .filter_map(|name| Some(format!("{}={}", name, url_encode(params.get(name)?))))
+            .collect();
+        let mut url = realm.clone();
+        if !query.is_empty() {
+            url.push(if realm.contains('?') { '&' } else { '?' });
+            url.push_str(&query.join("&"));
+        }
+        debug!("Requesting a registry token from [{}]", realm);
+        let headers: Vec<_> = self
+            .basic_authorization()
+            .into_iter()
+            .map(|auth| ("Authorization", auth))
+            .collect();
+        let mut response = self.transport.request("GET", &url, &headers, &[])?;
+        if response.status != 200 {
+            return Err(response_error(response));
+        }
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            token: Option<String>,
+            access_token: Option<String>,
+        }
+        let json = read_limited(&mut response.body, MAX_ERROR_SIZE)?;
+        let token: TokenResponse = serde_json::from_slice(&json)?;
+        let token = token
+            .token
+            .or(token.access_token)
+            .ok_or(WSError::ParseError)?;
+        *self.token.lock().unwrap_or_else(|e| e.into_inner()) = Some(token);
+        Ok(true)
+    }
+
+    /// Send a request, authenticating and following redirects if needed.
+    fn send(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<HttpResponse, WSError> {
+        let mut authenticated = false;
+        let mut url = url.to_string();
+        let mut redirects = 0;
+        loop {
+            let mut request_headers = headers.to_vec();
+            // Redirects usually point to pre-signed URLs of a storage service, that mustn't get the credentials.
+            if redirects == 0 {
+                if let Some(auth) = self.authorization() {
+                    request_headers.push(("Authorization", auth));
+                }
+            }
+            let response = self
+                .transport
+                .request(method, &url, &request_headers, body)?;
+            match response.status {
+                401 if !authenticated && redirects == 0 => {
+                    let challenge = response.header("www-authenticate").unwrap_or_default();
+                    if !self.authenticate(challenge)? {
+                        return Ok(response);
+                    }
+                    authenticated = true;
+                }
+                301 | 302 | 303 | 307 | 308 if method == "GET" || method == "HEAD" => {
+                    if redirects == MAX_REDIRECTS {
+                        return Err(WSError::HttpError(response.status));
+                    }
+                    url = response
+                        .header("location")
+                        .ok_or(WSError::ParseError)?
+                        .to_string();
+                    redirects += 1;
+                }
+                _ => return Ok(response),
+            }
+        }
+    }
+
+    /// Return the size of a blob, or `None` if the repository doesn't contain it.
+    pub fn blob_size(&self, repository: &str, digest: &str) -> Result<Option<u64>, WSError> {
+        let url = format!("/v2/{}/blobs/{}", repository, digest);
+        let response = self.send("HEAD", &url, &[], &[])?;
+        match response.status {
+            200 => {}
+            404 => return Ok(None),
+            _ => return Err(response_error(response)),
+        }
+        let size = response
+            .header("content-length")
+            .ok_or(WSError::ParseError)?
+            .parse()
+            .map_err(|_| WSError::ParseError)?;
+        Ok(Some(size))
+    }
+
+    fn push_blob(&self, repository: &str, data: &[u8]) -> Result<String, WSError> {
+        let digest = digest(data);
+        if self.blob_size(repository, &digest)?.is_some() {
+            debug!("Blob {} already exists", digest);
+            return Ok(digest);
+        }
+        let url = format!("/v2/{}/blobs/uploads/", repository);
+        let response = self.send("POST", &url, &[], &[])?;
+        if response.status != 202 {
+            return Err(response_error(response));
+        }
+        let location = response.header("location").ok_or(WSError::ParseError)?;
+        let separator = if location.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}digest={}", location, separator, url_encode(&digest));
+        let headers = [("Content-Type", "application/octet-stream".to_string())];
+        let response = self.send("PUT", &url, &headers, data)?;
+        if response.status != 201 {
+            return Err(response_error(response));
+        }
+        debug!("Pushed blob {} ({} bytes)", digest, data.len());
+        Ok(digest)
+    }
+
+    /// Download a blob, and check it against its descriptor.
+    pub fn blob(&self, repository: &str, descriptor: &OciDescriptor) -> Result<Vec<u8>, WSError> {
+        let mut data = vec![];
+        self.blob_range(repository, &descriptor.digest, 0, None)?
+            .take(descriptor.size + 1)
+            .read_to_end(&mut data)?;
+        if data.len() as u64 != descriptor.size {
+            return Err(WSError::DigestMismatch);
+        }
+        check_digest(&data, &descriptor.digest)?;
+        Ok(data)
+    }
+
+    /// Download part of a blob, starting at `offset`.
+    ///
+    /// If `len` is `None`, the blob is read until the end. The data is not checked against the digest.
+    pub fn blob_range(
+        &self,
+        repository: &str,
+        digest: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read>, WSError> {
+        parse_digest(digest)?;
+        let range = match len {
+            Some(0) => return Ok(Box::new(io::empty())),
+            Some(len) => Some(format!("bytes={}-{}", offset, offset + len - 1)),
+            None if offset > 0 => Some(format!("bytes={}-", offset)),
+            None => None,
+        };
+        let headers: Vec<_> = range.into_iter().map(|range| ("Range", range)).collect();
+        let url = format!("/v2/{}/blobs/{}", repository, digest);
+        let mut response = self.send("GET", &url, &headers, &[])?;
+        let body = match response.status {
+            206 if !headers.is_empty() => response.body,
+            200 => {
+                // The registry ignored the range; skip the data we don't need.
+                io::copy(&mut (&mut response.body).take(offset), &mut io::sink())?;
+                response.body
+            }
+            _ => return Err(response_error(response)),
+        };
+        match len {
+            Some(len) => Ok(Box::new(body.take(len))),
+            None => Ok(body),
+        }
+    }
+
+    /// Return the tags of a repository.
+    pub fn tags(&self, repository: &str) -> Result<Vec<String>, WSError> {
+        #[derive(Deserialize)]
+        struct TagList {
+            #[serde(default)]
+            tags: Option<Vec<String>>,
+        }
+        let mut tags = vec![];
+        let mut url = format!("/v2/{}/tags/list", repository);
+        loop {
+            let mut response = self.send("GET", &url, &[], &[])?;
+            match response.status {
+                200 => {}
+                404 => return Err(WSError::NotFound(repository.to_string())),
+                _ => return Err(response_error(response)),
+            }
+            // Large tag lists are paginated, with a link to the next page.
+            let next = response.header("link").and_then(|link| {
+                let (target, rel) = link.split_once(';')?;
+                rel.contains("next").then(|| {
+                    target
+                        .trim()
+                        .trim_start_matches('<')
+                        .trim_end_matches('>')
+                        .to_string()
+                })
+            });
+            let json = read_limited(&mut response.body, MAX_MANIFEST_SIZE)?;
+            let list: TagList = serde_json::from_slice(&json)?;
+            tags.extend(list.tags.unwrap_or_default());
+            match next {
+                Some(next) if tags.len() < MAX_TAGS => url = next,
+                _ => return Ok(tags),
+            }
+        }
+    }
+
+    /// Push a bundle, and return the digest of its manifest.
+    ///
+    /// Blobs already present in the repository are not uploaded again.
+    pub fn push(&self, reference: &OciReference, bundle: &OciBundle) -> Result<String, WSError> {
+        Module::deserialize(&mut io::Cursor::new(&bundle.module))?;
+        let (manifest, blobs) = bundle.manifest();
+        self.push_blob(&reference.repository, EMPTY_CONFIG)?;
+        for blob in &blobs {
+            self.push_blob(&reference.repository, blob)?;
+        }
+        let manifest_bin = serde_json::to_vec(&manifest)?;
+        let manifest_digest = digest(&manifest_bin);
+        let url = format!(
+            "/v2/{}/manifests/{}",
+            reference.repository,
+            reference.tag.as_deref().unwrap_or(&manifest_digest)
+        );
+        let headers = [("Content-Type", MANIFEST_MEDIA_TYPE.to_string())];
+        let response = self.send("PUT", &url, &headers, &manifest_bin)?;
+        if response.status != 201 {
+            return Err(response_error(response));
+        }
+        debug!("Pushed {} as {}", reference, manifest_digest);
+        Ok(manifest_digest)
+    }
+
+    /// Return the manifest of an artifact, along with its digest.
+    ///
+    /// If the reference includes a digest, the manifest is checked against it.
+    pub fn manifest(&self, reference: &OciReference) -> Result<(OciManifest, String), WSError> {
+        let url = format!(
+            "/v2/{}/manifests/{}",
+            reference.repository,
+            reference.reference()
+        );
+        let headers = [("Accept", MANIFEST_MEDIA_TYPE.to_string())];
+        let mut response = self.send("GET", &url, &headers, &[])?;
+        match response.status {
+            200 => {}
+            404 => return Err(WSError::NotFound(reference.to_string())),
+            _ => return Err(response_error(response)),
+        }
+        let manifest_bin = read_limited(&mut response.body, MAX_MANIFEST_SIZE)?;
+        if let Some(expected) = &reference.digest {
+            check_digest(&manifest_bin, expected)?;
+        }
+        let manifest: OciManifest = serde_json::from_slice(&manifest_bin)?;
+        if manifest.schema_version != 2 {
+            return Err(WSError::ParseError);
+        }
+        Ok((manifest, digest(&manifest_bin)))
+    }
+
+    /// Pull a bundle.
+    ///
+    /// Every blob is checked against the digest and size listed in the manifest, and the module
+    /// against the build identifier annotation. The signature is not verified; use `OciBundle::verify()`.
+    /// Artifacts pushed by other tools are accepted, as long as they have an `application/wasm` layer.
+    pub fn pull(&self, reference: &OciReference) -> Result<OciBundle, WSError> {
+        let (manifest, _) = self.manifest(reference)?;
+        let layer = |media_type| {
+            manifest
+                .layers
+                .iter()
+                .find(|layer| layer.media_type == media_type)
+        };
+        let module_layer = layer(MODULE_MEDIA_TYPE)
+            .ok_or_else(|| WSError::NotFound(format!("module layer of {}", reference)))?;
+        let module = self.blob(&reference.repository, module_layer)?;
+        Module::deserialize(&mut io::Cursor::new(&module))?;
+        let mut bundle = OciBundle::new(module);
+        if let Some(expected) = manifest.annotations.get(BUILD_ID_ANNOTATION) {
+            if !expected.eq_ignore_ascii_case(&bundle.build_id()) {
+                return Err(WSError::DigestMismatch);
+            }
+        }
+        if let Some(version) = manifest.annotations.get(VERSION_ANNOTATION) {
+            bundle.version = Some(Version::parse(version).map_err(|_| WSError::ParseError)?);
+        }
+        if let Some(signature_layer) = layer(SIGNATURE_MEDIA_TYPE) {
+            let signature_bin = self.blob(&reference.repository, signature_layer)?;
+            bundle.signature = Some(Signature::from_bytes(&signature_bin)?);
+        }
+        debug!("Pulled {} ({})", reference, bundle.build_id());
+        Ok(bundle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{data_response, serve_fake, Request, Response};
+    use std::sync::Arc;
+
+    const TOKEN: &str = "t0ken";
+
+    /// An in-memory registry requiring a bearer token, obtained with the `user:password` credentials.
+    #[derive(Default)]
+    struct Registry {
+        blobs: BTreeMap<String, Vec<u8>>,
+        manifests: BTreeMap<String, Vec<u8>>,
+        uploads: usize,
+    }
+
+    impl Registry {
+        fn handle(&mut self, request: &Request) -> Response {
+            let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+            if path == "/token" {
+                let basic = format!(
+                    "Basic {}",
+                    Base64::encode_to_string("user:password").unwrap()
+                );
+                if request.header("authorization") != Some(basic.as_str())
+                    || query != "service=fake&scope=repository%3Aapp%3Apull%2Cpush"
+                {
+                    return Response::error(403);
+                }
+                let token = format!("{{\"token\": \"{}\"}}", TOKEN);
+                return Response::new(200, "application/json", token.into_bytes());
+            }
+            if request.header("authorization") != Some(&format!("Bearer {}", TOKEN)) {
+                let mut response = Response::new(401, "application/json", vec![]);
+                response.headers.push((
+                    "WWW-Authenticate",
+                    r#"Bearer realm="/token",service="fake",scope="repository:app:pull,push""#
+                        .to_string(),
+                ));
+                return response;
+            }
+            let Some(path) = path.strip_prefix("/v2/app/") else {
+                return Response::error(404);
+            };
+            let found = |data: Option<&Vec<u8>>, content_type| match data {
+                Some(data) => data_response(request, content_type, data.clone()),
+                None => Response::error(404),
+            };
+            match (request.method.as_str(), path.split_once('/')) {
+                ("HEAD" | "GET", Some(("blobs", digest))) => {
+                    found(self.blobs.get(digest), "application/octet-stream")
+                }
+                ("GET", Some(("manifests", reference))) => {
+                    found(self.manifests.get(reference), MANIFEST_MEDIA_TYPE)
+                }
+                ("POST", Some(("blobs", "uploads/"))) => {
+                    let mut response = Response::new(202, "text/plain", vec![]);
+                    let location = format!("/v2/app/blobs/uploads/{}", self.uploads);
+                    response.headers.push(("Location", location));
+                    self.uploads += 1;
+                    response
+                }
+                ("PUT", Some(("blobs", _))) => {
+                    let digest = query.strip_prefix("digest=").unwrap().replace("%3A", ":");
+                    assert_eq!(digest, super::digest(&request.body));
+                    self.blobs.insert(digest, request.body.clone());
+                    Response::new(201, "text/plain", vec![])
+                }
+                ("PUT", Some(("manifests", reference))) => {
+                    let manifest_digest = digest(&request.body);
+                    self.manifests
+                        .insert(reference.to_string(), request.body.clone());
+                    self.manifests.insert(manifest_digest, request.body.clone());
+                    Response::new(201, "text/plain", vec![])
+                }
+                _ => Response::error(405),
+            }
+        }
+    }
+
+    fn module(payload: &[u8]) -> Vec<u8> {
+        let mut module_bin = b"\0asm\x01\0\0\0\0".to_vec();
+        module_bin.push(payload.len() as u8 + 1);
+        module_bin.push(0);
+        module_bin.extend_from_slice(payload);
+        module_bin
+    }
+
+    fn registry() -> (Arc<Mutex<Registry>>, OciClient) {
+        let registry = Arc::new(Mutex::new(Registry::default()));
+        let url = serve_fake({
+            let registry = registry.clone();
+            move |request| registry.lock().unwrap().handle(request)
+        });
+        (
+            registry,
+            OciClient::new(url).credentials("user", "password"),
+        )
+    }
+
+    #[test]
+    fn bundles_round_trip() {
+        let (registry, client) = registry();
+        let kp = KeyPair::generate();
+        let module_bin = module(b"v1");
+        let bundle = OciBundle::new(module_bin.clone())
+            .version(Version::new(1, 0, 0))
+            .signature(kp.sk.sign_module(&module_bin));
+        let reference = OciReference::parse("http://localhost/app:1.0.0").unwrap();
+        let manifest_digest = client.push(&reference, &bundle).unwrap();
+        assert_eq!(registry.lock().unwrap().uploads, 3);
+
+        let pulled = client.pull(&reference).unwrap();
+        assert_eq!(pulled, bundle);
+        let mut key_ring = KeyRing::new();
+        key_ring.add(kp.pk);
+        pulled.verify(&key_ring).unwrap();
+        let by_digest =
+            OciReference::parse(&format!("http://localhost/app@{}", manifest_digest)).unwrap();
+        assert_eq!(client.pull(&by_digest).unwrap(), bundle);
+
+        // Blobs that are already in the registry are not uploaded again.
+        let reference = OciReference::parse("http://localhost/app:latest").unwrap();
+        client.push(&reference, &bundle).unwrap();
+        assert_eq!(registry.lock().unwrap().uploads, 3);
+        let mut range = vec![];
+        client
+            .blob_range("app", &digest(&module_bin), 8, Some(3))
+            .unwrap()
+            .read_to_end(&mut range)
+            .unwrap();
+        assert_eq!(range, &module_bin[8..11]);
+    }
+
+    #[test]
+    fn digests_are_checked() {
+        let (registry, client) = registry();
+        let module_bin = module(b"v1");
+        let reference = OciReference::parse("http://localhost/app:1.0.0").unwrap();
+        let manifest_digest = client
+            .push(&reference, &OciBundle::new(module_bin.clone()))
+            .unwrap();
+        let (manifest, _) = client.manifest(&reference).unwrap();
+
+        // A blob that doesn't match its digest, or its size.
+        for tampered in [module(b"v2"), module(b"v1 ")] {
+            let mut registry = registry.lock().unwrap();
+            registry.blobs.insert(digest(&module_bin), tampered);
+            drop(registry);
+            assert!(matches!(
+                client.pull(&reference),
+                Err(WSError::DigestMismatch)
+            ));
+        }
+
+        // A manifest that doesn't match the digest of the reference.
+        let mut tampered = manifest.clone();
+        tampered.annotations.clear();
+        let tampered_bin = serde_json::to_vec(&tampered).unwrap();
+        registry
+            .lock()
+            .unwrap()
+            .manifests
+            .insert(manifest_digest.clone(), tampered_bin);
+        let by_digest =
+            OciReference::parse(&format!("http://localhost/app@{}", manifest_digest)).unwrap();
+        assert!(matches!(
+            client.manifest(&by_digest),
+            Err(WSError::DigestMismatch)
+        ));
+
+        // A module that doesn't match the build identifier of the manifest.
+        let other_bin = module(b"v2");
+        let mut registry_lock = registry.lock().unwrap();
+        registry_lock
+            .blobs
+            .insert(digest(&other_bin), other_bin.clone());
+        let mut tampered = manifest;
+        tampered.layers[0] = OciDescriptor::new(MODULE_MEDIA_TYPE, &other_bin);
+        registry_lock
+            .manifests
+            .insert("1.0.0".to_string(), serde_json::to_vec(&tampered).unwrap());
+        drop(registry_lock);
+        assert!(matches!(
+            client.pull(&reference),
+            Err(WSError::DigestMismatch)
+        ));
+    }
+}
@@ -0,0 +1,318 @@
+use crate::cbor;
+use crate::error::*;
+use crate::signature::{KeyRing, SecretKey};
+
+use ct_codecs::{Encoder, Hex};
+use ed25519_compact::Seed;
+use schemars::JsonSchema;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+/// Path of the update-check endpoint.
+///
+/// Clients send an `UpdateCheckRequest` as a JSON `POST` body, and receive an `UpdateCheckResponse`.
+pub const UPDATE_CHECK_PATH: &str = "/v1/update-check";
+
+/// Media type of CBOR-encoded update-check responses.
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// Media type of update-check responses wrapped in a `COSE_Sign1` envelope.
+pub const COSE_CONTENT_TYPE: &str = "application/cose; cose-type=\"cose-sign1\"";
+
+/// Description of the runtime a device executes modules with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct RuntimeProfile {
+    /// Runtime name, such as `wasmtime`.
+    pub name: String,
+    /// Runtime version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// WebAssembly features supported by the runtime.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub features: Vec<String>,
+}
+
+/// A request sent by a device to check for updates.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct UpdateCheckRequest {
+    /// Unique identifier of the device.
+    pub device_id: String,
+    /// Channel the device follows.
+    pub channel: String,
+    /// Build identifier of the installed module, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installed_build_id: Option<String>,
+    /// Version of the installed module, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installed_version: Option<Version>,
+    /// Build identifiers of other modules still stored on the device, usable as patch bases.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub available_build_ids: Vec<String>,
+    /// Runtime the device uses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<RuntimeProfile>,
+    /// Codecs the device can decode modules with, most preferred first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accept_encodings: Vec<String>,
+    /// Random value chosen by the device for this check, echoed in the response.
+    ///
+    /// Once signed into the response, it prevents an older response from being replayed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+/// Generate a random nonce for an update check.
+pub fn generate_nonce() -> String {
+    Hex::encode_to_string(&Seed::generate()[..16]).unwrap()
+}
+
+/// Location and digest of a downloadable object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Download {
+    /// URL of the object, possibly relative to the update server.
+    pub url: String,
+    /// Size of the object, in bytes.
+    pub size: u64,
+    /// Hex-encoded SHA-256 digest of the object.
+    pub sha256: String,
+}
+
+/// The full module, encoded with a codec.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct EncodedDownload {
+    /// Name of the codec.
+    pub encoding: String,
+    /// The encoded module.
+    pub download: Download,
+}
+
+/// A patch transforming a module into another one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PatchDescriptor {
+    /// Build identifier the patch applies to.
+    pub base_build_id: String,
+    /// Build identifier the patch produces.
+    pub build_id: String,
+    /// The patch itself.
+    pub download: Download,
+    /// Base64-encoded signature of the patch, if available.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// How a device should obtain an update.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "kind", content = "patches", rename_all = "snake_case")]
+pub enum UpdatePlan {
+    /// Download the full module.
+    #[default]
+    Full,
+    /// Apply a chain of patches, in order, to a module already stored on the device.
+    Patches(Vec<PatchDescriptor>),
+}
+
+/// An update available for a device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct UpdateDescriptor {
+    /// Version of the update.
+    pub version: Version,
+    /// Build identifier of the update.
+    pub build_id: String,
+    /// The full module.
+    pub full: Download,
+    /// The cheapest way to obtain the update.
+    #[serde(default)]
+    pub plan: UpdatePlan,
+    /// Base64-encoded signature of the module, if available.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// The full module, encoded with the first codec accepted by the device that the server has.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoded: Option<EncodedDownload>,
+}
+
+impl UpdateDescriptor {
+    /// Return the number of bytes to download to follow the plan.
+    ///
+    /// Full modules are downloaded encoded if an encoding is offered.
+    pub fn download_size(&self) -> u64 {
+        match &self.plan {
+            UpdatePlan::Full => match &self.encoded {
+                Some(encoded) => encoded.download.size,
+                None => self.full.size,
+            },
+            UpdatePlan::Patches(patches) => patches.iter().map(|p| p.download.size).sum(),
+        }
+    }
+}
+
+/// A known-good module that devices keep aside, to restore it if all else fails.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct GoldenArtifact {
+    /// Version of the module.
+    pub version: Version,
+    /// Build identifier of the module.
+    pub build_id: String,
+    /// The module.
+    pub download: Download,
+    /// Base64-encoded signature of the module, if available.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// The response to an update check.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct UpdateCheckResponse {
+    /// The available update, or `None` if the device is up to date.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update: Option<UpdateDescriptor>,
+    /// The nonce of the request, if it had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    /// The golden artifact pinned for the channel, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub golden: Option<GoldenArtifact>,
+}
+
+impl UpdateCheckResponse {
+    /// Encode the response as CBOR.
+    ///
+    /// The structure is the same as the JSON encoding, with maps keyed by field names.
+    pub fn to_cbor(&self) -> Result<Vec<u8>, WSError> {
+        Ok(cbor::Value::from_json(&serde_json::to_value(self)?).encode())
+    }
+
+    /// Decode a CBOR-encoded response.
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, WSError> {
+        Ok(serde_json::from_value(
+            cbor::Value::decode(bytes)?.to_json()?,
+        )?)
+    }
+
+    /// Encode the response as CBOR, and sign it in a `COSE_Sign1` envelope.
+    pub fn to_cose(&self, sk: &SecretKey) -> Result<Vec<u8>, WSError> {
+        Ok(sk.sign_cose(&self.to_cbor()?))
+    }
+
+    /// Verify a `COSE_Sign1` envelope with a trusted key, and decode the response it contains.
+    pub fn from_cose(cose: &[u8], trusted_keys: &KeyRing) -> Result<Self, WSError> {
+        Self::from_cbor(&trusted_keys.verify_cose(cose)?)
+    }
+
+    /// Verify a `COSE_Sign1` envelope with a trusted key, and check that the response it contains
+    /// answers the request sent with `nonce`.
+    pub fn from_cose_with_nonce(
+        cose: &[u8],
+        trusted_keys: &KeyRing,
+        nonce: &str,
+    ) -> Result<Self, WSError> {
+        let response = Self::from_cose(cose, trusted_keys)?;
+        response.check_nonce(nonce)?;
+        Ok(response)
+    }
+
+    /// Check that the response answers the request sent with `nonce`.
+    ///
+    /// This only proves that the response is fresh if it was signed, or received over an
+    /// authenticated channel.
+    pub fn check_nonce(&self, nonce: &str) -> Result<(), WSError> {
+        match self.nonce.as_deref() {
+            Some(response_nonce) if response_nonce == nonce => Ok(()),
+            Some(response_nonce) => Err(WSError::ReplayedResponse(format!(
+                "nonce [{}] instead of [{}]",
+                response_nonce, nonce
+            ))),
+            None => Err(WSError::ReplayedResponse("no nonce".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::KeyPair;
+
+    fn response() -> UpdateCheckResponse {
+        let download = |url: &str| Download {
+            url: url.to_string(),
+            size: 1234,
+            sha256: "00".repeat(32),
+        };
+        UpdateCheckResponse {
+            update: Some(UpdateDescriptor {
+                version: Version::new(1, 2, 3),
+                build_id: "ab".repeat(32),
+                full: download("/artifacts/b.wasm"),
+                plan: UpdatePlan::Patches(vec![PatchDescriptor {
+                    base_build_id: "cd".repeat(32),
+                    build_id: "ab".repeat(32),
+                    download: download("/patches/a/b.patch"),
+                    signature: Some("c2lnbmF0dXJl".to_string()),
+                }]),
+                signature: None,
+                encoded: None,
+            }),
+            nonce: Some("0123456789abcdef".to_string()),
+            golden: None,
+        }
+    }
+
+    #[test]
+    fn cose_envelopes_round_trip() {
+        let response = response();
+        assert_eq!(
+            UpdateCheckResponse::from_cbor(&response.to_cbor().unwrap()).unwrap(),
+            response
+        );
+
+        let kp = KeyPair::generate();
+        let mut trusted_keys = KeyRing::new();
+        trusted_keys.add(kp.pk.clone());
+        let cose = response.to_cose(&kp.sk).unwrap();
+
+        // A tagged COSE_Sign1 array, with the algorithm and the key in the protected header.
+        let envelope = cbor::Value::decode(&cose).unwrap();
+        let cbor::Value::Tag(18, items) = envelope else {
+            panic!("{:?}", envelope);
+        };
+        let items = items.as_array().unwrap();
+        assert_eq!(items.len(), 4);
+        let protected = cbor::Value::decode(items[0].as_bytes().unwrap()).unwrap();
+        assert_eq!(
+            protected.get(&cbor::Value::Unsigned(1)),
+            Some(&cbor::Value::from(-8))
+        );
+        assert_eq!(
+            protected
+                .get(&cbor::Value::Unsigned(4))
+                .and_then(cbor::Value::as_bytes),
+            Some(&kp.pk.key_id()[..])
+        );
+        assert_eq!(items[2].as_bytes().unwrap(), response.to_cbor().unwrap());
+        assert_eq!(items[3].as_bytes().unwrap().len(), 64);
+
+        assert_eq!(
+            UpdateCheckResponse::from_cose_with_nonce(&cose, &trusted_keys, "0123456789abcdef")
+                .unwrap(),
+            response
+        );
+        assert!(matches!(
+            UpdateCheckResponse::from_cose_with_nonce(&cose, &trusted_keys, "fedcba9876543210"),
+            Err(WSError::ReplayedResponse(_))
+        ));
+
+        let mut other_keys = KeyRing::new();
+        other_keys.add(KeyPair::generate().pk);
+        assert!(UpdateCheckResponse::from_cose(&cose, &other_keys).is_err());
+        for i in 0..cose.len() {
+            let mut tampered = cose.clone();
+            tampered[i] ^= 0x01;
+            assert!(
+                UpdateCheckResponse::from_cose(&tampered, &trusted_keys).is_err(),
+                "byte {}",
+                i
+            );
+        }
+        assert!(UpdateCheckResponse::from_cose(&cose[..cose.len() - 1], &trusted_keys).is_err());
+    }
+}
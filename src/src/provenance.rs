@@ -0,0 +1,292 @@
+//! Supply-chain differences between module versions.
+//!
+//! Byte-level diffs say which sections changed, not whether a new version was built with another
+//! toolchain or ships different dependencies. This compares what modules declare about how they
+//! were produced: tools listed in the `producers` section, licenses in the `notices` section,
+//! components in an `sbom` section, and fields of a `build-info` section.
+
+use crate::diff::ChangeKind;
+use crate::error::*;
+use crate::notices::*;
+use crate::query::producers_metadata;
+use crate::timestamps::PRODUCERS_SECTION_NAME;
+use crate::wasm_module::*;
+
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str;
+
+/// Name of the custom section storing a CycloneDX software bill of materials, as JSON.
+pub const SBOM_SECTION_NAME: &str = "sbom";
+
+/// Name of the custom section storing build information.
+pub const BUILD_INFO_SECTION_NAME: &str = "build-info";
+
+/// What a provenance entry describes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ProvenanceCategory {
+    /// A tool from the `producers` section, named `field/tool`, with its version.
+    Producer,
+    /// A component from the bill of materials, with its version.
+    Component,
+    /// A package from the `notices` section, with its license.
+    License,
+    /// A field of the `build-info` section, with its value.
+    BuildInfo,
+}
+
+impl fmt::Display for ProvenanceCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProvenanceCategory::Producer => write!(f, "producer"),
+            ProvenanceCategory::Component => write!(f, "component"),
+            ProvenanceCategory::License => write!(f, "license"),
+            ProvenanceCategory::BuildInfo => write!(f, "build-info"),
+        }
+    }
+}
+
+/// An entry whose value differs between two modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProvenanceChange {
+    pub category: ProvenanceCategory,
+    pub name: String,
+    /// Value in the old module, if present.
+    pub old: Option<String>,
+    /// Value in the new module, if present.
+    pub new: Option<String>,
+}
+
+impl ProvenanceChange {
+    /// Return whether the entry was added, removed or modified.
+    pub fn kind(&self) -> ChangeKind {
+        match (&self.old, &self.new) {
+            (None, Some(_)) => ChangeKind::Added,
+            (Some(_), None) => ChangeKind::Removed,
+            (old, new) if old == new => ChangeKind::Unchanged,
+            _ => ChangeKind::Modified,
+        }
+    }
+}
+
+impl fmt::Display for ProvenanceChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.category, self.name, self.kind())?;
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, ": {} -> {}", old, new),
+            (Some(value), None) | (None, Some(value)) if !value.is_empty() => {
+                write!(f, ": {}", value)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The supply-chain differences between two modules.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProvenanceDiff {
+    /// Changed entries, sorted by category and name.
+    pub changes: Vec<ProvenanceChange>,
+}
+
+impl ProvenanceDiff {
+    /// Return `true` if the modules declare the same provenance.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Return the changed entries of a category.
+    pub fn category(
+        &self,
+        category: ProvenanceCategory,
+    ) -> impl Iterator<Item = &ProvenanceChange> {
+        self.changes
+            .iter()
+            .filter(move |change| change.category == category)
+    }
+}
+
+type ProvenanceEntries = BTreeMap<(ProvenanceCategory, String), String>;
+
+fn value_to_string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn add_producers(entries: &mut ProvenanceEntries, payload: &[u8]) -> Result<(), WSError> {
+    if let Value::Object(fields) = producers_metadata(payload)? {
+        for (field, tools) in fields {
+            if let Value::Object(tools) = tools {
+                for (tool, version) in tools {
+                    entries.insert(
+                        (ProvenanceCategory::Producer, format!("{}/{}", field, tool)),
+                        value_to_string(&version),
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A component listed in a bill of materials.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SbomComponent {
+    /// Name of the component, as `group/name` if it has a group.
+    pub name: String,
+    /// Version of the component, or an empty string if unknown.
+    pub version: String,
+    /// SPDX license identifiers or expressions of the component.
+    pub licenses: Vec<String>,
+}
+
+/// Parse the components of a CycloneDX bill of materials. Components without a name are skipped.
+///
+/// Licenses are read from the `expression`, `license.id` or `license.name` of each entry of
+/// `licenses`.
+pub fn sbom_components(payload: &[u8]) -> Result<Vec<SbomComponent>, WSError> {
+    let sbom: Value = serde_json::from_slice(payload)?;
+    let components = match sbom.get("components") {
+        Some(Value::Array(components)) => components,
+        _ => return Ok(vec![]),
+    };
+    Ok(components
+        .iter()
+        .filter_map(|component| {
+            let name = component.get("name").and_then(Value::as_str)?;
+            let name = match component.get("group").and_then(Value::as_str) {
+                Some(group) if !group.is_empty() => format!("{}/{}", group, name),
+                _ => name.to_string(),
+            };
+            let version = component
+                .get("version")
+                .map(value_to_string)
+                .unwrap_or_default();
+            let licenses = match component.get("licenses") {
+                Some(Value::Array(licenses)) => licenses
+                    .iter()
+                    .filter_map(|choice| {
+                        let license = choice.get("license");
+                        choice
+                            .get("expression")
+                            .or_else(|| license.and_then(|license| license.get("id")))
+                            .or_else(|| license.and_then(|license| license.get("name")))
+                            .and_then(Value::as_str)
+                            .map(str::to_string)
+                    })
+                    .collect(),
+                _ => vec![],
+            };
+            Some(SbomComponent {
+                name,
+                version,
+                licenses,
+            })
+        })
+        .collect())
+}
+
+/// JSON objects are compared field by field, and other payloads as a whole.
+fn add_build_info(entries: &mut ProvenanceEntries, payload: &[u8]) -> Result<(), WSError> {
+    match serde_json::from_slice(payload) {
+        Ok(Value::Object(fields)) => {
+            for (name, value) in fields {
+                entries.insert(
+                    (ProvenanceCategory::BuildInfo, name),
+                    value_to_string(&value),
+                );
+            }
+        }
+        _ => {
+            entries.insert(
+                (
+                    ProvenanceCategory::BuildInfo,
+                    BUILD_INFO_SECTION_NAME.to_string(),
+                ),
+                String::from_utf8_lossy(payload).into_owned(),
+            );
+        }
+    }
+    Ok(())
+}
+
+impl Module {
+    /// Return the components listed in the bill of materials of the module, if it has one.
+    pub fn sbom_components(&self) -> Result<Option<Vec<SbomComponent>>, WSError> {
+        for section in &self.sections {
+            if let Section::Custom(custom) = section {
+                if custom.name() == SBOM_SECTION_NAME {
+                    return Ok(Some(sbom_components(custom.payload())?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn provenance_entries(&self) -> Result<ProvenanceEntries, WSError> {
+        let mut entries = ProvenanceEntries::new();
+        let mut seen = BTreeSet::new();
+        for section in &self.sections {
+            let custom = match section {
+                Section::Custom(custom) if seen.insert(custom.name()) => custom,
+                _ => continue,
+            };
+            let payload = custom.payload();
+            match custom.name() {
+                PRODUCERS_SECTION_NAME => add_producers(&mut entries, payload)?,
+                NOTICES_SECTION_NAME => {
+                    for notice in Notices::deserialize(payload)?.entries {
+                        entries.insert(
+                            (ProvenanceCategory::License, notice.package),
+                            notice.license,
+                        );
+                    }
+                }
+                SBOM_SECTION_NAME => {
+                    for component in sbom_components(payload)? {
+                        entries.insert(
+                            (ProvenanceCategory::Component, component.name),
+                            component.version,
+                        );
+                    }
+                }
+                BUILD_INFO_SECTION_NAME => add_build_info(&mut entries, payload)?,
+                _ => {}
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Compare the provenance declared by the module with the one declared by a newer version.
+    ///
+    /// Only the first custom section with a given name is taken into account.
+    pub fn provenance_diff(&self, new: &Module) -> Result<ProvenanceDiff, WSError> {
+        let mut old_entries = self.provenance_entries()?;
+        let new_entries = new.provenance_entries()?;
+        let mut changes = vec![];
+        for ((category, name), new_value) in new_entries {
+            let old_value = old_entries.remove(&(category, name.clone()));
+            if old_value.as_ref() != Some(&new_value) {
+                changes.push(ProvenanceChange {
+                    category,
+                    name,
+                    old: old_value,
+                    new: Some(new_value),
+                });
+            }
+        }
+        for ((category, name), old_value) in old_entries {
+            changes.push(ProvenanceChange {
+                category,
+                name,
+                old: Some(old_value),
+                new: None,
+            });
+        }
+        changes.sort_by(|a, b| (a.category, &a.name).cmp(&(b.category, &b.name)));
+        Ok(ProvenanceDiff { changes })
+    }
+}
@@ -0,0 +1,210 @@
+use crate::error::*;
+use crate::flags::*;
+use crate::notices::*;
+use crate::security_version::*;
+use crate::timestamps::PRODUCERS_SECTION_NAME;
+use crate::wasm_module::*;
+
+use ct_codecs::{Encoder, Hex};
+use hmac_sha256::Hash;
+use serde_json::{json, Map, Value};
+use std::str;
+
+/// A step in a query path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryStep {
+    /// An object member.
+    Field(String),
+    /// An array element.
+    Index(usize),
+}
+
+/// A path into the metadata of a module, such as `custom['producers'].sdk` or `sections[0].digest`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    pub steps: Vec<QueryStep>,
+}
+
+impl Query {
+    /// Create an empty query, returning the whole metadata.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an object member to the path.
+    pub fn field(mut self, name: impl Into<String>) -> Self {
+        self.steps.push(QueryStep::Field(name.into()));
+        self
+    }
+
+    /// Add an array element to the path.
+    pub fn index(mut self, index: usize) -> Self {
+        self.steps.push(QueryStep::Index(index));
+        self
+    }
+
+    /// Parse a query.
+    ///
+    /// Members are accessed with `.name` or `['name']`, and array elements with `[index]`.
+    pub fn parse(query: &str) -> Result<Self, WSError> {
+        let invalid = || WSError::InvalidQuery(query.to_string());
+        let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        let mut steps = vec![];
+        let mut rest = query;
+        while !rest.is_empty() {
+            if let Some(bracketed) = rest.strip_prefix('[') {
+                let end = bracketed.find(']').ok_or_else(invalid)?;
+                let inner = &bracketed[..end];
+                let step = match inner.chars().next() {
+                    Some(quote @ ('\'' | '"')) => {
+                        let name = inner
+                            .strip_prefix(quote)
+                            .and_then(|s| s.strip_suffix(quote))
+                            .ok_or_else(invalid)?;
+                        QueryStep::Field(name.to_string())
+                    }
+                    _ => QueryStep::Index(inner.parse().map_err(|_| invalid())?),
+                };
+                steps.push(step);
+                rest = &bracketed[end + 1..];
+                continue;
+            }
+            let name = match rest.strip_prefix('.') {
+                Some(name) => name,
+                None if steps.is_empty() => rest,
+                None => return Err(invalid()),
+            };
+            let end = name.find(|c| !is_name_char(c)).unwrap_or(name.len());
+            if end == 0 {
+                return Err(invalid());
+            }
+            steps.push(QueryStep::Field(name[..end].to_string()));
+            rest = &name[end..];
+        }
+        Ok(Query { steps })
+    }
+
+    /// Evaluate the query against a value.
+    pub fn eval<'t>(&self, value: &'t Value) -> Option<&'t Value> {
+        self.steps.iter().try_fold(value, |value, step| match step {
+            QueryStep::Field(name) => value.get(name),
+            QueryStep::Index(index) => value.get(index),
+        })
+    }
+}
+
+/// Decode a `producers` section as an object mapping fields to tool names and versions.
+pub(crate) fn producers_metadata(payload: &[u8]) -> Result<Value, WSError> {
+    TakeExact::decode(payload, payload.len() as _, |reader| {
+        let mut fields = Map::new();
+        for _ in 0..varint::get32(reader)? {
+            let field = str::from_utf8(&reader.get_slice()?)?.to_string();
+            let mut values = Map::new();
+            for _ in 0..varint::get32(reader)? {
+                let name = str::from_utf8(&reader.get_slice()?)?.to_string();
+                let version = str::from_utf8(&reader.get_slice()?)?.to_string();
+                values.insert(name, Value::String(version));
+            }
+            fields.insert(field, Value::Object(values));
+        }
+        Ok(Value::Object(fields))
+    })
+}
+
+/// Decode the payload of a custom section.
+///
+/// Known sections are decoded structurally. Other payloads are returned as JSON if they are valid JSON,
+/// as a string if they are valid UTF-8, or as a hex string otherwise.
+fn custom_section_metadata(section: &CustomSection) -> Value {
+    let payload = section.payload();
+    let decoded = match section.name() {
+        PRODUCERS_SECTION_NAME => producers_metadata(payload).ok(),
+        NOTICES_SECTION_NAME => Notices::deserialize(payload).ok().map(|notices| {
+            notices
+                .entries
+                .iter()
+                .map(|notice| {
+                    json!({
+                        "package": notice.package,
+                        "license": notice.license,
+                        "text": notice.text,
+                    })
+                })
+                .collect()
+        }),
+        FEATURE_FLAGS_SECTION_NAME => FeatureFlags::deserialize(payload).ok().map(|flags| {
+            flags
+                .flags
+                .iter()
+                .map(|flag| (flag.name.clone(), Value::Bool(flag.default)))
+                .collect::<Map<_, _>>()
+                .into()
+        }),
+        SECURITY_VERSION_SECTION_NAME => {
+            deserialize_security_version(payload).ok().map(Value::from)
+        }
+        _ => None,
+    };
+    if let Some(decoded) = decoded {
+        return decoded;
+    }
+    if let Ok(value) = serde_json::from_slice(payload) {
+        return value;
+    }
+    match str::from_utf8(payload) {
+        Ok(text) => Value::String(text.to_string()),
+        Err(_) => Value::String(Hex::encode_to_string(payload).unwrap()),
+    }
+}
+
+impl Module {
+    /// Return the metadata of the module, as a JSON value.
+    ///
+    /// `type` is `module` or `component`, and `digest` is the SHA-256 digest of the module.
+    /// `sections` lists the sections with their stable identifier, key, offset in the module, size and digest.
+    /// `custom` maps the names of custom sections to their decoded content. Only the first
+    /// custom section with a given name is included there.
+    pub fn metadata(&self) -> Result<Value, WSError> {
+        let mut sections = vec![];
+        let mut offset = self.header.len();
+        for (key, section) in self.section_keys().into_iter().zip(&self.sections) {
+            let mut bytes = vec![];
+            section.serialize(&mut bytes)?;
+            sections.push(json!({
+                "id": key.stable_id(),
+                "key": key.to_string(),
+                "offset": offset,
+                "size": bytes.len(),
+                "digest": Hex::encode_to_string(Hash::hash(&bytes)).unwrap(),
+            }));
+            offset += bytes.len();
+        }
+        let mut custom = Map::new();
+        for section in &self.sections {
+            if let Section::Custom(section) = section {
+                if !custom.contains_key(section.name()) {
+                    custom.insert(section.name().to_string(), custom_section_metadata(section));
+                }
+            }
+        }
+        Ok(json!({
+            "type": if self.is_component() { "component" } else { "module" },
+            "size": offset,
+            "digest": Hex::encode_to_string(self.stable_hash()?).unwrap(),
+            "sections": sections,
+            "custom": custom,
+        }))
+    }
+
+    /// Extract a single value from the metadata of the module.
+    ///
+    /// Return `None` if the path doesn't exist.
+    pub fn query(&self, query: &str) -> Result<Option<Value>, WSError> {
+        self.query_with(&Query::parse(query)?)
+    }
+
+    /// Extract a single value from the metadata of the module, using a parsed query.
+    pub fn query_with(&self, query: &Query) -> Result<Option<Value>, WSError> {
+        Ok(query.eval(&self.metadata()?).cloned())
+    }
+}
@@ -0,0 +1,340 @@
+//! Client for wasm package registries.
+//!
+//! Packages are named `namespace:name`, and stored in OCI registries following the `wasm-pkg` conventions:
+//! a package is an OCI repository named `<prefix><namespace>/<name>`, and every version is a tag.
+//! The registry of a domain is discovered with `/.well-known/wasm-pkg/registry.json`.
+//! Registries only speaking the warg protocol are not supported.
+
+use crate::agent::{HttpTransport, ObjectMetadata, Transport};
+use crate::error::*;
+use crate::oci::*;
+use crate::protocol::*;
+use crate::signature::Signature;
+
+use log::*;
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::prelude::*;
+
+/// Path of the registry metadata of a domain.
+pub const WELL_KNOWN_PATH: &str = "/.well-known/wasm-pkg/registry.json";
+
+/// The name of a package, such as `wasi:http`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PackageName {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl PackageName {
+    /// Parse a package name.
+    pub fn parse(name: &str) -> Result<Self, WSError> {
+        let invalid = || WSError::InvalidKey(format!("package name [{}]", name));
+        let (namespace, name) = name.split_once(':').ok_or_else(invalid)?;
+        let valid = |s: &str| {
+            !s.is_empty()
+                && !s.starts_with('-')
+                && !s.ends_with('-')
+                && s.bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        };
+        if !valid(namespace) || !valid(name) {
+            return Err(invalid());
+        }
+        Ok(PackageName {
+            namespace: namespace.to_string(),
+            name: name.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for PackageName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.namespace, self.name)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RegistryMetadata {
+    preferred_protocol: Option<String>,
+    oci: Option<OciMetadata>,
+    oci_registry: Option<String>,
+    oci_namespace_prefix: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OciMetadata {
+    registry: Option<String>,
+    namespace_prefix: Option<String>,
+}
+
+/// A wasm package registry.
+#[derive(Debug)]
+pub struct PackageRegistry {
+    client: OciClient,
+    registry: String,
+    namespace_prefix: String,
+}
+
+impl PackageRegistry {
+    /// Use an OCI registry, such as `https://ghcr.io`, storing packages under `namespace_prefix`.
+    ///
+    /// `client` must be a client for that registry.
+    pub fn new(
+        client: OciClient,
+        registry: impl Into<String>,
+        namespace_prefix: impl Into<String>,
+    ) -> Self {
+        PackageRegistry {
+            client,
+            registry: registry.into().trim_end_matches('/').to_string(),
+            namespace_prefix: namespace_prefix.into(),
+        }
+    }
+
+    /// Return the OCI registry and namespace prefix a domain stores packages in.
+    pub fn discover(transport: &HttpTransport) -> Result<(String, String), WSError> {
+        let mut response = transport.request("GET", WELL_KNOWN_PATH, &[], &[])?;
+        if response.status != 200 {
+            return Err(WSError::HttpError(response.status));
+        }
+        let mut json = vec![];
+        (&mut response.body)
+            .take(MAX_MANIFEST_SIZE)
+            .read_to_end(&mut json)?;
+        let metadata: RegistryMetadata = serde_json::from_slice(&json)?;
+        let (registry, prefix) = match metadata.oci {
+            Some(oci) => (oci.registry, oci.namespace_prefix),
+            None => (metadata.oci_registry, metadata.oci_namespace_prefix),
+        };
+        let registry = match registry {
+            Some(registry) => registry,
+            None => {
+                let protocol = metadata.preferred_protocol.unwrap_or_default();
+                return Err(WSError::UnsupportedUrl(format!(
+                    "{} registry without OCI support",
+                    protocol
+                )));
+            }
+        };
+        let registry = match registry.contains("://") {
+            true => registry,
+            false => format!("https://{}", registry),
+        };
+        debug!("Packages are stored in [{}]", registry);
+        Ok((registry, prefix.unwrap_or_default()))
+    }
+
+    /// Return the OCI reference of a package version.
+    pub fn reference(&self, package: &PackageName, version: Option<&Version>) -> OciReference {
+        OciReference {
+            registry: self.registry.clone(),
+            repository: format!(
+                "{}{}/{}",
+                self.namespace_prefix, package.namespace, package.name
+            ),
+            tag: version.map(|version| version.to_string()),
+            digest: None,
+        }
+    }
+
+    /// Return the published versions of a package, sorted in increasing order.
+    ///
+    /// Tags that are not versions are ignored.
+    pub fn versions(&self, package: &PackageName) -> Result<Vec<Version>, WSError> {
+        let reference = self.reference(package, None);
+        let mut versions: Vec<_> = self
+            .client
+            .tags(&reference.repository)?
+            .iter()
+            .filter_map(|tag| Version::parse(tag).ok())
+            .collect();
+        versions.sort();
+        Ok(versions)
+    }
+
+    /// Return the highest version of a package matching a requirement, if any.
+    pub fn resolve(
+        &self,
+        package: &PackageName,
+        requirement: &VersionReq,
+    ) -> Result<Option<Version>, WSError> {
+        Ok(self
+            .versions(package)?
+            .into_iter()
+            .rev()
+            .find(|version| requirement.matches(version)))
+    }
+
+    /// Publish a version of a package, and return the digest of its manifest.
+    pub fn publish(&self, package: &PackageName, bundle: &OciBundle) -> Result<String, WSError> {
+        let version = bundle
+            .version
+            .as_ref()
+            .ok_or_else(|| WSError::InvalidKey("package version".to_string()))?;
+        // Build metadata can't be part of a tag.
+        if !version.build.is_empty() {
+            return Err(WSError::UnsupportedUrl(format!("{}@{}", package, version)));
+        }
+        self.client
+            .push(&self.reference(package, Some(version)), bundle)
+    }
+
+    /// Fetch the highest version of a package matching a requirement.
+    pub fn fetch(
+        &self,
+        package: &PackageName,
+        requirement: &VersionReq,
+    ) -> Result<OciBundle, WSError> {
+        let version = self
+            .resolve(package, requirement)?
+            .ok_or_else(|| WSError::NotFound(format!("{} matching {}", package, requirement)))?;
+        let mut bundle = self.client.pull(&self.reference(package, Some(&version)))?;
+        bundle.version = Some(version);
+        Ok(bundle)
+    }
+}
+
+/// A transport answering update checks from a package registry.
+///
+/// Every channel follows a version requirement, such as `^1` for `stable`. Devices are offered the
+/// highest matching version of the package, if it is newer than the installed one. Modules are
+/// downloaded directly from the registry, and signatures pushed along with them are passed to the agent.
+#[derive(Debug)]
+pub struct RegistryTransport {
+    registry: PackageRegistry,
+    package: PackageName,
+    channels: BTreeMap<String, VersionReq>,
+}
+
+impl RegistryTransport {
+    /// Create a transport for a package. Channels follow the latest version until `channel()` is called.
+    pub fn new(registry: PackageRegistry, package: PackageName) -> Self {
+        RegistryTransport {
+            registry,
+            package,
+            channels: BTreeMap::new(),
+        }
+    }
+
+    /// Make a channel follow a version requirement. Other channels are then rejected.
+    pub fn channel(mut self, channel: impl Into<String>, requirement: VersionReq) -> Self {
+        self.channels.insert(channel.into(), requirement);
+        self
+    }
+
+    fn requirement(&self, channel: &str) -> Result<VersionReq, WSError> {
+        if self.channels.is_empty() {
+            return Ok(VersionReq::STAR);
+        }
+        self.channels
+            .get(channel)
+            .cloned()
+            .ok_or_else(|| WSError::NotFound(format!("channel [{}]", channel)))
+    }
+
+    fn repository(&self) -> String {
+        self.registry.reference(&self.package, None).repository
+    }
+}
+
+/// Return the digest of a blob, given its download URL.
+fn blob_digest(url: &str) -> Result<&str, WSError> {
+    url.rsplit_once("/blobs/")
+        .map(|(_, digest)| digest)
+        .ok_or_else(|| WSError::UnsupportedUrl(url.to_string()))
+}
+
+impl Transport for RegistryTransport {
+    fn check(&self, request: &UpdateCheckRequest) -> Result<UpdateCheckResponse, WSError> {
+        let requirement = self.requirement(&request.channel)?;
+        let up_to_date = UpdateCheckResponse {
+            update: None,
+            nonce: request.nonce.clone(),
+            golden: None,
+        };
+        let version = match self.registry.resolve(&self.package, &requirement)? {
+            None => return Ok(up_to_date),
+            Some(version) => version,
+        };
+        if request
+            .installed_version
+            .as_ref()
+            .is_some_and(|installed| installed >= &version)
+        {
+            return Ok(up_to_date);
+        }
+        let reference = self.registry.reference(&self.package, Some(&version));
+        let (manifest, _) = self.registry.client.manifest(&reference)?;
+        let layer = |media_type| {
+            manifest
+                .layers
+                .iter()
+                .find(|layer| layer.media_type == media_type)
+        };
+        let module_layer = layer(MODULE_MEDIA_TYPE)
+            .ok_or_else(|| WSError::NotFound(format!("module layer of {}", reference)))?;
+        let build_id = module_layer
+            .digest
+            .strip_prefix("sha256:")
+            .ok_or(WSError::ParseError)?
+            .to_ascii_lowercase();
+        if request.installed_build_id.as_ref() == Some(&build_id) {
+            return Ok(up_to_date);
+        }
+        let signature = match layer(SIGNATURE_MEDIA_TYPE) {
+            None => None,
+            Some(signature_layer) => {
+                let signature_bin = self
+                    .registry
+                    .client
+                    .blob(&reference.repository, signature_layer)?;
+                Some(Signature::from_bytes(&signature_bin)?.to_base64())
+            }
+        };
+        debug!(
+            "Offering {} {} to [{}]",
+            self.package, version, request.device_id
+        );
+        Ok(UpdateCheckResponse {
+            update: Some(UpdateDescriptor {
+                version,
+                full: Download {
+                    url: format!("/v2/{}/blobs/{}", reference.repository, module_layer.digest),
+                    size: module_layer.size,
+                    sha256: build_id.clone(),
+                },
+                build_id,
+                plan: UpdatePlan::Full,
+                signature,
+                encoded: None,
+            }),
+            nonce: request.nonce.clone(),
+            golden: None,
+        })
+    }
+
+    fn get_range(
+        &self,
+        url: &str,
+        offset: u64,
+        len: Option<u64>,
+    ) -> Result<Box<dyn Read>, WSError> {
+        self.registry
+            .client
+            .blob_range(&self.repository(), blob_digest(url)?, offset, len)
+    }
+
+    fn get_metadata(&self, url: &str) -> Result<ObjectMetadata, WSError> {
+        let size = self
+            .registry
+            .client
+            .blob_size(&self.repository(), blob_digest(url)?)?
+            .ok_or_else(|| WSError::NotFound(url.to_string()))?;
+        Ok(ObjectMetadata { size: Some(size) })
+    }
+}
@@ -0,0 +1,287 @@
+//! Release notes derived from the differences between two versions of a module.
+//!
+//! Notes only describe what can be observed in the modules themselves: imports and exports that
+//! changed, feature flags that were added, removed or got a new default, and how the size of each
+//! section evolved. They can be rendered as Markdown for people, or as JSON for tools, and stored
+//! along with a published artifact.
+
+use crate::diff::*;
+use crate::error::*;
+use crate::flags::*;
+use crate::interface::*;
+use crate::wasm_module::*;
+
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+
+/// An import or an export that changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InterfaceNote {
+    /// `added`, `modified` or `removed`.
+    pub change: String,
+    /// `import` or `export`.
+    pub direction: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_signature: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_signature: Option<String>,
+}
+
+/// A feature flag that was added, removed, or whose default value changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FeatureFlagNote {
+    /// `added`, `modified` or `removed`.
+    pub change: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_default: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_default: Option<bool>,
+}
+
+/// A section whose size changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SectionSizeNote {
+    pub section: String,
+    /// Size of the section in the previous version, if present.
+    pub old_size: Option<u64>,
+    /// Size of the section in the new version, if present.
+    pub new_size: Option<u64>,
+}
+
+/// The release notes of a module version.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReleaseNotes {
+    /// Version the notes describe, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Version the changes are relative to, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_version: Option<String>,
+    /// Most significant impact of the changes, such as `interface` or `data-only`.
+    pub impact: String,
+    #[serde(default)]
+    pub interface_changes: Vec<InterfaceNote>,
+    #[serde(default)]
+    pub feature_flags: Vec<FeatureFlagNote>,
+    #[serde(default)]
+    pub sections: Vec<SectionSizeNote>,
+    /// Total size of the sections of the previous version.
+    pub old_size: u64,
+    /// Total size of the sections of the new version.
+    pub new_size: u64,
+}
+
+fn flag_notes(old: Option<FeatureFlags>, new: Option<FeatureFlags>) -> Vec<FeatureFlagNote> {
+    let (old, new) = (old.unwrap_or_default(), new.unwrap_or_default());
+    let mut notes = vec![];
+    for flag in &new.flags {
+        let (change, old_default) = match old.get(&flag.name) {
+            None => (ChangeKind::Added, None),
+            Some(default) if default == flag.default => continue,
+            Some(default) => (ChangeKind::Modified, Some(default)),
+        };
+        notes.push(FeatureFlagNote {
+            change: change.to_string(),
+            name: flag.name.clone(),
+            old_default,
+            new_default: Some(flag.default),
+        });
+    }
+    for flag in &old.flags {
+        if new.get(&flag.name).is_none() {
+            notes.push(FeatureFlagNote {
+                change: ChangeKind::Removed.to_string(),
+                name: flag.name.clone(),
+                old_default: Some(flag.default),
+                new_default: None,
+            });
+        }
+    }
+    notes
+}
+
+fn size(size: Option<u64>) -> String {
+    size.map(|size| size.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn enabled(default: bool) -> &'static str {
+    match default {
+        true => "enabled",
+        false => "disabled",
+    }
+}
+
+impl ReleaseNotes {
+    /// Compose release notes from the differences between two versions of a module.
+    pub fn new(
+        diff: &ModuleDiff,
+        interface: &InterfaceDiff,
+        old_flags: Option<FeatureFlags>,
+        new_flags: Option<FeatureFlags>,
+    ) -> Self {
+        let interface_changes = interface
+            .changes
+            .iter()
+            .map(|change| InterfaceNote {
+                change: change.kind.to_string(),
+                direction: change.direction.to_string(),
+                name: change.name.clone(),
+                old_signature: change.old_signature.clone(),
+                new_signature: change.new_signature.clone(),
+            })
+            .collect();
+        let sections = diff
+            .changed()
+            .filter(|change| change.old_size != change.new_size)
+            .map(|change| SectionSizeNote {
+                section: change.key.to_string(),
+                old_size: change.old_size,
+                new_size: change.new_size,
+            })
+            .collect();
+        ReleaseNotes {
+            version: None,
+            previous_version: None,
+            impact: diff.impact().to_string(),
+            interface_changes,
+            feature_flags: flag_notes(old_flags, new_flags),
+            sections,
+            old_size: diff.changes.iter().filter_map(|c| c.old_size).sum(),
+            new_size: diff.changes.iter().filter_map(|c| c.new_size).sum(),
+        }
+    }
+
+    /// Set the version the notes describe, and the version the changes are relative to.
+    pub fn versions(
+        mut self,
+        previous_version: impl Into<String>,
+        version: impl Into<String>,
+    ) -> Self {
+        self.previous_version = Some(previous_version.into());
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Return `true` if the notes don't list any change.
+    pub fn is_empty(&self) -> bool {
+        self.interface_changes.is_empty()
+            && self.feature_flags.is_empty()
+            && self.sections.is_empty()
+    }
+
+    /// Serialize the notes as JSON.
+    pub fn to_json(&self) -> Result<String, WSError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Deserialize notes from JSON.
+    pub fn from_json(json: &[u8]) -> Result<Self, WSError> {
+        Ok(serde_json::from_slice(json)?)
+    }
+
+    /// Render the notes as a Markdown document.
+    pub fn to_markdown(&self) -> String {
+        let mut md = String::new();
+        // Writing to a string can't fail.
+        let _ = self.write_markdown(&mut md);
+        md
+    }
+
+    fn write_markdown(&self, md: &mut String) -> std::fmt::Result {
+        match &self.version {
+            Some(version) => writeln!(md, "# Release notes: {}", version)?,
+            None => writeln!(md, "# Release notes")?,
+        }
+        writeln!(md)?;
+        if let Some(previous_version) = &self.previous_version {
+            write!(md, "Changes since {}. ", previous_version)?;
+        }
+        writeln!(md, "Impact: {}.", self.impact)?;
+        if self.is_empty() {
+            writeln!(md)?;
+            writeln!(md, "No changes.")?;
+            return Ok(());
+        }
+        if !self.interface_changes.is_empty() {
+            writeln!(md)?;
+            writeln!(md, "## Interface changes")?;
+            writeln!(md)?;
+            for note in &self.interface_changes {
+                write!(md, "- {} {} `{}`", note.change, note.direction, note.name)?;
+                match (&note.old_signature, &note.new_signature) {
+                    (Some(old), Some(new)) => writeln!(md, ": `{}` -> `{}`", old, new)?,
+                    (Some(signature), None) | (None, Some(signature)) => {
+                        writeln!(md, ": `{}`", signature)?
+                    }
+                    (None, None) => writeln!(md)?,
+                }
+            }
+        }
+        if !self.feature_flags.is_empty() {
+            writeln!(md)?;
+            writeln!(md, "## Feature flags")?;
+            writeln!(md)?;
+            for note in &self.feature_flags {
+                match (note.old_default, note.new_default) {
+                    (None, Some(new)) => {
+                        writeln!(md, "- added `{}`, {} by default", note.name, enabled(new))?
+                    }
+                    (Some(old), Some(new)) => writeln!(
+                        md,
+                        "- `{}` is now {} by default (was {})",
+                        note.name,
+                        enabled(new),
+                        enabled(old)
+                    )?,
+                    _ => writeln!(md, "- removed `{}`", note.name)?,
+                }
+            }
+        }
+        writeln!(md)?;
+        writeln!(md, "## Size")?;
+        writeln!(md)?;
+        writeln!(
+            md,
+            "Total: {} -> {} bytes ({:+})",
+            self.old_size,
+            self.new_size,
+            self.new_size as i64 - self.old_size as i64
+        )?;
+        if !self.sections.is_empty() {
+            writeln!(md)?;
+            writeln!(md, "| Section | Before | After |")?;
+            writeln!(md, "|---|---:|---:|")?;
+            for note in &self.sections {
+                writeln!(
+                    md,
+                    "| {} | {} | {} |",
+                    note.section.replace('|', "\\|"),
+                    size(note.old_size),
+                    size(note.new_size)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Module {
+    /// Compose the release notes of a newer version of the module.
+    ///
+    /// Interface changes are only listed for core modules.
+    pub fn release_notes(&self, new: &Module) -> Result<ReleaseNotes, WSError> {
+        let interface = match self.is_component() || new.is_component() {
+            true => InterfaceDiff::default(),
+            false => self.diff_interface(new)?,
+        };
+        Ok(ReleaseNotes::new(
+            &self.diff(new)?,
+            &interface,
+            self.feature_flags()?,
+            new.feature_flags()?,
+        ))
+    }
+}
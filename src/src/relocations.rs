@@ -0,0 +1,245 @@
+//! Comparison of modules ignoring relocation noise.
+//!
+//! Linking the same code with a different base address, or a different amount of static data
+//! before it, shifts every address embedded in the module, so that a section-level diff reports
+//! the code, data and globals as modified. Before comparing modules, addresses can be made
+//! relative to the start of their static data.
+//!
+//! Addresses are recognized heuristically, as values between the start and the end of the
+//! active data segments of the first memory, rounded up to 16 bytes so that the end of the data
+//! as seen by allocators is included: constants pushed by `i32.const` in function bodies
+//! and global initializers, segment offsets, and aligned 32-bit words in data segments. Offsets
+//! of memory instructions are left unchanged. Rewritten values keep the length of their encoding,
+//! so that canonical modules remain valid and keep the size of the original ones.
+
+use crate::diff::*;
+use crate::error::*;
+use crate::wasm_module::*;
+
+use std::ops::Range;
+use wasmparser::{
+    CodeSectionReader, ConstExpr, DataKind, DataSectionReader, GlobalSectionReader, Operator,
+};
+
+fn parse_error(_: wasmparser::BinaryReaderError) -> WSError {
+    WSError::ParseError
+}
+
+/// Encode a signed LEB128 integer, padded to `len` bytes. Return `None` if it doesn't fit.
+fn padded_sleb32(value: i32, len: usize) -> Option<Vec<u8>> {
+    if !(1..=5).contains(&len) {
+        return None;
+    }
+    let mut value = value as i64;
+    let mut encoded = Vec::with_capacity(len);
+    for _ in 1..len {
+        encoded.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    let last = (value & 0x7f) as u8;
+    let sign_extension = if last & 0x40 != 0 { -1 } else { 0 };
+    if value >> 7 != sign_extension {
+        return None;
+    }
+    encoded.push(last);
+    Some(encoded)
+}
+
+/// The range of addresses of the static data of a module.
+#[derive(Debug, Clone, Copy)]
+struct AddressRange {
+    start: u32,
+    end: u32,
+}
+
+impl AddressRange {
+    fn relative(&self, value: i32) -> Option<i32> {
+        let address = value as u32;
+        if address < self.start || address > self.end {
+            return None;
+        }
+        Some((address - self.start) as i32)
+    }
+}
+
+/// Return the value of a constant expression that is a single `i32.const`, and the byte range
+/// of its immediate.
+fn const_i32(expr: &ConstExpr) -> Result<Option<(i32, Range<usize>)>, WSError> {
+    let mut reader = expr.get_operators_reader();
+    let (op, offset) = reader.read_with_offset().map_err(parse_error)?;
+    let value = match op {
+        Operator::I32Const { value } => value,
+        _ => return Ok(None),
+    };
+    let immediate = offset + 1..reader.original_position();
+    match reader.read().map_err(parse_error)? {
+        Operator::End if reader.eof() => Ok(Some((value, immediate))),
+        _ => Ok(None),
+    }
+}
+
+/// Collect rewrites of `i32.const` immediates and data words of a section payload, as
+/// `(range, bytes)` pairs.
+struct Rewriter {
+    range: AddressRange,
+    edits: Vec<(Range<usize>, Vec<u8>)>,
+}
+
+impl Rewriter {
+    fn sleb(&mut self, value: i32, immediate: Range<usize>) {
+        if let Some(relative) = self.range.relative(value) {
+            if let Some(encoded) = padded_sleb32(relative, immediate.len()) {
+                self.edits.push((immediate, encoded));
+            }
+        }
+    }
+
+    fn word(&mut self, value: i32, at: usize) {
+        if let Some(relative) = self.range.relative(value) {
+            self.edits
+                .push((at..at + 4, (relative as u32).to_le_bytes().to_vec()));
+        }
+    }
+
+    fn apply(self, payload: &[u8]) -> Vec<u8> {
+        let mut payload = payload.to_vec();
+        for (range, bytes) in self.edits {
+            payload[range].copy_from_slice(&bytes);
+        }
+        payload
+    }
+}
+
+fn data_range(payload: &[u8]) -> Result<Option<AddressRange>, WSError> {
+    let mut range: Option<AddressRange> = None;
+    for data in DataSectionReader::new(payload, 0).map_err(parse_error)? {
+        let data = data.map_err(parse_error)?;
+        if let DataKind::Active {
+            memory_index: 0,
+            offset_expr,
+        } = data.kind
+        {
+            if let Some((offset, _)) = const_i32(&offset_expr)? {
+                let start = offset as u32;
+                let end = start.saturating_add(data.data.len() as u32);
+                range = Some(match range {
+                    None => AddressRange { start, end },
+                    Some(range) => AddressRange {
+                        start: range.start.min(start),
+                        end: range.end.max(end),
+                    },
+                });
+            }
+        }
+    }
+    Ok(range.map(|range| AddressRange {
+        start: range.start,
+        end: range.end.saturating_add(15) & !15,
+    }))
+}
+
+fn canonicalize_data(payload: &[u8], rewriter: &mut Rewriter) -> Result<(), WSError> {
+    for data in DataSectionReader::new(payload, 0).map_err(parse_error)? {
+        let data = data.map_err(parse_error)?;
+        let offset_expr = match data.kind {
+            DataKind::Active {
+                memory_index: 0,
+                offset_expr,
+            } => offset_expr,
+            _ => continue,
+        };
+        let (offset, immediate) = match const_i32(&offset_expr)? {
+            Some(offset) => offset,
+            None => continue,
+        };
+        rewriter.sleb(offset, immediate);
+        let bytes_start = data.range.end - data.data.len();
+        let first_aligned = (4 - (offset as u32 % 4)) as usize % 4;
+        for i in (first_aligned..data.data.len().saturating_sub(3)).step_by(4) {
+            let word = i32::from_le_bytes(data.data[i..i + 4].try_into().unwrap());
+            rewriter.word(word, bytes_start + i);
+        }
+    }
+    Ok(())
+}
+
+fn canonicalize_globals(payload: &[u8], rewriter: &mut Rewriter) -> Result<(), WSError> {
+    for global in GlobalSectionReader::new(payload, 0).map_err(parse_error)? {
+        let global = global.map_err(parse_error)?;
+        if let Some((value, immediate)) = const_i32(&global.init_expr)? {
+            rewriter.sleb(value, immediate);
+        }
+    }
+    Ok(())
+}
+
+fn canonicalize_code(payload: &[u8], rewriter: &mut Rewriter) -> Result<(), WSError> {
+    for body in CodeSectionReader::new(payload, 0).map_err(parse_error)? {
+        let body = body.map_err(parse_error)?;
+        let mut reader = body.get_operators_reader().map_err(parse_error)?;
+        while !reader.eof() {
+            let (op, offset) = reader.read_with_offset().map_err(parse_error)?;
+            if let Operator::I32Const { value } = op {
+                rewriter.sleb(value, offset + 1..reader.original_position());
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Module {
+    /// Return a copy of the module with memory addresses made relative to the start of its
+    /// static data, for comparisons that ignore relocations.
+    ///
+    /// Modules without active data segments at constant offsets are returned unchanged.
+    pub fn canonicalize_addresses(&self) -> Result<Module, WSError> {
+        if self.is_component() {
+            return Err(WSError::UnsupportedModuleType);
+        }
+        let mut range = None;
+        for section in &self.sections {
+            if section.id() == SectionId::Data {
+                range = data_range(section.payload())?;
+            }
+        }
+        let range = match range {
+            None => return Ok(self.clone()),
+            Some(range) => range,
+        };
+        let mut module = self.clone();
+        for section in module.sections.iter_mut() {
+            let canonicalize = match section {
+                Section::Custom(_) => continue,
+                Section::Standard(_) => match section.id() {
+                    SectionId::Data => canonicalize_data,
+                    SectionId::Global => canonicalize_globals,
+                    SectionId::Code => canonicalize_code,
+                    _ => continue,
+                },
+            };
+            let mut rewriter = Rewriter {
+                range,
+                edits: vec![],
+            };
+            canonicalize(section.payload(), &mut rewriter)?;
+            if !rewriter.edits.is_empty() {
+                *section = Section::new(section.id(), rewriter.apply(section.payload()))?;
+            }
+        }
+        Ok(module)
+    }
+
+    /// Compare the sections of this module with the sections of a newer version, leaving out
+    /// the ignored sections, after making the memory addresses of both modules relative to the
+    /// start of their static data.
+    ///
+    /// Modules built from the same code with different base addresses compare as identical.
+    pub fn diff_ignoring_relocations(
+        &self,
+        new: &Module,
+        ignored: &IgnoreList,
+    ) -> Result<ModuleDiff, WSError> {
+        self.canonicalize_addresses()?
+            .diff_ignoring(&new.canonicalize_addresses()?, ignored)
+    }
+}
@@ -0,0 +1,180 @@
+use super::{validate_name, Backend, Repository};
+use crate::error::*;
+use crate::wasm_module::*;
+
+use ct_codecs::{Decoder, Encoder, Hex};
+use hmac_sha256::Hash;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+
+/// A module made of sections stored separately, by digest.
+///
+/// Variants of a module can be built by changing the list of sections, without storing
+/// complete copies of them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModuleTemplate {
+    /// Hex-encoded module header.
+    pub header: String,
+    /// Hex-encoded SHA-256 digests of the serialized sections, in order.
+    pub sections: Vec<String>,
+}
+
+impl ModuleTemplate {
+    /// Return the module header, checking that it is a module or component header.
+    pub fn header(&self) -> Result<Header, WSError> {
+        let header: Header = Hex::decode_to_vec(&self.header, None)
+            .ok()
+            .and_then(|header| header.try_into().ok())
+            .ok_or(WSError::ParseError)?;
+        Module::stream_init(&mut header.as_slice())
+    }
+}
+
+struct HashWriter<W: Write> {
+    writer: W,
+    hash: Hash,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.hash.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    fn section_key(digest: &str) -> String {
+        format!("sections/{}.bin", digest)
+    }
+
+    fn template_key(name: &str) -> String {
+        format!("templates/{}.json", name)
+    }
+
+    /// Store a section, and return its hex-encoded digest, the one returned by `Section::stable_hash()`.
+    ///
+    /// Sections are stored once, however many templates refer to them.
+    pub fn add_section(&self, section: &Section) -> Result<String, WSError> {
+        let mut section_bin = vec![];
+        section.serialize(&mut section_bin)?;
+        let digest = Hex::encode_to_string(Hash::hash(&section_bin)).unwrap();
+        let key = Self::section_key(&digest);
+        if self.backend.get(&key)?.is_none() {
+            self.backend.put(&key, &section_bin)?;
+        }
+        Ok(digest)
+    }
+
+    /// Return a serialized section, checking it against its digest.
+    fn section_bin(&self, digest: &str) -> Result<Vec<u8>, WSError> {
+        validate_name(digest)?;
+        let section_bin = self
+            .backend
+            .get(&Self::section_key(digest))?
+            .ok_or_else(|| WSError::NotFound(format!("section {}", digest)))?;
+        if !Hex::encode_to_string(Hash::hash(&section_bin))
+            .unwrap()
+            .eq_ignore_ascii_case(digest)
+        {
+            return Err(WSError::DigestMismatch);
+        }
+        Ok(section_bin)
+    }
+
+    /// Return a stored section, checking it against its digest.
+    pub fn section(&self, digest: &str) -> Result<Section, WSError> {
+        let section_bin = self.section_bin(digest)?;
+        let mut reader = section_bin.as_slice();
+        match Section::deserialize(&mut reader)? {
+            Some(section) if reader.is_empty() => Ok(section),
+            _ => Err(WSError::ParseError),
+        }
+    }
+
+    /// Store the sections of a module, and return a template to assemble it again.
+    pub fn add_fragments(&self, module: &Module) -> Result<ModuleTemplate, WSError> {
+        let sections = module
+            .sections
+            .iter()
+            .map(|section| self.add_section(section))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ModuleTemplate {
+            header: Hex::encode_to_string(module.header).unwrap(),
+            sections,
+        })
+    }
+
+    /// Store a template under a name, replacing any previous version.
+    ///
+    /// All the sections it refers to must have been stored.
+    pub fn add_template(&self, name: &str, template: &ModuleTemplate) -> Result<(), WSError> {
+        validate_name(name)?;
+        template.header()?;
+        for digest in &template.sections {
+            validate_name(digest)?;
+            if self.backend.get(&Self::section_key(digest))?.is_none() {
+                return Err(WSError::NotFound(format!("section {}", digest)));
+            }
+        }
+        self.backend.put(
+            &Self::template_key(name),
+            &serde_json::to_vec_pretty(template)?,
+        )
+    }
+
+    /// Return a stored template.
+    pub fn template(&self, name: &str) -> Result<ModuleTemplate, WSError> {
+        validate_name(name)?;
+        let json = self
+            .backend
+            .get(&Self::template_key(name))?
+            .ok_or_else(|| WSError::NotFound(format!("template {}", name)))?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    /// Assemble a module from a template, write it to `writer`, and return its build identifier.
+    ///
+    /// Sections are fetched and written one at a time, so that the module is never held in memory.
+    pub fn compose(
+        &self,
+        template: &ModuleTemplate,
+        writer: &mut impl Write,
+    ) -> Result<String, WSError> {
+        let mut writer = HashWriter {
+            writer,
+            hash: Hash::new(),
+        };
+        writer.write_all(&template.header()?)?;
+        for digest in &template.sections {
+            writer.write_all(&self.section_bin(digest)?)?;
+        }
+        writer.flush()?;
+        let build_id = Hex::encode_to_string(writer.hash.finalize()).unwrap();
+        debug!(
+            "Composed [{}] from {} sections",
+            build_id,
+            template.sections.len()
+        );
+        Ok(build_id)
+    }
+
+    /// Assemble a module from a template, in memory.
+    pub fn compose_module(&self, template: &ModuleTemplate) -> Result<Module, WSError> {
+        let sections = template
+            .sections
+            .iter()
+            .map(|digest| self.section(digest))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Module {
+            header: template.header()?,
+            sections,
+            quarantine: vec![],
+        })
+    }
+}
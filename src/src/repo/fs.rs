@@ -0,0 +1,107 @@
+use super::Backend;
+use crate::error::*;
+
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// A repository backend storing objects as files under a root directory.
+#[derive(Debug, Clone)]
+pub struct FsBackend {
+    root: PathBuf,
+}
+
+impl FsBackend {
+    /// Create a backend rooted at the given directory, creating it if necessary.
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, WSError> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        Ok(FsBackend { root })
+    }
+
+    /// Return the root directory of the backend.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, WSError> {
+        let mut path = self.root.clone();
+        for component in key.split('/') {
+            if component.is_empty() || component == "." || component == ".." {
+                return Err(WSError::InvalidKey(key.to_string()));
+            }
+            path.push(component);
+        }
+        Ok(path)
+    }
+
+    fn list_dir(&self, dir: &Path, prefix: &str, keys: &mut Vec<String>) -> Result<(), WSError> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if name.starts_with('.') {
+                continue;
+            }
+            let key = if prefix.is_empty() {
+                name
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            if entry.file_type()?.is_dir() {
+                self.list_dir(&entry.path(), &key, keys)?;
+            } else {
+                keys.push(key);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Backend for FsBackend {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), WSError> {
+        let path = self.path(key)?;
+        let parent = path.parent().ok_or(WSError::InvalidKey(key.to_string()))?;
+        fs::create_dir_all(parent)?;
+        let file_name = path.file_name().unwrap().to_string_lossy();
+        let tmp_path = parent.join(format!(".{}.tmp", file_name));
+        let mut fp = fs::File::create(&tmp_path)?;
+        fp.write_all(data)?;
+        fp.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, WSError> {
+        match fs::read(self.path(key)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, WSError> {
+        let prefix = prefix.trim_end_matches('/');
+        let dir = if prefix.is_empty() {
+            self.root.clone()
+        } else {
+            self.path(prefix)?
+        };
+        let mut keys = vec![];
+        if dir.is_dir() {
+            self.list_dir(&dir, prefix, &mut keys)?;
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), WSError> {
+        match fs::remove_file(self.path(key)?) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
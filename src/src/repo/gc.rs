@@ -0,0 +1,113 @@
+use super::{Backend, Repository};
+use crate::error::*;
+
+use log::*;
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Options for `Repository::collect_garbage()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GarbageCollection {
+    /// Minimum time since an artifact was published before it can be removed, in seconds.
+    pub min_age: u64,
+    /// Only report what would be removed.
+    pub dry_run: bool,
+}
+
+impl GarbageCollection {
+    /// Create options removing every artifact that isn't on any channel.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep artifacts published less than `min_age` seconds ago, so that artifacts being
+    /// published are not removed before they are added to a channel.
+    pub fn min_age(mut self, min_age: u64) -> Self {
+        self.min_age = min_age;
+        self
+    }
+
+    /// Only report what would be removed, without modifying the repository.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+}
+
+/// The result of `Repository::collect_garbage()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GarbageReport {
+    /// Build identifiers of the removed artifacts.
+    pub artifacts: Vec<String>,
+    /// Keys of the removed objects: modules, encodings, metadata, signatures and patches.
+    pub objects: Vec<String>,
+}
+
+impl<B: Backend> Repository<B> {
+    /// Remove the artifacts that are not on any channel, and not pinned as golden artifacts,
+    /// along with their encodings, signatures, and the patches producing them.
+    ///
+    /// Patches from a removed artifact to an artifact that is kept are left in place, as devices
+    /// may still be running it. The metadata of an artifact is removed last, so that an
+    /// interrupted collection can be resumed.
+    pub fn collect_garbage(&self, gc: &GarbageCollection) -> Result<GarbageReport, WSError> {
+        let mut referenced = HashSet::new();
+        for channel in self.channels()? {
+            for release in self.versions(&channel)? {
+                referenced.insert(release.build_id);
+            }
+        }
+        for (_, release) in self.golden_artifacts()? {
+            referenced.insert(release.build_id);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let build_ids: HashSet<String> = self.build_ids()?.into_iter().collect();
+        let mut collected = HashSet::new();
+        for build_id in &build_ids {
+            if referenced.contains(build_id) {
+                continue;
+            }
+            match self.info(build_id) {
+                Ok(info) if now.saturating_sub(info.published_at) >= gc.min_age => {
+                    collected.insert(build_id.as_str());
+                }
+                Ok(_) => debug!("Keeping recent artifact [{}]", build_id),
+                Err(e) => warn!("Unable to read the metadata of [{}]: {}", build_id, e),
+            }
+        }
+
+        let mut report = GarbageReport::default();
+        let mut artifact_keys: Vec<String> = self
+            .backend
+            .list("artifacts")?
+            .into_iter()
+            .filter(|key| {
+                key.strip_prefix("artifacts/")
+                    .and_then(|name| name.split('.').next())
+                    .is_some_and(|build_id| collected.contains(build_id))
+            })
+            .collect();
+        artifact_keys.sort_by_key(|key| key.ends_with(".json"));
+        let patch_keys = self.backend.list("patches")?.into_iter().filter(|key| {
+            key.strip_prefix("patches/")
+                .and_then(|name| name.split_once('/'))
+                .and_then(|(_, name)| name.split('.').next())
+                .is_some_and(|build_id| {
+                    collected.contains(build_id) || !build_ids.contains(build_id)
+                })
+        });
+        for key in patch_keys.chain(artifact_keys) {
+            if !gc.dry_run {
+                self.backend.delete(&key)?;
+            }
+            debug!("Removed [{}]", key);
+            report.objects.push(key);
+        }
+        report.artifacts = collected.into_iter().map(|s| s.to_string()).collect();
+        report.artifacts.sort();
+        Ok(report)
+    }
+}
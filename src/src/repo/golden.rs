@@ -0,0 +1,93 @@
+use super::{validate_name, Backend, Release, Repository};
+use crate::error::*;
+use crate::protocol::*;
+
+use log::*;
+
+impl<B: Backend> Repository<B> {
+    fn golden_key(channel: &str) -> String {
+        format!("golden/{}.json", channel)
+    }
+
+    /// Pin an artifact as the golden artifact of a channel.
+    ///
+    /// Devices following the channel keep a copy of it aside, verified independently of the
+    /// regular updates, to restore it if they can't recover otherwise. The artifact doesn't have to
+    /// be on the channel, and is never garbage collected while it is pinned. Pinning another
+    /// artifact replaces the previous one.
+    pub fn pin_golden(&self, channel: &str, build_id: &str) -> Result<Release, WSError> {
+        validate_name(channel)?;
+        let info = self.info(build_id)?;
+        let release = Release {
+            version: info.version,
+            build_id: info.build_id,
+        };
+        self.backend.put(
+            &Self::golden_key(channel),
+            &serde_json::to_vec_pretty(&release)?,
+        )?;
+        debug!(
+            "Pinned {} ({}) as the golden artifact of channel [{}]",
+            release.build_id, release.version, channel
+        );
+        Ok(release)
+    }
+
+    /// Remove the golden artifact of a channel. Devices keep the copy they already have.
+    pub fn unpin_golden(&self, channel: &str) -> Result<(), WSError> {
+        validate_name(channel)?;
+        self.backend.delete(&Self::golden_key(channel))
+    }
+
+    /// Return the golden artifact of a channel, if one is pinned.
+    pub fn golden(&self, channel: &str) -> Result<Option<Release>, WSError> {
+        validate_name(channel)?;
+        match self.backend.get(&Self::golden_key(channel))? {
+            None => Ok(None),
+            Some(json) => Ok(Some(serde_json::from_slice(&json)?)),
+        }
+    }
+
+    /// Return the golden artifacts of all the channels.
+    pub fn golden_artifacts(&self) -> Result<Vec<(String, Release)>, WSError> {
+        let mut golden_artifacts = vec![];
+        for key in self.backend.list("golden")? {
+            let channel = match key
+                .strip_prefix("golden/")
+                .and_then(|name| name.strip_suffix(".json"))
+            {
+                None => continue,
+                Some(channel) => channel,
+            };
+            if let Some(release) = self.golden(channel)? {
+                golden_artifacts.push((channel.to_string(), release));
+            }
+        }
+        Ok(golden_artifacts)
+    }
+
+    /// Describe the golden artifact of a channel for devices.
+    pub(super) fn golden_descriptor(
+        &self,
+        channel: &str,
+    ) -> Result<Option<GoldenArtifact>, WSError> {
+        let release = match self.golden(channel)? {
+            None => return Ok(None),
+            Some(release) => release,
+        };
+        let info = self.info(&release.build_id)?;
+        let signature = self
+            .signature(&info.build_id)?
+            .map(|signature| signature.to_base64());
+        Ok(Some(GoldenArtifact {
+            version: info.version,
+            download: Download {
+                url: format!("/{}", Self::artifact_key(&info.build_id)),
+                size: info.size,
+                sha256: info.build_id.clone(),
+            },
+            build_id: info.build_id,
+            signature,
+        }))
+    }
+}
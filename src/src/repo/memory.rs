@@ -0,0 +1,75 @@
+use super::{content_version, validate_key, Backend};
+use crate::error::*;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// A repository backend keeping objects in memory.
+///
+/// Clones share the same objects, so that a repository can be populated while a transport
+/// reads from it.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl MemoryBackend {
+    /// Create an empty backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn objects(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, Vec<u8>>> {
+        self.objects.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), WSError> {
+        validate_key(key)?;
+        self.objects().insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, WSError> {
+        validate_key(key)?;
+        Ok(self.objects().get(key).cloned())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, WSError> {
+        let prefix = prefix.trim_end_matches('/');
+        if prefix.is_empty() {
+            return Ok(self.objects().keys().cloned().collect());
+        }
+        validate_key(prefix)?;
+        let dir = format!("{}/", prefix);
+        Ok(self
+            .objects()
+            .keys()
+            .filter(|key| key.starts_with(&dir))
+            .cloned()
+            .collect())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), WSError> {
+        validate_key(key)?;
+        self.objects().remove(key);
+        Ok(())
+    }
+
+    fn put_if_version(
+        &self,
+        key: &str,
+        data: &[u8],
+        version: Option<&str>,
+    ) -> Result<bool, WSError> {
+        validate_key(key)?;
+        let mut objects = self.objects();
+        let current = objects.get(key).map(|data| content_version(data));
+        if current.as_deref() != version {
+            return Ok(false);
+        }
+        objects.insert(key.to_string(), data.to_vec());
+        Ok(true)
+    }
+}
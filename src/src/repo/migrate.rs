@@ -0,0 +1,223 @@
+use super::{Backend, Repository};
+use crate::error::*;
+use crate::signature::*;
+
+use log::*;
+
+/// Options for `Repository::migrate_signatures()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SignatureMigration {
+    /// Keys whose signatures must be replaced.
+    pub retired_keys: Vec<KeyId>,
+    /// Sign artifacts and patches that don't have a signature yet.
+    pub sign_missing: bool,
+    /// Only report what would be done.
+    pub dry_run: bool,
+}
+
+impl SignatureMigration {
+    /// Create options replacing only signatures that can't be read any more.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the signatures made by a key.
+    pub fn retire_key(mut self, key_id: KeyId) -> Self {
+        self.retired_keys.push(key_id);
+        self
+    }
+
+    /// Sign artifacts and patches that don't have a signature yet.
+    pub fn sign_missing(mut self, sign_missing: bool) -> Self {
+        self.sign_missing = sign_missing;
+        self
+    }
+
+    /// Only report what would be done, without modifying the repository.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+}
+
+/// What was done with a signature.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MigrationAction {
+    /// The signature was already in the current format.
+    Unchanged,
+    /// The signature was rewritten in the current format.
+    Upgraded,
+    /// The signature was replaced with a new one, as it used an unsupported format or a retired key.
+    Resigned,
+    /// A signature was added.
+    Signed,
+}
+
+/// A signature handled by a migration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigratedSignature {
+    /// The build identifier of the artifact, or `base/target` for a patch.
+    pub object: String,
+    pub action: MigrationAction,
+}
+
+/// The result of `Repository::migrate_signatures()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub signatures: Vec<MigratedSignature>,
+    /// Objects that couldn't be migrated, along with the reason.
+    pub failures: Vec<(String, String)>,
+}
+
+impl MigrationReport {
+    /// Return the number of signatures handled with the given action.
+    pub fn count(&self, action: MigrationAction) -> usize {
+        self.signatures
+            .iter()
+            .filter(|signature| signature.action == action)
+            .count()
+    }
+}
+
+impl<B: Backend> Repository<B> {
+    fn migration_action(
+        &self,
+        bytes: Option<Vec<u8>>,
+        migration: &SignatureMigration,
+    ) -> Option<MigrationAction> {
+        let bytes = match bytes {
+            None if migration.sign_missing => return Some(MigrationAction::Signed),
+            None => return None,
+            Some(bytes) => bytes,
+        };
+        match Signature::from_bytes(&bytes) {
+            Err(_) => Some(MigrationAction::Resigned),
+            Ok(signature) if migration.retired_keys.contains(&signature.key_id) => {
+                Some(MigrationAction::Resigned)
+            }
+            Ok(signature) if signature.to_bytes() != bytes => Some(MigrationAction::Upgraded),
+            Ok(_) => Some(MigrationAction::Unchanged),
+        }
+    }
+
+    /// Check that an artifact can be signed with a key, which must be bound to its signing domain.
+    fn check_signing_domain(&self, build_id: &str, sk: &SecretKey) -> Result<(), WSError> {
+        let info = self.info(build_id)?;
+        if info.domain.as_deref() != sk.domain() {
+            return Err(WSError::DomainMismatch(format!(
+                "{} belongs to domain [{}]",
+                build_id,
+                info.domain.as_deref().unwrap_or_default()
+            )));
+        }
+        Ok(())
+    }
+
+    fn migrate_artifact_signature(
+        &self,
+        build_id: &str,
+        sk: &SecretKey,
+        migration: &SignatureMigration,
+    ) -> Result<Option<MigrationAction>, WSError> {
+        let bytes = self.backend.get(&Self::signature_key(build_id))?;
+        let action = match self.migration_action(bytes.clone(), migration) {
+            None => return Ok(None),
+            Some(MigrationAction::Unchanged) => return Ok(Some(MigrationAction::Unchanged)),
+            Some(action) => action,
+        };
+        let signature = match action {
+            MigrationAction::Upgraded => Signature::from_bytes(&bytes.unwrap_or_default())?,
+            _ => {
+                self.check_signing_domain(build_id, sk)?;
+                sk.sign_module(&self.artifact(build_id)?)
+            }
+        };
+        if !migration.dry_run {
+            self.add_signature(build_id, &signature)?;
+        }
+        Ok(Some(action))
+    }
+
+    fn migrate_patch_signature(
+        &self,
+        base_build_id: &str,
+        build_id: &str,
+        sk: &SecretKey,
+        migration: &SignatureMigration,
+    ) -> Result<Option<MigrationAction>, WSError> {
+        let bytes = self
+            .backend
+            .get(&Self::patch_signature_key(base_build_id, build_id))?;
+        let action = match self.migration_action(bytes.clone(), migration) {
+            None => return Ok(None),
+            Some(MigrationAction::Unchanged) => return Ok(Some(MigrationAction::Unchanged)),
+            Some(action) => action,
+        };
+        let signature = match action {
+            MigrationAction::Upgraded => Signature::from_bytes(&bytes.unwrap_or_default())?,
+            _ => {
+                let patch = self
+                    .patch(base_build_id, build_id)?
+                    .ok_or_else(|| WSError::NotFound(format!("{}/{}", base_build_id, build_id)))?;
+                self.check_signing_domain(build_id, sk)?;
+                sk.sign_patch(base_build_id, build_id, &patch)?
+            }
+        };
+        if !migration.dry_run {
+            self.add_patch_signature(base_build_id, build_id, &signature)?;
+        }
+        Ok(Some(action))
+    }
+
+    /// Bring the signatures of all artifacts and patches to the current format.
+    ///
+    /// Signatures in an older, still readable format are rewritten. Signatures that can't be read any more,
+    /// or that were made by a retired key, are replaced with new signatures made with `sk`.
+    /// Objects that fail to migrate are listed in the report, and don't stop the migration.
+    pub fn migrate_signatures(
+        &self,
+        sk: &SecretKey,
+        migration: &SignatureMigration,
+    ) -> Result<MigrationReport, WSError> {
+        let mut report = MigrationReport::default();
+        let artifacts = self.backend.list("artifacts")?;
+        let build_ids = artifacts
+            .iter()
+            .filter_map(|key| key.strip_prefix("artifacts/")?.strip_suffix(".json"));
+        let patches = self.backend.list("patches")?;
+        let patch_ids = patches.iter().filter_map(|key| {
+            key.strip_prefix("patches/")?
+                .strip_suffix(".patch")?
+                .split_once('/')
+        });
+        let objects = build_ids
+            .map(|build_id| (None, build_id))
+            .chain(patch_ids.map(|(base, build_id)| (Some(base), build_id)));
+        for (base_build_id, build_id) in objects {
+            let (object, res) = match base_build_id {
+                None => (
+                    build_id.to_string(),
+                    self.migrate_artifact_signature(build_id, sk, migration),
+                ),
+                Some(base_build_id) => (
+                    format!("{}/{}", base_build_id, build_id),
+                    self.migrate_patch_signature(base_build_id, build_id, sk, migration),
+                ),
+            };
+            match res {
+                Ok(None) => {}
+                Ok(Some(action)) => {
+                    if action != MigrationAction::Unchanged {
+                        debug!("Signature of {}: {:?}", object, action);
+                    }
+                    report.signatures.push(MigratedSignature { object, action });
+                }
+                Err(e) => {
+                    warn!("Unable to migrate the signature of {}: {}", object, e);
+                    report.failures.push((object, e.to_string()));
+                }
+            }
+        }
+        Ok(report)
+    }
+}
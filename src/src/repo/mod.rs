@@ -0,0 +1,861 @@
+mod fragments;
+mod fs;
+mod gc;
+mod golden;
+mod memory;
+mod migrate;
+mod notes;
+mod planner;
+#[cfg(feature = "encryption")]
+mod rekey;
+#[cfg(feature = "s3")]
+mod s3;
+
+pub use fragments::*;
+pub use fs::FsBackend;
+pub use gc::*;
+pub use memory::MemoryBackend;
+pub use migrate::*;
+pub use planner::*;
+#[cfg(feature = "encryption")]
+pub use rekey::*;
+#[cfg(feature = "s3")]
+pub use s3::S3Backend;
+
+#[cfg(feature = "zstd")]
+use crate::codec::{apply_dictionary_patch, DictionaryPatch};
+use crate::codec::{Codec, NO_ENCODING};
+use crate::error::*;
+use crate::protocol::*;
+use crate::signature::*;
+use crate::wasm_module::*;
+
+use ct_codecs::{Encoder, Hex};
+use hmac_sha256::Hash;
+use log::*;
+use schemars::JsonSchema;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A key/value object store used by a repository.
+///
+/// Keys are `/`-separated paths made of non-empty components.
+pub trait Backend {
+    /// Store an object, replacing any previous version.
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), WSError>;
+
+    /// Retrieve an object, or `None` if it doesn't exist.
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, WSError>;
+
+    /// List the keys of all objects under the given prefix, in lexicographic order.
+    fn list(&self, prefix: &str) -> Result<Vec<String>, WSError>;
+
+    /// Delete an object. Deleting an object that doesn't exist is not an error.
+    fn delete(&self, key: &str) -> Result<(), WSError>;
+
+    /// Retrieve an object along with an opaque tag identifying its current version.
+    fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, String)>, WSError> {
+        Ok(self.get(key)?.map(|data| {
+            let version = content_version(&data);
+            (data, version)
+        }))
+    }
+
+    /// Store an object if its version is still `version`, or if it doesn't exist when `version` is `None`.
+    ///
+    /// Return `false`, without storing anything, if the object was modified in the meantime.
+    /// The default implementation is not atomic; backends shared by concurrent publishers should override it.
+    fn put_if_version(
+        &self,
+        key: &str,
+        data: &[u8],
+        version: Option<&str>,
+    ) -> Result<bool, WSError> {
+        let current = self.get_versioned(key)?;
+        if current.as_ref().map(|(_, current)| current.as_str()) != version {
+            return Ok(false);
+        }
+        self.put(key, data)?;
+        Ok(true)
+    }
+}
+
+/// A boxed backend, for repositories whose backend is chosen at runtime.
+impl<B: Backend + ?Sized> Backend for Box<B> {
+    fn put(&self, key: &str, data: &[u8]) -> Result<(), WSError> {
+        (**self).put(key, data)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, WSError> {
+        (**self).get(key)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, WSError> {
+        (**self).list(prefix)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), WSError> {
+        (**self).delete(key)
+    }
+
+    fn get_versioned(&self, key: &str) -> Result<Option<(Vec<u8>, String)>, WSError> {
+        (**self).get_versioned(key)
+    }
+
+    fn put_if_version(
+        &self,
+        key: &str,
+        data: &[u8],
+        version: Option<&str>,
+    ) -> Result<bool, WSError> {
+        (**self).put_if_version(key, data, version)
+    }
+}
+
+/// Maximum number of attempts to update a channel that keeps being modified concurrently.
+const MAX_UPDATE_ATTEMPTS: usize = 16;
+
+/// Metadata about a published artifact.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ArtifactInfo {
+    /// Build identifier (hex-encoded SHA-256 of the module).
+    pub build_id: String,
+    /// Version the artifact was published as.
+    pub version: Version,
+    /// Size of the module, in bytes.
+    pub size: u64,
+    /// Publication time, in seconds since the UNIX epoch.
+    pub published_at: u64,
+    /// Encoded copies of the module available for download.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub encodings: Vec<EncodedArtifact>,
+    /// Signing domain (tenant or project) the artifact belongs to, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+}
+
+/// A copy of a module encoded with a codec.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct EncodedArtifact {
+    /// Name of the codec.
+    pub encoding: String,
+    /// Size of the encoded module, in bytes.
+    pub size: u64,
+    /// Hex-encoded SHA-256 digest of the encoded module.
+    pub sha256: String,
+}
+
+/// Metadata about a stored patch.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct PatchInfo {
+    /// Size of the patch, in bytes.
+    pub size: u64,
+    /// Hex-encoded SHA-256 digest of the patch.
+    pub sha256: String,
+}
+
+impl PatchInfo {
+    fn new(patch: &[u8]) -> Self {
+        PatchInfo {
+            size: patch.len() as _,
+            sha256: Hex::encode_to_string(Hash::hash(patch)).unwrap(),
+        }
+    }
+}
+
+/// A version of an artifact available on a channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Release {
+    pub version: Version,
+    pub build_id: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ChannelIndex {
+    releases: Vec<Release>,
+}
+
+/// Check that a key is made of non-empty components, other than `.` and `..`.
+fn validate_key(key: &str) -> Result<(), WSError> {
+    if key
+        .split('/')
+        .any(|component| component.is_empty() || component == "." || component == "..")
+    {
+        return Err(WSError::InvalidKey(key.to_string()));
+    }
+    Ok(())
+}
+
+/// The version of an object, for backends that don't keep track of versions.
+fn content_version(data: &[u8]) -> String {
+    Hex::encode_to_string(Hash::hash(data)).unwrap()
+}
+
+/// Compute the build identifier of a module.
+pub fn build_id(module_bin: &[u8]) -> String {
+    Hex::encode_to_string(Hash::hash(module_bin)).unwrap()
+}
+
+pub(crate) fn validate_name(name: &str) -> Result<(), WSError> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        || name.starts_with('.')
+    {
+        return Err(WSError::InvalidKey(name.to_string()));
+    }
+    Ok(())
+}
+
+/// An update repository, storing artifacts and the channels they are published on.
+#[derive(Debug, Clone)]
+pub struct Repository<B: Backend> {
+    backend: B,
+}
+
+impl<B: Backend> Repository<B> {
+    /// Create a repository using the given storage backend.
+    pub fn new(backend: B) -> Self {
+        Repository { backend }
+    }
+
+    /// Return the storage backend.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    fn artifact_key(build_id: &str) -> String {
+        format!("artifacts/{}.wasm", build_id)
+    }
+
+    fn encoded_artifact_key(build_id: &str, encoding: &str) -> String {
+        format!("artifacts/{}.wasm.{}", build_id, encoding)
+    }
+
+    fn info_key(build_id: &str) -> String {
+        format!("artifacts/{}.json", build_id)
+    }
+
+    fn patch_key(base_build_id: &str, build_id: &str) -> String {
+        format!("patches/{}/{}.patch", base_build_id, build_id)
+    }
+
+    fn patch_info_key(base_build_id: &str, build_id: &str) -> String {
+        format!("patches/{}/{}.json", base_build_id, build_id)
+    }
+
+    fn signature_key(build_id: &str) -> String {
+        format!("artifacts/{}.sig", build_id)
+    }
+
+    fn release_notes_key(build_id: &str) -> String {
+        format!("artifacts/{}.notes", build_id)
+    }
+
+    fn patch_signature_key(base_build_id: &str, build_id: &str) -> String {
+        format!("patches/{}/{}.sig", base_build_id, build_id)
+    }
+
+    fn channel_key(channel: &str) -> String {
+        format!("channels/{}.json", channel)
+    }
+
+    fn channel_index(&self, channel: &str) -> Result<ChannelIndex, WSError> {
+        validate_name(channel)?;
+        match self.backend.get(&Self::channel_key(channel))? {
+            None => Ok(ChannelIndex::default()),
+            Some(json) => Ok(serde_json::from_slice(&json)?),
+        }
+    }
+
+    /// Apply `update` to the index of a channel, retrying if it gets modified concurrently.
+    fn update_channel_index(
+        &self,
+        channel: &str,
+        update: impl Fn(&mut ChannelIndex) -> Result<(), WSError>,
+    ) -> Result<(), WSError> {
+        validate_name(channel)?;
+        let key = Self::channel_key(channel);
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let (mut index, version) = match self.backend.get_versioned(&key)? {
+                None => (ChannelIndex::default(), None),
+                Some((json, version)) => (serde_json::from_slice(&json)?, Some(version)),
+            };
+            update(&mut index)?;
+            let json = serde_json::to_vec_pretty(&index)?;
+            if self
+                .backend
+                .put_if_version(&key, &json, version.as_deref())?
+            {
+                return Ok(());
+            }
+            debug!("Channel [{}] was modified concurrently, retrying", channel);
+        }
+        Err(WSError::ConcurrentModification(format!(
+            "channel [{}]",
+            channel
+        )))
+    }
+
+    /// Publish a module as the given version on a channel.
+    ///
+    /// The module is stored once, even if it is published on multiple channels.
+    pub fn publish(
+        &self,
+        module_bin: &[u8],
+        version: &Version,
+        channel: &str,
+    ) -> Result<ArtifactInfo, WSError> {
+        self.publish_in_domain(module_bin, version, channel, None)
+    }
+
+    /// Publish a module as the given version on a channel, as an artifact of a signing domain.
+    ///
+    /// An artifact belongs to the domain it was first published in, and can't be published again
+    /// in another one.
+    pub fn publish_in_domain(
+        &self,
+        module_bin: &[u8],
+        version: &Version,
+        channel: &str,
+        domain: Option<&str>,
+    ) -> Result<ArtifactInfo, WSError> {
+        Module::deserialize(&mut io::Cursor::new(module_bin))?;
+        let index = self.channel_index(channel)?;
+        if index.releases.iter().any(|r| &r.version == version) {
+            return Err(WSError::VersionExists(version.to_string()));
+        }
+        let build_id = build_id(module_bin);
+        let info = match self.info(&build_id) {
+            Ok(info) if info.domain.as_deref() != domain => {
+                return Err(WSError::DomainMismatch(format!(
+                    "{} already published in domain [{}]",
+                    build_id,
+                    info.domain.as_deref().unwrap_or_default()
+                )))
+            }
+            Ok(info) if &info.version == version => info,
+            Ok(info) => {
+                return Err(WSError::VersionExists(format!(
+                    "{} already published as {}",
+                    build_id, info.version
+                )))
+            }
+            Err(WSError::NotFound(_)) => {
+                let info = ArtifactInfo {
+                    build_id: build_id.clone(),
+                    version: version.clone(),
+                    size: module_bin.len() as _,
+                    published_at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or(0),
+                    encodings: vec![],
+                    domain: domain.map(str::to_string),
+                };
+                self.backend
+                    .put(&Self::artifact_key(&build_id), module_bin)?;
+                self.backend.put(
+                    &Self::info_key(&build_id),
+                    &serde_json::to_vec_pretty(&info)?,
+                )?;
+                info
+            }
+            Err(e) => return Err(e),
+        };
+        self.update_channel_index(channel, |index| {
+            if index.releases.iter().any(|r| &r.version == version) {
+                return Err(WSError::VersionExists(version.to_string()));
+            }
+            index.releases.push(Release {
+                version: version.clone(),
+                build_id: build_id.clone(),
+            });
+            index.releases.sort_by(|a, b| a.version.cmp(&b.version));
+            Ok(())
+        })?;
+        debug!("Published {} on channel [{}]", version, channel);
+        Ok(info)
+    }
+
+    /// Publish an artifact that is already in the repository on another channel, with the
+    /// version it was first published as.
+    ///
+    /// Promoting an artifact to a channel it is already on does nothing.
+    pub fn promote(&self, build_id: &str, channel: &str) -> Result<Release, WSError> {
+        let info = self.info(build_id)?;
+        let release = Release {
+            version: info.version,
+            build_id: info.build_id,
+        };
+        self.update_channel_index(channel, |index| {
+            match index.releases.iter().find(|r| r.version == release.version) {
+                Some(r) if r.build_id == release.build_id => return Ok(()),
+                Some(_) => return Err(WSError::VersionExists(release.version.to_string())),
+                None => {}
+            }
+            index.releases.push(release.clone());
+            index.releases.sort_by(|a, b| a.version.cmp(&b.version));
+            Ok(())
+        })?;
+        debug!(
+            "Promoted {} ({}) to channel [{}]",
+            release.build_id, release.version, channel
+        );
+        Ok(release)
+    }
+
+    /// Return the names of all the channels.
+    pub fn channels(&self) -> Result<Vec<String>, WSError> {
+        Ok(self
+            .backend
+            .list("channels")?
+            .iter()
+            .filter_map(|key| key.strip_prefix("channels/")?.strip_suffix(".json"))
+            .map(|s| s.to_string())
+            .collect())
+    }
+
+    /// Return the build identifiers of all the artifacts.
+    pub fn build_ids(&self) -> Result<Vec<String>, WSError> {
+        Ok(self
+            .backend
+            .list("artifacts")?
+            .iter()
+            .filter_map(|key| key.strip_prefix("artifacts/")?.strip_suffix(".json"))
+            .map(|s| s.to_string())
+            .collect())
+    }
+
+    /// Return the releases of a channel, sorted by increasing version.
+    pub fn versions(&self, channel: &str) -> Result<Vec<Release>, WSError> {
+        Ok(self.channel_index(channel)?.releases)
+    }
+
+    /// Return the metadata of an artifact.
+    pub fn info(&self, build_id: &str) -> Result<ArtifactInfo, WSError> {
+        validate_name(build_id)?;
+        let json = self
+            .backend
+            .get(&Self::info_key(build_id))?
+            .ok_or_else(|| WSError::NotFound(build_id.to_string()))?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    /// Return the content of an artifact.
+    pub fn artifact(&self, build_id: &str) -> Result<Vec<u8>, WSError> {
+        validate_name(build_id)?;
+        self.backend
+            .get(&Self::artifact_key(build_id))?
+            .ok_or_else(|| WSError::NotFound(build_id.to_string()))
+    }
+
+    /// Encode an artifact with a codec, and store the result next to it.
+    ///
+    /// The encoded module is checked to decode back to the artifact. Encoding an artifact again
+    /// with the same codec replaces the previous copy.
+    pub fn add_encoding(
+        &self,
+        build_id: &str,
+        codec: &dyn Codec,
+    ) -> Result<EncodedArtifact, WSError> {
+        let encoding = codec.name();
+        validate_name(encoding)?;
+        if encoding == NO_ENCODING {
+            return Err(WSError::UnsupportedCodec(encoding.to_string()));
+        }
+        let mut info = self.info(build_id)?;
+        let module_bin = self.artifact(build_id)?;
+        let encoded = codec.encode(&module_bin)?;
+        if codec.decode(&encoded, module_bin.len() as _)? != module_bin {
+            return Err(WSError::VerificationFailed);
+        }
+        self.backend
+            .put(&Self::encoded_artifact_key(build_id, encoding), &encoded)?;
+        let encoded_artifact = EncodedArtifact {
+            encoding: encoding.to_string(),
+            size: encoded.len() as _,
+            sha256: Hex::encode_to_string(Hash::hash(&encoded)).unwrap(),
+        };
+        info.encodings.retain(|e| e.encoding != encoding);
+        info.encodings.push(encoded_artifact.clone());
+        self.backend.put(
+            &Self::info_key(build_id),
+            &serde_json::to_vec_pretty(&info)?,
+        )?;
+        debug!(
+            "Encoded [{}] with {}: {} -> {} bytes",
+            build_id, encoding, info.size, encoded_artifact.size
+        );
+        Ok(encoded_artifact)
+    }
+
+    /// Return an artifact encoded with a codec.
+    pub fn encoded_artifact(&self, build_id: &str, encoding: &str) -> Result<Vec<u8>, WSError> {
+        validate_name(build_id)?;
+        validate_name(encoding)?;
+        self.backend
+            .get(&Self::encoded_artifact_key(build_id, encoding))?
+            .ok_or_else(|| WSError::NotFound(format!("{}.{}", build_id, encoding)))
+    }
+
+    /// Store a patch transforming the artifact `base_build_id` into `build_id`.
+    ///
+    /// Patches are opaque to the repository; both artifacts must have been published.
+    pub fn add_patch(
+        &self,
+        base_build_id: &str,
+        build_id: &str,
+        patch: &[u8],
+    ) -> Result<(), WSError> {
+        self.info(base_build_id)?;
+        self.info(build_id)?;
+        self.backend
+            .put(&Self::patch_key(base_build_id, build_id), patch)?;
+        self.backend.put(
+            &Self::patch_info_key(base_build_id, build_id),
+            &serde_json::to_vec_pretty(&PatchInfo::new(patch))?,
+        )
+    }
+
+    /// Return the size and digest of a patch, if there is one, without reading the patch itself.
+    ///
+    /// Patches stored before their metadata was recorded are read to compute it.
+    pub fn patch_info(
+        &self,
+        base_build_id: &str,
+        build_id: &str,
+    ) -> Result<Option<PatchInfo>, WSError> {
+        validate_name(base_build_id)?;
+        validate_name(build_id)?;
+        if let Some(json) = self
+            .backend
+            .get(&Self::patch_info_key(base_build_id, build_id))?
+        {
+            return Ok(Some(serde_json::from_slice(&json)?));
+        }
+        Ok(self
+            .patch(base_build_id, build_id)?
+            .map(|patch| PatchInfo::new(&patch)))
+    }
+
+    /// Return a patch transforming the artifact `base_build_id` into `build_id`, if there is one.
+    pub fn patch(&self, base_build_id: &str, build_id: &str) -> Result<Option<Vec<u8>>, WSError> {
+        validate_name(base_build_id)?;
+        validate_name(build_id)?;
+        self.backend.get(&Self::patch_key(base_build_id, build_id))
+    }
+
+    /// Generate and store a patch compressing the artifact `build_id` with Zstandard, using
+    /// the artifact `base_build_id` as the dictionary.
+    ///
+    /// The patch is checked to reproduce the artifact, and its size is returned.
+    #[cfg(feature = "zstd")]
+    pub fn add_dictionary_patch(
+        &self,
+        base_build_id: &str,
+        build_id: &str,
+        patcher: &DictionaryPatch,
+    ) -> Result<u64, WSError> {
+        let base = self.artifact(base_build_id)?;
+        let module = self.artifact(build_id)?;
+        let patch = patcher.make(&base, &module)?;
+        if apply_dictionary_patch(&base, &patch, module.len() as u64)? != module {
+            return Err(WSError::VerificationFailed);
+        }
+        self.add_patch(base_build_id, build_id, &patch)?;
+        debug!(
+            "Dictionary patch [{}] -> [{}]: {} -> {} bytes",
+            base_build_id,
+            build_id,
+            module.len(),
+            patch.len()
+        );
+        Ok(patch.len() as _)
+    }
+
+    /// Store the signature of an artifact.
+    pub fn add_signature(&self, build_id: &str, signature: &Signature) -> Result<(), WSError> {
+        if signature.kind != SignatureKind::Module {
+            return Err(WSError::ParseError);
+        }
+        self.info(build_id)?;
+        self.backend
+            .put(&Self::signature_key(build_id), &signature.to_bytes())
+    }
+
+    /// Return the signature of an artifact, if there is one.
+    pub fn signature(&self, build_id: &str) -> Result<Option<Signature>, WSError> {
+        validate_name(build_id)?;
+        self.backend
+            .get(&Self::signature_key(build_id))?
+            .map(|bytes| Signature::from_bytes(&bytes))
+            .transpose()
+    }
+
+    /// Store the signature of a patch.
+    pub fn add_patch_signature(
+        &self,
+        base_build_id: &str,
+        build_id: &str,
+        signature: &Signature,
+    ) -> Result<(), WSError> {
+        if signature.kind != SignatureKind::Patch {
+            return Err(WSError::ParseError);
+        }
+        if self.patch(base_build_id, build_id)?.is_none() {
+            return Err(WSError::NotFound(format!("{}/{}", base_build_id, build_id)));
+        }
+        self.backend.put(
+            &Self::patch_signature_key(base_build_id, build_id),
+            &signature.to_bytes(),
+        )
+    }
+
+    /// Return the signature of a patch, if there is one.
+    pub fn patch_signature(
+        &self,
+        base_build_id: &str,
+        build_id: &str,
+    ) -> Result<Option<Signature>, WSError> {
+        validate_name(base_build_id)?;
+        validate_name(build_id)?;
+        self.backend
+            .get(&Self::patch_signature_key(base_build_id, build_id))?
+            .map(|bytes| Signature::from_bytes(&bytes))
+            .transpose()
+    }
+
+    /// Answer an update check from a device.
+    ///
+    /// URLs are relative to the root of the update server. Patches are offered if the
+    /// device reports builds from which a chain of patches smaller than the update exists.
+    /// The module is also offered encoded with the first codec accepted by the device that
+    /// it was encoded with, if that makes it smaller. The nonce of the request is echoed, and the
+    /// golden artifact of the channel is described, if one is pinned.
+    pub fn check_update(
+        &self,
+        request: &UpdateCheckRequest,
+    ) -> Result<UpdateCheckResponse, WSError> {
+        Ok(UpdateCheckResponse {
+            update: self.update_descriptor(request)?,
+            nonce: request.nonce.clone(),
+            golden: self.golden_descriptor(&request.channel)?,
+        })
+    }
+
+    fn update_descriptor(
+        &self,
+        request: &UpdateCheckRequest,
+    ) -> Result<Option<UpdateDescriptor>, WSError> {
+        let release =
+            match self.resolve_update(&request.channel, request.installed_version.as_ref())? {
+                None => return Ok(None),
+                Some(release) => release,
+            };
+        if request.installed_build_id.as_deref() == Some(release.build_id.as_str()) {
+            return Ok(None);
+        }
+        let info = self.info(&release.build_id)?;
+        let full = Download {
+            url: format!("/{}", Self::artifact_key(&info.build_id)),
+            size: info.size,
+            sha256: info.build_id.clone(),
+        };
+        let bases: Vec<String> = request
+            .installed_build_id
+            .iter()
+            .chain(request.available_build_ids.iter())
+            .cloned()
+            .collect();
+        let plan = self.plan_update(&info.build_id, &bases)?;
+        let signature = self
+            .signature(&info.build_id)?
+            .map(|signature| signature.to_base64());
+        let encoded = request
+            .accept_encodings
+            .iter()
+            .take_while(|&encoding| encoding != NO_ENCODING)
+            .find_map(|encoding| {
+                info.encodings
+                    .iter()
+                    .find(|e| &e.encoding == encoding && e.size < info.size)
+            })
+            .map(|e| EncodedDownload {
+                encoding: e.encoding.clone(),
+                download: Download {
+                    url: format!(
+                        "/{}",
+                        Self::encoded_artifact_key(&info.build_id, &e.encoding)
+                    ),
+                    size: e.size,
+                    sha256: e.sha256.clone(),
+                },
+            });
+        Ok(Some(UpdateDescriptor {
+            version: info.version,
+            build_id: info.build_id,
+            full,
+            plan,
+            signature,
+            encoded,
+        }))
+    }
+
+    /// Find the smallest way to obtain `build_id` from any of the `bases` modules.
+    ///
+    /// This is the chain of patches with the lowest total size, if it is smaller than the
+    /// full module. Otherwise, or if no chain exists, the full module must be downloaded.
+    pub fn plan_update(&self, build_id: &str, bases: &[String]) -> Result<UpdatePlan, WSError> {
+        let full_size = self.info(build_id)?.size;
+        let mut edges: HashMap<String, Vec<String>> = HashMap::new();
+        for key in self.backend.list("patches")? {
+            let name = match key.strip_prefix("patches/") {
+                Some(name) => name,
+                None => continue,
+            };
+            if let Some((base, target)) = name
+                .strip_suffix(".patch")
+                .and_then(|name| name.split_once('/'))
+            {
+                edges
+                    .entry(base.to_string())
+                    .or_default()
+                    .push(target.to_string());
+            }
+        }
+        let mut distances: HashMap<String, u64> = HashMap::new();
+        let mut previous: HashMap<String, PatchDescriptor> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        for base in bases {
+            if base != build_id && validate_name(base).is_ok() {
+                distances.insert(base.clone(), 0);
+                queue.push(Reverse((0, base.clone())));
+            }
+        }
+        while let Some(Reverse((distance, node))) = queue.pop() {
+            if node == build_id {
+                let mut chain = vec![];
+                let mut current = node;
+                while let Some(patch) = previous.remove(&current) {
+                    current = patch.base_build_id.clone();
+                    chain.push(patch);
+                }
+                chain.reverse();
+                return Ok(UpdatePlan::Patches(chain));
+            }
+            if distances.get(&node).is_some_and(|&d| d < distance) {
+                continue;
+            }
+            for target in edges.get(&node).into_iter().flatten() {
+                let patch = match self.patch_info(&node, target) {
+                    Ok(Some(patch)) => patch,
+                    Ok(None) | Err(WSError::InvalidKey(_)) => continue,
+                    Err(e) => return Err(e),
+                };
+                let next_distance = distance + patch.size;
+                if next_distance >= full_size
+                    || distances.get(target).is_some_and(|&d| d <= next_distance)
+                {
+                    continue;
+                }
+                distances.insert(target.clone(), next_distance);
+                previous.insert(
+                    target.clone(),
+                    PatchDescriptor {
+                        base_build_id: node.clone(),
+                        build_id: target.clone(),
+                        download: Download {
+                            url: format!("/{}", Self::patch_key(&node, target)),
+                            size: patch.size,
+                            sha256: patch.sha256,
+                        },
+                        signature: self
+                            .patch_signature(&node, target)?
+                            .map(|signature| signature.to_base64()),
+                    },
+                );
+                queue.push(Reverse((next_distance, target.clone())));
+            }
+        }
+        Ok(UpdatePlan::Full)
+    }
+
+    /// Return the best update for a device running `installed` on a channel.
+    ///
+    /// This is the most recent release of the channel, if it is more recent than the
+    /// installed version. If no version is installed, the most recent release is returned.
+    pub fn resolve_update(
+        &self,
+        channel: &str,
+        installed: Option<&Version>,
+    ) -> Result<Option<Release>, WSError> {
+        let index = self.channel_index(channel)?;
+        let latest = match index.releases.into_iter().last() {
+            None => return Ok(None),
+            Some(latest) => latest,
+        };
+        match installed {
+            Some(installed) if &latest.version <= installed => Ok(None),
+            _ => Ok(Some(latest)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(payload: &[u8]) -> Vec<u8> {
+        let mut module_bin = vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        Section::Custom(CustomSection::new("payload".to_string(), payload.to_vec()))
+            .serialize(&mut module_bin)
+            .unwrap();
+        module_bin
+    }
+
+    #[test]
+    fn plans_use_the_patch_metadata() {
+        let repo = Repository::new(MemoryBackend::new());
+        let base = repo
+            .publish(&module(&[1; 1000]), &"1.0.0".parse().unwrap(), "stable")
+            .unwrap();
+        let target = repo
+            .publish(&module(&[2; 1000]), &"2.0.0".parse().unwrap(), "stable")
+            .unwrap();
+        repo.add_patch(&base.build_id, &target.build_id, b"patch")
+            .unwrap();
+        let info = repo
+            .patch_info(&base.build_id, &target.build_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(info, PatchInfo::new(b"patch"));
+
+        // Planning relies on the metadata, without reading the patch itself.
+        let info = PatchInfo {
+            size: 3,
+            sha256: "00".repeat(32),
+        };
+        repo.backend()
+            .put(
+                &Repository::<MemoryBackend>::patch_info_key(&base.build_id, &target.build_id),
+                &serde_json::to_vec(&info).unwrap(),
+            )
+            .unwrap();
+        let plan = repo
+            .plan_update(&target.build_id, std::slice::from_ref(&base.build_id))
+            .unwrap();
+        let chain = match plan {
+            UpdatePlan::Patches(chain) => chain,
+            UpdatePlan::Full => panic!("no patches were planned"),
+        };
+        assert_eq!(chain.len(), 1);
+        assert_eq!(chain[0].download.size, info.size);
+        assert_eq!(chain[0].download.sha256, info.sha256);
+    }
+}
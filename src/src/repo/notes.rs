@@ -0,0 +1,54 @@
+use super::{validate_name, Backend, Repository};
+use crate::error::*;
+use crate::release_notes::*;
+use crate::wasm_module::*;
+
+use std::io;
+
+impl<B: Backend> Repository<B> {
+    /// Store the release notes of an artifact, replacing the previous ones.
+    pub fn add_release_notes(&self, build_id: &str, notes: &ReleaseNotes) -> Result<(), WSError> {
+        self.info(build_id)?;
+        self.backend.put(
+            &Self::release_notes_key(build_id),
+            notes.to_json()?.as_bytes(),
+        )
+    }
+
+    /// Return the release notes of an artifact, if there are any.
+    pub fn release_notes(&self, build_id: &str) -> Result<Option<ReleaseNotes>, WSError> {
+        validate_name(build_id)?;
+        self.backend
+            .get(&Self::release_notes_key(build_id))?
+            .map(|json| ReleaseNotes::from_json(&json))
+            .transpose()
+    }
+
+    /// Compose the release notes of an artifact, relative to the highest lower version
+    /// published on a channel.
+    ///
+    /// Return `None` if the channel has no lower version.
+    pub fn compose_release_notes(
+        &self,
+        build_id: &str,
+        channel: &str,
+    ) -> Result<Option<ReleaseNotes>, WSError> {
+        let info = self.info(build_id)?;
+        let previous = match self
+            .versions(channel)?
+            .into_iter()
+            .rev()
+            .find(|release| release.version < info.version)
+        {
+            None => return Ok(None),
+            Some(previous) => previous,
+        };
+        let load = |build_id: &str| -> Result<Module, WSError> {
+            Module::deserialize(&mut io::Cursor::new(self.artifact(build_id)?))
+        };
+        let notes = load(&previous.build_id)?
+            .release_notes(&load(build_id)?)?
+            .versions(previous.version.to_string(), info.version.to_string());
+        Ok(Some(notes))
+    }
+}
@@ -0,0 +1,376 @@
+use super::{ArtifactInfo, Backend, Repository};
+use crate::error::*;
+use crate::protocol::*;
+use crate::report::*;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The number of devices running each build of a fleet.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct FleetInventory {
+    devices: BTreeMap<String, u64>,
+}
+
+impl FleetInventory {
+    /// Create an empty inventory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count devices running a build.
+    pub fn add(&mut self, build_id: impl Into<String>, devices: u64) {
+        *self.devices.entry(build_id.into()).or_default() += devices;
+    }
+
+    /// Build an inventory from install reports, in the order they were received.
+    ///
+    /// The last report of every device tells what it is running.
+    pub fn from_reports<'t>(reports: impl IntoIterator<Item = &'t InstallReport>) -> Self {
+        let mut installed = BTreeMap::new();
+        for report in reports {
+            let build = match report.status {
+                InstallStatus::Success | InstallStatus::RolledBack => report.to.as_ref(),
+                _ => report.from.as_ref(),
+            };
+            if let Some(build) = build {
+                installed.insert(report.device_id.as_str(), build.build_id.as_str());
+            }
+        }
+        let mut inventory = Self::new();
+        for build_id in installed.into_values() {
+            inventory.add(build_id, 1);
+        }
+        inventory
+    }
+
+    /// Return the builds and the number of devices running them.
+    pub fn builds(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.devices
+            .iter()
+            .map(|(build_id, &devices)| (build_id.as_str(), devices))
+    }
+
+    /// Return the total number of devices.
+    pub fn devices(&self) -> u64 {
+        self.devices.values().sum()
+    }
+}
+
+/// Estimates of what it costs to generate a patch.
+pub trait PatchCostModel {
+    /// Return the expected size of a patch transforming `base` into `target`, in bytes.
+    fn patch_size(&self, base: &ArtifactInfo, target: &ArtifactInfo) -> u64;
+
+    /// Return the expected time needed to generate a patch transforming `base` into `target`.
+    fn generation_time(&self, base: &ArtifactInfo, target: &ArtifactInfo) -> Duration;
+}
+
+/// A cost model based on the size of the artifacts.
+///
+/// Patches are expected to be a fixed fraction of the size of the target, and to be generated at a
+/// fixed rate, proportional to the size of both artifacts.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LinearCostModel {
+    /// Expected size of a patch, relative to the size of the target.
+    pub size_ratio: f64,
+    /// Number of bytes of input processed per second while generating a patch.
+    pub throughput: f64,
+}
+
+impl Default for LinearCostModel {
+    fn default() -> Self {
+        LinearCostModel {
+            size_ratio: 0.2,
+            throughput: 1024.0 * 1024.0,
+        }
+    }
+}
+
+impl PatchCostModel for LinearCostModel {
+    fn patch_size(&self, _base: &ArtifactInfo, target: &ArtifactInfo) -> u64 {
+        (target.size as f64 * self.size_ratio).ceil() as u64
+    }
+
+    fn generation_time(&self, base: &ArtifactInfo, target: &ArtifactInfo) -> Duration {
+        Duration::from_secs_f64((base.size + target.size) as f64 / self.throughput.max(1.0))
+    }
+}
+
+/// Options for `Repository::plan_patches()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatchPlanning {
+    /// Maximum total time spent generating patches.
+    pub max_generation_time: Option<Duration>,
+    /// Minimum number of bytes a patch must save across the fleet to be worth generating.
+    pub min_savings: u64,
+}
+
+impl PatchPlanning {
+    /// Create options planning every patch that saves bandwidth.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the total time spent generating patches.
+    pub fn max_generation_time(mut self, max_generation_time: Duration) -> Self {
+        self.max_generation_time = Some(max_generation_time);
+        self
+    }
+
+    /// Skip patches saving less than the given number of bytes across the fleet.
+    pub fn min_savings(mut self, min_savings: u64) -> Self {
+        self.min_savings = min_savings;
+        self
+    }
+}
+
+/// A patch worth generating.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedPatch {
+    pub base_build_id: String,
+    pub build_id: String,
+    /// Number of devices running the base.
+    pub devices: u64,
+    /// Expected size of the patch.
+    pub size: u64,
+    /// Expected time needed to generate the patch.
+    pub generation_time: Duration,
+    /// Number of bytes saved across the fleet once the patch exists.
+    pub savings: u64,
+}
+
+/// The result of `Repository::plan_patches()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatchPlan {
+    /// Patches to generate, the most valuable first.
+    pub patches: Vec<PlannedPatch>,
+    /// Number of bytes the fleet has to download to update, with the existing patches.
+    pub current_transfer: u64,
+    /// Number of bytes the fleet has to download to update, once the planned patches exist.
+    pub planned_transfer: u64,
+    /// Expected time needed to generate all the planned patches.
+    pub generation_time: Duration,
+}
+
+impl<B: Backend> Repository<B> {
+    /// Return the number of bytes a device running `base_build_id` downloads to get `target`.
+    fn transfer_size(&self, target: &ArtifactInfo, base_build_id: &str) -> Result<u64, WSError> {
+        let plan = self.plan_update(&target.build_id, &[base_build_id.to_string()])?;
+        Ok(match plan {
+            UpdatePlan::Patches(chain) if !chain.is_empty() => {
+                chain.iter().map(|patch| patch.download.size).sum()
+            }
+            _ => target.size,
+        })
+    }
+
+    /// Choose the patches to generate so that a fleet can update to `build_id` while downloading as
+    /// little as possible.
+    ///
+    /// A patch is considered from every build of the inventory to the target. A patch is only planned
+    /// if it is expected to be smaller than what devices running its base download today. When the
+    /// generation time is limited, the patches saving the most bytes per second of generation are chosen
+    /// first. Builds that are not in the repository always require the full module.
+    pub fn plan_patches(
+        &self,
+        build_id: &str,
+        inventory: &FleetInventory,
+        model: &impl PatchCostModel,
+        planning: &PatchPlanning,
+    ) -> Result<PatchPlan, WSError> {
+        let target = self.info(build_id)?;
+        let mut plan = PatchPlan::default();
+        let mut candidates = vec![];
+        for (base_build_id, devices) in inventory.builds() {
+            if base_build_id == build_id {
+                continue;
+            }
+            let base = match self.info(base_build_id) {
+                Ok(base) => base,
+                Err(WSError::NotFound(_)) | Err(WSError::InvalidKey(_)) => {
+                    debug!("Build [{}] is not in the repository", base_build_id);
+                    plan.current_transfer += devices * target.size;
+                    plan.planned_transfer += devices * target.size;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let current = self.transfer_size(&target, base_build_id)?;
+            plan.current_transfer += devices * current;
+            plan.planned_transfer += devices * current;
+            let size = model.patch_size(&base, &target);
+            if size >= current {
+                continue;
+            }
+            let savings = devices * (current - size);
+            if savings == 0 || savings < planning.min_savings {
+                continue;
+            }
+            candidates.push(PlannedPatch {
+                base_build_id: base_build_id.to_string(),
+                build_id: build_id.to_string(),
+                devices,
+                size,
+                generation_time: model.generation_time(&base, &target),
+                savings,
+            });
+        }
+        let value = |patch: &PlannedPatch| {
+            patch.savings as f64 / patch.generation_time.as_secs_f64().max(f64::MIN_POSITIVE)
+        };
+        match planning.max_generation_time {
+            Some(_) => candidates.sort_by(|a, b| value(b).total_cmp(&value(a))),
+            None => candidates.sort_by_key(|patch| Reverse(patch.savings)),
+        }
+        for patch in candidates {
+            let generation_time = plan.generation_time + patch.generation_time;
+            if planning
+                .max_generation_time
+                .is_some_and(|max| generation_time > max)
+            {
+                continue;
+            }
+            plan.generation_time = generation_time;
+            plan.planned_transfer -= patch.savings;
+            plan.patches.push(patch);
+        }
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::MemoryBackend;
+    use crate::wasm_module::*;
+
+    /// Patches a tenth of the size of the target, generated in one second per byte of the base.
+    struct TestCostModel;
+
+    impl PatchCostModel for TestCostModel {
+        fn patch_size(&self, _base: &ArtifactInfo, target: &ArtifactInfo) -> u64 {
+            target.size / 10
+        }
+
+        fn generation_time(&self, base: &ArtifactInfo, _target: &ArtifactInfo) -> Duration {
+            Duration::from_secs(base.size)
+        }
+    }
+
+    fn publish(repo: &Repository<MemoryBackend>, version: &str, len: usize) -> ArtifactInfo {
+        let module = Module {
+            header: [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00],
+            sections: vec![Section::Custom(CustomSection::new(
+                "payload".to_string(),
+                vec![version.as_bytes()[0]; len],
+            ))],
+            ..Module::default()
+        };
+        let mut module_bin = vec![];
+        module.serialize(&mut module_bin).unwrap();
+        repo.publish(&module_bin, &version.parse().unwrap(), "stable")
+            .unwrap()
+    }
+
+    #[test]
+    fn patches_saving_the_most_are_planned() {
+        let repo = Repository::new(MemoryBackend::new());
+        let v0 = publish(&repo, "0.1.0", 6000);
+        let v1 = publish(&repo, "1.0.0", 1000);
+        let v2 = publish(&repo, "2.0.0", 2000);
+        let v3 = publish(&repo, "3.0.0", 4000);
+        repo.add_patch(&v1.build_id, &v3.build_id, &[0; 100])
+            .unwrap();
+
+        let mut inventory = FleetInventory::new();
+        inventory.add(&v0.build_id, 150);
+        inventory.add(&v1.build_id, 10);
+        inventory.add(&v2.build_id, 60);
+        inventory.add(&v2.build_id, 40);
+        inventory.add(&v3.build_id, 5);
+        inventory.add("ff".repeat(32), 3);
+        assert_eq!(inventory.devices(), 268);
+
+        let current_transfer = 10 * 100 + (150 + 100 + 3) * v3.size;
+        let patch_size = v3.size / 10;
+        let planned = |base: &ArtifactInfo, devices: u64| PlannedPatch {
+            base_build_id: base.build_id.clone(),
+            build_id: v3.build_id.clone(),
+            devices,
+            size: patch_size,
+            generation_time: Duration::from_secs(base.size),
+            savings: devices * (v3.size - patch_size),
+        };
+
+        // The existing patch from v1 is already smaller than a new one would be.
+        let plan = repo
+            .plan_patches(
+                &v3.build_id,
+                &inventory,
+                &TestCostModel,
+                &PatchPlanning::new(),
+            )
+            .unwrap();
+        assert_eq!(plan.patches, [planned(&v0, 150), planned(&v2, 100)]);
+        assert_eq!(plan.current_transfer, current_transfer);
+        assert_eq!(
+            plan.planned_transfer,
+            current_transfer - 250 * (v3.size - patch_size)
+        );
+        assert_eq!(plan.generation_time, Duration::from_secs(v0.size + v2.size));
+
+        // With a time budget, patches saving the most bytes per second are chosen first, even if
+        // others save more in total.
+        let planning = PatchPlanning::new().max_generation_time(Duration::from_secs(v0.size));
+        let plan = repo
+            .plan_patches(&v3.build_id, &inventory, &TestCostModel, &planning)
+            .unwrap();
+        assert_eq!(plan.patches, [planned(&v2, 100)]);
+        assert_eq!(plan.current_transfer, current_transfer);
+
+        let planning = PatchPlanning::new().min_savings(150 * (v3.size - patch_size));
+        let plan = repo
+            .plan_patches(&v3.build_id, &inventory, &TestCostModel, &planning)
+            .unwrap();
+        assert_eq!(plan.patches, [planned(&v0, 150)]);
+    }
+
+    #[test]
+    fn inventories_use_the_last_report_of_each_device() {
+        let build = |build_id: &str| {
+            Some(BuildRef {
+                build_id: build_id.to_string(),
+                version: "1.0.0".parse().unwrap(),
+            })
+        };
+        let report = |device_id: &str, status, from: &str, to: &str| InstallReport {
+            device_id: device_id.to_string(),
+            channel: "stable".to_string(),
+            status,
+            from: build(from),
+            to: build(to),
+            error_code: None,
+            error: None,
+            started_at: 0,
+            duration_ms: 0,
+            bytes_downloaded: 0,
+        };
+        let reports = [
+            report("a", InstallStatus::Success, "v1", "v2"),
+            report("b", InstallStatus::Success, "v1", "v2"),
+            report("a", InstallStatus::Success, "v2", "v3"),
+            report("b", InstallStatus::Error, "v2", "v3"),
+            report("c", InstallStatus::RolledBack, "v2", "v1"),
+        ];
+        let inventory = FleetInventory::from_reports(&reports);
+        assert_eq!(
+            inventory.builds().collect::<Vec<_>>(),
+            [("v1", 1), ("v2", 1), ("v3", 1)]
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use super::{Backend, Repository};
+use crate::encryption::*;
+use crate::error::*;
+use crate::wasm_module::*;
+
+use log::*;
+use std::collections::BTreeSet;
+use std::io;
+
+/// An artifact whose encryption keys were rotated by `Repository::rotate_encryption_keys()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RekeyedArtifact {
+    /// The build identifier of the original artifact.
+    pub build_id: String,
+    /// The build identifier of the artifact with the new envelope.
+    pub new_build_id: String,
+}
+
+/// The result of `Repository::rotate_encryption_keys()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RekeyReport {
+    pub rekeyed: Vec<RekeyedArtifact>,
+    /// Artifacts that couldn't be rekeyed, along with the reason.
+    pub failures: Vec<(String, String)>,
+}
+
+impl<B: Backend> Repository<B> {
+    fn rekey_artifact(
+        &self,
+        build_id: &str,
+        decryptor: &impl Decryptor,
+        recipients: &[EncryptionPublicKey],
+        dry_run: bool,
+    ) -> Result<Option<String>, WSError> {
+        let module_bin = self.artifact(build_id)?;
+        let mut module = Module::deserialize(&mut io::Cursor::new(&module_bin))?;
+        if !module.rotate_encryption_keys(decryptor, recipients)? {
+            return Ok(None);
+        }
+        let mut rekeyed_bin = vec![];
+        module.serialize(&mut rekeyed_bin)?;
+        let new_build_id = super::build_id(&rekeyed_bin);
+        if dry_run {
+            return Ok(Some(new_build_id));
+        }
+        let mut info = self.info(build_id)?;
+        info.build_id = new_build_id.clone();
+        info.size = rekeyed_bin.len() as _;
+        info.encodings.clear();
+        self.backend
+            .put(&Self::artifact_key(&new_build_id), &rekeyed_bin)?;
+        self.backend.put(
+            &Self::info_key(&new_build_id),
+            &serde_json::to_vec_pretty(&info)?,
+        )?;
+        if let Some(signature) = self.backend.get(&Self::signature_key(build_id))? {
+            self.backend
+                .put(&Self::signature_key(&new_build_id), &signature)?;
+        }
+        if let Some(notes) = self.backend.get(&Self::release_notes_key(build_id))? {
+            self.backend
+                .put(&Self::release_notes_key(&new_build_id), &notes)?;
+        }
+        for channel in self.channels()? {
+            self.update_channel_index(&channel, |index| {
+                for release in index.releases.iter_mut() {
+                    if release.build_id == build_id {
+                        release.build_id = new_build_id.clone();
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        for (channel, release) in self.golden_artifacts()? {
+            if release.build_id == build_id {
+                self.pin_golden(&channel, &new_build_id)?;
+            }
+        }
+        Ok(Some(new_build_id))
+    }
+
+    /// Wrap the data keys of all encrypted artifacts for a new set of recipients.
+    ///
+    /// Each encrypted artifact is stored again with a new envelope, and replaces the original one on every
+    /// channel and golden pin; artifacts that are not encrypted, or are neither on a channel nor pinned, are left alone. As only the envelope changes, signatures and
+    /// release notes are copied: signatures remain valid if they were made with `ENCRYPTION_KEYS_SECTION_NAME` in the ignore list.
+    /// The original artifacts are left in the repository until they are garbage collected, and encoded
+    /// copies must be created again. Artifacts that fail to be rekeyed are listed in the report, and don't
+    /// stop the rotation.
+    pub fn rotate_encryption_keys(
+        &self,
+        decryptor: &impl Decryptor,
+        recipients: &[EncryptionPublicKey],
+        dry_run: bool,
+    ) -> Result<RekeyReport, WSError> {
+        if recipients.is_empty() {
+            return Err(WSError::InvalidKey("no recipients".to_string()));
+        }
+        let mut build_ids = BTreeSet::new();
+        for channel in self.channels()? {
+            for release in self.versions(&channel)? {
+                build_ids.insert(release.build_id);
+            }
+        }
+        for (_, release) in self.golden_artifacts()? {
+            build_ids.insert(release.build_id);
+        }
+        let mut report = RekeyReport::default();
+        for build_id in build_ids {
+            match self.rekey_artifact(&build_id, decryptor, recipients, dry_run) {
+                Ok(None) => {}
+                Ok(Some(new_build_id)) => {
+                    debug!("Rekeyed {} as {}", build_id, new_build_id);
+                    report.rekeyed.push(RekeyedArtifact {
+                        build_id,
+                        new_build_id,
+                    });
+                }
+                Err(e) => {
+                    warn!("Unable to rekey {}: {}", build_id, e);
+                    report.failures.push((build_id, e.to_string()));
+                }
+            }
+        }
+        Ok(report)
+    }
+}
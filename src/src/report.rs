@@ -0,0 +1,136 @@
+use crate::error::*;
+
+use schemars::JsonSchema;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::io::prelude::*;
+use std::sync::Mutex;
+
+/// The result of an installation attempt.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum InstallStatus {
+    /// The update was installed.
+    Success,
+    /// No update was needed.
+    UpToDate,
+    /// The previous installation is waiting for confirmation.
+    PendingConfirmation,
+    /// An update is available, but was not downloaded yet due to the download schedule.
+    Deferred,
+    /// The update was downloaded, but didn't pass verification.
+    VerificationFailed,
+    /// The previously installed module was restored.
+    RolledBack,
+    /// The update couldn't be installed for another reason.
+    Error,
+}
+
+/// A module identified by its build identifier and version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct BuildRef {
+    pub build_id: String,
+    pub version: Version,
+}
+
+/// A report describing an installation attempt on a device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct InstallReport {
+    /// Unique identifier of the device.
+    pub device_id: String,
+    /// Channel the device follows.
+    pub channel: String,
+    /// Result of the installation.
+    pub status: InstallStatus,
+    /// Module installed before the attempt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<BuildRef>,
+    /// Module installed by the attempt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<BuildRef>,
+    /// Error code, if the installation failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    /// Error message, if the installation failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Start time of the attempt, in seconds since the UNIX epoch.
+    pub started_at: u64,
+    /// Duration of the attempt, in milliseconds.
+    pub duration_ms: u64,
+    /// Number of bytes downloaded during the attempt.
+    pub bytes_downloaded: u64,
+}
+
+impl InstallReport {
+    /// Record an error in the report.
+    pub fn set_error(&mut self, error: &WSError) {
+        self.status = match error {
+            WSError::VerificationFailed
+            | WSError::DigestMismatch
+            | WSError::NoSignature
+            | WSError::UnknownKey(_)
+            | WSError::KeyRevoked(_)
+            | WSError::UnsupportedSignatureVersion(_) => InstallStatus::VerificationFailed,
+            _ => InstallStatus::Error,
+        };
+        self.error_code = Some(error.code().to_string());
+        self.error = Some(error.to_string());
+    }
+
+    /// Serialize the report as JSON.
+    pub fn to_json(&self) -> Result<String, WSError> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Deserialize a report from JSON.
+    pub fn from_json(json: &str) -> Result<Self, WSError> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+/// A destination for installation reports.
+pub trait Reporter {
+    /// Send a report.
+    fn report(&self, report: &InstallReport) -> Result<(), WSError>;
+}
+
+/// A reporter discarding all reports.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoopReporter;
+
+impl Reporter for NoopReporter {
+    fn report(&self, _report: &InstallReport) -> Result<(), WSError> {
+        Ok(())
+    }
+}
+
+/// A reporter writing each report as a line of JSON.
+pub struct JsonReporter<W: Write> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write> JsonReporter<W> {
+    /// Write reports to the given writer.
+    pub fn new(writer: W) -> Self {
+        JsonReporter {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    /// Return the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<W: Write> Reporter for JsonReporter<W> {
+    fn report(&self, report: &InstallReport) -> Result<(), WSError> {
+        let mut line = report.to_json()?;
+        line.push('\n');
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(line.as_bytes())?;
+        writer.flush()?;
+        Ok(())
+    }
+}
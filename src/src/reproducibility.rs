@@ -0,0 +1,312 @@
+//! Analysis of the differences between builds that should be identical.
+//!
+//! When two builds of the same source differ, a section-level diff only says that the code
+//! section changed. This compares function bodies one by one, and classifies each difference,
+//! so that nondeterministic function ordering, embedded constants such as timestamps or hashes,
+//! and changing references can be told apart from actual code generation differences.
+
+use crate::diff::*;
+use crate::error::*;
+use crate::wasm_module::*;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Range;
+use wasmparser::{
+    CodeSectionReader, FunctionBody, ImportSectionReader, Name, NameSectionReader, Operator,
+    TypeRef, ValType,
+};
+
+fn parse_error(_: wasmparser::BinaryReaderError) -> WSError {
+    WSError::ParseError
+}
+
+/// How a function differs between two builds, from least to most significant.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FunctionDifference {
+    /// The function is identical to the function at another index in the other build, except
+    /// for the indices of the functions it references.
+    Moved { old_index: u32 },
+    /// Same instructions, with immediates encoded differently.
+    Encoding,
+    /// Only the values of constants differ.
+    Constants,
+    /// Only the functions and globals referenced by the code differ, and possibly constants.
+    References,
+    /// The instructions or locals differ.
+    Code,
+}
+
+impl fmt::Display for FunctionDifference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FunctionDifference::Moved { old_index } => write!(f, "moved from {}", old_index),
+            FunctionDifference::Encoding => write!(f, "encoding"),
+            FunctionDifference::Constants => write!(f, "constants"),
+            FunctionDifference::References => write!(f, "references"),
+            FunctionDifference::Code => write!(f, "code"),
+        }
+    }
+}
+
+/// A function that differs between two builds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionChange {
+    /// Index of the function in the new build, including imported functions.
+    pub index: u32,
+    /// Name of the function, from the name section of the new build.
+    pub name: Option<String>,
+    pub difference: FunctionDifference,
+}
+
+/// The differences between two builds of the same source.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildComparison {
+    /// Number of functions defined by the old build.
+    pub old_functions: u32,
+    /// Number of functions defined by the new build.
+    pub new_functions: u32,
+    /// Functions defined by both builds that differ, in order.
+    pub functions: Vec<FunctionChange>,
+    /// Sections that differ, other than the code section.
+    pub sections: Vec<SectionChange>,
+}
+
+impl BuildComparison {
+    /// Return `true` if the builds are identical.
+    pub fn is_identical(&self) -> bool {
+        self.old_functions == self.new_functions
+            && self.functions.is_empty()
+            && self.sections.is_empty()
+    }
+
+    /// Return the number of functions with a given kind of difference.
+    ///
+    /// Functions that moved count as `Moved { old_index: 0 }`.
+    pub fn count(&self, difference: FunctionDifference) -> usize {
+        let normalize = |difference: FunctionDifference| match difference {
+            FunctionDifference::Moved { .. } => FunctionDifference::Moved { old_index: 0 },
+            difference => difference,
+        };
+        self.functions
+            .iter()
+            .filter(|change| normalize(change.difference) == normalize(difference))
+            .count()
+    }
+
+    /// Return the changed custom sections holding debug information.
+    pub fn debug_sections(&self) -> impl Iterator<Item = &SectionChange> {
+        self.sections
+            .iter()
+            .filter(|change| is_debug_section(&change.key))
+    }
+}
+
+/// Return `true` if a section holds debug information: DWARF sections, function names,
+/// and references to source maps or external debug information.
+pub fn is_debug_section(key: &SectionKey) -> bool {
+    match key {
+        SectionKey::Custom { name, .. } => {
+            name.starts_with(".debug")
+                || matches!(
+                    name.as_str(),
+                    "name" | "sourceMappingURL" | "external_debug_info"
+                )
+        }
+        SectionKey::Standard(_) => false,
+    }
+}
+
+/// The function bodies of a module, as byte ranges of its code section.
+struct Functions<'t> {
+    payload: &'t [u8],
+    bodies: Vec<FunctionBody<'t>>,
+    imported: u32,
+    names: BTreeMap<u32, String>,
+}
+
+impl<'t> Functions<'t> {
+    fn parse(module: &'t Module) -> Result<Self, WSError> {
+        let mut functions = Functions {
+            payload: &[],
+            bodies: vec![],
+            imported: 0,
+            names: BTreeMap::new(),
+        };
+        for section in &module.sections {
+            match section {
+                Section::Custom(custom) if custom.name() == "name" => {
+                    let reader = NameSectionReader::new(custom.payload(), 0);
+                    for name in reader.into_iter().flatten() {
+                        if let Name::Function(map) = name {
+                            for naming in map.into_iter().flatten() {
+                                functions
+                                    .names
+                                    .insert(naming.index, naming.name.to_string());
+                            }
+                        }
+                    }
+                }
+                Section::Custom(_) => {}
+                Section::Standard(_) if section.id() == SectionId::Import => {
+                    for import in
+                        ImportSectionReader::new(section.payload(), 0).map_err(parse_error)?
+                    {
+                        if let TypeRef::Func(_) = import.map_err(parse_error)?.ty {
+                            functions.imported += 1;
+                        }
+                    }
+                }
+                Section::Standard(_) if section.id() == SectionId::Code => {
+                    functions.payload = section.payload();
+                    for body in CodeSectionReader::new(section.payload(), 0).map_err(parse_error)? {
+                        functions.bodies.push(body.map_err(parse_error)?);
+                    }
+                }
+                Section::Standard(_) => {}
+            }
+        }
+        Ok(functions)
+    }
+
+    fn bytes(&self, body: &FunctionBody) -> &'t [u8] {
+        &self.payload[body.range()]
+    }
+
+    /// Return the encoding of a function body without the indices of the functions it
+    /// references, which change whenever functions are reordered.
+    fn fingerprint(&self, body: &FunctionBody) -> Result<Vec<u8>, WSError> {
+        let mut ops = body.get_operators_reader().map_err(parse_error)?;
+        let mut fingerprint = self.payload[body.range().start..ops.original_position()].to_vec();
+        while !ops.eof() {
+            let (op, offset) = ops.read_with_offset().map_err(parse_error)?;
+            match op {
+                Operator::Call { .. } | Operator::ReturnCall { .. } | Operator::RefFunc { .. } => {
+                    fingerprint.push(self.payload[offset])
+                }
+                _ => fingerprint.extend_from_slice(&self.payload[offset..ops.original_position()]),
+            }
+        }
+        Ok(fingerprint)
+    }
+}
+
+fn locals(body: &FunctionBody) -> Result<Vec<(u32, ValType)>, WSError> {
+    body.get_locals_reader()
+        .map_err(parse_error)?
+        .into_iter()
+        .map(|local| local.map_err(parse_error))
+        .collect()
+}
+
+/// Compare two function bodies instruction by instruction.
+fn classify(
+    old: (&[u8], &FunctionBody),
+    new: (&[u8], &FunctionBody),
+) -> Result<FunctionDifference, WSError> {
+    if locals(old.1)? != locals(new.1)? {
+        return Ok(FunctionDifference::Code);
+    }
+    let mut old_ops = old.1.get_operators_reader().map_err(parse_error)?;
+    let mut new_ops = new.1.get_operators_reader().map_err(parse_error)?;
+    let mut difference = FunctionDifference::Encoding;
+    loop {
+        match (old_ops.eof(), new_ops.eof()) {
+            (true, true) => return Ok(difference),
+            (false, false) => {}
+            _ => return Ok(FunctionDifference::Code),
+        }
+        let (old_op, old_offset) = old_ops.read_with_offset().map_err(parse_error)?;
+        let (new_op, new_offset) = new_ops.read_with_offset().map_err(parse_error)?;
+        let old_range: Range<usize> = old_offset..old_ops.original_position();
+        let new_range: Range<usize> = new_offset..new_ops.original_position();
+        let differs = match (&old_op, &new_op) {
+            (Operator::I32Const { value: a }, Operator::I32Const { value: b }) => {
+                (a != b).then_some(FunctionDifference::Constants)
+            }
+            (Operator::I64Const { value: a }, Operator::I64Const { value: b }) => {
+                (a != b).then_some(FunctionDifference::Constants)
+            }
+            (Operator::F32Const { value: a }, Operator::F32Const { value: b }) => {
+                (a.bits() != b.bits()).then_some(FunctionDifference::Constants)
+            }
+            (Operator::F64Const { value: a }, Operator::F64Const { value: b }) => {
+                (a.bits() != b.bits()).then_some(FunctionDifference::Constants)
+            }
+            (Operator::Call { function_index: a }, Operator::Call { function_index: b })
+            | (
+                Operator::ReturnCall { function_index: a },
+                Operator::ReturnCall { function_index: b },
+            )
+            | (Operator::RefFunc { function_index: a }, Operator::RefFunc { function_index: b })
+            | (Operator::GlobalGet { global_index: a }, Operator::GlobalGet { global_index: b })
+            | (Operator::GlobalSet { global_index: a }, Operator::GlobalSet { global_index: b }) => {
+                (a != b).then_some(FunctionDifference::References)
+            }
+            _ if old.0[old_range] == new.0[new_range] => None,
+            _ => return Ok(FunctionDifference::Code),
+        };
+        if let Some(differs) = differs {
+            difference = difference.max(differs);
+        }
+    }
+}
+
+impl Module {
+    /// Compare this build with another build that should be identical, function by function.
+    ///
+    /// Functions are matched by index. A function whose body is different from the one at the
+    /// same index, but identical to another function of the old build up to the functions it
+    /// calls, is reported as moved.
+    pub fn compare_builds(&self, new: &Module) -> Result<BuildComparison, WSError> {
+        if self.is_component() || new.is_component() {
+            return Err(WSError::UnsupportedModuleType);
+        }
+        let old_functions = Functions::parse(self)?;
+        let new_functions = Functions::parse(new)?;
+        let mut old_indices: HashMap<Vec<u8>, u32> = HashMap::new();
+        for (i, body) in old_functions.bodies.iter().enumerate() {
+            old_indices
+                .entry(old_functions.fingerprint(body)?)
+                .or_insert(old_functions.imported + i as u32);
+        }
+        let mut functions = vec![];
+        for (i, (old_body, new_body)) in old_functions
+            .bodies
+            .iter()
+            .zip(&new_functions.bodies)
+            .enumerate()
+        {
+            let old_bytes = old_functions.bytes(old_body);
+            let new_bytes = new_functions.bytes(new_body);
+            if old_bytes == new_bytes {
+                continue;
+            }
+            let index = new_functions.imported + i as u32;
+            let difference = match old_indices.get(&new_functions.fingerprint(new_body)?) {
+                Some(&old_index) if old_index != index => FunctionDifference::Moved { old_index },
+                _ => classify(
+                    (old_functions.payload, old_body),
+                    (new_functions.payload, new_body),
+                )?,
+            };
+            functions.push(FunctionChange {
+                index,
+                name: new_functions.names.get(&index).cloned(),
+                difference,
+            });
+        }
+        let sections = self
+            .diff(new)?
+            .changed()
+            .filter(|change| change.key != SectionKey::Standard(SectionId::Code))
+            .cloned()
+            .collect();
+        Ok(BuildComparison {
+            old_functions: old_functions.bodies.len() as u32,
+            new_functions: new_functions.bodies.len() as u32,
+            functions,
+            sections,
+        })
+    }
+}
@@ -0,0 +1,222 @@
+use crate::error::*;
+use crate::signature::*;
+use crate::wasm_module::*;
+
+use std::io::{self, prelude::*};
+
+/// Name of the custom section storing a key revocation list.
+pub const REVOCATIONS_SECTION_NAME: &str = "revocations";
+
+const REVOCATIONS_VERSION: u8 = 0x01;
+
+const REVOCATIONS_CONTEXT: &[u8] = b"wasi-update/revocations/v1\0";
+
+/// A revoked key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Revocation {
+    /// Identifier of the revoked key.
+    pub key_id: KeyId,
+    /// Time from which signatures made with the key are rejected, in seconds since the UNIX epoch.
+    pub effective_at: u64,
+}
+
+/// A list of revoked keys.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RevocationList {
+    /// Time the list was issued, in seconds since the UNIX epoch.
+    pub issued_at: u64,
+    pub revocations: Vec<Revocation>,
+}
+
+impl RevocationList {
+    fn serialize(&self) -> Result<Vec<u8>, WSError> {
+        let mut writer = io::Cursor::new(vec![]);
+        writer.write_all(&[REVOCATIONS_VERSION])?;
+        writer.write_all(&self.issued_at.to_le_bytes())?;
+        varint::put(&mut writer, self.revocations.len() as _)?;
+        for revocation in &self.revocations {
+            writer.write_all(&revocation.key_id)?;
+            writer.write_all(&revocation.effective_at.to_le_bytes())?;
+        }
+        Ok(writer.into_inner())
+    }
+
+    fn deserialize(reader: &mut impl Read) -> Result<Self, WSError> {
+        let mut version = [0u8; 1];
+        reader.read_exact(&mut version)?;
+        if version[0] != REVOCATIONS_VERSION {
+            return Err(WSError::ParseError);
+        }
+        let mut u64_bytes = [0u8; 8];
+        reader.read_exact(&mut u64_bytes)?;
+        let issued_at = u64::from_le_bytes(u64_bytes);
+        let count = varint::get32(reader)?;
+        let mut revocations = vec![];
+        for _ in 0..count {
+            let mut key_id = KeyId::default();
+            reader.read_exact(&mut key_id)?;
+            reader.read_exact(&mut u64_bytes)?;
+            revocations.push(Revocation {
+                key_id,
+                effective_at: u64::from_le_bytes(u64_bytes),
+            });
+        }
+        Ok(RevocationList {
+            issued_at,
+            revocations,
+        })
+    }
+
+    fn message(serialized: &[u8]) -> Vec<u8> {
+        let mut message = REVOCATIONS_CONTEXT.to_vec();
+        message.extend_from_slice(serialized);
+        message
+    }
+
+    /// Sign the list with a root key.
+    pub fn sign(self, root_sk: &SecretKey) -> Result<SignedRevocationList, WSError> {
+        let signature = root_sk.sign(
+            SignatureKind::RevocationList,
+            &Self::message(&self.serialize()?),
+        );
+        Ok(SignedRevocationList {
+            list: self,
+            signature,
+        })
+    }
+}
+
+/// A revocation list, along with the signature of the root key that issued it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedRevocationList {
+    list: RevocationList,
+    signature: Signature,
+}
+
+impl SignedRevocationList {
+    /// Serialize the signed list.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, WSError> {
+        let mut bytes = self.list.serialize()?;
+        bytes.extend_from_slice(&self.signature.to_bytes());
+        Ok(bytes)
+    }
+
+    /// Deserialize a signed list. The signature is not verified.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WSError> {
+        TakeExact::decode(bytes, bytes.len() as _, |reader| {
+            let list = RevocationList::deserialize(reader)?;
+            let signature = reader.read_rest()?;
+            Ok(SignedRevocationList {
+                list,
+                signature: Signature::from_bytes(&signature)?,
+            })
+        })
+    }
+
+    /// Verify the signature of the list, and return the list.
+    pub fn verify(&self, root_pk: &PublicKey) -> Result<&RevocationList, WSError> {
+        root_pk.verify(
+            SignatureKind::RevocationList,
+            &RevocationList::message(&self.list.serialize()?),
+            &self.signature,
+        )?;
+        Ok(&self.list)
+    }
+}
+
+impl KeyRing {
+    /// Verify a revocation list with the root key, and reject the keys it revokes.
+    ///
+    /// Revocations accumulate: a list never restores keys revoked by another one.
+    /// Return the verified list.
+    pub fn add_revocations<'t>(
+        &mut self,
+        list: &'t SignedRevocationList,
+        root_pk: &PublicKey,
+    ) -> Result<&'t RevocationList, WSError> {
+        let list = list.verify(root_pk)?;
+        for revocation in &list.revocations {
+            self.revoke(revocation.key_id, revocation.effective_at);
+        }
+        Ok(list)
+    }
+}
+
+impl Module {
+    /// Return the revocation list embedded in the module, if any.
+    pub fn revocation_list(&self) -> Result<Option<SignedRevocationList>, WSError> {
+        for section in &self.sections {
+            if let Section::Custom(custom) = section {
+                if custom.name() == REVOCATIONS_SECTION_NAME {
+                    return Ok(Some(SignedRevocationList::from_bytes(custom.payload())?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Embed a revocation list into the module, replacing the existing one.
+    ///
+    /// This allows offline devices to learn about revoked keys from the modules they install.
+    /// The module must be signed after the list has been embedded.
+    pub fn set_revocation_list(&mut self, list: &SignedRevocationList) -> Result<(), WSError> {
+        let payload = list.to_bytes()?;
+        for section in self.sections.iter_mut() {
+            if let Section::Custom(custom) = section {
+                if custom.name() == REVOCATIONS_SECTION_NAME {
+                    custom.set_payload(payload);
+                    return Ok(());
+                }
+            }
+        }
+        self.sections.push(Section::Custom(CustomSection::new(
+            REVOCATIONS_SECTION_NAME.to_string(),
+            payload,
+        )));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    #[test]
+    fn lists_are_embedded_and_verified() {
+        let (root, kp) = (KeyPair::generate(), KeyPair::generate());
+        let list = RevocationList {
+            issued_at: 1000,
+            revocations: vec![Revocation {
+                key_id: *kp.pk.key_id(),
+                effective_at: 0,
+            }],
+        };
+        let signed = list.clone().sign(&root.sk).unwrap();
+        let mut module = Module::deserialize(&mut io::Cursor::new(MODULE)).unwrap();
+        module.set_revocation_list(&signed).unwrap();
+        module.set_revocation_list(&signed).unwrap();
+        assert_eq!(module.sections.len(), 1);
+        let embedded = module.revocation_list().unwrap().unwrap();
+        assert_eq!(embedded.verify(&root.pk).unwrap(), &list);
+
+        let signature = kp.sk.sign_module(MODULE);
+        let mut key_ring = KeyRing::new();
+        key_ring.add(kp.pk.clone());
+        assert!(key_ring
+            .add_revocations(&embedded, &KeyPair::generate().pk)
+            .is_err());
+        key_ring.verify_module(MODULE, &signature).unwrap();
+        key_ring.add_revocations(&embedded, &root.pk).unwrap();
+        assert!(matches!(
+            key_ring.verify_module(MODULE, &signature),
+            Err(WSError::KeyRevoked(_))
+        ));
+
+        let mut tampered = embedded.to_bytes().unwrap();
+        tampered[1] ^= 0x01;
+        let tampered = SignedRevocationList::from_bytes(&tampered).unwrap();
+        assert!(tampered.verify(&root.pk).is_err());
+    }
+}
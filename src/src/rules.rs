@@ -0,0 +1,233 @@
+//! Custom rules, for checks that are specific to an organization.
+//!
+//! Rules are applied to the summary of a module: the metadata printed by `inspect`, along with
+//! its `imports` and `exports`, as lists of `{"name": ..., "signature": ...}` objects. Rules can
+//! be Rust closures, or, with the `wasmtime` feature, WebAssembly plugins.
+//!
+//! A plugin is a module without imports, exporting its `memory`, an `alloc(len: i32) -> i32`
+//! function returning a buffer for the summary, and a `check(ptr: i32, len: i32) -> i64`
+//! function receiving the JSON-encoded summary. `check` returns the address of its result in the
+//! upper 32 bits, and its length in the lower 32 bits. The result is a JSON array of strings,
+//! one per violation. Plugins run with a fuel budget, so that a buggy plugin can't hang the host.
+
+use crate::error::*;
+use crate::interface::InterfaceDirection;
+use crate::wasm_module::*;
+
+use serde_json::{json, Value};
+use std::fmt;
+
+/// A check applied to the summary of a module.
+pub trait Rule: Send + Sync {
+    /// Return the name of the rule, reported along with its violations.
+    fn name(&self) -> &str;
+
+    /// Return a description of each violation of the rule, given the summary of a module.
+    fn check(&self, summary: &Value) -> Result<Vec<String>, WSError>;
+}
+
+struct FnRule<F> {
+    name: String,
+    check: F,
+}
+
+impl<F: Fn(&Value) -> Vec<String> + Send + Sync> Rule for FnRule<F> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn check(&self, summary: &Value) -> Result<Vec<String>, WSError> {
+        Ok((self.check)(summary))
+    }
+}
+
+/// A set of custom rules.
+#[derive(Default)]
+pub struct RuleSet {
+    rules: Vec<Box<dyn Rule>>,
+}
+
+impl fmt::Debug for RuleSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.rules.iter().map(|rule| rule.name()))
+            .finish()
+    }
+}
+
+impl RuleSet {
+    /// Create an empty set of rules.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a rule.
+    pub fn rule(mut self, rule: impl Rule + 'static) -> Self {
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    /// Add a rule implemented by a closure, returning the violations found in a summary.
+    pub fn rule_fn(
+        self,
+        name: impl Into<String>,
+        check: impl Fn(&Value) -> Vec<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.rule(FnRule {
+            name: name.into(),
+            check,
+        })
+    }
+
+    /// Return the number of rules.
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Return `true` if the set has no rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Return a description of each violation, prefixed with the name of the rule.
+    pub fn violations(&self, module: &Module) -> Result<Vec<String>, WSError> {
+        if self.rules.is_empty() {
+            return Ok(vec![]);
+        }
+        let summary = module.summary()?;
+        let mut violations = vec![];
+        for rule in &self.rules {
+            for violation in rule.check(&summary)? {
+                violations.push(format!("{}: {}", rule.name(), violation));
+            }
+        }
+        Ok(violations)
+    }
+}
+
+impl Module {
+    /// Return the summary of the module that custom rules are applied to.
+    pub fn summary(&self) -> Result<Value, WSError> {
+        let mut summary = self.metadata()?;
+        let (mut imports, mut exports) = (vec![], vec![]);
+        if !self.is_component() {
+            for item in self.interface()? {
+                let item_json = json!({ "name": item.name, "signature": item.signature });
+                match item.direction {
+                    InterfaceDirection::Import => imports.push(item_json),
+                    InterfaceDirection::Export => exports.push(item_json),
+                }
+            }
+        }
+        summary["imports"] = Value::Array(imports);
+        summary["exports"] = Value::Array(exports);
+        Ok(summary)
+    }
+
+    /// Check the module against custom rules.
+    ///
+    /// If the module breaks any rule, an error listing every violation is returned.
+    pub fn check_rules(&self, rules: &RuleSet) -> Result<(), WSError> {
+        let violations = rules.violations(self)?;
+        if !violations.is_empty() {
+            return Err(WSError::PolicyViolation(violations.join("; ")));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "wasmtime")]
+pub use plugin::WasmRule;
+
+#[cfg(feature = "wasmtime")]
+mod plugin {
+    use super::*;
+
+    use std::path::Path;
+
+    /// Default fuel budget of a plugin, for each check.
+    const DEFAULT_FUEL: u64 = 100_000_000;
+
+    fn plugin_error(e: impl fmt::Display) -> WSError {
+        WSError::ValidationError(format!("rule plugin: {}", e))
+    }
+
+    /// A rule implemented by a WebAssembly plugin.
+    pub struct WasmRule {
+        name: String,
+        engine: wasmtime::Engine,
+        module: wasmtime::Module,
+        fuel: u64,
+    }
+
+    impl WasmRule {
+        /// Compile a plugin.
+        pub fn new(name: impl Into<String>, plugin_bin: &[u8]) -> Result<Self, WSError> {
+            let mut config = wasmtime::Config::new();
+            config.consume_fuel(true);
+            let engine = wasmtime::Engine::new(&config).map_err(plugin_error)?;
+            let module = wasmtime::Module::new(&engine, plugin_bin).map_err(plugin_error)?;
+            if module.imports().len() != 0 {
+                return Err(plugin_error("plugins can't have imports"));
+            }
+            Ok(WasmRule {
+                name: name.into(),
+                engine,
+                module,
+                fuel: DEFAULT_FUEL,
+            })
+        }
+
+        /// Load a plugin from a file, named after the file.
+        pub fn from_file(file: impl AsRef<Path>) -> Result<Self, WSError> {
+            let file = file.as_ref();
+            let name = file
+                .file_stem()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Self::new(name, &std::fs::read(file)?)
+        }
+
+        /// Set the fuel budget of each check.
+        pub fn fuel(mut self, fuel: u64) -> Self {
+            self.fuel = fuel;
+            self
+        }
+    }
+
+    impl Rule for WasmRule {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn check(&self, summary: &Value) -> Result<Vec<String>, WSError> {
+            let summary_json = serde_json::to_vec(summary)?;
+            let len = u32::try_from(summary_json.len()).map_err(plugin_error)?;
+            let mut store = wasmtime::Store::new(&self.engine, ());
+            store.set_fuel(self.fuel).map_err(plugin_error)?;
+            let instance =
+                wasmtime::Instance::new(&mut store, &self.module, &[]).map_err(plugin_error)?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| plugin_error("the plugin doesn't export its memory"))?;
+            let alloc = instance
+                .get_typed_func::<u32, u32>(&mut store, "alloc")
+                .map_err(plugin_error)?;
+            let check = instance
+                .get_typed_func::<(u32, u32), u64>(&mut store, "check")
+                .map_err(plugin_error)?;
+
+            let ptr = alloc.call(&mut store, len).map_err(plugin_error)?;
+            memory
+                .write(&mut store, ptr as usize, &summary_json)
+                .map_err(plugin_error)?;
+            let res = check.call(&mut store, (ptr, len)).map_err(plugin_error)?;
+            let (res_ptr, res_len) = ((res >> 32) as usize, (res & 0xffff_ffff) as usize);
+            let res_json = memory
+                .data(&store)
+                .get(res_ptr..res_ptr.saturating_add(res_len))
+                .ok_or_else(|| plugin_error("the result is out of bounds"))?;
+            serde_json::from_slice(res_json).map_err(plugin_error)
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use crate::error::*;
+use crate::licenses::LicensePolicy;
+use crate::wasm_module::*;
+
+use log::*;
+use std::io::{self, prelude::*};
+use std::str;
+
+const LIMITS_HAS_MAX: u8 = 0x01;
+const LIMITS_SHARED: u8 = 0x02;
+const LIMITS_64: u8 = 0x04;
+const LIMITS_PAGE_SIZE: u8 = 0x08;
+
+/// Rules applied by `Module::sanitize()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SanitizePolicy {
+    /// Custom sections to remove.
+    pub removed_sections: IgnoreList,
+    /// Remove all custom sections.
+    pub remove_custom_sections: bool,
+    /// Maximum number of pages of memories defined by the module.
+    pub max_memory_pages: Option<u64>,
+    /// Remove the start function.
+    pub strip_start: bool,
+    /// Allowed imports, as `(module, name)` pairs. A name of `*` allows every name of a module.
+    /// If empty, all imports are allowed.
+    pub allowed_imports: Vec<(String, String)>,
+    /// Rules applied to the licenses declared by the module, before custom sections are removed.
+    pub licenses: Option<LicensePolicy>,
+}
+
+impl SanitizePolicy {
+    /// Create a policy leaving modules unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove a custom section.
+    pub fn remove_section(mut self, name: impl Into<String>) -> Self {
+        self.removed_sections.add(name);
+        self
+    }
+
+    /// Remove all custom sections.
+    pub fn remove_custom_sections(mut self, remove: bool) -> Self {
+        self.remove_custom_sections = remove;
+        self
+    }
+
+    /// Limit the maximum size of memories defined by the module, in pages.
+    pub fn max_memory_pages(mut self, pages: u64) -> Self {
+        self.max_memory_pages = Some(pages);
+        self
+    }
+
+    /// Remove the start function.
+    pub fn strip_start(mut self, strip: bool) -> Self {
+        self.strip_start = strip;
+        self
+    }
+
+    /// Allow an import. Once an import is allowed, all other imports are rejected.
+    pub fn allow_import(mut self, module: impl Into<String>, name: impl Into<String>) -> Self {
+        self.allowed_imports.push((module.into(), name.into()));
+        self
+    }
+
+    /// Check the licenses declared by the module.
+    pub fn licenses(mut self, policy: LicensePolicy) -> Self {
+        self.licenses = Some(policy);
+        self
+    }
+
+    fn allows_import(&self, module: &str, name: &str) -> bool {
+        self.allowed_imports.is_empty()
+            || self
+                .allowed_imports
+                .iter()
+                .any(|(m, n)| m == module && (n == "*" || n == name))
+    }
+}
+
+/// A memory whose maximum size was lowered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClampedMemory {
+    /// Index of the memory among the memories defined by the module.
+    pub index: u32,
+    /// Previous maximum number of pages, if any.
+    pub previous_max: Option<u64>,
+    /// New maximum number of pages.
+    pub max: u64,
+}
+
+/// Changes made by `Module::sanitize()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SanitizeReport {
+    /// Names of the removed custom sections.
+    pub removed_sections: Vec<String>,
+    /// Memories whose maximum size was lowered.
+    pub clamped_memories: Vec<ClampedMemory>,
+    /// `true` if the start function was removed.
+    pub start_removed: bool,
+}
+
+impl SanitizeReport {
+    /// Return `true` if the module was left unchanged.
+    pub fn is_empty(&self) -> bool {
+        self.removed_sections.is_empty() && self.clamped_memories.is_empty() && !self.start_removed
+    }
+}
+
+pub(crate) fn get_u8(reader: &mut impl Read) -> Result<u8, WSError> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Skip a signed or unsigned LEB128-encoded integer.
+pub(crate) fn skip_leb(reader: &mut impl Read) -> Result<(), WSError> {
+    while get_u8(reader)? & 0x80 != 0 {}
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    flags: u8,
+    pub(crate) min: u64,
+    pub(crate) max: Option<u64>,
+    page_size_log2: Option<u32>,
+}
+
+impl Limits {
+    pub(crate) fn deserialize(reader: &mut impl Read) -> Result<Self, WSError> {
+        let flags = get_u8(reader)?;
+        if flags & !(LIMITS_HAS_MAX | LIMITS_SHARED | LIMITS_64 | LIMITS_PAGE_SIZE) != 0 {
+            return Err(WSError::ParseError);
+        }
+        let get = |reader: &mut _| match flags & LIMITS_64 {
+            0 => varint::get32(reader).map(u64::from),
+            _ => varint::get64(reader),
+        };
+        let min = get(reader)?;
+        let max = match flags & LIMITS_HAS_MAX {
+            0 => None,
+            _ => Some(get(reader)?),
+        };
+        let page_size_log2 = match flags & LIMITS_PAGE_SIZE {
+            0 => None,
+            _ => Some(varint::get32(reader)?),
+        };
+        Ok(Limits {
+            flags,
+            min,
+            max,
+            page_size_log2,
+        })
+    }
+
+    pub(crate) fn is_shared(&self) -> bool {
+        self.flags & LIMITS_SHARED != 0
+    }
+
+    pub(crate) fn is_64(&self) -> bool {
+        self.flags & LIMITS_64 != 0
+    }
+
+    fn serialize(&self, writer: &mut impl Write) -> Result<(), WSError> {
+        let mut flags = self.flags & !LIMITS_HAS_MAX;
+        if self.max.is_some() {
+            flags |= LIMITS_HAS_MAX;
+        }
+        writer.write_all(&[flags])?;
+        varint::put(writer, self.min)?;
+        if let Some(max) = self.max {
+            varint::put(writer, max)?;
+        }
+        if let Some(page_size_log2) = self.page_size_log2 {
+            varint::put(writer, page_size_log2 as _)?;
+        }
+        Ok(())
+    }
+}
+
+/// Skip a value type, or a reference type.
+fn skip_val_type(reader: &mut impl Read) -> Result<(), WSError> {
+    match get_u8(reader)? {
+        // Nullable and non-nullable references to a heap type.
+        0x63 | 0x64 => skip_leb(reader),
+        _ => Ok(()),
+    }
+}
+
+/// Return the module and name of every import.
+pub(crate) fn imports(payload: &[u8]) -> Result<Vec<(String, String)>, WSError> {
+    let mut reader = io::Cursor::new(payload);
+    let mut imports = vec![];
+    for _ in 0..varint::get32(&mut reader)? {
+        let module = str::from_utf8(&varint::get_slice(&mut reader)?)?.to_string();
+        let name = str::from_utf8(&varint::get_slice(&mut reader)?)?.to_string();
+        match get_u8(&mut reader)? {
+            // Function
+            0x00 => skip_leb(&mut reader)?,
+            // Table
+            0x01 => {
+                skip_val_type(&mut reader)?;
+                Limits::deserialize(&mut reader)?;
+            }
+            // Memory
+            0x02 => {
+                Limits::deserialize(&mut reader)?;
+            }
+            // Global
+            0x03 => {
+                skip_val_type(&mut reader)?;
+                get_u8(&mut reader)?;
+            }
+            // Tag
+            0x04 => {
+                get_u8(&mut reader)?;
+                skip_leb(&mut reader)?;
+            }
+            _ => return Err(WSError::ParseError),
+        }
+        imports.push((module, name));
+    }
+    Ok(imports)
+}
+
+/// Return the minimum and maximum number of pages of the memories of a memory section.
+pub(crate) fn memory_limits(payload: &[u8]) -> Result<Vec<(u64, Option<u64>)>, WSError> {
+    let mut reader = io::Cursor::new(payload);
+    let count = varint::get32(&mut reader)?;
+    let mut limits = vec![];
+    for _ in 0..count {
+        let memory = Limits::deserialize(&mut reader)?;
+        limits.push((memory.min, memory.max));
+    }
+    if reader.position() != payload.len() as u64 {
+        return Err(WSError::ParseError);
+    }
+    Ok(limits)
+}
+
+/// Lower the maximum size of the memories of a memory section.
+fn clamp_memories(
+    payload: &[u8],
+    max_pages: u64,
+    clamped: &mut Vec<ClampedMemory>,
+) -> Result<Vec<u8>, WSError> {
+    let mut reader = io::Cursor::new(payload);
+    let count = varint::get32(&mut reader)?;
+    let mut new_payload = vec![];
+    varint::put(&mut new_payload, count as _)?;
+    for index in 0..count {
+        let mut limits = Limits::deserialize(&mut reader)?;
+        if limits.min > max_pages {
+            return Err(WSError::PolicyViolation(format!(
+                "memory {} requires {} pages, more than the maximum of {}",
+                index, limits.min, max_pages
+            )));
+        }
+        if limits.max.is_none_or(|max| max > max_pages) {
+            clamped.push(ClampedMemory {
+                index,
+                previous_max: limits.max,
+                max: max_pages,
+            });
+            limits.max = Some(max_pages);
+        }
+        limits.serialize(&mut new_payload)?;
+    }
+    if reader.position() != payload.len() as u64 {
+        return Err(WSError::ParseError);
+    }
+    Ok(new_payload)
+}
+
+impl Module {
+    /// Apply a sanitization policy to the module, before executing untrusted code.
+    ///
+    /// Disallowed custom sections and the start function are removed, and the maximum size of memories
+    /// is lowered. If the module imports anything the policy doesn't allow, or requires more memory than
+    /// allowed, or declares licenses the policy doesn't accept, an error listing every violation is
+    /// returned and the module is left unchanged.
+    pub fn sanitize(&mut self, policy: &SanitizePolicy) -> Result<SanitizeReport, WSError> {
+        if self.is_component() {
+            return Err(WSError::UnsupportedModuleType);
+        }
+        let mut report = SanitizeReport::default();
+        let mut violations = match &policy.licenses {
+            Some(licenses) => licenses.violations(self)?,
+            None => vec![],
+        };
+        let mut sections = Vec::with_capacity(self.sections.len());
+        for section in &self.sections {
+            match section {
+                Section::Custom(custom)
+                    if policy.remove_custom_sections
+                        || policy.removed_sections.ignores(section) =>
+                {
+                    report.removed_sections.push(custom.name().to_string());
+                    continue;
+                }
+                Section::Standard(_) if section.id() == SectionId::Start && policy.strip_start => {
+                    report.start_removed = true;
+                    continue;
+                }
+                Section::Standard(_) if section.id() == SectionId::Import => {
+                    for (module, name) in imports(section.payload())? {
+                        if !policy.allows_import(&module, &name) {
+                            violations.push(format!("import {}::{} is not allowed", module, name));
+                        }
+                    }
+                }
+                Section::Standard(_) if section.id() == SectionId::Memory => {
+                    if let Some(max_pages) = policy.max_memory_pages {
+                        match clamp_memories(
+                            section.payload(),
+                            max_pages,
+                            &mut report.clamped_memories,
+                        ) {
+                            Ok(payload) => {
+                                sections.push(Section::new(SectionId::Memory, payload)?);
+                                continue;
+                            }
+                            Err(WSError::PolicyViolation(violation)) => violations.push(violation),
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                _ => {}
+            }
+            sections.push(section.clone());
+        }
+        if !violations.is_empty() {
+            return Err(WSError::PolicyViolation(violations.join("; ")));
+        }
+        debug!("Sanitized module: {:?}", report);
+        self.sections = sections;
+        Ok(report)
+    }
+}
@@ -0,0 +1,29 @@
+use crate::error::*;
+use crate::protocol::*;
+use crate::repo::ArtifactInfo;
+use crate::report::InstallReport;
+
+use schemars::schema_for;
+use serde_json::Value;
+
+/// Names of the documents JSON Schemas are available for.
+pub const SCHEMA_NAMES: &[&str] = &[
+    "update-check-request",
+    "update-check-response",
+    "patch-descriptor",
+    "artifact-info",
+    "install-report",
+];
+
+/// Return the JSON Schema of a document exchanged with, or stored by, an update server.
+pub fn schema(name: &str) -> Result<Value, WSError> {
+    let schema = match name {
+        "update-check-request" => schema_for!(UpdateCheckRequest),
+        "update-check-response" => schema_for!(UpdateCheckResponse),
+        "patch-descriptor" => schema_for!(PatchDescriptor),
+        "artifact-info" => schema_for!(ArtifactInfo),
+        "install-report" => schema_for!(InstallReport),
+        _ => return Err(WSError::NotFound(name.to_string())),
+    };
+    Ok(serde_json::to_value(schema)?)
+}
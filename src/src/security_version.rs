@@ -0,0 +1,156 @@
+//! Security versions, protecting devices against downgrades.
+//!
+//! A module can declare a security version, raised whenever a release fixes a vulnerability.
+//! Devices remember the highest security version they confirmed, and refuse to install or roll
+//! back to a module with a lower one, even if it is validly signed. A downgrade can still be
+//! allowed on purpose, by embedding an authorization signed with a dedicated downgrade key.
+
+use crate::error::*;
+use crate::signature::*;
+use crate::wasm_module::*;
+
+use std::io::{self, prelude::*};
+
+/// Name of the custom section storing the security version.
+pub const SECURITY_VERSION_SECTION_NAME: &str = "security-version";
+
+/// Name of the custom section storing the authorization to install a module despite its security version.
+pub const DOWNGRADE_AUTHORIZATION_SECTION_NAME: &str = "downgrade-authorization";
+
+const SECURITY_VERSION_VERSION: u8 = 0x01;
+
+const DOWNGRADE_CONTEXT: &[u8] = b"wasi-update/downgrade/v1\0";
+
+fn serialize_security_version(security_version: u64) -> Result<Vec<u8>, WSError> {
+    let mut writer = io::Cursor::new(vec![]);
+    writer.write_all(&[SECURITY_VERSION_VERSION])?;
+    varint::put(&mut writer, security_version)?;
+    Ok(writer.into_inner())
+}
+
+pub(crate) fn deserialize_security_version(payload: &[u8]) -> Result<u64, WSError> {
+    TakeExact::decode(payload, payload.len() as _, |reader| {
+        let mut version = [0u8; 1];
+        reader.read_exact(&mut version)?;
+        if version[0] != SECURITY_VERSION_VERSION {
+            return Err(WSError::ParseError);
+        }
+        varint::get64(reader)
+    })
+}
+
+fn find_custom_section<'t>(module: &'t Module, name: &str) -> Option<&'t CustomSection> {
+    module.sections.iter().find_map(|section| match section {
+        Section::Custom(custom) if custom.name() == name => Some(custom),
+        _ => None,
+    })
+}
+
+impl Module {
+    /// Return the security version of the module, or `0` if it doesn't declare one.
+    pub fn security_version(&self) -> Result<u64, WSError> {
+        match find_custom_section(self, SECURITY_VERSION_SECTION_NAME) {
+            None => Ok(0),
+            Some(custom) => deserialize_security_version(custom.payload()),
+        }
+    }
+
+    /// Set the security version of the module, replacing the existing one.
+    ///
+    /// This invalidates any downgrade authorization, which is removed.
+    pub fn set_security_version(&mut self, security_version: u64) -> Result<(), WSError> {
+        let payload = serialize_security_version(security_version)?;
+        self.remove_downgrade_authorization();
+        for section in self.sections.iter_mut() {
+            if let Section::Custom(custom) = section {
+                if custom.name() == SECURITY_VERSION_SECTION_NAME {
+                    custom.set_payload(payload);
+                    return Ok(());
+                }
+            }
+        }
+        self.sections.push(Section::Custom(CustomSection::new(
+            SECURITY_VERSION_SECTION_NAME.to_string(),
+            payload,
+        )));
+        Ok(())
+    }
+
+    fn downgrade_message(&self) -> Result<Vec<u8>, WSError> {
+        let mut ignored = IgnoreList::new();
+        ignored.add(DOWNGRADE_AUTHORIZATION_SECTION_NAME);
+        let mut message = DOWNGRADE_CONTEXT.to_vec();
+        message.extend_from_slice(&self.stable_hash_ignoring(&ignored)?);
+        Ok(message)
+    }
+
+    fn remove_downgrade_authorization(&mut self) {
+        self.sections.retain(|section| match section {
+            Section::Custom(custom) => custom.name() != DOWNGRADE_AUTHORIZATION_SECTION_NAME,
+            _ => true,
+        });
+    }
+
+    /// Authorize devices to install the module even if they confirmed a higher security version.
+    ///
+    /// The authorization covers the whole module, so it must be added after any other change,
+    /// but before the module is signed.
+    pub fn authorize_downgrade(&mut self, sk: &SecretKey) -> Result<(), WSError> {
+        self.remove_downgrade_authorization();
+        let signature = sk.sign(SignatureKind::Downgrade, &self.downgrade_message()?);
+        self.sections.push(Section::Custom(CustomSection::new(
+            DOWNGRADE_AUTHORIZATION_SECTION_NAME.to_string(),
+            signature.to_bytes(),
+        )));
+        Ok(())
+    }
+
+    /// Return `true` if the module carries a downgrade authorization made with one of the keys.
+    ///
+    /// Authorizations made with other keys, or that don't match the module, are rejected.
+    pub fn verify_downgrade_authorization(
+        &self,
+        downgrade_keys: &KeyRing,
+    ) -> Result<bool, WSError> {
+        let custom = match find_custom_section(self, DOWNGRADE_AUTHORIZATION_SECTION_NAME) {
+            None => return Ok(false),
+            Some(custom) => custom,
+        };
+        let signature = Signature::from_bytes(custom.payload())?;
+        downgrade_keys.verify(
+            SignatureKind::Downgrade,
+            &self.downgrade_message()?,
+            &signature,
+        )?;
+        Ok(true)
+    }
+
+    /// Check that the module can replace one with the given security version.
+    ///
+    /// Modules with a lower security version are only accepted with a downgrade authorization
+    /// made with one of `downgrade_keys`.
+    pub fn check_security_version(
+        &self,
+        min_security_version: u64,
+        downgrade_keys: &KeyRing,
+    ) -> Result<(), WSError> {
+        let security_version = self.security_version()?;
+        if security_version >= min_security_version {
+            return Ok(());
+        }
+        let downgrade = || {
+            WSError::SecurityDowngrade(format!(
+                "security version {} is lower than {}",
+                security_version, min_security_version
+            ))
+        };
+        if downgrade_keys.is_empty() {
+            return Err(downgrade());
+        }
+        match self.verify_downgrade_authorization(downgrade_keys) {
+            Ok(true) => Ok(()),
+            Ok(false) | Err(WSError::VerificationFailed) => Err(downgrade()),
+            Err(e) => Err(e),
+        }
+    }
+}
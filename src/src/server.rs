@@ -0,0 +1,396 @@
+use crate::error::*;
+use crate::protocol::*;
+use crate::repo::*;
+use crate::signature::SecretKey;
+
+use log::*;
+use std::io::{self, prelude::*, BufReader};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const MAX_HEADERS_SIZE: usize = 64 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn read_line(reader: &mut impl BufRead, headers_size: &mut usize) -> Result<String, WSError> {
+        let mut line = String::new();
+        let len = reader
+            .take((MAX_HEADERS_SIZE - *headers_size) as u64)
+            .read_line(&mut line)?;
+        *headers_size += len;
+        if !line.ends_with('\n') {
+            return Err(WSError::ParseError);
+        }
+        Ok(line.trim_end().to_string())
+    }
+
+    pub(crate) fn read(reader: &mut impl BufRead) -> Result<Self, WSError> {
+        let mut headers_size = 0;
+        let request_line = Self::read_line(reader, &mut headers_size)?;
+        let mut parts = request_line.split_whitespace();
+        let (method, path) = match (parts.next(), parts.next()) {
+            (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+            _ => return Err(WSError::ParseError),
+        };
+        let mut headers = vec![];
+        loop {
+            let line = Self::read_line(reader, &mut headers_size)?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').ok_or(WSError::ParseError)?;
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        let mut request = Request {
+            method,
+            path,
+            headers,
+            body: vec![],
+        };
+        if let Some(len) = request.header("content-length") {
+            let len: usize = len.parse().map_err(|_| WSError::ParseError)?;
+            if len > MAX_BODY_SIZE {
+                return Err(WSError::ParseError);
+            }
+            request.body = vec![0u8; len];
+            reader.read_exact(&mut request.body)?;
+        }
+        Ok(request)
+    }
+}
+
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub(crate) fn new(status: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body,
+        }
+    }
+
+    pub(crate) fn error(status: u16) -> Self {
+        Self::new(status, "text/plain", reason(status).as_bytes().to_vec())
+    }
+
+    pub(crate) fn write(&self, writer: &mut impl Write, head_only: bool) -> Result<(), WSError> {
+        let mut out = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        out.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.body.len()
+        ));
+        writer.write_all(out.as_bytes())?;
+        if !head_only {
+            writer.write_all(&self.body)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Serve requests with `handler` on a local port, and return the URL of the server.
+#[cfg(all(test, any(feature = "oci", feature = "peer", feature = "s3")))]
+pub(crate) fn serve_fake(mut handler: impl FnMut(&Request) -> Response + Send + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let response = match Request::read(&mut reader) {
+                Ok(request) => handler(&request),
+                Err(_) => Response::error(400),
+            };
+            let _ = response.write(&mut io::BufWriter::new(stream), false);
+        }
+    });
+    url
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        416 => "Range Not Satisfiable",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Parse a single `bytes=start-end` range, returning the offsets of the first and last bytes.
+fn parse_range(range: &str, len: u64) -> Option<(u64, u64)> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            if suffix == 0 {
+                return None;
+            }
+            (len.saturating_sub(suffix), len.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, len.checked_sub(1)?),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(len.checked_sub(1)?),
+        ),
+    };
+    if start > end || start >= len {
+        return None;
+    }
+    Some((start, end))
+}
+
+/// A minimal HTTP server exposing a repository with the update-check protocol.
+///
+/// Artifacts and patches are served under the URLs returned in update-check responses,
+/// with support for single-range requests so that interrupted downloads can be resumed.
+///
+/// Update-check responses are encoded as JSON, or as CBOR for clients accepting `application/cbor`.
+/// If a manifest key is set, clients accepting `application/cose` receive a signed `COSE_Sign1` envelope.
+///
+/// Connections are handled by a fixed number of workers, and every read and write on a connection
+/// must complete within the timeout, so that slow or idle clients can't exhaust the server.
+pub struct Server<B: Backend> {
+    repo: Arc<Repository<B>>,
+    manifest_key: Option<Arc<SecretKey>>,
+    workers: usize,
+    timeout: Duration,
+}
+
+impl<B: Backend + Send + Sync + 'static> Server<B> {
+    /// Create a server for the given repository.
+    pub fn new(repo: Repository<B>) -> Self {
+        Server {
+            repo: Arc::new(repo),
+            manifest_key: None,
+            workers: 16,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Set the maximum number of connections handled at the same time.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Set the timeout for reads and writes on a connection.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set the key used to sign update-check responses sent as COSE envelopes.
+    pub fn manifest_key(mut self, sk: SecretKey) -> Self {
+        self.manifest_key = Some(Arc::new(sk));
+        self
+    }
+
+    /// Listen on the given address and serve requests.
+    ///
+    /// This only returns if the address can't be bound.
+    pub fn serve(&self, addr: impl ToSocketAddrs) -> Result<(), WSError> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Listening on {}", listener.local_addr()?);
+        self.serve_listener(&listener);
+        Ok(())
+    }
+
+    fn serve_listener(&self, listener: &TcpListener) {
+        thread::scope(|scope| {
+            for _ in 0..self.workers {
+                scope.spawn(|| loop {
+                    let stream = match listener.accept() {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            warn!("Connection failed: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = self.handle_connection(stream) {
+                        debug!("Connection error: {}", e);
+                    }
+                });
+            }
+        });
+    }
+
+    fn handle_connection(&self, stream: TcpStream) -> Result<(), WSError> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        handle_connection(&self.repo, self.manifest_key.as_deref(), stream)
+    }
+}
+
+fn handle_connection<B: Backend>(
+    repo: &Repository<B>,
+    manifest_key: Option<&SecretKey>,
+    stream: TcpStream,
+) -> Result<(), WSError> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = io::BufWriter::new(stream);
+    let request = match Request::read(&mut reader) {
+        Ok(request) => request,
+        Err(_) => return Response::error(400).write(&mut writer, false),
+    };
+    let response = match handle_request(repo, manifest_key, &request) {
+        Ok(response) => response,
+        Err(WSError::NotFound(_)) | Err(WSError::InvalidKey(_)) => Response::error(404),
+        Err(WSError::JSONError(_)) => Response::error(400),
+        Err(e) => {
+            warn!("{} {}: {}", request.method, request.path, e);
+            Response::error(500)
+        }
+    };
+    debug!("{} {} {}", request.method, request.path, response.status);
+    response.write(&mut writer, request.method == "HEAD")
+}
+
+fn handle_request<B: Backend>(
+    repo: &Repository<B>,
+    manifest_key: Option<&SecretKey>,
+    request: &Request,
+) -> Result<Response, WSError> {
+    let path = request.path.split('?').next().unwrap_or_default();
+    if path == UPDATE_CHECK_PATH {
+        if request.method != "POST" {
+            return Ok(Response::error(405));
+        }
+        let check: UpdateCheckRequest = serde_json::from_slice(&request.body)?;
+        let response = repo.check_update(&check)?;
+        let accept = request.header("accept").unwrap_or_default();
+        return Ok(match manifest_key {
+            Some(sk) if accept.contains("application/cose") => {
+                Response::new(200, COSE_CONTENT_TYPE, response.to_cose(sk)?)
+            }
+            _ if accept.contains(CBOR_CONTENT_TYPE) => {
+                Response::new(200, CBOR_CONTENT_TYPE, response.to_cbor()?)
+            }
+            _ => Response::new(200, "application/json", serde_json::to_vec(&response)?),
+        });
+    }
+    if request.method != "GET" && request.method != "HEAD" {
+        return Ok(Response::error(405));
+    }
+    let (data, content_type) = if let Some(name) = path.strip_prefix("/artifacts/") {
+        let (build_id, encoding) = name
+            .split_once(".wasm")
+            .ok_or_else(|| WSError::NotFound(path.to_string()))?;
+        match encoding.strip_prefix('.') {
+            None if encoding.is_empty() => (repo.artifact(build_id)?, "application/wasm"),
+            None => return Err(WSError::NotFound(path.to_string())),
+            Some(encoding) => (
+                repo.encoded_artifact(build_id, encoding)?,
+                "application/octet-stream",
+            ),
+        }
+    } else if let Some(name) = path.strip_prefix("/patches/") {
+        let (base_build_id, build_id) = name
+            .strip_suffix(".patch")
+            .and_then(|name| name.split_once('/'))
+            .ok_or_else(|| WSError::NotFound(path.to_string()))?;
+        let patch = repo
+            .patch(base_build_id, build_id)?
+            .ok_or_else(|| WSError::NotFound(path.to_string()))?;
+        (patch, "application/octet-stream")
+    } else {
+        return Ok(Response::error(404));
+    };
+    Ok(data_response(request, content_type, data))
+}
+
+/// Respond with an object, or with the part of it requested with a `Range` header.
+pub(crate) fn data_response(request: &Request, content_type: &str, data: Vec<u8>) -> Response {
+    let len = data.len() as u64;
+    let mut response = match request.header("range") {
+        None => Response::new(200, content_type, data),
+        Some(range) => match parse_range(range, len) {
+            None => {
+                let mut response = Response::error(416);
+                response
+                    .headers
+                    .push(("Content-Range", format!("bytes */{}", len)));
+                return response;
+            }
+            Some((start, end)) => {
+                let mut response = Response::new(
+                    206,
+                    content_type,
+                    data[start as usize..=end as usize].to_vec(),
+                );
+                response
+                    .headers
+                    .push(("Content-Range", format!("bytes {}-{}/{}", start, end, len)));
+                response
+            }
+        },
+    };
+    response
+        .headers
+        .push(("Accept-Ranges", "bytes".to_string()));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::MemoryBackend;
+    use std::time::Instant;
+
+    fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn idle_connections_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timeout = Duration::from_millis(200);
+        let server = Server::new(Repository::new(MemoryBackend::new()))
+            .workers(1)
+            .timeout(timeout);
+        thread::spawn(move || server.serve_listener(&listener));
+
+        // An idle client holds the only worker until it times out.
+        let start = Instant::now();
+        let mut idle = TcpStream::connect(addr).unwrap();
+        assert!(get(addr, "/missing").starts_with("HTTP/1.1 404"));
+        assert!(start.elapsed() >= timeout);
+        let mut response = String::new();
+        idle.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"));
+    }
+}
@@ -0,0 +1,152 @@
+use super::*;
+use crate::cbor::Value;
+
+const COSE_SIGN1_TAG: u64 = 18;
+const HEADER_ALG: u64 = 1;
+const HEADER_KID: u64 = 4;
+const ALG_EDDSA: i64 = -8;
+
+struct Sign1 {
+    protected: Vec<u8>,
+    key_id: KeyId,
+    payload: Option<Vec<u8>>,
+    signature: [u8; 64],
+}
+
+fn sig_structure(protected: &[u8], payload: &[u8]) -> Vec<u8> {
+    Value::Array(vec![
+        Value::Text("Signature1".to_string()),
+        Value::Bytes(protected.to_vec()),
+        Value::Bytes(vec![]),
+        Value::Bytes(payload.to_vec()),
+    ])
+    .encode()
+}
+
+fn parse_sign1(cose: &[u8]) -> Result<Sign1, WSError> {
+    let value = Value::decode(cose)?;
+    let value = match value {
+        Value::Tag(COSE_SIGN1_TAG, value) => *value,
+        Value::Tag(..) => return Err(WSError::ParseError),
+        value => value,
+    };
+    let items = match value.as_array() {
+        Some(items) if items.len() == 4 => items,
+        _ => return Err(WSError::ParseError),
+    };
+    let protected = items[0].as_bytes().ok_or(WSError::ParseError)?;
+    let protected_headers = if protected.is_empty() {
+        Value::Map(vec![])
+    } else {
+        Value::decode(protected)?
+    };
+    let header = |label: u64| {
+        protected_headers
+            .get(&Value::Unsigned(label))
+            .or_else(|| items[1].get(&Value::Unsigned(label)))
+    };
+    if header(HEADER_ALG).and_then(Value::as_i64) != Some(ALG_EDDSA) {
+        return Err(WSError::VerificationFailed);
+    }
+    let key_id = header(HEADER_KID)
+        .and_then(Value::as_bytes)
+        .and_then(|kid| KeyId::try_from(kid).ok())
+        .ok_or(WSError::NoSignature)?;
+    let payload = match &items[2] {
+        Value::Null => None,
+        Value::Bytes(payload) => Some(payload.clone()),
+        _ => return Err(WSError::ParseError),
+    };
+    let signature = items[3]
+        .as_bytes()
+        .and_then(|sig| <[u8; 64]>::try_from(sig).ok())
+        .ok_or(WSError::ParseError)?;
+    Ok(Sign1 {
+        protected: protected.to_vec(),
+        key_id,
+        payload,
+        signature,
+    })
+}
+
+impl PublicKey {
+    /// Verify a `COSE_Sign1` envelope, and return its payload.
+    ///
+    /// The envelope must use the EdDSA algorithm, and identify the key in its `kid` header.
+    pub fn verify_cose(&self, cose: &[u8]) -> Result<Vec<u8>, WSError> {
+        let sign1 = parse_sign1(cose)?;
+        let payload = sign1.payload.clone().ok_or(WSError::ParseError)?;
+        self.verify_sign1(&sign1, &payload)?;
+        Ok(payload)
+    }
+
+    /// Verify a `COSE_Sign1` envelope whose payload is transmitted separately.
+    pub fn verify_cose_detached(&self, cose: &[u8], payload: &[u8]) -> Result<(), WSError> {
+        let sign1 = parse_sign1(cose)?;
+        if sign1.payload.is_some() {
+            return Err(WSError::ParseError);
+        }
+        self.verify_sign1(&sign1, payload)
+    }
+
+    fn verify_sign1(&self, sign1: &Sign1, payload: &[u8]) -> Result<(), WSError> {
+        if sign1.key_id != self.key_id {
+            return Err(WSError::VerificationFailed);
+        }
+        self.pk
+            .verify(
+                sig_structure(&sign1.protected, payload),
+                &ed25519_compact::Signature::new(sign1.signature),
+            )
+            .map_err(|_| WSError::VerificationFailed)
+    }
+}
+
+impl SecretKey {
+    /// Wrap `payload` in a tagged `COSE_Sign1` envelope.
+    pub fn sign_cose(&self, payload: &[u8]) -> Vec<u8> {
+        self.sign1(payload, Value::Bytes(payload.to_vec()))
+    }
+
+    /// Create a tagged `COSE_Sign1` envelope for `payload`, without including the payload itself.
+    pub fn sign_cose_detached(&self, payload: &[u8]) -> Vec<u8> {
+        self.sign1(payload, Value::Null)
+    }
+
+    fn sign1(&self, payload: &[u8], payload_value: Value) -> Vec<u8> {
+        let protected = Value::Map(vec![
+            (Value::Unsigned(HEADER_ALG), Value::from(ALG_EDDSA)),
+            (
+                Value::Unsigned(HEADER_KID),
+                Value::Bytes(self.key_id.to_vec()),
+            ),
+        ])
+        .encode();
+        let signature = self.sk.sign(sig_structure(&protected, payload), None);
+        Value::Tag(
+            COSE_SIGN1_TAG,
+            Box::new(Value::Array(vec![
+                Value::Bytes(protected),
+                Value::Map(vec![]),
+                payload_value,
+                Value::Bytes(signature.to_vec()),
+            ])),
+        )
+        .encode()
+    }
+}
+
+impl KeyRing {
+    /// Verify a `COSE_Sign1` envelope using the key that created it, and return its payload.
+    pub fn verify_cose(&self, cose: &[u8]) -> Result<Vec<u8>, WSError> {
+        let sign1 = parse_sign1(cose)?;
+        self.key_for(&sign1.key_id)?.verify_cose(cose)
+    }
+
+    /// Verify a `COSE_Sign1` envelope with a detached payload using the key that created it.
+    pub fn verify_cose_detached(&self, cose: &[u8], payload: &[u8]) -> Result<(), WSError> {
+        let sign1 = parse_sign1(cose)?;
+        self.key_for(&sign1.key_id)?
+            .verify_cose_detached(cose, payload)
+    }
+}
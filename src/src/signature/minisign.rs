@@ -0,0 +1,231 @@
+use super::*;
+
+const SIG_ALG_LEGACY: &[u8; 2] = b"Ed";
+const SIG_ALG_PREHASHED: &[u8; 2] = b"ED";
+const UNTRUSTED_COMMENT_PREFIX: &str = "untrusted comment: ";
+const TRUSTED_COMMENT_PREFIX: &str = "trusted comment: ";
+
+fn minisign_key_id(key_id: &KeyId) -> String {
+    format!("{:016X}", u64::from_le_bytes(*key_id))
+}
+
+fn decode_line(line: Option<&str>) -> Result<Vec<u8>, WSError> {
+    let line = line.ok_or(WSError::ParseError)?;
+    Base64::decode_to_vec(line.trim(), None).map_err(|_| WSError::ParseError)
+}
+
+/// The content of a minisign signature file.
+struct MinisignSignature<'t> {
+    alg: [u8; 2],
+    key_id: KeyId,
+    signature: [u8; 64],
+    trusted_comment: &'t str,
+    global_signature: Vec<u8>,
+}
+
+impl<'t> MinisignSignature<'t> {
+    fn parse(signature: &'t str) -> Result<Self, WSError> {
+        let mut lines = signature.lines();
+        let untrusted_comment = lines.next().ok_or(WSError::ParseError)?;
+        if !untrusted_comment.starts_with(UNTRUSTED_COMMENT_PREFIX) {
+            return Err(WSError::ParseError);
+        }
+        let bin = decode_line(lines.next())?;
+        let trusted_comment = lines
+            .next()
+            .and_then(|line| line.strip_prefix(TRUSTED_COMMENT_PREFIX))
+            .ok_or(WSError::ParseError)?
+            .trim_end_matches('\r');
+        let global_signature = decode_line(lines.next())?;
+        if bin.len() != 2 + 8 + 64 || global_signature.len() != 64 {
+            return Err(WSError::ParseError);
+        }
+        Ok(MinisignSignature {
+            alg: bin[..2].try_into().unwrap(),
+            key_id: bin[2..10].try_into().unwrap(),
+            signature: bin[10..].try_into().unwrap(),
+            trusted_comment,
+            global_signature,
+        })
+    }
+}
+
+impl PublicKey {
+    /// Return the public key in minisign format.
+    pub fn to_minisign(&self) -> String {
+        let mut bin = SIG_ALG_LEGACY.to_vec();
+        bin.extend_from_slice(&self.key_id);
+        bin.extend_from_slice(self.pk.as_ref());
+        format!(
+            "{}minisign public key {}\n{}\n",
+            UNTRUSTED_COMMENT_PREFIX,
+            minisign_key_id(&self.key_id),
+            Base64::encode_to_string(bin).unwrap()
+        )
+    }
+
+    /// Read a public key in minisign format, either a full file or only its base64 line.
+    pub fn from_minisign(s: &str) -> Result<Self, WSError> {
+        let line = s
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with(UNTRUSTED_COMMENT_PREFIX));
+        let bin = decode_line(line)?;
+        if bin.len() != 2 + 8 + 32 || &bin[..2] != SIG_ALG_LEGACY {
+            return Err(WSError::InvalidKey("minisign public key".to_string()));
+        }
+        let pk = ed25519_compact::PublicKey::from_slice(&bin[10..])
+            .map_err(|_| WSError::InvalidKey("minisign public key".to_string()))?;
+        Ok(PublicKey {
+            key_id: bin[2..10].try_into().unwrap(),
+            pk,
+            domain: None,
+        })
+    }
+
+    /// Verify a minisign signature of `data`, and return its trusted comment.
+    ///
+    /// Both prehashed and legacy signatures are accepted. Use `KeyRing::verify_minisign()` to
+    /// honor revocations and signing domains.
+    pub fn verify_minisign(&self, data: &[u8], signature: &str) -> Result<String, WSError> {
+        let signature = MinisignSignature::parse(signature)?;
+        if signature.key_id != self.key_id {
+            return Err(WSError::UnknownKey(minisign_key_id(&signature.key_id)));
+        }
+        self.verify_parsed_minisign(data, &signature)
+    }
+
+    fn verify_parsed_minisign(
+        &self,
+        data: &[u8],
+        signature: &MinisignSignature,
+    ) -> Result<String, WSError> {
+        let data = domain_message(self.domain(), data);
+        let verified = match &signature.alg {
+            alg if alg == SIG_ALG_PREHASHED => self.pk.verify(
+                blake2b::hash(&data),
+                &ed25519_compact::Signature::new(signature.signature),
+            ),
+            alg if alg == SIG_ALG_LEGACY => self
+                .pk
+                .verify(&data, &ed25519_compact::Signature::new(signature.signature)),
+            _ => return Err(WSError::ParseError),
+        };
+        verified.map_err(|_| WSError::VerificationFailed)?;
+        let mut global_message = signature.signature.to_vec();
+        global_message.extend_from_slice(signature.trusted_comment.as_bytes());
+        self.pk
+            .verify(
+                global_message,
+                &ed25519_compact::Signature::from_slice(&signature.global_signature)
+                    .map_err(|_| WSError::ParseError)?,
+            )
+            .map_err(|_| WSError::VerificationFailed)?;
+        Ok(signature.trusted_comment.to_string())
+    }
+}
+
+impl KeyRing {
+    /// Verify a minisign signature of `data` using the key that created it, and return its
+    /// trusted comment.
+    pub fn verify_minisign(&self, data: &[u8], signature: &str) -> Result<String, WSError> {
+        let signature = MinisignSignature::parse(signature)?;
+        self.key_for(&signature.key_id)?
+            .verify_parsed_minisign(data, &signature)
+    }
+}
+
+impl SecretKey {
+    /// Create a prehashed minisign signature of `data`.
+    pub fn sign_minisign(&self, data: &[u8], trusted_comment: &str) -> Result<String, WSError> {
+        if trusted_comment.contains(['\r', '\n']) {
+            return Err(WSError::ParseError);
+        }
+        let data = domain_message(self.domain(), data);
+        let signature = self.sk.sign(blake2b::hash(&data), None);
+        let mut bin = SIG_ALG_PREHASHED.to_vec();
+        bin.extend_from_slice(&self.key_id);
+        bin.extend_from_slice(signature.as_ref());
+        let mut global_message = signature.to_vec();
+        global_message.extend_from_slice(trusted_comment.as_bytes());
+        let global_signature = self.sk.sign(global_message, None);
+        Ok(format!(
+            "{}signature from wasi-update secret key\n{}\n{}{}\n{}\n",
+            UNTRUSTED_COMMENT_PREFIX,
+            Base64::encode_to_string(bin).unwrap(),
+            TRUSTED_COMMENT_PREFIX,
+            trusted_comment,
+            Base64::encode_to_string(global_signature).unwrap()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"module";
+
+    #[test]
+    fn key_ring_verifies_minisign_signatures() {
+        let kp = KeyPair::generate();
+        let signature = kp.sk.sign_minisign(DATA, "app 1.2.0").unwrap();
+        let mut key_ring = KeyRing::new();
+        key_ring.add(kp.pk.clone());
+        assert_eq!(
+            key_ring.verify_minisign(DATA, &signature).unwrap(),
+            "app 1.2.0"
+        );
+        assert!(matches!(
+            key_ring.verify_minisign(b"other", &signature),
+            Err(WSError::VerificationFailed)
+        ));
+        assert!(matches!(
+            KeyRing::new().verify_minisign(DATA, &signature),
+            Err(WSError::UnknownKey(_))
+        ));
+    }
+
+    #[test]
+    fn key_ring_rejects_revoked_minisign_keys() {
+        let kp = KeyPair::generate();
+        let signature = kp.sk.sign_minisign(DATA, "").unwrap();
+        let mut key_ring = KeyRing::new();
+        key_ring.add(kp.pk.clone());
+        key_ring.revoke(*kp.pk.key_id(), 0);
+        assert!(matches!(
+            key_ring.verify_minisign(DATA, &signature),
+            Err(WSError::KeyRevoked(_))
+        ));
+    }
+
+    #[test]
+    fn minisign_signatures_are_bound_to_their_domain() {
+        let kp = KeyPair::generate();
+        let signature = kp
+            .sk
+            .clone()
+            .in_domain("billing")
+            .sign_minisign(DATA, "")
+            .unwrap();
+
+        let mut key_ring = KeyRing::new();
+        key_ring.add(kp.pk.clone().in_domain("billing"));
+        key_ring.set_domain("billing");
+        key_ring.verify_minisign(DATA, &signature).unwrap();
+
+        let mut other_ring = KeyRing::new();
+        other_ring.add(kp.pk.clone().in_domain("shipping"));
+        other_ring.set_domain("shipping");
+        assert!(other_ring.verify_minisign(DATA, &signature).is_err());
+        assert!(kp.pk.verify_minisign(DATA, &signature).is_err());
+
+        let mut unbound_ring = KeyRing::new();
+        unbound_ring.add(kp.pk.clone());
+        unbound_ring.set_domain("billing");
+        assert!(matches!(
+            unbound_ring.verify_minisign(DATA, &signature),
+            Err(WSError::DomainMismatch(_))
+        ));
+    }
+}
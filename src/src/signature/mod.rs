@@ -0,0 +1,670 @@
+mod blake2b;
+mod cose;
+mod minisign;
+mod sshsig;
+
+use crate::error::*;
+use crate::wasm_module::*;
+
+use ct_codecs::{Base64, Decoder, Encoder, Hex};
+use hmac_sha256::Hash;
+use std::borrow::Cow;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const KEY_VERSION: u8 = 0x01;
+
+/// Version of the signature format written by this crate.
+///
+/// Signatures start with their format version. Signatures using a version that is not supported any more
+/// are rejected with `WSError::UnsupportedSignatureVersion`, and can be replaced in bulk with
+/// `Repository::migrate_signatures()`.
+pub const SIGNATURE_VERSION: u8 = 0x01;
+
+const MODULE_CONTEXT: &[u8] = b"wasi-update/module/v1\0";
+const PATCH_CONTEXT: &[u8] = b"wasi-update/patch/v1\0";
+const FILTERED_MODULE_CONTEXT: &[u8] = b"wasi-update/module-filtered/v1\0";
+const DOMAIN_CONTEXT: &[u8] = b"wasi-update/domain/v1\0";
+
+/// A key identifier.
+pub type KeyId = [u8; 8];
+
+/// Return the hex-encoded representation of a key identifier.
+pub fn key_id_to_hex(key_id: &KeyId) -> String {
+    Hex::encode_to_string(key_id).unwrap()
+}
+
+fn derive_key_id(pk: &ed25519_compact::PublicKey) -> KeyId {
+    Hash::hash(pk.as_ref())[..8].try_into().unwrap()
+}
+
+fn digest_from_build_id(build_id: &str) -> Result<[u8; 32], WSError> {
+    let digest = Hex::decode_to_vec(build_id, None).map_err(|_| WSError::ParseError)?;
+    digest.try_into().map_err(|_| WSError::ParseError)
+}
+
+/// Prefix a message with the signing domain of a key, if it has one.
+///
+/// A signature made with a key bound to a domain is thus never valid for another domain, nor
+/// without a domain.
+fn domain_message<'t>(domain: Option<&str>, message: &'t [u8]) -> Cow<'t, [u8]> {
+    let domain = match domain {
+        None => return Cow::Borrowed(message),
+        Some(domain) => domain,
+    };
+    let mut domain_message = DOMAIN_CONTEXT.to_vec();
+    // Writing to a vector can't fail.
+    varint::put_slice(&mut domain_message, domain).unwrap();
+    domain_message.extend_from_slice(message);
+    Cow::Owned(domain_message)
+}
+
+fn module_message(module_bin: &[u8]) -> Vec<u8> {
+    module_digest_message(&Hash::hash(module_bin))
+}
+
+fn module_digest_message(digest: &[u8; 32]) -> Vec<u8> {
+    let mut message = MODULE_CONTEXT.to_vec();
+    message.extend_from_slice(digest);
+    message
+}
+
+/// The message signed for a module when some sections are ignored.
+///
+/// It includes the list of ignored sections, so that signatures made with different lists can't be mixed up.
+fn filtered_module_message(module_bin: &[u8], ignored: &IgnoreList) -> Result<Vec<u8>, WSError> {
+    if ignored.is_empty() {
+        return Ok(module_message(module_bin));
+    }
+    let module = Module::deserialize(&mut io::Cursor::new(module_bin))?;
+    let mut message = FILTERED_MODULE_CONTEXT.to_vec();
+    let names = ignored.names();
+    varint::put(&mut message, names.len() as _)?;
+    for name in names {
+        varint::put_slice(&mut message, name)?;
+    }
+    message.extend_from_slice(&module.stable_hash_ignoring(ignored)?);
+    Ok(message)
+}
+
+fn patch_message(base_build_id: &str, build_id: &str, patch: &[u8]) -> Result<Vec<u8>, WSError> {
+    let mut message = PATCH_CONTEXT.to_vec();
+    message.extend_from_slice(&digest_from_build_id(base_build_id)?);
+    message.extend_from_slice(&digest_from_build_id(build_id)?);
+    message.extend_from_slice(&Hash::hash(patch));
+    Ok(message)
+}
+
+/// The kind of object a signature covers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SignatureKind {
+    /// A complete module.
+    Module,
+    /// A patch, along with the modules it transforms.
+    Patch,
+    /// A key revocation list.
+    RevocationList,
+    /// A precompiled artifact, along with the module and the runtime it was made for.
+    Artifact,
+    /// The authorization to install a module with a lower security version.
+    Downgrade,
+}
+
+impl From<SignatureKind> for u8 {
+    fn from(v: SignatureKind) -> Self {
+        match v {
+            SignatureKind::Module => 0x01,
+            SignatureKind::Patch => 0x02,
+            SignatureKind::RevocationList => 0x03,
+            SignatureKind::Artifact => 0x04,
+            SignatureKind::Downgrade => 0x05,
+        }
+    }
+}
+
+impl TryFrom<u8> for SignatureKind {
+    type Error = WSError;
+
+    fn try_from(v: u8) -> Result<Self, WSError> {
+        match v {
+            0x01 => Ok(SignatureKind::Module),
+            0x02 => Ok(SignatureKind::Patch),
+            0x03 => Ok(SignatureKind::RevocationList),
+            0x04 => Ok(SignatureKind::Artifact),
+            0x05 => Ok(SignatureKind::Downgrade),
+            _ => Err(WSError::ParseError),
+        }
+    }
+}
+
+/// A detached signature.
+///
+/// Signatures can also be exchanged in the minisign and OpenSSH `sshsig` formats, using
+/// `SecretKey::sign_minisign()` and `SecretKey::sign_sshsig()`, and verified with
+/// `KeyRing::verify_minisign()` and `KeyRing::verify_sshsig()`. Signatures made with a key bound
+/// to a domain can only be verified in that domain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    /// What the signature covers.
+    pub kind: SignatureKind,
+    /// Identifier of the key that created the signature.
+    pub key_id: KeyId,
+    signature: [u8; 64],
+}
+
+impl Signature {
+    /// Serialize the signature.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![SIGNATURE_VERSION, self.kind.into()];
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    /// Return the format version of a serialized signature.
+    pub fn version(bytes: &[u8]) -> Result<u8, WSError> {
+        bytes.first().copied().ok_or(WSError::ParseError)
+    }
+
+    /// Deserialize a signature.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WSError> {
+        let version = Self::version(bytes)?;
+        if version != SIGNATURE_VERSION {
+            return Err(WSError::UnsupportedSignatureVersion(version));
+        }
+        if bytes.len() != 2 + 8 + 64 {
+            return Err(WSError::ParseError);
+        }
+        Ok(Signature {
+            kind: bytes[1].try_into()?,
+            key_id: bytes[2..10].try_into().unwrap(),
+            signature: bytes[10..].try_into().unwrap(),
+        })
+    }
+
+    /// Serialize the signature as base64.
+    pub fn to_base64(&self) -> String {
+        Base64::encode_to_string(self.to_bytes()).unwrap()
+    }
+
+    /// Deserialize a base64-encoded signature.
+    pub fn from_base64(s: &str) -> Result<Self, WSError> {
+        let bytes = Base64::decode_to_vec(s.trim(), None).map_err(|_| WSError::ParseError)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// A public key, used to verify signatures.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    key_id: KeyId,
+    pk: ed25519_compact::PublicKey,
+    domain: Option<String>,
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicKey({})", key_id_to_hex(&self.key_id))
+    }
+}
+
+impl PublicKey {
+    /// Return the identifier of the key.
+    pub fn key_id(&self) -> &KeyId {
+        &self.key_id
+    }
+
+    /// Bind the key to a signing domain, such as a tenant or a project.
+    ///
+    /// The key then only verifies signatures made by a secret key bound to the same domain.
+    pub fn in_domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// Return the signing domain the key is bound to, if any.
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    /// Serialize the public key.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![KEY_VERSION];
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(self.pk.as_ref());
+        bytes
+    }
+
+    /// Deserialize a public key.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WSError> {
+        if bytes.len() != 1 + 8 + 32 || bytes[0] != KEY_VERSION {
+            return Err(WSError::InvalidKey("public key".to_string()));
+        }
+        let pk = ed25519_compact::PublicKey::from_slice(&bytes[9..])
+            .map_err(|_| WSError::InvalidKey("public key".to_string()))?;
+        Ok(PublicKey {
+            key_id: bytes[1..9].try_into().unwrap(),
+            pk,
+            domain: None,
+        })
+    }
+
+    pub(crate) fn verify(
+        &self,
+        kind: SignatureKind,
+        message: &[u8],
+        signature: &Signature,
+    ) -> Result<(), WSError> {
+        if signature.kind != kind || signature.key_id != self.key_id {
+            return Err(WSError::VerificationFailed);
+        }
+        self.pk
+            .verify(
+                domain_message(self.domain(), message),
+                &ed25519_compact::Signature::new(signature.signature),
+            )
+            .map_err(|_| WSError::VerificationFailed)
+    }
+
+    /// Verify the signature of a module.
+    pub fn verify_module(&self, module_bin: &[u8], signature: &Signature) -> Result<(), WSError> {
+        self.verify(
+            SignatureKind::Module,
+            &module_message(module_bin),
+            signature,
+        )
+    }
+
+    /// Verify the signature of a module, given the SHA-256 digest of its content.
+    ///
+    /// This allows modules to be hashed as they are read, instead of being loaded into memory.
+    pub fn verify_module_digest(
+        &self,
+        digest: &[u8; 32],
+        signature: &Signature,
+    ) -> Result<(), WSError> {
+        self.verify(
+            SignatureKind::Module,
+            &module_digest_message(digest),
+            signature,
+        )
+    }
+
+    /// Verify the signature of a module, leaving out the ignored sections.
+    ///
+    /// The signature must have been created with the same list. Ignored sections are not authenticated.
+    pub fn verify_module_ignoring(
+        &self,
+        module_bin: &[u8],
+        ignored: &IgnoreList,
+        signature: &Signature,
+    ) -> Result<(), WSError> {
+        self.verify(
+            SignatureKind::Module,
+            &filtered_module_message(module_bin, ignored)?,
+            signature,
+        )
+    }
+
+    /// Verify the signature of a patch transforming `base_build_id` into `build_id`.
+    ///
+    /// This can be done before the patch is applied.
+    pub fn verify_patch(
+        &self,
+        base_build_id: &str,
+        build_id: &str,
+        patch: &[u8],
+        signature: &Signature,
+    ) -> Result<(), WSError> {
+        self.verify(
+            SignatureKind::Patch,
+            &patch_message(base_build_id, build_id, patch)?,
+            signature,
+        )
+    }
+}
+
+/// A secret key, used to create signatures.
+#[derive(Clone)]
+pub struct SecretKey {
+    key_id: KeyId,
+    sk: ed25519_compact::SecretKey,
+    domain: Option<String>,
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretKey({})", key_id_to_hex(&self.key_id))
+    }
+}
+
+impl SecretKey {
+    /// Return the identifier of the key.
+    pub fn key_id(&self) -> &KeyId {
+        &self.key_id
+    }
+
+    /// Bind the key to a signing domain, such as a tenant or a project.
+    ///
+    /// Its signatures are then only valid for public keys bound to the same domain.
+    pub fn in_domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// Return the signing domain the key is bound to, if any.
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    /// Return the public key corresponding to this secret key, bound to the same domain.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            key_id: self.key_id,
+            pk: self.sk.public_key(),
+            domain: self.domain.clone(),
+        }
+    }
+
+    /// Serialize the secret key.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![KEY_VERSION];
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(self.sk.as_ref());
+        bytes
+    }
+
+    /// Deserialize a secret key.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, WSError> {
+        if bytes.len() != 1 + 8 + 64 || bytes[0] != KEY_VERSION {
+            return Err(WSError::InvalidKey("secret key".to_string()));
+        }
+        let sk = ed25519_compact::SecretKey::from_slice(&bytes[9..])
+            .map_err(|_| WSError::InvalidKey("secret key".to_string()))?;
+        Ok(SecretKey {
+            key_id: bytes[1..9].try_into().unwrap(),
+            sk,
+            domain: None,
+        })
+    }
+
+    pub(crate) fn sign(&self, kind: SignatureKind, message: &[u8]) -> Signature {
+        let message = domain_message(self.domain(), message);
+        Signature {
+            kind,
+            key_id: self.key_id,
+            signature: *self.sk.sign(message, None),
+        }
+    }
+
+    /// Sign a module.
+    pub fn sign_module(&self, module_bin: &[u8]) -> Signature {
+        self.sign(SignatureKind::Module, &module_message(module_bin))
+    }
+
+    /// Sign a module, leaving out the ignored sections.
+    ///
+    /// With an empty list, this is equivalent to `sign_module`.
+    pub fn sign_module_ignoring(
+        &self,
+        module_bin: &[u8],
+        ignored: &IgnoreList,
+    ) -> Result<Signature, WSError> {
+        Ok(self.sign(
+            SignatureKind::Module,
+            &filtered_module_message(module_bin, ignored)?,
+        ))
+    }
+
+    /// Sign a patch transforming `base_build_id` into `build_id`.
+    ///
+    /// The signature covers the digests of both modules, so a patch can't be used with another base.
+    pub fn sign_patch(
+        &self,
+        base_build_id: &str,
+        build_id: &str,
+        patch: &[u8],
+    ) -> Result<Signature, WSError> {
+        Ok(self.sign(
+            SignatureKind::Patch,
+            &patch_message(base_build_id, build_id, patch)?,
+        ))
+    }
+}
+
+/// A key pair.
+#[derive(Debug, Clone)]
+pub struct KeyPair {
+    pub pk: PublicKey,
+    pub sk: SecretKey,
+}
+
+impl KeyPair {
+    /// Generate a new key pair.
+    ///
+    /// The key identifier is derived from the public key.
+    pub fn generate() -> Self {
+        let kp = ed25519_compact::KeyPair::generate();
+        let key_id = derive_key_id(&kp.pk);
+        KeyPair {
+            pk: PublicKey {
+                key_id,
+                pk: kp.pk,
+                domain: None,
+            },
+            sk: SecretKey {
+                key_id,
+                sk: kp.sk,
+                domain: None,
+            },
+        }
+    }
+
+    /// Write the secret key and the public key to new files.
+    ///
+    /// Existing files are never overwritten, and on Unix, the secret key is only readable by its owner.
+    pub fn save(
+        &self,
+        sk_file: impl AsRef<Path>,
+        pk_file: impl AsRef<Path>,
+    ) -> Result<(), WSError> {
+        let sk_file = sk_file.as_ref();
+        let mut pk_options = fs::OpenOptions::new();
+        pk_options.write(true).create_new(true);
+        let mut sk_options = pk_options.clone();
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut sk_options, 0o600);
+        let mut sk_writer = sk_options.open(sk_file)?;
+        let mut pk_writer = match pk_options.open(pk_file.as_ref()) {
+            Ok(pk_writer) => pk_writer,
+            Err(e) => {
+                drop(sk_writer);
+                let _ = fs::remove_file(sk_file);
+                return Err(e.into());
+            }
+        };
+        sk_writer.write_all(&self.sk.to_bytes())?;
+        pk_writer.write_all(&self.pk.to_bytes())?;
+        Ok(())
+    }
+}
+
+/// A set of trusted public keys.
+///
+/// Signatures from revoked keys are rejected once the revocation is effective.
+#[derive(Debug, Clone, Default)]
+pub struct KeyRing {
+    keys: Vec<PublicKey>,
+    revoked: Vec<(KeyId, u64)>,
+    domain: Option<String>,
+    time: Option<SystemTime>,
+}
+
+impl KeyRing {
+    /// Create an empty key ring.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a trusted key.
+    pub fn add(&mut self, pk: PublicKey) {
+        if self.get(pk.key_id()).is_none() {
+            self.keys.push(pk);
+        }
+    }
+
+    /// Return the key with the given identifier.
+    pub fn get(&self, key_id: &KeyId) -> Option<&PublicKey> {
+        self.keys.iter().find(|pk| pk.key_id() == key_id)
+    }
+
+    /// Return `true` if the key ring doesn't contain any keys.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Return the trusted keys.
+    pub fn keys(&self) -> &[PublicKey] {
+        &self.keys
+    }
+
+    /// Only accept signatures from keys bound to a signing domain.
+    ///
+    /// This allows a ring shared by several tenants or projects to verify the artifacts of one of
+    /// them, without accepting signatures made with the keys of the others.
+    pub fn set_domain(&mut self, domain: impl Into<String>) {
+        self.domain = Some(domain.into());
+    }
+
+    /// Return the signing domain signatures are accepted for, if the ring is restricted to one.
+    pub fn domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    /// Check revocations at the given time, instead of the current time of the system.
+    ///
+    /// Agents use their own clock, which may not be the one of the system on devices without a
+    /// real-time clock.
+    pub fn set_time(&mut self, time: SystemTime) {
+        self.time = Some(time);
+    }
+
+    /// Reject signatures from a key, starting at `effective_at` (in seconds since the UNIX epoch).
+    pub fn revoke(&mut self, key_id: KeyId, effective_at: u64) {
+        match self.revoked.iter_mut().find(|(id, _)| *id == key_id) {
+            Some((_, at)) => *at = (*at).min(effective_at),
+            None => self.revoked.push((key_id, effective_at)),
+        }
+    }
+
+    /// Return `true` if a key is revoked at the given time.
+    pub fn is_revoked(&self, key_id: &KeyId, at: u64) -> bool {
+        self.revoked
+            .iter()
+            .any(|(id, effective_at)| id == key_id && *effective_at <= at)
+    }
+
+    fn key_for(&self, key_id: &KeyId) -> Result<&PublicKey, WSError> {
+        let now = self
+            .time
+            .unwrap_or_else(SystemTime::now)
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        if self.is_revoked(key_id, now) {
+            return Err(WSError::KeyRevoked(key_id_to_hex(key_id)));
+        }
+        let pk = self
+            .get(key_id)
+            .ok_or_else(|| WSError::UnknownKey(key_id_to_hex(key_id)))?;
+        if let Some(domain) = self.domain() {
+            if pk.domain() != Some(domain) {
+                return Err(WSError::DomainMismatch(format!(
+                    "key {} is not bound to domain [{}]",
+                    key_id_to_hex(key_id),
+                    domain
+                )));
+            }
+        }
+        Ok(pk)
+    }
+
+    pub(crate) fn verify(
+        &self,
+        kind: SignatureKind,
+        message: &[u8],
+        signature: &Signature,
+    ) -> Result<(), WSError> {
+        self.key_for(&signature.key_id)?
+            .verify(kind, message, signature)
+    }
+
+    /// Verify the signature of a module using the key that created it.
+    pub fn verify_module(&self, module_bin: &[u8], signature: &Signature) -> Result<(), WSError> {
+        self.key_for(&signature.key_id)?
+            .verify_module(module_bin, signature)
+    }
+
+    /// Verify the signature of a module using the key that created it, given the SHA-256 digest of its content.
+    pub fn verify_module_digest(
+        &self,
+        digest: &[u8; 32],
+        signature: &Signature,
+    ) -> Result<(), WSError> {
+        self.key_for(&signature.key_id)?
+            .verify_module_digest(digest, signature)
+    }
+
+    /// Verify the signature of a module using the key that created it, leaving out the ignored sections.
+    pub fn verify_module_ignoring(
+        &self,
+        module_bin: &[u8],
+        ignored: &IgnoreList,
+        signature: &Signature,
+    ) -> Result<(), WSError> {
+        self.key_for(&signature.key_id)?
+            .verify_module_ignoring(module_bin, ignored, signature)
+    }
+
+    /// Verify the signature of a patch using the key that created it.
+    pub fn verify_patch(
+        &self,
+        base_build_id: &str,
+        build_id: &str,
+        patch: &[u8],
+        signature: &Signature,
+    ) -> Result<(), WSError> {
+        self.key_for(&signature.key_id)?
+            .verify_patch(base_build_id, build_id, patch, signature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const MODULE: &[u8] = b"\0asm\x01\0\0\0";
+
+    #[test]
+    fn revocations_use_the_time_of_the_key_ring() {
+        let kp = KeyPair::generate();
+        let signature = kp.sk.sign_module(MODULE);
+        let mut key_ring = KeyRing::new();
+        key_ring.add(kp.pk.clone());
+        key_ring.revoke(*kp.pk.key_id(), 1_000);
+
+        key_ring.set_time(UNIX_EPOCH + Duration::from_secs(999));
+        key_ring.verify_module(MODULE, &signature).unwrap();
+
+        key_ring.set_time(UNIX_EPOCH + Duration::from_secs(1_000));
+        assert!(matches!(
+            key_ring.verify_module(MODULE, &signature),
+            Err(WSError::KeyRevoked(_))
+        ));
+    }
+}
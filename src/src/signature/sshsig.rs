@@ -0,0 +1,281 @@
+use super::*;
+
+const SSHSIG_MAGIC: &[u8; 6] = b"SSHSIG";
+const SSHSIG_VERSION: u32 = 1;
+const SSH_ED25519: &str = "ssh-ed25519";
+const ARMOR_BEGIN: &str = "-----BEGIN SSH SIGNATURE-----";
+const ARMOR_END: &str = "-----END SSH SIGNATURE-----";
+
+fn put_string(out: &mut Vec<u8>, s: impl AsRef<[u8]>) {
+    let s = s.as_ref();
+    out.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    out.extend_from_slice(s);
+}
+
+fn get_string<'t>(reader: &mut &'t [u8]) -> Result<&'t [u8], WSError> {
+    if reader.len() < 4 {
+        return Err(WSError::ParseError);
+    }
+    let len = u32::from_be_bytes(reader[..4].try_into().unwrap()) as usize;
+    if reader.len() - 4 < len {
+        return Err(WSError::ParseError);
+    }
+    let s = &reader[4..4 + len];
+    *reader = &reader[4 + len..];
+    Ok(s)
+}
+
+fn ssh_public_key_blob(pk: &ed25519_compact::PublicKey) -> Vec<u8> {
+    let mut blob = vec![];
+    put_string(&mut blob, SSH_ED25519);
+    put_string(&mut blob, pk.as_ref());
+    blob
+}
+
+fn parse_ssh_public_key_blob(mut blob: &[u8]) -> Result<ed25519_compact::PublicKey, WSError> {
+    let invalid = || WSError::InvalidKey("OpenSSH public key".to_string());
+    if get_string(&mut blob)? != SSH_ED25519.as_bytes() {
+        return Err(invalid());
+    }
+    let pk =
+        ed25519_compact::PublicKey::from_slice(get_string(&mut blob)?).map_err(|_| invalid())?;
+    if !blob.is_empty() {
+        return Err(invalid());
+    }
+    Ok(pk)
+}
+
+fn signed_data(namespace: &str, hash_algorithm: &str, data: &[u8]) -> Result<Vec<u8>, WSError> {
+    let digest = match hash_algorithm {
+        "sha256" => Hash::hash(data).to_vec(),
+        "sha512" => ed25519_compact::sha512::Hash::hash(data).to_vec(),
+        _ => return Err(WSError::ParseError),
+    };
+    let mut message = SSHSIG_MAGIC.to_vec();
+    put_string(&mut message, namespace);
+    put_string(&mut message, "");
+    put_string(&mut message, hash_algorithm);
+    put_string(&mut message, digest);
+    Ok(message)
+}
+
+/// The content of an armored `sshsig` signature.
+struct SshSignature {
+    pk: ed25519_compact::PublicKey,
+    namespace: Vec<u8>,
+    hash_algorithm: String,
+    signature: ed25519_compact::Signature,
+}
+
+impl SshSignature {
+    fn parse(signature: &str) -> Result<Self, WSError> {
+        let armored = signature.trim();
+        let body = armored
+            .strip_prefix(ARMOR_BEGIN)
+            .and_then(|s| s.strip_suffix(ARMOR_END))
+            .ok_or(WSError::ParseError)?;
+        let body: String = body.split_whitespace().collect();
+        let bin = Base64::decode_to_vec(body, None).map_err(|_| WSError::ParseError)?;
+        let mut reader = bin
+            .strip_prefix(SSHSIG_MAGIC.as_slice())
+            .ok_or(WSError::ParseError)?;
+        if reader.len() < 4 || u32::from_be_bytes(reader[..4].try_into().unwrap()) != SSHSIG_VERSION
+        {
+            return Err(WSError::ParseError);
+        }
+        reader = &reader[4..];
+        let pk = parse_ssh_public_key_blob(get_string(&mut reader)?)?;
+        let namespace = get_string(&mut reader)?.to_vec();
+        get_string(&mut reader)?;
+        let hash_algorithm = str::from_utf8(get_string(&mut reader)?)?.to_string();
+        let mut signature_blob = get_string(&mut reader)?;
+        if get_string(&mut signature_blob)? != SSH_ED25519.as_bytes() {
+            return Err(WSError::ParseError);
+        }
+        let signature = ed25519_compact::Signature::from_slice(get_string(&mut signature_blob)?)
+            .map_err(|_| WSError::ParseError)?;
+        Ok(SshSignature {
+            pk,
+            namespace,
+            hash_algorithm,
+            signature,
+        })
+    }
+}
+
+impl PublicKey {
+    /// Return the public key in OpenSSH `authorized_keys` format.
+    pub fn to_openssh(&self, comment: &str) -> String {
+        let blob = Base64::encode_to_string(ssh_public_key_blob(&self.pk)).unwrap();
+        format!("{} {} {}", SSH_ED25519, blob, comment)
+            .trim_end()
+            .to_string()
+    }
+
+    /// Read an Ed25519 public key in OpenSSH `authorized_keys` format.
+    ///
+    /// The key identifier is derived from the public key.
+    pub fn from_openssh(s: &str) -> Result<Self, WSError> {
+        let mut parts = s.split_whitespace();
+        if parts.next() != Some(SSH_ED25519) {
+            return Err(WSError::InvalidKey("OpenSSH public key".to_string()));
+        }
+        let blob = Base64::decode_to_vec(parts.next().unwrap_or_default(), None)
+            .map_err(|_| WSError::InvalidKey("OpenSSH public key".to_string()))?;
+        let pk = parse_ssh_public_key_blob(&blob)?;
+        Ok(PublicKey {
+            key_id: derive_key_id(&pk),
+            pk,
+            domain: None,
+        })
+    }
+
+    /// Verify an armored `sshsig` signature of `data`, as created by `ssh-keygen -Y sign`.
+    ///
+    /// Use `KeyRing::verify_sshsig()` to honor revocations and signing domains.
+    pub fn verify_sshsig(
+        &self,
+        data: &[u8],
+        namespace: &str,
+        signature: &str,
+    ) -> Result<(), WSError> {
+        let signature = SshSignature::parse(signature)?;
+        if signature.pk != self.pk {
+            return Err(WSError::UnknownKey(key_id_to_hex(&derive_key_id(
+                &signature.pk,
+            ))));
+        }
+        self.verify_parsed_sshsig(data, namespace, &signature)
+    }
+
+    fn verify_parsed_sshsig(
+        &self,
+        data: &[u8],
+        namespace: &str,
+        signature: &SshSignature,
+    ) -> Result<(), WSError> {
+        if signature.namespace != namespace.as_bytes() {
+            return Err(WSError::VerificationFailed);
+        }
+        let data = domain_message(self.domain(), data);
+        self.pk
+            .verify(
+                signed_data(namespace, &signature.hash_algorithm, &data)?,
+                &signature.signature,
+            )
+            .map_err(|_| WSError::VerificationFailed)
+    }
+}
+
+impl KeyRing {
+    /// Verify an armored `sshsig` signature of `data` using the trusted key that created it.
+    pub fn verify_sshsig(
+        &self,
+        data: &[u8],
+        namespace: &str,
+        signature: &str,
+    ) -> Result<(), WSError> {
+        let signature = SshSignature::parse(signature)?;
+        let key_id = self
+            .keys()
+            .iter()
+            .find(|pk| pk.pk == signature.pk)
+            .map(|pk| *pk.key_id())
+            .unwrap_or_else(|| derive_key_id(&signature.pk));
+        self.key_for(&key_id)?
+            .verify_parsed_sshsig(data, namespace, &signature)
+    }
+}
+
+impl SecretKey {
+    /// Create an armored `sshsig` signature of `data`, that can be checked with `ssh-keygen -Y verify`.
+    pub fn sign_sshsig(&self, data: &[u8], namespace: &str) -> Result<String, WSError> {
+        let hash_algorithm = "sha512";
+        let data = domain_message(self.domain(), data);
+        let signature = self
+            .sk
+            .sign(signed_data(namespace, hash_algorithm, &data)?, None);
+        let mut signature_blob = vec![];
+        put_string(&mut signature_blob, SSH_ED25519);
+        put_string(&mut signature_blob, signature.as_ref());
+        let mut bin = SSHSIG_MAGIC.to_vec();
+        bin.extend_from_slice(&SSHSIG_VERSION.to_be_bytes());
+        put_string(&mut bin, ssh_public_key_blob(&self.sk.public_key()));
+        put_string(&mut bin, namespace);
+        put_string(&mut bin, "");
+        put_string(&mut bin, hash_algorithm);
+        put_string(&mut bin, signature_blob);
+        let encoded = Base64::encode_to_string(bin).unwrap();
+        let mut armored = format!("{}\n", ARMOR_BEGIN);
+        for line in encoded.as_bytes().chunks(70) {
+            armored.push_str(str::from_utf8(line)?);
+            armored.push('\n');
+        }
+        armored.push_str(ARMOR_END);
+        armored.push('\n');
+        Ok(armored)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"module";
+    const NAMESPACE: &str = "file";
+
+    #[test]
+    fn key_ring_verifies_sshsig_signatures() {
+        let kp = KeyPair::generate();
+        let signature = kp.sk.sign_sshsig(DATA, NAMESPACE).unwrap();
+        let mut key_ring = KeyRing::new();
+        key_ring.add(kp.pk.clone());
+        key_ring.verify_sshsig(DATA, NAMESPACE, &signature).unwrap();
+        assert!(matches!(
+            key_ring.verify_sshsig(DATA, "git", &signature),
+            Err(WSError::VerificationFailed)
+        ));
+        assert!(matches!(
+            KeyRing::new().verify_sshsig(DATA, NAMESPACE, &signature),
+            Err(WSError::UnknownKey(_))
+        ));
+    }
+
+    #[test]
+    fn key_ring_rejects_revoked_sshsig_keys() {
+        let kp = KeyPair::generate();
+        let signature = kp.sk.sign_sshsig(DATA, NAMESPACE).unwrap();
+        let mut key_ring = KeyRing::new();
+        key_ring.add(kp.pk.clone());
+        key_ring.revoke(*kp.pk.key_id(), 0);
+        assert!(matches!(
+            key_ring.verify_sshsig(DATA, NAMESPACE, &signature),
+            Err(WSError::KeyRevoked(_))
+        ));
+    }
+
+    #[test]
+    fn sshsig_signatures_are_bound_to_their_domain() {
+        let kp = KeyPair::generate();
+        let signature = kp
+            .sk
+            .clone()
+            .in_domain("billing")
+            .sign_sshsig(DATA, NAMESPACE)
+            .unwrap();
+
+        let mut key_ring = KeyRing::new();
+        key_ring.add(kp.pk.clone().in_domain("billing"));
+        key_ring.set_domain("billing");
+        key_ring.verify_sshsig(DATA, NAMESPACE, &signature).unwrap();
+
+        assert!(kp.pk.verify_sshsig(DATA, NAMESPACE, &signature).is_err());
+        let mut unbound_ring = KeyRing::new();
+        unbound_ring.add(kp.pk.clone());
+        unbound_ring.set_domain("billing");
+        assert!(matches!(
+            unbound_ring.verify_sshsig(DATA, NAMESPACE, &signature),
+            Err(WSError::DomainMismatch(_))
+        ));
+    }
+}
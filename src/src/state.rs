@@ -0,0 +1,74 @@
+//! State formats.
+//!
+//! Stateful modules can declare the version of the format of their state in a custom section.
+//! When such a module is updated, the agent asks the runtime for a snapshot of its state before
+//! switching slots, transforms it if the format of the new module is different, and has it restored
+//! into the new module.
+
+use crate::error::*;
+use crate::wasm_module::*;
+
+use std::io::{self, prelude::*};
+
+/// Name of the custom section storing the version of the state format.
+pub const STATE_FORMAT_SECTION_NAME: &str = "state-format";
+
+const STATE_FORMAT_VERSION: u8 = 0x01;
+
+/// Serialized state of a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateSnapshot {
+    /// Version of the state format.
+    pub format: u32,
+    pub data: Vec<u8>,
+}
+
+fn serialize_state_format(format: u32) -> Result<Vec<u8>, WSError> {
+    let mut writer = io::Cursor::new(vec![]);
+    writer.write_all(&[STATE_FORMAT_VERSION])?;
+    varint::put(&mut writer, format as _)?;
+    Ok(writer.into_inner())
+}
+
+fn deserialize_state_format(payload: &[u8]) -> Result<u32, WSError> {
+    TakeExact::decode(payload, payload.len() as _, |reader| {
+        let mut version = [0u8; 1];
+        reader.read_exact(&mut version)?;
+        if version[0] != STATE_FORMAT_VERSION {
+            return Err(WSError::ParseError);
+        }
+        varint::get32(reader)
+    })
+}
+
+impl Module {
+    /// Return the version of the state format of the module, if it declares one.
+    pub fn state_format(&self) -> Result<Option<u32>, WSError> {
+        for section in &self.sections {
+            if let Section::Custom(custom) = section {
+                if custom.name() == STATE_FORMAT_SECTION_NAME {
+                    return Ok(Some(deserialize_state_format(custom.payload())?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Declare the version of the state format of the module, replacing the existing one.
+    pub fn set_state_format(&mut self, format: u32) -> Result<(), WSError> {
+        let payload = serialize_state_format(format)?;
+        for section in self.sections.iter_mut() {
+            if let Section::Custom(custom) = section {
+                if custom.name() == STATE_FORMAT_SECTION_NAME {
+                    custom.set_payload(payload);
+                    return Ok(());
+                }
+            }
+        }
+        self.sections.push(Section::Custom(CustomSection::new(
+            STATE_FORMAT_SECTION_NAME.to_string(),
+            payload,
+        )));
+        Ok(())
+    }
+}
@@ -0,0 +1,325 @@
+use crate::cbor::Value;
+use crate::error::*;
+use crate::protocol::*;
+use crate::signature::{KeyRing, SecretKey};
+
+use ct_codecs::{Decoder, Encoder, Hex};
+use hmac_sha256::Hash;
+use semver::Version;
+
+/// Media type of SUIT envelopes.
+pub const SUIT_CONTENT_TYPE: &str = "application/suit-envelope+cose";
+
+/// Component identifier used for the module, unless another one is set.
+pub const DEFAULT_COMPONENT_ID: &[u8] = b"wasm-module";
+
+const ENVELOPE_TAG: u64 = 107;
+const MANIFEST_VERSION: u64 = 1;
+const TEXT_LANGUAGE: &str = "en";
+const REPORT_ALL: u64 = 15;
+const ALG_SHA256: i64 = -16;
+
+const ENVELOPE_AUTHENTICATION: u64 = 2;
+const ENVELOPE_MANIFEST: u64 = 3;
+
+const MANIFEST_VERSION_KEY: u64 = 1;
+const MANIFEST_SEQUENCE_NUMBER: u64 = 2;
+const MANIFEST_COMMON: u64 = 3;
+const MANIFEST_VALIDATE: u64 = 7;
+const MANIFEST_PAYLOAD_FETCH: u64 = 16;
+const MANIFEST_INSTALL: u64 = 20;
+const MANIFEST_TEXT: u64 = 23;
+
+const COMMON_COMPONENTS: u64 = 2;
+const COMMON_SHARED_SEQUENCE: u64 = 4;
+
+const CONDITION_IMAGE_MATCH: u64 = 3;
+const DIRECTIVE_SET_PARAMETERS: u64 = 19;
+const DIRECTIVE_OVERRIDE_PARAMETERS: u64 = 20;
+const DIRECTIVE_FETCH: u64 = 21;
+
+const PARAMETER_IMAGE_DIGEST: u64 = 3;
+const PARAMETER_IMAGE_SIZE: u64 = 14;
+const PARAMETER_URI: u64 = 21;
+
+const TEXT_COMPONENT_VERSION: u64 = 6;
+
+fn uint(v: u64) -> Value {
+    Value::Unsigned(v)
+}
+
+fn wrap(value: Value) -> Value {
+    Value::Bytes(value.encode())
+}
+
+fn unwrap(value: Option<&Value>) -> Result<Value, WSError> {
+    Value::decode(value.and_then(Value::as_bytes).ok_or(WSError::ParseError)?)
+}
+
+fn suit_digest(digest: &[u8]) -> Value {
+    Value::Array(vec![Value::from(ALG_SHA256), Value::Bytes(digest.to_vec())])
+}
+
+fn parse_suit_digest(value: &Value) -> Result<Vec<u8>, WSError> {
+    match value.as_array() {
+        Some([alg, digest]) if alg.as_i64() == Some(ALG_SHA256) => {
+            Ok(digest.as_bytes().ok_or(WSError::ParseError)?.to_vec())
+        }
+        _ => Err(WSError::ParseError),
+    }
+}
+
+/// Values of the parameters set by command sequences.
+#[derive(Default)]
+struct Parameters {
+    digest: Option<Vec<u8>>,
+    size: Option<u64>,
+    uri: Option<String>,
+}
+
+impl Parameters {
+    fn apply(&mut self, parameters: &Value, overwrite: bool) -> Result<(), WSError> {
+        let entries = match parameters {
+            Value::Map(entries) => entries,
+            _ => return Err(WSError::ParseError),
+        };
+        for (key, value) in entries {
+            match key {
+                Value::Unsigned(PARAMETER_IMAGE_DIGEST) if overwrite || self.digest.is_none() => {
+                    self.digest = Some(parse_suit_digest(&unwrap(Some(value))?)?);
+                }
+                Value::Unsigned(PARAMETER_IMAGE_SIZE) if overwrite || self.size.is_none() => {
+                    match value {
+                        Value::Unsigned(size) => self.size = Some(*size),
+                        _ => return Err(WSError::ParseError),
+                    }
+                }
+                Value::Unsigned(PARAMETER_URI) if overwrite || self.uri.is_none() => {
+                    self.uri = Some(value.as_text().ok_or(WSError::ParseError)?.to_string());
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect the parameters of a command sequence, ignoring conditions and other directives.
+    fn collect(&mut self, sequence: &Value) -> Result<(), WSError> {
+        let commands = sequence.as_array().ok_or(WSError::ParseError)?;
+        if commands.len() % 2 != 0 {
+            return Err(WSError::ParseError);
+        }
+        for command in commands.chunks(2) {
+            match command[0] {
+                Value::Unsigned(DIRECTIVE_OVERRIDE_PARAMETERS) => self.apply(&command[1], true)?,
+                Value::Unsigned(DIRECTIVE_SET_PARAMETERS) => self.apply(&command[1], false)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A SUIT manifest updating a single component with a module.
+///
+/// Only a subset of SUIT is supported: the manifest describes the digest and size of the module,
+/// a shared sequence setting them, a validation condition, and an install sequence fetching the
+/// module from its URL. The version is stored as the component version in the text section.
+///
+/// Patch plans and module signatures have no SUIT equivalent, and are not preserved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuitManifest {
+    /// Monotonic sequence number of the manifest.
+    pub sequence_number: u64,
+    /// Identifier of the updated component.
+    pub component_id: Vec<Vec<u8>>,
+    /// The update described by the manifest.
+    pub update: UpdateDescriptor,
+}
+
+impl SuitManifest {
+    /// Create a manifest for an update, using the default component identifier.
+    pub fn new(update: UpdateDescriptor, sequence_number: u64) -> Self {
+        SuitManifest {
+            sequence_number,
+            component_id: vec![DEFAULT_COMPONENT_ID.to_vec()],
+            update,
+        }
+    }
+
+    fn component_id_value(&self) -> Value {
+        Value::Array(
+            self.component_id
+                .iter()
+                .map(|id| Value::Bytes(id.clone()))
+                .collect(),
+        )
+    }
+
+    fn manifest(&self) -> Result<Value, WSError> {
+        let digest =
+            Hex::decode_to_vec(&self.update.full.sha256, None).map_err(|_| WSError::ParseError)?;
+        let shared_sequence = Value::Array(vec![
+            uint(DIRECTIVE_OVERRIDE_PARAMETERS),
+            Value::Map(vec![
+                (uint(PARAMETER_IMAGE_DIGEST), wrap(suit_digest(&digest))),
+                (uint(PARAMETER_IMAGE_SIZE), uint(self.update.full.size)),
+            ]),
+        ]);
+        let common = Value::Map(vec![
+            (
+                uint(COMMON_COMPONENTS),
+                Value::Array(vec![self.component_id_value()]),
+            ),
+            (uint(COMMON_SHARED_SEQUENCE), wrap(shared_sequence)),
+        ]);
+        let validate = Value::Array(vec![uint(CONDITION_IMAGE_MATCH), uint(REPORT_ALL)]);
+        let install = Value::Array(vec![
+            uint(DIRECTIVE_OVERRIDE_PARAMETERS),
+            Value::Map(vec![(
+                uint(PARAMETER_URI),
+                Value::Text(self.update.full.url.clone()),
+            )]),
+            uint(DIRECTIVE_FETCH),
+            uint(REPORT_ALL),
+            uint(CONDITION_IMAGE_MATCH),
+            uint(REPORT_ALL),
+        ]);
+        let text = Value::Map(vec![(
+            Value::Text(TEXT_LANGUAGE.to_string()),
+            Value::Map(vec![(
+                self.component_id_value(),
+                Value::Map(vec![(
+                    uint(TEXT_COMPONENT_VERSION),
+                    Value::Text(self.update.version.to_string()),
+                )]),
+            )]),
+        )]);
+        Ok(Value::Map(vec![
+            (uint(MANIFEST_VERSION_KEY), uint(MANIFEST_VERSION)),
+            (uint(MANIFEST_SEQUENCE_NUMBER), uint(self.sequence_number)),
+            (uint(MANIFEST_COMMON), wrap(common)),
+            (uint(MANIFEST_VALIDATE), wrap(validate)),
+            (uint(MANIFEST_INSTALL), wrap(install)),
+            (uint(MANIFEST_TEXT), wrap(text)),
+        ]))
+    }
+
+    /// Encode the manifest in a tagged SUIT envelope.
+    ///
+    /// The authentication wrapper always includes the digest of the manifest, and a signature if a key is given.
+    pub fn to_envelope(&self, sk: Option<&SecretKey>) -> Result<Vec<u8>, WSError> {
+        let manifest = wrap(self.manifest()?);
+        let digest = suit_digest(&Hash::hash(&manifest.encode())).encode();
+        let mut authentication = vec![Value::Bytes(digest.clone())];
+        if let Some(sk) = sk {
+            authentication.push(Value::Bytes(sk.sign_cose_detached(&digest)));
+        }
+        let envelope = Value::Map(vec![
+            (
+                uint(ENVELOPE_AUTHENTICATION),
+                wrap(Value::Array(authentication)),
+            ),
+            (uint(ENVELOPE_MANIFEST), manifest),
+        ]);
+        Ok(Value::Tag(ENVELOPE_TAG, Box::new(envelope)).encode())
+    }
+
+    /// Decode a SUIT envelope, tagged or not.
+    ///
+    /// The digest of the manifest is always verified. If `trusted_keys` is not empty,
+    /// the envelope must also include a signature from one of these keys.
+    pub fn from_envelope(envelope: &[u8], trusted_keys: &KeyRing) -> Result<Self, WSError> {
+        let envelope = match Value::decode(envelope)? {
+            Value::Tag(ENVELOPE_TAG, envelope) => *envelope,
+            Value::Tag(..) => return Err(WSError::ParseError),
+            envelope => envelope,
+        };
+        let manifest_bstr = envelope
+            .get(&uint(ENVELOPE_MANIFEST))
+            .ok_or(WSError::ParseError)?;
+        let authentication = unwrap(envelope.get(&uint(ENVELOPE_AUTHENTICATION)))?;
+        let authentication = authentication.as_array().ok_or(WSError::ParseError)?;
+        let (digest, blocks) = authentication.split_first().ok_or(WSError::ParseError)?;
+        let digest = digest.as_bytes().ok_or(WSError::ParseError)?;
+        if parse_suit_digest(&Value::decode(digest)?)? != Hash::hash(&manifest_bstr.encode()) {
+            return Err(WSError::DigestMismatch);
+        }
+        if !trusted_keys.is_empty() {
+            let mut result = Err(WSError::NoSignature);
+            for block in blocks {
+                let block = block.as_bytes().ok_or(WSError::ParseError)?;
+                result = trusted_keys.verify_cose_detached(block, digest);
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result?;
+        }
+
+        let manifest = unwrap(Some(manifest_bstr))?;
+        if manifest.get(&uint(MANIFEST_VERSION_KEY)) != Some(&uint(MANIFEST_VERSION)) {
+            return Err(WSError::ParseError);
+        }
+        let sequence_number = match manifest.get(&uint(MANIFEST_SEQUENCE_NUMBER)) {
+            Some(Value::Unsigned(v)) => *v,
+            _ => return Err(WSError::ParseError),
+        };
+        let common = unwrap(manifest.get(&uint(MANIFEST_COMMON)))?;
+        let component_id_value = match common
+            .get(&uint(COMMON_COMPONENTS))
+            .and_then(Value::as_array)
+        {
+            Some([component_id]) => component_id.clone(),
+            _ => return Err(WSError::ParseError),
+        };
+        let component_id = component_id_value
+            .as_array()
+            .ok_or(WSError::ParseError)?
+            .iter()
+            .map(|id| id.as_bytes().map(<[u8]>::to_vec))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(WSError::ParseError)?;
+
+        let mut parameters = Parameters::default();
+        if let Some(sequence) = common.get(&uint(COMMON_SHARED_SEQUENCE)) {
+            parameters.collect(&unwrap(Some(sequence))?)?;
+        }
+        for key in [MANIFEST_PAYLOAD_FETCH, MANIFEST_INSTALL] {
+            if let Some(sequence) = manifest.get(&uint(key)) {
+                parameters.collect(&unwrap(Some(sequence))?)?;
+            }
+        }
+        let (digest, size, url) = match parameters {
+            Parameters {
+                digest: Some(digest),
+                size: Some(size),
+                uri: Some(uri),
+            } => (digest, size, uri),
+            _ => return Err(WSError::ParseError),
+        };
+
+        let text = unwrap(manifest.get(&uint(MANIFEST_TEXT)))?;
+        let version = text
+            .get(&Value::Text(TEXT_LANGUAGE.to_string()))
+            .and_then(|text| text.get(&component_id_value))
+            .and_then(|text| text.get(&uint(TEXT_COMPONENT_VERSION)))
+            .and_then(Value::as_text)
+            .ok_or(WSError::ParseError)?;
+        let version = Version::parse(version).map_err(|_| WSError::ParseError)?;
+
+        let sha256 = Hex::encode_to_string(digest).map_err(|_| WSError::ParseError)?;
+        Ok(SuitManifest {
+            sequence_number,
+            component_id,
+            update: UpdateDescriptor {
+                version,
+                build_id: sha256.clone(),
+                full: Download { url, size, sha256 },
+                plan: UpdatePlan::Full,
+                signature: None,
+                encoded: None,
+            },
+        })
+    }
+}
@@ -0,0 +1,135 @@
+use crate::error::*;
+use crate::wasm_module::*;
+
+use log::*;
+use std::collections::HashMap;
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Values used to replace `${NAME}` placeholders in custom sections.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateVars {
+    vars: HashMap<String, String>,
+}
+
+impl TemplateVars {
+    /// Create an empty set of variables.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a set of variables including the built-in ones.
+    ///
+    /// `BUILD_TIME` is set to the current UTC time in RFC 3339 format,
+    /// or to `SOURCE_DATE_EPOCH` if that environment variable is defined.
+    pub fn with_builtins() -> Self {
+        let timestamp = source_date_epoch().unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+        let mut vars = Self::new();
+        vars.set("BUILD_TIME", rfc3339(timestamp));
+        vars
+    }
+
+    /// Set the value of a variable.
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.vars.insert(name.into(), value.into());
+        self
+    }
+
+    /// Return the value of a variable.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|s| s.as_str())
+    }
+
+    /// Replace all placeholders in the given bytes.
+    ///
+    /// A placeholder is `${NAME}`, with `NAME` only made of ASCII alphanumeric characters
+    /// and underscores. Anything else is copied verbatim, so binary payloads are left intact.
+    pub fn expand(&self, input: &[u8]) -> Result<Vec<u8>, WSError> {
+        let mut output = Vec::with_capacity(input.len());
+        let mut i = 0;
+        while i < input.len() {
+            if input[i] == b'$' && input.get(i + 1) == Some(&b'{') {
+                let start = i + 2;
+                let len = input[start..]
+                    .iter()
+                    .take_while(|&&c| c.is_ascii_alphanumeric() || c == b'_')
+                    .count();
+                if len > 0 && input.get(start + len) == Some(&b'}') {
+                    let name = str::from_utf8(&input[start..start + len])?;
+                    let value = self
+                        .get(name)
+                        .ok_or_else(|| WSError::UndefinedVariable(name.to_string()))?;
+                    output.extend_from_slice(value.as_bytes());
+                    i = start + len + 1;
+                    continue;
+                }
+            }
+            output.push(input[i]);
+            i += 1;
+        }
+        Ok(output)
+    }
+}
+
+/// Expand placeholders in the custom sections whose name is in `section_names`.
+///
+/// Return the number of sections that have been modified.
+pub fn substitute(
+    module: &mut Module,
+    section_names: &[impl AsRef<str>],
+    vars: &TemplateVars,
+) -> Result<usize, WSError> {
+    let mut count = 0;
+    for section in module.sections.iter_mut() {
+        let section = match section {
+            Section::Custom(section) => section,
+            _ => continue,
+        };
+        if !section_names.iter().any(|n| n.as_ref() == section.name()) {
+            continue;
+        }
+        let payload = vars.expand(section.payload())?;
+        if payload != section.payload() {
+            debug!("Substituted placeholders in [{}]", section.name());
+            section.set_payload(payload);
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Return the value of the `SOURCE_DATE_EPOCH` environment variable, if it is defined and valid.
+pub(crate) fn source_date_epoch() -> Option<u64> {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+}
+
+pub(crate) fn rfc3339(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
@@ -0,0 +1,158 @@
+use crate::agent::Transport;
+use crate::error::*;
+use crate::wasm_module::*;
+
+use hmac_sha256::Hash;
+use log::*;
+use std::io::{self, prelude::*};
+use std::str;
+
+/// Name of the custom section replacing a section stored outside the module.
+pub const EXTERNAL_SECTION_NAME: &str = "wasi-update.external";
+
+const EXTERNAL_SECTION_VERSION: u8 = 0x01;
+
+/// A reference to a section stored outside the module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SectionReference {
+    /// SHA-256 digest of the serialized section.
+    pub digest: [u8; 32],
+    /// Size of the serialized section, in bytes.
+    pub size: u64,
+    /// Location of the serialized section.
+    pub url: String,
+}
+
+impl SectionReference {
+    /// Return the custom section storing the reference.
+    pub fn to_section(&self) -> Result<Section, WSError> {
+        let mut writer = io::Cursor::new(vec![]);
+        writer.write_all(&[EXTERNAL_SECTION_VERSION])?;
+        writer.write_all(&self.digest)?;
+        varint::put(&mut writer, self.size)?;
+        varint::put_slice(&mut writer, self.url.as_bytes())?;
+        Ok(Section::Custom(CustomSection::new(
+            EXTERNAL_SECTION_NAME.to_string(),
+            writer.into_inner(),
+        )))
+    }
+
+    /// Decode a reference, if `section` is one.
+    pub fn from_section(section: &Section) -> Result<Option<Self>, WSError> {
+        let payload = match section {
+            Section::Custom(s) if s.name() == EXTERNAL_SECTION_NAME => s.payload(),
+            _ => return Ok(None),
+        };
+        let reference = TakeExact::decode(payload, payload.len() as _, |reader| {
+            let mut version = [0u8; 1];
+            reader.read_exact(&mut version)?;
+            if version[0] != EXTERNAL_SECTION_VERSION {
+                return Err(WSError::ParseError);
+            }
+            let mut digest = [0u8; 32];
+            reader.read_exact(&mut digest)?;
+            let size = varint::get32(reader)? as u64;
+            let url = str::from_utf8(&reader.get_slice()?)?.to_string();
+            Ok(SectionReference { digest, size, url })
+        })?;
+        Ok(Some(reference))
+    }
+
+    /// Check and decode the serialized section this reference points to.
+    pub fn resolve(&self, bytes: &[u8]) -> Result<Section, WSError> {
+        if bytes.len() as u64 != self.size || Hash::hash(bytes) != self.digest {
+            return Err(WSError::DigestMismatch);
+        }
+        let mut reader = io::Cursor::new(bytes);
+        let section = Section::deserialize(&mut reader)?.ok_or(WSError::ParseError)?;
+        if reader.position() != self.size {
+            return Err(WSError::ParseError);
+        }
+        Ok(section)
+    }
+}
+
+/// A section removed from a thin module, to be stored at the location of its reference.
+#[derive(Debug, Clone)]
+pub struct ExternalSection {
+    pub reference: SectionReference,
+    /// The serialized section.
+    pub bytes: Vec<u8>,
+}
+
+impl Module {
+    /// Replace sections whose serialized size is at least `min_size` with references.
+    ///
+    /// `url` is called with the digest of each replaced section, and returns the location it will be stored at.
+    /// The replaced sections are returned, so that they can be uploaded there.
+    pub fn thin(
+        &mut self,
+        min_size: u64,
+        mut url: impl FnMut(&[u8; 32]) -> String,
+    ) -> Result<Vec<ExternalSection>, WSError> {
+        let mut external_sections = vec![];
+        for section in self.sections.iter_mut() {
+            if SectionReference::from_section(section)?.is_some() {
+                continue;
+            }
+            let mut bytes = vec![];
+            section.serialize(&mut bytes)?;
+            if (bytes.len() as u64) < min_size {
+                continue;
+            }
+            let digest = Hash::hash(&bytes);
+            let reference = SectionReference {
+                digest,
+                size: bytes.len() as _,
+                url: url(&digest),
+            };
+            debug!(
+                "Replacing {} with a reference to {}",
+                section, reference.url
+            );
+            *section = reference.to_section()?;
+            external_sections.push(ExternalSection { reference, bytes });
+        }
+        Ok(external_sections)
+    }
+
+    /// Return `true` if some sections are stored outside the module.
+    pub fn is_thin(&self) -> bool {
+        self.sections.iter().any(
+            |section| matches!(section, Section::Custom(s) if s.name() == EXTERNAL_SECTION_NAME),
+        )
+    }
+
+    /// Replace section references with the sections they point to.
+    ///
+    /// `fetch` returns the serialized section for a reference, whose size and digest are then verified.
+    /// Return the number of sections that have been inlined.
+    pub fn materialize(
+        &mut self,
+        mut fetch: impl FnMut(&SectionReference) -> Result<Vec<u8>, WSError>,
+    ) -> Result<usize, WSError> {
+        let mut count = 0;
+        for section in self.sections.iter_mut() {
+            let reference = match SectionReference::from_section(section)? {
+                Some(reference) => reference,
+                None => continue,
+            };
+            debug!("Fetching {}", reference.url);
+            *section = reference.resolve(&fetch(&reference)?)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Replace section references with the sections they point to, downloaded using a transport.
+    pub fn materialize_from(&mut self, transport: &impl Transport) -> Result<usize, WSError> {
+        self.materialize(|reference| {
+            let mut bytes = vec![];
+            transport
+                .get_range(&reference.url, 0, Some(reference.size))?
+                .take(reference.size + 1)
+                .read_to_end(&mut bytes)?;
+            Ok(bytes)
+        })
+    }
+}
@@ -0,0 +1,186 @@
+use crate::error::*;
+use crate::template::{rfc3339, source_date_epoch};
+use crate::wasm_module::*;
+
+use log::*;
+use std::str;
+
+/// Name of the standard custom section listing the tools a module was produced with.
+pub const PRODUCERS_SECTION_NAME: &str = "producers";
+
+/// Custom sections timestamps are normalized in by default.
+pub const DEFAULT_TIMESTAMP_SECTIONS: &[&str] = &[PRODUCERS_SECTION_NAME, "build-info"];
+
+/// Return the length of the timestamp at the start of `input`, if there is one.
+///
+/// Accepted timestamps look like `2024-05-01T12:34:56`, with a space or `T` as a separator,
+/// optional fractional seconds, and an optional `Z` or `+hh:mm` offset.
+fn timestamp_len(input: &[u8]) -> Option<usize> {
+    fn digits(input: &[u8], at: usize, count: usize) -> bool {
+        input.len() >= at + count && input[at..at + count].iter().all(u8::is_ascii_digit)
+    }
+    let template: [(usize, Option<u8>); 6] = [
+        (4, Some(b'-')),
+        (2, Some(b'-')),
+        (2, None),
+        (2, Some(b':')),
+        (2, Some(b':')),
+        (2, None),
+    ];
+    let mut i = 0;
+    for (count, separator) in template {
+        if !digits(input, i, count) {
+            return None;
+        }
+        i += count;
+        match separator {
+            Some(separator) if input.get(i) == Some(&separator) => i += 1,
+            Some(_) => return None,
+            None if i == 10 => match input.get(i) {
+                Some(b'T') | Some(b' ') => i += 1,
+                _ => return None,
+            },
+            None => {}
+        }
+    }
+    if input.get(i) == Some(&b'.') && digits(input, i + 1, 1) {
+        i += 1;
+        while digits(input, i, 1) {
+            i += 1;
+        }
+    }
+    match input.get(i) {
+        Some(b'Z') => i += 1,
+        Some(b'+') | Some(b'-')
+            if digits(input, i + 1, 2)
+                && input.get(i + 3) == Some(&b':')
+                && digits(input, i + 4, 2) =>
+        {
+            i += 6
+        }
+        _ => {}
+    }
+    Some(i)
+}
+
+/// Replace all the timestamps found in `input`.
+fn replace_timestamps(input: &[u8], replacement: &str) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut i = 0;
+    while i < input.len() {
+        let at_boundary = i == 0 || !input[i - 1].is_ascii_digit();
+        if at_boundary {
+            if let Some(len) = timestamp_len(&input[i..]) {
+                output.extend_from_slice(replacement.as_bytes());
+                i += len;
+                continue;
+            }
+        }
+        output.push(input[i]);
+        i += 1;
+    }
+    output
+}
+
+/// Replace the timestamps in the strings of a `producers` section, and re-encode it.
+fn replace_producers_timestamps(payload: &[u8], replacement: &str) -> Result<Vec<u8>, WSError> {
+    let mut output = vec![];
+    TakeExact::decode(payload, payload.len() as _, |reader| {
+        let field_count = varint::get32(reader)?;
+        varint::put(&mut output, field_count as _)?;
+        for _ in 0..field_count {
+            varint::put_slice(&mut output, reader.get_slice()?)?;
+            let value_count = varint::get32(reader)?;
+            varint::put(&mut output, value_count as _)?;
+            for _ in 0..value_count {
+                for _ in 0..2 {
+                    let value = reader.get_slice()?;
+                    str::from_utf8(&value)?;
+                    varint::put_slice(&mut output, replace_timestamps(&value, replacement))?;
+                }
+            }
+        }
+        Ok(())
+    })?;
+    Ok(output)
+}
+
+/// A transform replacing timestamps in custom sections with a fixed value, for reproducible builds.
+#[derive(Debug, Clone)]
+pub struct TimestampNormalizer {
+    sections: Vec<String>,
+    timestamp: u64,
+    sort_custom_sections: bool,
+}
+
+impl Default for TimestampNormalizer {
+    fn default() -> Self {
+        TimestampNormalizer {
+            sections: DEFAULT_TIMESTAMP_SECTIONS
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            timestamp: source_date_epoch().unwrap_or(0),
+            sort_custom_sections: false,
+        }
+    }
+}
+
+impl TimestampNormalizer {
+    /// Create a normalizer for the default sections.
+    ///
+    /// Timestamps are replaced with `SOURCE_DATE_EPOCH` if that environment variable is defined,
+    /// or with the UNIX epoch otherwise.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the list of sections to process.
+    pub fn sections(mut self, sections: &[impl AsRef<str>]) -> Self {
+        self.sections = sections.iter().map(|s| s.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Set the timestamp to use, in seconds since the UNIX epoch.
+    pub fn timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    /// Also sort custom sections by name, with `Module::sort_custom_sections()`.
+    pub fn sort_custom_sections(mut self, sort_custom_sections: bool) -> Self {
+        self.sort_custom_sections = sort_custom_sections;
+        self
+    }
+
+    /// Normalize the timestamps of a module, and the order of its custom sections if requested.
+    ///
+    /// Return the number of sections that have been modified or moved.
+    pub fn normalize(&self, module: &mut Module) -> Result<usize, WSError> {
+        let replacement = rfc3339(self.timestamp);
+        let mut count = 0;
+        for section in module.sections.iter_mut() {
+            let section = match section {
+                Section::Custom(section) => section,
+                _ => continue,
+            };
+            if !self.sections.iter().any(|n| n == section.name()) {
+                continue;
+            }
+            let payload = if section.name() == PRODUCERS_SECTION_NAME {
+                replace_producers_timestamps(section.payload(), &replacement)?
+            } else {
+                replace_timestamps(section.payload(), &replacement)
+            };
+            if payload != section.payload() {
+                debug!("Normalized timestamps in [{}]", section.name());
+                section.set_payload(payload);
+                count += 1;
+            }
+        }
+        if self.sort_custom_sections {
+            count += module.sort_custom_sections();
+        }
+        Ok(count)
+    }
+}
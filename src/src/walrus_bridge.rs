@@ -0,0 +1,60 @@
+//! A bridge between this crate's `Module` and `walrus::Module`.
+//!
+//! `walrus` keeps custom sections it doesn't interpret, but always emits them after the standard sections.
+//! Padding sections are dropped when converting to `walrus`; `Module::align_sections()` can be used again
+//! after converting back.
+
+use crate::error::*;
+use crate::wasm_module::*;
+
+use std::io;
+
+impl From<&CustomSection> for walrus::RawCustomSection {
+    fn from(section: &CustomSection) -> Self {
+        walrus::RawCustomSection {
+            name: section.name().to_string(),
+            data: section.payload().to_vec(),
+        }
+    }
+}
+
+impl From<walrus::RawCustomSection> for CustomSection {
+    fn from(section: walrus::RawCustomSection) -> Self {
+        CustomSection::new(section.name, section.data)
+    }
+}
+
+impl Module {
+    /// Convert the module to a `walrus::Module`, using the given configuration.
+    pub fn to_walrus_with_config(
+        &self,
+        config: &walrus::ModuleConfig,
+    ) -> Result<walrus::Module, WSError> {
+        if self.is_component() {
+            return Err(WSError::UnsupportedModuleType);
+        }
+        let mut module = self.clone();
+        module.remove_padding();
+        let mut bin = vec![];
+        module.serialize(&mut bin)?;
+        config
+            .parse(&bin)
+            .map_err(|e| WSError::ValidationError(e.to_string()))
+    }
+}
+
+impl TryFrom<&Module> for walrus::Module {
+    type Error = WSError;
+
+    fn try_from(module: &Module) -> Result<Self, WSError> {
+        module.to_walrus_with_config(&walrus::ModuleConfig::new())
+    }
+}
+
+impl TryFrom<&mut walrus::Module> for Module {
+    type Error = WSError;
+
+    fn try_from(module: &mut walrus::Module) -> Result<Self, WSError> {
+        Module::deserialize(&mut io::Cursor::new(module.emit_wasm()))
+    }
+}
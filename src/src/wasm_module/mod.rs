@@ -1,7 +1,21 @@
-pub(crate) mod varint;
+mod quarantine;
+mod quick_check;
+mod scan;
+mod take_exact;
+pub mod varint;
+mod writer;
+
+pub use quarantine::*;
+pub use quick_check::*;
+pub use scan::*;
+pub(crate) use take_exact::TakeExact;
+pub use writer::*;
 
 use crate::error::*;
+use crate::instrument::{CountingReader, Phase};
+use crate::memory::Reservation;
 
+use hmac_sha256::Hash;
 use log::*;
 use std::fmt::{self};
 use std::fs::File;
@@ -14,8 +28,10 @@ pub type Header = [u8; 8];
 const WASM_HEADER: Header = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
 const WASM_HEADER_COMPONENT: Header = [0x00, 0x61, 0x73, 0x6d, 0x0d, 0x00, 0x01, 0x00];
 
+const MAX_PREALLOC: u64 = 1024 * 1024;
+
 /// A section identifier.
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 #[repr(u8)]
 pub enum SectionId {
     CustomSection,
@@ -101,7 +117,7 @@ pub trait SectionLike {
 }
 
 /// A standard section.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StandardSection {
     id: SectionId,
     payload: Vec<u8>,
@@ -132,7 +148,7 @@ impl SectionLike for StandardSection {
 }
 
 /// A custom section.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct CustomSection {
     name: String,
     payload: Vec<u8>,
@@ -160,6 +176,11 @@ impl CustomSection {
         writer.write_all(&self.payload)?;
         Ok(writer.into_inner())
     }
+
+    /// Replace the payload of the custom section.
+    pub fn set_payload(&mut self, payload: Vec<u8>) {
+        self.payload = payload;
+    }
 }
 
 impl SectionLike for CustomSection {
@@ -179,7 +200,7 @@ impl SectionLike for CustomSection {
 /// A WebAssembly module section.
 ///
 /// It is recommended to import the `SectionLike` trait for additional functions.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub enum Section {
     /// A standard section.
     Standard(StandardSection),
@@ -215,14 +236,11 @@ impl Section {
     pub fn new(id: SectionId, payload: Vec<u8>) -> Result<Self, WSError> {
         match id {
             SectionId::CustomSection => {
-                let mut reader = io::Cursor::new(payload);
-                let name_len = varint::get32(&mut reader)? as usize;
-                let mut name_slice = vec![0u8; name_len];
-                reader.read_exact(&mut name_slice)?;
-                let name = str::from_utf8(&name_slice)?.to_string();
-                let mut payload = Vec::new();
-                let len = reader.read_to_end(&mut payload)?;
-                payload.truncate(len);
+                let (name, payload) =
+                    TakeExact::decode(payload.as_slice(), payload.len() as _, |reader| {
+                        let name = str::from_utf8(&reader.get_slice()?)?.to_string();
+                        Ok((name, reader.read_rest()?))
+                    })?;
                 Ok(Section::Custom(CustomSection::new(name, payload)))
             }
             _ => Ok(Section::Standard(StandardSection::new(id, payload))),
@@ -237,8 +255,14 @@ impl Section {
             Err(e) => return Err(e),
         };
         let len = varint::get32(reader)? as usize;
+        Reservation::new(len as _)?.retain();
         let mut payload = vec![0u8; len];
         reader.read_exact(&mut payload)?;
+        // The payload of a custom section is copied without the name.
+        let _copy = match id {
+            SectionId::CustomSection => Some(Reservation::new(len as _)?),
+            _ => None,
+        };
         let section = Section::new(id, payload)?;
         Ok(Some(section))
     }
@@ -246,19 +270,28 @@ impl Section {
     /// Serialize a section.
     #[allow(dead_code)]
     pub fn serialize(&self, writer: &mut impl Write) -> Result<(), WSError> {
-        let outer_payload;
-        let payload = match self {
-            Section::Standard(s) => s.payload(),
+        varint::put(writer, u8::from(self.id()) as _)?;
+        match self {
+            Section::Standard(s) => varint::put_slice(writer, s.payload())?,
             Section::Custom(s) => {
-                outer_payload = s.outer_payload()?;
-                &outer_payload
+                let mut name_len = vec![];
+                varint::put(&mut name_len, s.name().len() as _)?;
+                let len = name_len.len() + s.name().len() + s.payload().len();
+                varint::put(writer, len as _)?;
+                writer.write_all(&name_len)?;
+                writer.write_all(s.name().as_bytes())?;
+                writer.write_all(s.payload())?;
             }
-        };
-        varint::put(writer, u8::from(self.id()) as _)?;
-        varint::put(writer, payload.len() as _)?;
-        writer.write_all(payload)?;
+        }
         Ok(())
     }
+
+    /// Return the SHA-256 digest of the serialized section.
+    pub fn stable_hash(&self) -> Result<[u8; 32], WSError> {
+        let mut writer = HashWriter(Hash::new());
+        self.serialize(&mut writer)?;
+        Ok(writer.0.finalize())
+    }
 }
 
 impl CustomSection {}
@@ -275,23 +308,121 @@ impl fmt::Debug for Section {
     }
 }
 
+/// A key identifying a section across versions of a module.
+///
+/// Standard sections are identified by their identifier, as a module includes each of them at most once.
+/// Custom sections are identified by their name, and their index among the custom sections with that name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SectionKey {
+    Standard(SectionId),
+    Custom { name: String, index: usize },
+}
+
+impl SectionKey {
+    /// Return an identifier for the section that is stable across versions, and suitable for external references.
+    ///
+    /// Standard sections are `section:<id>`, and custom sections are `custom:<name>:<index>`.
+    pub fn stable_id(&self) -> String {
+        match self {
+            SectionKey::Standard(id) => format!("section:{}", u8::from(*id)),
+            SectionKey::Custom { name, index } => format!("custom:{}:{}", name, index),
+        }
+    }
+}
+
+impl fmt::Display for SectionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SectionKey::Standard(id) => write!(f, "{}", id),
+            SectionKey::Custom { name, index: 0 } => write!(f, "custom section: [{}]", name),
+            SectionKey::Custom { name, index } => {
+                write!(f, "custom section: [{}] #{}", name, index + 1)
+            }
+        }
+    }
+}
+
+/// Names of custom sections to ignore when comparing or verifying modules.
+///
+/// This is meant for metadata that changes between otherwise identical builds, such as timestamps or build paths.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IgnoreList {
+    names: Vec<String>,
+}
+
+impl IgnoreList {
+    /// Create an empty list.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a custom section name to the list.
+    pub fn add(&mut self, name: impl Into<String>) {
+        let name = name.into();
+        if !self.names.contains(&name) {
+            self.names.push(name);
+        }
+    }
+
+    /// Return the names in the list, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.names.iter().map(|n| n.as_str()).collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Return `true` if no sections are ignored.
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Return `true` if the section is ignored.
+    pub fn ignores(&self, section: &Section) -> bool {
+        match section {
+            Section::Custom(s) => self.names.iter().any(|n| n == s.name()),
+            Section::Standard(_) => false,
+        }
+    }
+}
+
+impl<S: Into<String>> FromIterator<S> for IgnoreList {
+    fn from_iter<I: IntoIterator<Item = S>>(iter: I) -> Self {
+        let mut list = IgnoreList::new();
+        for name in iter {
+            list.add(name);
+        }
+        list
+    }
+}
+
 /// A WebAssembly module.
-#[derive(Debug, Clone, Default)]
+///
+/// Modules are equal if they have the same header, the same sections in the same order, and the same quarantined data.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Module {
     pub header: Header,
     pub sections: Vec<Section>,
+    /// Data set aside by `deserialize_with_options()` in quarantine mode, in order.
+    pub quarantine: Vec<QuarantinedData>,
 }
 
 impl Module {
     /// Deserialize a WebAssembly module from the given reader.
     pub fn deserialize(reader: &mut impl Read) -> Result<Self, WSError> {
-        let header = Self::stream_init(reader)?;
-        let it = Self::stream(reader)?;
+        let mut phase = Phase::Parse.start();
+        let mut reader = CountingReader::new(reader);
+        let header = Self::stream_init(&mut reader)?;
+        let it = Self::stream(&mut reader)?;
         let mut sections = Vec::new();
         for section in it {
             sections.push(section?);
         }
-        Ok(Module { header, sections })
+        phase.add_bytes(reader.count);
+        Ok(Module {
+            header,
+            sections,
+            quarantine: vec![],
+        })
     }
 
     /// Deserialize a WebAssembly module from the given file.
@@ -311,6 +442,63 @@ impl Module {
         Ok(())
     }
 
+    /// Return the keys of the sections, in order.
+    pub fn section_keys(&self) -> Vec<SectionKey> {
+        let mut custom_counts: Vec<(&str, usize)> = vec![];
+        self.sections
+            .iter()
+            .map(|section| match section {
+                Section::Standard(s) => SectionKey::Standard(s.id()),
+                Section::Custom(s) => {
+                    let index = match custom_counts.iter_mut().find(|(n, _)| *n == s.name()) {
+                        Some((_, count)) => {
+                            *count += 1;
+                            *count - 1
+                        }
+                        None => {
+                            custom_counts.push((s.name(), 1));
+                            0
+                        }
+                    };
+                    SectionKey::Custom {
+                        name: s.name().to_string(),
+                        index,
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Return the key and SHA-256 digest of each section, in order.
+    pub fn section_hashes(&self) -> Result<Vec<(SectionKey, [u8; 32])>, WSError> {
+        self.section_keys()
+            .into_iter()
+            .zip(&self.sections)
+            .map(|(key, section)| Ok((key, section.stable_hash()?)))
+            .collect()
+    }
+
+    /// Return the SHA-256 digest of the serialized module, computed without buffering it.
+    ///
+    /// This is the digest the build identifier of a module is derived from.
+    pub fn stable_hash(&self) -> Result<[u8; 32], WSError> {
+        let mut writer = HashWriter(Hash::new());
+        self.serialize(&mut writer)?;
+        Ok(writer.0.finalize())
+    }
+
+    /// Return the SHA-256 digest of the serialized module, leaving out the ignored sections.
+    pub fn stable_hash_ignoring(&self, ignored: &IgnoreList) -> Result<[u8; 32], WSError> {
+        let mut writer = HashWriter(Hash::new());
+        writer.write_all(&self.header)?;
+        for section in &self.sections {
+            if !ignored.ignores(section) {
+                section.serialize(&mut writer)?;
+            }
+        }
+        Ok(writer.0.finalize())
+    }
+
     /// Serialize a WebAssembly module to the given file.
     #[allow(dead_code)]
     pub fn serialize_to_file(&self, file: impl AsRef<Path>) -> Result<(), WSError> {
@@ -318,6 +506,39 @@ impl Module {
         self.serialize(&mut BufWriter::new(fp))
     }
 
+    /// Return `true` if the module is a component.
+    pub fn is_component(&self) -> bool {
+        self.header == WASM_HEADER_COMPONENT
+    }
+
+    /// Sort custom sections by name, so that the serialized module doesn't depend on the order
+    /// toolchains emitted them in.
+    ///
+    /// Standard sections keep their order, and custom sections are only reordered within each run
+    /// of consecutive custom sections, so that they remain between the same standard sections and
+    /// the offsets of the standard sections don't change. Sections with the same name keep their
+    /// relative order. Return the number of custom sections that moved.
+    pub fn sort_custom_sections(&mut self) -> usize {
+        let mut moved = 0;
+        for run in self
+            .sections
+            .split_mut(|section| !matches!(section, Section::Custom(_)))
+        {
+            let name = |section: &Section| match section {
+                Section::Custom(custom) => custom.name().to_string(),
+                Section::Standard(_) => unreachable!(),
+            };
+            let names: Vec<String> = run.iter().map(name).collect();
+            run.sort_by_key(name);
+            moved += run
+                .iter()
+                .zip(&names)
+                .filter(|(section, original)| &name(section) != *original)
+                .count();
+        }
+        moved
+    }
+
     /// Parse the module's header. That function must be called before `stream`.
     pub fn stream_init<T: Read>(reader: &mut T) -> Result<Header, WSError> {
         let mut header = Header::default();
@@ -335,9 +556,19 @@ impl Module {
     /// Return an iterator over the sections of a WebAssembly module.    
     ///
     /// The module is read in a streaming fashion, and doesn't have to be fully loaded into memory.
-    pub fn stream<T: Read>(reader: &mut T) -> Result<SectionsIterator<T>, WSError> {
+    pub fn stream<T: Read>(reader: &mut T) -> Result<SectionsIterator<'_, T>, WSError> {
         Ok(SectionsIterator { reader })
     }
+
+    /// Return a stream of the sections of a WebAssembly module, whose payloads are read on demand.
+    ///
+    /// Unlike `stream`, payloads are never buffered. `stream_init` must be called first.
+    pub fn stream_payloads<T: Read>(reader: &mut T) -> PayloadStream<'_, T> {
+        PayloadStream {
+            reader,
+            remaining: 0,
+        }
+    }
 }
 
 /// An iterator over the sections of a WebAssembly module.
@@ -356,3 +587,124 @@ impl<'t, T: Read> Iterator for SectionsIterator<'t, T> {
         }
     }
 }
+
+struct HashWriter(Hash);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A stream of sections, whose payloads are read directly from the underlying reader.
+pub struct PayloadStream<'t, T: Read> {
+    reader: &'t mut T,
+    remaining: u64,
+}
+
+impl<'t, T: Read> PayloadStream<'t, T> {
+    /// Return the next section, or `None` at the end of the module.
+    ///
+    /// Whatever wasn't read from the payload of the previous section is skipped.
+    pub fn next_section(&mut self) -> Result<Option<SectionPayload<'_, 't, T>>, WSError> {
+        self.skip()?;
+        let id = match varint::get7(self.reader) {
+            Ok(id) => SectionId::from(id),
+            Err(WSError::Eof) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        self.remaining = varint::get32(self.reader)? as _;
+        let name = match id {
+            SectionId::CustomSection => {
+                let mut reader = (&mut *self.reader).take(self.remaining);
+                let name_len = varint::get32(&mut reader)? as u64;
+                if name_len > reader.limit() {
+                    return Err(WSError::ParseError);
+                }
+                Reservation::new(name_len)?.retain();
+                let mut name = vec![0u8; name_len as usize];
+                reader.read_exact(&mut name)?;
+                self.remaining = reader.limit();
+                Some(String::from_utf8(name).map_err(|e| e.utf8_error())?)
+            }
+            _ => None,
+        };
+        Ok(Some(SectionPayload {
+            stream: self,
+            id,
+            name,
+        }))
+    }
+
+    fn skip(&mut self) -> Result<(), WSError> {
+        let skipped = io::copy(
+            &mut (&mut *self.reader).take(self.remaining),
+            &mut io::sink(),
+        )?;
+        if skipped != self.remaining {
+            return Err(WSError::Eof);
+        }
+        self.remaining = 0;
+        Ok(())
+    }
+}
+
+/// A section whose payload is read on demand, bounded by its declared length.
+///
+/// For custom sections, the payload excludes the name.
+pub struct SectionPayload<'s, 't, T: Read> {
+    stream: &'s mut PayloadStream<'t, T>,
+    id: SectionId,
+    name: Option<String>,
+}
+
+impl<T: Read> SectionPayload<'_, '_, T> {
+    /// Return the identifier of the section.
+    pub fn id(&self) -> SectionId {
+        self.id
+    }
+
+    /// Return the name of the section, if it is a custom section.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Return the number of payload bytes that haven't been read yet.
+    pub fn remaining(&self) -> u64 {
+        self.stream.remaining
+    }
+
+    /// Read the rest of the payload, and return the section.
+    pub fn into_section(mut self) -> Result<Section, WSError> {
+        Reservation::new(self.remaining())?.retain();
+        let mut payload = Vec::with_capacity(self.remaining().min(MAX_PREALLOC) as usize);
+        self.read_to_end(&mut payload)?;
+        Ok(match self.name.take() {
+            Some(name) => Section::Custom(CustomSection::new(name, payload)),
+            None => Section::Standard(StandardSection::new(self.id, payload)),
+        })
+    }
+}
+
+impl<T: Read> Read for SectionPayload<'_, '_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = &mut self.stream.remaining;
+        if *remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let max = buf
+            .len()
+            .min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+        let len = self.stream.reader.read(&mut buf[..max])?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        *remaining -= len as u64;
+        Ok(len)
+    }
+}
@@ -0,0 +1,127 @@
+use std::io::{self, prelude::*};
+
+use super::*;
+use crate::memory;
+
+/// Largest section identifier defined for core modules (tag section).
+const MAX_MODULE_SECTION_ID: u8 = 13;
+
+/// Largest section identifier defined for components (component type section).
+const MAX_COMPONENT_SECTION_ID: u8 = 11;
+
+/// Options for parsing a module.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ParseOptions {
+    quarantine: bool,
+}
+
+impl ParseOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set aside sections with unknown identifiers, and data that cannot be parsed, instead of failing.
+    pub fn quarantine(mut self, quarantine: bool) -> Self {
+        self.quarantine = quarantine;
+        self
+    }
+}
+
+/// Why data has been quarantined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuarantineReason {
+    /// A section with an identifier that is not defined for this module type.
+    UnknownSection(u8),
+    /// Data that could not be parsed as a section. Everything after it is included.
+    ParseError,
+}
+
+/// Raw data set aside while parsing a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuarantinedData {
+    pub reason: QuarantineReason,
+    /// Index, in the module's sections, of the section the data was found before.
+    pub index: usize,
+    /// The raw data, including section headers.
+    pub bytes: Vec<u8>,
+}
+
+impl Module {
+    /// Deserialize a WebAssembly module from the given reader, using the given options.
+    pub fn deserialize_with_options(
+        reader: &mut impl Read,
+        options: &ParseOptions,
+    ) -> Result<Self, WSError> {
+        if !options.quarantine {
+            return Self::deserialize(reader);
+        }
+        let header = Self::stream_init(reader)?;
+        let max_section_id = if header == WASM_HEADER_COMPONENT {
+            MAX_COMPONENT_SECTION_ID
+        } else {
+            MAX_MODULE_SECTION_ID
+        };
+        let mut reservation = Reservation::new(0)?;
+        let data = memory::read_to_end(reader, &mut reservation)?;
+        let mut reader = io::Cursor::new(data.as_slice());
+        let mut sections = vec![];
+        let mut quarantine = vec![];
+        loop {
+            let offset = reader.position() as usize;
+            let section = match Section::deserialize(&mut reader) {
+                Ok(None) => break,
+                Ok(Some(section)) => section,
+                Err(e @ WSError::MemoryLimit { .. }) => return Err(e),
+                Err(e) => {
+                    warn!("Quarantining data at offset {}: {}", offset, e);
+                    quarantine.push(QuarantinedData {
+                        reason: QuarantineReason::ParseError,
+                        index: sections.len(),
+                        bytes: retained_copy(&data[offset..])?,
+                    });
+                    break;
+                }
+            };
+            match section.id() {
+                SectionId::Extension(id) if id > max_section_id => {
+                    warn!("Quarantining unknown section id#{}", id);
+                    quarantine.push(QuarantinedData {
+                        reason: QuarantineReason::UnknownSection(id),
+                        index: sections.len(),
+                        bytes: retained_copy(&data[offset..reader.position() as usize])?,
+                    });
+                }
+                _ => sections.push(section),
+            }
+        }
+        Ok(Module {
+            header,
+            sections,
+            quarantine,
+        })
+    }
+
+    /// Serialize a WebAssembly module, putting quarantined data back where it was found.
+    ///
+    /// `serialize()` always leaves quarantined data out.
+    pub fn serialize_with_quarantine(&self, writer: &mut impl Write) -> Result<(), WSError> {
+        writer.write_all(&self.header)?;
+        let mut quarantine = self.quarantine.iter().peekable();
+        for (index, section) in self.sections.iter().enumerate() {
+            while let Some(data) = quarantine.next_if(|data| data.index <= index) {
+                writer.write_all(&data.bytes)?;
+            }
+            section.serialize(writer)?;
+        }
+        for data in quarantine {
+            writer.write_all(&data.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy quarantined data, charging it until the end of the memory limit.
+fn retained_copy(data: &[u8]) -> Result<Vec<u8>, WSError> {
+    Reservation::new(data.len() as _)?.retain();
+    Ok(data.to_vec())
+}
@@ -0,0 +1,148 @@
+use std::fmt;
+use std::io::{self, prelude::*, BufReader, SeekFrom};
+
+use super::*;
+
+/// Number of bytes read at the end of the module by `Module::quick_check()`.
+const QUICK_CHECK_SAMPLE_SIZE: u64 = 64;
+
+/// A section header found by `Module::quick_check()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct QuickSection {
+    pub id: SectionId,
+    /// Offset of the section in the module.
+    pub offset: u64,
+    /// Declared length of the payload.
+    pub len: u64,
+}
+
+/// A problem found by `Module::quick_check()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuickCheckIssue {
+    /// The file doesn't start with the header of a module or a component.
+    Header,
+    /// The header of the section at this offset can't be decoded.
+    SectionHeader { offset: u64 },
+    /// The section at this offset extends beyond the end of the file.
+    Truncated { offset: u64, end: u64 },
+    /// The last bytes of the file couldn't be read.
+    Unreadable,
+}
+
+impl fmt::Display for QuickCheckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuickCheckIssue::Header => write!(f, "not a WebAssembly module"),
+            QuickCheckIssue::SectionHeader { offset } => {
+                write!(f, "invalid section header at offset {}", offset)
+            }
+            QuickCheckIssue::Truncated { offset, end } => write!(
+                f,
+                "the section at offset {} ends at {}, beyond the end of the file",
+                offset, end
+            ),
+            QuickCheckIssue::Unreadable => write!(f, "the end of the file can't be read"),
+        }
+    }
+}
+
+/// The result of `Module::quick_check()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuickReport {
+    /// Size of the file.
+    pub len: u64,
+    /// Sections whose header was read, in order.
+    pub sections: Vec<QuickSection>,
+    /// The first problem found, if any. Sections after it are not checked.
+    pub issue: Option<QuickCheckIssue>,
+}
+
+impl QuickReport {
+    /// Return `true` if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.issue.is_none()
+    }
+
+    /// Return the report if no problems were found, or the error corresponding to the problem.
+    pub fn into_result(self) -> Result<Self, WSError> {
+        match &self.issue {
+            None => Ok(self),
+            Some(QuickCheckIssue::Header) => Err(WSError::UnsupportedModuleType),
+            Some(QuickCheckIssue::SectionHeader { .. }) => Err(WSError::ParseError),
+            Some(QuickCheckIssue::Truncated { .. } | QuickCheckIssue::Unreadable) => {
+                Err(WSError::Eof)
+            }
+        }
+    }
+}
+
+impl Module {
+    /// Check the structure of a module file without parsing it.
+    ///
+    /// This is a sanity check, cheap enough to run before any heavier verification: the header
+    /// is checked, section headers are read while seeking over their payloads, their lengths must
+    /// add up to the size of the file, and the last bytes are read to make sure that it can be
+    /// read to the end. Payloads are not validated, so a module passing the check can still be
+    /// rejected by `deserialize()`.
+    pub fn quick_check(file: impl AsRef<Path>) -> Result<QuickReport, WSError> {
+        let fp = File::open(file.as_ref())?;
+        Self::quick_check_reader(&mut BufReader::new(fp))
+    }
+
+    /// Check the structure of a module, like `quick_check()`, given a seekable reader.
+    pub fn quick_check_reader(reader: &mut (impl Read + Seek)) -> Result<QuickReport, WSError> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut report = QuickReport {
+            len,
+            sections: vec![],
+            issue: None,
+        };
+        let mut header = Header::default();
+        match reader.read_exact(&mut header) {
+            Ok(()) if header == WASM_HEADER || header == WASM_HEADER_COMPONENT => {}
+            Ok(()) => {
+                report.issue = Some(QuickCheckIssue::Header);
+                return Ok(report);
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                report.issue = Some(QuickCheckIssue::Header);
+                return Ok(report);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let mut offset = header.len() as u64;
+        while offset < len {
+            let section_header = varint::get7(reader).and_then(|id| {
+                let payload_len = varint::get32(reader)?;
+                Ok((SectionId::from(id), payload_len as u64))
+            });
+            let (id, payload_len) = match section_header {
+                Ok(section_header) => section_header,
+                Err(WSError::Eof | WSError::ParseError) => {
+                    report.issue = Some(QuickCheckIssue::SectionHeader { offset });
+                    return Ok(report);
+                }
+                Err(e) => return Err(e),
+            };
+            let end = reader.stream_position()? + payload_len;
+            if end > len {
+                report.issue = Some(QuickCheckIssue::Truncated { offset, end });
+                return Ok(report);
+            }
+            report.sections.push(QuickSection {
+                id,
+                offset,
+                len: payload_len,
+            });
+            offset = reader.seek(SeekFrom::Start(end))?;
+        }
+        let sample_size = QUICK_CHECK_SAMPLE_SIZE.min(len - header.len() as u64);
+        reader.seek(SeekFrom::Start(len - sample_size))?;
+        let mut sample = vec![0u8; sample_size as usize];
+        if reader.read_exact(&mut sample).is_err() {
+            report.issue = Some(QuickCheckIssue::Unreadable);
+        }
+        Ok(report)
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+
+use super::*;
+
+/// Size of the chunks a module is read in.
+const SCAN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// A section found by `Module::scan()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScannedSection {
+    pub key: SectionKey,
+    /// Offset of the section in the module.
+    pub offset: u64,
+    /// Size of the serialized section.
+    pub size: u64,
+    /// SHA-256 digest of the serialized section, as returned by `Section::stable_hash()`.
+    pub digest: [u8; 32],
+}
+
+/// The structure of a module, and the digests of its sections, as computed by `Module::scan()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleScan {
+    pub header: Header,
+    pub sections: Vec<ScannedSection>,
+    /// Number of bytes read.
+    pub len: u64,
+}
+
+impl ModuleScan {
+    /// Return `true` if the module is a component.
+    pub fn is_component(&self) -> bool {
+        self.header == WASM_HEADER_COMPONENT
+    }
+
+    /// Return the key and SHA-256 digest of each section, in order, like `Module::section_hashes()`.
+    pub fn section_hashes(&self) -> Vec<(SectionKey, [u8; 32])> {
+        self.sections
+            .iter()
+            .map(|section| (section.key.clone(), section.digest))
+            .collect()
+    }
+}
+
+impl Module {
+    /// Check the structure of a module, and compute the digests of its sections, in a single pass.
+    ///
+    /// This accepts the same modules as `deserialize()`, but payloads are hashed as they are read,
+    /// in large chunks, instead of being copied into sections. Time is mostly spent hashing, and
+    /// memory use doesn't depend on the size of the module, which suits indexing many modules.
+    pub fn scan(reader: &mut impl Read) -> Result<ModuleScan, WSError> {
+        let mut phase = Phase::Parse.start();
+        let _reservation = Reservation::new(SCAN_CHUNK_SIZE as _)?;
+        let mut reader = ChunkedReader {
+            reader,
+            buf: vec![0u8; SCAN_CHUNK_SIZE],
+            pos: 0,
+            end: 0,
+            count: 0,
+        };
+        let header = Self::stream_init(&mut reader)?;
+        let mut custom_counts: HashMap<String, usize> = HashMap::new();
+        let mut sections = vec![];
+        loop {
+            let offset = reader.consumed();
+            let id = match varint::get7(&mut reader) {
+                Ok(id) => SectionId::from(id),
+                Err(WSError::Eof) => break,
+                Err(e) => return Err(e),
+            };
+            let len = varint::get32(&mut reader)? as u64;
+            let mut hash = Hash::new();
+            let mut prefix = vec![u8::from(id)];
+            let (key, payload_len) = match id {
+                SectionId::CustomSection => {
+                    let start = reader.consumed();
+                    let name_len = varint::get32(&mut reader)? as u64;
+                    let header_len = reader.consumed() - start;
+                    if header_len + name_len > len {
+                        return Err(WSError::ParseError);
+                    }
+                    Reservation::new(name_len)?.retain();
+                    let mut name = vec![0u8; name_len as usize];
+                    reader.read_exact(&mut name)?;
+                    let name = String::from_utf8(name).map_err(|e| e.utf8_error())?;
+                    let payload_len = len - header_len - name_len;
+                    let mut name_prefix = vec![];
+                    varint::put(&mut name_prefix, name_len)?;
+                    let serialized_len = name_prefix.len() as u64 + name_len + payload_len;
+                    varint::put(&mut prefix, serialized_len)?;
+                    prefix.extend_from_slice(&name_prefix);
+                    prefix.extend_from_slice(name.as_bytes());
+                    let count = custom_counts.entry(name.clone()).or_default();
+                    *count += 1;
+                    let index = *count - 1;
+                    (SectionKey::Custom { name, index }, payload_len)
+                }
+                _ => {
+                    varint::put(&mut prefix, len)?;
+                    (SectionKey::Standard(id), len)
+                }
+            };
+            hash.update(&prefix);
+            reader.hash(payload_len, &mut hash)?;
+            sections.push(ScannedSection {
+                key,
+                offset,
+                size: prefix.len() as u64 + payload_len,
+                digest: hash.finalize(),
+            });
+        }
+        phase.add_bytes(reader.count);
+        Ok(ModuleScan {
+            header,
+            sections,
+            len: reader.count,
+        })
+    }
+}
+
+/// A reader buffering large chunks, whose content can be hashed without being copied.
+struct ChunkedReader<'t, R: Read> {
+    reader: &'t mut R,
+    buf: Vec<u8>,
+    pos: usize,
+    end: usize,
+    count: u64,
+}
+
+impl<R: Read> ChunkedReader<'_, R> {
+    /// Return the number of bytes consumed so far.
+    fn consumed(&self) -> u64 {
+        self.count - (self.end - self.pos) as u64
+    }
+
+    /// Make sure that the buffer isn't empty, and return `false` at the end of the input.
+    fn fill(&mut self) -> io::Result<bool> {
+        while self.pos == self.end {
+            match self.reader.read(&mut self.buf) {
+                Ok(0) => return Ok(false),
+                Ok(len) => {
+                    (self.pos, self.end) = (0, len);
+                    self.count += len as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Feed the next `len` bytes to a hash function.
+    fn hash(&mut self, mut len: u64, hash: &mut Hash) -> Result<(), WSError> {
+        while len > 0 {
+            if !self.fill()? {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            let chunk_len = (self.end - self.pos).min(usize::try_from(len).unwrap_or(usize::MAX));
+            let chunk = &self.buf[self.pos..self.pos + chunk_len];
+            hash.update(chunk);
+            self.pos += chunk_len;
+            len -= chunk_len as u64;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChunkedReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || !self.fill()? {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.end - self.pos);
+        buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
@@ -0,0 +1,89 @@
+use std::io::{self, prelude::*};
+
+use super::varint;
+use crate::error::*;
+
+/// A reader bounded by the declared length of a section.
+///
+/// Reading past the declared length is an error, and so is leaving unread data.
+pub(crate) struct TakeExact<R: Read> {
+    reader: R,
+    remaining: u64,
+    overrun: bool,
+}
+
+impl<R: Read> TakeExact<R> {
+    pub fn new(reader: R, len: u64) -> Self {
+        TakeExact {
+            reader,
+            remaining: len,
+            overrun: false,
+        }
+    }
+
+    /// Run a decoder over exactly `len` bytes of `reader`.
+    pub fn decode<T>(
+        reader: R,
+        len: u64,
+        decode: impl FnOnce(&mut Self) -> Result<T, WSError>,
+    ) -> Result<T, WSError> {
+        let mut reader = Self::new(reader, len);
+        let value = match decode(&mut reader) {
+            Err(_) if reader.overrun => return Err(WSError::ParseError),
+            res => res?,
+        };
+        reader.finish()?;
+        Ok(value)
+    }
+
+    /// Read a length-prefixed slice, which must fit in the remaining data.
+    pub fn get_slice(&mut self) -> Result<Vec<u8>, WSError> {
+        let len = varint::get32(self)? as u64;
+        if len > self.remaining {
+            self.overrun = true;
+            return Err(WSError::ParseError);
+        }
+        let mut bytes = vec![0u8; len as usize];
+        self.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Read all the remaining data.
+    pub fn read_rest(&mut self) -> Result<Vec<u8>, WSError> {
+        let mut bytes = vec![0u8; self.remaining as usize];
+        self.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Check that all the data has been read.
+    pub fn finish(self) -> Result<(), WSError> {
+        if self.remaining != 0 {
+            return Err(WSError::ParseError);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for TakeExact<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.overrun = true;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "read past the end of the section",
+            ));
+        }
+        let max = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let len = self.reader.read(&mut buf[..max])?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
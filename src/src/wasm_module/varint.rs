@@ -1,6 +1,7 @@
 use std::io::{self, prelude::*};
 
 use crate::error::*;
+use crate::memory::Reservation;
 
 pub fn get7(reader: &mut impl Read) -> Result<u8, WSError> {
     let mut v: u8 = 0;
@@ -34,6 +35,19 @@ pub fn get32(reader: &mut impl Read) -> Result<u32, WSError> {
     Err(WSError::ParseError)
 }
 
+pub fn get64(reader: &mut impl Read) -> Result<u64, WSError> {
+    let mut v: u64 = 0;
+    for i in 0..10 {
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte)?;
+        v |= ((byte[0] & 0x7f) as u64) << (i * 7);
+        if (byte[0] & 0x80) == 0 {
+            return Ok(v);
+        }
+    }
+    Err(WSError::ParseError)
+}
+
 pub fn put(writer: &mut impl Write, mut v: u64) -> Result<(), WSError> {
     let mut byte = [0u8; 1];
     loop {
@@ -59,6 +73,7 @@ pub fn put_slice(writer: &mut impl Write, bytes: impl AsRef<[u8]>) -> Result<(),
 
 pub fn get_slice(reader: &mut impl Read) -> Result<Vec<u8>, WSError> {
     let len = get32(reader)? as _;
+    Reservation::new(len as _)?.retain();
     let mut bytes = vec![0u8; len];
     reader.read_exact(&mut bytes)?;
     Ok(bytes)
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+
+use super::*;
+
+/// The result of writing a module with a `ModuleWriter`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteSummary {
+    pub header: Header,
+    /// Sections in the order they were written, with the offsets and digests `Module::scan()`
+    /// would find in the output.
+    pub sections: Vec<ScannedSection>,
+    /// Number of bytes written.
+    pub len: u64,
+    /// SHA-256 digest of the whole module, as returned by `Module::stable_hash()`.
+    pub digest: [u8; 32],
+}
+
+impl WriteSummary {
+    /// Return the key and SHA-256 digest of each section, in order, like `Module::section_hashes()`.
+    pub fn section_hashes(&self) -> Vec<(SectionKey, [u8; 32])> {
+        self.sections
+            .iter()
+            .map(|section| (section.key.clone(), section.digest))
+            .collect()
+    }
+}
+
+/// A writer hashing what goes through it.
+struct DigestWriter<'t, W: Write> {
+    writer: &'t mut W,
+    module_hash: &'t mut Hash,
+    section_hash: Hash,
+    count: u64,
+}
+
+impl<W: Write> Write for DigestWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.module_hash.update(&buf[..len]);
+        self.section_hash.update(&buf[..len]);
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Write a module section by section, computing the digests of the sections as they are written.
+///
+/// Publishers get the digests needed for manifests and indexes from the summary returned by
+/// `finish()`, without having to read the output again.
+pub struct ModuleWriter<W: Write> {
+    writer: W,
+    header: Header,
+    module_hash: Hash,
+    sections: Vec<ScannedSection>,
+    custom_counts: HashMap<String, usize>,
+    len: u64,
+}
+
+impl<W: Write> ModuleWriter<W> {
+    /// Start a module with the given header.
+    pub fn new(mut writer: W, header: Header) -> Result<Self, WSError> {
+        writer.write_all(&header)?;
+        let mut module_hash = Hash::new();
+        module_hash.update(header);
+        Ok(ModuleWriter {
+            writer,
+            header,
+            module_hash,
+            sections: vec![],
+            custom_counts: HashMap::new(),
+            len: header.len() as _,
+        })
+    }
+
+    /// Append a section, and return its description.
+    pub fn write_section(&mut self, section: &Section) -> Result<&ScannedSection, WSError> {
+        let key = match section {
+            Section::Standard(s) => SectionKey::Standard(s.id()),
+            Section::Custom(s) => {
+                let count = self.custom_counts.entry(s.name().to_string()).or_default();
+                *count += 1;
+                SectionKey::Custom {
+                    name: s.name().to_string(),
+                    index: *count - 1,
+                }
+            }
+        };
+        let mut writer = DigestWriter {
+            writer: &mut self.writer,
+            module_hash: &mut self.module_hash,
+            section_hash: Hash::new(),
+            count: 0,
+        };
+        section.serialize(&mut writer)?;
+        let (size, digest) = (writer.count, writer.section_hash.finalize());
+        self.sections.push(ScannedSection {
+            key,
+            offset: self.len,
+            size,
+            digest,
+        });
+        self.len += size;
+        Ok(self.sections.last().unwrap())
+    }
+
+    /// Flush the output, and return it along with the summary of what was written.
+    pub fn finish(mut self) -> Result<(W, WriteSummary), WSError> {
+        self.writer.flush()?;
+        let summary = WriteSummary {
+            header: self.header,
+            sections: self.sections,
+            len: self.len,
+            digest: self.module_hash.finalize(),
+        };
+        Ok((self.writer, summary))
+    }
+}
+
+impl Module {
+    /// Serialize the module, like `serialize()`, and return the digests of what was written.
+    pub fn serialize_with_summary(&self, writer: &mut impl Write) -> Result<WriteSummary, WSError> {
+        let mut module_writer = ModuleWriter::new(writer, self.header)?;
+        for section in &self.sections {
+            module_writer.write_section(section)?;
+        }
+        Ok(module_writer.finish()?.1)
+    }
+
+    /// Serialize the module to a file, and return the digests of what was written.
+    pub fn serialize_to_file_with_summary(
+        &self,
+        file: impl AsRef<Path>,
+    ) -> Result<WriteSummary, WSError> {
+        let fp = File::create(file.as_ref())?;
+        self.serialize_with_summary(&mut BufWriter::new(fp))
+    }
+}
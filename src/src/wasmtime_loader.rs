@@ -0,0 +1,122 @@
+//! Loading verified modules into wasmtime.
+//!
+//! Modules are handed over to wasmtime as bytes, once their signature has been checked. Compiled
+//! artifacts can be cached on disk, keyed by build identifier, so that a module is only compiled
+//! once per engine configuration. The cache directory must only be writable by the host, since
+//! cached artifacts are native code loaded without further checks.
+
+use crate::agent::Precompiler;
+use crate::error::*;
+use crate::repo::build_id;
+use crate::signature::*;
+use crate::wasm_module::*;
+
+use log::*;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+fn wasmtime_error(e: wasmtime::Error) -> WSError {
+    WSError::ValidationError(e.to_string())
+}
+
+/// Compiles modules with a wasmtime engine, reusing cached artifacts.
+#[derive(Clone)]
+pub struct WasmtimeLoader {
+    engine: wasmtime::Engine,
+    cache_dir: Option<PathBuf>,
+}
+
+impl WasmtimeLoader {
+    /// Create a loader compiling modules with `engine`, without caching.
+    pub fn new(engine: wasmtime::Engine) -> Self {
+        WasmtimeLoader {
+            engine,
+            cache_dir: None,
+        }
+    }
+
+    /// Cache compiled artifacts in a directory.
+    pub fn cache_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.cache_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Return the engine modules are compiled with.
+    pub fn engine(&self) -> &wasmtime::Engine {
+        &self.engine
+    }
+
+    /// Verify the signature of a module, then load it.
+    pub fn load_verified(
+        &self,
+        module_bin: &[u8],
+        key_ring: &KeyRing,
+        signature: &Signature,
+    ) -> Result<wasmtime::Module, WSError> {
+        key_ring.verify_module(module_bin, signature)?;
+        self.load_bytes(module_bin)
+    }
+
+    /// Load a module that has already been verified.
+    pub fn load(&self, module: &Module) -> Result<wasmtime::Module, WSError> {
+        let mut module_bin = vec![];
+        module.serialize(&mut module_bin)?;
+        self.load_bytes(&module_bin)
+    }
+
+    /// Load a serialized module that has already been verified.
+    ///
+    /// Components are not supported.
+    pub fn load_bytes(&self, module_bin: &[u8]) -> Result<wasmtime::Module, WSError> {
+        let cache_file = match &self.cache_dir {
+            None => return wasmtime::Module::new(&self.engine, module_bin).map_err(wasmtime_error),
+            Some(cache_dir) => cache_dir.join(format!("{}.cwasm", build_id(module_bin))),
+        };
+        if cache_file.exists() {
+            // SAFETY: artifacts in the cache directory were created by `store()`, for the build
+            // identifier of the module, and wasmtime rejects artifacts made for other engines.
+            match unsafe { wasmtime::Module::deserialize_file(&self.engine, &cache_file) } {
+                Ok(module) => {
+                    debug!("Loaded [{}] from the cache", cache_file.display());
+                    return Ok(module);
+                }
+                Err(e) => debug!("Ignoring cached [{}]: {}", cache_file.display(), e),
+            }
+        }
+        let module = wasmtime::Module::new(&self.engine, module_bin).map_err(wasmtime_error)?;
+        if let Err(e) = Self::store(&module, &cache_file) {
+            warn!("Unable to cache [{}]: {}", cache_file.display(), e);
+        }
+        Ok(module)
+    }
+
+    fn store(module: &wasmtime::Module, cache_file: &Path) -> Result<(), WSError> {
+        let artifact = module.serialize().map_err(wasmtime_error)?;
+        if let Some(dir) = cache_file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_file = cache_file.with_extension("cwasm.tmp");
+        fs::write(&tmp_file, artifact)?;
+        fs::rename(&tmp_file, cache_file)?;
+        Ok(())
+    }
+}
+
+/// Artifacts are identified by a hash of the wasmtime version and of the engine settings.
+impl Precompiler for WasmtimeLoader {
+    fn runtime_id(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.engine
+            .precompile_compatibility_hash()
+            .hash(&mut hasher);
+        format!("wasmtime-{:016x}", hasher.finish())
+    }
+
+    fn precompile(&self, module_bin: &[u8]) -> Result<Vec<u8>, WSError> {
+        self.engine
+            .precompile_module(module_bin)
+            .map_err(wasmtime_error)
+    }
+}
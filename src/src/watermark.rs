@@ -0,0 +1,141 @@
+use crate::error::*;
+use crate::wasm_module::*;
+
+use hmac_sha256::{Hash, HMAC};
+use std::io::{self, prelude::*};
+use std::str;
+
+/// Name of the custom section storing a watermark.
+pub const WATERMARK_SECTION_NAME: &str = "watermark";
+
+const WATERMARK_VERSION: u8 = 0x01;
+
+/// A watermark identifying the recipient (customer, device) of a module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watermark {
+    /// Recipient identifier.
+    pub id: String,
+    tag: [u8; 32],
+}
+
+impl Watermark {
+    fn serialize(&self) -> Result<Vec<u8>, WSError> {
+        let mut writer = io::Cursor::new(vec![]);
+        writer.write_all(&[WATERMARK_VERSION])?;
+        varint::put_slice(&mut writer, self.id.as_bytes())?;
+        writer.write_all(&self.tag)?;
+        Ok(writer.into_inner())
+    }
+
+    fn deserialize(payload: &[u8]) -> Result<Self, WSError> {
+        TakeExact::decode(payload, payload.len() as _, |reader| {
+            let mut version = [0u8; 1];
+            reader.read_exact(&mut version)?;
+            if version[0] != WATERMARK_VERSION {
+                return Err(WSError::ParseError);
+            }
+            let id = str::from_utf8(&reader.get_slice()?)?.to_string();
+            let mut tag = [0u8; 32];
+            reader.read_exact(&mut tag)?;
+            Ok(Watermark { id, tag })
+        })
+    }
+}
+
+/// Hash all the sections of a module, except watermarks.
+fn module_hash(module: &Module) -> Result<[u8; 32], WSError> {
+    let mut h = Hash::new();
+    h.update(module.header);
+    for section in &module.sections {
+        if let Section::Custom(custom) = section {
+            if custom.name() == WATERMARK_SECTION_NAME {
+                continue;
+            }
+        }
+        let mut buf = vec![];
+        section.serialize(&mut buf)?;
+        h.update(buf);
+    }
+    Ok(h.finalize())
+}
+
+fn compute_tag(module: &Module, id: &str, key: &[u8]) -> Result<[u8; 32], WSError> {
+    let mut mac = HMAC::new(key);
+    mac.update(WATERMARK_SECTION_NAME);
+    mac.update([WATERMARK_VERSION]);
+    mac.update((id.len() as u64).to_le_bytes());
+    mac.update(id);
+    mac.update(module_hash(module)?);
+    Ok(mac.finalize())
+}
+
+impl Module {
+    /// Embed a watermark for the recipient `id`, replacing any existing one.
+    ///
+    /// The watermark is bound to the content of the module and to a secret key, so that it
+    /// cannot be moved to another module or forged without that key.
+    /// It is always stored as the last section, so that sections covering a prefix of the
+    /// module (such as partial signatures) are not affected by per-recipient watermarks.
+    pub fn embed_watermark(&mut self, id: &str, key: &[u8]) -> Result<(), WSError> {
+        self.remove_watermark();
+        let tag = compute_tag(self, id, key)?;
+        let watermark = Watermark {
+            id: id.to_string(),
+            tag,
+        };
+        self.sections.push(Section::Custom(CustomSection::new(
+            WATERMARK_SECTION_NAME.to_string(),
+            watermark.serialize()?,
+        )));
+        Ok(())
+    }
+
+    /// Embed the watermark again for the same recipient, after the module was changed.
+    pub fn refresh_watermark(&mut self, key: &[u8]) -> Result<Watermark, WSError> {
+        let watermark = self.watermark()?.ok_or(WSError::NoWatermark)?;
+        self.embed_watermark(&watermark.id, key)?;
+        self.watermark()?.ok_or(WSError::NoWatermark)
+    }
+
+    /// Remove all the watermarks from the module.
+    ///
+    /// Return `true` if at least one watermark section was found.
+    pub fn remove_watermark(&mut self) -> bool {
+        let count = self.sections.len();
+        self.sections.retain(|section| match section {
+            Section::Custom(custom) => custom.name() != WATERMARK_SECTION_NAME,
+            _ => true,
+        });
+        self.sections.len() != count
+    }
+
+    /// Extract the watermark of a module, without verifying it.
+    pub fn watermark(&self) -> Result<Option<Watermark>, WSError> {
+        let mut found = None;
+        for section in &self.sections {
+            if let Section::Custom(custom) = section {
+                if custom.name() == WATERMARK_SECTION_NAME {
+                    if found.is_some() {
+                        return Err(WSError::DuplicateWatermark);
+                    }
+                    found = Some(Watermark::deserialize(custom.payload())?);
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Extract the watermark of a module, and verify it with the given key.
+    pub fn verify_watermark(&self, key: &[u8]) -> Result<Watermark, WSError> {
+        let watermark = self.watermark()?.ok_or(WSError::NoWatermark)?;
+        let expected = compute_tag(self, &watermark.id, key)?;
+        let diff = expected
+            .iter()
+            .zip(watermark.tag.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return Err(WSError::VerificationFailed);
+        }
+        Ok(watermark)
+    }
+}